@@ -0,0 +1,142 @@
+//! Command line options. Everything else is configured with environment variables;
+//! these exist so scripts can run the client without answering prompts.
+
+pub const USAGE: &str = "Usage: client [--server <addr>] [--name <name>] [--token <token>]
+              [--room <room>] [--output text|json] [--pipe]
+
+  --server <addr>  Server to connect to (instead of CHAT_SERVER or the prompt)
+  --name <name>    Nickname to join with (instead of CHAT_USERNAME or the prompt)
+  --token <token>  Log in as a bot with a token from the server's /token create
+                   (instead of CHAT_TOKEN); the nickname is the bot's
+  --room <room>    Join a room after connecting; messages go to it
+  --output json    Write received events to stdout as JSON lines, without colors
+                   (logs go to stderr)
+  --pipe           Send each line of stdin as a message, write received events
+                   as JSON lines, and exit at the end of stdin";
+
+/// How received events are shown
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    /// Colored log lines for people
+    #[default]
+    Text,
+    /// One JSON object per event on stdout, for tools
+    Json,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct CliArgs {
+    pub server: Option<String>,
+    pub name: Option<String>,
+    /// Bot token, sent in place of a password
+    pub token: Option<String>,
+    pub room: Option<String>,
+    pub output: OutputFormat,
+    /// Non-interactive mode for scripts (--pipe)
+    pub pipe: bool,
+    pub help: bool,
+}
+
+impl CliArgs {
+    /// Parse the arguments after the program name
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Self, String> {
+        let mut parsed = CliArgs::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let value = match arg.as_str() {
+                "--pipe" => {
+                    parsed.pipe = true;
+                    continue;
+                }
+                "--help" | "-h" => {
+                    parsed.help = true;
+                    continue;
+                }
+                "--server" => &mut parsed.server,
+                "--name" => &mut parsed.name,
+                "--token" => &mut parsed.token,
+                "--room" => &mut parsed.room,
+                "--output" => {
+                    parsed.output = match args.next().as_deref() {
+                        Some("text") => OutputFormat::Text,
+                        Some("json") => OutputFormat::Json,
+                        _ => return Err("--output must be text or json".to_string()),
+                    };
+                    continue;
+                }
+                _ => return Err(format!("Unknown argument '{}'", arg)),
+            };
+            match args.next() {
+                Some(next) if !next.starts_with("--") => *value = Some(next),
+                _ => return Err(format!("{} needs a value", arg)),
+            }
+        }
+        Ok(parsed)
+    }
+
+    /// Whether events are written as JSON (`--pipe` always does)
+    pub fn json_output(&self) -> bool {
+        self.pipe || self.output == OutputFormat::Json
+    }
+}
+
+/// The nickname a bot token logs in as: `bot:<bot>` for a `<bot>.<secret>` token
+pub fn bot_name(token: &str) -> Option<String> {
+    match token.split_once('.') {
+        Some((bot, secret)) if !bot.is_empty() && !secret.is_empty() => {
+            Some(format!("bot:{}", bot))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<CliArgs, String> {
+        CliArgs::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn test_parse_args() {
+        assert_eq!(parse(&[]), Ok(CliArgs::default()));
+        assert_eq!(
+            parse(&[
+                "--pipe",
+                "--server",
+                "chat.lan:8080",
+                "--name",
+                "bot",
+                "--room",
+                "general"
+            ]),
+            Ok(CliArgs {
+                server: Some("chat.lan:8080".to_string()),
+                name: Some("bot".to_string()),
+                token: None,
+                room: Some("general".to_string()),
+                output: OutputFormat::Text,
+                pipe: true,
+                help: false,
+            })
+        );
+        assert!(parse(&["--name"]).is_err());
+        assert!(parse(&["--name", "--pipe"]).is_err());
+        assert!(parse(&["--verbose"]).is_err());
+
+        let args = parse(&["--output", "json"]).unwrap();
+        assert_eq!(args.output, OutputFormat::Json);
+        assert!(args.json_output());
+        assert!(parse(&["--pipe"]).unwrap().json_output());
+        assert!(!parse(&[]).unwrap().json_output());
+        assert!(parse(&["--output", "xml"]).is_err());
+        assert!(parse(&["--output"]).is_err());
+
+        let args = parse(&["--token", "deploy.5f3a"]).unwrap();
+        assert_eq!(args.token.as_deref(), Some("deploy.5f3a"));
+        assert_eq!(bot_name("deploy.5f3a"), Some("bot:deploy".to_string()));
+        assert_eq!(bot_name("deploy"), None);
+        assert_eq!(bot_name(".5f3a"), None);
+    }
+}
@@ -1,27 +1,107 @@
+use crate::delivery::{AwaitingEcho, Delivery, Destination};
+use crate::doctor;
+use crate::drafts::Drafts;
+use crate::events::{self, Event};
+use crate::export;
+use crate::filters::{self, Filter, Filters, Seen};
+use crate::gaps::Gaps;
+use crate::highlight::{self, Highlights};
 use crate::input::{self, ClientUserInput};
+use crate::keys::{self, KnownKeys, Trust};
+use crate::notify::{self, NotificationKind, Notifier};
+use crate::on_connect;
+use crate::paths::{self, StateFile};
+use crate::quiet::QuietJoins;
 use crate::readline_helper;
-use rustls::ClientConfig;
-use rustls::pki_types::ServerName;
+use crate::repeats::{Collapsed, Place, RepeatPolicy, Repeats};
+use crate::scrollback::Scrollback;
+use crate::sent::SentFrames;
+use crate::startup::{
+    self, ClientIdentity, IpPreference, Proxy, ReconnectPolicy, StartupError, Timeouts,
+};
+use crate::state_dir;
+use crate::status_bar::{ConnectionState, StatusBar};
+use crate::title::{Multiplexer, TerminalTitle};
+use crate::trust;
+use chrono::{Local, TimeZone};
+use shared::challenge::Challenge;
 use shared::commands::client as commands;
+use shared::digest::Digest;
+use shared::disconnect::{Disconnect, DisconnectReason};
+use shared::error::ChatError;
+use shared::fingerprint::Fingerprint;
+use shared::history::{self, HistoryLine};
+use shared::join_ack::JoinAck;
 use shared::logger;
-use shared::message::{ChatMessage, ChatMessageError, MessageTypes};
-use shared::network::{MAX_FILE_SIZE, TcpMessageHandler};
+use shared::message::{ChatMessage, MessageTypes};
+use shared::network::{CHUNK_SIZE, MAX_FILE_SIZE, TcpMessageHandler, WRITE_BUFFER_SIZE};
+use shared::parts::{self, Reassembler};
+use shared::presence::{self, PresenceDelta, Roster};
+use shared::rejection::Rejection;
+use shared::room_keys::{self, Keyring, RoomKey, RoomKeyFrame};
+use shared::rooms::{self, RoomSummary};
+use shared::sender_class::{self, SenderClass};
+use shared::server_info::ServerInfo;
+use shared::signing::{self, SigningKey};
+use shared::trace::Tracer;
+use shared::translation::{self, Translation};
 use shared::version::VERSION;
-use std::collections::{HashMap, HashSet};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::io;
-use std::net::AddrParseError;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::pin::Pin;
-use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
 use std::task::{Context, Poll};
-use std::time::Duration;
-use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
+use std::time::{Duration, Instant};
+use tokio::io::{
+    AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, BufStream, ReadBuf,
+};
 use tokio::net::TcpStream;
 use tokio::time::sleep;
-use tokio_rustls::TlsConnector;
 use tokio_rustls::client::TlsStream;
 use uuid::Uuid;
 
+/// Messages can't cross on the wire (each side waits for the other's OK), so
+/// messages sent back to back wait until nothing has arrived for this long
+const SETTLE: Duration = Duration::from_millis(200);
+
+/// Longest we wait for the server to acknowledge our Leave before closing anyway
+const LEAVE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Marks our own messages that were sent from another session of our nickname
+const SENT_ELSEWHERE: &str = "(sent elsewhere)";
+
+/// Shown for a private room's message sealed with a key we weren't given
+const NO_ROOM_KEY: &str = "🔒 [encrypted with a key you don't have]";
+
+/// Extra wait past the end of a kick or ban before reconnecting, so we don't arrive
+/// a moment early
+const REJOIN_MARGIN: Duration = Duration::from_secs(1);
+
+/// How often the member counts of our rooms are asked for again
+const MEMBER_REFRESH: Duration = Duration::from_secs(30);
+
+/// Room messages remembered for translations that follow them
+const MAX_TRANSLATABLE: usize = 100;
+
+/// Identifies the parts of one long message
+fn part_id() -> String {
+    Uuid::new_v4().simple().to_string()[..8].to_string()
+}
+
+/// Wait until held-back repeats are due to be shown (forever if there are none)
+async fn sleep_until_flush(at: Option<Instant>) {
+    match at {
+        Some(at) => tokio::time::sleep_until(at.into()).await,
+        None => std::future::pending().await,
+    }
+}
+
+pub fn connection_buffers(connection: ClientStream) -> BufStream<ClientStream> {
+    BufStream::with_capacity(CHUNK_SIZE, WRITE_BUFFER_SIZE, connection)
+}
+
 /// Pending file transfer request (for senders waiting for acceptance)
 #[derive(Debug, Clone)]
 pub struct PendingOutgoingTransfer {
@@ -32,6 +112,17 @@ pub struct PendingOutgoingTransfer {
     pub file_size: usize,
 }
 
+/// A room message shown, that a translation may follow
+#[derive(Debug)]
+struct Translatable {
+    room: String,
+    sender: String,
+    /// shared::translation::reference of the text as sent
+    reference: String,
+    /// The text as shown
+    text: String,
+}
+
 /// Pending file transfer request (for receivers)
 #[derive(Debug, Clone)]
 pub struct PendingIncomingTransfer {
@@ -43,30 +134,6 @@ pub struct PendingIncomingTransfer {
 }
 
 #[derive(Debug)]
-pub enum ChatClientError {
-    InvalidAddress,
-    IoError,
-    ChatMessageError,
-}
-
-impl From<AddrParseError> for ChatClientError {
-    fn from(_: AddrParseError) -> Self {
-        ChatClientError::InvalidAddress
-    }
-}
-
-impl From<io::Error> for ChatClientError {
-    fn from(_: io::Error) -> Self {
-        ChatClientError::IoError
-    }
-}
-
-impl From<ChatMessageError> for ChatClientError {
-    fn from(_: ChatMessageError) -> Self {
-        ChatClientError::ChatMessageError
-    }
-}
-
 pub enum ClientStream {
     Plain(TcpStream),
     Tls(Box<TlsStream<TcpStream>>),
@@ -112,106 +179,440 @@ impl AsyncWrite for ClientStream {
     }
 }
 
+impl ClientStream {
+    /// Fingerprint of the server's TLS certificate (None for plain TCP)
+    pub fn certificate_fingerprint(&self) -> Option<String> {
+        match self {
+            ClientStream::Plain(_) => None,
+            ClientStream::Tls(stream) => stream
+                .get_ref()
+                .1
+                .peer_certificates()
+                .and_then(|certs| certs.first())
+                .map(|cert| shared::tls::certificate_fingerprint(cert)),
+        }
+    }
+}
+
+/// Settings read from the environment at startup
+#[derive(Clone)]
+pub struct ClientSettings {
+    pub scrollback_lines: usize,
+    /// Program run on mentions and DMs
+    pub notify_command: Option<String>,
+    pub sign_messages: bool,
+    /// Password for a registered nickname
+    pub password: Option<String>,
+    /// Address family tried first when the server's name resolves to both
+    pub ip_preference: IpPreference,
+    /// Send a fingerprint during the handshake (see shared::fingerprint)
+    pub send_fingerprint: bool,
+    /// Pin a status line to the bottom of the terminal
+    pub status_bar: bool,
+    /// Show the room and unread count in the terminal's title
+    pub terminal_title: bool,
+    /// tmux or screen, to name the window and flag it on mentions
+    pub multiplexer: Option<Multiplexer>,
+    /// Certificate presented to servers that accept client certificates (mutual TLS)
+    pub identity: Option<Arc<ClientIdentity>>,
+    /// SOCKS5 proxy (e.g. Tor) every connection goes through
+    pub proxy: Option<Proxy>,
+    /// Write received messages to stdout as JSON lines (--pipe)
+    pub json_events: bool,
+    /// Send messages longer than the server allows in parts without asking
+    pub auto_split: bool,
+    /// Show our messages once the server relays them back, not when it acknowledges them
+    pub self_echo: bool,
+    /// Save what's being typed for each room for the next launch
+    pub save_drafts: bool,
+    /// When a sender's repeats of one message are collapsed into a count
+    pub repeats: RepeatPolicy,
+    /// Senders whose messages aren't shown, by class (bots, say)
+    pub hidden_classes: Vec<SenderClass>,
+    /// Show machine translations the server sends after room messages
+    pub translations: bool,
+    /// Quiet time after which a dated divider goes in front of the next message
+    /// (None = no dividers)
+    pub gap: Option<Duration>,
+    /// Start with joins, leaves and renames held back (/quiet joins)
+    pub quiet_joins: bool,
+    pub timeouts: Timeouts,
+    pub reconnect: ReconnectPolicy,
+}
+
 pub struct ChatClient {
-    connection: ClientStream,
+    /// Buffered so waiting for the next message can be abandoned without losing part
+    /// of a frame, and so each frame sent is written in one go
+    connection: BufStream<ClientStream>,
     server_host: String,
     server_port: u16,
     use_tls: bool,
+    /// Address family tried first when the server's name resolves to both
+    ip_preference: IpPreference,
+    /// Client certificate presented on every TLS connection
+    identity: Option<Arc<ClientIdentity>>,
+    /// SOCKS5 proxy connections go through, isolated per nickname
+    proxy: Option<Proxy>,
+    timeouts: Timeouts,
+    reconnect_policy: ReconnectPolicy,
+    /// When the last message arrived, for the read timeout
+    last_received: Instant,
     chat_name: String,
     /// Session token used to identify reconnecting clients and reclaim ghost sessions
     session_token: String,
+    /// Password for a registered nickname, sent with every join
+    password: Option<String>,
+    /// Password sent with /register, kept until the server confirms it
+    pending_password: Option<String>,
+    /// Our place in the server's waiting room while it's full (None = not queued)
+    queue_position: Option<usize>,
+    /// Sent after the version check on every connection (None = fingerprinting off)
+    fingerprint: Option<Fingerprint>,
+    /// Where `/debug trace on` writes frames (None = no state directory)
+    trace_path: Option<PathBuf>,
+    tracer: Option<Tracer>,
     last_dm_sender: Option<String>,
     connected_users: Arc<RwLock<HashSet<String>>>,
+    /// Everyone online and their status: the server's snapshot, kept up to date
+    /// from its presence deltas
+    roster: Roster,
+    /// Longest message the server accepts in bytes, shared with the input line's
+    /// length meter (0 = not advertised)
+    max_message: Arc<AtomicUsize>,
+    /// Message refused for its length, sent in parts by /split
+    pending_split: Option<ClientUserInput>,
+    /// Send over-long messages in parts instead of refusing them
+    auto_split: bool,
+    /// Long messages from others still arriving in parts
+    parts: Reassembler,
+    /// Ask the server to relay our own messages back (CHAT_SELF_ECHO)
+    self_echo: bool,
+    /// Our messages the server hasn't relayed back yet (self-echo only)
+    awaiting_echo: AwaitingEcho,
     was_kicked: bool,
+    /// What sent our recent frames, to explain the ones the server rejects
+    sent: SentFrames,
+    /// When a kick or ban we were told the end of runs out, if we're to come back then
+    /// (CHAT_RECONNECT_AFTER_KICK)
+    rejoin_at: Option<Instant>,
+    /// The server said it's shutting down: give it time to go before reconnecting
+    server_shut_down: bool,
+    /// We told the server we're leaving (/quit, Ctrl+C or end of input)
+    left: bool,
     current_status: Option<String>,
     /// Pending outgoing transfers (keyed by recipient name)
     pending_outgoing: HashMap<String, PendingOutgoingTransfer>,
     /// Pending incoming transfers (keyed by sender name)
     pending_incoming: HashMap<String, PendingIncomingTransfer>,
+    /// Rooms we are a member of
+    joined_rooms: HashSet<String>,
+    /// Room to join once we're in the chat (--room)
+    pending_room: Option<String>,
+    /// Whether we're in the chat (past any challenge or waiting room) and the
+    /// server has finished greeting us
+    in_chat: bool,
+    /// Room that plain messages are sent to (None = main chat)
+    current_room: Option<String>,
+    /// Messages that arrived in joined rooms while another room was current
+    unread: HashMap<String, usize>,
+    /// Whether the next room list was asked for with /rooms --verbose
+    verbose_room_list: bool,
+    /// Whether that listing is of archived rooms
+    archived_room_list: bool,
+    /// Message length limits of the rooms we're in that have their own
+    room_limits: HashMap<String, usize>,
+    /// Member counts of the rooms we're in, from the last room info about them
+    room_members: HashMap<String, usize>,
+    /// Rooms asked about to learn their limits, whose answers aren't shown
+    quiet_room_info: HashSet<String>,
+    /// Files /export-room will write the history to (keyed by room)
+    pending_exports: HashMap<String, PathBuf>,
+    /// Name, network and description the server sent when we joined
+    server_info: Option<ServerInfo>,
+    /// Whether the next server info was asked for with /server info
+    show_server_info: bool,
+    /// Recent messages persisted across restarts
+    scrollback: Scrollback,
+    /// Runs the user's notify command on mentions and DMs
+    notifier: Notifier,
+    /// Words shown and notified like mentions of our nickname (/highlight)
+    highlights: Highlights,
+    /// What's shown and what isn't (/filter)
+    filters: Filters,
+    /// Joins, leaves and renames held back (/quiet joins)
+    quiet_joins: QuietJoins,
+    /// Messages others keep repeating, shown once with a count
+    repeats: Repeats,
+    gaps: Gaps,
+    show_translations: bool,
+    /// Room messages lately shown, that a translation may follow
+    translatable: VecDeque<Translatable>,
+    /// Classes of sender whose messages aren't shown
+    hidden_classes: Vec<SenderClass>,
+    /// What's been typed for each room, shared with the input line
+    drafts: Arc<Mutex<Drafts>>,
+    /// Commands run, in order, every time we get into the chat (on_connect.txt)
+    on_connect: Vec<String>,
+    /// Key our messages are signed with (None = signing disabled)
+    signing_key: Option<SigningKey>,
+    /// Other users' public keys, pinned on first use
+    known_keys: KnownKeys,
+    /// Which of our rooms are private, and their encryption keys
+    room_keys: Keyring,
+    /// Whether to show the status line once the chat starts
+    show_status_bar: bool,
+    /// Received messages are also written to stdout as JSON lines
+    json_events: bool,
+    status_bar: StatusBar,
+    /// Whether to take over the terminal title once the chat starts
+    show_title: bool,
+    multiplexer: Option<Multiplexer>,
+    title: TerminalTitle,
+    /// Direct messages received since we last typed anything
+    unread_dms: usize,
 }
 
 impl ChatClient {
-    pub async fn new(server_addr: &str, name: String) -> Result<Self, ChatClientError> {
+    pub async fn new(
+        server_addr: &str,
+        name: String,
+        settings: ClientSettings,
+    ) -> Result<Self, StartupError> {
+        let ClientSettings {
+            scrollback_lines,
+            notify_command,
+            sign_messages,
+            password,
+            ip_preference,
+            send_fingerprint,
+            status_bar: show_status_bar,
+            terminal_title: show_title,
+            multiplexer,
+            identity,
+            proxy,
+            json_events,
+            auto_split,
+            self_echo,
+            save_drafts,
+            repeats,
+            hidden_classes,
+            translations: show_translations,
+            gap,
+            quiet_joins,
+            timeouts,
+            reconnect: reconnect_policy,
+        } = settings;
         // Parse address - could be host:port or just host
-        let (host, port, use_tls) = Self::parse_server_addr(server_addr)?;
-
-        logger::log_info(&format!("Connecting to {}:{}...", host, port));
-        let stream = TcpStream::connect(format!("{}:{}", host, port))
-            .await
-            .map_err(|e| {
-                logger::log_error(&format!("Failed to connect to {}:{} - {}", host, port, e));
-                ChatClientError::IoError
-            })?;
-
-        logger::log_success(&format!("TCP connection established to {}:{}", host, port));
-
-        let connection = if use_tls {
-            logger::log_info("Establishing TLS connection...");
-            let mut root_cert_store = rustls::RootCertStore::empty();
-            root_cert_store.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
-
-            let config = ClientConfig::builder()
-                .with_root_certificates(root_cert_store)
-                .with_no_client_auth();
-
-            let connector = TlsConnector::from(Arc::new(config));
-            let server_name = ServerName::try_from(host.clone()).map_err(|e| {
-                logger::log_error(&format!("Invalid server name '{}': {:?}", host, e));
-                io::Error::new(io::ErrorKind::InvalidInput, "Invalid server name")
-            })?;
-
-            let tls_stream = connector.connect(server_name, stream).await.map_err(|e| {
-                logger::log_error(&format!("TLS handshake failed: {}", e));
-                ChatClientError::IoError
-            })?;
-            logger::log_success("TLS connection established");
-            ClientStream::Tls(Box::new(tls_stream))
+        let (host, port, use_tls) = startup::parse_server_addr(server_addr)?;
+        let addr = startup::display_addr(&host, port);
+
+        match &proxy {
+            Some(proxy) => logger::log_info(&format!(
+                "Connecting to {} through proxy {}...",
+                addr, proxy.addr
+            )),
+            None => logger::log_info(&format!("Connecting to {}...", addr)),
+        }
+        let connection = startup::open_connection(
+            &host,
+            port,
+            use_tls,
+            ip_preference,
+            identity.as_deref(),
+            proxy.as_ref().map(|proxy| (proxy, name.as_str())),
+            &timeouts,
+        )
+        .await?;
+        if use_tls {
+            logger::log_success(&format!("TLS connection established to {}", addr));
         } else {
+            logger::log_success(&format!("TCP connection established to {}", addr));
             logger::log_info("Using plain TCP (no encryption)");
-            ClientStream::Plain(stream)
-        };
+        }
 
         // Generate a unique session token for this client session
         // This token is used to reclaim a ghost session on reconnection
         let session_token = Uuid::new_v4().to_string();
 
+        let file_stem = paths::server_file_stem(&host, port);
+        let state_dir = paths::state_dir();
+        let scrollback_path = state_dir
+            .as_ref()
+            .map(|dir| StateFile::History.path(dir, &file_stem));
+        let known_keys_path = state_dir
+            .as_ref()
+            .map(|dir| StateFile::KnownKeys.path(dir, &file_stem));
+        let trace_path = state_dir
+            .as_ref()
+            .map(|dir| StateFile::Trace.path(dir, &file_stem));
+        let drafts_path = state_dir
+            .as_ref()
+            .filter(|_| save_drafts)
+            .map(|dir| StateFile::Drafts.path(dir, &file_stem));
+
+        // Our signing key is created the first time we sign on this server
+        let signing_key = if sign_messages {
+            match &state_dir {
+                Some(dir) => {
+                    let key_path = StateFile::SigningKey.path(dir, &file_stem);
+                    match keys::load_or_create(&key_path) {
+                        Ok(key) => {
+                            logger::log_info("Signing outgoing messages");
+                            Some(key)
+                        }
+                        Err(e) => {
+                            logger::log_error(&format!("Message signing disabled: {}", e));
+                            None
+                        }
+                    }
+                }
+                None => {
+                    logger::log_error("Message signing disabled: no state directory");
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        // The lineage secret is per server, so fingerprints can't link us across servers
+        let fingerprint = match (&state_dir, send_fingerprint) {
+            (Some(dir), true) => {
+                let lineage_path = StateFile::Lineage.path(dir, &file_stem);
+                match keys::load_or_create_lineage(&lineage_path) {
+                    Ok(lineage) => {
+                        let mut capabilities = Vec::new();
+                        if use_tls {
+                            capabilities.push("tls");
+                        }
+                        if signing_key.is_some() {
+                            capabilities.push("signing");
+                        }
+                        if notify_command.is_some() {
+                            capabilities.push("notify");
+                        }
+                        let client_version = format!(
+                            "{} {}/{}",
+                            VERSION,
+                            std::env::consts::OS,
+                            std::env::consts::ARCH
+                        );
+                        Some(Fingerprint::new(&client_version, &capabilities, &lineage))
+                    }
+                    Err(e) => {
+                        logger::log_warning(&format!("Client fingerprint disabled: {}", e));
+                        None
+                    }
+                }
+            }
+            _ => None,
+        };
+
         Ok(ChatClient {
-            connection,
+            connection: connection_buffers(connection),
             server_host: host,
             server_port: port,
             use_tls,
+            ip_preference,
+            identity,
+            proxy,
+            timeouts,
+            reconnect_policy,
+            last_received: Instant::now(),
             chat_name: name,
             session_token,
+            password,
+            pending_password: None,
+            queue_position: None,
+            fingerprint,
+            trace_path,
+            tracer: None,
             last_dm_sender: None,
             connected_users: Arc::new(RwLock::new(HashSet::new())),
+            roster: Roster::default(),
+            max_message: Arc::new(AtomicUsize::new(0)),
+            pending_split: None,
+            auto_split,
+            parts: Reassembler::default(),
+            self_echo,
+            awaiting_echo: AwaitingEcho::default(),
             was_kicked: false,
+            rejoin_at: None,
+            sent: SentFrames::default(),
+            server_shut_down: false,
+            left: false,
             current_status: None,
             pending_outgoing: HashMap::new(),
             pending_incoming: HashMap::new(),
+            joined_rooms: HashSet::new(),
+            pending_room: None,
+            in_chat: false,
+            current_room: None,
+            unread: HashMap::new(),
+            verbose_room_list: false,
+            archived_room_list: false,
+            room_limits: HashMap::new(),
+            room_members: HashMap::new(),
+            quiet_room_info: HashSet::new(),
+            pending_exports: HashMap::new(),
+            server_info: None,
+            show_server_info: false,
+            scrollback: Scrollback::new(scrollback_path, scrollback_lines),
+            notifier: Notifier::new(notify_command),
+            highlights: Highlights::load(state_dir.as_ref().map(|dir| {
+                dir.join(state_dir::CONFIG_DIR)
+                    .join(highlight::HIGHLIGHTS_FILE)
+            })),
+            filters: Filters::load(
+                state_dir
+                    .as_ref()
+                    .map(|dir| dir.join(state_dir::CONFIG_DIR).join(filters::FILTERS_FILE)),
+            ),
+            quiet_joins: QuietJoins::new(quiet_joins),
+            repeats: Repeats::new(repeats),
+            gaps: Gaps::new(gap),
+            show_translations,
+            translatable: VecDeque::new(),
+            hidden_classes,
+            drafts: Arc::new(Mutex::new(Drafts::load(drafts_path))),
+            on_connect: state_dir
+                .as_ref()
+                .map(|dir| on_connect::load(dir, &file_stem))
+                .unwrap_or_default(),
+            signing_key,
+            known_keys: KnownKeys::load(known_keys_path),
+            room_keys: Keyring::default(),
+            show_status_bar,
+            json_events,
+            status_bar: StatusBar::disabled(),
+            show_title,
+            multiplexer,
+            title: TerminalTitle::disabled(),
+            unread_dms: 0,
         })
     }
 
-    fn parse_server_addr(addr: &str) -> Result<(String, u16, bool), ChatClientError> {
-        // Check if address starts with tls://
-        let (use_tls, addr) = if let Some(stripped) = addr.strip_prefix("tls://") {
-            (true, stripped)
-        } else {
-            (false, addr)
-        };
+    /// Show messages saved from the previous session with this server
+    pub fn restore_scrollback(&mut self) {
+        self.scrollback.restore(&mut self.gaps);
+    }
 
-        // Parse host:port
-        if let Some((host, port)) = addr.rsplit_once(':') {
-            let port = port
-                .parse::<u16>()
-                .map_err(|_| ChatClientError::InvalidAddress)?;
-            Ok((host.to_string(), port, use_tls))
-        } else {
-            // No port specified, use default
-            Ok((addr.to_string(), 8080, use_tls))
+    pub fn save_scrollback(&self) {
+        if let Err(e) = self.scrollback.save() {
+            logger::log_warning(&format!("Failed to save scrollback: {}", e));
+        }
+    }
+
+    /// Keep what's being typed, saving the drafts if CHAT_SAVE_DRAFTS is on
+    pub fn save_drafts(&self) {
+        if let Ok(mut drafts) = self.drafts.lock()
+            && let Err(e) = drafts.save()
+        {
+            logger::log_warning(&format!("Failed to save drafts: {}", e));
         }
     }
 
-    pub async fn join_server(&mut self) -> Result<(), ChatClientError> {
+    pub async fn join_server(&mut self) -> Result<(), ChatError> {
         // First send version check
         logger::log_info(&format!("Sending version check (v{})...", VERSION));
         let version_message = ChatMessage::try_new(
@@ -220,68 +621,134 @@ impl ChatClient {
         )?;
         self.send_message_chunked(version_message).await?;
 
-        // Send join message with username and session token
-        // Format: username|session_token
-        let join_content = format!("{}|{}", self.chat_name, self.session_token);
+        if let Some(fingerprint) = &self.fingerprint {
+            let fingerprint_message = ChatMessage::try_new(
+                MessageTypes::Fingerprint,
+                Some(fingerprint.encode().into_bytes()),
+            )?;
+            self.send_message_chunked(fingerprint_message).await?;
+        }
+
+        self.send_join().await
+    }
+
+    /// Send join message with username and session token
+    async fn send_join(&mut self) -> Result<(), ChatError> {
+        self.queue_position = None;
+        // Format: username|session_token[|password]
+        let mut join_content = format!("{}|{}", self.chat_name, self.session_token);
+        if let Some(password) = &self.password {
+            join_content.push('|');
+            join_content.push_str(password);
+        }
         let chat_message =
             ChatMessage::try_new(MessageTypes::Join, Some(join_content.into_bytes()))?;
         self.send_message_chunked(chat_message).await?;
         Ok(())
     }
 
-    async fn reconnect(&mut self) -> Result<(), ChatClientError> {
-        const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
-        const MAX_BACKOFF: Duration = Duration::from_secs(60);
-        const BACKOFF_MULTIPLIER: u32 = 2;
+    /// Solve the server's proof-of-work challenge, answer it and join again
+    async fn answer_challenge(&mut self, challenge: Challenge) -> Result<(), ChatError> {
+        logger::log_info(&format!(
+            "Server requires a proof-of-work challenge (difficulty {}), solving...",
+            challenge.difficulty
+        ));
+        let started = Instant::now();
+        let answer = tokio::task::spawn_blocking(move || challenge.solve())
+            .await
+            .map_err(|e| ChatError::IoError(e.into()))?;
+        logger::log_info(&format!(
+            "Challenge solved in {:.1}s",
+            started.elapsed().as_secs_f64()
+        ));
+        let answer_message = ChatMessage::try_new(
+            MessageTypes::Challenge,
+            Some(answer.to_string().into_bytes()),
+        )?;
+        self.send_message_chunked(answer_message).await?;
+        self.send_join().await
+    }
 
+    /// Whether to reconnect after losing the connection. Network errors and the reasons
+    /// the server gives for coming back always do; a kick or ban only when
+    /// CHAT_RECONNECT_AFTER_KICK is on and the server said when it ends, after waiting
+    /// until then.
+    async fn may_reconnect(&mut self) -> bool {
+        if !self.was_kicked {
+            return true;
+        }
+        let Some(rejoin_at) = self.rejoin_at.take() else {
+            logger::log_info("Not reconnecting - the server ended this session");
+            return false;
+        };
+        let wait = rejoin_at.saturating_duration_since(Instant::now());
+        logger::log_info(&format!(
+            "Reconnecting in {}s, once the server lets us back",
+            wait.as_secs()
+        ));
+        self.status_bar
+            .update(|status| status.state = ConnectionState::Reconnecting);
+        sleep(wait).await;
+        self.was_kicked = false;
+        true
+    }
+
+    async fn reconnect(&mut self) -> Result<(), ChatError> {
         // Explicitly shutdown the old connection before reconnecting
         let _ = self.connection.shutdown().await;
+        self.in_chat = false;
+        // Nothing sent on the old connection will be relayed back now
+        for (to, text) in self.awaiting_echo.clear() {
+            self.show_own(&to, &text, Delivery::Failed);
+        }
+        self.update_sending();
+
+        self.status_bar
+            .update(|status| status.state = ConnectionState::Reconnecting);
 
-        // Give the server time to detect the closure and clean up
-        sleep(Duration::from_millis(100)).await;
+        // Give the server time to detect the closure and clean up - or, when it said it's
+        // shutting down, to be gone before the first attempt
+        if std::mem::take(&mut self.server_shut_down) {
+            sleep(self.reconnect_policy.delay(1)).await;
+        } else {
+            sleep(Duration::from_millis(100)).await;
+        }
 
-        let mut backoff = INITIAL_BACKOFF;
         let mut attempt = 1;
 
         loop {
             logger::log_info(&format!(
-                "Attempting to reconnect to {}:{} (attempt {})...",
-                self.server_host, self.server_port, attempt
+                "Attempting to reconnect to {} (attempt {})...",
+                startup::display_addr(&self.server_host, self.server_port),
+                attempt
             ));
 
-            match TcpStream::connect(format!("{}:{}", self.server_host, self.server_port)).await {
-                Ok(stream) => {
-                    // Re-establish TLS if needed
-                    let connection = if self.use_tls {
-                        logger::log_info("Re-establishing TLS connection...");
-                        let mut root_cert_store = rustls::RootCertStore::empty();
-                        root_cert_store.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
-
-                        let config = ClientConfig::builder()
-                            .with_root_certificates(root_cert_store)
-                            .with_no_client_auth();
-
-                        let connector = TlsConnector::from(Arc::new(config));
-                        let server_name =
-                            ServerName::try_from(self.server_host.clone()).map_err(|_| {
-                                io::Error::new(io::ErrorKind::InvalidInput, "Invalid server name")
-                            })?;
-
-                        let tls_stream = connector.connect(server_name, stream).await?;
-                        logger::log_success("TLS connection re-established");
-                        ClientStream::Tls(Box::new(tls_stream))
-                    } else {
-                        ClientStream::Plain(stream)
-                    };
-
-                    self.connection = connection;
+            match startup::open_connection(
+                &self.server_host,
+                self.server_port,
+                self.use_tls,
+                self.ip_preference,
+                self.identity.as_deref(),
+                self.proxy
+                    .as_ref()
+                    .map(|proxy| (proxy, self.chat_name.as_str())),
+                &self.timeouts,
+            )
+            .await
+            {
+                Ok(connection) => {
+                    self.connection = connection_buffers(connection);
+                    self.last_received = Instant::now();
                     logger::log_success("Reconnected to server!");
+                    self.gaps.force();
 
                     // Rejoin the server with the same username
                     if let Err(e) = self.join_server().await {
                         logger::log_error(&format!("Failed to rejoin server: {:?}", e));
                         return Err(e);
                     }
+                    self.status_bar
+                        .update(|status| status.state = ConnectionState::Connected);
 
                     // Restore user's status if they had one set
                     if let Some(status) = &self.current_status {
@@ -294,24 +761,77 @@ impl ChatClient {
                         }
                     }
 
+                    // Rejoin any rooms we were in. Private ones say so again and
+                    // hand out new keys.
+                    self.room_keys.clear();
+                    for room in self.joined_rooms.clone() {
+                        if let Ok(join_msg) = self.join_room_message(&room)
+                            && let Err(e) = self.send_message_chunked(join_msg).await
+                        {
+                            logger::log_warning(&format!("Failed to rejoin room: {:?}", e));
+                        }
+                    }
+
                     return Ok(());
                 }
+                Err(e) if self.reconnect_policy.max_retries == Some(attempt) => {
+                    logger::log_warning(&format!(
+                        "Reconnection attempt {} failed: {}. Giving up",
+                        attempt, e
+                    ));
+                    return Err(ChatError::Disconnect);
+                }
                 Err(e) => {
+                    // Exponential backoff with cap
+                    let backoff = self.reconnect_policy.delay(attempt);
                     logger::log_warning(&format!(
                         "Reconnection attempt {} failed: {}. Retrying in {:?}...",
                         attempt, e, backoff
                     ));
                     sleep(backoff).await;
-
-                    // Exponential backoff with cap
-                    backoff =
-                        std::cmp::min(backoff.saturating_mul(BACKOFF_MULTIPLIER), MAX_BACKOFF);
                     attempt += 1;
                 }
             }
         }
     }
 
+    /// Remember a room message we showed, so a translation of it can be shown under
+    /// it. `wire` is the text as sent, which the translation refers to.
+    fn note_translatable(&mut self, room: &str, sender: &str, wire: &str, text: &str) {
+        if !self.show_translations {
+            return;
+        }
+        if self.translatable.len() == MAX_TRANSLATABLE {
+            self.translatable.pop_front();
+        }
+        self.translatable.push_back(Translatable {
+            room: room.to_string(),
+            sender: sender.to_string(),
+            reference: translation::reference(wire),
+            text: text.to_string(),
+        });
+    }
+
+    /// Ask about a room without showing the answer, to keep its limits up to date
+    async fn learn_room_limits(&mut self, room: &str) {
+        if let Ok(request) = ChatMessage::try_new(
+            MessageTypes::RoomInfoRequest,
+            Some(room.as_bytes().to_vec()),
+        ) && self.send_message_chunked(request).await.is_ok()
+        {
+            self.quiet_room_info.insert(room.to_string());
+        }
+    }
+
+    /// Ask for the room list without showing it, to keep member counts current
+    async fn refresh_room_members(&mut self) {
+        if let Ok(request) = ChatMessage::try_new(MessageTypes::RoomInfoRequest, None)
+            && self.send_message_chunked(request).await.is_ok()
+        {
+            self.quiet_room_info.insert(String::new());
+        }
+    }
+
     fn get_message_content(&self, message: &ChatMessage, msg_type_name: &str) -> Option<String> {
         message.content_as_string().or_else(|| {
             logger::log_error(&format!("Received invalid UTF-8 {} message", msg_type_name));
@@ -319,135 +839,1189 @@ impl ChatClient {
         })
     }
 
-    async fn handle_message(&mut self, message: ChatMessage) -> bool {
-        match message.msg_type {
-            MessageTypes::Ping => {
-                // Respond to server ping with pong
-                if let Ok(pong_msg) = ChatMessage::try_new(MessageTypes::Pong, None)
-                    && let Err(e) = self.send_message_chunked(pong_msg).await
-                {
-                    logger::log_warning(&format!("Failed to send pong: {:?}", e));
-                    return false; // Signal connection issue
-                }
-                return true;
+    async fn handle_message(&mut self, message: ChatMessage) -> bool {
+        let (class, message) = take_class(message);
+        // Filtered messages are still recorded and emitted, just not shown
+        let filtered = self.filters.hide_frame(class, &message);
+        let rename = message.msg_type == MessageTypes::ChatMessage
+            && class == SenderClass::Notice
+            && message
+                .content_as_string()
+                .is_some_and(|content| content.contains(presence::RENAME_NOTICE));
+        if matches!(
+            message.msg_type,
+            MessageTypes::ChatMessage
+                | MessageTypes::RoomMessage
+                | MessageTypes::DirectMessage
+                | MessageTypes::Announcement
+                | MessageTypes::RoomAnnouncement
+        ) && !self.hidden_classes.contains(&class)
+            && !filtered
+            && (!rename || !self.quiet_joins.is_on())
+        {
+            if let Some(count) = self.quiet_joins.take_count() {
+                logger::log_divider(&count);
+            }
+            if let Some(divider) = self.gaps.observe(Local::now()) {
+                logger::log_divider(&divider);
+            }
+        }
+        match message.msg_type {
+            MessageTypes::Ping => {
+                // Respond to server ping with pong, timing the server's acknowledgement
+                let sent = Instant::now();
+                if let Ok(pong_msg) = ChatMessage::try_new(MessageTypes::Pong, None)
+                    && let Err(e) = self.send_message_chunked(pong_msg).await
+                {
+                    logger::log_warning(&format!("Failed to send pong: {:?}", e));
+                    return false; // Signal connection issue
+                }
+                self.status_bar.update(|status| {
+                    status.latency = Some(sent.elapsed());
+                    status.record_send(sent);
+                });
+                return true;
+            }
+            MessageTypes::PresenceDelta => {
+                if let Some(content) = self.get_message_content(&message, "presence")
+                    && let Some(delta) = PresenceDelta::decode(&content)
+                {
+                    self.roster.apply(&delta);
+                    self.sync_completion();
+                    match &delta {
+                        PresenceDelta::Joined { user, .. } => {
+                            if self.filters.shows(&Seen::presence(None, user)) {
+                                self.show_presence(&format!("{} has joined the chat", user));
+                            }
+                            self.emit(Event::Join { room: None, user });
+                        }
+                        PresenceDelta::Left(user) => {
+                            if self.filters.shows(&Seen::presence(None, user)) {
+                                self.show_presence(&format!("{} has left the chat", user));
+                            }
+                            self.emit(Event::Leave { room: None, user });
+                        }
+                        // Renames are announced in the chat
+                        PresenceDelta::Renamed { from, to } => {
+                            self.follow_rename(from, to);
+                            self.emit(Event::Rename { from, to });
+                        }
+                        // Statuses show in /list
+                        PresenceDelta::Status { .. } => {}
+                    }
+                }
+            }
+            MessageTypes::UserRename => {
+                if let Some(content) = self.get_message_content(&message, "rename")
+                    && let Some((_, to)) = presence::decode_rename(&content)
+                {
+                    logger::log_success(&format!("You have been renamed to '{}'", to));
+                    self.set_chat_name(to.to_string());
+                }
+            }
+            MessageTypes::ChatMessage => {
+                if let Some(content) = self.get_message_content(&message, "chat") {
+                    let (content, trailer) = signing::split_signature(&content);
+                    // A notice's text is the server's, whatever it looks like
+                    match content
+                        .split_once(": ")
+                        .filter(|_| class != SenderClass::Notice)
+                    {
+                        Some((sender, text)) => {
+                            // Parts of a long message wait for the rest of it
+                            let Some(text) =
+                                self.parts.push(signing::MAIN_CHAT_SCOPE, sender, text)
+                            else {
+                                return true;
+                            };
+                            // Ours, relayed back because we asked for self-echo or
+                            // because it was sent from another session
+                            if sender == self.chat_name {
+                                if !self.own_echo(Destination::Chat, &text) {
+                                    let content = format!("{}: {}", sender, text);
+                                    logger::log_chat(&format!("{} {}", content, SENT_ELSEWHERE));
+                                    self.scrollback.record_chat(&content);
+                                }
+                                return true;
+                            }
+                            if self.hidden_classes.contains(&class) {
+                                return true;
+                            }
+                            let badge = format!(
+                                "{}{}",
+                                class_badge(class),
+                                self.signature_badge(
+                                    trailer,
+                                    sender,
+                                    signing::MAIN_CHAT_SCOPE,
+                                    &text,
+                                )
+                            );
+                            let shown = !filtered
+                                && self.observe_repeat(Place::Chat, sender, &badge, &text);
+                            let content = format!("{}: {}", sender, text);
+                            let highlighted = shown && self.is_highlighted(&text);
+                            if highlighted {
+                                logger::log_highlighted_chat(
+                                    None,
+                                    &format!("{}{}", badge, content),
+                                );
+                            } else if shown {
+                                logger::log_chat(&format!("{}{}", badge, content));
+                            }
+                            if shown || filtered {
+                                self.scrollback.record_chat(&content);
+                            }
+                            self.emit(Event::Message {
+                                room: None,
+                                from: sender,
+                                class,
+                                text: &text,
+                            });
+                            if highlighted {
+                                self.notify(NotificationKind::Mention, sender, "", &text);
+                            }
+                        }
+                        None => {
+                            if self.hidden_classes.contains(&class) {
+                                return true;
+                            }
+                            if rename && !filtered {
+                                self.show_presence(content);
+                            } else if !filtered {
+                                logger::log_system(content);
+                            }
+                            self.scrollback.record_chat(content);
+                        }
+                    }
+                }
+            }
+            MessageTypes::ListUsers => {
+                // The snapshot sent as we join (or after falling behind); deltas keep
+                // it up to date from here
+                if let Some(content) = self.get_message_content(&message, "list users") {
+                    self.roster = Roster::from_snapshot(&content);
+                    self.sync_completion();
+                }
+            }
+            MessageTypes::DirectMessage => {
+                if let Some(content) = self.get_message_content(&message, "dm")
+                    && let Some((sender, rest)) = content.split_once('|')
+                    && let Some((recipient, msg)) = rest.split_once('|')
+                {
+                    if sender == self.chat_name && recipient != self.chat_name {
+                        let (msg, _) = signing::split_signature(msg);
+                        let scope = signing::dm_scope(recipient);
+                        if let Some(msg) = self.parts.push(&scope, sender, msg)
+                            && !self.own_echo(Destination::Direct(recipient.to_string()), &msg)
+                        {
+                            // We sent it from another session
+                            logger::log_info(&format!(
+                                "[DM to {}] {}: {}",
+                                recipient, SENT_ELSEWHERE, msg
+                            ));
+                        }
+                    } else if recipient == self.chat_name {
+                        let (msg, trailer) = signing::split_signature(msg);
+                        let scope = signing::dm_scope(recipient);
+                        let Some(msg) = self.parts.push(&scope, sender, msg) else {
+                            return true;
+                        };
+                        let msg: &str = &msg;
+                        let badge = format!(
+                            "{}{}",
+                            class_badge(class),
+                            self.signature_badge(trailer, sender, &scope, msg)
+                        );
+                        if self.hidden_classes.contains(&class) {
+                            return true;
+                        }
+                        self.emit(Event::DirectMessage {
+                            from: sender,
+                            to: recipient,
+                            class,
+                            text: msg,
+                        });
+                        let shown =
+                            !filtered && self.observe_repeat(Place::Direct, sender, &badge, msg);
+                        // Track the sender so we can reply with /r
+                        self.last_dm_sender = Some(sender.to_string());
+                        if shown {
+                            logger::log_warning(&format!("{}[DM from {}]: {}", badge, sender, msg));
+                            self.unread_dms += 1;
+                            let unread_dms = self.unread_dms;
+                            self.status_bar
+                                .update(|status| status.unread_dms = unread_dms);
+                            self.notify(NotificationKind::DirectMessage, sender, "", msg);
+                        }
+                    }
+                }
+            }
+            MessageTypes::Error => {
+                if let Some(content) = self.get_message_content(&message, "error") {
+                    let (error, text) = ChatError::parse(&content);
+                    // A frame of ours the server wouldn't take: say which, and what sent it
+                    let rejection = error
+                        .as_ref()
+                        .filter(|error| Rejection::rejects_frame(error))
+                        .and_then(|_| Rejection::decode(text));
+                    let (text, frame) = match &rejection {
+                        Some(rejection) => (rejection.text.as_str(), Some(rejection.frame)),
+                        None => (text, None),
+                    };
+                    match (&error, frame) {
+                        (Some(error), Some(_)) => logger::log_error(&format!(
+                            "Server rejected {}: {} (error {})",
+                            self.sent.describe(frame),
+                            text,
+                            error.code()
+                        )),
+                        _ => logger::log_error(text),
+                    }
+                    self.emit(Event::Error {
+                        code: error.as_ref().map(ChatError::code),
+                        text,
+                        frame,
+                        action: frame.and_then(|frame| self.sent.action(Some(frame))),
+                    });
+                    // Kicked (or still on a kick's cooldown), our nickname was refused
+                    // or there's no room for guests: don't reconnect
+                    if matches!(
+                        error,
+                        Some(
+                            ChatError::Kicked
+                                | ChatError::KickCooldown
+                                | ChatError::JoinError
+                                | ChatError::GuestsFull
+                        )
+                    ) {
+                        self.was_kicked = true;
+                    }
+                }
+            }
+            MessageTypes::Disconnect => {
+                if let Some(content) = self.get_message_content(&message, "disconnect") {
+                    let Some(disconnect) = Disconnect::decode(&content) else {
+                        logger::log_warning(&format!("Disconnected by the server: {}", content));
+                        return true;
+                    };
+                    let (reason, text) = (disconnect.reason, disconnect.text());
+                    if reason == DisconnectReason::ProtocolViolation {
+                        // Likely the last frame we sent (e.g. one too big to read)
+                        logger::log_error(&format!(
+                            "Disconnected by the server after {}: {}",
+                            self.sent.describe(None),
+                            text
+                        ));
+                    } else if reason.reconnects() {
+                        logger::log_warning(&format!("Disconnected by the server: {}", text));
+                    } else {
+                        logger::log_error(&format!("Disconnected by the server: {}", text));
+                    }
+                    self.emit(Event::Disconnect {
+                        reason,
+                        text,
+                        retry_after: disconnect.retry_after,
+                    });
+                    // The server closes the connection next; whether we come back then
+                    // depends on why
+                    if !reason.reconnects() {
+                        self.was_kicked = true;
+                    }
+                    self.rejoin_at = disconnect
+                        .retry_after
+                        .filter(|_| {
+                            self.reconnect_policy.after_kick
+                                && matches!(
+                                    reason,
+                                    DisconnectReason::Kicked | DisconnectReason::Banned
+                                )
+                        })
+                        .map(|wait| Instant::now() + wait + REJOIN_MARGIN);
+                    self.server_shut_down = reason == DisconnectReason::Shutdown;
+                }
+            }
+            MessageTypes::FileTransfer => {
+                self.handle_file_transfer(&message);
+            }
+            MessageTypes::FileTransferAck => {
+                if let Some(content) = self.get_message_content(&message, "file ack") {
+                    logger::log_success(&content);
+                }
+            }
+            MessageTypes::FileTransferRequest => {
+                self.handle_file_transfer_request(&message);
+            }
+            MessageTypes::FileTransferResponse => {
+                return self.handle_file_transfer_response(&message).await;
+            }
+            MessageTypes::SetStatus => {
+                if let Some(content) = self.get_message_content(&message, "status") {
+                    logger::log_success(&content);
+                }
+            }
+            MessageTypes::Pong => {
+                // Ignore pong messages (we don't send pings from client)
+            }
+            MessageTypes::VersionMismatch => {
+                if let Some(content) = self.get_message_content(&message, "version mismatch") {
+                    let parts: Vec<&str> = content.split('|').collect();
+                    if parts.len() >= 3 {
+                        logger::log_error(&format!(
+                            "Version mismatch: client v{} != server v{}",
+                            parts[0], parts[1]
+                        ));
+                        logger::log_error(&format!(
+                            "Please upgrade your binary or Docker image. See: {}",
+                            parts[2]
+                        ));
+                    } else {
+                        logger::log_error(
+                            "Version mismatch with server. Please upgrade your client.",
+                        );
+                    }
+                    // Mark as kicked so we don't try to reconnect
+                    self.was_kicked = true;
+                    return false;
+                }
+            }
+            MessageTypes::VersionCheck => {
+                // Server shouldn't send this to client, ignore
+            }
+            MessageTypes::Register => {
+                // Our nickname is registered (or its password changed) - log in with
+                // the password from now on
+                if let Some(password) = self.pending_password.take() {
+                    if self.password.replace(password).is_some() {
+                        logger::log_success(&format!(
+                            "The password of '{}' is changed. Update CHAT_PASSWORD to log in with it next time you start the client",
+                            self.chat_name
+                        ));
+                    } else {
+                        logger::log_success(&format!(
+                            "'{}' is now registered to you. Set CHAT_PASSWORD to log in to it next time you start the client",
+                            self.chat_name
+                        ));
+                    }
+                }
+            }
+            MessageTypes::Challenge => {
+                if let Some(content) = self.get_message_content(&message, "challenge") {
+                    let Some(challenge) = Challenge::decode(&content) else {
+                        logger::log_error(
+                            "Server sent a proof-of-work challenge this client cannot solve",
+                        );
+                        // Reconnecting would only get the same challenge
+                        self.was_kicked = true;
+                        return false;
+                    };
+                    if let Err(e) = self.answer_challenge(challenge).await {
+                        logger::log_warning(&format!("Failed to answer challenge: {:?}", e));
+                        return false;
+                    }
+                }
+            }
+            MessageTypes::WaitingRoom => {
+                if let Some(content) = self.get_message_content(&message, "waiting room")
+                    && let Ok(position) = content.parse::<usize>()
+                {
+                    if self.queue_position.is_none() {
+                        logger::log_warning(&format!(
+                            "The server is full - you are #{} in the queue and will join automatically when a slot frees",
+                            position
+                        ));
+                    } else {
+                        logger::log_info(&format!("You are now #{} in the queue", position));
+                    }
+                    self.queue_position = Some(position);
+                    self.status_bar
+                        .update(|status| status.state = ConnectionState::Queued(position));
+                }
+            }
+            MessageTypes::JoinRoom => {
+                if let Some(content) = self.get_message_content(&message, "join room")
+                    && let Some((room, user)) = content.split_once('|')
+                {
+                    if user == self.chat_name {
+                        // Rejoins after a reconnect keep the current room unchanged
+                        if self.joined_rooms.insert(room.to_string()) {
+                            self.current_room = Some(room.to_string());
+                            self.unread.remove(room);
+                            logger::log_success(&format!(
+                                "Joined #{} - messages now go to this room (/part to leave)",
+                                room
+                            ));
+                            self.emit(Event::Join {
+                                room: Some(room),
+                                user,
+                            });
+                        }
+                        // Learn the room's message length limit before we type into it
+                        self.learn_room_limits(room).await;
+                    } else if self.joined_rooms.contains(room) {
+                        if self.filters.shows(&Seen::presence(Some(room), user)) {
+                            self.show_presence(&format!("{} has joined #{}", user, room));
+                        }
+                        self.emit(Event::Join {
+                            room: Some(room),
+                            user,
+                        });
+                    }
+                }
+            }
+            MessageTypes::LeaveRoom => {
+                if let Some(content) = self.get_message_content(&message, "leave room")
+                    && let Some((room, user)) = content.split_once('|')
+                {
+                    if user == self.chat_name {
+                        self.joined_rooms.remove(room);
+                        self.unread.remove(room);
+                        self.room_limits.remove(room);
+                        self.room_members.remove(room);
+                        self.room_keys.forget(room);
+                        if self.current_room.as_deref() == Some(room) {
+                            self.current_room = None;
+                        }
+                        logger::log_info(&format!("Left #{}", room));
+                        self.emit(Event::Leave {
+                            room: Some(room),
+                            user,
+                        });
+                    } else if self.joined_rooms.contains(room) {
+                        if self.filters.shows(&Seen::presence(Some(room), user)) {
+                            self.show_presence(&format!("{} has left #{}", user, room));
+                        }
+                        self.emit(Event::Leave {
+                            room: Some(room),
+                            user,
+                        });
+                    }
+                }
+            }
+            MessageTypes::RoomMessage => {
+                if let Some(content) = self.get_message_content(&message, "room")
+                    && let Some((room, rest)) = content.split_once('|')
+                    && let Some((sender, msg)) = rest.split_once('|')
+                {
+                    // Only display rooms we're in
+                    if self.joined_rooms.contains(room) {
+                        let (msg, trailer) = signing::split_signature(msg);
+                        let scope = signing::room_scope(room);
+                        let Some(wire) = self.parts.push(&scope, sender, msg) else {
+                            return true;
+                        };
+                        // The signature covers what was sent, encrypted or not
+                        let wire: &str = &wire;
+                        let decrypted;
+                        let msg = if room_keys::is_encrypted(wire) {
+                            match self.room_keys.decrypt(room, sender, wire) {
+                                Some(text) => {
+                                    decrypted = text;
+                                    decrypted.as_str()
+                                }
+                                None => {
+                                    logger::log_room_chat(
+                                        room,
+                                        &format!("{}: {}", sender, NO_ROOM_KEY),
+                                    );
+                                    return true;
+                                }
+                            }
+                        } else {
+                            wire
+                        };
+                        // Ours come back for self-echo or from another session
+                        if sender == self.chat_name {
+                            if !self.own_echo(Destination::Room(room.to_string()), msg) {
+                                let text = format!("{}: {}", sender, msg);
+                                logger::log_room_chat(
+                                    room,
+                                    &format!("{} {}", text, SENT_ELSEWHERE),
+                                );
+                                self.scrollback.record_room(room, &text);
+                            }
+                            return true;
+                        }
+                        if self.hidden_classes.contains(&class) {
+                            return true;
+                        }
+                        let badge = format!(
+                            "{}{}",
+                            class_badge(class),
+                            self.signature_badge(trailer, sender, &scope, wire)
+                        );
+                        let place = Place::Room(room.to_string());
+                        let shown = !filtered && self.observe_repeat(place, sender, &badge, msg);
+                        let text = format!("{}: {}", sender, msg);
+                        let highlighted = shown && self.is_highlighted(msg);
+                        if highlighted {
+                            logger::log_highlighted_chat(Some(room), &format!("{}{}", badge, text));
+                        } else if shown {
+                            logger::log_room_chat(room, &format!("{}{}", badge, text));
+                        }
+                        self.emit(Event::Message {
+                            room: Some(room),
+                            from: sender,
+                            class,
+                            text: msg,
+                        });
+                        if !shown {
+                            if filtered {
+                                self.scrollback.record_room(room, &text);
+                            }
+                            return true;
+                        }
+                        self.scrollback.record_room(room, &text);
+                        self.note_translatable(room, sender, wire, msg);
+                        if self.current_room.as_deref() != Some(room) {
+                            *self.unread.entry(room.to_string()).or_default() += 1;
+                        }
+                        if highlighted {
+                            self.notify(NotificationKind::Mention, sender, room, msg);
+                        }
+                    }
+                }
+            }
+            MessageTypes::Announcement => {
+                // A moderator changed the room's settings, its limit perhaps among them
+                if class == SenderClass::Notice
+                    && let Some(content) = message.content_as_string()
+                    && let Some((room, _)) = content.split_once('|')
+                    && self.joined_rooms.contains(room)
+                {
+                    self.learn_room_limits(room).await;
+                }
+                if let Some(content) = self.get_message_content(&message, "announcement")
+                    && let Some((room, rest)) = content.split_once('|')
+                    && let Some((sender, msg)) = rest.split_once('|')
+                    && !self.hidden_classes.contains(&class)
+                {
+                    let text = format!("[ANNOUNCE] {}: {}", sender, msg);
+                    if room.is_empty() {
+                        if !filtered {
+                            logger::log_announcement(&format!("{}: {}", sender, msg));
+                        }
+                        self.scrollback.record_chat(&text);
+                    } else if self.joined_rooms.contains(room) {
+                        if !filtered {
+                            logger::log_announcement(&format!("#{} {}: {}", room, sender, msg));
+                        }
+                        self.scrollback.record_room(room, &text);
+                    } else {
+                        return true;
+                    }
+                    self.emit(Event::Announcement {
+                        room: Some(room).filter(|room| !room.is_empty()),
+                        from: sender,
+                        class,
+                        text: msg,
+                    });
+                }
+            }
+            MessageTypes::RoomAnnouncement => {
+                if let Some(content) = self.get_message_content(&message, "room announcement")
+                    && let Some((room, rest)) = content.split_once('|')
+                    && let Some((moderator, msg)) = rest.split_once('|')
+                    && self.joined_rooms.contains(room)
+                    && !self.hidden_classes.contains(&class)
+                {
+                    let from = format!("{} (moderator)", moderator);
+                    if !filtered {
+                        logger::log_announcement(&format!("#{} {}: {}", room, from, msg));
+                    }
+                    self.scrollback
+                        .record_room(room, &format!("[ANNOUNCE] {}: {}", from, msg));
+                    self.emit(Event::Announcement {
+                        room: Some(room),
+                        from: moderator,
+                        class,
+                        text: msg,
+                    });
+                }
+            }
+            MessageTypes::RoomKey => {
+                if let Some(content) = self.get_message_content(&message, "room key") {
+                    match RoomKeyFrame::decode(&content) {
+                        Some(frame) => Box::pin(self.handle_room_key(frame)).await,
+                        None => logger::log_warning("Received a malformed room key"),
+                    }
+                }
+            }
+            MessageTypes::Notice => {
+                if let Some(content) = self.get_message_content(&message, "notice") {
+                    logger::log_warning(&content);
+                }
+            }
+            MessageTypes::RoomInfoResponse => {
+                if let Some(content) = self.get_message_content(&message, "room info") {
+                    let response = rooms::decode_response(&content);
+                    for room in response.iter().flat_map(|(_, rooms)| rooms) {
+                        if !self.joined_rooms.contains(&room.name) {
+                            continue;
+                        }
+                        self.room_members.insert(room.name.clone(), room.members);
+                        if room.max_length > 0 {
+                            self.room_limits.insert(room.name.clone(), room.max_length);
+                        } else {
+                            self.room_limits.remove(&room.name);
+                        }
+                    }
+                    match response {
+                        Some((query, _)) if self.quiet_room_info.remove(&query) => {}
+                        Some((query, rooms)) if query.is_empty() => {
+                            let verbose = std::mem::take(&mut self.verbose_room_list);
+                            let archived = std::mem::take(&mut self.archived_room_list);
+                            self.show_room_list(&rooms, verbose, archived);
+                        }
+                        Some((_, rooms)) => rooms.iter().for_each(|r| self.show_room_info(r)),
+                        None => logger::log_warning("Received malformed room info"),
+                    }
+                }
+            }
+            MessageTypes::HistoryResponse => {
+                if let Some(content) = self.get_message_content(&message, "room history") {
+                    match history::decode_response(&content) {
+                        Some((room, mut lines)) => match self.pending_exports.remove(&room) {
+                            Some(path) => {
+                                self.decrypt_history(&room, &mut lines);
+                                match export::write(&path, &room, &lines) {
+                                    Ok(()) => logger::log_success(&format!(
+                                        "Exported {} message(s) from #{} to {}",
+                                        lines.len(),
+                                        room,
+                                        path.display()
+                                    )),
+                                    Err(e) => logger::log_error(&format!(
+                                        "Failed to write {}: {}",
+                                        path.display(),
+                                        e
+                                    )),
+                                }
+                            }
+                            None => logger::log_warning(&format!(
+                                "Received the history of #{} without asking for it",
+                                room
+                            )),
+                        },
+                        None => logger::log_warning("Received malformed room history"),
+                    }
+                }
+            }
+            MessageTypes::ServerInfo => {
+                if let Some(content) = self.get_message_content(&message, "server info") {
+                    match ServerInfo::decode(&content) {
+                        Some(info) => self.update_server_info(info),
+                        None => logger::log_warning("Received malformed server info"),
+                    }
+                }
+            }
+            MessageTypes::RateLimited => {
+                // Format: reason|retry_after_secs|message
+                if let Some(content) = self.get_message_content(&message, "rate limited") {
+                    let mut parts = content.splitn(3, '|');
+                    if let Some(secs) = parts.nth(1).and_then(|secs| secs.parse::<u64>().ok()) {
+                        let until = Instant::now() + Duration::from_secs(secs);
+                        self.status_bar.update(|status| status.rate_limited(until));
+                    }
+                    let text = parts.next().unwrap_or(&content);
+                    logger::log_error(text);
+                    self.emit(Event::Error {
+                        code: Some(ChatError::RateLimited.code()),
+                        text,
+                        frame: None,
+                        action: None,
+                    });
+                }
+            }
+            MessageTypes::JoinAck => {
+                // Sent once we're in the chat (after any wait in the waiting room)
+                if let Some(content) = self.get_message_content(&message, "join acknowledgement") {
+                    match JoinAck::decode(&content) {
+                        Some(ack) => self.accept_join(ack).await,
+                        None => logger::log_warning("Received malformed join acknowledgement"),
+                    }
+                }
+            }
+            MessageTypes::Translation => {
+                // Only under a message we showed: one hidden, blocked or collapsed
+                // takes its translation with it
+                if let Some(content) = self.get_message_content(&message, "translation")
+                    && let Some(translation) = Translation::decode(&content)
+                    && self.show_translations
+                    && let Some(original) = self
+                        .translatable
+                        .iter()
+                        .find(|t| {
+                            t.room == translation.room
+                                && t.sender == translation.sender
+                                && t.reference == translation.reference
+                        })
+                        .map(|t| t.text.clone())
+                {
+                    logger::log_translation(&format!(
+                        "#{} {} [{}]: {}",
+                        translation.room, translation.sender, translation.lang, translation.text
+                    ));
+                    self.emit(Event::Translation {
+                        room: &translation.room,
+                        from: &translation.sender,
+                        lang: &translation.lang,
+                        text: &translation.text,
+                        original: &original,
+                    });
+                }
+            }
+            MessageTypes::WelcomeBack => {
+                // Direct messages held while we were away follow right after it
+                if let Some(content) = self.get_message_content(&message, "welcome-back digest") {
+                    match Digest::decode(&content) {
+                        Some(digest) => logger::log_info(&digest.summary()),
+                        None => logger::log_warning("Received malformed welcome-back digest"),
+                    }
+                }
+            }
+            _ => {
+                logger::log_warning(&format!("Unknown message type: {:?}", message.msg_type));
+            }
+        }
+        true
+    }
+
+    fn emit(&self, event: Event) {
+        if self.json_events {
+            events::emit(event);
+        }
+    }
+
+    /// Note a message from someone else, showing any of their repeats held back
+    /// before it. Returns whether to show it.
+    fn observe_repeat(&mut self, place: Place, sender: &str, badge: &str, text: &str) -> bool {
+        let (shown, collapsed) = self
+            .repeats
+            .observe(&place, sender, badge, text, Instant::now());
+        if let Some(collapsed) = collapsed {
+            self.show_collapsed(collapsed);
+        }
+        shown
+    }
+
+    /// Show the repeats held back for a window or since their sender went quiet
+    fn flush_repeats(&mut self) {
+        for collapsed in self.repeats.flush(Instant::now()) {
+            self.show_collapsed(collapsed);
+        }
+    }
+
+    /// One line for repeats that were held back: "alice: buy now (x3)"
+    fn show_collapsed(&mut self, collapsed: Collapsed) {
+        let Collapsed {
+            place,
+            sender,
+            badge,
+            text,
+            count,
+        } = collapsed;
+        let line = format!("{}: {} (x{})", sender, text, count);
+        match place {
+            Place::Chat => {
+                logger::log_chat(&format!("{}{}", badge, line));
+                self.scrollback.record_chat(&line);
+            }
+            Place::Room(room) => {
+                logger::log_room_chat(&room, &format!("{}{}", badge, line));
+                self.scrollback.record_room(&room, &line);
+            }
+            Place::Direct => {
+                logger::log_warning(&format!(
+                    "{}[DM from {}]: {} (x{})",
+                    badge, sender, text, count
+                ));
+            }
+        }
+    }
+
+    /// Print the /rooms listing. Verbose mode puts our rooms first, with unread counts.
+    /// Archived rooms are only listed when asked for, and then on their own.
+    fn show_room_list(&self, rooms: &[RoomSummary], verbose: bool, archived: bool) {
+        let rooms: Vec<&RoomSummary> = rooms
+            .iter()
+            .filter(|room| room.archived == archived)
+            .collect();
+        if archived && rooms.is_empty() {
+            logger::log_info("No archived rooms.");
+            return;
+        }
+        if !verbose {
+            if rooms.is_empty() {
+                logger::log_info("No rooms yet. Use /join <room> to create one.");
+                return;
+            }
+            logger::log_info(if archived {
+                "Archived rooms:"
+            } else {
+                "Rooms:"
+            });
+            for room in rooms {
+                let joined = if self.joined_rooms.contains(&room.name) {
+                    " (joined)"
+                } else {
+                    ""
+                };
+                logger::log_info(&format!(" - {}{}", room_line(room), joined));
+            }
+            return;
+        }
+
+        let (mine, others): (Vec<&RoomSummary>, Vec<&RoomSummary>) = rooms
+            .into_iter()
+            .partition(|room| self.joined_rooms.contains(&room.name));
+        if mine.is_empty() {
+            logger::log_info("You are not in any rooms.");
+        } else {
+            logger::log_info("Your rooms (* = current):");
+            for room in mine {
+                let marker = if self.current_room.as_deref() == Some(room.name.as_str()) {
+                    '*'
+                } else {
+                    '-'
+                };
+                let unread = match self.unread.get(&room.name) {
+                    Some(count) => format!(" ({} unread)", count),
+                    None => String::new(),
+                };
+                logger::log_info(&format!(" {} {}{}", marker, room_line(room), unread));
+            }
+        }
+        if !others.is_empty() {
+            logger::log_info("Other rooms:");
+            for room in others {
+                logger::log_info(&format!(" - {}", room_line(room)));
+            }
+        }
+    }
+
+    fn show_room_info(&self, room: &RoomSummary) {
+        if room.topic.is_empty() {
+            logger::log_info(&format!("#{}", room.name));
+        } else {
+            logger::log_info(&format!("#{} - {}", room.name, room.topic));
+        }
+        if room.private {
+            logger::log_info("  🔒 Private: invite-only, messages end-to-end encrypted");
+        }
+        if room.archived {
+            logger::log_info("  🗄 Archived: read-only, its history kept to read and export");
+        }
+        logger::log_info(&format!(
+            "  Members: {}{} | Moderators: {}",
+            room.members,
+            if self.joined_rooms.contains(&room.name) {
+                " (including you)"
+            } else {
+                ""
+            },
+            room.moderators.join(", ")
+        ));
+        let slowmode = match room.slowmode_secs {
+            0 => "off".to_string(),
+            secs => format!("one message every {}s", secs),
+        };
+        logger::log_info(&format!(
+            "  Slow mode: {} | History: {}",
+            slowmode, room.retention
+        ));
+        if room.max_length > 0 {
+            logger::log_info(&format!(
+                "  Messages: at most {} bytes, not split into parts",
+                room.max_length
+            ));
+        }
+        if !room.translate_to.is_empty() {
+            logger::log_info(&format!("  Translated into: {}", room.translate_to));
+        }
+        if let Some(created) = Local.timestamp_opt(room.created_at, 0).single() {
+            logger::log_info(&format!("  Created: {}", created.format("%Y-%m-%d %H:%M")));
+        }
+    }
+
+    /// Append our signature to an outgoing message (unchanged if signing is off)
+    /// Send a message's text after `prefix` (the room or recipient), signed if we sign
+    /// messages. Text longer than the server allows goes in parts, with the signature
+    /// over the whole text on the last one.
+    async fn send_text(
+        &mut self,
+        msg_type: MessageTypes,
+        prefix: &str,
+        scope: &str,
+        text: &str,
+    ) -> Result<(), ChatError> {
+        let max_message = self.max_message.load(Ordering::Relaxed);
+        let mut contents = match max_message {
+            0 => None,
+            max => parts::split(text, max, &part_id()),
+        }
+        .unwrap_or_else(|| vec![text.to_string()]);
+        if let Some(key) = &self.signing_key
+            && let Some(last) = contents.last_mut()
+        {
+            *last = signing::attach(last, &signing::sign(key, &self.chat_name, scope, text));
+        }
+        let in_parts = contents.len() > 1;
+        for (i, content) in contents.into_iter().enumerate() {
+            if i > 0 {
+                self.settle().await?;
+            }
+            let message = ChatMessage::try_new(
+                msg_type,
+                Some(format!("{}{}", prefix, content).into_bytes()),
+            )?;
+            self.send_message_chunked(message).await?;
+        }
+        // Let the echo of the last part through before anything else is sent
+        if in_parts {
+            self.settle().await?;
+        }
+        Ok(())
+    }
+
+    /// Send a message we typed, then show it: the status bar says it's sending until
+    /// the server acknowledges it, and it's marked as not delivered if that fails
+    async fn send_own(&mut self, to: Destination, text: &str) -> Result<(), ChatError> {
+        // Private rooms are sent the text encrypted; it's still the text we show and
+        // wait to see echoed
+        let encrypted = match &to {
+            Destination::Room(room) if self.room_keys.is_private(room) => {
+                match self.room_keys.current(room) {
+                    Some(key) => Some(key.encrypt(room, &self.chat_name, text)),
+                    None => {
+                        logger::log_error(&format!(
+                            "#{} is private and its key hasn't reached you yet - try again in a moment",
+                            room
+                        ));
+                        return Ok(());
+                    }
+                }
+            }
+            _ => None,
+        };
+        self.status_bar.update(|status| status.sending += 1);
+        let result = self
+            .send_text(
+                to.message_type(),
+                &to.prefix(),
+                &to.scope(),
+                encrypted.as_deref().unwrap_or(text),
+            )
+            .await;
+        if result.is_ok() && self.self_echo {
+            // Shown when the server relays it back
+            self.awaiting_echo.push(to, text);
+        } else {
+            self.show_own(&to, text, Delivery::of(&result));
+        }
+        self.update_sending();
+        result
+    }
+
+    /// One of our messages came back from the server. Returns false if it wasn't
+    /// sent from this session.
+    fn own_echo(&mut self, to: Destination, text: &str) -> bool {
+        let Some(missed) = self.awaiting_echo.echoed(&to, text) else {
+            return false;
+        };
+        // Sent before it but never relayed: the server turned them down
+        for (missed_to, missed_text) in missed {
+            self.show_own(&missed_to, &missed_text, Delivery::Failed);
+        }
+        self.show_own(&to, text, Delivery::Confirmed);
+        self.update_sending();
+        true
+    }
+
+    /// Messages waiting for the server, in the status bar
+    fn update_sending(&self) {
+        let waiting = self.awaiting_echo.waiting();
+        self.status_bar.update(|status| status.sending = waiting);
+    }
+
+    /// Show a message we sent, and what became of it
+    fn show_own(&mut self, to: &Destination, text: &str, delivery: Delivery) {
+        let line = delivery.echo(&format!("{}: {}", self.chat_name, text));
+        let (room, recipient) = match to {
+            Destination::Chat => {
+                logger::log_chat(&line);
+                self.scrollback.record_chat(&line);
+                (None, None)
             }
-            MessageTypes::Join => {
-                if let Some(content) = self.get_message_content(&message, "join") {
-                    logger::log_system(&format!("{} has joined the chat", content));
-                }
+            Destination::Room(room) => {
+                logger::log_room_chat(room, &line);
+                self.scrollback.record_room(room, &line);
+                (Some(room.as_str()), None)
             }
-            MessageTypes::Leave => {
-                if let Some(content) = self.get_message_content(&message, "leave") {
-                    logger::log_system(&format!("{} has left the chat", content));
-                }
+            Destination::Direct(recipient) => {
+                logger::log_info(&delivery.echo(&format!("[DM to {}]: {}", recipient, text)));
+                (None, Some(recipient.as_str()))
             }
-            MessageTypes::UserRename => {
-                if let Some(content) = self.get_message_content(&message, "rename") {
-                    logger::log_success(&format!("You have been renamed to '{}'", content));
-                    self.chat_name = content;
-                }
+        };
+        self.emit(Event::Sent {
+            room,
+            to: recipient,
+            text,
+            status: delivery.name(),
+        });
+    }
+
+    /// Decrypt a private room's stored history as far as our keys go
+    fn decrypt_history(&self, room: &str, lines: &mut [HistoryLine]) {
+        for line in lines {
+            if room_keys::is_encrypted(&line.message) {
+                line.message = self
+                    .room_keys
+                    .decrypt(room, &line.sender, &line.message)
+                    .unwrap_or_else(|| NO_ROOM_KEY.to_string());
             }
-            MessageTypes::ChatMessage => {
-                if let Some(content) = self.get_message_content(&message, "chat") {
-                    let should_display = content
-                        .split_once(": ")
-                        .is_none_or(|(username, _)| username != self.chat_name);
+        }
+    }
 
-                    if should_display {
-                        logger::log_chat(&content);
-                    }
+    /// JoinRoom for `room`, with our public key if we sign so we can join private rooms
+    fn join_room_message(&self, room: &str) -> Result<ChatMessage, ChatError> {
+        let content = match &self.signing_key {
+            Some(key) => format!("{}|{}", room, hex::encode(key.verifying_key().to_bytes())),
+            None => room.to_string(),
+        };
+        Ok(ChatMessage::try_new(
+            MessageTypes::JoinRoom,
+            Some(content.into_bytes()),
+        )?)
+    }
+
+    /// Keep up with a private room's encryption: whether it's private, new keys we're
+    /// asked to make as its keeper, and keys the keeper sealed to us
+    async fn handle_room_key(&mut self, frame: RoomKeyFrame) {
+        // Private rooms only let in clients that sign
+        let Some(signing_key) = self.signing_key.clone() else {
+            return;
+        };
+        match frame {
+            RoomKeyFrame::Private { room, on } => {
+                self.room_keys.set_private(&room, on);
+                if on {
+                    logger::log_info(&format!(
+                        "🔒 #{} is private: only invited users can join, and messages are end-to-end encrypted",
+                        room
+                    ));
+                } else {
+                    logger::log_warning(&format!(
+                        "#{} is no longer private: messages to it are sent unencrypted",
+                        room
+                    ));
                 }
             }
-            MessageTypes::ListUsers => {
-                if let Some(content) = self.get_message_content(&message, "list users") {
-                    // Update the connected users list for autocomplete
-                    let mut users = self.connected_users.write().unwrap();
-                    users.clear();
-                    for user in content.lines() {
-                        users.insert(user.to_string());
+            RoomKeyFrame::Rotate { room, members, .. } => {
+                let key = RoomKey::generate();
+                let mut sealed = Vec::new();
+                for (member, public_key) in &members {
+                    if *member == self.chat_name {
+                        continue;
                     }
-                    drop(users);
-
-                    logger::log_info("Current users online:");
-                    for user in content.lines() {
-                        logger::log_info(&format!(" - {}", user));
+                    // The server hands out the public keys, so only seal to the one
+                    // pinned for each member
+                    if self.known_keys.check(member, public_key) == Trust::Changed {
+                        logger::log_warning(&format!(
+                            "{} joined #{} with a different key than before - not giving them the room's key",
+                            member, room
+                        ));
+                        continue;
                     }
-                }
-            }
-            MessageTypes::DirectMessage => {
-                if let Some(content) = self.get_message_content(&message, "dm")
-                    && let Some((sender, rest)) = content.split_once('|')
-                    && let Some((recipient, msg)) = rest.split_once('|')
-                {
-                    // Only display if we are the recipient (not the sender - we already showed it locally)
-                    if recipient == self.chat_name {
-                        logger::log_warning(&format!("[DM from {}]: {}", sender, msg));
-                        // Track the sender so we can reply with /r
-                        self.last_dm_sender = Some(sender.to_string());
+                    if let Some(copy) =
+                        key.seal(&signing_key, &self.chat_name, &room, member, public_key)
+                    {
+                        sealed.push((member.clone(), copy));
                     }
                 }
-            }
-            MessageTypes::Error => {
-                if let Some(content) = self.get_message_content(&message, "error") {
-                    logger::log_error(&content);
-                    // Check if this is a kick message
-                    if content.contains("kicked") {
-                        self.was_kicked = true;
+                self.room_keys.set_private(&room, true);
+                self.room_keys.install(&room, key);
+                for (i, (member, copy)) in sealed.into_iter().enumerate() {
+                    if i > 0 && self.settle().await.is_err() {
+                        return;
                     }
-                }
-            }
-            MessageTypes::FileTransfer => {
-                self.handle_file_transfer(&message);
-            }
-            MessageTypes::FileTransferAck => {
-                if let Some(content) = self.get_message_content(&message, "file ack") {
-                    logger::log_success(&content);
-                }
-            }
-            MessageTypes::FileTransferRequest => {
-                self.handle_file_transfer_request(&message);
-            }
-            MessageTypes::FileTransferResponse => {
-                return self.handle_file_transfer_response(&message).await;
-            }
-            MessageTypes::SetStatus => {
-                if let Some(content) = self.get_message_content(&message, "status") {
-                    logger::log_success(&content);
-                }
-            }
-            MessageTypes::Pong => {
-                // Ignore pong messages (we don't send pings from client)
-            }
-            MessageTypes::VersionMismatch => {
-                if let Some(content) = self.get_message_content(&message, "version mismatch") {
-                    let parts: Vec<&str> = content.split('|').collect();
-                    if parts.len() >= 3 {
-                        logger::log_error(&format!(
-                            "Version mismatch: client v{} != server v{}",
-                            parts[0], parts[1]
-                        ));
-                        logger::log_error(&format!(
-                            "Please upgrade your binary or Docker image. See: {}",
-                            parts[2]
+                    let frame = RoomKeyFrame::Key {
+                        room: room.clone(),
+                        to: member,
+                        from: String::new(),
+                        sealed: copy,
+                    };
+                    if let Ok(message) = ChatMessage::try_new(
+                        MessageTypes::RoomKey,
+                        Some(frame.encode().into_bytes()),
+                    ) && let Err(e) = self.send_message_chunked(message).await
+                    {
+                        logger::log_warning(&format!(
+                            "Failed to share the key for #{}: {:?}",
+                            room, e
                         ));
-                    } else {
-                        logger::log_error(
-                            "Version mismatch with server. Please upgrade your client.",
-                        );
+                        return;
                     }
-                    // Mark as kicked so we don't try to reconnect
-                    self.was_kicked = true;
-                    return false;
                 }
             }
-            MessageTypes::VersionCheck => {
-                // Server shouldn't send this to client, ignore
+            RoomKeyFrame::Key {
+                room, from, sealed, ..
+            } => {
+                let Some((key, keeper_key)) =
+                    RoomKey::open(&sealed, &signing_key, &self.chat_name, &from, &room)
+                else {
+                    logger::log_warning(&format!(
+                        "The key for #{} from {} didn't open - it wasn't sealed to you by them",
+                        room, from
+                    ));
+                    return;
+                };
+                if self.known_keys.check(&from, &keeper_key) == Trust::Changed {
+                    logger::log_warning(&format!(
+                        "{} signed the key for #{} with a different key than before - not using it",
+                        from, room
+                    ));
+                    return;
+                }
+                let first = self.room_keys.current(&room).is_none();
+                self.room_keys.set_private(&room, true);
+                self.room_keys.install(&room, key);
+                if first {
+                    logger::log_success(&format!(
+                        "🔒 Got the key for #{} from {} - you can read and send messages there",
+                        room, from
+                    ));
+                }
             }
-            _ => {
-                logger::log_warning(&format!("Unknown message type: {:?}", message.msg_type));
+        }
+    }
+
+    /// Badge shown before a received message: "[✓] " for a valid signature from the
+    /// key pinned for the sender, "[!] " for a bad or unexpected one, nothing if unsigned
+    fn signature_badge(
+        &mut self,
+        trailer: Option<&str>,
+        sender: &str,
+        scope: &str,
+        message: &str,
+    ) -> &'static str {
+        let Some(trailer) = trailer else {
+            return "";
+        };
+        let Some(public_key) = signing::verify(trailer, sender, scope, message) else {
+            logger::log_warning(&format!("Message from {} has an invalid signature", sender));
+            return "[!] ";
+        };
+        match self.known_keys.check(sender, &public_key) {
+            Trust::New | Trust::Trusted => "[✓] ",
+            Trust::Changed => {
+                logger::log_warning(&format!(
+                    "{} signed with a different key than before - this may not be the same person",
+                    sender
+                ));
+                "[!] "
             }
         }
-        true
     }
 
     fn handle_file_transfer(&self, message: &ChatMessage) {
@@ -754,113 +2328,621 @@ impl ChatClient {
         true
     }
 
+    /// Handle a line of input, as what sent the frames it leads to
+    async fn handle_input_line(
+        &mut self,
+        line: &str,
+        user_input: input::ClientUserInput,
+    ) -> Result<(), ChatError> {
+        self.sent.begin(line);
+        let result = Box::pin(self.handle_user_input(user_input)).await;
+        self.sent.end();
+        result
+    }
+
     async fn handle_user_input(
         &mut self,
         user_input: input::ClientUserInput,
-    ) -> Result<(), ChatClientError> {
+    ) -> Result<(), ChatError> {
+        // A room's own limit is strict: messages to it can't be sent in parts
+        if let input::ClientUserInput::Message(text) = &user_input
+            && let Some(room) = &self.current_room
+            && let Some(max_length) = self.room_limits.get(room)
+            && text.len() > *max_length
+        {
+            logger::log_error(&format!(
+                "Message is {} bytes - #{} takes at most {}",
+                text.len(),
+                room,
+                max_length
+            ));
+            return Ok(());
+        }
+        // Refuse what the server would, offering to send it in parts instead
+        let max_message = self.max_message.load(Ordering::Relaxed);
+        if let Some(text) = user_input.text()
+            && max_message > 0
+            && text.len() > max_message
+        {
+            match parts::split(text, max_message, &part_id()) {
+                None => {
+                    logger::log_error(&format!(
+                        "Message is {} bytes - too long to send even in {} parts",
+                        text.len(),
+                        parts::MAX_PARTS
+                    ));
+                    return Ok(());
+                }
+                Some(parts) if !self.auto_split => {
+                    logger::log_error(&format!(
+                        "Message is {} bytes - the server allows at most {}. Type /split to send it as {} parts",
+                        text.len(),
+                        max_message,
+                        parts.len()
+                    ));
+                    self.pending_split = Some(user_input);
+                    return Ok(());
+                }
+                Some(_) => {}
+            }
+        }
         match user_input {
+            input::ClientUserInput::Split => {
+                let Some(pending) = self.pending_split.take() else {
+                    logger::log_error(
+                        "No message to split - only messages that were too long can be",
+                    );
+                    return Ok(());
+                };
+                let auto_split = std::mem::replace(&mut self.auto_split, true);
+                let result = Box::pin(self.handle_user_input(pending)).await;
+                self.auto_split = auto_split;
+                result
+            }
             input::ClientUserInput::Message(msg) => {
                 if msg.trim().is_empty() {
                     return Ok(());
                 }
-                // Display locally immediately
-                let display_msg = format!("{}: {}", self.chat_name, msg);
-                logger::log_chat(&display_msg);
-
+                let to = match self.current_room.clone() {
+                    Some(room) => Destination::Room(room),
+                    None => Destination::Chat,
+                };
+                self.send_own(to, &msg).await
+            }
+            input::ClientUserInput::DirectMessage {
+                recipient,
+                message: msg,
+            } => {
+                if msg.trim().is_empty() {
+                    return Ok(());
+                }
+                self.send_own(Destination::Direct(recipient), &msg).await
+            }
+            input::ClientUserInput::Reply(msg) => {
+                if msg.trim().is_empty() {
+                    return Ok(());
+                }
+                if let Some(recipient) = self.last_dm_sender.clone() {
+                    self.send_own(Destination::Direct(recipient), &msg).await
+                } else {
+                    logger::log_error("No one to reply to. Use /dm <username> <message> first.");
+                    Ok(())
+                }
+            }
+            input::ClientUserInput::Help => {
+                for line in commands::help_text() {
+                    logger::log_info(&line);
+                }
+                Ok(())
+            }
+            input::ClientUserInput::ListUsers => {
+                self.list_users();
+                Ok(())
+            }
+            input::ClientUserInput::Rename(new_name) => {
+                let message =
+                    ChatMessage::try_new(MessageTypes::RenameRequest, Some(new_name.into_bytes()))?;
+                self.send_message_chunked(message).await?;
+                Ok(())
+            }
+            input::ClientUserInput::Register(password) => {
+                let message = ChatMessage::try_new(
+                    MessageTypes::Register,
+                    Some(password.clone().into_bytes()),
+                )?;
+                self.send_message_chunked(message).await?;
+                self.pending_password = Some(password);
+                Ok(())
+            }
+            input::ClientUserInput::SendFile {
+                recipient,
+                file_path,
+            } => self.send_file_request(&recipient, &file_path).await,
+            input::ClientUserInput::AcceptFile { sender } => {
+                self.accept_file_transfer(&sender).await
+            }
+            input::ClientUserInput::RejectFile { sender } => {
+                self.reject_file_transfer(&sender).await
+            }
+            input::ClientUserInput::Status(status) => {
+                // Store status locally so we can restore it after reconnection
+                self.current_status = status.clone();
+                let content = status.map(|s| s.into_bytes());
+                let message = ChatMessage::try_new(MessageTypes::SetStatus, content)?;
+                self.send_message_chunked(message).await?;
+                Ok(())
+            }
+            input::ClientUserInput::JoinRoom(room) => {
+                let room = room.trim_start_matches('#').to_lowercase();
+                // Already in it - just make it the current room
+                if self.joined_rooms.contains(&room) {
+                    match self.unread.remove(&room) {
+                        Some(count) => logger::log_success(&format!(
+                            "Switched to #{} ({} unread)",
+                            room, count
+                        )),
+                        None => logger::log_success(&format!("Switched to #{}", room)),
+                    }
+                    self.current_room = Some(room);
+                    return Ok(());
+                }
+                let message = self.join_room_message(&room)?;
+                self.send_message_chunked(message).await?;
+                Ok(())
+            }
+            input::ClientUserInput::PartRoom(room) => {
+                let room = match room {
+                    Some(room) => room.trim_start_matches('#').to_lowercase(),
+                    None => match &self.current_room {
+                        Some(room) => room.clone(),
+                        None => {
+                            logger::log_error("You are not in a room. Use /part <room>.");
+                            return Ok(());
+                        }
+                    },
+                };
+                let message =
+                    ChatMessage::try_new(MessageTypes::LeaveRoom, Some(room.into_bytes()))?;
+                self.send_message_chunked(message).await?;
+                Ok(())
+            }
+            input::ClientUserInput::Block(user) => {
+                let content = match user {
+                    Some(user) => format!("block|{}", user),
+                    None => "list|".to_string(),
+                };
+                let message =
+                    ChatMessage::try_new(MessageTypes::BlockUser, Some(content.into_bytes()))?;
+                self.send_message_chunked(message).await?;
+                Ok(())
+            }
+            input::ClientUserInput::Unblock(user) => {
+                let content = format!("unblock|{}", user);
+                let message =
+                    ChatMessage::try_new(MessageTypes::BlockUser, Some(content.into_bytes()))?;
+                self.send_message_chunked(message).await?;
+                Ok(())
+            }
+            input::ClientUserInput::RoomSlowmode(seconds) => {
+                let Some(room) = &self.current_room else {
+                    logger::log_error("You are not in a room. Use /join <room> first.");
+                    return Ok(());
+                };
+                let content = format!("{}|slowmode|{}", room, seconds);
+                let message =
+                    ChatMessage::try_new(MessageTypes::RoomCommand, Some(content.into_bytes()))?;
+                self.send_message_chunked(message).await?;
+                Ok(())
+            }
+            input::ClientUserInput::RoomRetention(policy) => {
+                let Some(room) = &self.current_room else {
+                    logger::log_error("You are not in a room. Use /join <room> first.");
+                    return Ok(());
+                };
+                let content = format!("{}|retention|{}", room, policy);
+                let message =
+                    ChatMessage::try_new(MessageTypes::RoomCommand, Some(content.into_bytes()))?;
+                self.send_message_chunked(message).await?;
+                Ok(())
+            }
+            input::ClientUserInput::RoomTranslate(lang) => {
+                let Some(room) = &self.current_room else {
+                    logger::log_error("You are not in a room. Use /join <room> first.");
+                    return Ok(());
+                };
+                let content = format!("{}|translate|{}", room, lang);
+                let message =
+                    ChatMessage::try_new(MessageTypes::RoomCommand, Some(content.into_bytes()))?;
+                self.send_message_chunked(message).await?;
+                Ok(())
+            }
+            input::ClientUserInput::RoomMaxLength(limit) => {
+                let Some(room) = &self.current_room else {
+                    logger::log_error("You are not in a room. Use /join <room> first.");
+                    return Ok(());
+                };
+                let content = format!("{}|maxlength|{}", room, limit);
+                let message =
+                    ChatMessage::try_new(MessageTypes::RoomCommand, Some(content.into_bytes()))?;
+                self.send_message_chunked(message).await?;
+                Ok(())
+            }
+            input::ClientUserInput::RoomAnnounce(text) => {
+                let Some(room) = &self.current_room else {
+                    logger::log_error("You are not in a room. Use /join <room> first.");
+                    return Ok(());
+                };
+                let content = format!("{}|announce|{}", room, text);
+                let message =
+                    ChatMessage::try_new(MessageTypes::RoomCommand, Some(content.into_bytes()))?;
+                self.send_message_chunked(message).await?;
+                Ok(())
+            }
+            input::ClientUserInput::RoomArchive { room, archive } => {
+                let room = match room {
+                    Some(room) => room.trim_start_matches('#').to_lowercase(),
+                    None => match &self.current_room {
+                        Some(room) => room.clone(),
+                        None => {
+                            logger::log_error("You are not in a room. Use /room archive <room>.");
+                            return Ok(());
+                        }
+                    },
+                };
+                let command = if archive { "archive" } else { "unarchive" };
+                let content = format!("{}|{}|", room, command);
+                let message =
+                    ChatMessage::try_new(MessageTypes::RoomCommand, Some(content.into_bytes()))?;
+                self.send_message_chunked(message).await?;
+                Ok(())
+            }
+            input::ClientUserInput::RoomInfo(room) => {
+                let Some(room) = room.or_else(|| self.current_room.clone()) else {
+                    logger::log_error("You are not in a room. Use /room info <room>.");
+                    return Ok(());
+                };
+                let message =
+                    ChatMessage::try_new(MessageTypes::RoomInfoRequest, Some(room.into_bytes()))?;
+                self.send_message_chunked(message).await?;
+                Ok(())
+            }
+            input::ClientUserInput::RoomTopic(topic) => {
+                let Some(room) = &self.current_room else {
+                    logger::log_error("You are not in a room. Use /join <room> first.");
+                    return Ok(());
+                };
+                let content = format!("{}|topic|{}", room, topic.unwrap_or_default());
+                let message =
+                    ChatMessage::try_new(MessageTypes::RoomCommand, Some(content.into_bytes()))?;
+                self.send_message_chunked(message).await?;
+                Ok(())
+            }
+            input::ClientUserInput::RoomPrivate(private) => {
+                let Some(room) = &self.current_room else {
+                    logger::log_error("You are not in a room. Use /join <room> first.");
+                    return Ok(());
+                };
+                if private && self.signing_key.is_none() {
+                    logger::log_error(
+                        "Private rooms are encrypted with your signing key - set CHAT_SIGN_MESSAGES=1 first",
+                    );
+                    return Ok(());
+                }
+                let content = format!("{}|private|{}", room, if private { "on" } else { "off" });
                 let message =
-                    ChatMessage::try_new(MessageTypes::ChatMessage, Some(msg.into_bytes()))?;
+                    ChatMessage::try_new(MessageTypes::RoomCommand, Some(content.into_bytes()))?;
                 self.send_message_chunked(message).await?;
                 Ok(())
             }
-            input::ClientUserInput::DirectMessage {
-                recipient,
-                message: msg,
-            } => {
-                if msg.trim().is_empty() {
+            input::ClientUserInput::RoomInvite(user) => {
+                let Some(room) = &self.current_room else {
+                    logger::log_error("You are not in a room. Use /join <room> first.");
                     return Ok(());
-                }
-                // Display DM locally immediately
-                logger::log_info(&format!("[DM to {}]: {}", recipient, msg));
-
-                let dm_content = format!("{}|{}", recipient, msg);
-                let message = ChatMessage::try_new(
-                    MessageTypes::DirectMessage,
-                    Some(dm_content.into_bytes()),
-                )?;
+                };
+                let content = format!("{}|invite|{}", room, user);
+                let message =
+                    ChatMessage::try_new(MessageTypes::RoomCommand, Some(content.into_bytes()))?;
                 self.send_message_chunked(message).await?;
                 Ok(())
             }
-            input::ClientUserInput::Reply(msg) => {
-                if msg.trim().is_empty() {
+            input::ClientUserInput::ExportRoom { room, path } => {
+                let room = room.trim_start_matches('#').to_lowercase();
+                let content = history::encode_request(&room, 0);
+                let message =
+                    ChatMessage::try_new(MessageTypes::HistoryRequest, Some(content.into_bytes()))?;
+                self.send_message_chunked(message).await?;
+                logger::log_info(&format!("Requesting the history of #{}...", room));
+                self.pending_exports.insert(room, PathBuf::from(path));
+                Ok(())
+            }
+            input::ClientUserInput::ListRooms { verbose, archived } => {
+                self.verbose_room_list = verbose;
+                self.archived_room_list = archived;
+                let message = ChatMessage::try_new(MessageTypes::RoomInfoRequest, None)?;
+                self.send_message_chunked(message).await?;
+                Ok(())
+            }
+            input::ClientUserInput::Last { user, count } => {
+                let messages = self.scrollback.last_from(&user, count);
+                if messages.is_empty() {
+                    logger::log_info(&format!("No recent messages from {} in scrollback", user));
                     return Ok(());
                 }
-                if let Some(recipient) = &self.last_dm_sender {
-                    // Display reply locally immediately
-                    logger::log_info(&format!("[DM to {}]: {}", recipient, msg));
-
-                    let dm_content = format!("{}|{}", recipient, msg);
-                    let message = ChatMessage::try_new(
-                        MessageTypes::DirectMessage,
-                        Some(dm_content.into_bytes()),
-                    )?;
-                    self.send_message_chunked(message).await?;
-                    Ok(())
-                } else {
-                    logger::log_error("No one to reply to. Use /dm <username> <message> first.");
-                    Ok(())
-                }
-            }
-            input::ClientUserInput::Help => {
-                for line in commands::help_text() {
-                    logger::log_info(&line);
+                logger::log_info(&format!(
+                    "Last {} message(s) from {}:",
+                    messages.len(),
+                    user
+                ));
+                for (timestamp, text) in messages {
+                    logger::log_scrollback(&timestamp, &text);
                 }
                 Ok(())
             }
-            input::ClientUserInput::ListUsers => {
-                let message = ChatMessage::try_new(MessageTypes::ListUsers, None)?;
+            input::ClientUserInput::Seen(user) => {
+                let message = ChatMessage::try_new(MessageTypes::Seen, Some(user.into_bytes()))?;
                 self.send_message_chunked(message).await?;
                 Ok(())
             }
-            input::ClientUserInput::Rename(new_name) => {
-                let message =
-                    ChatMessage::try_new(MessageTypes::RenameRequest, Some(new_name.into_bytes()))?;
+            input::ClientUserInput::ServerInfo => {
+                self.show_server_info = true;
+                let message = ChatMessage::try_new(MessageTypes::ServerInfo, None)?;
                 self.send_message_chunked(message).await?;
                 Ok(())
             }
-            input::ClientUserInput::SendFile {
-                recipient,
-                file_path,
-            } => self.send_file_request(&recipient, &file_path).await,
-            input::ClientUserInput::AcceptFile { sender } => {
-                self.accept_file_transfer(&sender).await
+            input::ClientUserInput::DebugTrace(enabled) => {
+                self.set_tracing(enabled);
+                Ok(())
             }
-            input::ClientUserInput::RejectFile { sender } => {
-                self.reject_file_transfer(&sender).await
+            input::ClientUserInput::Trust(fingerprint) => {
+                self.trust_certificate(fingerprint.as_deref());
+                Ok(())
             }
-            input::ClientUserInput::Status(status) => {
-                // Store status locally so we can restore it after reconnection
-                self.current_status = status.clone();
-                let content = status.map(|s| s.into_bytes());
-                let message = ChatMessage::try_new(MessageTypes::SetStatus, content)?;
-                self.send_message_chunked(message).await?;
+            input::ClientUserInput::Doctor => {
+                run_doctor();
+                Ok(())
+            }
+            input::ClientUserInput::HighlightList => {
+                self.list_highlights();
+                Ok(())
+            }
+            input::ClientUserInput::HighlightAdd(spec) => {
+                match self.highlights.add(&spec) {
+                    Ok(true) => logger::log_success(&format!("Highlighting {}", spec)),
+                    Ok(false) => logger::log_info(&format!("Already highlighting {}", spec)),
+                    Err(e) => logger::log_error(&format!("Can't highlight {}: {}", spec, e)),
+                }
+                Ok(())
+            }
+            input::ClientUserInput::HighlightRemove(spec) => {
+                match self.highlights.remove(&spec) {
+                    Ok(true) => logger::log_success(&format!("No longer highlighting {}", spec)),
+                    Ok(false) => logger::log_error(&format!("Not highlighting {}", spec)),
+                    Err(e) => logger::log_error(&format!("Couldn't save highlights: {}", e)),
+                }
+                Ok(())
+            }
+            input::ClientUserInput::FilterList => {
+                self.list_filters();
+                Ok(())
+            }
+            input::ClientUserInput::FilterAdd(spec) => {
+                match Filter::parse(&spec).map(|filter| {
+                    let added = self.filters.add(filter.clone());
+                    (filter, added)
+                }) {
+                    Ok((filter, Ok(true))) => {
+                        logger::log_success(&format!("Filter added: {}", filter))
+                    }
+                    Ok((filter, Ok(false))) => {
+                        logger::log_info(&format!("Already filtering: {}", filter))
+                    }
+                    Ok((_, Err(e))) => logger::log_error(&format!("Couldn't save filters: {}", e)),
+                    Err(e) => logger::log_error(&format!("Can't filter: {}", e)),
+                }
+                Ok(())
+            }
+            input::ClientUserInput::FilterRemove(spec) => {
+                match Filter::parse(&spec).map(|filter| self.filters.remove(&filter)) {
+                    Ok(Ok(true)) => logger::log_success(&format!("Filter removed: {}", spec)),
+                    Ok(Ok(false)) => logger::log_error(&format!("No filter '{}'", spec)),
+                    Ok(Err(e)) => logger::log_error(&format!("Couldn't save filters: {}", e)),
+                    Err(e) => logger::log_error(&format!("Can't filter: {}", e)),
+                }
+                Ok(())
+            }
+            input::ClientUserInput::FilterClear => {
+                match self.filters.clear() {
+                    Ok(()) => logger::log_success("Filters cleared - everything is shown"),
+                    Err(e) => logger::log_error(&format!("Couldn't save filters: {}", e)),
+                }
+                Ok(())
+            }
+            input::ClientUserInput::QuietJoins => {
+                if self.quiet_joins.toggle() {
+                    logger::log_success(
+                        "Joins, leaves and renames are now hidden and counted - /show to see them",
+                    );
+                } else {
+                    logger::log_success("Joins, leaves and renames are shown again");
+                    self.show_hidden_presence();
+                }
+                Ok(())
+            }
+            input::ClientUserInput::ShowHidden => {
+                self.show_hidden_presence();
                 Ok(())
             }
             input::ClientUserInput::Quit => {
-                // Send Leave message to server so it knows this is an explicit quit
-                // (as opposed to a connection drop that might be a reconnection)
-                let message = ChatMessage::try_new(MessageTypes::Leave, None)?;
-                let _ = self.send_message_chunked(message).await;
+                self.leave().await;
                 Ok(())
             }
         }
     }
 
+    fn update_server_info(&mut self, info: ServerInfo) {
+        if std::mem::take(&mut self.show_server_info) {
+            logger::log_info(&format!("Server: {}", info.name));
+            if !info.network.is_empty() {
+                logger::log_info(&format!("  Network: {}", info.network));
+            }
+            if !info.description.is_empty() {
+                logger::log_info(&format!("  Description: {}", info.description));
+            }
+            logger::log_info(&format!("  Version: {}", info.version));
+            logger::log_info(&format!(
+                "  Address: {} ({})",
+                startup::display_addr(&self.server_host, self.server_port),
+                if self.use_tls { "TLS" } else { "plain TCP" }
+            ));
+        } else if self.server_info.as_ref() != Some(&info) {
+            // Greet on the first join, and after reconnecting somewhere different
+            logger::log_success(&format!("Welcome to {}", info.display_name()));
+            if !info.description.is_empty() {
+                logger::log_info(&info.description);
+            }
+        }
+        self.server_info = Some(info);
+    }
+
+    fn set_tracing(&mut self, enabled: bool) {
+        if !enabled {
+            if self.tracer.take().is_some() {
+                logger::log_info("Protocol tracing stopped");
+            } else {
+                logger::log_info("Protocol tracing is not on");
+            }
+            return;
+        }
+        if self.tracer.is_some() {
+            logger::log_info("Protocol tracing is already on");
+            return;
+        }
+        let Some(path) = &self.trace_path else {
+            logger::log_error("No state directory for the trace file - set CHAT_STATE_DIR");
+            return;
+        };
+        match Tracer::open(path) {
+            Ok(tracer) => {
+                let tracer =
+                    tracer.labelled(startup::display_addr(&self.server_host, self.server_port));
+                tracer.event("tracing started");
+                self.tracer = Some(tracer);
+                logger::log_success(&format!(
+                    "Tracing every frame to {} (/debug trace off to stop)",
+                    path.display()
+                ));
+            }
+            Err(e) => logger::log_error(&format!("Failed to open trace file: {}", e)),
+        }
+    }
+
+    /// Whether someone else's message mentions us or one of our highlight words
+    fn is_highlighted(&self, text: &str) -> bool {
+        notify::mentions(text, &self.chat_name) || self.highlights.matches(text)
+    }
+
+    /// Who is online, from the roster the server keeps us in sync with
+    fn list_users(&self) {
+        logger::log_info(&format!("Current users online ({}):", self.roster.len()));
+        for (user, status) in self.roster.users() {
+            logger::log_info(&format!(" - {}", presence::snapshot_line(user, status)));
+        }
+        self.emit(Event::Presence {
+            users: self.roster.users().collect(),
+        });
+    }
+
+    /// Offer exactly the users online for tab completion
+    fn sync_completion(&self) {
+        if let Ok(mut users) = self.connected_users.write() {
+            users.clear();
+            users.extend(self.roster.users().map(|(user, _)| user.to_string()));
+        }
+    }
+
+    fn list_highlights(&self) {
+        let specs: Vec<&str> = self.highlights.list().collect();
+        if specs.is_empty() {
+            logger::log_info("No highlight words - /highlight add <word|/regex/> to add one");
+        } else {
+            logger::log_info(&format!("Highlight words: {}", specs.join(", ")));
+        }
+    }
+
+    /// A join, leave or rename notice, unless /quiet joins holds it back
+    fn show_presence(&mut self, notice: &str) {
+        if !self.quiet_joins.hold(Local::now(), notice) {
+            logger::log_system(notice);
+        }
+    }
+
+    /// The joins, leaves and renames /quiet joins held back
+    fn show_hidden_presence(&mut self) {
+        let hidden = self.quiet_joins.expand();
+        if hidden.is_empty() {
+            logger::log_info("No joins/leaves hidden");
+            return;
+        }
+        for (at, notice) in hidden {
+            logger::log_system(&format!("{} {}", at.format("%H:%M:%S"), notice));
+        }
+    }
+
+    fn list_filters(&self) {
+        let filters: Vec<String> = self.filters.list().iter().map(Filter::to_string).collect();
+        if filters.is_empty() {
+            logger::log_info("No filters - /filter hide|only <rule> to add one");
+        } else {
+            logger::log_info(&format!("Filters: {}", filters.join(", ")));
+        }
+    }
+
+    /// Show the server's certificate fingerprint, or pin it for future connections.
+    /// Only the certificate we are connected with can be trusted.
+    fn trust_certificate(&self, fingerprint: Option<&str>) {
+        let Some(current) = self.connection.get_ref().certificate_fingerprint() else {
+            logger::log_error("Not connected with TLS - there is no certificate to trust");
+            return;
+        };
+        let pin_path = trust::pin_path(&self.server_host, self.server_port);
+        let pinned = pin_path.as_deref().and_then(trust::load_pin);
+        let Some(fingerprint) = fingerprint else {
+            logger::log_info(&format!("Certificate fingerprint: {}", current));
+            if pinned.as_deref() == Some(current.as_str()) {
+                logger::log_info("  Trusted with /trust");
+            } else {
+                logger::log_info(
+                    "  Not pinned - /trust <fingerprint> to accept only this certificate",
+                );
+            }
+            return;
+        };
+        if shared::tls::parse_fingerprint(fingerprint).as_deref() != Some(current.as_str()) {
+            logger::log_error("That isn't the fingerprint of the server's certificate");
+            return;
+        }
+        let Some(path) = pin_path else {
+            logger::log_error(
+                "No state directory to keep trusted certificates in - set CHAT_STATE_DIR",
+            );
+            return;
+        };
+        match trust::save_pin(&path, &current) {
+            Ok(()) => logger::log_success(&format!(
+                "Trusted certificate {} - from now on {} must present it",
+                current,
+                startup::display_addr(&self.server_host, self.server_port)
+            )),
+            Err(e) => logger::log_error(&format!("Could not save the trusted certificate: {}", e)),
+        }
+    }
+
     /// Send a file transfer request (not the actual file data)
     async fn send_file_request(
         &mut self,
         recipient: &str,
         file_path: &str,
-    ) -> Result<(), ChatClientError> {
+    ) -> Result<(), ChatError> {
         let path = Path::new(file_path);
 
         // Check if file exists
@@ -941,11 +3023,7 @@ impl ChatClient {
     }
 
     /// Actually send the file data (called after recipient accepts)
-    async fn send_file_data(
-        &mut self,
-        recipient: &str,
-        file_path: &str,
-    ) -> Result<(), ChatClientError> {
+    async fn send_file_data(&mut self, recipient: &str, file_path: &str) -> Result<(), ChatError> {
         let path = Path::new(file_path);
 
         // Check if file still exists
@@ -993,7 +3071,7 @@ impl ChatClient {
     }
 
     /// Accept a pending file transfer
-    async fn accept_file_transfer(&mut self, sender: &str) -> Result<(), ChatClientError> {
+    async fn accept_file_transfer(&mut self, sender: &str) -> Result<(), ChatError> {
         // Check if there's a pending transfer from this sender
         if let Some(transfer) = self.pending_incoming.remove(sender) {
             logger::log_info(&format!(
@@ -1018,7 +3096,7 @@ impl ChatClient {
     }
 
     /// Reject a pending file transfer
-    async fn reject_file_transfer(&mut self, sender: &str) -> Result<(), ChatClientError> {
+    async fn reject_file_transfer(&mut self, sender: &str) -> Result<(), ChatError> {
         // Check if there's a pending transfer from this sender
         if let Some(transfer) = self.pending_incoming.remove(sender) {
             logger::log_info(&format!(
@@ -1043,15 +3121,30 @@ impl ChatClient {
     }
 
     pub async fn run(&mut self) -> io::Result<()> {
+        if self.show_status_bar {
+            self.status_bar = StatusBar::start();
+        }
+        if self.show_title {
+            self.title = TerminalTitle::start(&self.server_host, self.multiplexer);
+        }
         // Spawn readline handler in a blocking thread with username as prompt
         let mut readline_rx = readline_helper::spawn_readline_handler(
             self.connected_users.clone(),
+            self.max_message.clone(),
+            self.drafts.clone(),
             self.chat_name.clone(),
+            self.json_events,
         );
 
+        let mut member_refresh = tokio::time::interval(MEMBER_REFRESH);
         loop {
+            let next_flush = self.repeats.next_flush();
             tokio::select! {
-                result = self.read_message_chunked() => {
+                result = self.incoming() => {
+                    let result = match result {
+                        Ok(()) => self.read_next().await,
+                        Err(e) => Err(e),
+                    };
                     match result {
                         Ok(message) => {
                             if !self.handle_message(message).await {
@@ -1059,13 +3152,11 @@ impl ChatClient {
                                 logger::log_warning("Connection issue detected while handling message");
                             }
                         }
-                        Err(shared::network::TcpMessageHandlerError::IoError(_)) |
-                        Err(shared::network::TcpMessageHandlerError::Disconnect) => {
+                        Err(_) => {
                             logger::log_warning("Disconnected from server");
 
-                            // Don't reconnect if we were kicked
-                            if self.was_kicked {
-                                logger::log_info("Not reconnecting - you were kicked from the server");
+                            // Don't reconnect if we were kicked (unless told when to come back)
+                            if !self.may_reconnect().await {
                                 return Ok(());
                             }
 
@@ -1085,34 +3176,27 @@ impl ChatClient {
                 Some(line) = readline_rx.recv() => {
                     match line {
                         Some(input_line) => {
-                            match ClientUserInput::try_from(input_line.as_str()) {
-                                Ok(input::ClientUserInput::Quit) => return Ok(()),
-                                Ok(input::ClientUserInput::ListUsers) => {
-                                    let message = ChatMessage::try_new(MessageTypes::ListUsers, None)
-                                        .map_err(|e| io::Error::other(format!("Failed to create ListUsers message: {e:?}")))?;
-                                    if let Err(e) = self.send_message_chunked(message).await {
-                                        logger::log_warning("Connection lost while sending message");
-
-                                        if !self.was_kicked {
-                                            match self.reconnect().await {
-                                                Ok(()) => {
-                                                    // Connection restored
-                                                }
-                                                Err(reconnect_err) => {
-                                                    logger::log_error(&format!("Failed to reconnect: {:?}", reconnect_err));
-                                                    return Err(io::Error::other(format!("Failed to send ListUsers message: {e:?}")));
-                                                }
-                                            }
-                                        }
-                                    }
+                            let input = ClientUserInput::try_from(input_line.as_str());
+                            // Typing anything means the DMs so far have been seen
+                            self.unread_dms = 0;
+                            self.status_bar.update(|status| {
+                                status.unread_dms = 0;
+                                if input.as_ref().is_ok_and(|input| !input.is_local()) {
+                                    status.record_send(Instant::now());
+                                }
+                            });
+                            match input {
+                                Ok(input::ClientUserInput::Quit) => {
+                                    self.leave().await;
+                                    return Ok(());
                                 }
                                 Ok(user_input) => {
-                                    if let Err(e) = self.handle_user_input(user_input).await {
+                                    if let Err(e) = self.handle_input_line(&input_line, user_input).await {
                                         // Check if this is a connection error that needs reconnection
-                                        if matches!(e, ChatClientError::IoError) {
+                                        if matches!(e, ChatError::IoError(_) | ChatError::Disconnect) {
                                             logger::log_warning("Connection lost while sending message");
 
-                                            if !self.was_kicked {
+                                            if self.may_reconnect().await {
                                                 match self.reconnect().await {
                                                     Ok(()) => {
                                                         // Connection restored
@@ -1139,14 +3223,377 @@ impl ChatClient {
                         }
                     }
                 }
+                _ = sleep_until_flush(next_flush) => {
+                    self.flush_repeats();
+                }
+                _ = member_refresh.tick(), if self.in_chat && !self.joined_rooms.is_empty() => {
+                    self.refresh_room_members().await;
+                }
+            }
+
+            // Commands and server messages can both change the current room
+            let room = self.current_room.clone();
+            let encrypted = room
+                .as_ref()
+                .is_some_and(|room| self.room_keys.is_private(room));
+            if let Ok(mut drafts) = self.drafts.lock() {
+                drafts.sync(room.as_deref(), self.joined_rooms.iter().cloned());
+            }
+            let members = room
+                .as_ref()
+                .and_then(|room| self.room_members.get(room).copied());
+            let unread = self.unread.values().sum::<usize>() + self.unread_dms;
+            self.title.update(room.as_deref(), unread);
+            self.status_bar.update(|status| {
+                status.room = room;
+                status.encrypted = encrypted;
+                status.members = members;
+            });
+        }
+    }
+
+    /// Keep what we hold about `from` for them under their new nickname: `from:`
+    /// filters, who said what in the scrollback, who /reply answers and file transfers
+    fn follow_rename(&mut self, from: &str, to: &str) {
+        if let Err(e) = self.filters.rename(from, to) {
+            logger::log_warning(&format!("Failed to save filters: {}", e));
+        }
+        self.scrollback.rename(from, to);
+        if self.last_dm_sender.as_deref() == Some(from) {
+            self.last_dm_sender = Some(to.to_string());
+        }
+        if let Some(mut transfer) = self.pending_outgoing.remove(from) {
+            transfer.recipient = to.to_string();
+            self.pending_outgoing.insert(to.to_string(), transfer);
+        }
+        if let Some(mut transfer) = self.pending_incoming.remove(from) {
+            transfer.sender = to.to_string();
+            self.pending_incoming.insert(to.to_string(), transfer);
+        }
+    }
+
+    /// Take on a nickname the server gave us
+    fn set_chat_name(&mut self, name: String) {
+        // Completion offers the name we're really known by
+        if let Ok(mut users) = self.connected_users.write() {
+            users.remove(&self.chat_name);
+            users.insert(name.clone());
+        }
+        self.chat_name = name;
+    }
+
+    /// The server accepted our join: set up everything it told us about, then enter
+    /// the chat
+    async fn accept_join(&mut self, ack: JoinAck) {
+        if ack.nickname != self.chat_name {
+            // The nickname we asked for was taken
+            logger::log_warning(&format!(
+                "You joined as '{}' instead of '{}'",
+                ack.nickname, self.chat_name
+            ));
+            self.set_chat_name(ack.nickname);
+        }
+        self.max_message
+            .store(ack.limits.max_message.unwrap_or(0), Ordering::Relaxed);
+        self.status_bar.update(|status| {
+            status.state = ConnectionState::Connected;
+            status.rate_limits = Some(ack.limits);
+        });
+        self.update_server_info(ack.server);
+        if let Some(room) = ack.room {
+            self.current_room = Some(room);
+        }
+        self.entered_chat().await;
+    }
+
+    /// The server has let us into the chat and finished greeting us, so what we
+    /// send won't cross its messages
+    async fn entered_chat(&mut self) {
+        self.in_chat = true;
+        // Asked again on every connection: the setting belongs to the connection
+        if self.self_echo
+            && let Ok(echo_msg) = ChatMessage::try_new(MessageTypes::SelfEcho, Some(b"1".to_vec()))
+        {
+            let _ = self.send_message_chunked(echo_msg).await;
+        }
+        // Messages sent from now on go to the room; the server handles the join
+        // before them
+        if let Some(room) = self.pending_room.take()
+            && let Ok(join_msg) = self.join_room_message(&room)
+            && self.send_message_chunked(join_msg).await.is_ok()
+        {
+            self.current_room = Some(room);
+        }
+        self.run_on_connect().await;
+    }
+
+    /// Run the on_connect commands, as if typed, so a reconnect restores the rooms,
+    /// status and anything else they set up
+    async fn run_on_connect(&mut self) {
+        for line in self.on_connect.clone() {
+            let Ok(input) = ClientUserInput::try_from(line.as_str()) else {
+                continue;
+            };
+            // Each waits for the server's replies to the one before, or they'd cross
+            if let Err(e) = self.settle().await {
+                logger::log_error(&format!("on_connect commands stopped: {:?}", e));
+                return;
+            }
+            if let Err(e) = Box::pin(self.handle_input_line(&line, input)).await {
+                logger::log_error(&format!("on_connect command failed: {:?}", e));
+            }
+        }
+    }
+
+    /// Join `room` (--room) as soon as the server lets us into the chat
+    pub fn join_room_on_entry(&mut self, room: String) {
+        self.pending_room = Some(room);
+    }
+
+    /// Non-interactive mode (--pipe): each line of stdin is handled like typed input,
+    /// so plain lines are sent as messages and commands such as /dm work too.
+    /// Returns at the end of stdin.
+    pub async fn run_pipe(&mut self) -> io::Result<()> {
+        // A line is only sent once nothing has arrived for a moment (see SETTLE)
+        let mut lines = BufReader::new(tokio::io::stdin()).lines();
+        let mut last_traffic = Instant::now();
+        let mut stdin_done = false;
+
+        loop {
+            // Lines also wait until we're in the chat, or they'd be refused
+            let settled = self.in_chat && last_traffic.elapsed() >= SETTLE;
+            if settled && stdin_done {
+                break;
+            }
+            let next_flush = self.repeats.next_flush();
+            tokio::select! {
+                result = self.incoming() => {
+                    last_traffic = Instant::now();
+                    let result = match result {
+                        Ok(()) => self.read_next().await,
+                        Err(e) => Err(e),
+                    };
+                    match result {
+                        Ok(message) => {
+                            self.handle_message(message).await;
+                        }
+                        Err(_) => {
+                            logger::log_warning("Disconnected from server");
+                            if !self.may_reconnect().await {
+                                return Ok(());
+                            }
+                            if let Err(e) = self.reconnect().await {
+                                logger::log_error(&format!("Failed to reconnect: {:?}", e));
+                                return Err(io::Error::other("Reconnection failed"));
+                            }
+                        }
+                    }
+                }
+                line = lines.next_line(), if settled && !stdin_done => {
+                    let Some(line) = line? else {
+                        stdin_done = true;
+                        continue;
+                    };
+                    let input = ClientUserInput::try_from(line.as_str());
+                    last_traffic = Instant::now();
+                    match input {
+                        Ok(ClientUserInput::Quit) => break,
+                        Ok(input) => {
+                            if let Err(e) = self.handle_input_line(&line, input).await {
+                                logger::log_error(&format!("Error: {e:?}"));
+                                if matches!(e, ChatError::IoError(_) | ChatError::Disconnect) && self.may_reconnect().await {
+                                    self.reconnect()
+                                        .await
+                                        .map_err(|_| io::Error::other("Reconnection failed"))?;
+                                }
+                            }
+                        }
+                        Err(e) => logger::log_error(&format!("Input error: {e:?}")),
+                    }
+                }
+                _ = tokio::time::sleep_until((last_traffic + SETTLE).into()), if self.in_chat && !settled => {}
+                _ = sleep_until_flush(next_flush) => {
+                    self.flush_repeats();
+                }
+            }
+        }
+
+        self.leave().await;
+        Ok(())
+    }
+
+    /// Tell the server we're leaving on purpose (as opposed to a connection drop that
+    /// might be a reconnection), so it doesn't hold our session, then close the
+    /// connection. Only the first call does anything.
+    pub async fn leave(&mut self) {
+        if std::mem::replace(&mut self.left, true) {
+            return;
+        }
+        if let Ok(message) = ChatMessage::try_new(MessageTypes::Leave, None) {
+            let _ = tokio::time::timeout(LEAVE_TIMEOUT, self.send_message_chunked(message)).await;
+        }
+        let _ = self.connection.shutdown().await;
+    }
+
+    /// Handle whatever the server sends until it goes quiet, so the next message
+    /// doesn't cross one on its way to us
+    async fn settle(&mut self) -> Result<(), ChatError> {
+        while let Ok(result) = tokio::time::timeout(SETTLE, self.incoming()).await {
+            result?;
+            let message = self.read_next().await?;
+            Box::pin(self.handle_message(message)).await;
+        }
+        Ok(())
+    }
+
+    /// Wait until the server starts sending something, counting a server that has
+    /// gone quiet for longer than the read timeout as disconnected. Unlike reading a
+    /// message, this is safe to give up on in a select!: nothing is taken off the
+    /// connection until read_next.
+    async fn incoming(&mut self) -> Result<(), ChatError> {
+        let waiting = match self.timeouts.read {
+            Some(limit) => {
+                let deadline = (self.last_received + limit).into();
+                match tokio::time::timeout_at(deadline, self.connection.fill_buf()).await {
+                    Ok(waiting) => waiting,
+                    Err(_) => {
+                        logger::log_warning(&format!(
+                            "Nothing received from the server for {}s",
+                            limit.as_secs()
+                        ));
+                        return Err(ChatError::Disconnect);
+                    }
+                }
             }
+            None => self.connection.fill_buf().await,
+        };
+        match waiting {
+            Ok([]) => Err(ChatError::Disconnect),
+            Ok(_) => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Read the next message once incoming has seen its first bytes
+    async fn read_next(&mut self) -> Result<ChatMessage, ChatError> {
+        let result = self.read_message_chunked().await;
+        if result.is_ok() {
+            self.last_received = Instant::now();
         }
+        result
+    }
+
+    /// Give the status line's row and the title back to the terminal before exiting
+    pub fn stop_status_bar(&self) {
+        self.status_bar.stop();
+        self.title.stop();
+    }
+
+    /// Tell the notify command about a mention or direct message, and flag our window
+    fn notify(&mut self, kind: NotificationKind, sender: &str, room: &str, text: &str) {
+        self.notifier.notify(kind, sender, room, text);
+        self.title.alert();
     }
 }
 
 impl TcpMessageHandler for ChatClient {
-    type Stream = ClientStream;
+    type Stream = BufStream<ClientStream>;
     fn get_stream(&mut self) -> &mut Self::Stream {
         &mut self.connection
     }
+
+    fn tracer(&self) -> Option<&Tracer> {
+        self.tracer.as_ref()
+    }
+
+    fn write_timeout(&self) -> Option<Duration> {
+        self.timeouts.write
+    }
+
+    fn on_send(&mut self, msg_type: MessageTypes) {
+        self.sent.record(msg_type);
+    }
+}
+
+/// One room in the /rooms listing: name, member count and topic
+/// Take the sender's class off a relayed message (see shared::sender_class). Notice
+/// frames without one are notices, and anything else a user's.
+fn take_class(message: ChatMessage) -> (SenderClass, ChatMessage) {
+    let default = match message.msg_type {
+        MessageTypes::Notice => SenderClass::Notice,
+        _ => SenderClass::User,
+    };
+    if !matches!(
+        message.msg_type,
+        MessageTypes::ChatMessage
+            | MessageTypes::RoomMessage
+            | MessageTypes::DirectMessage
+            | MessageTypes::Announcement
+            | MessageTypes::RoomAnnouncement
+            | MessageTypes::Notice
+    ) {
+        return (default, message);
+    }
+    let Some(content) = message.content_as_string() else {
+        return (default, message);
+    };
+    match sender_class::untag(&content) {
+        (Some(class), rest) => {
+            match ChatMessage::try_new(message.msg_type, Some(rest.as_bytes().to_vec())) {
+                Ok(untagged) => (class, untagged),
+                Err(_) => (class, message),
+            }
+        }
+        (None, _) => (default, message),
+    }
+}
+
+/// Sets bots' and the server's messages apart from people's
+fn class_badge(class: SenderClass) -> &'static str {
+    match class {
+        SenderClass::Bot => "[bot] ",
+        SenderClass::Server => "[server] ",
+        SenderClass::User | SenderClass::Notice => "",
+    }
+}
+
+fn room_line(room: &RoomSummary) -> String {
+    let mut line = format!(
+        "#{} [{} member{}{}{}]",
+        room.name,
+        room.members,
+        if room.members == 1 { "" } else { "s" },
+        if room.private { ", private" } else { "" },
+        if room.archived { ", archived" } else { "" }
+    );
+    if !room.topic.is_empty() {
+        line.push_str(&format!(" - {}", room.topic));
+    }
+    line
+}
+
+/// /doctor: check the state directory and say what was repaired
+fn run_doctor() {
+    let Some(dir) = paths::state_dir() else {
+        logger::log_error("No state directory (set HOME or CHAT_STATE_DIR)");
+        return;
+    };
+    match doctor::run(&dir) {
+        Ok(report) if report.repairs.is_empty() => logger::log_success(&format!(
+            "Checked {} file(s) in {} - all fine",
+            report.checked,
+            dir.display()
+        )),
+        Ok(report) => {
+            logger::log_warning(&format!(
+                "Checked {} file(s) in {}, repaired:",
+                report.checked,
+                dir.display()
+            ));
+            for repair in &report.repairs {
+                logger::log_warning(&format!("  - {}", repair));
+            }
+        }
+        Err(e) => logger::log_error(&format!("Couldn't check {}: {}", dir.display(), e)),
+    }
 }
@@ -1,3 +1,5 @@
+use crate::drafts::Drafts;
+use crate::length;
 use rustyline::completion::{Completer, Pair};
 use rustyline::highlight::Highlighter;
 use rustyline::hint::Hinter;
@@ -5,30 +7,45 @@ use rustyline::validate::Validator;
 use rustyline::{Context, Helper};
 use shared::commands::client as commands;
 use std::collections::HashSet;
-use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
 
-/// Client command and username completer
+/// Client command and username completer, which also hints how much of the server's
+/// message size a line is using
 pub struct ClientCompleter {
     commands: Vec<&'static str>,
     users: Arc<RwLock<HashSet<String>>>,
+    /// Longest message the server accepts in bytes (0 = not known yet)
+    max_message: Arc<AtomicUsize>,
+    /// Told what's on the line as it changes, to keep it as a draft
+    drafts: Arc<Mutex<Drafts>>,
 }
 
 impl ClientCompleter {
-    pub fn new(users: Arc<RwLock<HashSet<String>>>) -> Self {
+    pub fn new(
+        users: Arc<RwLock<HashSet<String>>>,
+        max_message: Arc<AtomicUsize>,
+        drafts: Arc<Mutex<Drafts>>,
+    ) -> Self {
         Self {
             commands: commands::completion_names(),
             users,
+            max_message,
+            drafts,
         }
     }
 
     fn get_candidates(&self, line: &str) -> Vec<String> {
         let trimmed = line.trim_start();
 
-        // If line starts with /dm or /send and has a space, complete usernames
-        if trimmed.starts_with("/dm ") || trimmed.starts_with("/send ") {
+        // If line starts with /dm, /send or /last and has a space, complete usernames
+        if trimmed.starts_with("/dm ")
+            || trimmed.starts_with("/send ")
+            || trimmed.starts_with("/last ")
+        {
             let parts: Vec<&str> = trimmed.splitn(3, ' ').collect();
             if parts.len() == 2 {
-                // Complete username after /dm or /send
+                // Complete username after /dm, /send or /last
                 let cmd = parts[0];
                 let prefix = parts[1];
                 let users = self.users.read().unwrap();
@@ -79,7 +96,11 @@ impl Completer for ClientCompleter {
 impl Hinter for ClientCompleter {
     type Hint = String;
 
-    fn hint(&self, line: &str, _pos: usize, _ctx: &Context<'_>) -> Option<String> {
+    fn hint(&self, line: &str, pos: usize, _ctx: &Context<'_>) -> Option<String> {
+        // Asked on every change to the line
+        if let Ok(mut drafts) = self.drafts.lock() {
+            drafts.typing(line);
+        }
         let candidates = self.get_candidates(line);
         if candidates.len() == 1 {
             let candidate = &candidates[0];
@@ -87,7 +108,14 @@ impl Hinter for ClientCompleter {
                 return Some(candidate[line.len()..].to_string());
             }
         }
-        None
+        // Length meter at the end of a message getting close to the limit
+        if pos < line.len() {
+            return None;
+        }
+        length::meter(
+            length::message_text(line)?,
+            self.max_message.load(Ordering::Relaxed),
+        )
     }
 }
 
@@ -0,0 +1,163 @@
+//! What became of a message we typed. Every frame is acknowledged with an OK, so a
+//! message is pending until the OK for its last part comes back, then confirmed -
+//! or failed if sending errored or the OK never came. Messages are shown once
+//! they're confirmed or failed; the status bar shows when any are pending.
+//!
+//! With self-echo on (CHAT_SELF_ECHO) a message is only confirmed when the server
+//! relays it back to us, so one the server refused (rate limited, say) is never shown
+//! as sent.
+
+use shared::message::MessageTypes;
+use shared::signing;
+use std::collections::VecDeque;
+
+/// Most messages waiting for their echo; older ones are given up on
+const MAX_AWAITING_ECHO: usize = 64;
+
+/// Where one of our messages is going
+#[derive(Debug, Clone, PartialEq)]
+pub enum Destination {
+    Chat,
+    Room(String),
+    Direct(String),
+}
+
+impl Destination {
+    pub fn message_type(&self) -> MessageTypes {
+        match self {
+            Destination::Chat => MessageTypes::ChatMessage,
+            Destination::Room(_) => MessageTypes::RoomMessage,
+            Destination::Direct(_) => MessageTypes::DirectMessage,
+        }
+    }
+
+    /// What goes before the text in the message: the room or recipient
+    pub fn prefix(&self) -> String {
+        match self {
+            Destination::Chat => String::new(),
+            Destination::Room(name) | Destination::Direct(name) => format!("{}|", name),
+        }
+    }
+
+    /// Scope a signature of the message covers
+    pub fn scope(&self) -> String {
+        match self {
+            Destination::Chat => signing::MAIN_CHAT_SCOPE.to_string(),
+            Destination::Room(room) => signing::room_scope(room),
+            Destination::Direct(recipient) => signing::dm_scope(recipient),
+        }
+    }
+}
+
+/// How a message ended up once sending finished. While it's pending the status
+/// bar says so; it's shown in the chat once it's one of these.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Delivery {
+    Confirmed,
+    Failed,
+}
+
+impl Delivery {
+    pub fn of<T, E>(result: &Result<T, E>) -> Self {
+        match result {
+            Ok(_) => Delivery::Confirmed,
+            Err(_) => Delivery::Failed,
+        }
+    }
+
+    /// Name used in JSON events
+    pub fn name(self) -> &'static str {
+        match self {
+            Delivery::Confirmed => "confirmed",
+            Delivery::Failed => "failed",
+        }
+    }
+
+    /// Our message as shown and kept in scrollback: a confirmed one looks like
+    /// anyone else's, a failed one says so
+    pub fn echo(self, line: &str) -> String {
+        match self {
+            Delivery::Confirmed => line.to_string(),
+            Delivery::Failed => format!("{} (not delivered)", line),
+        }
+    }
+}
+
+/// Messages sent with self-echo on that the server hasn't relayed back yet
+#[derive(Debug, Default)]
+pub struct AwaitingEcho {
+    sent: VecDeque<(Destination, String)>,
+}
+
+impl AwaitingEcho {
+    pub fn push(&mut self, to: Destination, text: &str) {
+        if self.sent.len() >= MAX_AWAITING_ECHO {
+            self.sent.pop_front();
+        }
+        self.sent.push_back((to, text.to_string()));
+    }
+
+    /// How many are waiting
+    pub fn waiting(&self) -> usize {
+        self.sent.len()
+    }
+
+    /// One of our messages came back. If we were waiting for it, returns the messages
+    /// sent before it that never came back (the server didn't relay them), oldest
+    /// first; None means it wasn't sent from this session.
+    pub fn echoed(&mut self, to: &Destination, text: &str) -> Option<Vec<(Destination, String)>> {
+        let at = self
+            .sent
+            .iter()
+            .position(|(sent_to, sent)| sent_to == to && sent == text)?;
+        let missed = self.sent.drain(..at).collect();
+        self.sent.pop_front();
+        Some(missed)
+    }
+
+    /// Give up on every message still waiting (the connection was lost)
+    pub fn clear(&mut self) -> Vec<(Destination, String)> {
+        self.sent.drain(..).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delivery_echo() {
+        let sent: Result<(), ()> = Ok(());
+        assert_eq!(Delivery::of(&sent), Delivery::Confirmed);
+        assert_eq!(Delivery::Confirmed.echo("alice: hi"), "alice: hi");
+        assert_eq!(
+            Delivery::of(&Err::<(), _>(())).echo("alice: hi"),
+            "alice: hi (not delivered)"
+        );
+
+        let to = Destination::Direct("bob".to_string());
+        assert_eq!(to.message_type(), MessageTypes::DirectMessage);
+        assert_eq!(to.prefix(), "bob|");
+        assert_eq!(Destination::Chat.prefix(), "");
+    }
+
+    #[test]
+    fn test_awaiting_echo() {
+        let ops = Destination::Room("ops".to_string());
+        let mut awaiting = AwaitingEcho::default();
+        awaiting.push(Destination::Chat, "one");
+        awaiting.push(ops.clone(), "refused");
+        awaiting.push(ops.clone(), "three");
+
+        assert_eq!(awaiting.echoed(&Destination::Chat, "one"), Some(vec![]));
+        assert_eq!(awaiting.echoed(&Destination::Chat, "three"), None);
+        assert_eq!(
+            awaiting.echoed(&ops, "three"),
+            Some(vec![(ops.clone(), "refused".to_string())])
+        );
+        assert_eq!(awaiting.waiting(), 0);
+
+        awaiting.push(ops.clone(), "lost");
+        assert_eq!(awaiting.clear(), vec![(ops, "lost".to_string())]);
+    }
+}
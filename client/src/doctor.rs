@@ -0,0 +1,218 @@
+//! /doctor: checks the files in the state directory and repairs what it can. A
+//! corrupt key or pinned certificate is set aside as `<file>.corrupt` (a new one is
+//! made the next time it's needed), lines that can't be read are dropped from
+//! scrollback and pinned keys, and files left half written by a crash are removed.
+
+use crate::paths::StateFile;
+use crate::scrollback;
+use crate::state_dir::{self, LAYOUT_VERSION, Migration};
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// What /doctor found
+#[derive(Debug, Default)]
+pub struct Report {
+    /// State files looked at
+    pub checked: usize,
+    /// What was wrong and what was done about it
+    pub repairs: Vec<String>,
+}
+
+/// Check (and repair) the state directory `dir`
+pub fn run(dir: &Path) -> io::Result<Report> {
+    let mut report = Report::default();
+    match state_dir::read_version(dir) {
+        Some(LAYOUT_VERSION) => {}
+        Some(version) if version > LAYOUT_VERSION => {
+            report.repairs.push(format!(
+                "Layout version {} is from a newer client - nothing was checked",
+                version
+            ));
+            return Ok(report);
+        }
+        version => {
+            if let Migration::Upgraded { from } = state_dir::migrate(dir)? {
+                report.repairs.push(match version {
+                    Some(_) => format!("Migrated from layout version {}", from),
+                    None => "VERSION was missing or unreadable - layout checked and recorded"
+                        .to_string(),
+                });
+            }
+        }
+    }
+
+    remove_interrupted_writes(dir, dir, &mut report)?;
+    for kind in StateFile::ALL {
+        for path in files(&dir.join(kind.dir()), kind.extension())? {
+            report.checked += 1;
+            let name = path
+                .strip_prefix(dir)
+                .unwrap_or(&path)
+                .display()
+                .to_string();
+            match kind {
+                StateFile::SigningKey | StateFile::Lineage => {
+                    let contents = fs::read(&path)?;
+                    if !is_key(&String::from_utf8_lossy(&contents)) {
+                        set_aside(&path)?;
+                        report.repairs.push(format!(
+                            "{}: not a valid key - set aside as .corrupt, a new one will be made",
+                            name
+                        ));
+                    }
+                }
+                StateFile::TrustedCert => {
+                    let contents = fs::read(&path)?;
+                    if shared::tls::parse_fingerprint(&String::from_utf8_lossy(&contents)).is_none()
+                    {
+                        set_aside(&path)?;
+                        report.repairs.push(format!(
+                            "{}: not a certificate fingerprint - set aside as .corrupt, /trust the server again",
+                            name
+                        ));
+                    }
+                }
+                StateFile::KnownKeys => {
+                    let dropped = keep_lines(&path, |line| {
+                        line.split_once('\t')
+                            .is_some_and(|(user, key)| !user.is_empty() && is_key(key))
+                    })?;
+                    if dropped > 0 {
+                        report
+                            .repairs
+                            .push(format!("{}: dropped {} unreadable pin(s)", name, dropped));
+                    }
+                }
+                StateFile::History => {
+                    let dropped = keep_lines(&path, scrollback::is_entry)?;
+                    if dropped > 0 {
+                        report
+                            .repairs
+                            .push(format!("{}: dropped {} unreadable line(s)", name, dropped));
+                    }
+                }
+                // Traces are free-form, and a draft is whatever was typed
+                StateFile::Trace | StateFile::Drafts => {}
+            }
+        }
+    }
+    Ok(report)
+}
+
+/// 32 bytes in hex, as keys and lineage secrets are saved
+fn is_key(text: &str) -> bool {
+    let text = text.trim();
+    text.len() == 64 && text.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Files in `dir` (not its subdirectories) with the extension `extension`
+fn files(dir: &Path, extension: &str) -> io::Result<Vec<PathBuf>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut files = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        if path.is_file() && path.extension().is_some_and(|ext| ext == extension) {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Remove temporary files under `dir` left by writes a crash interrupted (the file
+/// they were replacing is intact)
+fn remove_interrupted_writes(root: &Path, dir: &Path, report: &mut Report) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            remove_interrupted_writes(root, &path, report)?;
+        } else if path.to_string_lossy().ends_with(state_dir::TEMP_SUFFIX) {
+            fs::remove_file(&path)?;
+            report.repairs.push(format!(
+                "{}: removed, left by an interrupted write",
+                path.strip_prefix(root).unwrap_or(&path).display()
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Rename `path` to `<path>.corrupt`, out of the client's way but not lost
+fn set_aside(path: &Path) -> io::Result<()> {
+    let mut corrupt = OsString::from(path.as_os_str());
+    corrupt.push(".corrupt");
+    fs::rename(path, corrupt)
+}
+
+/// Rewrite `path` with only the lines `valid` accepts. Returns how many were dropped.
+fn keep_lines(path: &Path, valid: fn(&str) -> bool) -> io::Result<usize> {
+    let contents = fs::read(path)?;
+    let text = String::from_utf8_lossy(&contents);
+    let (kept, dropped): (Vec<&str>, Vec<&str>) = text.lines().partition(|line| valid(line));
+    if !dropped.is_empty() {
+        let mut repaired = kept.join("\n");
+        if !kept.is_empty() {
+            repaired.push('\n');
+        }
+        state_dir::write_atomic(path, repaired.as_bytes())?;
+    }
+    Ok(dropped.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_repairs_corrupt_state() {
+        let dir = std::env::temp_dir().join(format!("rust_chat_doctor_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        state_dir::migrate(&dir).unwrap();
+        let key = "ab".repeat(32);
+        let stem = "host_1";
+        let path = |kind: StateFile| kind.path(&dir, stem);
+        state_dir::write_secret(&path(StateFile::SigningKey), key.as_bytes()).unwrap();
+        fs::create_dir_all(dir.join("keys/lineage")).unwrap();
+        fs::write(path(StateFile::Lineage), [0xff, 0x00, 0x12]).unwrap();
+        state_dir::write_atomic(
+            &path(StateFile::KnownKeys),
+            format!("alice\t{}\nbroken line\nbob\tabc\n", key).as_bytes(),
+        )
+        .unwrap();
+        fs::create_dir_all(dir.join("history")).unwrap();
+        fs::write(
+            path(StateFile::History),
+            b"\t2026-10-16 10:00\talice: hi\n\xff\xfe garbage\n",
+        )
+        .unwrap();
+        fs::write(dir.join("keys/known/host_1.tsv.tmp"), "half").unwrap();
+
+        let report = run(&dir).unwrap();
+        assert_eq!(report.checked, 4);
+        assert_eq!(report.repairs.len(), 4, "{:?}", report.repairs);
+        assert!(!path(StateFile::Lineage).exists());
+        assert!(dir.join("keys/lineage/host_1.key.corrupt").exists());
+        assert!(path(StateFile::SigningKey).exists());
+        assert_eq!(
+            fs::read_to_string(path(StateFile::KnownKeys)).unwrap(),
+            format!("alice\t{}\n", key)
+        );
+        assert_eq!(
+            fs::read_to_string(path(StateFile::History)).unwrap(),
+            "\t2026-10-16 10:00\talice: hi\n"
+        );
+        assert!(!dir.join("keys/known/host_1.tsv.tmp").exists());
+
+        // Nothing left to repair
+        let report = run(&dir).unwrap();
+        assert_eq!(report.checked, 3);
+        assert!(report.repairs.is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,215 @@
+//! Drafts: what's been typed for one room is kept while another is current, and put
+//! back on the input line on returning to it. Alt+Left and Alt+Right move between the
+//! rooms we're in taking the line along; a room switched to with /join or /part gets
+//! its draft back too. Whatever is being typed when the client exits is kept as
+//! well, and with CHAT_SAVE_DRAFTS the drafts are saved for the next launch.
+//! Shared between the client, which knows which room is current, and the input
+//! thread, which knows what's on the line.
+
+use crate::state_dir;
+use shared::commands::client as commands;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+/// How the main chat is written in the drafts file
+const MAIN_CHAT: &str = "";
+
+#[derive(Debug, Default)]
+pub struct Drafts {
+    /// Room the input line is for (None = main chat)
+    current: Option<String>,
+    /// Room the client last said was current
+    synced: Option<String>,
+    /// Rooms we're in, in the order Alt+Left/Right go through them
+    rooms: Vec<String>,
+    /// What's on the input line right now
+    typing: String,
+    drafts: HashMap<Option<String>, String>,
+    /// None = drafts aren't saved
+    path: Option<PathBuf>,
+}
+
+impl Drafts {
+    /// Drafts saved to `path` (None = kept in memory only), loading any saved there
+    pub fn load(path: Option<PathBuf>) -> Self {
+        let mut drafts = Drafts {
+            path,
+            ..Drafts::default()
+        };
+        if let Some(contents) = drafts
+            .path
+            .as_ref()
+            .and_then(|path| fs::read_to_string(path).ok())
+        {
+            for (room, text) in contents.lines().filter_map(|line| line.split_once('\t')) {
+                let room = Some(room).filter(|room| *room != MAIN_CHAT);
+                drafts
+                    .drafts
+                    .insert(room.map(str::to_string), text.to_string());
+            }
+        }
+        drafts
+    }
+
+    /// The input line changed
+    pub fn typing(&mut self, line: &str) {
+        if self.typing != line {
+            self.typing = line.to_string();
+        }
+    }
+
+    /// The input line was sent
+    pub fn sent(&mut self) {
+        self.typing.clear();
+    }
+
+    /// Room the input line is for (None = main chat)
+    pub fn current(&self) -> Option<&str> {
+        self.current.as_deref()
+    }
+
+    /// Start a new input line: the current room's draft, if it has one
+    pub fn take_current(&mut self) -> String {
+        let draft = self.drafts.remove(&self.current).unwrap_or_default();
+        self.typing = draft.clone();
+        draft
+    }
+
+    /// Keep `line` as the current room's draft and make `room` current. Returns
+    /// `room`'s draft, to put on the input line.
+    pub fn switch_to(&mut self, room: Option<String>, line: &str) -> String {
+        self.keep(line);
+        self.current = room;
+        self.take_current()
+    }
+
+    /// The room `step` places along from the current one, among the rooms we're in
+    /// (None when there's nowhere else to go)
+    pub fn neighbour(&self, step: isize) -> Option<String> {
+        let count = self.rooms.len() as isize;
+        let next = match self
+            .current
+            .as_ref()
+            .and_then(|current| self.rooms.iter().position(|room| room == current))
+        {
+            Some(index) => (index as isize + step).rem_euclid(count),
+            None if count > 0 && step < 0 => count - 1,
+            None if count > 0 => 0,
+            None => return None,
+        };
+        let room = &self.rooms[next as usize];
+        (self.current.as_ref() != Some(room)).then(|| room.clone())
+    }
+
+    /// Follow the client's current room and the rooms we're in. A room the client
+    /// switched to on its own takes over the line as it is.
+    pub fn sync(&mut self, current: Option<&str>, rooms: impl IntoIterator<Item = String>) {
+        if self.synced.as_deref() != current {
+            self.synced = current.map(str::to_string);
+            self.current = self.synced.clone();
+        }
+        self.rooms = rooms.into_iter().collect();
+        self.rooms.sort();
+    }
+
+    /// Keep what's being typed as the current room's draft, and save the drafts if
+    /// they're saved
+    pub fn save(&mut self) -> io::Result<()> {
+        let typing = std::mem::take(&mut self.typing);
+        self.keep(&typing);
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let mut contents = String::new();
+        for (room, text) in &self.drafts {
+            contents.push_str(room.as_deref().unwrap_or(MAIN_CHAT));
+            contents.push('\t');
+            contents.push_str(text);
+            contents.push('\n');
+        }
+        // What people type to each other isn't for anyone else to read
+        state_dir::write_secret(path, contents.as_bytes())
+    }
+
+    fn keep(&mut self, line: &str) {
+        // The file is line based
+        let line = line.replace(['\n', '\r'], " ");
+        // Passwords aren't kept, as they aren't in the input history
+        let register = commands::REGISTER.matches(line.split_whitespace().next().unwrap_or(""));
+        if line.trim().is_empty() || register {
+            self.drafts.remove(&self.current);
+        } else {
+            self.drafts.insert(self.current.clone(), line);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn room(name: &str) -> Option<String> {
+        Some(name.to_string())
+    }
+
+    #[test]
+    fn test_drafts_follow_rooms() {
+        let mut drafts = Drafts::default();
+        drafts.sync(Some("ops"), ["ops".to_string(), "general".to_string()]);
+
+        // Moving on keeps the line for when we come back
+        assert_eq!(drafts.neighbour(1), room("general"));
+        assert_eq!(drafts.switch_to(room("general"), "deploy is do"), "");
+        assert_eq!(drafts.neighbour(1), room("ops"));
+        assert_eq!(drafts.neighbour(-1), room("ops"));
+        assert_eq!(drafts.switch_to(room("ops"), "hi all"), "deploy is do");
+        assert_eq!(
+            drafts.switch_to(room("general"), "deploy is done"),
+            "hi all"
+        );
+
+        // The client catching up changes nothing; it moving us on its own does
+        drafts.sent();
+        let rooms = || ["ops".to_string(), "general".to_string()];
+        drafts.sync(Some("general"), rooms());
+        assert_eq!(drafts.take_current(), "");
+        drafts.sync(Some("ops"), rooms());
+        assert_eq!(drafts.take_current(), "deploy is done");
+        assert_eq!(drafts.switch_to(room("general"), "  "), "");
+        assert_eq!(drafts.switch_to(room("ops"), ""), "");
+
+        // Parting the last room leaves the main chat, which has nowhere to go
+        drafts.sync(None, []);
+        assert_eq!(drafts.neighbour(1), None);
+        assert_eq!(drafts.switch_to(None, ""), "");
+    }
+
+    #[test]
+    fn test_drafts_saved() {
+        let dir = std::env::temp_dir().join(format!("drafts_test_{}", std::process::id()));
+        let path = dir.join("drafts").join("chat.lan_8080.tsv");
+
+        let mut drafts = Drafts::load(Some(path.clone()));
+        drafts.switch_to(room("ops"), "main\tchat draft");
+        drafts.typing("half a thought");
+        drafts.save().unwrap();
+
+        let mut drafts = Drafts::load(Some(path));
+        assert_eq!(drafts.take_current(), "main\tchat draft");
+        assert_eq!(drafts.switch_to(room("ops"), ""), "half a thought");
+
+        // Unsaved drafts only last the session
+        let mut unsaved = Drafts::load(None);
+        unsaved.typing("gone");
+        unsaved.save().unwrap();
+        assert_eq!(unsaved.take_current(), "gone");
+        // ...and passwords aren't kept at all
+        unsaved.typing("/register hunter2");
+        unsaved.save().unwrap();
+        assert_eq!(unsaved.take_current(), "");
+
+        fs::remove_dir_all(&dir).ok();
+    }
+}
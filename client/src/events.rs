@@ -0,0 +1,313 @@
+//! Received events, and what became of messages we sent, as JSON lines on stdout
+//! (`--output json`, and `--pipe`), for scripts and tools like jq to read one object
+//! per line. Log lines go to stderr
+//! in this mode, so stdout is only events. Field names are part of the interface:
+//! add new ones rather than renaming.
+
+use chrono::Local;
+use serde_json::{Value, json};
+use shared::disconnect::DisconnectReason;
+use shared::message::MessageTypes;
+use shared::sender_class::SenderClass;
+use std::time::Duration;
+
+pub enum Event<'a> {
+    /// Message in the main chat (room None) or a room. `class` is the sender's, as
+    /// the server sees it: "user", "bot", "server" or "notice".
+    Message {
+        room: Option<&'a str>,
+        from: &'a str,
+        class: SenderClass,
+        text: &'a str,
+    },
+    DirectMessage {
+        from: &'a str,
+        to: &'a str,
+        class: SenderClass,
+        text: &'a str,
+    },
+    Announcement {
+        room: Option<&'a str>,
+        from: &'a str,
+        class: SenderClass,
+        text: &'a str,
+    },
+    /// A machine translation of a room message (`original`), from the server's
+    /// translation hook
+    Translation {
+        room: &'a str,
+        from: &'a str,
+        lang: &'a str,
+        text: &'a str,
+        original: &'a str,
+    },
+    /// Someone joined the chat (room None) or a room we're in
+    Join {
+        room: Option<&'a str>,
+        user: &'a str,
+    },
+    Leave {
+        room: Option<&'a str>,
+        user: &'a str,
+    },
+    /// Someone changed nickname, or was renamed (us too)
+    Rename { from: &'a str, to: &'a str },
+    /// Error reported by the server, including rate limiting, with its code (if the
+    /// server sent one). A rejected frame also says its type and the input line that sent it, if known
+    Error {
+        code: Option<u16>,
+        text: &'a str,
+        frame: Option<MessageTypes>,
+        action: Option<&'a str>,
+    },
+    /// The server is closing the connection, why, and whether the client will try
+    /// connecting again
+    Disconnect {
+        reason: DisconnectReason,
+        text: &'a str,
+        /// When a kick or ban ends, if the server said
+        retry_after: Option<Duration>,
+    },
+    /// Who is online (the reply to /list), with their status messages
+    Presence {
+        users: Vec<(&'a str, Option<&'a str>)>,
+    },
+    /// A message we sent to the main chat (room and to None), a room or a user, once
+    /// the server acknowledged it ("confirmed") or sending failed ("failed")
+    Sent {
+        room: Option<&'a str>,
+        to: Option<&'a str>,
+        text: &'a str,
+        status: &'static str,
+    },
+}
+
+impl Event<'_> {
+    pub fn to_json(&self) -> Value {
+        let time = Local::now().to_rfc3339();
+        match self {
+            Event::Message {
+                room,
+                from,
+                class,
+                text,
+            } => json!({
+                "type": "message",
+                "time": time,
+                "room": room,
+                "from": from,
+                "class": class.name(),
+                "text": text,
+            }),
+            Event::DirectMessage {
+                from,
+                to,
+                class,
+                text,
+            } => json!({
+                "type": "dm",
+                "time": time,
+                "from": from,
+                "to": to,
+                "class": class.name(),
+                "text": text,
+            }),
+            Event::Announcement {
+                room,
+                from,
+                class,
+                text,
+            } => json!({
+                "type": "announcement",
+                "time": time,
+                "room": room,
+                "from": from,
+                "class": class.name(),
+                "text": text,
+            }),
+            Event::Translation {
+                room,
+                from,
+                lang,
+                text,
+                original,
+            } => json!({
+                "type": "translation",
+                "time": time,
+                "room": room,
+                "from": from,
+                "lang": lang,
+                "text": text,
+                "original": original,
+            }),
+            Event::Join { room, user } => json!({
+                "type": "join",
+                "time": time,
+                "room": room,
+                "user": user,
+            }),
+            Event::Leave { room, user } => json!({
+                "type": "leave",
+                "time": time,
+                "room": room,
+                "user": user,
+            }),
+            Event::Rename { from, to } => json!({
+                "type": "rename",
+                "time": time,
+                "from": from,
+                "to": to,
+            }),
+            Event::Error {
+                code,
+                text,
+                frame,
+                action,
+            } => json!({
+                "type": "error",
+                "time": time,
+                "code": code,
+                "text": text,
+                "frame": frame.map(|frame| format!("{:?}", frame)),
+                "action": action,
+            }),
+            Event::Disconnect {
+                reason,
+                text,
+                retry_after,
+            } => json!({
+                "type": "disconnect",
+                "time": time,
+                "reason": reason.name(),
+                "code": reason.code(),
+                "text": text,
+                "reconnect": reason.reconnects(),
+                "retry_after": retry_after.map(|wait| wait.as_secs()),
+            }),
+            Event::Presence { users } => json!({
+                "type": "presence",
+                "time": time,
+                "users": users
+                    .iter()
+                    .map(|(name, status)| json!({ "name": name, "status": status }))
+                    .collect::<Vec<_>>(),
+            }),
+            Event::Sent {
+                room,
+                to,
+                text,
+                status,
+            } => json!({
+                "type": "sent",
+                "time": time,
+                "room": room,
+                "to": to,
+                "text": text,
+                "status": status,
+            }),
+        }
+    }
+}
+
+/// Write an event to stdout as one line
+pub fn emit(event: Event) {
+    println!("{}", event.to_json());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_event_json() {
+        let event = Event::Message {
+            room: Some("general"),
+            from: "alice",
+            class: SenderClass::User,
+            text: "hi \"there\"\nsecond line",
+        }
+        .to_json();
+        assert_eq!(event["type"], "message");
+        assert_eq!(event["room"], "general");
+        assert_eq!(event["from"], "alice");
+        assert_eq!(event["class"], "user");
+        assert_eq!(event["text"], "hi \"there\"\nsecond line");
+        assert!(event["time"].is_string());
+        // One event per line
+        assert!(!event.to_string().contains('\n'));
+
+        let event = Event::Announcement {
+            room: None,
+            from: "Server",
+            class: SenderClass::Server,
+            text: "Restarting",
+        }
+        .to_json();
+        assert_eq!(event["type"], "announcement");
+        assert_eq!(event["class"], "server");
+        assert!(event["room"].is_null());
+
+        let event = Event::Translation {
+            room: "ops",
+            from: "alice",
+            lang: "en",
+            text: "hello",
+            original: "bonjour",
+        }
+        .to_json();
+        assert_eq!(event["type"], "translation");
+        assert_eq!(event["lang"], "en");
+        assert_eq!(event["original"], "bonjour");
+
+        let event = Event::Error {
+            code: Some(21),
+            text: "You have been kicked by the server",
+            frame: None,
+            action: None,
+        }
+        .to_json();
+        assert_eq!(event["type"], "error");
+        assert_eq!(event["code"], 21);
+        assert!(event["frame"].is_null());
+
+        let event = Event::Error {
+            code: Some(9),
+            text: "SetStatus not allowed in state Connected",
+            frame: Some(MessageTypes::SetStatus),
+            action: Some("/status away"),
+        }
+        .to_json();
+        assert_eq!(event["frame"], "SetStatus");
+        assert_eq!(event["action"], "/status away");
+
+        let event = Event::Disconnect {
+            reason: DisconnectReason::Shutdown,
+            text: "The server is shutting down",
+            retry_after: None,
+        }
+        .to_json();
+        assert_eq!(event["type"], "disconnect");
+        assert_eq!(event["reason"], "shutdown");
+        assert_eq!(event["code"], 3);
+        assert_eq!(event["reconnect"], true);
+        assert!(event["retry_after"].is_null());
+
+        let event = Event::Disconnect {
+            reason: DisconnectReason::Kicked,
+            text: "You have been kicked by the server - you can rejoin in 5m",
+            retry_after: Some(Duration::from_secs(300)),
+        }
+        .to_json();
+        assert_eq!(event["reconnect"], false);
+        assert_eq!(event["retry_after"], 300);
+
+        let event = Event::Presence {
+            users: vec![("alice", Some("away")), ("bob", None)],
+        }
+        .to_json();
+        assert_eq!(event["type"], "presence");
+        assert_eq!(event["users"][0]["name"], "alice");
+        assert_eq!(event["users"][0]["status"], "away");
+        assert!(event["users"][1]["status"].is_null());
+    }
+}
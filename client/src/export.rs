@@ -0,0 +1,92 @@
+//! Room history saved with /export-room. Paths ending in `.json` get a JSON array of
+//! messages, anything else one `[time] sender: message` line per message.
+
+use chrono::{Local, TimeZone};
+use serde_json::json;
+use shared::history::HistoryLine;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Write a room's history to `path`, in the format its extension asks for
+pub fn write(path: &Path, room: &str, lines: &[HistoryLine]) -> io::Result<()> {
+    let json = path
+        .extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("json"));
+    let content = if json {
+        to_json(room, lines)
+    } else {
+        to_text(room, lines)
+    };
+    fs::write(path, content)
+}
+
+fn timestamp(line: &HistoryLine) -> String {
+    Local
+        .timestamp_opt(line.sent_at, 0)
+        .single()
+        .map(|at| at.format("%Y-%m-%d %H:%M:%S").to_string())
+        .unwrap_or_default()
+}
+
+fn to_text(room: &str, lines: &[HistoryLine]) -> String {
+    let mut content = format!("# #{} - {} message(s)\n", room, lines.len());
+    for line in lines {
+        content.push_str(&format!(
+            "[{}] {}: {}\n",
+            timestamp(line),
+            line.sender,
+            line.message
+        ));
+    }
+    content
+}
+
+fn to_json(room: &str, lines: &[HistoryLine]) -> String {
+    let messages: Vec<_> = lines
+        .iter()
+        .map(|line| {
+            json!({
+                "room": room,
+                "time": timestamp(line),
+                "unix_time": line.sent_at,
+                "from": line.sender,
+                "text": line.message,
+            })
+        })
+        .collect();
+    // Serializing plain values can't fail
+    serde_json::to_string_pretty(&messages).unwrap_or_default() + "\n"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_export_formats() {
+        let lines = vec![HistoryLine {
+            sent_at: 1_700_000_000,
+            sender: "alice".to_string(),
+            message: "deploy at 5".to_string(),
+        }];
+        let dir = std::env::temp_dir().join(format!("rust_chat_export_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let text_path = dir.join("ops.txt");
+        write(&text_path, "ops", &lines).unwrap();
+        let text = fs::read_to_string(&text_path).unwrap();
+        assert!(text.starts_with("# #ops - 1 message(s)\n["));
+        assert!(text.ends_with("] alice: deploy at 5\n"));
+
+        let json_path = dir.join("ops.JSON");
+        write(&json_path, "ops", &lines).unwrap();
+        let value: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(&json_path).unwrap()).unwrap();
+        assert_eq!(value[0]["from"], "alice");
+        assert_eq!(value[0]["unix_time"], 1_700_000_000);
+        assert_eq!(value[0]["room"], "ops");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,356 @@
+//! Display filters (/filter): rules deciding what the client shows, without changing
+//! what it receives, records in the scrollback or writes as JSON events.
+//! `hide <rule>` hides what matches; `only <rule>` hides everything else, and with
+//! several `only` filters, what matches any of them is shown. Rules are `join/leave`
+//! (people coming and going), `from:<nickname>` and `room:<room>` (`room:` alone is
+//! the main chat). Direct messages are addressed to us, so `only` filters never hide
+//! them; a `hide` filter still can. Kept one per line in `config/filters.txt` in the
+//! state directory, for every server.
+
+use crate::state_dir;
+use shared::message::{ChatMessage, MessageTypes};
+use shared::sender_class::SenderClass;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+/// File in the state directory's config/ holding the filters
+pub const FILTERS_FILE: &str = "filters.txt";
+
+#[derive(Debug)]
+pub enum FilterError {
+    UnknownRule(String),
+}
+
+impl fmt::Display for FilterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FilterError::UnknownRule(rule) => write!(
+                f,
+                "unknown rule '{}' - use join/leave, from:<nickname> or room:<room>",
+                rule
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Rule {
+    Presence,
+    From(String),
+    /// Empty = the main chat
+    Room(String),
+}
+
+impl Rule {
+    fn parse(rule: &str) -> Result<Self, FilterError> {
+        match rule.split_once(':') {
+            None if matches!(rule, "join/leave" | "joins" | "presence") => Ok(Rule::Presence),
+            Some(("from", nickname)) if !nickname.is_empty() => {
+                Ok(Rule::From(nickname.to_string()))
+            }
+            Some(("room", room)) => Ok(Rule::Room(room.trim_start_matches('#').to_lowercase())),
+            _ => Err(FilterError::UnknownRule(rule.to_string())),
+        }
+    }
+
+    fn matches(&self, seen: &Seen) -> bool {
+        match self {
+            Rule::Presence => seen.presence,
+            Rule::From(nickname) => seen.from == Some(nickname.as_str()),
+            Rule::Room(room) => !seen.direct && seen.room.unwrap_or_default() == room,
+        }
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rule::Presence => write!(f, "join/leave"),
+            Rule::From(nickname) => write!(f, "from:{}", nickname),
+            Rule::Room(room) => write!(f, "room:{}", room),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Filter {
+    /// true = `only`, false = `hide`
+    only: bool,
+    rule: Rule,
+}
+
+impl Filter {
+    /// "hide from:noisybot", "only room:ops"
+    pub fn parse(spec: &str) -> Result<Self, FilterError> {
+        let (action, rule) = spec
+            .trim()
+            .split_once(char::is_whitespace)
+            .ok_or_else(|| FilterError::UnknownRule(spec.trim().to_string()))?;
+        let only = match action {
+            "hide" => false,
+            "only" => true,
+            _ => return Err(FilterError::UnknownRule(spec.trim().to_string())),
+        };
+        Ok(Filter {
+            only,
+            rule: Rule::parse(rule.trim())?,
+        })
+    }
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let action = if self.only { "only" } else { "hide" };
+        write!(f, "{} {}", action, self.rule)
+    }
+}
+
+/// What's about to be shown, as far as filters go
+#[derive(Debug, Default)]
+pub struct Seen<'a> {
+    /// None = the main chat
+    pub room: Option<&'a str>,
+    /// None = a notice nobody said
+    pub from: Option<&'a str>,
+    pub presence: bool,
+    pub direct: bool,
+}
+
+impl<'a> Seen<'a> {
+    /// Someone joining or leaving `room` (None = the chat)
+    pub fn presence(room: Option<&'a str>, user: &'a str) -> Self {
+        Seen {
+            room,
+            from: Some(user),
+            presence: true,
+            direct: false,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Filters {
+    /// None = only kept in memory
+    path: Option<PathBuf>,
+    entries: Vec<Filter>,
+}
+
+impl Filters {
+    /// Load the filters from `path` (a missing file is no filters; lines that no longer
+    /// parse are skipped)
+    pub fn load(path: Option<PathBuf>) -> Self {
+        let entries = path
+            .as_ref()
+            .and_then(|path| fs::read_to_string(path).ok())
+            .map(|contents| {
+                contents
+                    .lines()
+                    .filter_map(|line| Filter::parse(line).ok())
+                    .collect()
+            })
+            .unwrap_or_default();
+        Filters { path, entries }
+    }
+
+    pub fn list(&self) -> &[Filter] {
+        &self.entries
+    }
+
+    /// Add a filter. Returns false if it was already there.
+    pub fn add(&mut self, filter: Filter) -> io::Result<bool> {
+        if self.entries.contains(&filter) {
+            return Ok(false);
+        }
+        self.entries.push(filter);
+        self.save()?;
+        Ok(true)
+    }
+
+    /// Remove a filter. Returns false if it wasn't there.
+    pub fn remove(&mut self, filter: &Filter) -> io::Result<bool> {
+        let before = self.entries.len();
+        self.entries.retain(|entry| entry != filter);
+        if self.entries.len() == before {
+            return Ok(false);
+        }
+        self.save()?;
+        Ok(true)
+    }
+
+    /// Remove every filter
+    pub fn clear(&mut self) -> io::Result<()> {
+        self.entries.clear();
+        self.save()
+    }
+
+    /// Point `from:` filters for `from` at `to`, who they are now. Returns whether any
+    /// changed.
+    pub fn rename(&mut self, from: &str, to: &str) -> io::Result<bool> {
+        let mut changed = false;
+        for entry in &mut self.entries {
+            if entry.rule == Rule::From(from.to_string()) {
+                entry.rule = Rule::From(to.to_string());
+                changed = true;
+            }
+        }
+        if !changed {
+            return Ok(false);
+        }
+        // A filter that only differed in the old name is now a duplicate
+        let mut kept: Vec<Filter> = Vec::with_capacity(self.entries.len());
+        for entry in self.entries.drain(..) {
+            if !kept.contains(&entry) {
+                kept.push(entry);
+            }
+        }
+        self.entries = kept;
+        self.save()?;
+        Ok(true)
+    }
+
+    /// Whether the filters let `seen` be shown
+    pub fn shows(&self, seen: &Seen) -> bool {
+        let mut only = self.entries.iter().filter(|entry| entry.only).peekable();
+        let let_through =
+            seen.direct || only.peek().is_none() || only.any(|entry| entry.rule.matches(seen));
+        let_through
+            && !self
+                .entries
+                .iter()
+                .any(|entry| !entry.only && entry.rule.matches(seen))
+    }
+
+    /// Whether the filters hide a relayed message (`message` without its class). Only
+    /// what's said - chat, room, direct messages and announcements - is judged here.
+    pub fn hide_frame(&self, class: SenderClass, message: &ChatMessage) -> bool {
+        if self.entries.is_empty() {
+            return false;
+        }
+        let Some(content) = message.content_as_string() else {
+            return false;
+        };
+        let mut fields = content.split('|');
+        let seen = match message.msg_type {
+            // "sender: text", or a notice's text
+            MessageTypes::ChatMessage => Seen {
+                from: content
+                    .split_once(": ")
+                    .filter(|_| class != SenderClass::Notice)
+                    .map(|(sender, _)| sender),
+                ..Seen::default()
+            },
+            // room|sender|text
+            MessageTypes::RoomMessage
+            | MessageTypes::Announcement
+            | MessageTypes::RoomAnnouncement => Seen {
+                room: fields.next().filter(|room| !room.is_empty()),
+                from: fields.next(),
+                ..Seen::default()
+            },
+            // sender|recipient|text
+            MessageTypes::DirectMessage => Seen {
+                from: fields.next(),
+                direct: true,
+                ..Seen::default()
+            },
+            _ => return false,
+        };
+        !self.shows(&seen)
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let contents: String = self
+            .entries
+            .iter()
+            .map(|filter| format!("{}\n", filter))
+            .collect();
+        state_dir::write_atomic(path, contents.as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hide_and_only() {
+        let path =
+            std::env::temp_dir().join(format!("rust_chat_filters_{}.txt", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let mut filters = Filters::load(Some(path.clone()));
+        let said = |room, from| Seen {
+            room,
+            from: Some(from),
+            ..Seen::default()
+        };
+        assert!(filters.shows(&Seen::presence(None, "alice")));
+
+        assert!(
+            filters
+                .add(Filter::parse("hide join/leave").unwrap())
+                .unwrap()
+        );
+        assert!(!filters.add(Filter::parse("hide  joins").unwrap()).unwrap());
+        assert!(
+            filters
+                .add(Filter::parse("hide from:noisybot").unwrap())
+                .unwrap()
+        );
+        assert!(!filters.shows(&Seen::presence(Some("ops"), "alice")));
+        assert!(!filters.shows(&said(None, "noisybot")));
+        assert!(filters.shows(&said(Some("ops"), "alice")));
+
+        assert!(
+            filters
+                .add(Filter::parse("only room:#Ops").unwrap())
+                .unwrap()
+        );
+        assert!(filters.shows(&said(Some("ops"), "alice")));
+        assert!(!filters.shows(&said(Some("random"), "alice")));
+        assert!(!filters.shows(&said(None, "alice")));
+        assert!(!filters.shows(&said(Some("ops"), "noisybot")));
+        let dm = Seen {
+            from: Some("bob"),
+            direct: true,
+            ..Seen::default()
+        };
+        assert!(filters.shows(&dm));
+
+        assert!(matches!(
+            Filter::parse("only to:alice"),
+            Err(FilterError::UnknownRule(_))
+        ));
+        assert!(Filter::parse("show room:ops").is_err());
+
+        let reloaded = Filters::load(Some(path.clone()));
+        let specs: Vec<String> = reloaded.list().iter().map(Filter::to_string).collect();
+        assert_eq!(
+            specs,
+            vec!["hide join/leave", "hide from:noisybot", "only room:ops"]
+        );
+        assert!(
+            filters
+                .remove(&Filter::parse("only room:ops").unwrap())
+                .unwrap()
+        );
+        assert!(filters.shows(&said(None, "alice")));
+
+        // A rename takes the filter along
+        assert!(filters.rename("noisybot", "quietbot").unwrap());
+        assert!(!filters.rename("noisybot", "quietbot").unwrap());
+        assert!(filters.shows(&said(None, "noisybot")));
+        assert!(!filters.shows(&said(None, "quietbot")));
+        assert_eq!(
+            Filters::load(Some(path.clone())).list()[1].to_string(),
+            "hide from:quietbot"
+        );
+        filters.clear().unwrap();
+        assert!(filters.shows(&said(None, "noisybot")));
+        fs::remove_file(&path).unwrap();
+    }
+}
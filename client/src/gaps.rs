@@ -0,0 +1,87 @@
+//! Gap markers: a message that arrives long after the one before it, or is the first
+//! after a reconnect, gets a dated divider in front of it - "— 14 Mar 09:12 —" - so a
+//! session left open for days still says when things were said. Scrollback restored
+//! from the previous session is divided the same way, and the first message after it
+//! is measured from its last entry.
+
+use chrono::{DateTime, Local, NaiveDateTime, TimeZone};
+use std::time::Duration;
+
+/// Quiet time after which a divider is shown, when not configured
+pub const DEFAULT_GAP: Duration = Duration::from_secs(30 * 60);
+
+#[derive(Debug)]
+pub struct Gaps {
+    /// None = no dividers
+    threshold: Option<Duration>,
+    /// When the last message shown was said
+    last: Option<DateTime<Local>>,
+    /// The next message gets a divider however soon it comes
+    forced: bool,
+}
+
+impl Gaps {
+    pub fn new(threshold: Option<Duration>) -> Self {
+        Gaps {
+            threshold,
+            last: None,
+            forced: false,
+        }
+    }
+
+    /// Note a message said at `at`. Returns the divider to show before it, if any.
+    pub fn observe(&mut self, at: DateTime<Local>) -> Option<String> {
+        let threshold = self.threshold?;
+        let quiet = self
+            .last
+            .and_then(|last| (at - last).to_std().ok())
+            .is_some_and(|gap| gap > threshold);
+        let due = quiet || std::mem::take(&mut self.forced);
+        self.last = Some(at);
+        due.then(|| divider(at))
+    }
+
+    /// Put a divider in front of the next message (we were disconnected, and what
+    /// comes next may not follow on from what came before)
+    pub fn force(&mut self) {
+        self.forced = self.last.is_some();
+    }
+}
+
+/// "— 14 Mar 09:12 —"
+pub fn divider(at: DateTime<Local>) -> String {
+    format!("— {} —", at.format("%-d %b %H:%M"))
+}
+
+/// The time of a scrollback entry ("2026-03-14 09:12")
+pub fn parse_entry_time(timestamp: &str) -> Option<DateTime<Local>> {
+    let time = NaiveDateTime::parse_from_str(timestamp, "%Y-%m-%d %H:%M").ok()?;
+    Local.from_local_datetime(&time).earliest()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gaps() {
+        let at = parse_entry_time("2026-03-14 09:12").unwrap();
+        let later = |minutes| at + chrono::Duration::minutes(minutes);
+
+        let mut gaps = Gaps::new(Some(DEFAULT_GAP));
+        // Nothing to measure the first message from
+        assert_eq!(gaps.observe(at), None);
+        assert_eq!(gaps.observe(later(30)), None);
+        assert_eq!(gaps.observe(later(61)).as_deref(), Some("— 14 Mar 10:13 —"));
+
+        gaps.force();
+        assert_eq!(gaps.observe(later(62)).as_deref(), Some("— 14 Mar 10:14 —"));
+        assert_eq!(gaps.observe(later(63)), None);
+
+        let mut off = Gaps::new(None);
+        off.observe(at);
+        assert_eq!(off.observe(later(24 * 60)), None);
+
+        assert_eq!(parse_entry_time("14 Mar"), None);
+    }
+}
@@ -0,0 +1,183 @@
+//! Highlight words (/highlight): besides our own nickname, messages containing any of
+//! these are shown and notified like mentions. A word matches as a whole word, case
+//! insensitively, the way nicknames do; `/pattern/` is a (case-insensitive) regex.
+//! Kept one per line in `config/highlights.txt` in the state directory, for every
+//! server.
+
+use crate::state_dir;
+use regex::{Regex, RegexBuilder};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+/// File in the state directory's config/ holding the highlight words
+pub const HIGHLIGHTS_FILE: &str = "highlights.txt";
+
+#[derive(Debug)]
+pub enum HighlightError {
+    /// Words can't contain spaces (a regex can)
+    NotAWord,
+    BadRegex(regex::Error),
+    Io(io::Error),
+}
+
+impl fmt::Display for HighlightError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HighlightError::NotAWord => {
+                write!(f, "highlight words can't contain spaces - use /a regex/")
+            }
+            HighlightError::BadRegex(e) => write!(f, "invalid regex: {}", e),
+            HighlightError::Io(e) => write!(f, "couldn't save highlights: {}", e),
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Matcher {
+    Word(String),
+    Regex(Regex),
+}
+
+#[derive(Debug)]
+struct Highlight {
+    /// As the user typed it
+    spec: String,
+    matcher: Matcher,
+}
+
+impl Highlight {
+    fn parse(spec: &str) -> Result<Self, HighlightError> {
+        let matcher = match spec
+            .strip_prefix('/')
+            .and_then(|rest| rest.strip_suffix('/'))
+        {
+            Some(pattern) if !pattern.is_empty() => Matcher::Regex(
+                RegexBuilder::new(pattern)
+                    .case_insensitive(true)
+                    .build()
+                    .map_err(HighlightError::BadRegex)?,
+            ),
+            _ if spec.is_empty() || spec.contains(char::is_whitespace) => {
+                return Err(HighlightError::NotAWord);
+            }
+            _ => Matcher::Word(spec.to_string()),
+        };
+        Ok(Highlight {
+            spec: spec.to_string(),
+            matcher,
+        })
+    }
+
+    fn matches(&self, text: &str) -> bool {
+        match &self.matcher {
+            Matcher::Word(word) => shared::digest::mentions(text, word),
+            Matcher::Regex(regex) => regex.is_match(text),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Highlights {
+    /// None = only kept in memory
+    path: Option<PathBuf>,
+    entries: Vec<Highlight>,
+}
+
+impl Highlights {
+    /// Load the highlight words from `path` (a missing file is an empty list; lines
+    /// that no longer parse are skipped)
+    pub fn load(path: Option<PathBuf>) -> Self {
+        let entries = path
+            .as_ref()
+            .and_then(|path| fs::read_to_string(path).ok())
+            .map(|contents| {
+                contents
+                    .lines()
+                    .filter_map(|line| Highlight::parse(line.trim()).ok())
+                    .collect()
+            })
+            .unwrap_or_default();
+        Highlights { path, entries }
+    }
+
+    /// The highlight words, as they were added
+    pub fn list(&self) -> impl Iterator<Item = &str> {
+        self.entries.iter().map(|entry| entry.spec.as_str())
+    }
+
+    /// Add a word or `/regex/`. Returns false if it was already there.
+    pub fn add(&mut self, spec: &str) -> Result<bool, HighlightError> {
+        let highlight = Highlight::parse(spec)?;
+        if self.entries.iter().any(|entry| entry.spec == spec) {
+            return Ok(false);
+        }
+        self.entries.push(highlight);
+        self.save().map_err(HighlightError::Io)?;
+        Ok(true)
+    }
+
+    /// Remove a word or `/regex/`. Returns false if it wasn't there.
+    pub fn remove(&mut self, spec: &str) -> io::Result<bool> {
+        let before = self.entries.len();
+        self.entries.retain(|entry| entry.spec != spec);
+        if self.entries.len() == before {
+            return Ok(false);
+        }
+        self.save()?;
+        Ok(true)
+    }
+
+    /// Whether `text` contains any of the highlight words
+    pub fn matches(&self, text: &str) -> bool {
+        self.entries.iter().any(|entry| entry.matches(text))
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let contents: String = self.list().map(|spec| format!("{}\n", spec)).collect();
+        state_dir::write_atomic(path, contents.as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_words_and_regexes() {
+        let path =
+            std::env::temp_dir().join(format!("rust_chat_highlights_{}.txt", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let mut highlights = Highlights::load(Some(path.clone()));
+        assert!(highlights.add("deploy").unwrap());
+        assert!(!highlights.add("deploy").unwrap());
+        assert!(highlights.add(r"/build #\d+ failed/").unwrap());
+        assert!(matches!(
+            highlights.add("two words"),
+            Err(HighlightError::NotAWord)
+        ));
+        assert!(matches!(
+            highlights.add("/(unclosed/"),
+            Err(HighlightError::BadRegex(_))
+        ));
+
+        assert!(highlights.matches("Deploy is done"));
+        assert!(!highlights.matches("redeployed it"));
+        assert!(highlights.matches("BUILD #42 FAILED again"));
+        assert!(!highlights.matches("build passed"));
+
+        let reloaded = Highlights::load(Some(path.clone()));
+        assert_eq!(
+            reloaded.list().collect::<Vec<_>>(),
+            vec!["deploy", r"/build #\d+ failed/"]
+        );
+        assert!(highlights.remove("deploy").unwrap());
+        assert!(!highlights.remove("deploy").unwrap());
+        assert!(!highlights.matches("deploy"));
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,3 +1,4 @@
+use crate::scrollback::DEFAULT_LAST_COUNT;
 use shared::commands::client as commands;
 use shared::input::{UserInput, UserInputError};
 
@@ -11,7 +12,14 @@ pub enum ClientUserInput {
         message: String,
     },
     Reply(String),
+    Split, // Send the refused too-long message in parts
+    Last {
+        user: String,
+        count: usize,
+    },
+    Seen(String), // When a user was last connected and last spoke
     Rename(String),
+    Register(String), // Password for our current nickname
     SendFile {
         recipient: String,
         file_path: String,
@@ -23,9 +31,81 @@ pub enum ClientUserInput {
         sender: String,
     },
     Status(Option<String>),
+    Block(Option<String>), // None = list blocked users
+    Unblock(String),
+    JoinRoom(String),
+    PartRoom(Option<String>),  // None = current room
+    RoomSlowmode(u64),         // Seconds between messages in the current room, 0 = off
+    RoomRetention(String),     // History policy for the current room (checked by the server)
+    RoomMaxLength(String),     // Message length limit for the current room (checked by the server)
+    RoomTranslate(String),     // Language to translate the current room into, or "off"
+    RoomInfo(Option<String>),  // None = current room
+    RoomTopic(Option<String>), // None = clear the current room's topic
+    RoomPrivate(bool),         // Make the current room private (invite-only, encrypted) or public
+    RoomInvite(String),        // Let a user join the current private room once
+    RoomAnnounce(String),      // Announcement to the current room (moderators only)
+    RoomArchive {
+        room: Option<String>, // None = current room
+        archive: bool,        // false = unarchive
+    },
+    ListRooms {
+        verbose: bool,  // Our rooms first, with unread counts
+        archived: bool, // Archived rooms instead of the others
+    },
+    ExportRoom {
+        room: String,
+        path: String,
+    },
+    ServerInfo,
+    DebugTrace(bool),      // Protocol tracing on/off
+    Trust(Option<String>), // None = show the server's certificate fingerprint
+    Doctor,
+    HighlightList,
+    HighlightAdd(String),    // A word, or a regex written as /pattern/
+    HighlightRemove(String), // As it was added
+    FilterList,
+    FilterAdd(String),    // "hide <rule>" or "only <rule>"
+    FilterRemove(String), // As it was added
+    FilterClear,
+    QuietJoins, // Toggle
+    ShowHidden, // What /quiet joins held back
     Quit,
 }
 
+impl ClientUserInput {
+    /// Commands handled entirely by the client, which don't count against the server's rate limit
+    pub fn is_local(&self) -> bool {
+        matches!(
+            self,
+            ClientUserInput::Help
+                | ClientUserInput::ListUsers
+                | ClientUserInput::Last { .. }
+                | ClientUserInput::DebugTrace(_)
+                | ClientUserInput::Trust(_)
+                | ClientUserInput::Doctor
+                | ClientUserInput::HighlightList
+                | ClientUserInput::HighlightAdd(_)
+                | ClientUserInput::HighlightRemove(_)
+                | ClientUserInput::FilterList
+                | ClientUserInput::FilterAdd(_)
+                | ClientUserInput::FilterRemove(_)
+                | ClientUserInput::FilterClear
+                | ClientUserInput::QuietJoins
+                | ClientUserInput::ShowHidden
+        )
+    }
+
+    /// What a message, DM or reply says (None for other input)
+    pub fn text(&self) -> Option<&str> {
+        match self {
+            ClientUserInput::Message(text)
+            | ClientUserInput::DirectMessage { message: text, .. }
+            | ClientUserInput::Reply(text) => Some(text),
+            _ => None,
+        }
+    }
+}
+
 impl UserInput for ClientUserInput {
     fn get_quit_command() -> Self {
         ClientUserInput::Quit
@@ -61,6 +141,30 @@ impl TryFrom<&str> for ClientUserInput {
                 let message = parts[1..].join(" ");
                 Ok(ClientUserInput::Reply(message))
             }
+        } else if commands::SPLIT.matches(cmd) {
+            Ok(ClientUserInput::Split)
+        } else if commands::LAST.matches(cmd) {
+            match parts.as_slice() {
+                [_, user] => Ok(ClientUserInput::Last {
+                    user: user.to_string(),
+                    count: DEFAULT_LAST_COUNT,
+                }),
+                [_, user, count] => count
+                    .parse()
+                    .ok()
+                    .filter(|count| *count > 0)
+                    .map(|count| ClientUserInput::Last {
+                        user: user.to_string(),
+                        count,
+                    })
+                    .ok_or(UserInputError::InvalidCommand),
+                _ => Err(UserInputError::InvalidCommand),
+            }
+        } else if commands::SEEN.matches(cmd) {
+            match parts.as_slice() {
+                [_, user] => Ok(ClientUserInput::Seen(user.to_string())),
+                _ => Err(UserInputError::InvalidCommand),
+            }
         } else if commands::RENAME.matches(cmd) {
             if parts.len() < 2 {
                 Err(UserInputError::InvalidCommand)
@@ -68,6 +172,14 @@ impl TryFrom<&str> for ClientUserInput {
                 let new_name = parts[1].to_string();
                 Ok(ClientUserInput::Rename(new_name))
             }
+        } else if commands::REGISTER.matches(cmd) {
+            // Everything after the command, so passwords may contain spaces
+            let password = trimmed[cmd.len()..].trim();
+            if password.is_empty() {
+                Err(UserInputError::InvalidCommand)
+            } else {
+                Ok(ClientUserInput::Register(password.to_string()))
+            }
         } else if commands::SEND.matches(cmd) {
             if parts.len() < 3 {
                 Err(UserInputError::InvalidCommand)
@@ -101,6 +213,128 @@ impl TryFrom<&str> for ClientUserInput {
                 let status = parts[1..].join(" ");
                 Ok(ClientUserInput::Status(Some(status)))
             }
+        } else if commands::BLOCK.matches(cmd) {
+            Ok(ClientUserInput::Block(parts.get(1).map(|u| u.to_string())))
+        } else if commands::UNBLOCK.matches(cmd) {
+            if parts.len() < 2 {
+                Err(UserInputError::InvalidCommand)
+            } else {
+                Ok(ClientUserInput::Unblock(parts[1].to_string()))
+            }
+        } else if commands::JOIN.matches(cmd) {
+            if parts.len() < 2 {
+                Err(UserInputError::InvalidCommand)
+            } else {
+                Ok(ClientUserInput::JoinRoom(parts[1].to_string()))
+            }
+        } else if commands::PART.matches(cmd) {
+            Ok(ClientUserInput::PartRoom(
+                parts.get(1).map(|r| r.to_string()),
+            ))
+        } else if commands::ROOMS.matches(cmd) {
+            let (mut verbose, mut archived) = (false, false);
+            for flag in &parts[1..] {
+                match *flag {
+                    "--verbose" | "-v" => verbose = true,
+                    "--archived" | "-a" => archived = true,
+                    _ => return Err(UserInputError::InvalidCommand),
+                }
+            }
+            Ok(ClientUserInput::ListRooms { verbose, archived })
+        } else if commands::ROOM_SLOWMODE.matches(cmd) {
+            match (parts.get(1).copied(), parts.get(2)) {
+                (Some("slowmode"), Some(seconds)) => seconds
+                    .parse()
+                    .map(ClientUserInput::RoomSlowmode)
+                    .map_err(|_| UserInputError::InvalidCommand),
+                (Some("retention"), Some(policy)) => {
+                    Ok(ClientUserInput::RoomRetention(policy.to_string()))
+                }
+                (Some("maxlength"), Some(limit)) => {
+                    Ok(ClientUserInput::RoomMaxLength(limit.to_string()))
+                }
+                (Some("translate"), Some(lang)) => {
+                    Ok(ClientUserInput::RoomTranslate(lang.to_string()))
+                }
+                (Some("info"), room) => Ok(ClientUserInput::RoomInfo(room.map(|r| r.to_string()))),
+                (Some("topic"), None) => Ok(ClientUserInput::RoomTopic(None)),
+                (Some("topic"), Some(_)) => {
+                    Ok(ClientUserInput::RoomTopic(Some(parts[2..].join(" "))))
+                }
+                (Some("private"), Some(&"on")) => Ok(ClientUserInput::RoomPrivate(true)),
+                (Some("private"), Some(&"off")) => Ok(ClientUserInput::RoomPrivate(false)),
+                (Some("invite"), Some(user)) => Ok(ClientUserInput::RoomInvite(user.to_string())),
+                (Some(command @ ("archive" | "unarchive")), room) => {
+                    Ok(ClientUserInput::RoomArchive {
+                        room: room.map(|r| r.to_string()),
+                        archive: command == "archive",
+                    })
+                }
+                _ => Err(UserInputError::InvalidCommand),
+            }
+        } else if commands::ANNOUNCE.matches(cmd) {
+            if parts.len() < 2 {
+                Err(UserInputError::InvalidCommand)
+            } else {
+                Ok(ClientUserInput::RoomAnnounce(parts[1..].join(" ")))
+            }
+        } else if commands::EXPORT_ROOM.matches(cmd) {
+            if parts.len() < 3 {
+                Err(UserInputError::InvalidCommand)
+            } else {
+                Ok(ClientUserInput::ExportRoom {
+                    room: parts[1].to_string(),
+                    path: parts[2..].join(" "),
+                })
+            }
+        } else if commands::SERVER_INFO.matches(cmd) {
+            match parts.as_slice() {
+                [_, "info"] => Ok(ClientUserInput::ServerInfo),
+                _ => Err(UserInputError::InvalidCommand),
+            }
+        } else if commands::DEBUG.matches(cmd) {
+            match parts.as_slice() {
+                [_, "trace", "on"] => Ok(ClientUserInput::DebugTrace(true)),
+                [_, "trace", "off"] => Ok(ClientUserInput::DebugTrace(false)),
+                _ => Err(UserInputError::InvalidCommand),
+            }
+        } else if commands::TRUST.matches(cmd) {
+            match parts.as_slice() {
+                [_] => Ok(ClientUserInput::Trust(None)),
+                [_, fingerprint] => Ok(ClientUserInput::Trust(Some(fingerprint.to_string()))),
+                _ => Err(UserInputError::InvalidCommand),
+            }
+        } else if commands::DOCTOR.matches(cmd) {
+            Ok(ClientUserInput::Doctor)
+        } else if commands::HIGHLIGHT.matches(cmd) {
+            // A regex may contain spaces, so take the rest of the line
+            let rest = trimmed[cmd.len()..].trim();
+            match rest.split_once(char::is_whitespace) {
+                None if rest.is_empty() => Ok(ClientUserInput::HighlightList),
+                Some(("add", spec)) => Ok(ClientUserInput::HighlightAdd(spec.trim().to_string())),
+                Some(("remove", spec)) => {
+                    Ok(ClientUserInput::HighlightRemove(spec.trim().to_string()))
+                }
+                _ => Err(UserInputError::InvalidCommand),
+            }
+        } else if commands::FILTER.matches(cmd) {
+            let rest = trimmed[cmd.len()..].trim();
+            match rest.split_once(char::is_whitespace) {
+                None if rest.is_empty() => Ok(ClientUserInput::FilterList),
+                None if rest == "clear" => Ok(ClientUserInput::FilterClear),
+                Some(("hide" | "only", _)) => Ok(ClientUserInput::FilterAdd(rest.to_string())),
+                Some(("remove", filter)) => {
+                    Ok(ClientUserInput::FilterRemove(filter.trim().to_string()))
+                }
+                _ => Err(UserInputError::InvalidCommand),
+            }
+        } else if commands::QUIET.matches(cmd) {
+            match parts.as_slice() {
+                [_, "joins"] => Ok(ClientUserInput::QuietJoins),
+                _ => Err(UserInputError::InvalidCommand),
+            }
+        } else if commands::SHOW.matches(cmd) {
+            Ok(ClientUserInput::ShowHidden)
         } else if trimmed.starts_with('/') {
             Err(UserInputError::InvalidCommand)
         } else {
@@ -257,6 +491,37 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_register_command() {
+        let input = ClientUserInput::try_from("/register  correct horse battery ");
+        assert!(
+            matches!(input, Ok(ClientUserInput::Register(password)) if password == "correct horse battery")
+        );
+        assert!(ClientUserInput::try_from("/register").is_err());
+    }
+
+    #[test]
+    fn test_last_command() {
+        let input = ClientUserInput::try_from("/last alice");
+        assert!(matches!(
+            input,
+            Ok(ClientUserInput::Last { user, count }) if user == "alice" && count == DEFAULT_LAST_COUNT
+        ));
+        let input = ClientUserInput::try_from("/last alice 3");
+        assert!(matches!(input, Ok(ClientUserInput::Last { count: 3, .. })));
+        assert!(ClientUserInput::try_from("/last").is_err());
+        assert!(ClientUserInput::try_from("/last alice 0").is_err());
+        assert!(ClientUserInput::try_from("/last alice lots").is_err());
+    }
+
+    #[test]
+    fn test_seen_command() {
+        let input = ClientUserInput::try_from("/seen bob");
+        assert!(matches!(input, Ok(ClientUserInput::Seen(user)) if user == "bob"));
+        assert!(ClientUserInput::try_from("/seen").is_err());
+        assert!(ClientUserInput::try_from("/seen bob carol").is_err());
+    }
+
     #[test]
     fn test_status_command_with_message() {
         let input = ClientUserInput::try_from("/status AFK for lunch");
@@ -274,4 +539,245 @@ mod tests {
         assert!(input.is_ok());
         assert!(matches!(input.unwrap(), ClientUserInput::Status(None)));
     }
+
+    #[test]
+    fn test_join_command() {
+        let input = ClientUserInput::try_from("/join #ops");
+        match input.unwrap() {
+            ClientUserInput::JoinRoom(room) => assert_eq!(room, "#ops"),
+            _ => panic!("Expected JoinRoom variant"),
+        }
+        assert!(ClientUserInput::try_from("/join").is_err());
+    }
+
+    #[test]
+    fn test_part_command() {
+        assert!(matches!(
+            ClientUserInput::try_from("/part").unwrap(),
+            ClientUserInput::PartRoom(None)
+        ));
+        match ClientUserInput::try_from("/part ops").unwrap() {
+            ClientUserInput::PartRoom(Some(room)) => assert_eq!(room, "ops"),
+            _ => panic!("Expected PartRoom variant with room"),
+        }
+    }
+
+    #[test]
+    fn test_block_commands() {
+        assert!(matches!(
+            ClientUserInput::try_from("/block").unwrap(),
+            ClientUserInput::Block(None)
+        ));
+        match ClientUserInput::try_from("/block bob").unwrap() {
+            ClientUserInput::Block(Some(user)) => assert_eq!(user, "bob"),
+            _ => panic!("Expected Block variant with user"),
+        }
+        match ClientUserInput::try_from("/unblock bob").unwrap() {
+            ClientUserInput::Unblock(user) => assert_eq!(user, "bob"),
+            _ => panic!("Expected Unblock variant"),
+        }
+        assert!(ClientUserInput::try_from("/unblock").is_err());
+    }
+
+    #[test]
+    fn test_room_slowmode_command() {
+        assert!(matches!(
+            ClientUserInput::try_from("/room slowmode 10").unwrap(),
+            ClientUserInput::RoomSlowmode(10)
+        ));
+        assert!(matches!(
+            ClientUserInput::try_from("/room slowmode 0").unwrap(),
+            ClientUserInput::RoomSlowmode(0)
+        ));
+        assert!(ClientUserInput::try_from("/room slowmode").is_err());
+        assert!(ClientUserInput::try_from("/room slowmode soon").is_err());
+        assert!(ClientUserInput::try_from("/room").is_err());
+    }
+
+    #[test]
+    fn test_room_retention_and_info() {
+        assert!(matches!(
+            ClientUserInput::try_from("/room retention 7d"),
+            Ok(ClientUserInput::RoomRetention(policy)) if policy == "7d"
+        ));
+        assert!(ClientUserInput::try_from("/room retention").is_err());
+        assert!(matches!(
+            ClientUserInput::try_from("/room maxlength 200"),
+            Ok(ClientUserInput::RoomMaxLength(limit)) if limit == "200"
+        ));
+        assert!(ClientUserInput::try_from("/room maxlength").is_err());
+        assert!(matches!(
+            ClientUserInput::try_from("/room translate pt-BR"),
+            Ok(ClientUserInput::RoomTranslate(lang)) if lang == "pt-BR"
+        ));
+        assert!(matches!(
+            ClientUserInput::try_from("/room info"),
+            Ok(ClientUserInput::RoomInfo(None))
+        ));
+        assert!(matches!(
+            ClientUserInput::try_from("/room info #ops"),
+            Ok(ClientUserInput::RoomInfo(Some(room))) if room == "#ops"
+        ));
+    }
+
+    #[test]
+    fn test_rooms_and_topic() {
+        assert!(matches!(
+            ClientUserInput::try_from("/rooms"),
+            Ok(ClientUserInput::ListRooms {
+                verbose: false,
+                archived: false
+            })
+        ));
+        assert!(matches!(
+            ClientUserInput::try_from("/rooms --verbose"),
+            Ok(ClientUserInput::ListRooms {
+                verbose: true,
+                archived: false
+            })
+        ));
+        assert!(matches!(
+            ClientUserInput::try_from("/rooms --archived -v"),
+            Ok(ClientUserInput::ListRooms {
+                verbose: true,
+                archived: true
+            })
+        ));
+        assert!(matches!(
+            ClientUserInput::try_from("/room archive #old"),
+            Ok(ClientUserInput::RoomArchive { room: Some(room), archive: true }) if room == "#old"
+        ));
+        assert!(matches!(
+            ClientUserInput::try_from("/room unarchive"),
+            Ok(ClientUserInput::RoomArchive {
+                room: None,
+                archive: false
+            })
+        ));
+        assert!(ClientUserInput::try_from("/rooms --loud").is_err());
+        assert!(matches!(
+            ClientUserInput::try_from("/room topic Deploys and incidents"),
+            Ok(ClientUserInput::RoomTopic(Some(topic))) if topic == "Deploys and incidents"
+        ));
+        assert!(matches!(
+            ClientUserInput::try_from("/room topic"),
+            Ok(ClientUserInput::RoomTopic(None))
+        ));
+        assert!(matches!(
+            ClientUserInput::try_from("/announce Deploy freeze until 17:00"),
+            Ok(ClientUserInput::RoomAnnounce(text)) if text == "Deploy freeze until 17:00"
+        ));
+        assert!(ClientUserInput::try_from("/announce").is_err());
+        assert!(matches!(
+            ClientUserInput::try_from("/room private on"),
+            Ok(ClientUserInput::RoomPrivate(true))
+        ));
+        assert!(ClientUserInput::try_from("/room private maybe").is_err());
+        assert!(matches!(
+            ClientUserInput::try_from("/room invite bob"),
+            Ok(ClientUserInput::RoomInvite(user)) if user == "bob"
+        ));
+    }
+
+    #[test]
+    fn test_export_room() {
+        assert!(matches!(
+            ClientUserInput::try_from("/export-room #ops ops log.json"),
+            Ok(ClientUserInput::ExportRoom { room, path }) if room == "#ops" && path == "ops log.json"
+        ));
+        assert!(ClientUserInput::try_from("/export-room #ops").is_err());
+    }
+
+    #[test]
+    fn test_debug_trace() {
+        assert!(matches!(
+            ClientUserInput::try_from("/debug trace on"),
+            Ok(ClientUserInput::DebugTrace(true))
+        ));
+        assert!(matches!(
+            ClientUserInput::try_from("/debug trace off"),
+            Ok(ClientUserInput::DebugTrace(false))
+        ));
+        assert!(ClientUserInput::try_from("/debug trace").is_err());
+        assert!(ClientUserInput::try_from("/debug").is_err());
+    }
+
+    #[test]
+    fn test_server_info() {
+        assert!(matches!(
+            ClientUserInput::try_from("/server info"),
+            Ok(ClientUserInput::ServerInfo)
+        ));
+        assert!(ClientUserInput::try_from("/server").is_err());
+    }
+
+    #[test]
+    fn test_trust() {
+        assert!(matches!(
+            ClientUserInput::try_from("/trust"),
+            Ok(ClientUserInput::Trust(None))
+        ));
+        assert!(matches!(
+            ClientUserInput::try_from("/trust AB:CD"),
+            Ok(ClientUserInput::Trust(Some(fingerprint))) if fingerprint == "AB:CD"
+        ));
+        assert!(ClientUserInput::try_from("/trust AB CD").is_err());
+    }
+
+    #[test]
+    fn test_doctor_is_local() {
+        let doctor = ClientUserInput::try_from("/doctor").unwrap();
+        assert!(matches!(doctor, ClientUserInput::Doctor));
+        assert!(doctor.is_local());
+    }
+
+    #[test]
+    fn test_highlight_command() {
+        assert!(matches!(
+            ClientUserInput::try_from("/highlight").unwrap(),
+            ClientUserInput::HighlightList
+        ));
+        match ClientUserInput::try_from("/highlight add /deploy (failed|done)/").unwrap() {
+            ClientUserInput::HighlightAdd(spec) => assert_eq!(spec, "/deploy (failed|done)/"),
+            _ => panic!("Expected HighlightAdd variant"),
+        }
+        let remove = ClientUserInput::try_from("/highlight remove deploy").unwrap();
+        assert!(matches!(&remove, ClientUserInput::HighlightRemove(spec) if spec == "deploy"));
+        assert!(remove.is_local());
+        assert!(ClientUserInput::try_from("/highlight add").is_err());
+        assert!(ClientUserInput::try_from("/highlight deploy").is_err());
+    }
+
+    #[test]
+    fn test_filter_command() {
+        assert!(matches!(
+            ClientUserInput::try_from("/filter").unwrap(),
+            ClientUserInput::FilterList
+        ));
+        let hide = ClientUserInput::try_from("/filter hide join/leave").unwrap();
+        assert!(matches!(&hide, ClientUserInput::FilterAdd(spec) if spec == "hide join/leave"));
+        assert!(hide.is_local());
+        assert!(matches!(
+            ClientUserInput::try_from("/filter remove only room:ops").unwrap(),
+            ClientUserInput::FilterRemove(spec) if spec == "only room:ops"
+        ));
+        assert!(matches!(
+            ClientUserInput::try_from("/filter clear").unwrap(),
+            ClientUserInput::FilterClear
+        ));
+        assert!(ClientUserInput::try_from("/filter show room:ops").is_err());
+    }
+
+    #[test]
+    fn test_quiet_joins() {
+        let quiet = ClientUserInput::try_from("/quiet joins").unwrap();
+        assert!(matches!(quiet, ClientUserInput::QuietJoins));
+        assert!(quiet.is_local());
+        assert!(matches!(
+            ClientUserInput::try_from("/show").unwrap(),
+            ClientUserInput::ShowHidden
+        ));
+        assert!(ClientUserInput::try_from("/quiet").is_err());
+        assert!(ClientUserInput::try_from("/quiet bots").is_err());
+    }
 }
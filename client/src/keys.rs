@@ -0,0 +1,144 @@
+//! Signing keys: our own ed25519 key (created the first time we sign on a server)
+//! and the public keys of other users, pinned the first time we see them.
+//! Also the lineage secret behind our client fingerprint on each server.
+
+use crate::state_dir;
+use rand::RngCore;
+use rand::rngs::OsRng;
+use shared::signing::SigningKey;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Load our signing key, generating and saving a new one if there is none yet
+pub fn load_or_create(path: &Path) -> io::Result<SigningKey> {
+    if let Ok(contents) = fs::read_to_string(path) {
+        let bytes: [u8; 32] = hex::decode(contents.trim())
+            .ok()
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "Invalid signing key in {} (/doctor sets it aside)",
+                        path.display()
+                    ),
+                )
+            })?;
+        return Ok(SigningKey::from_bytes(&bytes));
+    }
+
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    let key = SigningKey::from_bytes(&bytes);
+    // The key is a secret - keep it readable by us only
+    state_dir::write_secret(path, hex::encode(bytes).as_bytes())?;
+    Ok(key)
+}
+
+/// Load the lineage secret our fingerprint is derived from, creating it on first use
+pub fn load_or_create_lineage(path: &Path) -> io::Result<String> {
+    if let Ok(contents) = fs::read_to_string(path)
+        && !contents.trim().is_empty()
+    {
+        return Ok(contents.trim().to_string());
+    }
+
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    let lineage = hex::encode(bytes);
+    state_dir::write_secret(path, lineage.as_bytes())?;
+    Ok(lineage)
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Trust {
+    /// First key seen for this user - now pinned
+    New,
+    /// Matches the pinned key
+    Trusted,
+    /// Differs from the pinned key: possibly someone else using the name
+    Changed,
+}
+
+/// Public keys pinned per username (trust on first use)
+pub struct KnownKeys {
+    /// None = pins only live in memory
+    path: Option<PathBuf>,
+    keys: HashMap<String, String>,
+}
+
+impl KnownKeys {
+    pub fn load(path: Option<PathBuf>) -> Self {
+        let keys = path
+            .as_ref()
+            .and_then(|path| fs::read_to_string(path).ok())
+            .map(|contents| {
+                // Format: username \t public_key (one per line)
+                contents
+                    .lines()
+                    .filter_map(|line| line.split_once('\t'))
+                    .map(|(user, key)| (user.to_string(), key.to_string()))
+                    .collect()
+            })
+            .unwrap_or_default();
+        KnownKeys { path, keys }
+    }
+
+    /// Compare a verified public key with the one pinned for `user`
+    pub fn check(&mut self, user: &str, public_key: &str) -> Trust {
+        match self.keys.get(user) {
+            Some(pinned) if pinned == public_key => Trust::Trusted,
+            Some(_) => Trust::Changed,
+            None => {
+                self.keys.insert(user.to_string(), public_key.to_string());
+                self.save();
+                Trust::New
+            }
+        }
+    }
+
+    fn save(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let mut contents = String::new();
+        for (user, key) in &self.keys {
+            contents.push_str(&format!("{}\t{}\n", user, key));
+        }
+        let _ = state_dir::write_atomic(path, contents.as_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("rust_chat_keys_{}_{}", name, std::process::id()))
+    }
+
+    #[test]
+    fn test_key_is_created_once() {
+        let path = temp_path("signing");
+        let first = load_or_create(&path).unwrap();
+        let second = load_or_create(&path).unwrap();
+        assert_eq!(first.to_bytes(), second.to_bytes());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_trust_on_first_use() {
+        let path = temp_path("known");
+        let mut known = KnownKeys::load(Some(path.clone()));
+        assert_eq!(known.check("alice", "aaaa"), Trust::New);
+        assert_eq!(known.check("alice", "aaaa"), Trust::Trusted);
+        assert_eq!(known.check("alice", "bbbb"), Trust::Changed);
+
+        // Pins survive a restart
+        let mut reloaded = KnownKeys::load(Some(path.clone()));
+        assert_eq!(reloaded.check("alice", "aaaa"), Trust::Trusted);
+        fs::remove_file(path).unwrap();
+    }
+}
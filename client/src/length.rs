@@ -0,0 +1,63 @@
+//! Message length checked against the size the server advertises, shown as a meter
+//! while typing. Text that's too long is sent in parts (see shared::parts).
+
+use shared::commands::client as commands;
+
+/// The part of a typed line that counts against the limit: the whole line for a
+/// message, the text of a /dm or /r, nothing for other commands
+pub fn message_text(line: &str) -> Option<&str> {
+    let line = line.trim();
+    if !line.starts_with('/') {
+        return Some(line);
+    }
+    let (cmd, rest) = line.split_once(char::is_whitespace)?;
+    if commands::DM.matches(cmd) {
+        let (_, text) = rest.trim_start().split_once(char::is_whitespace)?;
+        Some(text.trim_start())
+    } else if commands::REPLY.matches(cmd) {
+        Some(rest.trim_start())
+    } else {
+        None
+    }
+}
+
+/// `[900/1024 bytes]`, once the text is getting close to `max` (0 = no limit known)
+pub fn meter(text: &str, max: usize) -> Option<String> {
+    let bytes = text.len();
+    if max == 0 || bytes * 4 < max * 3 {
+        return None;
+    }
+    let chars = text.chars().count();
+    let count = if chars == bytes {
+        format!("{}/{} bytes", bytes, max)
+    } else {
+        format!("{} chars, {}/{} bytes", chars, bytes, max)
+    };
+    Some(if bytes > max {
+        format!("  [{} - too long, /split sends it in parts]", count)
+    } else {
+        format!("  [{}]", count)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_meter() {
+        assert_eq!(message_text("  hello there "), Some("hello there"));
+        assert_eq!(message_text("/dm bob  see you"), Some("see you"));
+        assert_eq!(message_text("/r thanks"), Some("thanks"));
+        assert_eq!(message_text("/join ops"), None);
+
+        assert_eq!(meter("short", 100), None);
+        assert_eq!(meter(&"x".repeat(80), 100).unwrap(), "  [80/100 bytes]");
+        assert!(meter(&"x".repeat(101), 100).unwrap().contains("too long"));
+        assert_eq!(
+            meter(&"é".repeat(40), 100).unwrap(),
+            "  [40 chars, 80/100 bytes]"
+        );
+        assert_eq!(meter(&"x".repeat(500), 0), None);
+    }
+}
@@ -1,23 +1,70 @@
+mod cli;
 mod client;
 mod completer;
+mod delivery;
+mod doctor;
+mod drafts;
+mod events;
+mod export;
+mod filters;
+mod gaps;
+mod highlight;
 mod input;
+mod keys;
+mod length;
+mod notify;
+mod on_connect;
+mod paths;
+mod quiet;
 mod readline_helper;
+mod repeats;
+mod scrollback;
+mod sent;
+mod setup;
+mod startup;
+mod state_dir;
+mod status_bar;
+mod title;
+mod trust;
 
-use client::ChatClient;
+use cli::CliArgs;
+use client::{ChatClient, ClientSettings};
+use gaps::DEFAULT_GAP;
+use repeats::RepeatPolicy;
+use scrollback::DEFAULT_SCROLLBACK_LINES;
+use setup::Profile;
 use shared::logger;
+use shared::sender_class::SenderClass;
+use shared::socket::{Keepalive, SocketOptions};
+use startup::{
+    ClientIdentity, IpPreference, Proxy, ReconnectPolicy, Recovery, Stage, StartupError, Timeouts,
+};
+use state_dir::Migration;
 use std::env;
-use std::io::{self, Write};
+use std::io::{self, IsTerminal, Write};
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::time::Duration;
+use title::Multiplexer;
+use tokio::time::timeout;
 
 const DEFAULT_SERVER: &str = "tls://milesrust.chat:8443";
 const DEFAULT_NAME: &str = "Guest";
+/// Largest socket buffer CHAT_RECV_BUFFER_KB and CHAT_SEND_BUFFER_KB accept (64 MB)
+const MAX_SOCKET_BUFFER: u64 = 64 << 20;
 
-/// Restore terminal to a sane state (cursor visible, line buffered, echo on)
-fn restore_terminal() {
-    // Show cursor (ANSI escape sequence)
-    print!("\x1B[?25h");
-    // Reset all attributes
-    print!("\x1B[0m");
-    let _ = io::stdout().flush();
+/// Restore terminal to a sane state (cursor visible, line buffered, echo on).
+/// With JSON output stdout isn't the terminal's, so no escape sequences are written.
+fn restore_terminal(json_output: bool) {
+    if !json_output {
+        // Show cursor (ANSI escape sequence)
+        print!("\x1B[?25h");
+        // Reset all attributes
+        print!("\x1B[0m");
+        // Let the whole screen scroll again (the status bar reserves the bottom row)
+        print!("\x1B[r");
+        let _ = io::stdout().flush();
+    }
 
     // Also restore terminal from raw mode using stty
     // This ensures the terminal is fully restored even if rustyline
@@ -29,35 +76,424 @@ fn restore_terminal() {
     }
 }
 
+/// Bring the state directory up to this client's layout before anything reads it
+fn migrate_state_dir() {
+    let Some(dir) = paths::state_dir() else {
+        return;
+    };
+    match state_dir::migrate(&dir) {
+        Ok(Migration::Upgraded { from }) => logger::log_info(&format!(
+            "Moved client state in {} from layout version {} to {}",
+            dir.display(),
+            from,
+            state_dir::LAYOUT_VERSION
+        )),
+        Ok(Migration::Newer(version)) => logger::log_warning(&format!(
+            "Client state in {} is in layout version {}, from a newer client - some of it may not be found",
+            dir.display(),
+            version
+        )),
+        Ok(Migration::Current | Migration::Created) => {}
+        Err(e) => logger::log_warning(&format!(
+            "Couldn't update client state in {}: {} (/doctor checks it)",
+            dir.display(),
+            e
+        )),
+    }
+}
+
 #[tokio::main]
-async fn main() -> io::Result<()> {
-    let (chat_server, chat_name) = get_server_info()?;
+async fn main() -> io::Result<ExitCode> {
+    const CHAT_SCROLLBACK_LINES_ENV_VAR: &str = "CHAT_SCROLLBACK_LINES";
+    const CHAT_NOTIFY_COMMAND_ENV_VAR: &str = "CHAT_NOTIFY_COMMAND";
+    const CHAT_SIGN_MESSAGES_ENV_VAR: &str = "CHAT_SIGN_MESSAGES";
+    const CHAT_PASSWORD_ENV_VAR: &str = "CHAT_PASSWORD";
+    const CHAT_TOKEN_ENV_VAR: &str = "CHAT_TOKEN";
+    const CHAT_IP_PREFERENCE_ENV_VAR: &str = "CHAT_IP_PREFERENCE";
+    const CHAT_FINGERPRINT_ENV_VAR: &str = "CHAT_FINGERPRINT";
+    const CHAT_STATUS_BAR_ENV_VAR: &str = "CHAT_STATUS_BAR";
+    const CHAT_TERMINAL_TITLE_ENV_VAR: &str = "CHAT_TERMINAL_TITLE";
+    const CHAT_MULTIPLEXER_ENV_VAR: &str = "CHAT_MULTIPLEXER";
+    const CHAT_AUTO_SPLIT_ENV_VAR: &str = "CHAT_AUTO_SPLIT";
+    const CHAT_SELF_ECHO_ENV_VAR: &str = "CHAT_SELF_ECHO";
+    const CHAT_SAVE_DRAFTS_ENV_VAR: &str = "CHAT_SAVE_DRAFTS";
+    const CHAT_REPEAT_THRESHOLD_ENV_VAR: &str = "CHAT_REPEAT_THRESHOLD";
+    const CHAT_REPEAT_WINDOW_ENV_VAR: &str = "CHAT_REPEAT_WINDOW";
+    const CHAT_HIDE_ENV_VAR: &str = "CHAT_HIDE";
+    const CHAT_GAP_MINUTES_ENV_VAR: &str = "CHAT_GAP_MINUTES";
+    const CHAT_QUIET_JOINS_ENV_VAR: &str = "CHAT_QUIET_JOINS";
+    const CHAT_TRANSLATIONS_ENV_VAR: &str = "CHAT_TRANSLATIONS";
+    const CHAT_CLIENT_CERT_ENV_VAR: &str = "CHAT_CLIENT_CERT";
+    const CHAT_CLIENT_KEY_ENV_VAR: &str = "CHAT_CLIENT_KEY";
+    const CHAT_PROXY_ENV_VAR: &str = "CHAT_PROXY";
+    const CHAT_CONNECT_TIMEOUT_ENV_VAR: &str = "CHAT_CONNECT_TIMEOUT";
+    const CHAT_READ_TIMEOUT_ENV_VAR: &str = "CHAT_READ_TIMEOUT";
+    const CHAT_WRITE_TIMEOUT_ENV_VAR: &str = "CHAT_WRITE_TIMEOUT";
+    const CHAT_KEEPALIVE_INTERVAL_ENV_VAR: &str = "CHAT_KEEPALIVE_INTERVAL";
+    const CHAT_KEEPALIVE_PROBE_INTERVAL_ENV_VAR: &str = "CHAT_KEEPALIVE_PROBE_INTERVAL";
+    const CHAT_KEEPALIVE_PROBES_ENV_VAR: &str = "CHAT_KEEPALIVE_PROBES";
+    const CHAT_TCP_NODELAY_ENV_VAR: &str = "CHAT_TCP_NODELAY";
+    const CHAT_RECV_BUFFER_KB_ENV_VAR: &str = "CHAT_RECV_BUFFER_KB";
+    const CHAT_SEND_BUFFER_KB_ENV_VAR: &str = "CHAT_SEND_BUFFER_KB";
+    const CHAT_RECONNECT_BACKOFF_ENV_VAR: &str = "CHAT_RECONNECT_BACKOFF";
+    const CHAT_RECONNECT_MAX_BACKOFF_ENV_VAR: &str = "CHAT_RECONNECT_MAX_BACKOFF";
+    const CHAT_RECONNECT_MAX_RETRIES_ENV_VAR: &str = "CHAT_RECONNECT_MAX_RETRIES";
+    const CHAT_RECONNECT_AFTER_KICK_ENV_VAR: &str = "CHAT_RECONNECT_AFTER_KICK";
 
-    let mut client = ChatClient::new(&chat_server, chat_name)
-        .await
-        .map_err(|e| {
-            logger::log_error(&format!("Failed to create client: {:?}", e));
-            io::Error::other(format!("Failed to create client: {e:?}"))
-        })?;
+    let args = match CliArgs::parse(env::args().skip(1)) {
+        Ok(args) => args,
+        Err(e) => {
+            logger::log_error(&e);
+            eprintln!("{}", cli::USAGE);
+            return Ok(ExitCode::from(64)); // EX_USAGE
+        }
+    };
+    if args.help {
+        println!("{}", cli::USAGE);
+        return Ok(ExitCode::SUCCESS);
+    }
+    let json_output = args.json_output();
+    if json_output {
+        // stdout carries only JSON events; logs go to stderr, uncolored
+        colored::control::set_override(false);
+        logger::set_output(|line| eprintln!("{}", line));
+    } else {
+        logger::enable_wrapping();
+    }
+    migrate_state_dir();
 
-    client
-        .join_server()
-        .await
-        .map_err(|e| io::Error::other(format!("Failed to join server: {e:?}")))?;
+    // A bot token logs in as its bot, in place of a password
+    let token = args.token.clone().or_else(|| {
+        env::var(CHAT_TOKEN_ENV_VAR)
+            .ok()
+            .filter(|val| !val.is_empty())
+    });
+    if let Some(token) = &token
+        && cli::bot_name(token).is_none()
+    {
+        logger::log_error(
+            "A bot token looks like <bot>.<secret> - copy it whole from /token create",
+        );
+        return Ok(ExitCode::from(64)); // EX_USAGE
+    }
+    let scrollback_lines = env::var(CHAT_SCROLLBACK_LINES_ENV_VAR)
+        .ok()
+        .and_then(|val| val.parse::<usize>().ok())
+        .unwrap_or(DEFAULT_SCROLLBACK_LINES);
+    // Sign outgoing messages so others can verify they really come from us
+    let sign_messages = env::var(CHAT_SIGN_MESSAGES_ENV_VAR)
+        .map(|val| matches!(val.to_lowercase().as_str(), "1" | "true" | "yes" | "on"))
+        .unwrap_or(false);
+    // Password for a nickname registered on the server
+    let password = token.clone().or_else(|| {
+        env::var(CHAT_PASSWORD_ENV_VAR)
+            .ok()
+            .filter(|val| !val.is_empty())
+    });
+    // Address family to try first when the server resolves to both IPv4 and IPv6
+    let ip_preference = match env::var(CHAT_IP_PREFERENCE_ENV_VAR) {
+        Ok(val) => IpPreference::parse(&val).unwrap_or_else(|| {
+            logger::log_warning(&format!(
+                "Unknown {} '{}' - using 'system'",
+                CHAT_IP_PREFERENCE_ENV_VAR, val
+            ));
+            IpPreference::System
+        }),
+        Err(_) => IpPreference::System,
+    };
+    // Let servers recognise this client across IP and nickname changes (on unless disabled)
+    let send_fingerprint = env::var(CHAT_FINGERPRINT_ENV_VAR)
+        .map(|val| !matches!(val.to_lowercase().as_str(), "0" | "false" | "no" | "off"))
+        .unwrap_or(true);
+    // Connection health pinned to the bottom row of the terminal (on unless disabled)
+    let status_bar = !json_output
+        && env::var(CHAT_STATUS_BAR_ENV_VAR)
+            .map(|val| !matches!(val.to_lowercase().as_str(), "0" | "false" | "no" | "off"))
+            .unwrap_or(true);
+    // Room and unread count in the terminal's title (on unless disabled)
+    let terminal_title = !json_output
+        && env::var(CHAT_TERMINAL_TITLE_ENV_VAR)
+            .map(|val| !matches!(val.to_lowercase().as_str(), "0" | "false" | "no" | "off"))
+            .unwrap_or(true);
+    // tmux or screen window naming and bell flags on mentions (off unless set)
+    let multiplexer = match env::var(CHAT_MULTIPLEXER_ENV_VAR) {
+        Ok(val) => Multiplexer::parse(&val).unwrap_or_else(|| {
+            logger::log_warning(&format!(
+                "Unknown {} '{}' - use tmux, screen, auto or off",
+                CHAT_MULTIPLEXER_ENV_VAR, val
+            ));
+            None
+        }),
+        Err(_) => None,
+    };
+    // Send messages longer than the server allows in parts without asking first
+    let auto_split = env::var(CHAT_AUTO_SPLIT_ENV_VAR)
+        .map(|val| matches!(val.to_lowercase().as_str(), "1" | "true" | "yes" | "on"))
+        .unwrap_or(false);
+    // Show our messages once the server relays them back rather than once it has them
+    let self_echo = env::var(CHAT_SELF_ECHO_ENV_VAR)
+        .map(|val| matches!(val.to_lowercase().as_str(), "1" | "true" | "yes" | "on"))
+        .unwrap_or(false);
+    // Keep what's been typed for each room across restarts
+    let save_drafts = env::var(CHAT_SAVE_DRAFTS_ENV_VAR)
+        .map(|val| matches!(val.to_lowercase().as_str(), "1" | "true" | "yes" | "on"))
+        .unwrap_or(false);
+    // Copies of one message from a sender shown before the rest are collapsed into a
+    // count (0 = never), and the most seconds between copies that still counts
+    let defaults = RepeatPolicy::default();
+    let repeats = RepeatPolicy {
+        threshold: number_var(CHAT_REPEAT_THRESHOLD_ENV_VAR)
+            .map_or(defaults.threshold, |count| count as usize),
+        window: number_var(CHAT_REPEAT_WINDOW_ENV_VAR)
+            .filter(|secs| *secs > 0)
+            .map_or(defaults.window, Duration::from_secs),
+    };
+    // Senders not to show, by class: "bots", "server", "notices" or "users"
+    let mut hidden_classes = Vec::new();
+    for class in env::var(CHAT_HIDE_ENV_VAR).unwrap_or_default().split(',') {
+        match SenderClass::parse(class.trim()) {
+            Some(class) => hidden_classes.push(class),
+            None if class.trim().is_empty() => {}
+            None => logger::log_warning(&format!(
+                "Unknown {} class '{}' - use users, bots, server or notices",
+                CHAT_HIDE_ENV_VAR,
+                class.trim()
+            )),
+        }
+    }
+    // Machine translations of room messages, from servers with a translation hook
+    let translations = env::var(CHAT_TRANSLATIONS_ENV_VAR)
+        .map(|val| !matches!(val.to_lowercase().as_str(), "0" | "false" | "no" | "off"))
+        .unwrap_or(true);
+    // Minutes of quiet before a dated divider goes in front of the next message (0 = never)
+    let gap = match number_var(CHAT_GAP_MINUTES_ENV_VAR) {
+        Some(0) => None,
+        Some(minutes) => Some(Duration::from_secs(minutes * 60)),
+        None => Some(DEFAULT_GAP),
+    };
+    // Start with joins, leaves and renames hidden behind a count, as /quiet joins does
+    let quiet_joins = env::var(CHAT_QUIET_JOINS_ENV_VAR)
+        .map(|val| matches!(val.to_lowercase().as_str(), "1" | "true" | "yes" | "on"))
+        .unwrap_or(false);
+    // Certificate to log in with on servers that accept them (mutual TLS)
+    let identity = match (
+        env::var(CHAT_CLIENT_CERT_ENV_VAR),
+        env::var(CHAT_CLIENT_KEY_ENV_VAR),
+    ) {
+        (Ok(cert_path), Ok(key_path)) => match ClientIdentity::load(&cert_path, &key_path) {
+            Ok(identity) => {
+                logger::log_info(&format!("Using client certificate {}", cert_path));
+                Some(Arc::new(identity))
+            }
+            Err(e) => {
+                logger::log_error(&format!("Failed to load client certificate: {}", e));
+                return Ok(ExitCode::from(66)); // EX_NOINPUT
+            }
+        },
+        _ => None,
+    };
+    // SOCKS5 proxy to connect through, e.g. "tor" for a local Tor (needed for .onion servers)
+    let proxy = match env::var(CHAT_PROXY_ENV_VAR) {
+        Ok(val) if !val.is_empty() => match Proxy::parse(&val) {
+            Some(proxy) => Some(proxy),
+            None => {
+                logger::log_error(&format!(
+                    "Invalid {} '{}' - use socks5://host:port or tor",
+                    CHAT_PROXY_ENV_VAR, val
+                ));
+                return Ok(ExitCode::from(64)); // EX_USAGE
+            }
+        },
+        _ => None,
+    };
+    // Timeouts in seconds; 0 turns off the read and write timeouts and keepalive
+    let defaults = Timeouts::default();
+    let timeouts = Timeouts {
+        connect: number_var(CHAT_CONNECT_TIMEOUT_ENV_VAR)
+            .filter(|secs| *secs > 0)
+            .map_or(defaults.connect, Duration::from_secs),
+        read: number_var(CHAT_READ_TIMEOUT_ENV_VAR).map_or(defaults.read, enabled_secs),
+        write: number_var(CHAT_WRITE_TIMEOUT_ENV_VAR).map_or(defaults.write, enabled_secs),
+        // Small frames go out at once unless Nagle's algorithm is asked for; keepalive
+        // probes start after CHAT_KEEPALIVE_INTERVAL idle seconds; buffers are in KB
+        socket: SocketOptions {
+            nodelay: env::var(CHAT_TCP_NODELAY_ENV_VAR)
+                .map(|val| !matches!(val.to_lowercase().as_str(), "0" | "false" | "no" | "off"))
+                .unwrap_or(defaults.socket.nodelay),
+            keepalive: number_var(CHAT_KEEPALIVE_INTERVAL_ENV_VAR)
+                .map_or(defaults.socket.keepalive.map(|k| k.idle), enabled_secs)
+                .map(|idle| Keepalive {
+                    idle,
+                    interval: number_var(CHAT_KEEPALIVE_PROBE_INTERVAL_ENV_VAR)
+                        .and_then(enabled_secs),
+                    probes: number_var(CHAT_KEEPALIVE_PROBES_ENV_VAR)
+                        .filter(|count| *count > 0)
+                        .map(|count| count.min(u32::MAX as u64) as u32),
+                }),
+            recv_buffer: buffer_var(CHAT_RECV_BUFFER_KB_ENV_VAR),
+            send_buffer: buffer_var(CHAT_SEND_BUFFER_KB_ENV_VAR),
+        },
+    };
+    // Reconnect backoff in seconds, how many attempts before giving up (0 = never), and
+    // whether to come back when a kick's cooldown or a ban runs out
+    let defaults = ReconnectPolicy::default();
+    let reconnect = ReconnectPolicy {
+        backoff: number_var(CHAT_RECONNECT_BACKOFF_ENV_VAR)
+            .filter(|secs| *secs > 0)
+            .map_or(defaults.backoff, Duration::from_secs),
+        max_backoff: number_var(CHAT_RECONNECT_MAX_BACKOFF_ENV_VAR)
+            .filter(|secs| *secs > 0)
+            .map_or(defaults.max_backoff, Duration::from_secs),
+        max_retries: number_var(CHAT_RECONNECT_MAX_RETRIES_ENV_VAR)
+            .map_or(defaults.max_retries, |count| {
+                u32::try_from(count).ok().filter(|count| *count > 0)
+            }),
+        after_kick: env::var(CHAT_RECONNECT_AFTER_KICK_ENV_VAR)
+            .map(|val| matches!(val.to_lowercase().as_str(), "1" | "true" | "yes" | "on"))
+            .unwrap_or(defaults.after_kick),
+    };
+
+    // Answers saved by first-run setup, which runs when there's no profile yet and
+    // nothing else says where to connect
+    let profile_path = paths::state_dir().map(|dir| setup::path(&dir));
+    let profile = match profile_path.as_deref().and_then(setup::Profile::load) {
+        Some(profile) => profile,
+        None if profile_path.is_some()
+            && !args.pipe
+            && !json_output
+            && token.is_none()
+            && args.server.is_none()
+            && !env::var("CHAT_SERVER").is_ok_and(|val| !val.is_empty())
+            && io::stdin().is_terminal() =>
+        {
+            let probe = setup::Probe {
+                preference: ip_preference,
+                identity: identity.as_deref(),
+                proxy: proxy.as_ref(),
+                timeouts: &timeouts,
+            };
+            let profile = setup::run(DEFAULT_SERVER, DEFAULT_NAME, &probe).await?;
+            if let Some(path) = &profile_path {
+                match profile.save(path) {
+                    Ok(()) => logger::log_success(&format!(
+                        "Saved to {} - edit it to change these, or delete it to run setup again",
+                        path.display()
+                    )),
+                    Err(e) => logger::log_warning(&format!("Failed to save profile: {}", e)),
+                }
+            }
+            profile
+        }
+        None => Profile::default(),
+    };
+    if !profile.colors {
+        colored::control::set_override(false);
+    }
+    // stdin is the message stream in pipe mode, so there's nobody to prompt
+    let (chat_server, chat_name) = match get_server_info(&args, token.as_deref(), &profile)? {
+        Some(info) => info,
+        None => {
+            logger::log_error("--pipe needs a server: use --server or set CHAT_SERVER");
+            return Ok(ExitCode::from(64)); // EX_USAGE
+        }
+    };
+    // Program run on mentions and DMs, e.g. "notify-send" or a custom script
+    let notify_command = env::var(CHAT_NOTIFY_COMMAND_ENV_VAR)
+        .ok()
+        .or(profile.notify_command);
+    let settings = ClientSettings {
+        scrollback_lines,
+        notify_command,
+        sign_messages,
+        password,
+        ip_preference,
+        send_fingerprint,
+        status_bar,
+        terminal_title,
+        multiplexer,
+        identity,
+        proxy,
+        json_events: json_output,
+        auto_split,
+        self_echo,
+        save_drafts,
+        repeats,
+        hidden_classes,
+        translations,
+        gap,
+        quiet_joins,
+        timeouts,
+        reconnect,
+    };
+
+    let mut chat_server = chat_server;
+    let mut client = loop {
+        let result = async {
+            let mut client =
+                ChatClient::new(&chat_server, chat_name.clone(), settings.clone()).await?;
+            match timeout(timeouts.connect, client.join_server()).await {
+                Ok(Ok(())) => Ok(client),
+                Ok(Err(_)) => Err(StartupError::Io(io::Error::new(
+                    io::ErrorKind::ConnectionAborted,
+                    "the server closed the connection while joining",
+                ))),
+                Err(_) => Err(StartupError::Timeout {
+                    addr: chat_server.clone(),
+                    stage: Stage::Join,
+                    after: Some(timeouts.connect),
+                }),
+            }
+        }
+        .await;
+
+        match result {
+            Ok(client) => break client,
+            Err(e) => {
+                e.log();
+                match startup::prompt_recovery(&chat_server, &e) {
+                    Recovery::Retry => {}
+                    Recovery::Edit(addr) => chat_server = addr,
+                    Recovery::Quit => return Ok(e.into()),
+                }
+            }
+        }
+    };
+
+    if let Some(room) = args.room {
+        client.join_room_on_entry(room);
+    }
+
+    if args.pipe {
+        let result = tokio::select! {
+            result = client.run_pipe() => result,
+            _ = tokio::signal::ctrl_c() => Ok(()),
+        };
+        client.leave().await;
+        return result.map(|()| ExitCode::SUCCESS);
+    }
+
+    client.restore_scrollback();
 
     // Run client with Ctrl+C handling
-    tokio::select! {
+    let result = tokio::select! {
         result = client.run() => {
-            restore_terminal();
+            restore_terminal(json_output);
             result
         }
         _ = tokio::signal::ctrl_c() => {
-            restore_terminal();
-            println!(); // New line after ^C
+            restore_terminal(json_output);
+            eprintln!(); // New line after ^C
             logger::log_info("Interrupted, exiting...");
             Ok(())
         }
-    }
+    };
+
+    // Leave explicitly, so the server doesn't hold the session for a reconnect
+    client.leave().await;
+    client.stop_status_bar();
+    client.save_scrollback();
+    client.save_drafts();
+    result.map(|()| ExitCode::SUCCESS)
 }
 
 fn prompt_input(prompt: &str, default: &str) -> io::Result<String> {
@@ -73,23 +509,71 @@ fn prompt_input(prompt: &str, default: &str) -> io::Result<String> {
     })
 }
 
-fn get_server_info() -> io::Result<(String, String)> {
-    // Check for environment variables first
-    let server = match env::var("CHAT_SERVER") {
-        Ok(val) if !val.is_empty() => {
+/// A whole number from an environment variable (None when unset or invalid)
+fn number_var(name: &str) -> Option<u64> {
+    let val = env::var(name).ok().filter(|val| !val.is_empty())?;
+    val.trim().parse().ok().or_else(|| {
+        logger::log_warning(&format!("Invalid {} '{}' - using the default", name, val));
+        None
+    })
+}
+
+/// A socket buffer size in KB, in bytes (None = the system's default)
+fn buffer_var(name: &str) -> Option<usize> {
+    let kb = number_var(name).filter(|kb| *kb > 0)?;
+    kb.checked_mul(1024)
+        .filter(|bytes| *bytes <= MAX_SOCKET_BUFFER)
+        .and_then(|bytes| usize::try_from(bytes).ok())
+        .or_else(|| {
+            logger::log_warning(&format!(
+                "{} is too large (at most {}) - using the system default",
+                name,
+                MAX_SOCKET_BUFFER / 1024
+            ));
+            None
+        })
+}
+
+/// A timeout that 0 turns off
+fn enabled_secs(secs: u64) -> Option<Duration> {
+    (secs > 0).then(|| Duration::from_secs(secs))
+}
+
+/// Server and nickname from the command line, the environment, the profile or
+/// prompts (None in pipe mode when no server is given). A bot `token` names the bot.
+fn get_server_info(
+    args: &CliArgs,
+    token: Option<&str>,
+    profile: &Profile,
+) -> io::Result<Option<(String, String)>> {
+    // Command line first, then environment variables, then the profile
+    let server = match (&args.server, env::var("CHAT_SERVER"), &profile.server) {
+        (Some(server), _, _) => server.clone(),
+        (None, Ok(val), _) if !val.is_empty() => {
             logger::log_info(&format!("Using server from CHAT_SERVER: {}", val));
             val
         }
+        (None, _, Some(server)) => server.clone(),
+        _ if args.pipe => return Ok(None),
         _ => prompt_input("Enter Chat Server", DEFAULT_SERVER)?,
     };
 
-    let name = match env::var("CHAT_USERNAME") {
-        Ok(val) if !val.is_empty() => {
+    let name = match (
+        &args.name,
+        token.and_then(cli::bot_name),
+        env::var("CHAT_USERNAME"),
+        &profile.name,
+    ) {
+        (Some(name), _, _, _) => name.clone(),
+        (None, Some(bot), _, _) => bot,
+        (None, None, Ok(val), _) if !val.is_empty() => {
             logger::log_info(&format!("Using username from CHAT_USERNAME: {}", val));
             val
         }
+        (None, None, _, Some(name)) => name.clone(),
+        _ if args.pipe => DEFAULT_NAME.to_string(),
         _ => prompt_input("Enter Chat Name", DEFAULT_NAME)?,
     };
 
-    Ok((server, name))
+    Ok(Some((server, name)))
 }
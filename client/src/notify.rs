@@ -0,0 +1,133 @@
+//! External notifications: runs a user-configured command when someone mentions
+//! us or sends a direct message, so the chat can integrate with desktop notifiers,
+//! tmux status lines or custom scripts.
+//!
+//! The command is called as `<command> <kind> <sender> <room>` (kind is "mention"
+//! or "dm", room is empty outside rooms) with the message text on stdin.
+
+pub use shared::digest::mentions;
+use shared::logger;
+use std::collections::VecDeque;
+use std::process::Stdio;
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+/// At most this many commands are started per window, to avoid process storms
+const MAX_NOTIFICATIONS: usize = 5;
+const RATE_WINDOW: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NotificationKind {
+    Mention,
+    DirectMessage,
+}
+
+impl NotificationKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            NotificationKind::Mention => "mention",
+            NotificationKind::DirectMessage => "dm",
+        }
+    }
+}
+
+pub struct Notifier {
+    /// Program and arguments (None = notifications disabled)
+    command: Option<Vec<String>>,
+    recent: VecDeque<Instant>,
+    /// Whether we already reported dropping notifications in the current window
+    warned: bool,
+}
+
+impl Notifier {
+    pub fn new(command: Option<String>) -> Self {
+        let command = command
+            .map(|c| c.split_whitespace().map(str::to_string).collect::<Vec<_>>())
+            .filter(|parts| !parts.is_empty());
+        Notifier {
+            command,
+            recent: VecDeque::new(),
+            warned: false,
+        }
+    }
+
+    /// Run the notify command for a message, unless rate limited
+    pub fn notify(&mut self, kind: NotificationKind, sender: &str, room: &str, message: &str) {
+        if self.command.is_none() {
+            return;
+        }
+        if !self.allow(Instant::now()) {
+            if !self.warned {
+                self.warned = true;
+                logger::log_warning("Too many notifications - skipping some for now");
+            }
+            return;
+        }
+
+        let Some(command) = &self.command else {
+            return;
+        };
+        let mut child = match Command::new(&command[0])
+            .args(&command[1..])
+            .args([kind.as_str(), sender, room])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+        {
+            Ok(child) => child,
+            Err(e) => {
+                logger::log_error(&format!("Failed to run notify command: {}", e));
+                return;
+            }
+        };
+
+        // Feed the message and reap the process in the background
+        let message = message.to_string();
+        tokio::spawn(async move {
+            if let Some(mut stdin) = child.stdin.take() {
+                let _ = stdin.write_all(message.as_bytes()).await;
+            }
+            let _ = child.wait().await;
+        });
+    }
+
+    fn allow(&mut self, now: Instant) -> bool {
+        while self
+            .recent
+            .front()
+            .is_some_and(|t| now.duration_since(*t) >= RATE_WINDOW)
+        {
+            self.recent.pop_front();
+        }
+        if self.recent.len() >= MAX_NOTIFICATIONS {
+            return false;
+        }
+        self.warned = false;
+        self.recent.push_back(now);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_limit() {
+        let mut notifier = Notifier::new(Some("true".to_string()));
+        let start = Instant::now();
+        for _ in 0..MAX_NOTIFICATIONS {
+            assert!(notifier.allow(start));
+        }
+        assert!(!notifier.allow(start + Duration::from_secs(1)));
+        assert!(notifier.allow(start + RATE_WINDOW));
+    }
+
+    #[test]
+    fn test_disabled_without_command() {
+        assert!(Notifier::new(None).command.is_none());
+        assert!(Notifier::new(Some("   ".to_string())).command.is_none());
+    }
+}
@@ -0,0 +1,81 @@
+//! Commands run every time the server lets us into the chat, so a reconnect restores
+//! the whole setup: rooms joined, status set, a service identified with. One command
+//! per line, as it would be typed, in `config/on_connect.txt` (every server) and then
+//! `config/on_connect/<server>.txt` (that server only). Blank lines and lines starting
+//! with `#` are skipped.
+
+use crate::input::ClientUserInput;
+use crate::state_dir::CONFIG_DIR;
+use shared::logger;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Commands for every server, in the state directory's config/
+pub const ON_CONNECT_FILE: &str = "on_connect.txt";
+/// Directory in config/ with a `<server>.txt` of commands for each server
+pub const ON_CONNECT_DIR: &str = "on_connect";
+
+/// The files read for the server `server_file_stem` names, in the order they run
+fn files(state_dir: &Path, server_file_stem: &str) -> [PathBuf; 2] {
+    let config = state_dir.join(CONFIG_DIR);
+    [
+        config.join(ON_CONNECT_FILE),
+        config
+            .join(ON_CONNECT_DIR)
+            .join(format!("{}.txt", server_file_stem)),
+    ]
+}
+
+/// Read the commands to run on connecting to the server `server_file_stem` names.
+/// Lines that aren't commands the client understands are reported and skipped.
+pub fn load(state_dir: &Path, server_file_stem: &str) -> Vec<String> {
+    let mut commands = Vec::new();
+    for path in files(state_dir, server_file_stem) {
+        let Ok(contents) = fs::read_to_string(&path) else {
+            continue;
+        };
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match ClientUserInput::try_from(line) {
+                Ok(ClientUserInput::Quit) | Err(_) => logger::log_warning(&format!(
+                    "{}:{}: skipped, not a command that can run on connect",
+                    path.display(),
+                    number + 1
+                )),
+                Ok(_) => commands.push(line.to_string()),
+            }
+        }
+    }
+    commands
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_loads_every_server_then_this_server() {
+        let dir = std::env::temp_dir().join(format!("rust_chat_on_connect_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        assert!(load(&dir, "host_1").is_empty());
+
+        let [common, server] = files(&dir, "host_1");
+        fs::create_dir_all(server.parent().unwrap()).unwrap();
+        fs::write(&common, "# every server\n/join general\n\n/status away\n").unwrap();
+        fs::write(&server, "/dm NickServ identify hunter2\n/bogus\n/quit\n").unwrap();
+        fs::write(dir.join("config/on_connect/host_2.txt"), "/join other\n").unwrap();
+
+        assert_eq!(
+            load(&dir, "host_1"),
+            vec![
+                "/join general",
+                "/status away",
+                "/dm NickServ identify hunter2"
+            ]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,93 @@
+//! Locations of files the client keeps between runs
+
+use std::env;
+use std::path::{Path, PathBuf};
+
+const CHAT_STATE_DIR_ENV_VAR: &str = "CHAT_STATE_DIR";
+
+/// Directory for client state (defaults to ~/.rust_chat, override with CHAT_STATE_DIR)
+pub fn state_dir() -> Option<PathBuf> {
+    if let Ok(dir) = env::var(CHAT_STATE_DIR_ENV_VAR)
+        && !dir.is_empty()
+    {
+        return Some(PathBuf::from(dir));
+    }
+    env::var_os("HOME")
+        .or_else(|| env::var_os("USERPROFILE"))
+        .map(|home| PathBuf::from(home).join(".rust_chat"))
+}
+
+/// Turn a server address into something safe to use as a file name
+pub fn server_file_stem(host: &str, port: u16) -> String {
+    let host: String = host
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() || c == '-' || c == '.' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    format!("{}_{}", host, port)
+}
+
+/// The kinds of per-server file kept in the state directory
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StateFile {
+    /// Scrollback replayed on the next launch
+    History,
+    /// Protocol trace (/debug trace)
+    Trace,
+    /// Our signing key
+    SigningKey,
+    /// Secret our client fingerprint is derived from
+    Lineage,
+    /// Other users' public keys, pinned on first use
+    KnownKeys,
+    /// Pinned TLS certificate fingerprint (/trust)
+    TrustedCert,
+    /// What was being typed for each room (CHAT_SAVE_DRAFTS)
+    Drafts,
+}
+
+impl StateFile {
+    pub const ALL: [StateFile; 7] = [
+        StateFile::History,
+        StateFile::Trace,
+        StateFile::SigningKey,
+        StateFile::Lineage,
+        StateFile::KnownKeys,
+        StateFile::TrustedCert,
+        StateFile::Drafts,
+    ];
+
+    /// Directory holding this kind of file, relative to the state directory
+    pub fn dir(self) -> PathBuf {
+        match self {
+            StateFile::History => PathBuf::from("history"),
+            StateFile::Trace => PathBuf::from("logs"),
+            StateFile::SigningKey => PathBuf::from("keys"),
+            StateFile::Lineage => Path::new("keys").join("lineage"),
+            StateFile::KnownKeys => Path::new("keys").join("known"),
+            StateFile::TrustedCert => Path::new("keys").join("trusted"),
+            StateFile::Drafts => PathBuf::from("drafts"),
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            StateFile::History | StateFile::Trace => "log",
+            StateFile::SigningKey | StateFile::Lineage => "key",
+            StateFile::KnownKeys | StateFile::Drafts => "tsv",
+            StateFile::TrustedCert => "txt",
+        }
+    }
+
+    /// This kind of file for the server `server_file_stem` names
+    pub fn path(self, state_dir: &Path, server_file_stem: &str) -> PathBuf {
+        state_dir
+            .join(self.dir())
+            .join(format!("{}.{}", server_file_stem, self.extension()))
+    }
+}
@@ -0,0 +1,106 @@
+//! Quiet joins (/quiet joins): on a busy server people coming, going and changing
+//! nickname drown out the conversation. While it's on those notices aren't shown as
+//! they arrive; they're counted, and the count is shown as one line -
+//! "12 joins/leaves hidden - /show to expand" - before the next message that is.
+//! `/show` prints the notices held back since, with the time each arrived.
+
+use chrono::{DateTime, Local};
+use std::collections::VecDeque;
+
+/// Notices kept for /show; older ones are only counted
+pub const MAX_HIDDEN: usize = 200;
+
+#[derive(Debug, Default)]
+pub struct QuietJoins {
+    on: bool,
+    /// Held back since the last /show, oldest first
+    hidden: VecDeque<(DateTime<Local>, String)>,
+    /// Held back since the count was last shown
+    uncounted: usize,
+}
+
+impl QuietJoins {
+    pub fn new(on: bool) -> Self {
+        QuietJoins {
+            on,
+            ..QuietJoins::default()
+        }
+    }
+
+    pub fn is_on(&self) -> bool {
+        self.on
+    }
+
+    /// Turn it on or off. Returns whether it's now on.
+    pub fn toggle(&mut self) -> bool {
+        self.on = !self.on;
+        self.on
+    }
+
+    /// Hold back `notice`, said at `at`, if quiet. Returns whether it was held back
+    /// (false = show it).
+    pub fn hold(&mut self, at: DateTime<Local>, notice: &str) -> bool {
+        if !self.on {
+            return false;
+        }
+        if self.hidden.len() == MAX_HIDDEN {
+            self.hidden.pop_front();
+        }
+        self.hidden.push_back((at, notice.to_string()));
+        self.uncounted += 1;
+        true
+    }
+
+    /// The line counting what was held back since it was last shown, if anything was
+    pub fn take_count(&mut self) -> Option<String> {
+        let count = std::mem::take(&mut self.uncounted);
+        (count > 0).then(|| {
+            format!(
+                "{} join{}/leave{} hidden - /show to expand",
+                count,
+                if count == 1 { "" } else { "s" },
+                if count == 1 { "" } else { "s" }
+            )
+        })
+    }
+
+    /// The notices held back, with when they arrived, forgetting them
+    pub fn expand(&mut self) -> Vec<(DateTime<Local>, String)> {
+        self.uncounted = 0;
+        self.hidden.drain(..).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hold_count_expand() {
+        let now = Local::now();
+        let mut quiet = QuietJoins::new(false);
+        assert!(!quiet.hold(now, "alice has joined the chat"));
+        assert_eq!(quiet.take_count(), None);
+
+        assert!(quiet.toggle());
+        assert!(quiet.hold(now, "alice has joined the chat"));
+        assert_eq!(
+            quiet.take_count().as_deref(),
+            Some("1 join/leave hidden - /show to expand")
+        );
+        assert_eq!(quiet.take_count(), None);
+        for _ in 0..MAX_HIDDEN {
+            quiet.hold(now, "bob has left #ops");
+        }
+        assert_eq!(
+            quiet.take_count().as_deref(),
+            Some("200 joins/leaves hidden - /show to expand")
+        );
+
+        let shown = quiet.expand();
+        assert_eq!(shown.len(), MAX_HIDDEN);
+        assert_eq!(shown[0].1, "bob has left #ops");
+        assert!(quiet.expand().is_empty());
+        assert!(!quiet.toggle());
+    }
+}
@@ -1,27 +1,109 @@
 use crate::completer::ClientCompleter;
-use rustyline::Editor;
+use crate::drafts::Drafts;
+use crate::input::ClientUserInput;
 use rustyline::config::Configurer;
+use rustyline::{
+    Cmd, ConditionalEventHandler, Editor, Event, EventContext, EventHandler, ExternalPrinter,
+    KeyCode, KeyEvent, Modifiers, Movement, RepeatCount,
+};
+use shared::commands::client as commands;
+use shared::logger;
 use std::collections::HashSet;
-use std::sync::{Arc, RwLock};
+use std::sync::atomic::AtomicUsize;
+use std::sync::{Arc, Mutex, RwLock};
 use tokio::sync::mpsc;
 
-/// Runs rustyline in a blocking thread and sends input via channel
+/// Alt+Left/Right: switch to the previous or next room we're in, swapping the line
+/// being typed for the draft kept for that room
+struct SwitchRoom {
+    step: isize,
+    drafts: Arc<Mutex<Drafts>>,
+    tx: mpsc::UnboundedSender<Option<String>>,
+}
+
+impl ConditionalEventHandler for SwitchRoom {
+    fn handle(&self, _: &Event, _: RepeatCount, _: bool, ctx: &EventContext) -> Option<Cmd> {
+        let mut drafts = self.drafts.lock().ok()?;
+        let room = drafts.neighbour(self.step)?;
+        // Joining a room we're in makes it the current one
+        self.tx.send(Some(format!("/join {}", room))).ok()?;
+        let draft = drafts.switch_to(Some(room), ctx.line());
+        Some(Cmd::Replace(Movement::WholeLine, Some(draft)))
+    }
+}
+
+/// The room a line sent at the prompt moves us to, if it does (Some(None) = the
+/// main chat)
+fn switches_to(line: &str, current: Option<&str>) -> Option<Option<String>> {
+    match ClientUserInput::try_from(line).ok()? {
+        ClientUserInput::JoinRoom(room) => Some(Some(room.trim_start_matches('#').to_lowercase())),
+        ClientUserInput::PartRoom(None) => Some(None),
+        ClientUserInput::PartRoom(Some(room))
+            if Some(room.trim_start_matches('#').to_lowercase().as_str()) == current =>
+        {
+            Some(None)
+        }
+        _ => None,
+    }
+}
+
+/// Runs rustyline in a blocking thread and sends input via channel. With
+/// `keep_log_output` the logger's output (e.g. stderr for JSON output) is left alone.
 pub fn spawn_readline_handler(
     users: Arc<RwLock<HashSet<String>>>,
+    max_message: Arc<AtomicUsize>,
+    drafts: Arc<Mutex<Drafts>>,
     _prompt: String,
+    keep_log_output: bool,
 ) -> mpsc::UnboundedReceiver<Option<String>> {
     let (tx, rx) = mpsc::unbounded_channel();
 
     std::thread::spawn(move || {
-        let completer = ClientCompleter::new(users);
+        let completer = ClientCompleter::new(users, max_message, drafts.clone());
         let mut rl = Editor::new().expect("Failed to create editor");
         rl.set_helper(Some(completer));
-        rl.set_auto_add_history(true);
         rl.set_max_history_size(1000).ok();
+        for (code, step) in [(KeyCode::Left, -1), (KeyCode::Right, 1)] {
+            let handler = SwitchRoom {
+                step,
+                drafts: drafts.clone(),
+                tx: tx.clone(),
+            };
+            rl.bind_sequence(
+                KeyEvent(code, Modifiers::ALT),
+                EventHandler::Conditional(Box::new(handler)),
+            );
+        }
+        // Print messages above the line being typed and redraw it, instead of
+        // writing over it
+        if !keep_log_output && let Ok(printer) = rl.create_external_printer() {
+            let printer = Mutex::new(printer);
+            logger::set_output(move |line| {
+                if let Ok(mut printer) = printer.lock() {
+                    let _ = printer.print(format!("{}\n", line));
+                }
+            });
+        }
 
+        // Each line starts out as the current room's draft
+        let mut initial = drafts
+            .lock()
+            .map(|mut drafts| drafts.take_current())
+            .unwrap_or_default();
         loop {
-            match rl.readline("") {
+            match rl.readline_with_initial("", (&initial, "")) {
                 Ok(line) => {
+                    // Keep passwords out of the history
+                    if !commands::REGISTER.matches(line.split_whitespace().next().unwrap_or("")) {
+                        let _ = rl.add_history_entry(line.as_str());
+                    }
+                    if let Ok(mut drafts) = drafts.lock() {
+                        drafts.sent();
+                        initial = match switches_to(&line, drafts.current()) {
+                            Some(room) => drafts.switch_to(room, ""),
+                            None => drafts.take_current(),
+                        };
+                    }
                     if tx.send(Some(line)).is_err() {
                         break; // Receiver dropped
                     }
@@ -0,0 +1,261 @@
+//! Collapses a sender repeating the same message (spam, a bot stuck in a loop) so it
+//! doesn't bury the scrollback. The first copies, up to the threshold, are shown as
+//! usual; copies after that, each within the window of the one before, are held back
+//! and shown as one line with a count - "alice: buy now (x3)" - once the sender says
+//! something else, goes quiet, or a window has passed (so a loop gets a line per
+//! window rather than none).
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Where a message was said
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Place {
+    Chat,
+    Room(String),
+    /// A direct message to us
+    Direct,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RepeatPolicy {
+    /// Copies shown before the rest are collapsed (0 = never collapse)
+    pub threshold: usize,
+    /// Longest gap between copies that still counts as repeating
+    pub window: Duration,
+}
+
+impl Default for RepeatPolicy {
+    fn default() -> Self {
+        RepeatPolicy {
+            threshold: 1,
+            window: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Copies that were held back, to be shown as one line
+#[derive(Debug, PartialEq)]
+pub struct Collapsed {
+    pub place: Place,
+    pub sender: String,
+    /// Signature badge the copies were shown with
+    pub badge: String,
+    pub text: String,
+    /// Times it was said since a line last showed it, the shown copies included
+    pub count: usize,
+}
+
+#[derive(Debug)]
+struct Run {
+    badge: String,
+    text: String,
+    last: Instant,
+    count: usize,
+    shown: usize,
+    /// When the first copy still waiting to be shown arrived
+    held_since: Option<Instant>,
+}
+
+#[derive(Debug)]
+pub struct Repeats {
+    policy: RepeatPolicy,
+    runs: HashMap<(Place, String), Run>,
+}
+
+impl Repeats {
+    pub fn new(policy: RepeatPolicy) -> Self {
+        Repeats {
+            policy,
+            runs: HashMap::new(),
+        }
+    }
+
+    /// Note a message `sender` said. Returns whether to show it, and the line for any
+    /// copies of their previous message that were held back (to show first).
+    pub fn observe(
+        &mut self,
+        place: &Place,
+        sender: &str,
+        badge: &str,
+        text: &str,
+        now: Instant,
+    ) -> (bool, Option<Collapsed>) {
+        if self.policy.threshold == 0 {
+            return (true, None);
+        }
+        let key = (place.clone(), sender.to_string());
+        if let Some(run) = self.runs.get_mut(&key)
+            && run.badge == badge
+            && run.text == text
+            && now.duration_since(run.last) <= self.policy.window
+        {
+            run.last = now;
+            run.count += 1;
+            if run.shown < self.policy.threshold {
+                run.shown += 1;
+                return (true, None);
+            }
+            run.held_since.get_or_insert(now);
+            return (false, None);
+        }
+        let collapsed = self
+            .runs
+            .remove(&key)
+            .and_then(|run| run.collapse(key.0.clone(), key.1.clone()));
+        self.runs.insert(
+            key,
+            Run {
+                badge: badge.to_string(),
+                text: text.to_string(),
+                last: now,
+                count: 1,
+                shown: 1,
+                held_since: None,
+            },
+        );
+        (true, collapsed)
+    }
+
+    /// Lines for copies held back for a window, forgetting runs that have gone quiet
+    pub fn flush(&mut self, now: Instant) -> Vec<Collapsed> {
+        let window = self.policy.window;
+        let mut collapsed = Vec::new();
+        self.runs.retain(|(place, sender), run| {
+            let quiet = now.duration_since(run.last) > window;
+            if run
+                .held_since
+                .is_some_and(|since| now.duration_since(since) >= window || quiet)
+            {
+                collapsed.extend(run.collapse_and_reset(place, sender));
+            }
+            !quiet
+        });
+        collapsed
+    }
+
+    /// When flush() next has something to show (None = nothing held back)
+    pub fn next_flush(&self) -> Option<Instant> {
+        self.runs
+            .values()
+            .filter_map(|run| {
+                let since = run.held_since?;
+                Some((since + self.policy.window).min(run.last + self.policy.window))
+            })
+            .min()
+    }
+}
+
+impl Run {
+    fn collapse(self, place: Place, sender: String) -> Option<Collapsed> {
+        self.held_since?;
+        Some(Collapsed {
+            place,
+            sender,
+            badge: self.badge,
+            text: self.text,
+            count: self.count,
+        })
+    }
+
+    /// Collapse what's held back, and keep holding back further copies
+    fn collapse_and_reset(&mut self, place: &Place, sender: &str) -> Option<Collapsed> {
+        self.held_since.take()?;
+        let count = std::mem::take(&mut self.count);
+        Some(Collapsed {
+            place: place.clone(),
+            sender: sender.to_string(),
+            badge: self.badge.clone(),
+            text: self.text.clone(),
+            count,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_repeats_collapse() {
+        let window = Duration::from_secs(30);
+        let mut repeats = Repeats::new(RepeatPolicy {
+            threshold: 2,
+            window,
+        });
+        let room = Place::Room("ops".to_string());
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        // Two copies are shown, the third and fourth held back
+        assert_eq!(
+            repeats.observe(&room, "bot", "", "ping", at(0)),
+            (true, None)
+        );
+        assert_eq!(
+            repeats.observe(&room, "bot", "", "ping", at(1)),
+            (true, None)
+        );
+        assert_eq!(
+            repeats.observe(&room, "bot", "", "ping", at(2)),
+            (false, None)
+        );
+        // Others and other places aren't affected
+        assert_eq!(
+            repeats.observe(&room, "alice", "", "ping", at(3)),
+            (true, None)
+        );
+        assert_eq!(
+            repeats.observe(&Place::Chat, "bot", "", "ping", at(3)),
+            (true, None)
+        );
+        assert_eq!(
+            repeats.observe(&room, "bot", "", "ping", at(4)),
+            (false, None)
+        );
+        assert_eq!(repeats.next_flush(), Some(at(32)));
+
+        // Saying something else shows the held copies first
+        let (shown, collapsed) = repeats.observe(&room, "bot", "", "pong", at(5));
+        assert!(shown);
+        assert_eq!(
+            collapsed,
+            Some(Collapsed {
+                place: room.clone(),
+                sender: "bot".to_string(),
+                badge: String::new(),
+                text: "ping".to_string(),
+                count: 4,
+            })
+        );
+        assert_eq!(repeats.next_flush(), None);
+
+        // A different badge (an unsigned impostor) isn't a repeat
+        assert!(repeats.observe(&room, "bot", "[✓] ", "pong", at(6)).0);
+
+        // A loop gets a line per window
+        repeats.observe(&room, "bot", "[✓] ", "pong", at(7));
+        assert!(!repeats.observe(&room, "bot", "[✓] ", "pong", at(8)).0);
+        assert!(repeats.flush(at(20)).is_empty());
+        for secs in 21..=38 {
+            repeats.observe(&room, "bot", "[✓] ", "pong", at(secs));
+        }
+        let collapsed = repeats.flush(at(38));
+        assert_eq!(collapsed.len(), 1);
+        assert_eq!(collapsed[0].count, 21);
+        assert!(!repeats.observe(&room, "bot", "[✓] ", "pong", at(39)).0);
+        // ...and one more once it goes quiet
+        assert_eq!(repeats.flush(at(70))[0].count, 1);
+        assert_eq!(repeats.next_flush(), None);
+
+        // After a pause it's a new message
+        assert!(repeats.observe(&room, "bot", "[✓] ", "pong", at(200)).0);
+
+        let mut off = Repeats::new(RepeatPolicy {
+            threshold: 0,
+            window,
+        });
+        assert!(off.observe(&room, "bot", "", "ping", at(0)).0);
+        assert!(off.observe(&room, "bot", "", "ping", at(0)).0);
+    }
+}
@@ -0,0 +1,287 @@
+//! Scrollback persistence: the last few messages of each room are saved on exit
+//! and replayed on the next launch, so restarting the client keeps some context.
+
+use crate::gaps::{self, Gaps};
+use crate::state_dir;
+use chrono::Local;
+use shared::logger;
+use std::collections::VecDeque;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+/// Messages kept per room (and for the main chat) when not configured
+pub const DEFAULT_SCROLLBACK_LINES: usize = 50;
+/// Messages shown by /last when no count is given
+pub const DEFAULT_LAST_COUNT: usize = 10;
+
+/// Key used for the main chat (rooms use their name)
+const MAIN_CHAT: &str = "";
+
+#[derive(Debug, Clone, PartialEq)]
+struct Entry {
+    room: String,
+    timestamp: String,
+    text: String,
+}
+
+impl Entry {
+    /// Format: room \t timestamp \t text (one entry per line)
+    fn to_line(&self) -> String {
+        format!("{}\t{}\t{}", self.room, self.timestamp, self.text)
+    }
+
+    fn from_line(line: &str) -> Option<Self> {
+        let mut parts = line.splitn(3, '\t');
+        Some(Entry {
+            room: parts.next()?.to_string(),
+            timestamp: parts.next()?.to_string(),
+            text: parts.next()?.to_string(),
+        })
+    }
+
+    /// The text as it was shown, with the room in front of room messages
+    fn display_text(&self) -> String {
+        if self.room == MAIN_CHAT {
+            self.text.clone()
+        } else {
+            format!("#{} {}", self.room, self.text)
+        }
+    }
+
+    /// Whether `user` sent this message (text is "sender: message", announcements
+    /// have an "[ANNOUNCE] " prefix)
+    fn is_from(&self, user: &str) -> bool {
+        let text = self.text.strip_prefix("[ANNOUNCE] ").unwrap_or(&self.text);
+        text.split_once(": ")
+            .is_some_and(|(sender, _)| sender.eq_ignore_ascii_case(user))
+    }
+
+    /// Credit this message to `to` if `from` sent it
+    fn rename_sender(&mut self, from: &str, to: &str) -> bool {
+        if !self.is_from(from) {
+            return false;
+        }
+        let announce = if self.text.starts_with("[ANNOUNCE] ") {
+            "[ANNOUNCE] "
+        } else {
+            ""
+        };
+        if let Some((_, message)) = self.text[announce.len()..].split_once(": ") {
+            self.text = format!("{}{}: {}", announce, to, message);
+        }
+        true
+    }
+}
+
+/// Messages are kept in memory for /last even when persistence is disabled
+pub struct Scrollback {
+    /// None = persistence disabled
+    path: Option<PathBuf>,
+    lines_per_room: usize,
+    entries: VecDeque<Entry>,
+}
+
+impl Scrollback {
+    pub fn new(path: Option<PathBuf>, lines_per_room: usize) -> Self {
+        Scrollback {
+            path: path.filter(|_| lines_per_room > 0),
+            lines_per_room,
+            entries: VecDeque::new(),
+        }
+    }
+
+    /// Load the previous session's scrollback and print it, clearly separated
+    /// from live messages and with `gaps` dividing it. The loaded entries are kept so
+    /// they survive another restart.
+    pub fn restore(&mut self, gaps: &mut Gaps) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let Ok(contents) = fs::read_to_string(path) else {
+            return; // Nothing saved yet
+        };
+
+        for entry in contents.lines().filter_map(Entry::from_line) {
+            self.push(entry);
+        }
+        if self.entries.is_empty() {
+            return;
+        }
+
+        logger::log_info("--- previous session ---");
+        for entry in &self.entries {
+            if let Some(divider) =
+                gaps::parse_entry_time(&entry.timestamp).and_then(|at| gaps.observe(at))
+            {
+                logger::log_divider(&divider);
+            }
+            logger::log_scrollback(&entry.timestamp, &entry.display_text());
+        }
+        logger::log_info("--- end of previous session ---");
+    }
+
+    /// Remember a message shown in the main chat
+    pub fn record_chat(&mut self, text: &str) {
+        self.record(MAIN_CHAT, text);
+    }
+
+    /// Remember a message shown in a room
+    pub fn record_room(&mut self, room: &str, text: &str) {
+        self.record(room, text);
+    }
+
+    fn record(&mut self, room: &str, text: &str) {
+        if self.lines_per_room == 0 {
+            return;
+        }
+        // Tabs and newlines would break the line-based file format
+        let text = text.replace(['\t', '\n', '\r'], " ");
+        self.push(Entry {
+            room: room.to_string(),
+            timestamp: Local::now().format("%Y-%m-%d %H:%M").to_string(),
+            text,
+        });
+    }
+
+    fn push(&mut self, entry: Entry) {
+        let room = entry.room.clone();
+        self.entries.push_back(entry);
+
+        // Drop the oldest entry of this room once it's over the limit
+        let count = self.entries.iter().filter(|e| e.room == room).count();
+        if count > self.lines_per_room
+            && let Some(oldest) = self.entries.iter().position(|e| e.room == room)
+        {
+            self.entries.remove(oldest);
+        }
+    }
+
+    /// The most recent `count` messages from `user` across all rooms, oldest first,
+    /// as (timestamp, text) pairs
+    pub fn last_from(&self, user: &str, count: usize) -> Vec<(String, String)> {
+        let mut matches: Vec<(String, String)> = self
+            .entries
+            .iter()
+            .rev()
+            .filter(|entry| entry.is_from(user))
+            .take(count)
+            .map(|entry| (entry.timestamp.clone(), entry.display_text()))
+            .collect();
+        matches.reverse();
+        matches
+    }
+
+    /// Credit what `from` said to `to`, their new nickname, so /last finds it under
+    /// the name they go by now
+    pub fn rename(&mut self, from: &str, to: &str) {
+        for entry in &mut self.entries {
+            entry.rename_sender(from, to);
+        }
+    }
+
+    /// Write the scrollback to disk
+    pub fn save(&self) -> io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let mut contents = String::new();
+        for entry in &self.entries {
+            contents.push_str(&entry.to_line());
+            contents.push('\n');
+        }
+        state_dir::write_atomic(path, contents.as_bytes())
+    }
+}
+
+/// Whether `line` is a scrollback entry (/doctor drops the ones that aren't)
+pub fn is_entry(line: &str) -> bool {
+    Entry::from_line(line).is_some()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "rust_chat_scrollback_{}_{}",
+            name,
+            std::process::id()
+        ))
+    }
+
+    #[test]
+    fn test_limit_is_per_room() {
+        let mut scrollback = Scrollback::new(Some(temp_path("limit")), 2);
+        scrollback.record_chat("alice: one");
+        scrollback.record_room("ops", "bob: deploy");
+        scrollback.record_chat("alice: two");
+        scrollback.record_chat("alice: three");
+
+        let texts: Vec<&str> = scrollback.entries.iter().map(|e| e.text.as_str()).collect();
+        assert_eq!(texts, vec!["bob: deploy", "alice: two", "alice: three"]);
+    }
+
+    #[test]
+    fn test_save_and_restore_round_trip() {
+        let path = temp_path("round_trip");
+        let mut scrollback = Scrollback::new(Some(path.clone()), 10);
+        scrollback.record_chat("alice: hello\tthere");
+        scrollback.record_room("ops", "bob: hi");
+        scrollback.save().unwrap();
+
+        let mut restored = Scrollback::new(Some(path.clone()), 10);
+        restored.restore(&mut Gaps::new(None));
+        assert_eq!(restored.entries, scrollback.entries);
+        assert_eq!(restored.entries[0].text, "alice: hello there");
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_last_from_user() {
+        // Kept in memory even without a file to save to
+        let mut scrollback = Scrollback::new(None, 10);
+        scrollback.record_chat("alice: one");
+        scrollback.record_room("ops", "bob: deploy");
+        scrollback.record_room("ops", "alice: two");
+        scrollback.record_chat("[ANNOUNCE] Alice: three");
+        scrollback.record_chat("carol: alice: not alice");
+
+        let texts: Vec<String> = scrollback
+            .last_from("alice", 2)
+            .into_iter()
+            .map(|(_, text)| text)
+            .collect();
+        assert_eq!(texts, vec!["#ops alice: two", "[ANNOUNCE] Alice: three"]);
+        assert_eq!(scrollback.last_from("alice", 10).len(), 3);
+        assert!(scrollback.last_from("dave", 10).is_empty());
+
+        // After a rename, what they said is theirs under the new name
+        scrollback.rename("alice", "alicia");
+        assert!(scrollback.last_from("alice", 10).is_empty());
+        let texts: Vec<String> = scrollback
+            .last_from("alicia", 10)
+            .into_iter()
+            .map(|(_, text)| text)
+            .collect();
+        assert_eq!(
+            texts,
+            vec![
+                "alicia: one",
+                "#ops alicia: two",
+                "[ANNOUNCE] alicia: three"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_disabled_when_zero_lines() {
+        let path = temp_path("disabled");
+        let mut scrollback = Scrollback::new(Some(path.clone()), 0);
+        scrollback.record_chat("alice: hello");
+        assert!(scrollback.entries.is_empty());
+        scrollback.save().unwrap();
+        assert!(!path.exists());
+    }
+}
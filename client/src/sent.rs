@@ -0,0 +1,103 @@
+//! What sent each recent frame. A rejection from the server (see shared::rejection)
+//! names only the type of the frame it turned down; this ties it back to the command
+//! or message that sent it, so the error can say which of ours was at fault.
+
+use shared::message::MessageTypes;
+use std::collections::VecDeque;
+
+/// Frames remembered; a rejection comes back right after the frame it's about
+const REMEMBERED: usize = 32;
+/// Longer input lines are cut short in messages
+const MAX_ACTION_CHARS: usize = 60;
+
+#[derive(Default)]
+pub struct SentFrames {
+    /// The input line being handled, if any
+    action: Option<String>,
+    recent: VecDeque<(MessageTypes, Option<String>)>,
+}
+
+impl SentFrames {
+    /// Frames sent until `end` come from this input line
+    pub fn begin(&mut self, line: &str) {
+        let mut action: String = line.trim().chars().take(MAX_ACTION_CHARS).collect();
+        if action.len() < line.trim().len() {
+            action.push('…');
+        }
+        self.action = Some(action);
+    }
+
+    pub fn end(&mut self) {
+        self.action = None;
+    }
+
+    pub fn record(&mut self, frame: MessageTypes) {
+        if self.recent.len() == REMEMBERED {
+            self.recent.pop_front();
+        }
+        self.recent.push_back((frame, self.action.clone()));
+    }
+
+    fn find(&self, frame: Option<MessageTypes>) -> Option<&(MessageTypes, Option<String>)> {
+        match frame {
+            Some(frame) => self.recent.iter().rev().find(|(sent, _)| *sent == frame),
+            None => self.recent.back(),
+        }
+    }
+
+    /// Describe what sent the last frame of this type (or the last frame at all)
+    pub fn describe(&self, frame: Option<MessageTypes>) -> String {
+        match self.find(frame) {
+            Some((frame, Some(action))) => format!("the {:?} frame sent by `{}`", frame, action),
+            Some((frame, None)) => format!("the {:?} frame the client sent on its own", frame),
+            None => match frame {
+                Some(frame) => format!("a {:?} frame", frame),
+                None => "a frame".to_string(),
+            },
+        }
+    }
+
+    /// The input line behind the last frame of this type, if one sent it
+    pub fn action(&self, frame: Option<MessageTypes>) -> Option<&str> {
+        self.find(frame).and_then(|(_, action)| action.as_deref())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rejected_frames_are_traced_to_their_input() {
+        let mut sent = SentFrames::default();
+        sent.record(MessageTypes::VersionCheck);
+        sent.begin("/status away");
+        sent.record(MessageTypes::SetStatus);
+        sent.end();
+        sent.begin(&"x".repeat(100));
+        sent.record(MessageTypes::ChatMessage);
+        sent.end();
+        sent.record(MessageTypes::Ping);
+
+        assert_eq!(
+            sent.describe(Some(MessageTypes::SetStatus)),
+            "the SetStatus frame sent by `/status away`"
+        );
+        assert_eq!(
+            sent.action(Some(MessageTypes::SetStatus)),
+            Some("/status away")
+        );
+        assert_eq!(
+            sent.describe(Some(MessageTypes::VersionCheck)),
+            "the VersionCheck frame the client sent on its own"
+        );
+        assert_eq!(
+            sent.describe(None),
+            "the Ping frame the client sent on its own"
+        );
+        let action = sent.action(Some(MessageTypes::ChatMessage)).unwrap();
+        assert_eq!(action.chars().count(), MAX_ACTION_CHARS + 1);
+        assert!(action.ends_with('…'));
+        assert_eq!(sent.describe(Some(MessageTypes::Join)), "a Join frame");
+    }
+}
@@ -0,0 +1,335 @@
+//! First-run setup: with no profile saved and a terminal to ask on, the client walks
+//! through the server to use (checking it can be reached), a nickname (asking the
+//! server whether it's free) and colors and notifications, and saves the answers to
+//! `config/profile.txt`. Later launches start from the profile; --server, --name and
+//! the environment variables still win over it. Delete the file to run setup again.
+//!
+//! The profile is `key = value` lines - `server`, `name`, `colors` (on or off) and
+//! `notify_command` - with blank lines and lines starting with `#` skipped.
+
+use crate::client::{ClientStream, connection_buffers};
+use crate::prompt_input;
+use crate::startup::{self, ClientIdentity, IpPreference, Proxy, StartupError, Timeouts};
+use crate::state_dir::{self, CONFIG_DIR};
+use shared::error::ChatError;
+use shared::logger;
+use shared::message::{ChatMessage, MessageTypes};
+use shared::name_check::NameStatus;
+use shared::network::TcpMessageHandler;
+use shared::rejection::Rejection;
+use shared::version::VERSION;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::io::BufStream;
+use tokio::time::timeout;
+
+/// The profile, in the state directory's config/
+pub const PROFILE_FILE: &str = "profile.txt";
+/// Proxy isolation identity for test connections, which have no nickname yet
+const PROBE_IDENTITY: &str = "setup";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Profile {
+    pub server: Option<String>,
+    pub name: Option<String>,
+    /// Colored output
+    pub colors: bool,
+    /// Run on mentions and direct messages (CHAT_NOTIFY_COMMAND)
+    pub notify_command: Option<String>,
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Profile {
+            server: None,
+            name: None,
+            colors: true,
+            notify_command: None,
+        }
+    }
+}
+
+pub fn path(state_dir: &Path) -> PathBuf {
+    state_dir.join(CONFIG_DIR).join(PROFILE_FILE)
+}
+
+impl Profile {
+    /// The profile saved at `path` (None when there isn't one yet). Lines that
+    /// aren't settings are reported and skipped.
+    pub fn load(path: &Path) -> Option<Self> {
+        let contents = fs::read_to_string(path).ok()?;
+        let mut profile = Profile::default();
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let value = |value: &str| Some(value.to_string()).filter(|value| !value.is_empty());
+            match line.split_once('=').map(|(k, v)| (k.trim(), v.trim())) {
+                Some(("server", server)) => profile.server = value(server),
+                Some(("name", name)) => profile.name = value(name),
+                Some(("colors", colors)) => {
+                    profile.colors = !matches!(colors, "0" | "false" | "no" | "off")
+                }
+                Some(("notify_command", command)) => profile.notify_command = value(command),
+                _ => logger::log_warning(&format!(
+                    "{}:{}: skipped, not a profile setting",
+                    path.display(),
+                    number + 1
+                )),
+            }
+        }
+        Some(profile)
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut contents = String::from("# Written by the client's first-run setup\n");
+        if let Some(server) = &self.server {
+            contents.push_str(&format!("server = {}\n", server));
+        }
+        if let Some(name) = &self.name {
+            contents.push_str(&format!("name = {}\n", name));
+        }
+        contents.push_str(&format!(
+            "colors = {}\n",
+            if self.colors { "on" } else { "off" }
+        ));
+        if let Some(command) = &self.notify_command {
+            contents.push_str(&format!("notify_command = {}\n", command));
+        }
+        state_dir::write_atomic(path, contents.as_bytes())
+    }
+}
+
+/// How test connections are made: the way the client's own will be
+pub struct Probe<'a> {
+    pub preference: IpPreference,
+    pub identity: Option<&'a ClientIdentity>,
+    pub proxy: Option<&'a Proxy>,
+    pub timeouts: &'a Timeouts,
+}
+
+/// A connection that has passed the version check, and gone no further
+struct ProbeConnection {
+    stream: BufStream<ClientStream>,
+    write_timeout: Option<Duration>,
+}
+
+impl TcpMessageHandler for ProbeConnection {
+    type Stream = BufStream<ClientStream>;
+    fn get_stream(&mut self) -> &mut Self::Stream {
+        &mut self.stream
+    }
+
+    fn write_timeout(&self) -> Option<Duration> {
+        self.write_timeout
+    }
+}
+
+impl ProbeConnection {
+    /// Say goodbye, so the server doesn't take us for a dropped connection
+    async fn leave(mut self) {
+        if let Ok(leave) = ChatMessage::try_new(MessageTypes::Leave, None) {
+            let _ = self.send_message_chunked(leave).await;
+        }
+    }
+}
+
+impl Probe<'_> {
+    /// Connect to `server` and send our version
+    async fn hello(&self, server: &str) -> Result<ProbeConnection, StartupError> {
+        let (host, port, use_tls) = startup::parse_server_addr(server)?;
+        let stream = startup::open_connection(
+            &host,
+            port,
+            use_tls,
+            self.preference,
+            self.identity,
+            self.proxy.map(|proxy| (proxy, PROBE_IDENTITY)),
+            self.timeouts,
+        )
+        .await?;
+        let mut connection = ProbeConnection {
+            stream: connection_buffers(stream),
+            write_timeout: self.timeouts.write,
+        };
+        let hello = ChatMessage::try_new(
+            MessageTypes::VersionCheck,
+            Some(VERSION.as_bytes().to_vec()),
+        )
+        .map_err(|_| StartupError::Io(io::ErrorKind::InvalidData.into()))?;
+        connection
+            .send_message_chunked(hello)
+            .await
+            .map_err(StartupError::Io)?;
+        Ok(connection)
+    }
+
+    /// Whether `name` is free on `server` (Err says why we couldn't tell)
+    async fn check_name(&self, server: &str, name: &str) -> Result<NameStatus, String> {
+        let check = async {
+            let mut connection = self.hello(server).await.map_err(|e| e.to_string())?;
+            let question =
+                ChatMessage::try_new(MessageTypes::NameCheck, Some(name.as_bytes().to_vec()))
+                    .map_err(|_| "the nickname is too long".to_string())?;
+            connection
+                .send_message_chunked(question)
+                .await
+                .map_err(|e| e.to_string())?;
+            loop {
+                let reply = connection
+                    .read_message_chunked()
+                    .await
+                    .map_err(|e| e.to_string())?;
+                let content = reply.content_as_string().unwrap_or_default();
+                match reply.msg_type {
+                    MessageTypes::NameCheck => {
+                        connection.leave().await;
+                        return NameStatus::decode(&content)
+                            .ok_or_else(|| "the server's answer wasn't understood".to_string());
+                    }
+                    MessageTypes::VersionMismatch => {
+                        return Err("the server runs an incompatible version".to_string());
+                    }
+                    // Servers from before nickname checks refuse the question
+                    MessageTypes::Error => {
+                        let text = ChatError::parse(&content).1;
+                        return Err(Rejection::decode(text)
+                            .map_or_else(|| text.to_string(), |rejection| rejection.text));
+                    }
+                    _ => {}
+                }
+            }
+        };
+        timeout(self.timeouts.connect, check)
+            .await
+            .unwrap_or_else(|_| Err("the server didn't answer".to_string()))
+    }
+}
+
+/// Ask for everything a profile holds, trying each answer against the server
+pub async fn run(
+    default_server: &str,
+    default_name: &str,
+    probe: &Probe<'_>,
+) -> io::Result<Profile> {
+    logger::log_info("No profile yet - a few questions to get you set up.");
+
+    let mut server = default_server.to_string();
+    loop {
+        server = prompt_input("Chat server", &server)?;
+        logger::log_info(&format!("Checking {}...", server));
+        match probe.hello(&server).await {
+            Ok(connection) => {
+                connection.leave().await;
+                logger::log_success(&format!("{} is reachable", server));
+                break;
+            }
+            Err(e) => {
+                e.log();
+                // It may just be down right now, but an address has to parse
+                if !matches!(e, StartupError::InvalidAddress(_)) && confirm("Use it anyway", false)?
+                {
+                    break;
+                }
+            }
+        }
+    }
+
+    let mut name = default_name.to_string();
+    loop {
+        name = prompt_input("Nickname", &name)?;
+        match probe.check_name(&server, &name).await {
+            Ok(NameStatus::Available) => {
+                logger::log_success(&format!("'{}' is free", name));
+                break;
+            }
+            Ok(NameStatus::Registered) => {
+                logger::log_warning(&format!(
+                    "'{}' is registered - joining with it needs its password (CHAT_PASSWORD)",
+                    name
+                ));
+                if confirm("Is it yours", false)? {
+                    break;
+                }
+            }
+            Ok(NameStatus::InUse) => {
+                logger::log_warning(&format!(
+                    "'{}' is in use right now - the server will rename you if it still is when you join",
+                    name
+                ));
+                if confirm("Keep it anyway", false)? {
+                    break;
+                }
+            }
+            Ok(NameStatus::Reserved(reason)) => {
+                logger::log_warning(&reason);
+                name = default_name.to_string();
+            }
+            Ok(NameStatus::Invalid) => {
+                logger::log_warning(
+                    "Nicknames are letters, digits, underscore and hyphen - pick another",
+                );
+                name = default_name.to_string();
+            }
+            Err(reason) => {
+                logger::log_warning(&format!("Couldn't check '{}': {}", name, reason));
+                break;
+            }
+        }
+    }
+
+    let colors = confirm("Use colors", true)?;
+    let notify_command = prompt_input(
+        "Command to run on mentions and direct messages, e.g. notify-send",
+        "none",
+    )?;
+    Ok(Profile {
+        server: Some(server),
+        name: Some(name),
+        colors,
+        notify_command: Some(notify_command).filter(|command| command != "none"),
+    })
+}
+
+fn confirm(question: &str, default: bool) -> io::Result<bool> {
+    let answer = prompt_input(
+        &format!("{}? [{}]", question, if default { "Y/n" } else { "y/N" }),
+        if default { "y" } else { "n" },
+    )?;
+    Ok(matches!(answer.to_lowercase().as_str(), "y" | "yes"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_profile_round_trip() {
+        let dir = std::env::temp_dir().join(format!("rust_chat_profile_{}", std::process::id()));
+        let path = path(&dir);
+        assert_eq!(Profile::load(&path), None);
+
+        let profile = Profile {
+            server: Some("tls://chat.lan:8443".to_string()),
+            name: Some("alice".to_string()),
+            colors: false,
+            notify_command: Some("notify-send -u critical".to_string()),
+        };
+        profile.save(&path).unwrap();
+        assert_eq!(Profile::load(&path), Some(profile));
+
+        // Hand edits: comments, spacing, unknown keys and empty values
+        fs::write(&path, "# mine\nname=bob\n\nserver =\nfont = mono\n").unwrap();
+        assert_eq!(
+            Profile::load(&path),
+            Some(Profile {
+                name: Some("bob".to_string()),
+                ..Profile::default()
+            })
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,838 @@
+//! Connecting to the server at startup: failures are diagnosed in plain words,
+//! interactive users can retry or edit the address, and scripts get an exit
+//! code that says what went wrong (sysexits.h values).
+
+use crate::client::ClientStream;
+use crate::trust::{self, PinningVerifier};
+use rustls::ClientConfig;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use shared::logger;
+use shared::socket::{Keepalive, SocketOptions};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, IsTerminal, Write};
+use std::net::SocketAddr;
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpStream, lookup_host};
+use tokio::time::timeout;
+use tokio_rustls::TlsConnector;
+use tokio_socks::tcp::Socks5Stream;
+
+/// How long to wait for the TCP connection, the TLS handshake and the join
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// The server pings every 30 seconds, so this long without a frame means it's gone
+pub const READ_TIMEOUT: Duration = Duration::from_secs(90);
+/// How long sending a message may take, until the server acknowledges it
+pub const WRITE_TIMEOUT: Duration = Duration::from_secs(30);
+/// Idle time before TCP keepalive probes, which keep NAT and firewall state alive
+pub const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(60);
+/// Port used when the address doesn't name one
+pub const DEFAULT_PORT: u16 = 8080;
+/// How long to wait for a proxy to reach the server - building a Tor circuit to
+/// an onion service can take a while
+pub const PROXY_CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+/// Tor's default SOCKS port, used for `CHAT_PROXY=tor`
+const TOR_SOCKS_ADDR: &str = "127.0.0.1:9050";
+
+/// SOCKS5 proxy to connect through, e.g. Tor's
+#[derive(Debug, Clone, PartialEq)]
+pub struct Proxy {
+    /// host:port of the proxy
+    pub addr: String,
+}
+
+impl Proxy {
+    /// `socks5://host:port`, `host:port`, or `tor` for a local Tor. Host names are
+    /// always resolved by the proxy (socks5h:// is accepted for the same thing).
+    pub fn parse(value: &str) -> Option<Self> {
+        let value = value.trim();
+        if value.eq_ignore_ascii_case("tor") {
+            return Some(Proxy {
+                addr: TOR_SOCKS_ADDR.to_string(),
+            });
+        }
+        let addr = value
+            .strip_prefix("socks5://")
+            .or_else(|| value.strip_prefix("socks5h://"))
+            .unwrap_or(value);
+        let (host, port) = addr.rsplit_once(':')?;
+        (!host.is_empty() && port.parse::<u16>().is_ok()).then(|| Proxy {
+            addr: addr.to_string(),
+        })
+    }
+}
+
+/// Connection timeouts (CHAT_CONNECT_TIMEOUT and friends), and the TCP tuning that
+/// goes with them (CHAT_TCP_NODELAY and friends). None turns a timeout off.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Timeouts {
+    /// Each of the TCP connection, the TLS handshake and joining
+    pub connect: Duration,
+    /// Nothing received for this long counts as a lost connection
+    pub read: Option<Duration>,
+    pub write: Option<Duration>,
+    /// Nagle's algorithm, keepalive probes and buffer sizes
+    pub socket: SocketOptions,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts {
+            connect: CONNECT_TIMEOUT,
+            read: Some(READ_TIMEOUT),
+            write: Some(WRITE_TIMEOUT),
+            socket: SocketOptions {
+                keepalive: Some(Keepalive {
+                    idle: KEEPALIVE_INTERVAL,
+                    interval: None,
+                    probes: None,
+                }),
+                ..SocketOptions::default()
+            },
+        }
+    }
+}
+
+/// How reconnecting backs off (CHAT_RECONNECT_* variables)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReconnectPolicy {
+    /// Wait after the first failed attempt, doubled after each one after that
+    pub backoff: Duration,
+    pub max_backoff: Duration,
+    /// Give up after this many failed attempts (None = keep trying)
+    pub max_retries: Option<u32>,
+    /// Come back after a kick or ban once the server says it's over, instead of
+    /// staying disconnected (CHAT_RECONNECT_AFTER_KICK)
+    pub after_kick: bool,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        ReconnectPolicy {
+            backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+            max_retries: None,
+            after_kick: false,
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    /// Wait after failed attempt number `attempt` (counting from 1)
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let doublings = attempt.saturating_sub(1).min(31);
+        self.backoff
+            .saturating_mul(1 << doublings)
+            .min(self.max_backoff)
+    }
+}
+
+/// Which address family to try first when a host name has both
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IpPreference {
+    /// Keep the resolver's order
+    System,
+    Ipv4,
+    Ipv6,
+}
+
+impl IpPreference {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.to_lowercase().as_str() {
+            "system" | "any" => Some(IpPreference::System),
+            "ipv4" | "4" => Some(IpPreference::Ipv4),
+            "ipv6" | "6" => Some(IpPreference::Ipv6),
+            _ => None,
+        }
+    }
+
+    /// Put the preferred family first, otherwise keeping the resolver's order
+    pub fn order(self, addrs: &mut [SocketAddr]) {
+        match self {
+            IpPreference::System => {}
+            IpPreference::Ipv4 => addrs.sort_by_key(|addr| !addr.is_ipv4()),
+            IpPreference::Ipv6 => addrs.sort_by_key(|addr| !addr.is_ipv6()),
+        }
+    }
+}
+
+/// Certificate and key we log in with on servers that accept client certificates
+pub struct ClientIdentity {
+    certs: Vec<CertificateDer<'static>>,
+    key: PrivateKeyDer<'static>,
+}
+
+impl ClientIdentity {
+    /// Load a PEM certificate (chain) and private key
+    pub fn load(cert_path: &str, key_path: &str) -> io::Result<Self> {
+        let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(cert_path)?))
+            .collect::<Result<Vec<_>, _>>()?;
+        if certs.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("No certificate found in {}", cert_path),
+            ));
+        }
+        let key = rustls_pemfile::private_key(&mut BufReader::new(File::open(key_path)?))?
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("No private key found in {}", key_path),
+                )
+            })?;
+        Ok(ClientIdentity { certs, key })
+    }
+}
+
+/// Split `[tls://]host[:port]` into host, port and whether to use TLS.
+/// IPv6 literals are written in brackets when a port is given (`[::1]:8080`).
+pub fn parse_server_addr(addr: &str) -> Result<(String, u16, bool), StartupError> {
+    let invalid = || StartupError::InvalidAddress(addr.to_string());
+    let (use_tls, rest) = match addr.trim().strip_prefix("tls://") {
+        Some(stripped) => (true, stripped),
+        None => (false, addr.trim()),
+    };
+
+    let (host, port) = if let Some(bracketed) = rest.strip_prefix('[') {
+        let (host, after) = bracketed.split_once(']').ok_or_else(invalid)?;
+        match after.strip_prefix(':') {
+            Some(port) => (host, Some(port)),
+            None if after.is_empty() => (host, None),
+            None => return Err(invalid()),
+        }
+    } else if rest.matches(':').count() > 1 {
+        // Bare IPv6 literal - can't carry a port
+        (rest, None)
+    } else {
+        match rest.rsplit_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (rest, None),
+        }
+    };
+
+    if host.is_empty() || host.contains(char::is_whitespace) {
+        return Err(invalid());
+    }
+    let port = match port {
+        Some(port) => port.parse::<u16>().map_err(|_| invalid())?,
+        None => DEFAULT_PORT,
+    };
+    Ok((host.to_string(), port, use_tls))
+}
+
+/// `host:port`, with IPv6 literals in brackets
+pub fn display_addr(host: &str, port: u16) -> String {
+    if host.contains(':') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
+/// What we were doing when we gave up waiting
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Stage {
+    Connect,
+    TlsHandshake,
+    Join,
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Stage::Connect => write!(f, "connecting"),
+            Stage::TlsHandshake => write!(f, "the TLS handshake"),
+            Stage::Join => write!(f, "joining the chat"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum StartupError {
+    /// The address couldn't be parsed
+    InvalidAddress(String),
+    /// The host name didn't resolve
+    Dns {
+        host: String,
+        error: io::Error,
+    },
+    /// Nothing is listening on the port
+    Refused(String),
+    /// No route to the host or network
+    Unreachable(String),
+    Timeout {
+        addr: String,
+        stage: Stage,
+        /// Our limit, if it was ours that ran out (not the operating system's)
+        after: Option<Duration>,
+    },
+    /// The TLS handshake failed (bad certificate, not a TLS server, ...)
+    Tls {
+        host: String,
+        error: io::Error,
+    },
+    /// The certificate isn't signed by a public CA (e.g. self-signed) and isn't pinned
+    UntrustedCertificate {
+        host: String,
+        fingerprint: String,
+    },
+    /// The certificate doesn't match the one pinned with /trust
+    CertificateChanged {
+        host: String,
+        fingerprint: String,
+    },
+    /// The proxy couldn't be reached, or couldn't reach the server
+    Proxy {
+        proxy: String,
+        error: String,
+    },
+    /// A .onion address without a proxy to reach it
+    OnionWithoutProxy(String),
+    Io(io::Error),
+}
+
+impl fmt::Display for StartupError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StartupError::InvalidAddress(addr) => write!(f, "'{}' is not a valid address", addr),
+            StartupError::Dns { host, error } => {
+                write!(f, "Could not find server '{}': {}", host, error)
+            }
+            StartupError::Refused(addr) => write!(f, "Connection to {} was refused", addr),
+            StartupError::Unreachable(addr) => write!(f, "{} is unreachable", addr),
+            StartupError::Timeout {
+                addr,
+                stage,
+                after: Some(after),
+            } => write!(
+                f,
+                "Timed out after {}s during {} ({})",
+                after.as_secs(),
+                stage,
+                addr
+            ),
+            StartupError::Timeout {
+                addr,
+                stage,
+                after: None,
+            } => write!(f, "Timed out during {} ({})", stage, addr),
+            StartupError::Tls { host, error } => {
+                write!(f, "TLS handshake with {} failed: {}", host, error)
+            }
+            StartupError::UntrustedCertificate { host, fingerprint } => write!(
+                f,
+                "{}'s certificate is not signed by a trusted authority (fingerprint {})",
+                host, fingerprint
+            ),
+            StartupError::CertificateChanged { host, fingerprint } => write!(
+                f,
+                "{}'s certificate is not the one you trusted (fingerprint now {})",
+                host, fingerprint
+            ),
+            StartupError::Proxy { proxy, error } => {
+                write!(f, "Connecting through proxy {} failed: {}", proxy, error)
+            }
+            StartupError::OnionWithoutProxy(host) => {
+                write!(f, "{} is a Tor onion service", host)
+            }
+            StartupError::Io(error) => write!(f, "Connection failed: {}", error),
+        }
+    }
+}
+
+impl StartupError {
+    /// What the user can do about it
+    pub fn hint(&self) -> &'static str {
+        match self {
+            StartupError::InvalidAddress(_) => {
+                "Use host:port, e.g. chat.example.com:8080 (prefix with tls:// for TLS)"
+            }
+            StartupError::Dns { .. } => {
+                "Check the spelling of the host name and your DNS / internet connection"
+            }
+            StartupError::Refused(_) => {
+                "The host is up but no chat server is listening on that port - check the port number and that the server is running"
+            }
+            StartupError::Unreachable(_) => "Check your network connection and the server address",
+            StartupError::Timeout { stage, .. } => match stage {
+                Stage::Connect => {
+                    "The server didn't answer - a firewall may be dropping the connection"
+                }
+                Stage::TlsHandshake => {
+                    "The server accepted the connection but never finished TLS - is it a TLS server?"
+                }
+                Stage::Join => {
+                    "The server accepted the connection but never answered - it may be overloaded, or speak TLS (try tls://)"
+                }
+            },
+            StartupError::Tls { .. } => {
+                "The server's certificate was rejected or it doesn't speak TLS - try without tls:// if it is a plain server"
+            }
+            StartupError::UntrustedCertificate { .. } => {
+                "If the server uses a self-signed certificate, check this fingerprint against the one in the server's log, then enter /trust <fingerprint>"
+            }
+            StartupError::CertificateChanged { .. } => {
+                "The certificate may have been regenerated - or someone is intercepting the connection. Check with the server's operator before you /trust the new fingerprint"
+            }
+            StartupError::Proxy { .. } => {
+                "Check that the proxy (e.g. tor) is running and that CHAT_PROXY points at its SOCKS port"
+            }
+            StartupError::OnionWithoutProxy(_) => {
+                "Onion services are only reachable through Tor - start tor and set CHAT_PROXY=tor"
+            }
+            StartupError::Io(_) => "Check the server address and try again",
+        }
+    }
+
+    /// Process exit status for scripts (see sysexits.h)
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            StartupError::InvalidAddress(_) => 64, // EX_USAGE
+            StartupError::Dns { .. } => 68,        // EX_NOHOST
+            StartupError::Refused(_)
+            | StartupError::Unreachable(_)
+            | StartupError::Proxy { .. } => {
+                69 // EX_UNAVAILABLE
+            }
+            StartupError::OnionWithoutProxy(_) => 78, // EX_CONFIG
+            StartupError::Timeout { .. } => 75,       // EX_TEMPFAIL
+            StartupError::Tls { .. }
+            | StartupError::UntrustedCertificate { .. }
+            | StartupError::CertificateChanged { .. } => 76, // EX_PROTOCOL
+            StartupError::Io(_) => 74,                // EX_IOERR
+        }
+    }
+
+    /// Diagnose a failed TCP connect
+    fn from_connect(addr: &str, error: io::Error) -> Self {
+        match error.kind() {
+            io::ErrorKind::ConnectionRefused => StartupError::Refused(addr.to_string()),
+            io::ErrorKind::HostUnreachable | io::ErrorKind::NetworkUnreachable => {
+                StartupError::Unreachable(addr.to_string())
+            }
+            io::ErrorKind::TimedOut => StartupError::Timeout {
+                addr: addr.to_string(),
+                stage: Stage::Connect,
+                after: None,
+            },
+            _ => StartupError::Io(error),
+        }
+    }
+
+    /// Fingerprint of a certificate that could be trusted with /trust
+    pub fn certificate_fingerprint(&self) -> Option<&str> {
+        match self {
+            StartupError::UntrustedCertificate { fingerprint, .. }
+            | StartupError::CertificateChanged { fingerprint, .. } => Some(fingerprint),
+            _ => None,
+        }
+    }
+
+    pub fn log(&self) {
+        logger::log_error(&self.to_string());
+        logger::log_info(self.hint());
+    }
+}
+
+impl From<StartupError> for ExitCode {
+    fn from(error: StartupError) -> Self {
+        ExitCode::from(error.exit_code())
+    }
+}
+
+/// Connect - directly, or through a proxy with connections isolated by the given
+/// identity (a nickname) - and do the TLS handshake if asked, presenting our
+/// certificate if we have one
+pub async fn open_connection(
+    host: &str,
+    port: u16,
+    use_tls: bool,
+    preference: IpPreference,
+    identity: Option<&ClientIdentity>,
+    proxy: Option<(&Proxy, &str)>,
+    timeouts: &Timeouts,
+) -> Result<ClientStream, StartupError> {
+    let addr = display_addr(host, port);
+    let stream = match proxy {
+        Some((proxy, isolation)) => connect_via_proxy(proxy, host, port, isolation).await?,
+        None if host.ends_with(".onion") => {
+            return Err(StartupError::OnionWithoutProxy(host.to_string()));
+        }
+        None => connect_direct(host, port, preference, timeouts.connect).await?,
+    };
+    if let Err(e) = timeouts.socket.apply(&stream) {
+        logger::log_warning(&format!("Could not tune the TCP connection: {}", e));
+    }
+
+    if !use_tls {
+        return Ok(ClientStream::Plain(stream));
+    }
+
+    // A fingerprint pinned with /trust replaces the usual CA checks
+    let pinned = trust::pin_path(host, port).and_then(|path| trust::load_pin(&path));
+    let changed = pinned.is_some();
+    let verifier =
+        Arc::new(PinningVerifier::new(pinned).map_err(|e| StartupError::Io(io::Error::other(e)))?);
+    let builder = ClientConfig::builder()
+        .dangerous()
+        .with_custom_certificate_verifier(verifier.clone());
+    let config = match identity {
+        Some(identity) => builder
+            .with_client_auth_cert(identity.certs.clone(), identity.key.clone_key())
+            .map_err(|e| StartupError::Io(io::Error::other(e)))?,
+        None => builder.with_no_client_auth(),
+    };
+    let connector = TlsConnector::from(Arc::new(config));
+    let server_name = ServerName::try_from(host.to_string())
+        .map_err(|_| StartupError::InvalidAddress(host.to_string()))?;
+
+    match timeout(timeouts.connect, connector.connect(server_name, stream)).await {
+        Ok(Ok(tls_stream)) => Ok(ClientStream::Tls(Box::new(tls_stream))),
+        Ok(Err(error)) => Err(match verifier.rejected() {
+            Some(fingerprint) if changed => StartupError::CertificateChanged {
+                host: host.to_string(),
+                fingerprint,
+            },
+            Some(fingerprint) => StartupError::UntrustedCertificate {
+                host: host.to_string(),
+                fingerprint,
+            },
+            None => StartupError::Tls {
+                host: host.to_string(),
+                error,
+            },
+        }),
+        Err(_) => Err(StartupError::Timeout {
+            addr,
+            stage: Stage::TlsHandshake,
+            after: Some(timeouts.connect),
+        }),
+    }
+}
+
+/// Probe an idle connection every `interval`, so a dead peer is noticed and
+/// middleboxes don't forget the connection
+/// Resolve the host and connect, trying each address in order of preference
+async fn connect_direct(
+    host: &str,
+    port: u16,
+    preference: IpPreference,
+    connect_timeout: Duration,
+) -> Result<TcpStream, StartupError> {
+    let addr = display_addr(host, port);
+    let mut addrs: Vec<SocketAddr> = lookup_host((host, port))
+        .await
+        .map_err(|error| StartupError::Dns {
+            host: host.to_string(),
+            error,
+        })?
+        .collect();
+    preference.order(&mut addrs);
+    if addrs.is_empty() {
+        return Err(StartupError::Dns {
+            host: host.to_string(),
+            error: io::Error::new(io::ErrorKind::NotFound, "no addresses found"),
+        });
+    }
+
+    // Keep the first failure - it's for the address we preferred
+    let mut first_error = None;
+    let mut stream = None;
+    let count = addrs.len();
+    for (i, socket_addr) in addrs.into_iter().enumerate() {
+        let error = match timeout(connect_timeout, TcpStream::connect(socket_addr)).await {
+            Ok(Ok(connected)) => {
+                stream = Some(connected);
+                break;
+            }
+            Ok(Err(e)) => StartupError::from_connect(&addr, e),
+            Err(_) => StartupError::Timeout {
+                addr: addr.clone(),
+                stage: Stage::Connect,
+                after: Some(connect_timeout),
+            },
+        };
+        if i + 1 < count {
+            logger::log_warning(&format!(
+                "Could not connect to {} ({}), trying the next address",
+                socket_addr, error
+            ));
+        }
+        first_error.get_or_insert(error);
+    }
+    stream.ok_or_else(|| first_error.unwrap_or(StartupError::Refused(addr)))
+}
+
+/// Connect through a SOCKS5 proxy, which also resolves the host name (so lookups
+/// don't leak outside Tor). The identity and server are sent as the SOCKS
+/// credentials: Tor gives each pair its own circuit, so two nicknames can't be
+/// linked by sharing one.
+async fn connect_via_proxy(
+    proxy: &Proxy,
+    host: &str,
+    port: u16,
+    isolation: &str,
+) -> Result<TcpStream, StartupError> {
+    let server = display_addr(host, port);
+    let connect = Socks5Stream::connect_with_password(
+        proxy.addr.as_str(),
+        (host, port),
+        isolation,
+        server.as_str(),
+    );
+    match timeout(PROXY_CONNECT_TIMEOUT, connect).await {
+        Ok(Ok(stream)) => Ok(stream.into_inner()),
+        Ok(Err(error)) => Err(StartupError::Proxy {
+            proxy: proxy.addr.clone(),
+            error: error.to_string(),
+        }),
+        Err(_) => Err(StartupError::Proxy {
+            proxy: proxy.addr.clone(),
+            error: format!(
+                "no connection to {} after {}s",
+                server,
+                PROXY_CONNECT_TIMEOUT.as_secs()
+            ),
+        }),
+    }
+}
+
+/// What to do after a failed startup
+pub enum Recovery {
+    Retry,
+    Edit(String),
+    Quit,
+}
+
+/// Ask an interactive user whether to retry, edit the address or give up - or,
+/// when the server's certificate was rejected, to trust it.
+/// Without a terminal (scripts, pipes) we always give up.
+pub fn prompt_recovery(server_addr: &str, error: &StartupError) -> Recovery {
+    if !io::stdin().is_terminal() {
+        return Recovery::Quit;
+    }
+    let presented = error.certificate_fingerprint();
+    loop {
+        if presented.is_some() {
+            logger::log_info(
+                "[r]etry, [e]dit the address, /trust <fingerprint> or [q]uit? (default: q)",
+            );
+        } else {
+            logger::log_info("[r]etry, [e]dit the address or [q]uit? (default: q)");
+        }
+        let _ = io::stdout().flush();
+        let mut answer = String::new();
+        if io::stdin().read_line(&mut answer).unwrap_or(0) == 0 {
+            return Recovery::Quit;
+        }
+        match answer.trim().to_lowercase().as_str() {
+            "r" | "retry" => return Recovery::Retry,
+            "e" | "edit" => {
+                logger::log_info(&format!("Enter Chat Server (current: {}):", server_addr));
+                let _ = io::stdout().flush();
+                let mut addr = String::new();
+                if io::stdin().read_line(&mut addr).unwrap_or(0) == 0 {
+                    return Recovery::Quit;
+                }
+                let addr = addr.trim();
+                return if addr.is_empty() {
+                    Recovery::Retry
+                } else {
+                    Recovery::Edit(addr.to_string())
+                };
+            }
+            "" | "q" | "quit" => return Recovery::Quit,
+            answer if answer.starts_with("/trust") => {
+                let Some(presented) = presented else {
+                    continue;
+                };
+                // They have to type it, having checked it against the server's log
+                let typed = answer["/trust".len()..].trim();
+                if shared::tls::parse_fingerprint(typed).as_deref() != Some(presented) {
+                    logger::log_error("That isn't the fingerprint of the server's certificate");
+                } else if trust_certificate(server_addr, presented) {
+                    return Recovery::Retry;
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Pin `fingerprint` for the server at `server_addr`
+fn trust_certificate(server_addr: &str, fingerprint: &str) -> bool {
+    let Some(path) = parse_server_addr(server_addr)
+        .ok()
+        .and_then(|(host, port, _)| trust::pin_path(&host, port))
+    else {
+        logger::log_error(
+            "No state directory to keep trusted certificates in - set CHAT_STATE_DIR",
+        );
+        return false;
+    };
+    match trust::save_pin(&path, fingerprint) {
+        Ok(()) => {
+            logger::log_success(&format!("Trusted certificate {}", fingerprint));
+            true
+        }
+        Err(e) => {
+            logger::log_error(&format!("Could not save the trusted certificate: {}", e));
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_server_addr() {
+        let parse = |addr| parse_server_addr(addr).ok();
+        assert_eq!(
+            parse("chat.example.com:9000"),
+            Some(("chat.example.com".to_string(), 9000, false))
+        );
+        assert_eq!(
+            parse("tls://chat.example.com"),
+            Some(("chat.example.com".to_string(), DEFAULT_PORT, true))
+        );
+        assert_eq!(parse("[::1]:9000"), Some(("::1".to_string(), 9000, false)));
+        assert_eq!(
+            parse("tls://[2001:db8::1]"),
+            Some(("2001:db8::1".to_string(), DEFAULT_PORT, true))
+        );
+        assert_eq!(parse("::1"), Some(("::1".to_string(), DEFAULT_PORT, false)));
+        assert_eq!(parse("localhost:port"), None);
+        assert_eq!(parse("[::1"), None);
+        assert_eq!(parse(":8080"), None);
+        assert_eq!(display_addr("::1", 9000), "[::1]:9000");
+    }
+
+    #[test]
+    fn test_ip_preference_order() {
+        let v4: SocketAddr = "192.0.2.1:8080".parse().unwrap();
+        let v4b: SocketAddr = "192.0.2.2:8080".parse().unwrap();
+        let v6: SocketAddr = "[2001:db8::1]:8080".parse().unwrap();
+
+        let mut addrs = vec![v4, v6, v4b];
+        IpPreference::Ipv6.order(&mut addrs);
+        assert_eq!(addrs, vec![v6, v4, v4b]);
+        IpPreference::Ipv4.order(&mut addrs);
+        assert_eq!(addrs, vec![v4, v4b, v6]);
+        IpPreference::System.order(&mut addrs);
+        assert_eq!(addrs, vec![v4, v4b, v6]);
+        assert_eq!(IpPreference::parse("IPv6"), Some(IpPreference::Ipv6));
+        assert_eq!(IpPreference::parse("both"), None);
+    }
+
+    #[test]
+    fn test_reconnect_backoff() {
+        let policy = ReconnectPolicy::default();
+        assert_eq!(policy.delay(1), Duration::from_secs(1));
+        assert_eq!(policy.delay(2), Duration::from_secs(2));
+        assert_eq!(policy.delay(4), Duration::from_secs(8));
+        assert_eq!(policy.delay(7), Duration::from_secs(60));
+        assert_eq!(policy.delay(100), Duration::from_secs(60));
+
+        let policy = ReconnectPolicy {
+            backoff: Duration::from_secs(5),
+            max_backoff: Duration::from_secs(12),
+            max_retries: Some(3),
+            ..ReconnectPolicy::default()
+        };
+        assert_eq!(policy.delay(2), Duration::from_secs(10));
+        assert_eq!(policy.delay(3), Duration::from_secs(12));
+    }
+
+    #[tokio::test]
+    async fn test_hostname_is_resolved() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let connection = open_connection(
+            "localhost",
+            port,
+            false,
+            IpPreference::Ipv4,
+            None,
+            None,
+            &Timeouts::default(),
+        )
+        .await;
+        assert!(matches!(connection, Ok(ClientStream::Plain(_))));
+    }
+
+    #[tokio::test]
+    async fn test_refused_connection_is_diagnosed() {
+        // Bind then drop a listener so the port is known to be closed
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        drop(listener);
+
+        let error = open_connection(
+            "127.0.0.1",
+            port,
+            false,
+            IpPreference::System,
+            None,
+            None,
+            &Timeouts::default(),
+        )
+        .await
+        .err()
+        .unwrap();
+        assert!(matches!(error, StartupError::Refused(_)), "{:?}", error);
+        assert_eq!(error.exit_code(), 69);
+    }
+
+    #[test]
+    fn test_exit_codes_are_distinct_per_cause() {
+        let timeout = StartupError::Timeout {
+            addr: "example.com:8080".to_string(),
+            stage: Stage::Join,
+            after: Some(Duration::from_secs(25)),
+        };
+        assert_eq!(timeout.exit_code(), 75);
+        assert!(timeout.to_string().contains("joining the chat"));
+        assert!(timeout.to_string().contains("25s"));
+        assert_eq!(
+            StartupError::InvalidAddress("host:port".to_string()).exit_code(),
+            64
+        );
+        let dns = StartupError::Dns {
+            host: "nowhere".to_string(),
+            error: io::Error::new(io::ErrorKind::NotFound, "no addresses found"),
+        };
+        assert_eq!(dns.exit_code(), 68);
+    }
+
+    #[tokio::test]
+    async fn test_proxy() {
+        let tor = Proxy::parse("Tor").unwrap();
+        assert_eq!(tor.addr, "127.0.0.1:9050");
+        assert_eq!(
+            Proxy::parse("socks5h://proxy.lan:1080"),
+            Proxy::parse("proxy.lan:1080")
+        );
+        assert_eq!(Proxy::parse("http://proxy.lan"), None);
+        assert_eq!(Proxy::parse(":1080"), None);
+
+        // Onion services can only be reached through Tor
+        let error = open_connection(
+            "example.onion",
+            8080,
+            false,
+            IpPreference::System,
+            None,
+            None,
+            &Timeouts::default(),
+        )
+        .await
+        .err()
+        .unwrap();
+        assert!(matches!(error, StartupError::OnionWithoutProxy(_)));
+        assert_eq!(error.exit_code(), 78);
+    }
+}
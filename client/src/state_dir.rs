@@ -0,0 +1,197 @@
+//! The state directory's layout and how it's kept intact. `VERSION` records the
+//! layout the directory is in; on startup an older layout is migrated one step at a
+//! time, each finished step recorded before the next begins, so a client killed half
+//! way through picks up where it stopped. Files are replaced by writing a temporary
+//! copy and renaming it over the old one: a crash leaves the old file or the new
+//! one, never half of either.
+//!
+//! Layout version 1 (see `paths::StateFile`):
+//!
+//! ```text
+//! VERSION
+//! config/                    settings saved by the client
+//! history/<server>.log       scrollback
+//! logs/<server>.log          protocol traces
+//! drafts/<server>.tsv        unsent input, per room
+//! keys/<server>.key          our signing keys
+//! keys/lineage/<server>.key  client fingerprint secrets
+//! keys/known/<server>.tsv    other users' pinned public keys
+//! keys/trusted/<server>.txt  pinned TLS certificates
+//! ```
+
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// Layout this client reads and writes
+pub const LAYOUT_VERSION: u32 = 1;
+const VERSION_FILE: &str = "VERSION";
+/// Directory for settings the client saves
+pub const CONFIG_DIR: &str = "config";
+/// Suffix of a file being written; one left behind is from an interrupted write
+pub const TEMP_SUFFIX: &str = ".tmp";
+
+/// Migrations from each layout to the next (index 0 migrates version 0 to 1). Every
+/// step must be safe to run again on a directory it has partly migrated.
+const MIGRATIONS: &[fn(&Path) -> io::Result<()>] = &[group_by_kind];
+
+/// What `migrate` found
+#[derive(Debug, PartialEq)]
+pub enum Migration {
+    /// Already in the current layout
+    Current,
+    /// No state yet; the directory was created
+    Created,
+    /// Migrated from an older layout
+    Upgraded { from: u32 },
+    /// Written by a newer client; left alone
+    Newer(u32),
+}
+
+/// The layout version `dir` is in, or None if VERSION is missing or unreadable
+pub fn read_version(dir: &Path) -> Option<u32> {
+    fs::read_to_string(dir.join(VERSION_FILE))
+        .ok()
+        .and_then(|contents| contents.trim().parse().ok())
+}
+
+/// Bring `dir` up to the current layout
+pub fn migrate(dir: &Path) -> io::Result<Migration> {
+    let version = match read_version(dir) {
+        Some(version) if version > LAYOUT_VERSION => return Ok(Migration::Newer(version)),
+        Some(version) => version,
+        None if is_empty(dir) => {
+            fs::create_dir_all(dir.join(CONFIG_DIR))?;
+            write_version(dir, LAYOUT_VERSION)?;
+            return Ok(Migration::Created);
+        }
+        // Clients from before the layout was versioned didn't write VERSION
+        None => 0,
+    };
+    if version == LAYOUT_VERSION {
+        return Ok(Migration::Current);
+    }
+    for (step, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        migration(dir)?;
+        write_version(dir, step as u32 + 1)?;
+    }
+    Ok(Migration::Upgraded { from: version })
+}
+
+fn is_empty(dir: &Path) -> bool {
+    fs::read_dir(dir).map_or(true, |mut entries| entries.next().is_none())
+}
+
+fn write_version(dir: &Path, version: u32) -> io::Result<()> {
+    write_atomic(&dir.join(VERSION_FILE), format!("{}\n", version).as_bytes())
+}
+
+/// 0 -> 1: one directory per kind of state, with everything secret under keys/
+fn group_by_kind(dir: &Path) -> io::Result<()> {
+    fs::create_dir_all(dir.join(CONFIG_DIR))?;
+    for (from, to) in [
+        ("scrollback", PathBuf::from("history")),
+        ("traces", PathBuf::from("logs")),
+        ("lineage", Path::new("keys").join("lineage")),
+        ("known_keys", Path::new("keys").join("known")),
+        ("trusted_certs", Path::new("keys").join("trusted")),
+    ] {
+        move_files(&dir.join(from), &dir.join(to))?;
+    }
+    Ok(())
+}
+
+/// Move every file in `from` into `to` (keeping any already there) and remove `from`
+fn move_files(from: &Path, to: &Path) -> io::Result<()> {
+    let entries = match fs::read_dir(from) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    fs::create_dir_all(to)?;
+    for entry in entries {
+        let entry = entry?;
+        let target = to.join(entry.file_name());
+        if !target.exists() {
+            fs::rename(entry.path(), target)?;
+        }
+    }
+    // Anything left is an older copy of a file that was already moved
+    fs::remove_dir_all(from)
+}
+
+/// Replace `path` with `contents` so that a crash leaves the old file or the new one
+pub fn write_atomic(path: &Path, contents: &[u8]) -> io::Result<()> {
+    write_replacing(path, contents, false)
+}
+
+/// `write_atomic` for secrets: the file is only readable by us
+pub fn write_secret(path: &Path, contents: &[u8]) -> io::Result<()> {
+    write_replacing(path, contents, true)
+}
+
+fn write_replacing(path: &Path, contents: &[u8], secret: bool) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(TEMP_SUFFIX);
+    let temp = PathBuf::from(temp);
+    let mut file = fs::File::create(&temp)?;
+    #[cfg(unix)]
+    if secret {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    }
+    #[cfg(not(unix))]
+    let _ = secret;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    fs::rename(temp, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("rust_chat_state_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn test_migrates_unversioned_layout() {
+        let dir = temp_dir("migrate");
+        assert_eq!(migrate(&dir).unwrap(), Migration::Created);
+        assert_eq!(read_version(&dir), Some(LAYOUT_VERSION));
+        assert_eq!(migrate(&dir).unwrap(), Migration::Current);
+        fs::remove_dir_all(&dir).unwrap();
+
+        // A client from before VERSION existed, interrupted after moving one file
+        fs::create_dir_all(dir.join("scrollback")).unwrap();
+        fs::create_dir_all(dir.join("lineage")).unwrap();
+        fs::create_dir_all(dir.join("keys/lineage")).unwrap();
+        fs::write(dir.join("scrollback/host_1.log"), "\t10:00\thi\n").unwrap();
+        fs::write(dir.join("lineage/host_1.key"), "old").unwrap();
+        fs::write(dir.join("keys/lineage/host_1.key"), "moved").unwrap();
+        fs::write(dir.join("lineage/host_2.key"), "other").unwrap();
+        fs::write(dir.join("keys/host_1.key"), "signing").unwrap();
+
+        assert_eq!(migrate(&dir).unwrap(), Migration::Upgraded { from: 0 });
+        assert_eq!(read_version(&dir), Some(LAYOUT_VERSION));
+        assert!(!dir.join("scrollback").exists());
+        assert!(!dir.join("lineage").exists());
+        assert!(dir.join("history/host_1.log").exists());
+        let lineage = |name: &str| fs::read_to_string(dir.join("keys/lineage").join(name));
+        assert_eq!(lineage("host_1.key").unwrap(), "moved");
+        assert_eq!(lineage("host_2.key").unwrap(), "other");
+        assert!(dir.join("keys/host_1.key").exists());
+
+        // A newer client's directory isn't touched
+        write_version(&dir, LAYOUT_VERSION + 1).unwrap();
+        assert_eq!(migrate(&dir).unwrap(), Migration::Newer(LAYOUT_VERSION + 1));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
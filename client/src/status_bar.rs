@@ -0,0 +1,305 @@
+//! Status line pinned to the bottom row of the terminal: connection state, latency,
+//! unread direct messages, the current room and how much of the server's rate limit
+//! is left. Chat output scrolls in a region above it, so the line stays put.
+
+use shared::limits::RateLimits;
+use std::collections::VecDeque;
+use std::io::{self, IsTerminal, Write};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConnectionState {
+    Connected,
+    /// In the server's waiting room at this position
+    Queued(usize),
+    Reconnecting,
+}
+
+#[derive(Debug)]
+pub struct Status {
+    pub state: ConnectionState,
+    /// Round trip of our last Pong (sent, then acknowledged by the server)
+    pub latency: Option<Duration>,
+    /// Direct messages received since we last typed anything
+    pub unread_dms: usize,
+    /// Messages we sent that the server hasn't acknowledged yet
+    pub sending: usize,
+    pub room: Option<String>,
+    /// The current room is private, so what we send to it is encrypted
+    pub encrypted: bool,
+    /// Members of the current room, as of the last room info refresh
+    pub members: Option<usize>,
+    /// Advertised by the server after joining (None = unknown)
+    pub rate_limits: Option<RateLimits>,
+    /// When we sent recent messages, to estimate what's left of the rate limit
+    sent: VecDeque<Instant>,
+    /// The server refused a message and asked us to wait until then
+    limited_until: Option<Instant>,
+}
+
+impl Default for Status {
+    fn default() -> Self {
+        Status {
+            state: ConnectionState::Connected,
+            latency: None,
+            unread_dms: 0,
+            sending: 0,
+            room: None,
+            encrypted: false,
+            members: None,
+            rate_limits: None,
+            sent: VecDeque::new(),
+            limited_until: None,
+        }
+    }
+}
+
+impl Status {
+    pub fn record_send(&mut self, now: Instant) {
+        self.sent.push_back(now);
+    }
+
+    pub fn rate_limited(&mut self, until: Instant) {
+        self.limited_until = Some(until);
+    }
+
+    /// Messages left in the current window and the window's size
+    fn budget(&mut self, now: Instant) -> Option<(usize, usize)> {
+        let limits = self.rate_limits?;
+        while self
+            .sent
+            .front()
+            .is_some_and(|sent| now.duration_since(*sent) >= limits.window)
+        {
+            self.sent.pop_front();
+        }
+        Some((
+            limits.messages.saturating_sub(self.sent.len()),
+            limits.messages,
+        ))
+    }
+
+    /// The status line as plain text, fitted to `width` columns
+    pub fn render(&mut self, now: Instant, width: usize) -> String {
+        let mut parts = vec![match self.state {
+            ConnectionState::Connected => "connected".to_string(),
+            ConnectionState::Queued(position) => format!("waiting room #{}", position),
+            ConnectionState::Reconnecting => "reconnecting...".to_string(),
+        }];
+        if let Some(latency) = self.latency {
+            parts.push(format!("{} ms", latency.as_millis()));
+        }
+        parts.push(match &self.room {
+            Some(room) if self.encrypted => format!("#{} (encrypted)", room),
+            Some(room) => format!("#{}", room),
+            None => "main chat".to_string(),
+        });
+        if let (Some(_), Some(members)) = (&self.room, self.members) {
+            parts.push(format!(
+                "{} member{}",
+                members,
+                if members == 1 { "" } else { "s" }
+            ));
+        }
+        if self.unread_dms > 0 {
+            parts.push(format!("{} unread DM(s)", self.unread_dms));
+        }
+        if self.sending > 0 {
+            parts.push("sending...".to_string());
+        }
+        match self.limited_until.filter(|until| *until > now) {
+            Some(until) => parts.push(format!(
+                "rate limited {}s",
+                until.duration_since(now).as_secs() + 1
+            )),
+            None => {
+                if let Some((left, total)) = self.budget(now) {
+                    parts.push(format!("rate {}/{}", left, total));
+                }
+            }
+        }
+
+        let line: String = format!(" {} ", parts.join(" | "))
+            .chars()
+            .take(width)
+            .collect();
+        let padding = width.saturating_sub(line.chars().count());
+        format!("{}{}", line, " ".repeat(padding))
+    }
+}
+
+struct Inner {
+    status: Status,
+    /// Terminal height the scroll region was set up for
+    rows: usize,
+    /// What's on screen, so unchanged lines aren't redrawn
+    drawn: String,
+}
+
+/// Handle to the status line (cheap to clone; a disabled bar ignores everything)
+#[derive(Clone)]
+pub struct StatusBar {
+    inner: Option<Arc<Mutex<Inner>>>,
+}
+
+impl StatusBar {
+    pub fn disabled() -> Self {
+        StatusBar { inner: None }
+    }
+
+    /// Reserve the bottom row of the terminal, if output is a terminal
+    pub fn start() -> Self {
+        let Some((_, rows)) = terminal_size()
+            .filter(|(_, rows)| *rows > 2)
+            .filter(|_| io::stdout().is_terminal())
+        else {
+            return Self::disabled();
+        };
+        // Scroll everything up a line so the bottom row is free, then keep output above it
+        write_raw(&format!("\n\x1b[1A{}", scroll_region(rows)));
+        let bar = StatusBar {
+            inner: Some(Arc::new(Mutex::new(Inner {
+                status: Status::default(),
+                rows,
+                drawn: String::new(),
+            }))),
+        };
+        bar.draw();
+        bar.watch_resizes();
+        bar
+    }
+
+    /// Change the status and redraw it
+    pub fn update(&self, change: impl FnOnce(&mut Status)) {
+        let Some(inner) = &self.inner else {
+            return;
+        };
+        if let Ok(mut inner) = inner.lock() {
+            change(&mut inner.status);
+        }
+        self.draw();
+    }
+
+    fn draw(&self) {
+        let Some(inner) = &self.inner else {
+            return;
+        };
+        let Some((columns, rows)) = terminal_size() else {
+            return;
+        };
+        let Ok(mut inner) = inner.lock() else {
+            return;
+        };
+        let line = inner.status.render(Instant::now(), columns);
+        if rows == inner.rows && line == inner.drawn {
+            return;
+        }
+        let mut output = String::new();
+        if rows != inner.rows {
+            // Resized: clear the old status row if it's now part of the chat area
+            if inner.rows < rows {
+                output.push_str(&format!("\x1b7\x1b[{};1H\x1b[2K\x1b8", inner.rows));
+            }
+            output.push_str(&scroll_region(rows));
+            inner.rows = rows;
+        }
+        // Save the cursor, draw in reverse video on the last row, put the cursor back
+        output.push_str(&format!(
+            "\x1b7\x1b[{};1H\x1b[2K\x1b[7m{}\x1b[0m\x1b8",
+            rows, line
+        ));
+        write_raw(&output);
+        inner.drawn = line;
+    }
+
+    #[cfg(unix)]
+    fn watch_resizes(&self) {
+        use tokio::signal::unix::{SignalKind, signal};
+        let Ok(mut resized) = signal(SignalKind::window_change()) else {
+            return;
+        };
+        let bar = self.clone();
+        tokio::spawn(async move {
+            while resized.recv().await.is_some() {
+                bar.draw();
+            }
+        });
+    }
+
+    #[cfg(not(unix))]
+    fn watch_resizes(&self) {}
+
+    /// Give the bottom row back to the terminal
+    pub fn stop(&self) {
+        let Some(inner) = &self.inner else {
+            return;
+        };
+        if let Ok(inner) = inner.lock() {
+            write_raw(&format!("\x1b7\x1b[r\x1b[{};1H\x1b[2K\x1b8", inner.rows));
+        }
+    }
+}
+
+/// Limit scrolling to every row but the last, keeping the cursor where it is
+fn scroll_region(rows: usize) -> String {
+    format!("\x1b7\x1b[1;{}r\x1b8", rows - 1)
+}
+
+fn terminal_size() -> Option<(usize, usize)> {
+    terminal_size::terminal_size()
+        .map(|(width, height)| (usize::from(width.0), usize::from(height.0)))
+}
+
+/// One write, so the sequence can't be split by the line editor's own output
+pub fn write_raw(sequence: &str) {
+    let mut stdout = io::stdout().lock();
+    let _ = stdout.write_all(sequence.as_bytes());
+    let _ = stdout.flush();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let now = Instant::now();
+        let mut status = Status {
+            latency: Some(Duration::from_millis(42)),
+            unread_dms: 2,
+            room: Some("ops".to_string()),
+            rate_limits: Some(RateLimits {
+                messages: 10,
+                window: Duration::from_secs(1),
+                max_message: None,
+            }),
+            ..Status::default()
+        };
+        status.record_send(now - Duration::from_secs(5));
+        status.record_send(now);
+        assert_eq!(
+            status.render(now, 60).trim_end(),
+            " connected | 42 ms | #ops | 2 unread DM(s) | rate 9/10"
+        );
+        assert_eq!(status.render(now, 80).len(), 80);
+        assert_eq!(status.render(now, 10), " connected");
+
+        status.state = ConnectionState::Queued(3);
+        status.rate_limited(now + Duration::from_millis(1500));
+        assert!(status.render(now, 80).contains("waiting room #3"));
+        assert!(status.render(now, 80).contains("rate limited 2s"));
+
+        status.encrypted = true;
+        assert!(status.render(now, 80).contains("#ops (encrypted)"));
+
+        status.members = Some(1);
+        assert!(
+            status
+                .render(now, 80)
+                .contains("#ops (encrypted) | 1 member |")
+        );
+        status.room = None;
+        assert!(!status.render(now, 80).contains("member"));
+    }
+}
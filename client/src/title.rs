@@ -0,0 +1,139 @@
+//! Terminal title: the current room and how many messages are unread, so a glance at
+//! a tab or window list is enough. Inside tmux or screen (CHAT_MULTIPLEXER) the window
+//! is named the same way, and mentions and direct messages ring the bell so the
+//! multiplexer flags the window. Nothing is written unless stdout is a terminal.
+
+use crate::status_bar::write_raw;
+use std::env;
+use std::io::{self, IsTerminal};
+
+/// Terminal multiplexer whose window name and bell flag we drive
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Multiplexer {
+    Tmux,
+    Screen,
+}
+
+impl Multiplexer {
+    /// "tmux", "screen", "auto" (whichever we're running in, if any) or "off".
+    /// Outer None = not a setting we know.
+    pub fn parse(value: &str) -> Option<Option<Self>> {
+        match value.to_lowercase().as_str() {
+            "tmux" => Some(Some(Multiplexer::Tmux)),
+            "screen" => Some(Some(Multiplexer::Screen)),
+            "auto" => Some(Multiplexer::detect()),
+            "off" | "0" | "false" | "no" | "none" => Some(None),
+            _ => None,
+        }
+    }
+
+    /// The multiplexer our terminal is in, from the variables each sets
+    fn detect() -> Option<Self> {
+        if env::var_os("TMUX").is_some() {
+            Some(Multiplexer::Tmux)
+        } else if env::var_os("STY").is_some() {
+            Some(Multiplexer::Screen)
+        } else {
+            None
+        }
+    }
+}
+
+/// "(3) #ops - chat.lan", or "main chat - chat.lan" with nothing unread
+pub fn title(room: Option<&str>, unread: usize, server: &str) -> String {
+    let place = match room {
+        Some(room) => format!("#{}", room),
+        None => "main chat".to_string(),
+    };
+    let title = match unread {
+        0 => format!("{} - {}", place, server),
+        unread => format!("({}) {} - {}", unread, place, server),
+    };
+    // Nothing in it may end the escape sequence early
+    title.chars().filter(|c| !c.is_control()).collect()
+}
+
+/// Handle to the terminal title (a disabled title ignores everything)
+pub struct TerminalTitle {
+    enabled: bool,
+    multiplexer: Option<Multiplexer>,
+    server: String,
+    /// What the title says now, so unchanged titles aren't rewritten
+    shown: String,
+}
+
+impl TerminalTitle {
+    pub fn disabled() -> Self {
+        TerminalTitle {
+            enabled: false,
+            multiplexer: None,
+            server: String::new(),
+            shown: String::new(),
+        }
+    }
+
+    /// Take over the title of the terminal `server`'s chat runs in, if output is one
+    pub fn start(server: &str, multiplexer: Option<Multiplexer>) -> Self {
+        if !io::stdout().is_terminal() {
+            return Self::disabled();
+        }
+        // Keep the terminal's own title to put back when we're done
+        write_raw("\x1b[22;0t");
+        TerminalTitle {
+            enabled: true,
+            multiplexer,
+            server: server.to_string(),
+            shown: String::new(),
+        }
+    }
+
+    pub fn update(&mut self, room: Option<&str>, unread: usize) {
+        if !self.enabled {
+            return;
+        }
+        let title = title(room, unread, &self.server);
+        if title == self.shown {
+            return;
+        }
+        let mut output = format!("\x1b]2;{}\x07", title);
+        if self.multiplexer.is_some() {
+            // Window names are short: leave the server off
+            let name = title
+                .rsplit_once(" - ")
+                .map_or(title.as_str(), |(name, _)| name);
+            output.push_str(&format!("\x1bk{}\x1b\\", name));
+        }
+        write_raw(&output);
+        self.shown = title;
+    }
+
+    /// A mention or direct message arrived: flag the window in the multiplexer
+    pub fn alert(&self) {
+        if self.enabled && self.multiplexer.is_some() {
+            write_raw("\x07");
+        }
+    }
+
+    /// Give the title back to the terminal
+    pub fn stop(&self) {
+        if self.enabled {
+            write_raw("\x1b[23;0t");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_title() {
+        assert_eq!(title(Some("ops"), 3, "chat.lan"), "(3) #ops - chat.lan");
+        assert_eq!(title(None, 0, "chat.lan"), "main chat - chat.lan");
+        assert_eq!(title(Some("ops\x07"), 0, "chat.lan"), "#ops - chat.lan");
+
+        assert_eq!(Multiplexer::parse("TMUX"), Some(Some(Multiplexer::Tmux)));
+        assert_eq!(Multiplexer::parse("off"), Some(None));
+        assert_eq!(Multiplexer::parse("zellij"), None);
+    }
+}
@@ -0,0 +1,148 @@
+//! Trusting a server's TLS certificate by its fingerprint (`/trust`), for servers
+//! with a self-signed certificate. Once a fingerprint is pinned for a server, that
+//! certificate is accepted - and no other, even one signed by a public CA.
+
+use crate::paths::{self, StateFile};
+use crate::state_dir;
+use rustls::client::WebPkiServerVerifier;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{CertificateError, DigitallySignedStruct, Error, RootCertStore, SignatureScheme};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// Where the pinned fingerprint for a server is kept
+pub fn pin_path(host: &str, port: u16) -> Option<PathBuf> {
+    paths::state_dir()
+        .map(|dir| StateFile::TrustedCert.path(&dir, &paths::server_file_stem(host, port)))
+}
+
+/// The fingerprint pinned in `path`, if any
+pub fn load_pin(path: &Path) -> Option<String> {
+    fs::read_to_string(path)
+        .ok()
+        .and_then(|contents| shared::tls::parse_fingerprint(&contents))
+}
+
+pub fn save_pin(path: &Path, fingerprint: &str) -> io::Result<()> {
+    state_dir::write_atomic(path, format!("{}\n", fingerprint).as_bytes())
+}
+
+/// Checks the server's certificate against the pinned fingerprint, or against the
+/// public CAs when nothing is pinned
+#[derive(Debug)]
+pub struct PinningVerifier {
+    pinned: Option<String>,
+    webpki: Arc<WebPkiServerVerifier>,
+    /// Fingerprint of a certificate we turned down
+    rejected: Mutex<Option<String>>,
+}
+
+impl PinningVerifier {
+    pub fn new(pinned: Option<String>) -> Result<Self, rustls::client::VerifierBuilderError> {
+        let mut roots = RootCertStore::empty();
+        roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+        Ok(PinningVerifier {
+            pinned,
+            webpki: WebPkiServerVerifier::builder(Arc::new(roots)).build()?,
+            rejected: Mutex::new(None),
+        })
+    }
+
+    /// Fingerprint of the certificate that failed verification, if one did
+    pub fn rejected(&self) -> Option<String> {
+        self.rejected
+            .lock()
+            .ok()
+            .and_then(|rejected| rejected.clone())
+    }
+
+    fn reject(&self, fingerprint: String, error: Error) -> Result<ServerCertVerified, Error> {
+        if let Ok(mut rejected) = self.rejected.lock() {
+            *rejected = Some(fingerprint);
+        }
+        Err(error)
+    }
+}
+
+impl ServerCertVerifier for PinningVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, Error> {
+        let fingerprint = shared::tls::certificate_fingerprint(end_entity);
+        match &self.pinned {
+            Some(pinned) if *pinned == fingerprint => Ok(ServerCertVerified::assertion()),
+            Some(_) => self.reject(
+                fingerprint,
+                Error::InvalidCertificate(CertificateError::ApplicationVerificationFailure),
+            ),
+            None => self
+                .webpki
+                .verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)
+                .or_else(|error| self.reject(fingerprint, error)),
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        self.webpki.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        self.webpki.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.webpki.supported_verify_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pinned_certificate() {
+        let cert = CertificateDer::from(b"not really a certificate".to_vec());
+        let fingerprint = shared::tls::certificate_fingerprint(&cert);
+        let name = ServerName::try_from("chat.local").unwrap();
+        let verify = |verifier: &PinningVerifier| {
+            verifier.verify_server_cert(&cert, &[], &name, &[], UnixTime::now())
+        };
+
+        let pinned = PinningVerifier::new(Some(fingerprint.clone())).unwrap();
+        assert!(verify(&pinned).is_ok());
+        assert_eq!(pinned.rejected(), None);
+
+        // Not signed by a public CA, and not pinned
+        let unpinned = PinningVerifier::new(None).unwrap();
+        assert!(verify(&unpinned).is_err());
+        assert_eq!(unpinned.rejected(), Some(fingerprint.clone()));
+
+        let other =
+            PinningVerifier::new(Some(shared::tls::certificate_fingerprint(b"other"))).unwrap();
+        assert!(verify(&other).is_err());
+        assert_eq!(other.rejected(), Some(fingerprint.clone()));
+
+        let path = std::env::temp_dir().join(format!("rust_chat_pin_{}", std::process::id()));
+        save_pin(&path, &fingerprint).unwrap();
+        assert_eq!(load_pin(&path), Some(fingerprint));
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,18 @@
+//! Decodes arbitrary message bytes into a `ChatMessage` and checks that
+//! encoding it again gives back the original bytes.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use shared::message::ChatMessage;
+
+fuzz_target!(|data: &[u8]| {
+    let message = ChatMessage::from(data.to_vec());
+    let _ = message.content_as_string();
+
+    // Anything shorter than the header decodes to an empty placeholder message
+    if data.len() >= 5 {
+        let encoded: Vec<u8> = message.into();
+        assert_eq!(encoded, data);
+    }
+});
@@ -0,0 +1,81 @@
+//! Feeds arbitrary bytes through the framing layer, as if a client sent them
+//! over the socket, and reads messages until the stream ends or errors.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use shared::network::TcpMessageHandler;
+use std::io::Cursor;
+use std::pin::Pin;
+use std::sync::OnceLock;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::runtime::Runtime;
+
+/// Reads from the fuzz input and discards everything written (the "OK" acks)
+struct FuzzStream {
+    input: Cursor<Vec<u8>>,
+}
+
+impl AsyncRead for FuzzStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().input).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for FuzzStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+struct FuzzConnection {
+    stream: FuzzStream,
+}
+
+impl TcpMessageHandler for FuzzConnection {
+    type Stream = FuzzStream;
+    fn get_stream(&mut self) -> &mut Self::Stream {
+        &mut self.stream
+    }
+}
+
+fn runtime() -> &'static Runtime {
+    static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+    RUNTIME.get_or_init(|| {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .expect("Failed to build runtime")
+    })
+}
+
+fuzz_target!(|data: &[u8]| {
+    let mut connection = FuzzConnection {
+        stream: FuzzStream {
+            input: Cursor::new(data.to_vec()),
+        },
+    };
+
+    runtime().block_on(async {
+        while let Ok(message) = connection.read_message_chunked().await {
+            // Decoded messages must be safe to inspect
+            let _ = message.content_as_string();
+        }
+    });
+});
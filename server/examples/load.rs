@@ -0,0 +1,493 @@
+//! Load generator for comparing server runtimes (e.g. the default reactor against
+//! the io_uring backend). Opens many connections, joins each, then sends chat
+//! messages one at a time from each connection in turn and times how long every
+//! message takes to reach every connection. Connections join one at a time, so
+//! joining also shows how fan-out grows with the number of connections.
+//!
+//!     cargo run --release -p server --example load -- --connections 1000 --messages 500
+//!
+//! With `--rooms <n>` the connections are spread over that many rooms and send room
+//! messages instead, each reaching only its room. Pass `--server-pid <pid>` (Linux) to
+//! also report the CPU time the server spent, per message and connection.
+//!
+//! The server needs room for the connections (CHAT_SERVER_MAX_CLIENTS) and both
+//! processes need enough file descriptors (ulimit -n).
+
+use shared::message::{ChatMessage, MessageTypes};
+use shared::network::TcpMessageHandler;
+use shared::version::VERSION;
+use std::env;
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+use tokio::sync::{Notify, mpsc, oneshot};
+
+const USAGE: &str = "Usage: load [--addr <host:port>] [--connections <n>] [--messages <n>] \
+                     [--rooms <n>] [--server-pid <pid>]";
+/// Server rate limit is 10 messages a second per connection; stay under it
+const MIN_SEND_INTERVAL: Duration = Duration::from_millis(110);
+/// Traffic stops this often so pings can be answered
+const PAUSE_INTERVAL: Duration = Duration::from_secs(1);
+/// Traffic has stopped once nothing has arrived for this long
+const PONG_DELAY: Duration = Duration::from_millis(20);
+/// Clock ticks per second in /proc/<pid>/stat (USER_HZ, 100 on Linux)
+const TICKS_PER_SECOND: f64 = 100.0;
+
+struct Options {
+    addr: String,
+    connections: usize,
+    messages: usize,
+    /// Rooms to spread the connections over, or 0 to chat in the lobby
+    rooms: usize,
+    server_pid: Option<u32>,
+}
+
+impl Options {
+    fn parse() -> Result<Self, String> {
+        let mut options = Options {
+            addr: "127.0.0.1:8080".to_string(),
+            connections: 1000,
+            messages: 500,
+            rooms: 0,
+            server_pid: None,
+        };
+        let mut args = env::args().skip(1);
+        while let Some(arg) = args.next() {
+            let value = args.next().ok_or(format!("{} needs a value", arg))?;
+            match arg.as_str() {
+                "--addr" => options.addr = value,
+                "--connections" => options.connections = number(&arg, &value)?,
+                "--messages" => options.messages = number(&arg, &value)?,
+                "--rooms" => options.rooms = number(&arg, &value)?,
+                "--server-pid" => options.server_pid = Some(number(&arg, &value)? as u32),
+                _ => return Err(format!("Unknown argument '{}'", arg)),
+            }
+        }
+        Ok(options)
+    }
+}
+
+fn number(arg: &str, value: &str) -> Result<usize, String> {
+    match value.parse() {
+        Ok(n) if n > 0 => Ok(n),
+        _ => Err(format!("{} must be a positive number", arg)),
+    }
+}
+
+/// The message being delivered (only one is in flight at a time)
+struct InFlight {
+    id: usize,
+    sent_at: Instant,
+    remaining: usize,
+}
+
+struct Bench {
+    connections: usize,
+    messages: usize,
+    rooms: usize,
+    /// Connections in the order they take turns sending: room by room
+    order: Vec<usize>,
+    in_flight: Mutex<Option<InFlight>>,
+    /// Messages sent so far (the next message's ID)
+    sent: AtomicUsize,
+    /// Frames received by all connections, to tell when joining has settled
+    frames: AtomicUsize,
+    /// When traffic last paused, and the time spent paused
+    pauses: Mutex<(Instant, Duration)>,
+    /// Connections with a ping to answer wait for this
+    pongs: Notify,
+    /// Each connection's turn to send
+    turns: Vec<Notify>,
+    /// Send to receive, per connection and message
+    deliveries: Mutex<Vec<Duration>>,
+    /// Send until the last connection has it, per message
+    fan_outs: Mutex<Vec<Duration>>,
+    /// True once the last message is delivered, false if a connection failed
+    done: mpsc::Sender<bool>,
+}
+
+impl Bench {
+    /// Pause if it's time to let the connections answer pings. Answering while a
+    /// frame is on its way to a connection would cross the two and drop it.
+    async fn pause_if_due(&self) {
+        if self.pauses.lock().unwrap().0.elapsed() >= PAUSE_INTERVAL {
+            self.pause().await;
+        }
+    }
+
+    /// Let everything in flight arrive and the connections answer pings
+    async fn pause(&self) {
+        let started = Instant::now();
+        self.settle().await;
+        self.pongs.notify_waiters();
+        tokio::time::sleep(PONG_DELAY).await;
+
+        let mut pauses = self.pauses.lock().unwrap();
+        *pauses = (Instant::now(), pauses.1 + started.elapsed());
+    }
+
+    /// Wait for what's in flight to arrive
+    async fn settle(&self) {
+        let mut frames = self.frames.load(Ordering::Relaxed);
+        loop {
+            tokio::time::sleep(PONG_DELAY).await;
+            let now = self.frames.load(Ordering::Relaxed);
+            if now == frames {
+                return;
+            }
+            frames = now;
+        }
+    }
+
+    /// Time spent paused since the last call
+    fn take_paused(&self) -> Duration {
+        std::mem::take(&mut self.pauses.lock().unwrap().1)
+    }
+
+    /// The connection that sends message `id`
+    fn sender(&self, id: usize) -> usize {
+        self.order[id % self.connections]
+    }
+
+    /// A connection received message `id`; the last one to get it passes the turn on
+    fn delivered(&self, id: usize) {
+        let now = Instant::now();
+        let mut in_flight = self.in_flight.lock().unwrap();
+        let Some(message) = in_flight.as_mut().filter(|message| message.id == id) else {
+            return;
+        };
+        self.deliveries.lock().unwrap().push(now - message.sent_at);
+        message.remaining -= 1;
+        if message.remaining > 0 {
+            return;
+        }
+        self.fan_outs.lock().unwrap().push(now - message.sent_at);
+        *in_flight = None;
+        if id + 1 < self.messages {
+            self.turns[self.sender(id + 1)].notify_one();
+        } else {
+            let _ = self.done.try_send(true);
+        }
+    }
+}
+
+struct LoadClient {
+    stream: TcpStream,
+}
+
+impl TcpMessageHandler for LoadClient {
+    type Stream = TcpStream;
+    fn get_stream(&mut self) -> &mut Self::Stream {
+        &mut self.stream
+    }
+}
+
+async fn send(client: &mut LoadClient, kind: MessageTypes, content: String) -> std::io::Result<()> {
+    let message = ChatMessage::try_new(kind, Some(content.into_bytes()))
+        .map_err(|_| std::io::Error::other("message too long"))?;
+    client.send_message_chunked(message).await
+}
+
+/// What a connection waits for next
+enum Wake {
+    Turn,
+    Readable,
+    Pong,
+}
+
+async fn run_connection(
+    index: usize,
+    stream: TcpStream,
+    bench: Arc<Bench>,
+    joined: oneshot::Sender<()>,
+) {
+    if let Err(e) = connection(index, stream, &bench, joined).await {
+        eprintln!("Connection {}: {}", index, e);
+        let _ = bench.done.try_send(false);
+    }
+}
+
+/// The room connection `index` chats in
+fn room_of(index: usize, rooms: usize) -> String {
+    format!("bench{}", index % rooms)
+}
+
+/// How many connections are in connection `index`'s room
+fn room_size(index: usize, connections: usize, rooms: usize) -> usize {
+    (connections - index % rooms).div_ceil(rooms)
+}
+
+/// CPU time a process has used so far (Linux only)
+fn cpu_time(pid: u32) -> Option<Duration> {
+    let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // Fields after the command name, which is in parentheses, start at the state;
+    // user and system time are the 12th and 13th of them
+    let mut fields = stat.rsplit_once(')')?.1.split_whitespace().skip(11);
+    let user: u64 = fields.next()?.parse().ok()?;
+    let system: u64 = fields.next()?.parse().ok()?;
+    Some(Duration::from_secs_f64(
+        (user + system) as f64 / TICKS_PER_SECOND,
+    ))
+}
+
+async fn connection(
+    index: usize,
+    stream: TcpStream,
+    bench: &Bench,
+    joined: oneshot::Sender<()>,
+) -> Result<(), String> {
+    let mut client = LoadClient { stream };
+    let name = format!("load{}", index);
+    send(&mut client, MessageTypes::VersionCheck, VERSION.to_string())
+        .await
+        .map_err(|e| format!("version check failed: {}", e))?;
+    send(&mut client, MessageTypes::Join, name.clone())
+        .await
+        .map_err(|e| format!("join failed: {}", e))?;
+    let mut joined = Some(joined);
+
+    let mut last_sent = Instant::now() - MIN_SEND_INTERVAL;
+    let mut pong_due = false;
+    loop {
+        // Wait for data without starting a read that would have to be cancelled
+        // (peeking, as readiness alone can be left over from the last frame)
+        let mut next = [0u8];
+        let wake = tokio::select! {
+            biased;
+            _ = bench.turns[index].notified() => Wake::Turn,
+            _ = client.stream.peek(&mut next) => Wake::Readable,
+            _ = bench.pongs.notified(), if pong_due => Wake::Pong,
+        };
+
+        match wake {
+            Wake::Turn => {
+                tokio::time::sleep_until((last_sent + MIN_SEND_INTERVAL).into()).await;
+                bench.pause_if_due().await;
+                let id = bench.sent.fetch_add(1, Ordering::Relaxed);
+                // The last room's message may still be on its way here if the server
+                // sends room messages to everyone, and sending now would cross the two
+                if bench.rooms > 0
+                    && id > 0
+                    && bench.sender(id - 1) % bench.rooms != index % bench.rooms
+                {
+                    bench.pause().await;
+                }
+                *bench.in_flight.lock().unwrap() = Some(InFlight {
+                    id,
+                    sent_at: Instant::now(),
+                    remaining: if bench.rooms > 0 {
+                        room_size(index, bench.connections, bench.rooms)
+                    } else {
+                        bench.connections
+                    },
+                });
+                last_sent = Instant::now();
+                let sent = if bench.rooms > 0 {
+                    let content = format!("{}|bench {}", room_of(index, bench.rooms), id);
+                    send(&mut client, MessageTypes::RoomMessage, content).await
+                } else {
+                    send(
+                        &mut client,
+                        MessageTypes::ChatMessage,
+                        format!("bench {}", id),
+                    )
+                    .await
+                };
+                sent.map_err(|e| format!("send failed: {}", e))?;
+                continue;
+            }
+            Wake::Pong => {
+                pong_due = false;
+                let pong = ChatMessage::try_new(MessageTypes::Pong, None).unwrap();
+                client
+                    .send_message_chunked(pong)
+                    .await
+                    .map_err(|e| format!("pong failed: {}", e))?;
+                continue;
+            }
+            Wake::Readable => {}
+        }
+
+        let message = client
+            .read_message_chunked()
+            .await
+            .map_err(|e| e.to_string())?;
+        bench.frames.fetch_add(1, Ordering::Relaxed);
+        match message.msg_type {
+            MessageTypes::ChatMessage | MessageTypes::RoomMessage => {
+                // A server that doesn't filter by room sends other rooms' messages too
+                let ours = |text: &String| {
+                    message.msg_type == MessageTypes::ChatMessage
+                        || text.starts_with(&format!("{}|", room_of(index, bench.rooms)))
+                };
+                let id = message
+                    .content_as_string()
+                    .filter(ours)
+                    .and_then(|text| text.rsplit_once("bench ")?.1.parse().ok());
+                if let Some(id) = id {
+                    bench.delivered(id);
+                }
+            }
+            // The join is acknowledged after everything the server sends on joining
+            MessageTypes::JoinAck => {
+                // Deliveries include the sender's own copy
+                send(&mut client, MessageTypes::SelfEcho, "1".to_string())
+                    .await
+                    .map_err(|e| format!("self echo failed: {}", e))?;
+                if bench.rooms > 0 {
+                    send(
+                        &mut client,
+                        MessageTypes::JoinRoom,
+                        room_of(index, bench.rooms),
+                    )
+                    .await
+                    .map_err(|e| format!("room join failed: {}", e))?;
+                } else if let Some(joined) = joined.take() {
+                    let _ = joined.send(());
+                }
+            }
+            // Our own room join coming back means we're in
+            MessageTypes::JoinRoom
+                if bench.rooms > 0
+                    && message.content_as_string()
+                        == Some(format!("{}|{}", room_of(index, bench.rooms), name)) =>
+            {
+                if let Some(joined) = joined.take() {
+                    let _ = joined.send(());
+                }
+            }
+            // Answered in the next pause, so the frames don't cross
+            MessageTypes::Ping => pong_due = true,
+            MessageTypes::Error | MessageTypes::RateLimited => {
+                return Err(message.content_as_string().unwrap_or_default());
+            }
+            _ => {}
+        }
+    }
+}
+
+fn percentile(sorted: &[Duration], percent: usize) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    sorted[(sorted.len() - 1) * percent / 100]
+}
+
+fn summary(name: &str, mut times: Vec<Duration>) {
+    times.sort();
+    println!(
+        "{:<10} p50 {:>9.3}ms  p99 {:>9.3}ms  max {:>9.3}ms",
+        name,
+        percentile(&times, 50).as_secs_f64() * 1000.0,
+        percentile(&times, 99).as_secs_f64() * 1000.0,
+        percentile(&times, 100).as_secs_f64() * 1000.0,
+    );
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let options = match Options::parse() {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}\n{}", e, USAGE);
+            return ExitCode::from(64);
+        }
+    };
+
+    let (done, mut finished) = mpsc::channel(1);
+    let bench = Arc::new(Bench {
+        connections: options.connections,
+        messages: options.messages,
+        rooms: options.rooms,
+        order: {
+            let mut order: Vec<usize> = (0..options.connections).collect();
+            if options.rooms > 0 {
+                order.sort_by_key(|index| index % options.rooms);
+            }
+            order
+        },
+        in_flight: Mutex::new(None),
+        sent: AtomicUsize::new(0),
+        frames: AtomicUsize::new(0),
+        pauses: Mutex::new((Instant::now(), Duration::ZERO)),
+        pongs: Notify::new(),
+        turns: (0..options.connections).map(|_| Notify::new()).collect(),
+        deliveries: Mutex::new(Vec::new()),
+        fan_outs: Mutex::new(Vec::new()),
+        done,
+    });
+
+    let started = Instant::now();
+    for index in 0..options.connections {
+        bench.pause_if_due().await;
+        let stream = match TcpStream::connect(&options.addr).await {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("Connection {} to {} failed: {}", index, options.addr, e);
+                return ExitCode::FAILURE;
+            }
+        };
+        // Our own small writes (the OKs) shouldn't wait on Nagle's algorithm
+        let _ = stream.set_nodelay(true);
+        // One at a time: frames cross (and the connection drops) if a join is sent
+        // while another connection's join is being delivered to it
+        let (joined, has_joined) = oneshot::channel();
+        tokio::spawn(run_connection(index, stream, bench.clone(), joined));
+        if has_joined.await.is_err() || finished.try_recv().is_ok() {
+            return ExitCode::FAILURE;
+        }
+    }
+
+    let join_time = started.elapsed();
+
+    // Everyone sees everyone else join; wait for that to finish
+    bench.settle().await;
+    println!(
+        "{} connections joined in {:.1}s ({} frames)",
+        options.connections,
+        join_time.as_secs_f64(),
+        bench.frames.load(Ordering::Relaxed)
+    );
+
+    bench.take_paused();
+    let frames = bench.frames.load(Ordering::Relaxed);
+    let cpu = options.server_pid.and_then(cpu_time);
+    let started = Instant::now();
+    bench.turns[0].notify_one();
+    if finished.recv().await != Some(true) {
+        return ExitCode::FAILURE;
+    }
+    let elapsed = started.elapsed() - bench.take_paused();
+    let frames = bench.frames.load(Ordering::Relaxed) - frames;
+    let cpu = cpu.zip(options.server_pid.and_then(cpu_time));
+
+    let deliveries = std::mem::take(&mut *bench.deliveries.lock().unwrap());
+    let fan_outs = std::mem::take(&mut *bench.fan_outs.lock().unwrap());
+    println!(
+        "{} messages to {} connections in {:.2}s: {:.0} messages/s, {:.0} deliveries/s",
+        options.messages,
+        options.connections,
+        elapsed.as_secs_f64(),
+        options.messages as f64 / elapsed.as_secs_f64(),
+        deliveries.len() as f64 / elapsed.as_secs_f64(),
+    );
+    summary("delivery", deliveries);
+    summary("fan-out", fan_outs);
+    // Pings count too, though there are few
+    println!(
+        "{:.1} frames received per message",
+        frames as f64 / options.messages as f64
+    );
+    if let Some((before, after)) = cpu {
+        let cpu = after - before;
+        println!(
+            "server CPU {:.2}s: {:.0}us per message, {:.2}us per message per connection",
+            cpu.as_secs_f64(),
+            cpu.as_secs_f64() * 1e6 / options.messages as f64,
+            cpu.as_secs_f64() * 1e6 / (options.messages * options.connections) as f64,
+        );
+    }
+    ExitCode::SUCCESS
+}
@@ -0,0 +1,444 @@
+//! Registered accounts: users who own their nickname.
+//! Stored in the data directory as one `name<TAB>password_hash` line per account;
+//! passwords are hashed with argon2. An account with a one-time password - the
+//! admin account made on first start - has a third `one-time` field until its owner
+//! logs in and sets their own with /register.
+
+use crate::data_file::{DataFile, Save};
+use argon2::Argon2;
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use rand::Rng;
+use rand::distributions::Alphanumeric;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+pub const ACCOUNTS_FILE: &str = "accounts.tsv";
+pub const MIN_PASSWORD_LENGTH: usize = 8;
+/// Account made on first start, when there are no accounts yet
+pub const ADMIN_NAME: &str = "admin";
+/// Characters in a generated one-time password
+const ONE_TIME_PASSWORD_LENGTH: usize = 20;
+/// Marks an account whose password was handed out rather than chosen
+const ONE_TIME_FIELD: &str = "one-time";
+
+/// What happens to a guest using a registered nickname when its owner logs in
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReclaimPolicy {
+    /// Give the guest a random name and keep them connected
+    Rename,
+    /// Disconnect the guest
+    Disconnect,
+}
+
+impl ReclaimPolicy {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.to_lowercase().as_str() {
+            "rename" => Some(ReclaimPolicy::Rename),
+            "disconnect" | "kick" => Some(ReclaimPolicy::Disconnect),
+            _ => None,
+        }
+    }
+}
+
+/// What happens when someone joins with a nickname that's already in use
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NickConflictPolicy {
+    /// Append a random number (`name_4821`)
+    Random,
+    /// Append the lowest free number, starting from 2 (`name_2`, `name_3`, ...)
+    Suffix,
+    /// Refuse the join
+    Reject,
+}
+
+impl NickConflictPolicy {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.to_lowercase().as_str() {
+            "random" => Some(NickConflictPolicy::Random),
+            "suffix" | "number" => Some(NickConflictPolicy::Suffix),
+            "reject" | "refuse" => Some(NickConflictPolicy::Reject),
+            _ => None,
+        }
+    }
+}
+
+/// `name_2`, `name_3`, ...: the nicknames to offer, in order, to someone joining as
+/// `name` while it's in use. `name` is cut short where needed so each one fits in
+/// `max_length` bytes.
+pub fn suffixed_names(name: &str, max_length: usize) -> impl Iterator<Item = String> + '_ {
+    (2u32..).map(move |n| {
+        let suffix = format!("_{}", n);
+        let mut end = max_length.saturating_sub(suffix.len()).min(name.len());
+        while !name.is_char_boundary(end) {
+            end -= 1;
+        }
+        format!("{}{}", &name[..end], suffix)
+    })
+}
+
+/// A random password to hand out once
+pub fn one_time_password() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(ONE_TIME_PASSWORD_LENGTH)
+        .map(char::from)
+        .collect()
+}
+
+#[derive(Debug)]
+pub enum AccountError {
+    AlreadyRegistered,
+    NotRegistered,
+    PasswordTooShort,
+    Hash,
+    Io(io::Error),
+}
+
+impl std::fmt::Display for AccountError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AccountError::AlreadyRegistered => write!(f, "nickname is already registered"),
+            AccountError::NotRegistered => write!(f, "nickname is not registered"),
+            AccountError::PasswordTooShort => write!(
+                f,
+                "password must be at least {} characters",
+                MIN_PASSWORD_LENGTH
+            ),
+            AccountError::Hash => write!(f, "failed to hash password"),
+            AccountError::Io(e) => write!(f, "failed to save accounts: {}", e),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct AccountStore {
+    /// None = accounts only live in memory
+    file: Option<DataFile>,
+    /// Lowercased name -> (name as registered, password hash, one-time password)
+    accounts: BTreeMap<String, (String, String, bool)>,
+}
+
+impl AccountStore {
+    pub fn load(path: Option<PathBuf>) -> io::Result<Self> {
+        let mut accounts = BTreeMap::new();
+        if let Some(path) = &path {
+            match fs::read_to_string(path) {
+                Ok(contents) => {
+                    for line in contents.lines() {
+                        let mut fields = line.split('\t');
+                        if let (Some(name), Some(hash)) = (fields.next(), fields.next()) {
+                            let one_time = fields.next() == Some(ONE_TIME_FIELD);
+                            accounts.insert(
+                                name.to_lowercase(),
+                                (name.to_string(), hash.to_string(), one_time),
+                            );
+                        }
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+        // Password hashes are secrets: the file is only readable by the server
+        let file = path.map(DataFile::secret);
+        Ok(AccountStore { file, accounts })
+    }
+
+    /// Whether `line` is an account as `save` writes it (for the startup integrity check)
+    pub fn check_line(line: &str) -> bool {
+        let fields: Vec<&str> = line.split('\t').collect();
+        match fields.as_slice() {
+            [name, hash] | [name, hash, ONE_TIME_FIELD] => {
+                !name.is_empty() && PasswordHash::new(hash).is_ok()
+            }
+            _ => false,
+        }
+    }
+
+    /// Registered names are matched case-insensitively so "Alice" can't pose as "alice"
+    pub fn is_registered(&self, name: &str) -> bool {
+        self.accounts.contains_key(&name.to_lowercase())
+    }
+
+    /// The password hash of the account exactly named `name`, to check a login against
+    /// with `verify_password` once the store is unlocked
+    pub fn password_hash(&self, name: &str) -> Option<String> {
+        self.accounts
+            .get(&name.to_lowercase())
+            .filter(|(registered, _, _)| registered == name)
+            .map(|(_, hash, _)| hash.clone())
+    }
+
+    /// Returns the store to save once it's unlocked (as do the other changes)
+    pub fn register(&mut self, name: &str, password: HashedPassword) -> Result<Save, AccountError> {
+        self.create(name, password, false)
+    }
+
+    /// Register `name` with a password its owner has to replace
+    pub fn register_one_time(
+        &mut self,
+        name: &str,
+        password: HashedPassword,
+    ) -> Result<Save, AccountError> {
+        self.create(name, password, true)
+    }
+
+    /// Set a new password for a registered account, which is then no longer one-time
+    pub fn change_password(
+        &mut self,
+        name: &str,
+        password: HashedPassword,
+    ) -> Result<Save, AccountError> {
+        let Some(account) = self.accounts.get_mut(&name.to_lowercase()) else {
+            return Err(AccountError::NotRegistered);
+        };
+        account.1 = password.0;
+        account.2 = false;
+        Ok(self.save())
+    }
+
+    /// Whether `name` still has the password it was handed
+    pub fn has_one_time_password(&self, name: &str) -> bool {
+        self.accounts
+            .get(&name.to_lowercase())
+            .is_some_and(|(_, _, one_time)| *one_time)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.accounts.is_empty()
+    }
+
+    fn create(
+        &mut self,
+        name: &str,
+        password: HashedPassword,
+        one_time: bool,
+    ) -> Result<Save, AccountError> {
+        if self.is_registered(name) {
+            return Err(AccountError::AlreadyRegistered);
+        }
+        self.accounts.insert(
+            name.to_lowercase(),
+            (name.to_string(), password.0, one_time),
+        );
+        Ok(self.save())
+    }
+
+    pub fn unregister(&mut self, name: &str) -> Result<Save, AccountError> {
+        if self.accounts.remove(&name.to_lowercase()).is_none() {
+            return Err(AccountError::NotRegistered);
+        }
+        Ok(self.save())
+    }
+
+    /// Registered names, sorted
+    pub fn names(&self) -> Vec<String> {
+        self.accounts
+            .values()
+            .map(|(name, _, _)| name.clone())
+            .collect()
+    }
+
+    fn save(&self) -> Save {
+        let Some(file) = &self.file else {
+            return Save::default();
+        };
+        let mut contents = String::new();
+        for (name, hash, one_time) in self.accounts.values() {
+            contents.push_str(&format!("{}\t{}", name, hash));
+            if *one_time {
+                contents.push_str(&format!("\t{}", ONE_TIME_FIELD));
+            }
+            contents.push('\n');
+        }
+        file.snapshot(contents)
+    }
+}
+
+/// A password hashed with argon2, ready to be stored
+#[derive(Debug)]
+pub struct HashedPassword(String);
+
+impl HashedPassword {
+    /// Hash `password`, off the async runtime's threads: argon2 is slow on purpose
+    pub async fn new(password: &str) -> Result<Self, AccountError> {
+        if password.chars().count() < MIN_PASSWORD_LENGTH {
+            return Err(AccountError::PasswordTooShort);
+        }
+        let password = password.to_string();
+        tokio::task::spawn_blocking(move || {
+            let salt = SaltString::generate(&mut OsRng);
+            Argon2::default()
+                .hash_password(password.as_bytes(), &salt)
+                .map(|hash| HashedPassword(hash.to_string()))
+                .map_err(|_| AccountError::Hash)
+        })
+        .await
+        .map_err(|_| AccountError::Hash)?
+    }
+}
+
+/// Whether `password` matches `hash` (see AccountStore::password_hash), checked off
+/// the async runtime's threads
+pub async fn verify_password(hash: String, password: &str) -> bool {
+    let password = password.to_string();
+    tokio::task::spawn_blocking(move || {
+        PasswordHash::new(&hash).is_ok_and(|hash| {
+            Argon2::default()
+                .verify_password(password.as_bytes(), &hash)
+                .is_ok()
+        })
+    })
+    .await
+    .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    async fn hashed(password: &str) -> HashedPassword {
+        HashedPassword::new(password).await.unwrap()
+    }
+
+    async fn verifies(accounts: &AccountStore, name: &str, password: &str) -> bool {
+        match accounts.password_hash(name) {
+            Some(hash) => verify_password(hash, password).await,
+            None => false,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_register_and_verify() {
+        let mut accounts = AccountStore::default();
+        let _ = accounts
+            .register("alice", hashed("correct horse").await)
+            .unwrap();
+        assert!(accounts.is_registered("alice"));
+        assert!(accounts.is_registered("ALICE"));
+        assert!(verifies(&accounts, "alice", "correct horse").await);
+        assert!(!verifies(&accounts, "alice", "wrong password").await);
+        assert!(!verifies(&accounts, "ALICE", "correct horse").await);
+        assert!(!verifies(&accounts, "bob", "correct horse").await);
+
+        assert!(matches!(
+            accounts.register("Alice", hashed("another password").await),
+            Err(AccountError::AlreadyRegistered)
+        ));
+        assert!(matches!(
+            HashedPassword::new("short").await,
+            Err(AccountError::PasswordTooShort)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_accounts_persist() {
+        let path =
+            std::env::temp_dir().join(format!("rust_chat_accounts_{}.tsv", std::process::id()));
+        let mut accounts = AccountStore::load(Some(path.clone())).unwrap();
+        let save = accounts
+            .register("alice", hashed("correct horse").await)
+            .unwrap();
+        // Nothing is written until the save is
+        assert!(!path.exists());
+        save.write().await.unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        let mut reloaded = AccountStore::load(Some(path.clone())).unwrap();
+        assert!(verifies(&reloaded, "alice", "correct horse").await);
+        assert_eq!(reloaded.names(), vec!["alice"]);
+        reloaded.unregister("alice").unwrap().write().await.unwrap();
+        assert!(
+            AccountStore::load(Some(path.clone()))
+                .unwrap()
+                .names()
+                .is_empty()
+        );
+        fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_one_time_password() {
+        let path =
+            std::env::temp_dir().join(format!("rust_chat_one_time_{}.tsv", std::process::id()));
+        let mut accounts = AccountStore::load(Some(path.clone())).unwrap();
+        assert!(accounts.is_empty());
+        let password = one_time_password();
+        assert_eq!(password.len(), ONE_TIME_PASSWORD_LENGTH);
+        accounts
+            .register_one_time(ADMIN_NAME, hashed(&password).await)
+            .unwrap()
+            .write()
+            .await
+            .unwrap();
+
+        // Survives a restart, until the owner picks their own
+        let mut accounts = AccountStore::load(Some(path.clone())).unwrap();
+        assert!(verifies(&accounts, ADMIN_NAME, &password).await);
+        assert!(accounts.has_one_time_password("Admin"));
+        accounts
+            .change_password(ADMIN_NAME, hashed("correct horse").await)
+            .unwrap()
+            .write()
+            .await
+            .unwrap();
+        let accounts = AccountStore::load(Some(path.clone())).unwrap();
+        assert!(!accounts.has_one_time_password(ADMIN_NAME));
+        assert!(verifies(&accounts, ADMIN_NAME, "correct horse").await);
+        assert!(!verifies(&accounts, ADMIN_NAME, &password).await);
+
+        let mut accounts = AccountStore::default();
+        assert!(matches!(
+            accounts.change_password("bob", hashed("correct horse").await),
+            Err(AccountError::NotRegistered)
+        ));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_reclaim_policy() {
+        assert_eq!(ReclaimPolicy::parse("rename"), Some(ReclaimPolicy::Rename));
+        assert_eq!(
+            ReclaimPolicy::parse("Disconnect"),
+            Some(ReclaimPolicy::Disconnect)
+        );
+        assert_eq!(ReclaimPolicy::parse("ignore"), None);
+    }
+
+    #[test]
+    fn test_nick_conflict_policy() {
+        assert_eq!(
+            NickConflictPolicy::parse("Suffix"),
+            Some(NickConflictPolicy::Suffix)
+        );
+        assert_eq!(
+            NickConflictPolicy::parse("reject"),
+            Some(NickConflictPolicy::Reject)
+        );
+        assert_eq!(NickConflictPolicy::parse("ignore"), None);
+
+        let taken: HashSet<&str> = ["alice", "alice_2", "alice_3"].into();
+        let free = |name| {
+            suffixed_names(name, 32)
+                .find(|candidate| !taken.contains(candidate.as_str()))
+                .unwrap()
+        };
+        assert_eq!(free("alice"), "alice_4");
+        assert_eq!(free("bob"), "bob_2");
+
+        // Long names are cut short to make room for the number
+        let long = "a".repeat(32);
+        let mut names = suffixed_names(&long, 32).skip(8);
+        assert_eq!(names.next().unwrap(), format!("{}_10", "a".repeat(29)));
+        assert_eq!(suffixed_names("ééé", 5).next().unwrap(), "é_2");
+    }
+}
@@ -0,0 +1,134 @@
+//! Paced execution of console actions that touch many connections. Steps run one at a
+//! time with a pause in between, so a /kickall of thousands of users or a burst of
+//! announcements can't flood the broadcast channels or hold up the accept loop.
+//! Whoever submits a job gets a progress receiver to report on it.
+
+use crate::ServerCommand;
+use crate::state::{SERVER_ORIGIN, ServerState};
+use shared::message::ChatMessage;
+use std::time::Duration;
+use tokio::sync::{mpsc, watch};
+
+/// Pause between two steps
+pub const STEP_INTERVAL: Duration = Duration::from_millis(50);
+/// Users disconnected by each step of a bulk kick
+pub const KICK_BATCH_SIZE: usize = 25;
+
+pub enum Step {
+    /// Sent to every connection; `items` is how much of the job it covers (e.g. users kicked)
+    Command {
+        command: ServerCommand,
+        items: usize,
+    },
+    /// Broadcast as coming from the server itself
+    Broadcast(ChatMessage),
+}
+
+impl Step {
+    fn items(&self) -> usize {
+        match self {
+            Step::Command { items, .. } => *items,
+            Step::Broadcast(_) => 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Progress {
+    pub done: usize,
+    pub total: usize,
+}
+
+impl Progress {
+    pub fn is_finished(&self) -> bool {
+        self.done >= self.total
+    }
+}
+
+struct Job {
+    steps: Vec<Step>,
+    progress: watch::Sender<Progress>,
+}
+
+/// Handle to the worker that runs queued steps (cheap to clone)
+#[derive(Clone)]
+pub struct ActionQueue {
+    jobs: mpsc::UnboundedSender<Job>,
+}
+
+impl ActionQueue {
+    /// Start the worker. Jobs run in the order they were submitted.
+    pub fn start(state: ServerState, interval: Duration) -> Self {
+        let (jobs, mut queue) = mpsc::unbounded_channel::<Job>();
+        tokio::spawn(async move {
+            while let Some(job) = queue.recv().await {
+                for step in job.steps {
+                    let items = step.items();
+                    // No receivers just means nobody is connected to act on
+                    match step {
+                        Step::Command { command, .. } => {
+                            let _ = state.server_commands.send(command);
+                        }
+                        Step::Broadcast(message) => {
+                            let _ = state.broadcast(message, SERVER_ORIGIN);
+                        }
+                    }
+                    job.progress.send_modify(|progress| progress.done += items);
+                    tokio::time::sleep(interval).await;
+                }
+            }
+        });
+        ActionQueue { jobs }
+    }
+
+    /// Queue steps to run as one job
+    pub fn submit(&self, steps: Vec<Step>) -> watch::Receiver<Progress> {
+        let total = steps.iter().map(Step::items).sum();
+        let (progress, receiver) = watch::channel(Progress { done: 0, total });
+        let _ = self.jobs.send(Job { steps, progress });
+        receiver
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history::RoomHistory;
+    use crate::mailbox::Mailbox;
+    use shared::message::MessageTypes;
+
+    #[tokio::test]
+    async fn test_steps_run_in_order_with_progress() {
+        let state = ServerState::for_tests();
+        let mut rx = state.channel.subscribe();
+        let mut command_rx = state.server_commands.subscribe();
+        let queue = ActionQueue::start(state.clone(), Duration::from_millis(1));
+
+        let kick = Step::Command {
+            command: ServerCommand::Kick {
+                usernames: vec!["alice".to_string(), "bob".to_string()],
+                cooldown: None,
+            },
+            items: 2,
+        };
+        let message = ChatMessage::try_new(MessageTypes::Announcement, None).unwrap();
+        let mut progress = queue.submit(vec![kick, Step::Broadcast(message)]);
+        assert_eq!(*progress.borrow(), Progress { done: 0, total: 3 });
+
+        while !progress.borrow_and_update().is_finished() {
+            progress.changed().await.unwrap();
+        }
+        assert!(matches!(
+            command_rx.recv().await.unwrap(),
+            ServerCommand::Kick { usernames, .. } if usernames.len() == 2
+        ));
+        let (message, origin) = rx.recv().await.unwrap();
+        assert!(matches!(message.msg_type, MessageTypes::Announcement));
+        assert_eq!(origin, SERVER_ORIGIN);
+        // Its size is noted for the memory cap, like any other broadcast's
+        let usage = state
+            .memory
+            .usage(&RoomHistory::new(), &Mailbox::default(), 1);
+        assert!(usage.outbound > 0);
+    }
+}
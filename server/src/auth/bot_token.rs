@@ -0,0 +1,382 @@
+//! Bot tokens: long-lived credentials created on the console with `/token create`,
+//! which bots present instead of a password. A token is `<bot>.<secret>` and logs in
+//! as `bot:<bot>`; the client sends it as the password in its Join. Each token
+//! carries a grant - what the bot may do (its scopes) and which rooms it may join -
+//! and can be revoked on its own, or rotated by creating it again.
+//!
+//! Stored in the data directory as one `bot<TAB>secret_hash<TAB>scopes<TAB>rooms`
+//! line per token, with the secret hashed with argon2 like account passwords. An
+//! empty rooms field means any room.
+
+use super::{AuthFuture, AuthProvider};
+use crate::accounts;
+use crate::data_file::{DataFile, Save};
+use crate::reserved::{self, Namespace};
+use crate::scopes::{Scope, Scopes};
+use argon2::Argon2;
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, SaltString};
+use rand::RngCore;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+
+pub const TOKENS_FILE: &str = "tokens.tsv";
+/// Provider name in logs
+pub const PROVIDER_NAME: &str = "token";
+/// Random bytes in a token's secret
+const SECRET_BYTES: usize = 32;
+
+/// What a bot logged in with a token may do
+#[derive(Debug, Clone, PartialEq)]
+pub struct Grant {
+    scopes: Scopes,
+    /// Rooms the bot may join, lowercased (empty = any)
+    rooms: Vec<String>,
+}
+
+impl Default for Grant {
+    /// Sending and reading, in any room
+    fn default() -> Self {
+        Grant::new(Scopes::from_iter([Scope::Send, Scope::Read]), Vec::new())
+    }
+}
+
+impl Grant {
+    pub fn new(scopes: Scopes, rooms: Vec<String>) -> Self {
+        let mut rooms: Vec<String> = rooms.iter().map(|room| room.to_lowercase()).collect();
+        rooms.sort();
+        rooms.dedup();
+        Grant { scopes, rooms }
+    }
+
+    pub fn scopes(&self) -> Scopes {
+        self.scopes
+    }
+
+    pub fn allows_room(&self, room: &str) -> bool {
+        self.rooms.is_empty() || self.rooms.contains(&room.to_lowercase())
+    }
+
+    /// "send,read in #ops, #deploys" for the console
+    pub fn describe(&self) -> String {
+        let scopes = if self.scopes.is_empty() {
+            "no scopes".to_string()
+        } else {
+            self.scopes.to_string()
+        };
+        if self.rooms.is_empty() {
+            format!("{} in any room", scopes)
+        } else {
+            let rooms: Vec<String> = self.rooms.iter().map(|room| format!("#{}", room)).collect();
+            format!("{} in {}", scopes, rooms.join(", "))
+        }
+    }
+
+    fn parse(scopes: &str, rooms: &str) -> Option<Self> {
+        let rooms = rooms
+            .split(',')
+            .filter(|room| !room.is_empty())
+            .map(str::to_string)
+            .collect();
+        Some(Grant::new(Scopes::parse(scopes)?, rooms))
+    }
+}
+
+#[derive(Debug)]
+pub enum TokenError {
+    InvalidName,
+    NotFound,
+    Hash,
+    Io(io::Error),
+}
+
+impl std::fmt::Display for TokenError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TokenError::InvalidName => write!(
+                f,
+                "bot names are letters, digits, underscore and hyphen (without '{}')",
+                reserved::BOT_PREFIX
+            ),
+            TokenError::NotFound => write!(f, "no token for that bot"),
+            TokenError::Hash => write!(f, "failed to hash token"),
+            TokenError::Io(e) => write!(f, "failed to save tokens: {}", e),
+        }
+    }
+}
+
+/// A freshly made token for a bot, not yet stored
+pub struct NewToken {
+    bot: String,
+    secret: String,
+    hash: String,
+}
+
+impl NewToken {
+    /// Make a token for `bot` (with or without its `bot:` prefix). The secret is
+    /// hashed off the async runtime's threads: argon2 is slow on purpose.
+    pub async fn new(bot: &str) -> Result<Self, TokenError> {
+        let bot = bot.strip_prefix(reserved::BOT_PREFIX).unwrap_or(bot);
+        if bot.len() + reserved::BOT_PREFIX.len() > crate::user_connection::MAX_USERNAME_LENGTH
+            || !reserved::is_well_formed(bot)
+            || reserved::split(bot).0 != Namespace::User
+        {
+            return Err(TokenError::InvalidName);
+        }
+        let mut bytes = [0u8; SECRET_BYTES];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        let secret: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+        let to_hash = secret.clone();
+        let hash = tokio::task::spawn_blocking(move || {
+            let salt = SaltString::generate(&mut OsRng);
+            Argon2::default()
+                .hash_password(to_hash.as_bytes(), &salt)
+                .map(|hash| hash.to_string())
+        })
+        .await
+        .map_err(|_| TokenError::Hash)?
+        .map_err(|_| TokenError::Hash)?;
+        Ok(NewToken {
+            bot: bot.to_string(),
+            secret,
+            hash,
+        })
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct TokenStore {
+    /// None = tokens only live in memory
+    file: Option<DataFile>,
+    /// Lowercased bot name -> (name as created, secret hash, grant)
+    tokens: BTreeMap<String, (String, String, Grant)>,
+}
+
+impl TokenStore {
+    pub fn load(path: Option<PathBuf>) -> io::Result<Self> {
+        let mut tokens = BTreeMap::new();
+        if let Some(path) = &path {
+            match fs::read_to_string(path) {
+                Ok(contents) => {
+                    for line in contents.lines() {
+                        let fields: Vec<&str> = line.split('\t').collect();
+                        if let [bot, hash, scopes, rooms] = fields.as_slice()
+                            && let Some(grant) = Grant::parse(scopes, rooms)
+                        {
+                            tokens.insert(
+                                bot.to_lowercase(),
+                                (bot.to_string(), hash.to_string(), grant),
+                            );
+                        }
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+        // Token hashes are secrets - keep them readable by the server only
+        let file = path.map(DataFile::secret);
+        Ok(TokenStore { file, tokens })
+    }
+
+    /// Whether `line` is a token as `save` writes it (for the startup integrity check)
+    pub fn check_line(line: &str) -> bool {
+        let fields: Vec<&str> = line.split('\t').collect();
+        matches!(fields.as_slice(), [bot, hash, scopes, rooms]
+            if !bot.is_empty()
+                && PasswordHash::new(hash).is_ok()
+                && Grant::parse(scopes, rooms).is_some())
+    }
+
+    /// Store `token` for its bot, replacing any it had. Returns the token, which is
+    /// shown once and never stored, and the store to save once it's unlocked.
+    pub fn create(&mut self, token: NewToken, grant: Grant) -> (String, Save) {
+        self.tokens.insert(
+            token.bot.to_lowercase(),
+            (token.bot.clone(), token.hash, grant),
+        );
+        (format!("{}.{}", token.bot, token.secret), self.save())
+    }
+
+    /// Returns the store to save once it's unlocked
+    pub fn revoke(&mut self, bot: &str) -> Result<Save, TokenError> {
+        let bot = bot.strip_prefix(reserved::BOT_PREFIX).unwrap_or(bot);
+        if self.tokens.remove(&bot.to_lowercase()).is_none() {
+            return Err(TokenError::NotFound);
+        }
+        Ok(self.save())
+    }
+
+    /// The hash `token` has to match to log in as `username` (`bot:<bot>`), and the
+    /// secret in it, to check with `accounts::verify_password` once the store is
+    /// unlocked (None = it can't match)
+    pub fn secret_hash(&self, username: &str, token: &str) -> Option<(String, String)> {
+        let (Namespace::Bot, bot) = reserved::split(username) else {
+            return None;
+        };
+        let (token_bot, secret) = token.split_once('.')?;
+        let (created, hash, _) = self.tokens.get(&bot.to_lowercase())?;
+        (created == bot && token_bot == bot).then(|| (hash.clone(), secret.to_string()))
+    }
+
+    /// What the bot logged in as `username` may do (None = not a bot with a token)
+    pub fn grant(&self, username: &str) -> Option<&Grant> {
+        let (Namespace::Bot, bot) = reserved::split(username) else {
+            return None;
+        };
+        self.tokens
+            .get(&bot.to_lowercase())
+            .map(|(_, _, grant)| grant)
+    }
+
+    /// Bots with tokens and their grants, sorted by name
+    pub fn list(&self) -> Vec<(String, Grant)> {
+        self.tokens
+            .values()
+            .map(|(bot, _, grant)| (format!("{}{}", reserved::BOT_PREFIX, bot), grant.clone()))
+            .collect()
+    }
+
+    fn save(&self) -> Save {
+        let Some(file) = &self.file else {
+            return Save::default();
+        };
+        let mut contents = String::new();
+        for (bot, hash, grant) in self.tokens.values() {
+            contents.push_str(&format!(
+                "{}\t{}\t{}\t{}\n",
+                bot,
+                hash,
+                grant.scopes,
+                grant.rooms.join(",")
+            ));
+        }
+        file.snapshot(contents)
+    }
+}
+
+/// Whether `token` logs in as `username` (`bot:<bot>`), checked with the store unlocked
+pub async fn verify(tokens: &RwLock<TokenStore>, username: &str, token: &str) -> bool {
+    let found = tokens.read().await.secret_hash(username, token);
+    match found {
+        Some((hash, secret)) => accounts::verify_password(hash, &secret).await,
+        None => false,
+    }
+}
+
+/// Owns the `bot:` names that have a token, which is the only way to log in to them
+pub struct BotTokenProvider {
+    tokens: Arc<RwLock<TokenStore>>,
+}
+
+impl BotTokenProvider {
+    pub fn new(tokens: Arc<RwLock<TokenStore>>) -> Self {
+        BotTokenProvider { tokens }
+    }
+}
+
+impl AuthProvider for BotTokenProvider {
+    fn name(&self) -> &'static str {
+        PROVIDER_NAME
+    }
+
+    fn is_registered<'a>(&'a self, username: &'a str) -> AuthFuture<'a, bool> {
+        Box::pin(async move { self.tokens.read().await.grant(username).is_some() })
+    }
+
+    fn verify<'a>(&'a self, username: &'a str, secret: &'a str) -> AuthFuture<'a, bool> {
+        Box::pin(verify(&self.tokens, username, secret))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn create(tokens: &RwLock<TokenStore>, bot: &str, grant: Grant) -> String {
+        let token = NewToken::new(bot).await.unwrap();
+        let (token, save) = tokens.write().await.create(token, grant);
+        save.write().await.unwrap();
+        token
+    }
+
+    #[tokio::test]
+    async fn test_create_verify_revoke() {
+        let tokens = RwLock::new(TokenStore::default());
+        let grant = Grant::new(Scopes::from_iter([Scope::Send]), vec!["Ops".to_string()]);
+        let token = create(&tokens, "deploy", grant.clone()).await;
+        assert!(token.starts_with("deploy."));
+
+        assert!(verify(&tokens, "bot:deploy", &token).await);
+        assert!(!verify(&tokens, "deploy", &token).await);
+        assert!(!verify(&tokens, "bot:deploy", "deploy.wrong").await);
+        assert!(!verify(&tokens, "bot:Deploy", &token).await);
+        assert_eq!(tokens.read().await.grant("BOT:deploy"), Some(&grant));
+        assert_eq!(tokens.read().await.grant("alice"), None);
+
+        // Creating it again rotates the token
+        let rotated = create(&tokens, "bot:deploy", Grant::default()).await;
+        assert!(!verify(&tokens, "bot:deploy", &token).await);
+        assert!(verify(&tokens, "bot:deploy", &rotated).await);
+
+        let save = tokens.write().await.revoke("deploy").unwrap();
+        save.write().await.unwrap();
+        assert!(!verify(&tokens, "bot:deploy", &rotated).await);
+        assert!(matches!(
+            tokens.write().await.revoke("deploy"),
+            Err(TokenError::NotFound)
+        ));
+        assert!(matches!(
+            NewToken::new("srv:motd").await,
+            Err(TokenError::InvalidName)
+        ));
+    }
+
+    #[test]
+    fn test_grants() {
+        let grant = Grant::parse("read,send,read", "ops,Deploys").unwrap();
+        assert!(grant.scopes().contains(Scope::Send) && grant.scopes().contains(Scope::Read));
+        assert!(grant.allows_room("OPS"));
+        assert!(!grant.allows_room("general"));
+        assert_eq!(grant.describe(), "send,read in #deploys, #ops");
+
+        let grant = Grant::parse("read", "").unwrap();
+        assert!(!grant.scopes().contains(Scope::Send));
+        assert!(grant.allows_room("general"));
+        assert_eq!(grant.describe(), "read in any room");
+        assert_eq!(Grant::parse("root", ""), None);
+        assert_eq!(
+            Grant::parse("", "").unwrap().describe(),
+            "no scopes in any room"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_tokens_persist() {
+        let dir = std::env::temp_dir().join(format!("tokens_test_{}", std::process::id()));
+        let path = dir.join(TOKENS_FILE);
+
+        let tokens = RwLock::new(TokenStore::load(Some(path.clone())).unwrap());
+        let grant = Grant::new(Scopes::from_iter([Scope::Read]), vec!["ops".to_string()]);
+        let token = create(&tokens, "monitor", grant.clone()).await;
+
+        let tokens = RwLock::new(TokenStore::load(Some(path.clone())).unwrap());
+        assert!(verify(&tokens, "bot:monitor", &token).await);
+        assert_eq!(
+            tokens.read().await.list(),
+            vec![("bot:monitor".to_string(), grant)]
+        );
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        fs::remove_dir_all(&dir).ok();
+    }
+}
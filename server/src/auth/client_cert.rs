@@ -0,0 +1,171 @@
+//! Client certificate logins (mutual TLS): clients present a certificate signed by
+//! a CA the server trusts, and a mapping file turns its common name or a subject
+//! alternative name into a nickname. Joining as that nickname needs no password,
+//! which suits bots and locked-down deployments.
+//!
+//! The mapping file has one `subject<TAB>username` line per mapping, where the
+//! subject is a CN, DNS name, email address or URI from the certificate. Blank
+//! lines and lines starting with `#` are ignored.
+
+use super::{AuthFuture, AuthProvider};
+use rustls::RootCertStore;
+use rustls::server::WebPkiClientVerifier;
+use rustls::server::danger::ClientCertVerifier;
+use rustls_pemfile::certs;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufReader};
+use std::path::Path;
+use std::sync::Arc;
+use x509_parser::certificate::X509Certificate;
+use x509_parser::extensions::GeneralName;
+use x509_parser::prelude::FromDer;
+
+pub const CLIENT_CERTS_FILE: &str = "client_certs.tsv";
+/// Provider name in logs
+pub const PROVIDER_NAME: &str = "certificate";
+
+/// Certificate subjects and the nicknames they log in as
+#[derive(Debug, Default)]
+pub struct ClientCertMap {
+    usernames: HashMap<String, String>,
+}
+
+impl ClientCertMap {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        Ok(Self::parse(&fs::read_to_string(path)?))
+    }
+
+    fn parse(contents: &str) -> Self {
+        let usernames = contents
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .filter_map(|line| line.split_once('\t'))
+            .map(|(subject, username)| (subject.trim().to_string(), username.trim().to_string()))
+            .collect();
+        ClientCertMap { usernames }
+    }
+
+    pub fn len(&self) -> usize {
+        self.usernames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.usernames.is_empty()
+    }
+
+    /// Nickname for a DER encoded client certificate: its CN is checked first,
+    /// then its subject alternative names in order
+    pub fn username_for(&self, der: &[u8]) -> Option<String> {
+        let (_, cert) = X509Certificate::from_der(der).ok()?;
+        let common_names = cert
+            .subject()
+            .iter_common_name()
+            .filter_map(|name| name.as_str().ok());
+        let alt_names = cert
+            .subject_alternative_name()
+            .ok()
+            .flatten()
+            .map(|extension| extension.value.general_names.clone())
+            .unwrap_or_default();
+        let alt_names = alt_names.into_iter().filter_map(|name| match name {
+            GeneralName::DNSName(name) | GeneralName::RFC822Name(name) | GeneralName::URI(name) => {
+                Some(name)
+            }
+            _ => None,
+        });
+        common_names
+            .chain(alt_names)
+            .find_map(|subject| self.usernames.get(subject).cloned())
+    }
+
+    fn is_mapped(&self, username: &str) -> bool {
+        self.usernames.values().any(|mapped| mapped == username)
+    }
+}
+
+/// Owns the mapped nicknames, so guests can't take them. There's no secret to check:
+/// the only way in is the certificate, which the connection checks when joining.
+pub struct ClientCertProvider {
+    map: Arc<ClientCertMap>,
+}
+
+impl ClientCertProvider {
+    pub fn new(map: Arc<ClientCertMap>) -> Self {
+        ClientCertProvider { map }
+    }
+}
+
+impl AuthProvider for ClientCertProvider {
+    fn name(&self) -> &'static str {
+        PROVIDER_NAME
+    }
+
+    fn is_registered<'a>(&'a self, username: &'a str) -> AuthFuture<'a, bool> {
+        Box::pin(async move { self.map.is_mapped(username) })
+    }
+
+    fn verify<'a>(&'a self, _username: &'a str, _secret: &'a str) -> AuthFuture<'a, bool> {
+        Box::pin(async { false })
+    }
+}
+
+/// Verifier for client certificates signed by the CAs in `ca_path`. Unless
+/// `required`, clients without a certificate can still connect (and use passwords).
+pub fn client_verifier(ca_path: &str, required: bool) -> io::Result<Arc<dyn ClientCertVerifier>> {
+    let mut reader = BufReader::new(File::open(ca_path)?);
+    let mut roots = RootCertStore::empty();
+    for cert in certs(&mut reader) {
+        roots.add(cert?).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid CA certificate: {}", e),
+            )
+        })?;
+    }
+    let builder = WebPkiClientVerifier::builder(Arc::new(roots));
+    let builder = if required {
+        builder
+    } else {
+        builder.allow_unauthenticated()
+    };
+    builder.build().map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Client certificate verifier: {}", e),
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_certificate_mapping() {
+        let map = Arc::new(ClientCertMap::parse(
+            "# bots\nbuild-bot.example.com\tbuildbot\n\nalerts@example.com\talerts\n",
+        ));
+        assert_eq!(map.len(), 2);
+
+        let mut params = rcgen::CertificateParams::new(vec![
+            "unmapped.example.com".to_string(),
+            "build-bot.example.com".to_string(),
+        ])
+        .unwrap();
+        params
+            .distinguished_name
+            .push(rcgen::DnType::CommonName, "Build Bot");
+        let key = rcgen::KeyPair::generate().unwrap();
+        let cert = params.self_signed(&key).unwrap();
+        assert_eq!(map.username_for(cert.der()), Some("buildbot".to_string()));
+        assert_eq!(map.username_for(b"not a certificate"), None);
+
+        let provider = ClientCertProvider::new(map);
+        assert!(provider.is_registered("alerts").await);
+        assert!(!provider.is_registered("alice").await);
+        // No password gets in
+        assert!(!provider.verify("alerts", "alerts").await);
+    }
+}
@@ -0,0 +1,70 @@
+//! LDAP logins: the password is checked by binding to the directory as the user.
+
+use super::{AuthFuture, AuthProvider};
+use ldap3::{LdapConnAsync, LdapConnSettings, dn_escape};
+use std::time::Duration;
+use tracing::warn;
+
+/// How long to wait for the directory before refusing the login
+const LDAP_TIMEOUT: Duration = Duration::from_secs(5);
+/// Replaced with the (escaped) nickname in the user DN template
+pub const USERNAME_PLACEHOLDER: &str = "{username}";
+
+pub struct LdapProvider {
+    /// e.g. "ldaps://ldap.example.com"
+    url: String,
+    /// e.g. "uid={username},ou=people,dc=example,dc=com"
+    user_dn: String,
+}
+
+impl LdapProvider {
+    /// None if the template has no `{username}` placeholder
+    pub fn new(url: String, user_dn: String) -> Option<Self> {
+        user_dn
+            .contains(USERNAME_PLACEHOLDER)
+            .then_some(LdapProvider { url, user_dn })
+    }
+
+    async fn bind(&self, username: &str, password: &str) -> ldap3::result::Result<bool> {
+        let dn = self
+            .user_dn
+            .replace(USERNAME_PLACEHOLDER, &dn_escape(username));
+        let settings = LdapConnSettings::new().set_conn_timeout(LDAP_TIMEOUT);
+        let (conn, mut ldap) = LdapConnAsync::with_settings(settings, &self.url).await?;
+        ldap3::drive!(conn);
+        let bound = ldap.simple_bind(&dn, password).await?.success().is_ok();
+        let _ = ldap.unbind().await;
+        Ok(bound)
+    }
+}
+
+impl AuthProvider for LdapProvider {
+    fn name(&self) -> &'static str {
+        "ldap"
+    }
+
+    /// Directory users don't reserve nicknames - register them locally for that
+    fn is_registered<'a>(&'a self, _username: &'a str) -> AuthFuture<'a, bool> {
+        Box::pin(async { false })
+    }
+
+    fn verify<'a>(&'a self, username: &'a str, secret: &'a str) -> AuthFuture<'a, bool> {
+        Box::pin(async move {
+            // An empty password makes an anonymous bind, which most directories accept
+            if secret.is_empty() {
+                return false;
+            }
+            match tokio::time::timeout(LDAP_TIMEOUT, self.bind(username, secret)).await {
+                Ok(Ok(bound)) => bound,
+                Ok(Err(e)) => {
+                    warn!("LDAP login for '{}' failed: {}", username, e);
+                    false
+                }
+                Err(_) => {
+                    warn!("LDAP login for '{}' timed out", username);
+                    false
+                }
+            }
+        })
+    }
+}
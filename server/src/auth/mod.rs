@@ -0,0 +1,159 @@
+//! Pluggable authentication: where registered-nickname logins are checked.
+//!
+//! The local account store (`/register`) is always consulted first. LDAP and OIDC
+//! providers can be compiled in with the `ldap` and `oidc` features so logins can
+//! reuse an existing identity system. Whatever the provider, the client sends its
+//! secret (a password, or an ID token for OIDC) as the password in its Join -
+//! except with client certificates (mutual TLS), where the certificate is the proof.
+//! Bots log in to their `bot:` names with tokens made on the console (`/token`).
+
+pub mod bot_token;
+pub mod client_cert;
+#[cfg(feature = "ldap")]
+pub mod ldap;
+#[cfg(feature = "oidc")]
+pub mod oidc;
+
+use crate::accounts::{self, AccountStore};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+
+/// Boxed future returned by provider methods, so providers can be used as trait objects
+pub type AuthFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Checks nickname ownership against one identity system
+pub trait AuthProvider: Send + Sync {
+    /// Short name for logs ("local", "ldap", "oidc")
+    fn name(&self) -> &'static str;
+
+    /// Whether this provider owns `username`. Guests can't use such nicknames,
+    /// and only this provider may log in to them.
+    fn is_registered<'a>(&'a self, username: &'a str) -> AuthFuture<'a, bool>;
+
+    /// Whether `secret` proves the client owns `username`
+    fn verify<'a>(&'a self, username: &'a str, secret: &'a str) -> AuthFuture<'a, bool>;
+}
+
+/// Nicknames registered on this server with /register
+pub struct LocalProvider {
+    accounts: Arc<RwLock<AccountStore>>,
+}
+
+impl LocalProvider {
+    pub fn new(accounts: Arc<RwLock<AccountStore>>) -> Self {
+        LocalProvider { accounts }
+    }
+}
+
+impl AuthProvider for LocalProvider {
+    fn name(&self) -> &'static str {
+        "local"
+    }
+
+    fn is_registered<'a>(&'a self, username: &'a str) -> AuthFuture<'a, bool> {
+        Box::pin(async move { self.accounts.read().await.is_registered(username) })
+    }
+
+    fn verify<'a>(&'a self, username: &'a str, secret: &'a str) -> AuthFuture<'a, bool> {
+        Box::pin(async move {
+            // Checked once the store is unlocked: argon2 is slow on purpose
+            let hash = self.accounts.read().await.password_hash(username);
+            match hash {
+                Some(hash) => accounts::verify_password(hash, secret).await,
+                None => false,
+            }
+        })
+    }
+}
+
+/// The providers a server checks logins against, in order
+pub struct Authenticator {
+    providers: Vec<Box<dyn AuthProvider>>,
+}
+
+impl Authenticator {
+    pub fn new(providers: Vec<Box<dyn AuthProvider>>) -> Self {
+        Authenticator { providers }
+    }
+
+    /// Names of the configured providers, for the startup log
+    pub fn provider_names(&self) -> Vec<&'static str> {
+        self.providers
+            .iter()
+            .map(|provider| provider.name())
+            .collect()
+    }
+
+    pub async fn is_registered(&self, username: &str) -> bool {
+        for provider in &self.providers {
+            if provider.is_registered(username).await {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Name of the provider that accepted the login, if any. A provider that owns the
+    /// nickname decides alone, so a directory user can't log in to a local account.
+    pub async fn verify(&self, username: &str, secret: &str) -> Option<&'static str> {
+        for provider in &self.providers {
+            if provider.is_registered(username).await {
+                return provider
+                    .verify(username, secret)
+                    .await
+                    .then(|| provider.name());
+            }
+        }
+        for provider in &self.providers {
+            if provider.verify(username, secret).await {
+                return Some(provider.name());
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::accounts::HashedPassword;
+
+    /// Accepts "token-<username>" for any nickname
+    struct TokenProvider;
+
+    impl AuthProvider for TokenProvider {
+        fn name(&self) -> &'static str {
+            "token"
+        }
+
+        fn is_registered<'a>(&'a self, _username: &'a str) -> AuthFuture<'a, bool> {
+            Box::pin(async { false })
+        }
+
+        fn verify<'a>(&'a self, username: &'a str, secret: &'a str) -> AuthFuture<'a, bool> {
+            Box::pin(async move { secret == format!("token-{}", username) })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_provider_chain() {
+        let mut accounts = AccountStore::default();
+        let password = HashedPassword::new("correct horse").await.unwrap();
+        let _ = accounts.register("alice", password).unwrap();
+        let auth = Authenticator::new(vec![
+            Box::new(LocalProvider::new(Arc::new(RwLock::new(accounts)))),
+            Box::new(TokenProvider),
+        ]);
+        assert_eq!(auth.provider_names(), vec!["local", "token"]);
+
+        assert!(auth.is_registered("alice").await);
+        assert!(!auth.is_registered("bob").await);
+        assert_eq!(auth.verify("alice", "correct horse").await, Some("local"));
+        assert_eq!(auth.verify("bob", "token-bob").await, Some("token"));
+        // alice is a local account, so the other providers can't log in to it
+        assert_eq!(auth.verify("alice", "token-alice").await, None);
+        assert_eq!(auth.verify("bob", "wrong").await, None);
+    }
+}
@@ -0,0 +1,238 @@
+//! Per-user bandwidth accounting and hourly quotas.
+//! Usage is keyed by username so reconnecting doesn't reset it.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Length of a quota window
+pub const QUOTA_WINDOW: Duration = Duration::from_secs(60 * 60);
+/// Users are warned once per window when they reach this share of their quota
+const WARNING_PERCENT: u64 = 80;
+
+#[derive(Debug, PartialEq)]
+pub enum QuotaStatus {
+    Ok,
+    /// The warning threshold was crossed by this message
+    Warning {
+        used: u64,
+        limit: u64,
+    },
+    /// The message would go over the quota and was not counted
+    Exceeded {
+        used: u64,
+        limit: u64,
+        retry_after: Duration,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct Usage {
+    pub window_bytes: u64,
+    window_start: Instant,
+    warned: bool,
+    pub total_bytes: u64,
+    pub total_messages: u64,
+}
+
+impl Usage {
+    fn new(now: Instant) -> Self {
+        Usage {
+            window_bytes: 0,
+            window_start: now,
+            warned: false,
+            total_bytes: 0,
+            total_messages: 0,
+        }
+    }
+
+    fn roll_window(&mut self, now: Instant) {
+        if now.duration_since(self.window_start) >= QUOTA_WINDOW {
+            self.window_bytes = 0;
+            self.window_start = now;
+            self.warned = false;
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct BandwidthTracker {
+    /// Bytes per user per hour (None = unlimited, usage is still tracked)
+    quota: Option<u64>,
+    users: HashMap<String, Usage>,
+    total_bytes: u64,
+}
+
+impl BandwidthTracker {
+    pub fn new(quota: Option<u64>) -> Self {
+        BandwidthTracker {
+            quota: quota.filter(|q| *q > 0),
+            users: HashMap::new(),
+            total_bytes: 0,
+        }
+    }
+
+    pub fn quota(&self) -> Option<u64> {
+        self.quota
+    }
+
+    pub fn total_bytes(&self) -> u64 {
+        self.total_bytes
+    }
+
+    /// Account for a message of `bytes` sent by `user`
+    pub fn record(&mut self, user: &str, bytes: u64) -> QuotaStatus {
+        self.record_at(user, bytes, Instant::now())
+    }
+
+    fn record_at(&mut self, user: &str, bytes: u64, now: Instant) -> QuotaStatus {
+        let usage = self
+            .users
+            .entry(user.to_string())
+            .or_insert_with(|| Usage::new(now));
+        usage.roll_window(now);
+
+        if let Some(limit) = self.quota
+            && usage.window_bytes + bytes > limit
+        {
+            return QuotaStatus::Exceeded {
+                used: usage.window_bytes,
+                limit,
+                retry_after: QUOTA_WINDOW.saturating_sub(now.duration_since(usage.window_start)),
+            };
+        }
+
+        usage.window_bytes += bytes;
+        usage.total_bytes += bytes;
+        usage.total_messages += 1;
+        self.total_bytes += bytes;
+
+        match self.quota {
+            Some(limit) if !usage.warned && usage.window_bytes * 100 >= limit * WARNING_PERCENT => {
+                usage.warned = true;
+                QuotaStatus::Warning {
+                    used: usage.window_bytes,
+                    limit,
+                }
+            }
+            _ => QuotaStatus::Ok,
+        }
+    }
+
+    /// Carry a user's usage over to their new name
+    pub fn rename(&mut self, old_name: &str, new_name: &str) {
+        if let Some(usage) = self.users.remove(old_name) {
+            self.users.insert(new_name.to_string(), usage);
+        }
+    }
+
+    /// Usage for every user seen so far, heaviest users first
+    pub fn usage_by_user(&self) -> Vec<(String, Usage)> {
+        let now = Instant::now();
+        let mut users: Vec<(String, Usage)> = self
+            .users
+            .iter()
+            .map(|(name, usage)| {
+                let mut usage = usage.clone();
+                usage.roll_window(now);
+                (name.clone(), usage)
+            })
+            .collect();
+        users.sort_by(|a, b| b.1.window_bytes.cmp(&a.1.window_bytes).then(a.0.cmp(&b.0)));
+        users
+    }
+}
+
+/// Human readable byte count, e.g. "512 bytes", "1.5 KB", "2.0 MB"
+pub fn format_bytes(bytes: u64) -> String {
+    if bytes >= 1024 * 1024 {
+        format!("{:.1} MB", bytes as f64 / (1024.0 * 1024.0))
+    } else if bytes >= 1024 {
+        format!("{:.1} KB", bytes as f64 / 1024.0)
+    } else {
+        format!("{} bytes", bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unlimited_still_tracks_usage() {
+        let mut tracker = BandwidthTracker::new(None);
+        assert_eq!(tracker.record("alice", 100), QuotaStatus::Ok);
+        assert_eq!(tracker.record("alice", 50), QuotaStatus::Ok);
+        let usage = tracker.usage_by_user();
+        assert_eq!(usage[0].1.window_bytes, 150);
+        assert_eq!(usage[0].1.total_messages, 2);
+        assert_eq!(tracker.total_bytes(), 150);
+    }
+
+    #[test]
+    fn test_warns_once_at_eighty_percent() {
+        let mut tracker = BandwidthTracker::new(Some(1000));
+        assert_eq!(tracker.record("alice", 700), QuotaStatus::Ok);
+        assert_eq!(
+            tracker.record("alice", 100),
+            QuotaStatus::Warning {
+                used: 800,
+                limit: 1000
+            }
+        );
+        assert_eq!(tracker.record("alice", 100), QuotaStatus::Ok);
+    }
+
+    #[test]
+    fn test_exceeded_message_is_not_counted() {
+        let mut tracker = BandwidthTracker::new(Some(1000));
+        let start = Instant::now();
+        tracker.record_at("alice", 900, start);
+        let status = tracker.record_at("alice", 200, start + Duration::from_secs(600));
+        assert_eq!(
+            status,
+            QuotaStatus::Exceeded {
+                used: 900,
+                limit: 1000,
+                retry_after: Duration::from_secs(3000)
+            }
+        );
+        // Smaller messages still fit
+        assert_eq!(
+            tracker.record_at("alice", 100, start + Duration::from_secs(601)),
+            QuotaStatus::Ok
+        );
+    }
+
+    #[test]
+    fn test_window_resets_after_an_hour() {
+        let mut tracker = BandwidthTracker::new(Some(1000));
+        let start = Instant::now();
+        tracker.record_at("alice", 1000, start);
+        assert!(matches!(
+            tracker.record_at("alice", 1, start),
+            QuotaStatus::Exceeded { .. }
+        ));
+        assert_eq!(
+            tracker.record_at("alice", 1, start + QUOTA_WINDOW),
+            QuotaStatus::Ok
+        );
+    }
+
+    #[test]
+    fn test_rename_keeps_usage() {
+        let mut tracker = BandwidthTracker::new(Some(1000));
+        tracker.record("alice", 900);
+        tracker.rename("alice", "alicia");
+        assert!(matches!(
+            tracker.record("alicia", 200),
+            QuotaStatus::Exceeded { .. }
+        ));
+    }
+
+    #[test]
+    fn test_format_bytes() {
+        assert_eq!(format_bytes(512), "512 bytes");
+        assert_eq!(format_bytes(1536), "1.5 KB");
+        assert_eq!(format_bytes(2 * 1024 * 1024), "2.0 MB");
+    }
+}
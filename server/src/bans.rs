@@ -0,0 +1,391 @@
+//! IP bans and post-kick cooldowns.
+//!
+//! Bans cover a single IP or a CIDR range (`/ban 203.0.113.0/24`) and are
+//! permanent or expire at a set time (`/ban <target> --for 24h`). They are kept
+//! in a prefix trie so checking an address at accept time doesn't scan every ban.
+//! Clients that send a fingerprint can also be banned by it (`/ban fp:<id>`), which
+//! follows them across IP and nickname changes.
+//! A kick with a cooldown is a short tempban on both the kicked user's IP and
+//! their nickname, so they can't come straight back under a new name or from
+//! another address. Everything lives in memory; the maintenance task lifts
+//! expired bans and cooldowns.
+
+use ip_network::IpNetwork;
+use ip_network_table::IpNetworkTable;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+#[derive(Default)]
+pub struct BanList {
+    /// Banned networks (single IPs are /32 or /128) and when each ban ends (None = permanent)
+    networks: IpNetworkTable<Option<Instant>>,
+    /// Banned client fingerprint IDs and when each ban ends (None = permanent)
+    fingerprints: HashMap<String, Option<Instant>>,
+    /// Post-kick cooldowns by IP: when each one ends
+    kicked_ips: HashMap<IpAddr, Instant>,
+    /// Post-kick cooldowns by lowercased username: (name as kicked, when it ends)
+    kicked_names: HashMap<String, (String, Instant)>,
+}
+
+impl BanList {
+    /// Ban `network` for `duration` from `now` (None = for good).
+    /// Returns false, leaving the existing ban as it is, if that exact network was already banned.
+    pub fn ban(&mut self, network: IpNetwork, duration: Option<Duration>, now: Instant) -> bool {
+        if self
+            .networks
+            .exact_match(network)
+            .is_some_and(|until| active(*until, now))
+        {
+            return false;
+        }
+        self.networks
+            .insert(network, duration.map(|duration| now + duration));
+        true
+    }
+
+    /// Lifts the ban on exactly `network` and any kick cooldown on it.
+    /// Returns false if there was neither.
+    pub fn unban(&mut self, network: IpNetwork) -> bool {
+        let cooldown = network.netmask() == host_prefix(network)
+            && self.kicked_ips.remove(&network.network_address()).is_some();
+        self.networks.remove(network).is_some() || cooldown
+    }
+
+    /// Whether any active ban covers `ip`
+    pub fn is_banned(&self, ip: IpAddr, now: Instant) -> bool {
+        self.covering(ip, now).is_some()
+    }
+
+    /// The narrowest active ban covering `ip`, if any
+    pub fn covering(&self, ip: IpAddr, now: Instant) -> Option<IpNetwork> {
+        // IPv4 clients of a dual-stack listener show up as ::ffff:a.b.c.d
+        self.networks
+            .matches(ip.to_canonical())
+            .filter(|(_, until)| active(**until, now))
+            .max_by_key(|(network, _)| network.netmask())
+            .map(|(network, _)| network)
+    }
+
+    /// Other active bans that overlap `network`: ones containing it or contained by it
+    pub fn overlapping(&self, network: IpNetwork, now: Instant) -> Vec<IpNetwork> {
+        let mut overlapping: Vec<IpNetwork> = self
+            .networks
+            .iter()
+            .filter(|(other, until)| {
+                *other != network && active(**until, now) && overlaps(*other, network)
+            })
+            .map(|(other, _)| other)
+            .collect();
+        overlapping.sort();
+        overlapping
+    }
+
+    /// Banned networks, sorted, with the time left on each (None = permanent)
+    pub fn bans(&self, now: Instant) -> Vec<(IpNetwork, Option<Duration>)> {
+        let mut bans: Vec<_> = self
+            .networks
+            .iter()
+            .filter_map(|(network, until)| match until {
+                Some(until) => Some((network, Some(remaining(*until, now)?))),
+                None => Some((network, None)),
+            })
+            .collect();
+        bans.sort_by_key(|(network, _)| *network);
+        bans
+    }
+
+    /// Ban a client fingerprint ID. Returns false if it was already banned.
+    pub fn ban_fingerprint(&mut self, id: &str, duration: Option<Duration>, now: Instant) -> bool {
+        if self.is_fingerprint_banned(id, now) {
+            return false;
+        }
+        self.fingerprints
+            .insert(id.to_string(), duration.map(|duration| now + duration));
+        true
+    }
+
+    /// Returns false if the fingerprint wasn't banned
+    pub fn unban_fingerprint(&mut self, id: &str) -> bool {
+        self.fingerprints.remove(id).is_some()
+    }
+
+    pub fn is_fingerprint_banned(&self, id: &str, now: Instant) -> bool {
+        self.fingerprints
+            .get(id)
+            .is_some_and(|until| active(*until, now))
+    }
+
+    /// Banned fingerprint IDs, sorted, with the time left on each (None = permanent)
+    pub fn fingerprint_bans(&self, now: Instant) -> Vec<(String, Option<Duration>)> {
+        let mut bans: Vec<_> = self
+            .fingerprints
+            .iter()
+            .filter_map(|(id, until)| match until {
+                Some(until) => Some((id.clone(), Some(remaining(*until, now)?))),
+                None => Some((id.clone(), None)),
+            })
+            .collect();
+        bans.sort();
+        bans
+    }
+
+    /// Keep `username` (and `ip`, if known) out until `duration` from `now`
+    pub fn add_cooldown(
+        &mut self,
+        username: &str,
+        ip: Option<IpAddr>,
+        duration: Duration,
+        now: Instant,
+    ) {
+        let until = now + duration;
+        if let Some(ip) = ip {
+            self.kicked_ips.insert(ip, until);
+        }
+        self.kicked_names
+            .insert(username.to_lowercase(), (username.to_string(), until));
+    }
+
+    /// Time left on the cooldown for `ip`, if it has one
+    pub fn ip_cooldown(&self, ip: IpAddr, now: Instant) -> Option<Duration> {
+        self.kicked_ips
+            .get(&ip)
+            .and_then(|until| remaining(*until, now))
+    }
+
+    /// Time left on the cooldown for `username` (matched case-insensitively), if it has one
+    pub fn name_cooldown(&self, username: &str, now: Instant) -> Option<Duration> {
+        self.kicked_names
+            .get(&username.to_lowercase())
+            .and_then(|(_, until)| remaining(*until, now))
+    }
+
+    /// Active cooldowns as (IP or "user 'name'", time left), soonest to end first
+    pub fn cooldowns(&self, now: Instant) -> Vec<(String, Duration)> {
+        let ips = self
+            .kicked_ips
+            .iter()
+            .filter_map(|(ip, until)| Some((ip.to_string(), remaining(*until, now)?)));
+        let names = self.kicked_names.values().filter_map(|(name, until)| {
+            Some((format!("user '{}'", name), remaining(*until, now)?))
+        });
+        let mut cooldowns: Vec<_> = ips.chain(names).collect();
+        cooldowns.sort_by(|a, b| a.1.cmp(&b.1).then_with(|| a.0.cmp(&b.0)));
+        cooldowns
+    }
+
+    /// Lift bans and cooldowns that have ended.
+    /// Returns what each lifted ban was on: "203.0.113.0/24", "fingerprint 0123456789abcdef".
+    pub fn prune(&mut self, now: Instant) -> Vec<String> {
+        let mut networks: Vec<IpNetwork> = self
+            .networks
+            .iter()
+            .filter(|(_, until)| !active(**until, now))
+            .map(|(network, _)| network)
+            .collect();
+        networks.sort();
+        for network in &networks {
+            self.networks.remove(*network);
+        }
+        let mut expired: Vec<String> = networks.into_iter().map(describe).collect();
+        let mut fingerprints: Vec<String> = self
+            .fingerprints
+            .iter()
+            .filter(|(_, until)| !active(**until, now))
+            .map(|(id, _)| id.clone())
+            .collect();
+        fingerprints.sort();
+        for id in fingerprints {
+            self.fingerprints.remove(&id);
+            expired.push(format!("fingerprint {}", id));
+        }
+        self.kicked_ips.retain(|_, until| *until > now);
+        self.kicked_names.retain(|_, (_, until)| *until > now);
+        expired
+    }
+}
+
+/// A single IP as a network (/32 or /128)
+pub fn host_network(ip: IpAddr) -> IpNetwork {
+    IpNetwork::from(ip.to_canonical())
+}
+
+/// "203.0.113.7" for a single IP, "203.0.113.0/24" for a range
+pub fn describe(network: IpNetwork) -> String {
+    if network.netmask() == host_prefix(network) {
+        network.network_address().to_string()
+    } else {
+        network.to_string()
+    }
+}
+
+fn host_prefix(network: IpNetwork) -> u8 {
+    if network.is_ipv4() { 32 } else { 128 }
+}
+
+/// Two CIDR ranges overlap exactly when one contains the other
+fn overlaps(a: IpNetwork, b: IpNetwork) -> bool {
+    a.contains(b.network_address()) || b.contains(a.network_address())
+}
+
+fn active(until: Option<Instant>, now: Instant) -> bool {
+    until.is_none_or(|until| until > now)
+}
+
+/// Whole seconds left until `until`, rounded up so a cooldown never shows as "0s"
+fn remaining(until: Instant, now: Instant) -> Option<Duration> {
+    let left = until.checked_duration_since(now).filter(|d| !d.is_zero())?;
+    Some(Duration::from_secs(left.as_secs_f64().ceil() as u64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ban_and_unban() {
+        let ip: IpAddr = "203.0.113.7".parse().unwrap();
+        let network = host_network(ip);
+        let now = Instant::now();
+        let mut bans = BanList::default();
+        assert!(bans.ban(network, None, now));
+        assert!(!bans.ban(network, Some(Duration::from_secs(60)), now));
+        assert!(bans.is_banned(ip, now + Duration::from_secs(3600)));
+        assert_eq!(bans.bans(now), vec![(network, None)]);
+        assert_eq!(describe(network), "203.0.113.7");
+        assert!(bans.unban(network));
+        assert!(!bans.unban(network));
+        assert!(!bans.is_banned(ip, now));
+    }
+
+    #[test]
+    fn test_expiring_ban() {
+        let ip = host_network("203.0.113.7".parse().unwrap());
+        let other = host_network("198.51.100.1".parse().unwrap());
+        let now = Instant::now();
+        let mut bans = BanList::default();
+        assert!(bans.ban(ip, Some(Duration::from_secs(24 * 3600)), now));
+        assert!(bans.ban(other, None, now));
+
+        let later = now + Duration::from_secs(3600);
+        assert!(bans.is_banned(ip.network_address(), later));
+        assert_eq!(
+            bans.bans(later),
+            vec![(other, None), (ip, Some(Duration::from_secs(23 * 3600)))]
+        );
+        assert!(bans.prune(later).is_empty());
+
+        let expired = now + Duration::from_secs(24 * 3600);
+        assert!(!bans.is_banned(ip.network_address(), expired));
+        assert_eq!(bans.prune(expired), vec!["203.0.113.7".to_string()]);
+        assert_eq!(bans.bans(expired), vec![(other, None)]);
+
+        // An expired ban can be replaced before maintenance gets to it
+        assert!(bans.ban(ip, Some(Duration::from_secs(60)), now));
+        assert!(bans.ban(ip, None, expired));
+        assert_eq!(bans.bans(expired), vec![(other, None), (ip, None)]);
+    }
+
+    #[test]
+    fn test_cidr_bans() {
+        let now = Instant::now();
+        let range: IpNetwork = "203.0.113.0/24".parse().unwrap();
+        let wider: IpNetwork = "203.0.0.0/16".parse().unwrap();
+        let host = host_network("203.0.113.7".parse().unwrap());
+        let v6: IpNetwork = "2001:db8::/32".parse().unwrap();
+        let mut bans = BanList::default();
+        assert!(bans.ban(range, None, now));
+        assert!(bans.ban(v6, Some(Duration::from_secs(60)), now));
+
+        assert!(bans.is_banned("203.0.113.200".parse().unwrap(), now));
+        assert!(bans.is_banned("::ffff:203.0.113.200".parse().unwrap(), now));
+        assert!(bans.is_banned("2001:db8:1::5".parse().unwrap(), now));
+        assert!(!bans.is_banned("203.0.114.1".parse().unwrap(), now));
+        assert_eq!(describe(range), "203.0.113.0/24");
+
+        // Overlaps are found in both directions, and the narrowest ban wins
+        assert_eq!(bans.overlapping(host, now), vec![range]);
+        assert!(bans.ban(host, None, now));
+        assert_eq!(bans.overlapping(wider, now), vec![range, host]);
+        assert!(bans.overlapping(v6, now).is_empty());
+        assert_eq!(
+            bans.covering("203.0.113.7".parse().unwrap(), now),
+            Some(host)
+        );
+
+        // Unbanning one IP leaves the range that covers it
+        assert!(bans.unban(host));
+        assert!(bans.is_banned("203.0.113.7".parse().unwrap(), now));
+        assert_eq!(
+            bans.prune(now + Duration::from_secs(60)),
+            vec!["2001:db8::/32".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_fingerprint_bans() {
+        let now = Instant::now();
+        let mut bans = BanList::default();
+        assert!(bans.ban_fingerprint("0123456789abcdef", None, now));
+        assert!(!bans.ban_fingerprint("0123456789abcdef", None, now));
+        assert!(bans.ban_fingerprint("fedcba9876543210", Some(Duration::from_secs(60)), now));
+        assert!(bans.is_fingerprint_banned("0123456789abcdef", now));
+        assert!(!bans.is_fingerprint_banned("1111111111111111", now));
+        // Fingerprint bans don't touch IP bans
+        assert!(!bans.is_banned("203.0.113.7".parse().unwrap(), now));
+
+        let later = now + Duration::from_secs(60);
+        assert_eq!(
+            bans.prune(later),
+            vec!["fingerprint fedcba9876543210".to_string()]
+        );
+        assert_eq!(
+            bans.fingerprint_bans(later),
+            vec![("0123456789abcdef".to_string(), None)]
+        );
+        assert!(bans.unban_fingerprint("0123456789abcdef"));
+        assert!(!bans.unban_fingerprint("0123456789abcdef"));
+    }
+
+    #[test]
+    fn test_kick_cooldown() {
+        let ip: IpAddr = "203.0.113.7".parse().unwrap();
+        let now = Instant::now();
+        let mut bans = BanList::default();
+        bans.add_cooldown("Bob", Some(ip), Duration::from_secs(600), now);
+
+        let later = now + Duration::from_millis(90_500);
+        assert_eq!(bans.ip_cooldown(ip, later), Some(Duration::from_secs(510)));
+        assert_eq!(
+            bans.name_cooldown("bob", later),
+            Some(Duration::from_secs(510))
+        );
+        assert_eq!(bans.name_cooldown("alice", later), None);
+        // A cooldown is not a ban
+        assert!(!bans.is_banned(ip, later));
+        assert_eq!(
+            bans.cooldowns(later),
+            vec![
+                ("203.0.113.7".to_string(), Duration::from_secs(510)),
+                ("user 'Bob'".to_string(), Duration::from_secs(510)),
+            ]
+        );
+
+        let ended = now + Duration::from_secs(600);
+        assert_eq!(bans.ip_cooldown(ip, ended), None);
+        assert!(bans.prune(ended).is_empty());
+        assert!(bans.cooldowns(now).is_empty());
+    }
+
+    #[test]
+    fn test_unban_lifts_cooldown() {
+        let ip: IpAddr = "2001:db8::1".parse().unwrap();
+        let now = Instant::now();
+        let mut bans = BanList::default();
+        bans.add_cooldown("bob", Some(ip), Duration::from_secs(60), now);
+        assert!(bans.unban(host_network(ip)));
+        assert_eq!(bans.ip_cooldown(ip, now), None);
+        // The nickname stays on cooldown until it ends
+        assert!(bans.name_cooldown("bob", now).is_some());
+    }
+}
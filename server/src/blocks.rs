@@ -0,0 +1,252 @@
+//! Server-side user blocking: direct messages and file transfers from a blocked
+//! user are never delivered. A logged in user's blocks belong to their account and are
+//! saved to disk, so they survive reconnects and server restarts. A guest's live only
+//! as long as their connection: nicknames change hands, and the next guest to take a
+//! name mustn't inherit the last one's list.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+/// File name of the block list inside the server data directory
+pub const BLOCKS_FILE: &str = "blocks.tsv";
+
+/// Whose list a block goes on
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Holder {
+    /// Logged in to a registered nickname: saved
+    Account,
+    /// Kept in memory until the guest leaves
+    Guest,
+}
+
+#[derive(Debug, Default)]
+pub struct BlockList {
+    /// None = blocks only live in memory
+    path: Option<PathBuf>,
+    /// account -> users they have blocked
+    accounts: BTreeMap<String, BTreeSet<String>>,
+    /// guest -> users they have blocked
+    guests: BTreeMap<String, BTreeSet<String>>,
+}
+
+impl BlockList {
+    /// Load the block list from `path` (a missing file is an empty list)
+    pub fn load(path: Option<PathBuf>) -> io::Result<Self> {
+        let mut list = BlockList {
+            path,
+            ..BlockList::default()
+        };
+        let Some(path) = &list.path else {
+            return Ok(list);
+        };
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(list),
+            Err(e) => return Err(e),
+        };
+        // Format: blocker \t blocked (one block per line)
+        for line in contents.lines() {
+            if let Some((blocker, blocked)) = line.split_once('\t') {
+                list.accounts
+                    .entry(blocker.to_string())
+                    .or_default()
+                    .insert(blocked.to_string());
+            }
+        }
+        Ok(list)
+    }
+
+    /// Whether `line` is a block as `save` writes it (for the startup integrity check)
+    pub fn check_line(line: &str) -> bool {
+        line.split_once('\t').is_some_and(|(blocker, blocked)| {
+            !blocker.is_empty() && !blocked.is_empty() && !blocked.contains('\t')
+        })
+    }
+
+    fn lists(&self, holder: Holder) -> &BTreeMap<String, BTreeSet<String>> {
+        match holder {
+            Holder::Account => &self.accounts,
+            Holder::Guest => &self.guests,
+        }
+    }
+
+    fn lists_mut(&mut self, holder: Holder) -> &mut BTreeMap<String, BTreeSet<String>> {
+        match holder {
+            Holder::Account => &mut self.accounts,
+            Holder::Guest => &mut self.guests,
+        }
+    }
+
+    /// Returns false if the block already existed
+    pub fn block(&mut self, blocker: &str, blocked: &str, holder: Holder) -> bool {
+        self.lists_mut(holder)
+            .entry(blocker.to_string())
+            .or_default()
+            .insert(blocked.to_string())
+    }
+
+    /// Returns false if there was no such block
+    pub fn unblock(&mut self, blocker: &str, blocked: &str, holder: Holder) -> bool {
+        let lists = self.lists_mut(holder);
+        let Some(entry) = lists.get_mut(blocker) else {
+            return false;
+        };
+        let removed = entry.remove(blocked);
+        if entry.is_empty() {
+            lists.remove(blocker);
+        }
+        removed
+    }
+
+    /// Whether `recipient` has blocked `sender`
+    pub fn is_blocked(&self, recipient: &str, sender: &str, holder: Holder) -> bool {
+        self.lists(holder)
+            .get(recipient)
+            .is_some_and(|blocked| blocked.contains(sender))
+    }
+
+    /// Users blocked by `blocker`, sorted by name
+    pub fn blocked_by(&self, blocker: &str, holder: Holder) -> Vec<String> {
+        self.lists(holder)
+            .get(blocker)
+            .map(|blocked| blocked.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// A guest's list goes along to the name they're given. One already there (a
+    /// guest's that wasn't forgotten) is left alone, and the moved list dropped.
+    pub fn move_guest(&mut self, old_name: &str, new_name: &str) {
+        if let Some(blocked) = self.guests.remove(old_name) {
+            self.guests.entry(new_name.to_string()).or_insert(blocked);
+        }
+    }
+
+    /// Carry a guest's blocks over to their new name, on both sides of each block.
+    /// Returns true if a saved list changed.
+    pub fn rename(&mut self, old_name: &str, new_name: &str) -> bool {
+        self.move_guest(old_name, new_name);
+        for blocked in self.guests.values_mut() {
+            if blocked.remove(old_name) {
+                blocked.insert(new_name.to_string());
+            }
+        }
+        let mut changed = false;
+        for blocked in self.accounts.values_mut() {
+            if blocked.remove(old_name) {
+                blocked.insert(new_name.to_string());
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    /// A guest who registered their nickname keeps their list, now saved with the
+    /// account. Returns true if there was one.
+    pub fn adopt(&mut self, name: &str) -> bool {
+        let Some(blocked) = self.guests.remove(name) else {
+            return false;
+        };
+        self.accounts
+            .entry(name.to_string())
+            .or_default()
+            .extend(blocked);
+        true
+    }
+
+    /// A guest left: their list goes with them
+    pub fn forget_guest(&mut self, name: &str) {
+        self.guests.remove(name);
+    }
+
+    /// Write the accounts' blocks to disk, off the async runtime's threads
+    pub async fn save(&self) -> io::Result<()> {
+        let Some(path) = self.path.clone() else {
+            return Ok(());
+        };
+        let mut contents = String::new();
+        for (blocker, blocked) in &self.accounts {
+            for user in blocked {
+                contents.push_str(&format!("{}\t{}\n", blocker, user));
+            }
+        }
+        tokio::task::spawn_blocking(move || {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::write(path, contents)
+        })
+        .await
+        .map_err(io::Error::other)?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_block_is_one_way() {
+        let mut blocks = BlockList::default();
+        assert!(blocks.block("alice", "bob", Holder::Account));
+        assert!(!blocks.block("alice", "bob", Holder::Account));
+        assert!(blocks.is_blocked("alice", "bob", Holder::Account));
+        assert!(!blocks.is_blocked("bob", "alice", Holder::Account));
+        assert!(blocks.unblock("alice", "bob", Holder::Account));
+        assert!(!blocks.unblock("alice", "bob", Holder::Account));
+        assert!(!blocks.is_blocked("alice", "bob", Holder::Account));
+    }
+
+    #[test]
+    fn test_rename_carries_both_sides() {
+        let mut blocks = BlockList::default();
+        blocks.block("alice", "bob", Holder::Guest);
+        blocks.block("carol", "alice", Holder::Account);
+        assert!(blocks.rename("alice", "alicia"));
+        assert!(blocks.is_blocked("alicia", "bob", Holder::Guest));
+        assert!(blocks.is_blocked("carol", "alicia", Holder::Account));
+        assert!(!blocks.rename("nobody", "somebody"));
+    }
+
+    #[test]
+    fn test_guest_lists_stay_apart_from_accounts() {
+        let mut blocks = BlockList::default();
+        blocks.block("dave", "mallory", Holder::Account);
+        // A guest renaming to a name with a saved list doesn't take it over
+        blocks.block("guest1", "bob", Holder::Guest);
+        blocks.rename("guest1", "dave");
+        assert_eq!(blocks.blocked_by("dave", Holder::Account), vec!["mallory"]);
+        assert_eq!(blocks.blocked_by("dave", Holder::Guest), vec!["bob"]);
+        blocks.forget_guest("dave");
+        assert!(blocks.blocked_by("dave", Holder::Guest).is_empty());
+
+        // Registering keeps a guest's list, now with the account
+        blocks.block("erin", "bob", Holder::Guest);
+        assert!(blocks.adopt("erin"));
+        assert!(!blocks.adopt("erin"));
+        assert!(blocks.is_blocked("erin", "bob", Holder::Account));
+    }
+
+    #[tokio::test]
+    async fn test_save_and_load_round_trip() {
+        let path = std::env::temp_dir().join(format!(
+            "rust_chat_blocks_{}_{}",
+            std::process::id(),
+            BLOCKS_FILE
+        ));
+        let mut blocks = BlockList::load(Some(path.clone())).unwrap();
+        blocks.block("alice", "bob", Holder::Account);
+        blocks.block("alice", "carol", Holder::Account);
+        blocks.block("guest", "carol", Holder::Guest);
+        blocks.save().await.unwrap();
+
+        let loaded = BlockList::load(Some(path.clone())).unwrap();
+        assert_eq!(
+            loaded.blocked_by("alice", Holder::Account),
+            vec!["bob", "carol"]
+        );
+        assert!(loaded.blocked_by("guest", Holder::Account).is_empty());
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,257 @@
+//! The broadcast channel every relayed message goes through. It can be swapped for
+//! one of another size while the server runs (/channel resize): each receiver reads
+//! what's left on the old channel, then carries on with the new one from the moment
+//! of the swap, so nothing is missed or seen twice. Broadcasts are numbered in the
+//! order they're sent, so a receiver can tell where it is. Connections that fall
+//! behind it are counted for /channel stats.
+
+use crate::state::ConnectionId;
+use shared::message::ChatMessage;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use tokio::sync::broadcast::{self, error::RecvError, error::SendError};
+
+pub type Broadcast = (ChatMessage, ConnectionId);
+
+/// Largest capacity /channel resize accepts (the channel allocates every slot up front)
+pub const MAX_CAPACITY: usize = 1 << 20;
+
+/// Receivers on newer channels, waiting for their subscriber to finish the one it's on
+type Handover = Mutex<VecDeque<broadcast::Receiver<Broadcast>>>;
+
+/// How far one connection fell behind the channel
+#[derive(Debug, Clone, PartialEq)]
+pub struct Lag {
+    /// Nickname at the time
+    pub who: String,
+    /// Times it fell behind
+    pub events: u64,
+    /// Broadcasts it missed (room messages among them were replayed from history)
+    pub skipped: u64,
+}
+
+struct Inner {
+    sender: RwLock<broadcast::Sender<Broadcast>>,
+    /// Broadcasts sent so far; held while sending, so they're numbered in channel order
+    sent: Mutex<u64>,
+    capacity: AtomicUsize,
+    handovers: Mutex<Vec<Weak<Handover>>>,
+    /// Connections that have lagged, until they close
+    lags: Mutex<HashMap<ConnectionId, Lag>>,
+}
+
+/// The server's broadcast channel (cheap to clone)
+#[derive(Clone)]
+pub struct BroadcastChannel {
+    inner: Arc<Inner>,
+}
+
+impl BroadcastChannel {
+    pub fn new(capacity: usize) -> Self {
+        let (sender, _rx) = broadcast::channel(capacity);
+        BroadcastChannel {
+            inner: Arc::new(Inner {
+                sender: RwLock::new(sender),
+                sent: Mutex::new(0),
+                capacity: AtomicUsize::new(capacity),
+                handovers: Mutex::new(Vec::new()),
+                lags: Mutex::new(HashMap::new()),
+            }),
+        }
+    }
+
+    /// Send to every receiver. Returns the broadcast's number.
+    pub fn send(&self, message: Broadcast) -> Result<u64, SendError<Broadcast>> {
+        let mut sent = self.inner.sent.lock().unwrap();
+        self.inner.sender.read().unwrap().send(message)?;
+        *sent += 1;
+        Ok(*sent - 1)
+    }
+
+    /// Broadcasts sent so far, which is also the number the next one will get
+    pub fn sent(&self) -> u64 {
+        *self.inner.sent.lock().unwrap()
+    }
+
+    /// A receiver of everything sent from now on
+    pub fn subscribe(&self) -> ChannelReceiver {
+        let sent = self.inner.sent.lock().unwrap();
+        let sender = self.inner.sender.read().unwrap();
+        let handover = Arc::new(Handover::default());
+        let mut handovers = self.inner.handovers.lock().unwrap();
+        handovers.retain(|handover| handover.strong_count() > 0);
+        handovers.push(Arc::downgrade(&handover));
+        ChannelReceiver {
+            rx: sender.subscribe(),
+            handover,
+            channel: self.clone(),
+            position: *sent,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.inner.capacity.load(Ordering::Relaxed)
+    }
+
+    /// Receivers on the current channel (connections, or shard tasks when sharding)
+    pub fn receivers(&self) -> usize {
+        self.inner.sender.read().unwrap().receiver_count()
+    }
+
+    /// Broadcasts not yet read by every receiver
+    pub fn queued(&self) -> usize {
+        self.inner.sender.read().unwrap().len()
+    }
+
+    /// Swap in a channel holding `capacity` broadcasts and move every receiver onto
+    /// it. Returns how many were moved.
+    pub fn resize(&self, capacity: usize) -> usize {
+        let mut sender = self.inner.sender.write().unwrap();
+        let (resized, _rx) = broadcast::channel(capacity);
+        let mut handovers = self.inner.handovers.lock().unwrap();
+        handovers.retain(|handover| match handover.upgrade() {
+            Some(handover) => {
+                handover.lock().unwrap().push_back(resized.subscribe());
+                true
+            }
+            None => false,
+        });
+        // Dropping the old sender closes the old channel once it's been read
+        *sender = resized;
+        self.inner.capacity.store(capacity, Ordering::Relaxed);
+        handovers.len()
+    }
+
+    /// A connection fell behind by `skipped` broadcasts
+    pub fn lagged(&self, id: ConnectionId, who: &str, skipped: u64) {
+        let mut lags = self.inner.lags.lock().unwrap();
+        let lag = lags.entry(id).or_insert_with(|| Lag {
+            who: String::new(),
+            events: 0,
+            skipped: 0,
+        });
+        lag.who = who.to_string();
+        lag.events += 1;
+        lag.skipped += skipped;
+    }
+
+    /// The connection closed
+    pub fn forget(&self, id: ConnectionId) {
+        self.inner.lags.lock().unwrap().remove(&id);
+    }
+
+    /// Open connections that have lagged, most often first
+    pub fn lags(&self) -> Vec<(ConnectionId, Lag)> {
+        let mut lags: Vec<_> = self
+            .inner
+            .lags
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, lag)| (*id, lag.clone()))
+            .collect();
+        lags.sort_by(|(_, a), (_, b)| b.events.cmp(&a.events).then(b.skipped.cmp(&a.skipped)));
+        lags
+    }
+}
+
+/// One subscriber's end of the channel, following it across resizes
+pub struct ChannelReceiver {
+    rx: broadcast::Receiver<Broadcast>,
+    handover: Arc<Handover>,
+    channel: BroadcastChannel,
+    /// Number of the next broadcast it will receive
+    position: u64,
+}
+
+impl ChannelReceiver {
+    pub async fn recv(&mut self) -> Result<Broadcast, RecvError> {
+        loop {
+            match self.rx.recv().await {
+                Err(RecvError::Closed) => {
+                    // Everything on the old channel has been read: move to the new one
+                    let next = self.handover.lock().unwrap().pop_front();
+                    match next {
+                        Some(next) => self.rx = next,
+                        None => return Err(RecvError::Closed),
+                    }
+                }
+                Err(RecvError::Lagged(skipped)) => {
+                    self.position += skipped;
+                    return Err(RecvError::Lagged(skipped));
+                }
+                result => {
+                    self.position += 1;
+                    return result;
+                }
+            }
+        }
+    }
+
+    /// Number of the next broadcast it will receive (the last one received is one less)
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// Skip everything sent so far; only broadcasts sent from now on are received
+    pub fn skip_queued(&mut self) {
+        let sent = self.channel.inner.sent.lock().unwrap();
+        let sender = self.channel.inner.sender.read().unwrap();
+        self.handover.lock().unwrap().clear();
+        self.rx = sender.subscribe();
+        self.position = *sent;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shared::message::MessageTypes;
+
+    fn message(text: &str) -> Broadcast {
+        let message =
+            ChatMessage::try_new(MessageTypes::ChatMessage, Some(text.as_bytes().to_vec()))
+                .unwrap();
+        (message, ConnectionId::next())
+    }
+
+    async fn text(rx: &mut ChannelReceiver) -> String {
+        let (message, _) = rx.recv().await.unwrap();
+        message.content_as_string().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_resize_moves_receivers_without_losing_messages() {
+        let channel = BroadcastChannel::new(2);
+        let mut rx = channel.subscribe();
+        channel.send(message("before")).unwrap();
+
+        assert_eq!(channel.resize(8), 1);
+        assert_eq!(channel.capacity(), 8);
+        channel.send(message("after")).unwrap();
+        assert_eq!(channel.resize(16), 1);
+        channel.send(message("again")).unwrap();
+
+        assert_eq!(text(&mut rx).await, "before");
+        assert_eq!(text(&mut rx).await, "after");
+        assert_eq!(text(&mut rx).await, "again");
+        assert_eq!(channel.receivers(), 1);
+
+        // A closed receiver isn't moved
+        drop(rx);
+        assert_eq!(channel.resize(4), 0);
+
+        let first = ConnectionId::next();
+        let second = ConnectionId::next();
+        channel.lagged(first, "alice", 3);
+        channel.lagged(second, "bob", 10);
+        channel.lagged(first, "alice", 1);
+        let lags = channel.lags();
+        assert_eq!(lags[0].0, first);
+        assert_eq!(lags[0].1.events, 2);
+        assert_eq!(lags[0].1.skipped, 4);
+        channel.forget(first);
+        assert_eq!(channel.lags().len(), 1);
+    }
+}
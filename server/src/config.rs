@@ -0,0 +1,926 @@
+//! Server settings, read from environment variables when the server starts. A value
+//! that can't be used is logged and its default used instead; sizes too large to
+//! allocate stop the server.
+
+use crate::accounts::{NickConflictPolicy, ReclaimPolicy};
+use crate::history::{self, ExportPolicy};
+use crate::history_writer;
+use crate::scopes::Scopes;
+use crate::translate::Translator;
+use crate::waiting_room::DEFAULT_WAITING_ROOM_SIZE;
+use crate::{bandwidth, schedule, self_signed, syslog, violations};
+use shared::challenge::MAX_DIFFICULTY;
+use shared::network::WRITE_BUFFER_SIZE;
+use shared::server_info::ServerInfo;
+use shared::socket::{Keepalive, SocketOptions};
+use shared::trace;
+use shared::version::VERSION;
+use std::fmt::Display;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+use std::{env, io};
+use tracing::{error, info, warn};
+
+pub const CHAT_SERVER_ADDR_ENV_VAR: &str = "CHAT_SERVER_ADDR";
+pub const CHAT_SERVER_MAX_CLIENTS_ENV_VAR: &str = "CHAT_SERVER_MAX_CLIENTS";
+pub const CHAT_SERVER_WAITING_ROOM_SIZE_ENV_VAR: &str = "CHAT_SERVER_WAITING_ROOM_SIZE";
+pub const CHAT_SERVER_RESERVED_SLOTS_ENV_VAR: &str = "CHAT_SERVER_RESERVED_SLOTS";
+pub const TLS_CERT_PATH_ENV_VAR: &str = "TLS_CERT_PATH";
+pub const TLS_KEY_PATH_ENV_VAR: &str = "TLS_KEY_PATH";
+pub const TLS_SELF_SIGNED_ENV_VAR: &str = "TLS_SELF_SIGNED";
+pub const TLS_CLIENT_CA_PATH_ENV_VAR: &str = "TLS_CLIENT_CA_PATH";
+pub const TLS_CLIENT_CERT_REQUIRED_ENV_VAR: &str = "TLS_CLIENT_CERT_REQUIRED";
+pub const TLS_CLIENT_CERT_MAP_ENV_VAR: &str = "TLS_CLIENT_CERT_MAP";
+pub const CHAT_SERVER_IDENTITY_ENV_VAR: &str = "CHAT_SERVER_IDENTITY";
+pub const CHAT_SERVER_PARANOID_ENV_VAR: &str = "CHAT_SERVER_PARANOID";
+pub const CHAT_SERVER_PARANOID_MAX_VIOLATIONS_ENV_VAR: &str = "CHAT_SERVER_PARANOID_MAX_VIOLATIONS";
+pub const CHAT_SERVER_HOURLY_QUOTA_MB_ENV_VAR: &str = "CHAT_SERVER_HOURLY_QUOTA_MB";
+pub const CHAT_SERVER_MEMORY_CAP_MB_ENV_VAR: &str = "CHAT_SERVER_MEMORY_CAP_MB";
+pub const CHAT_SERVER_BROADCAST_SHARDS_ENV_VAR: &str = "CHAT_SERVER_BROADCAST_SHARDS";
+pub const CHAT_SERVER_WRITE_BUFFER_KB_ENV_VAR: &str = "CHAT_SERVER_WRITE_BUFFER_KB";
+pub const CHAT_SERVER_TCP_NODELAY_ENV_VAR: &str = "CHAT_SERVER_TCP_NODELAY";
+pub const CHAT_SERVER_KEEPALIVE_INTERVAL_ENV_VAR: &str = "CHAT_SERVER_KEEPALIVE_INTERVAL";
+pub const CHAT_SERVER_KEEPALIVE_PROBE_INTERVAL_ENV_VAR: &str =
+    "CHAT_SERVER_KEEPALIVE_PROBE_INTERVAL";
+pub const CHAT_SERVER_KEEPALIVE_PROBES_ENV_VAR: &str = "CHAT_SERVER_KEEPALIVE_PROBES";
+pub const CHAT_SERVER_RECV_BUFFER_KB_ENV_VAR: &str = "CHAT_SERVER_RECV_BUFFER_KB";
+pub const CHAT_SERVER_SEND_BUFFER_KB_ENV_VAR: &str = "CHAT_SERVER_SEND_BUFFER_KB";
+pub const CHAT_SERVER_ROOM_EXPORT_ENV_VAR: &str = "CHAT_SERVER_ROOM_EXPORT";
+pub const CHAT_SERVER_ROOM_EXPORT_LIMIT_ENV_VAR: &str = "CHAT_SERVER_ROOM_EXPORT_LIMIT";
+pub const CHAT_SERVER_TRANSLATE_COMMAND_ENV_VAR: &str = "CHAT_SERVER_TRANSLATE_COMMAND";
+pub const CHAT_SERVER_DATA_DIR_ENV_VAR: &str = "CHAT_SERVER_DATA_DIR";
+pub const CHAT_SERVER_NICK_RECLAIM_ENV_VAR: &str = "CHAT_SERVER_NICK_RECLAIM";
+pub const CHAT_SERVER_NICK_CONFLICT_ENV_VAR: &str = "CHAT_SERVER_NICK_CONFLICT";
+pub const CHAT_SERVER_KICK_COOLDOWN_ENV_VAR: &str = "CHAT_SERVER_KICK_COOLDOWN";
+pub const CHAT_SERVER_OPEN_REGISTRATION_ENV_VAR: &str = "CHAT_SERVER_OPEN_REGISTRATION";
+pub const CHAT_SERVER_ADMIN_PASSWORD_ENV_VAR: &str = "CHAT_SERVER_ADMIN_PASSWORD";
+pub const CHAT_SERVER_GUEST_SCOPES_ENV_VAR: &str = "CHAT_SERVER_GUEST_SCOPES";
+pub const CHAT_SERVER_MULTI_SESSION_ENV_VAR: &str = "CHAT_SERVER_MULTI_SESSION";
+pub const CHAT_SERVER_LOG_CONTENT_ENV_VAR: &str = "CHAT_SERVER_LOG_CONTENT";
+pub const CHAT_SERVER_SYSLOG_ENV_VAR: &str = "CHAT_SERVER_SYSLOG";
+pub const CHAT_SERVER_SYSLOG_FACILITY_ENV_VAR: &str = "CHAT_SERVER_SYSLOG_FACILITY";
+pub const CHAT_SERVER_SYSLOG_SEVERITY_ENV_VAR: &str = "CHAT_SERVER_SYSLOG_SEVERITY";
+pub const CHAT_SERVER_SYSLOG_CA_PATH_ENV_VAR: &str = "CHAT_SERVER_SYSLOG_CA_PATH";
+pub const CHAT_SERVER_POW_DIFFICULTY_ENV_VAR: &str = "CHAT_SERVER_POW_DIFFICULTY";
+pub const CHAT_TRACE_ENV_VAR: &str = "CHAT_TRACE";
+pub const CHAT_SERVER_PERSIST_HISTORY_ENV_VAR: &str = "CHAT_SERVER_PERSIST_HISTORY";
+#[cfg(feature = "sqlite")]
+pub const CHAT_SERVER_HISTORY_DB_ENV_VAR: &str = "CHAT_SERVER_HISTORY_DB";
+pub const CHAT_SERVER_ONION_DIR_ENV_VAR: &str = "CHAT_SERVER_ONION_DIR";
+#[cfg(feature = "ldap")]
+pub const CHAT_SERVER_LDAP_URL_ENV_VAR: &str = "CHAT_SERVER_LDAP_URL";
+#[cfg(feature = "ldap")]
+pub const CHAT_SERVER_LDAP_USER_DN_ENV_VAR: &str = "CHAT_SERVER_LDAP_USER_DN";
+#[cfg(feature = "oidc")]
+pub const CHAT_SERVER_OIDC_KEY_PATH_ENV_VAR: &str = "CHAT_SERVER_OIDC_KEY_PATH";
+#[cfg(feature = "oidc")]
+pub const CHAT_SERVER_OIDC_ISSUER_ENV_VAR: &str = "CHAT_SERVER_OIDC_ISSUER";
+#[cfg(feature = "oidc")]
+pub const CHAT_SERVER_OIDC_AUDIENCE_ENV_VAR: &str = "CHAT_SERVER_OIDC_AUDIENCE";
+#[cfg(feature = "oidc")]
+pub const CHAT_SERVER_OIDC_USERNAME_CLAIM_ENV_VAR: &str = "CHAT_SERVER_OIDC_USERNAME_CLAIM";
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+pub const CHAT_SERVER_IO_URING_ENV_VAR: &str = "CHAT_SERVER_IO_URING";
+pub const CHAT_SERVER_NAME_ENV_VAR: &str = "CHAT_SERVER_NAME";
+pub const CHAT_SERVER_NETWORK_ENV_VAR: &str = "CHAT_SERVER_NETWORK";
+pub const CHAT_SERVER_DESCRIPTION_ENV_VAR: &str = "CHAT_SERVER_DESCRIPTION";
+
+/// Server name shown to clients unless CHAT_SERVER_NAME is set
+const DEFAULT_SERVER_NAME: &str = "rust_chat";
+/// Largest hourly quota and memory cap accepted (1 TB)
+const MAX_CONFIGURED_MEMORY: u64 = 1 << 40;
+const MB: u64 = 1024 * 1024;
+/// Largest per-connection write buffer and socket buffer accepted (64 MB)
+const MAX_CONFIGURED_BUFFER: u64 = 64 << 20;
+const KB: u64 = 1024;
+
+/// Where audit events are streamed besides the audit log
+pub struct SyslogSettings {
+    pub target: syslog::SyslogTarget,
+    pub facility: u8,
+    pub severities: syslog::SeverityMap,
+    /// CA certificates a tls:// collector is checked against (None = the system's)
+    pub ca_path: Option<PathBuf>,
+}
+
+/// Mutual TLS: the CAs client certificates are signed by and the nicknames they log in as
+pub struct ClientCertSettings {
+    pub ca_path: String,
+    /// Whether clients without a certificate are turned away
+    pub required: bool,
+    pub map_path: PathBuf,
+}
+
+#[cfg(feature = "ldap")]
+pub struct LdapSettings {
+    pub url: String,
+    pub user_dn: String,
+}
+
+#[cfg(feature = "oidc")]
+pub struct OidcSettings {
+    pub key_path: String,
+    pub issuer: String,
+    pub audience: String,
+    pub username_claim: String,
+}
+
+/// Everything the server is configured with, before any of it is loaded or opened
+pub struct ServerConfig {
+    pub addr: String,
+    pub max_clients: usize,
+    /// Joins past max_clients queue for a slot (0 = turn them away)
+    pub waiting_room_size: usize,
+    /// Slots of max_clients that guests can't fill, so a flood of them can't lock
+    /// registered users out
+    pub reserved_slots: usize,
+    pub server_identity: String,
+    pub info: ServerInfo,
+    /// Paranoid mode: protocol violations per IP before it's banned (None = off)
+    pub paranoid_max_violations: Option<u32>,
+    /// Bytes each user may send per hour (None = unlimited)
+    pub bandwidth_quota: Option<u64>,
+    /// Memory for room history, queued broadcasts and held messages (None = no cap)
+    pub memory_cap: Option<usize>,
+    /// Tasks fanning broadcasts out to connections (0 = off). Off by default: in
+    /// fanout::tests::fanout_benchmark sharding only added latency
+    pub broadcast_shards: usize,
+    /// Each frame is gathered in a write buffer and written in one go (0 = off)
+    pub write_buffer: usize,
+    pub socket_options: SocketOptions,
+    pub room_export: ExportPolicy,
+    pub room_export_limit: usize,
+    /// Command translating messages in rooms a moderator set a language on
+    pub translator: Option<Translator>,
+    /// Proof-of-work challenge before joining, in leading zero bits (None = off)
+    pub pow_difficulty: Option<u8>,
+    pub open_registration: bool,
+    pub guest_scopes: Scopes,
+    pub multi_session: bool,
+    pub log_content: bool,
+    #[cfg(feature = "ldap")]
+    pub ldap: Option<LdapSettings>,
+    #[cfg(feature = "oidc")]
+    pub oidc: Option<OidcSettings>,
+    /// Persistent server data (block lists, accounts, bot tokens, last seen)
+    pub data_dir: PathBuf,
+    /// One-time password of the admin account made on first start (None = generated)
+    pub admin_password: Option<String>,
+    pub reclaim_policy: ReclaimPolicy,
+    pub nick_conflict: NickConflictPolicy,
+    /// How long kicked users are kept out by default (None = no cooldown)
+    pub kick_cooldown: Option<Duration>,
+    /// Protocol trace mode: log every frame with a hex dump to the data directory
+    pub trace: bool,
+    /// Room history is kept in an SQLite database at this path
+    #[cfg(feature = "sqlite")]
+    pub history_db: Option<PathBuf>,
+    /// Room history is kept in the data directory's log
+    pub persist_history: bool,
+    pub syslog: Option<SyslogSettings>,
+    pub client_certs: Option<ClientCertSettings>,
+    /// Certificate and key (generated there if self_signed and missing)
+    pub tls_paths: Option<(String, String)>,
+    pub self_signed: bool,
+    /// tor's HiddenServiceDir, to read the onion address from
+    pub onion_dir: Option<PathBuf>,
+}
+
+impl ServerConfig {
+    pub fn from_env() -> io::Result<Self> {
+        Self::from_vars(|name| env::var(name).ok())
+    }
+
+    /// Read the settings through `var` (the environment, or a map in tests)
+    fn from_vars(var: impl Fn(&str) -> Option<String>) -> io::Result<Self> {
+        let vars = Vars(var);
+
+        let max_clients = vars.number(CHAT_SERVER_MAX_CLIENTS_ENV_VAR, 100);
+        let mut reserved_slots = vars.number(CHAT_SERVER_RESERVED_SLOTS_ENV_VAR, 0);
+        if reserved_slots > max_clients {
+            warn!(
+                "{} is more than {} - all {} slots are reserved",
+                CHAT_SERVER_RESERVED_SLOTS_ENV_VAR, CHAT_SERVER_MAX_CLIENTS_ENV_VAR, max_clients
+            );
+            reserved_slots = max_clients;
+        }
+
+        // '|' separates the branding fields on the wire, so only the description (the
+        // last field) may contain one
+        let branding = |name: &str, separators: &[char]| {
+            vars.get(name)
+                .map(|val| val.replace(separators, " ").trim().to_string())
+                .unwrap_or_default()
+        };
+        let name = branding(CHAT_SERVER_NAME_ENV_VAR, &['|', '\n', '\r']);
+        let info = ServerInfo {
+            name: if name.is_empty() {
+                DEFAULT_SERVER_NAME.to_string()
+            } else {
+                name
+            },
+            network: branding(CHAT_SERVER_NETWORK_ENV_VAR, &['|', '\n', '\r']),
+            version: VERSION.to_string(),
+            description: branding(CHAT_SERVER_DESCRIPTION_ENV_VAR, &['\n', '\r']),
+        };
+
+        let paranoid_max_violations = vars.flag(CHAT_SERVER_PARANOID_ENV_VAR, false).then(|| {
+            vars.number(
+                CHAT_SERVER_PARANOID_MAX_VIOLATIONS_ENV_VAR,
+                violations::DEFAULT_MAX_VIOLATIONS,
+            )
+        });
+
+        // Nagle's algorithm is off unless asked for, keepalive probes start after
+        // CHAT_SERVER_KEEPALIVE_INTERVAL idle seconds (off by default, the server pings
+        // on its own), buffers are in KB (0 or unset = the system's default)
+        let seconds = |name: &str| {
+            Some(vars.number::<u64>(name, 0))
+                .filter(|secs| *secs > 0)
+                .map(Duration::from_secs)
+        };
+        let kilobytes = |name: &str| {
+            vars.size(name, KB, MAX_CONFIGURED_BUFFER)
+                .map(|bytes| bytes.filter(|bytes| *bytes > 0))
+        };
+        let socket_options = SocketOptions {
+            nodelay: vars.flag(CHAT_SERVER_TCP_NODELAY_ENV_VAR, true),
+            keepalive: seconds(CHAT_SERVER_KEEPALIVE_INTERVAL_ENV_VAR).map(|idle| Keepalive {
+                idle,
+                interval: seconds(CHAT_SERVER_KEEPALIVE_PROBE_INTERVAL_ENV_VAR),
+                probes: Some(vars.number::<u32>(CHAT_SERVER_KEEPALIVE_PROBES_ENV_VAR, 0))
+                    .filter(|count| *count > 0),
+            }),
+            recv_buffer: kilobytes(CHAT_SERVER_RECV_BUFFER_KB_ENV_VAR)?,
+            send_buffer: kilobytes(CHAT_SERVER_SEND_BUFFER_KB_ENV_VAR)?,
+        };
+
+        let room_export = match vars.get(CHAT_SERVER_ROOM_EXPORT_ENV_VAR) {
+            Some(val) => ExportPolicy::parse(&val).unwrap_or_else(|| {
+                warn!(
+                    "Unknown {} '{}' - using 'members'",
+                    CHAT_SERVER_ROOM_EXPORT_ENV_VAR, val
+                );
+                ExportPolicy::Members
+            }),
+            None => ExportPolicy::Members,
+        };
+        let mut room_export_limit = Some(vars.number(
+            CHAT_SERVER_ROOM_EXPORT_LIMIT_ENV_VAR,
+            history::DEFAULT_EXPORT_LIMIT,
+        ))
+        .filter(|limit| *limit > 0)
+        .unwrap_or(history::DEFAULT_EXPORT_LIMIT);
+        if room_export_limit > history::MAX_RETAINED_MESSAGES {
+            warn!(
+                "{} is capped at {} messages",
+                CHAT_SERVER_ROOM_EXPORT_LIMIT_ENV_VAR,
+                history::MAX_RETAINED_MESSAGES
+            );
+            room_export_limit = history::MAX_RETAINED_MESSAGES;
+        }
+
+        let pow_difficulty = Some(vars.number::<u32>(CHAT_SERVER_POW_DIFFICULTY_ENV_VAR, 0))
+            .filter(|bits| *bits > 0)
+            .map(|bits| {
+                if bits > u32::from(MAX_DIFFICULTY) {
+                    warn!(
+                        "{} is capped at {} bits",
+                        CHAT_SERVER_POW_DIFFICULTY_ENV_VAR, MAX_DIFFICULTY
+                    );
+                }
+                bits.min(u32::from(MAX_DIFFICULTY)) as u8
+            });
+
+        let guest_scopes = match vars.get(CHAT_SERVER_GUEST_SCOPES_ENV_VAR) {
+            Some(val) => Scopes::parse(&val).unwrap_or_else(|| {
+                warn!(
+                    "Invalid {} '{}' (expected a list of send, read, moderate, admin, file-transfer) - guests may do everything",
+                    CHAT_SERVER_GUEST_SCOPES_ENV_VAR, val
+                );
+                Scopes::all()
+            }),
+            None => Scopes::all(),
+        };
+
+        let data_dir = PathBuf::from(
+            vars.get(CHAT_SERVER_DATA_DIR_ENV_VAR)
+                .unwrap_or("data".to_string()),
+        );
+
+        let reclaim_policy = match vars.get(CHAT_SERVER_NICK_RECLAIM_ENV_VAR) {
+            Some(val) => ReclaimPolicy::parse(&val).unwrap_or_else(|| {
+                warn!(
+                    "Unknown {} '{}' - using 'rename'",
+                    CHAT_SERVER_NICK_RECLAIM_ENV_VAR, val
+                );
+                ReclaimPolicy::Rename
+            }),
+            None => ReclaimPolicy::Rename,
+        };
+        let nick_conflict = match vars.get(CHAT_SERVER_NICK_CONFLICT_ENV_VAR) {
+            Some(val) => NickConflictPolicy::parse(&val).unwrap_or_else(|| {
+                warn!(
+                    "Unknown {} '{}' - using 'random'",
+                    CHAT_SERVER_NICK_CONFLICT_ENV_VAR, val
+                );
+                NickConflictPolicy::Random
+            }),
+            None => NickConflictPolicy::Random,
+        };
+        let kick_cooldown = vars.get(CHAT_SERVER_KICK_COOLDOWN_ENV_VAR).and_then(|val| {
+            let cooldown = schedule::parse_interval(val.trim());
+            if cooldown.is_none() {
+                warn!(
+                    "Invalid {} '{}' - kicked users can rejoin at once",
+                    CHAT_SERVER_KICK_COOLDOWN_ENV_VAR, val
+                );
+            }
+            cooldown
+        });
+
+        // A relative path is in the data directory
+        #[cfg(feature = "sqlite")]
+        let history_db = vars
+            .get(CHAT_SERVER_HISTORY_DB_ENV_VAR)
+            .map(|val| data_dir.join(val.trim()));
+
+        let syslog = vars.get(CHAT_SERVER_SYSLOG_ENV_VAR).and_then(|val| {
+            let target = syslog::SyslogTarget::parse(val.trim())
+                .inspect_err(|e| {
+                    warn!(
+                        "Invalid {} '{}' ({}) - audit events are not sent to syslog",
+                        CHAT_SERVER_SYSLOG_ENV_VAR, val, e
+                    )
+                })
+                .ok()?;
+            let facility = match vars.get(CHAT_SERVER_SYSLOG_FACILITY_ENV_VAR) {
+                Some(val) => syslog::parse_facility(&val).unwrap_or_else(|| {
+                    warn!(
+                        "Invalid {} '{}' - using auth",
+                        CHAT_SERVER_SYSLOG_FACILITY_ENV_VAR, val
+                    );
+                    syslog::DEFAULT_FACILITY
+                }),
+                None => syslog::DEFAULT_FACILITY,
+            };
+            let severities = match vars.get(CHAT_SERVER_SYSLOG_SEVERITY_ENV_VAR) {
+                Some(val) => syslog::SeverityMap::parse(&val).unwrap_or_else(|e| {
+                    warn!(
+                        "Invalid {} '{}' ({}) - using the default severities",
+                        CHAT_SERVER_SYSLOG_SEVERITY_ENV_VAR, val, e
+                    );
+                    syslog::SeverityMap::default()
+                }),
+                None => syslog::SeverityMap::default(),
+            };
+            Some(SyslogSettings {
+                target,
+                facility,
+                severities,
+                ca_path: vars
+                    .get(CHAT_SERVER_SYSLOG_CA_PATH_ENV_VAR)
+                    .map(PathBuf::from),
+            })
+        });
+
+        let client_certs = vars
+            .get(TLS_CLIENT_CA_PATH_ENV_VAR)
+            .map(|ca_path| ClientCertSettings {
+                ca_path,
+                required: vars.flag(TLS_CLIENT_CERT_REQUIRED_ENV_VAR, false),
+                map_path: vars
+                    .get(TLS_CLIENT_CERT_MAP_ENV_VAR)
+                    .map(PathBuf::from)
+                    .unwrap_or_else(|| data_dir.join(crate::auth::client_cert::CLIENT_CERTS_FILE)),
+            });
+
+        // With TLS_SELF_SIGNED a certificate is generated on first start (at the
+        // configured paths, or in the data directory)
+        let self_signed = vars.flag(TLS_SELF_SIGNED_ENV_VAR, false);
+        let tls_paths = match (
+            vars.get(TLS_CERT_PATH_ENV_VAR),
+            vars.get(TLS_KEY_PATH_ENV_VAR),
+        ) {
+            (Some(cert_path), Some(key_path)) => Some((cert_path, key_path)),
+            _ if self_signed => Some((
+                data_dir
+                    .join(self_signed::CERT_FILE)
+                    .to_string_lossy()
+                    .into_owned(),
+                data_dir
+                    .join(self_signed::KEY_FILE)
+                    .to_string_lossy()
+                    .into_owned(),
+            )),
+            (Some(_), None) | (None, Some(_)) => {
+                warn!(
+                    "{} and {} must both be set - running without TLS",
+                    TLS_CERT_PATH_ENV_VAR, TLS_KEY_PATH_ENV_VAR
+                );
+                None
+            }
+            (None, None) => None,
+        };
+
+        Ok(ServerConfig {
+            addr: vars
+                .get(CHAT_SERVER_ADDR_ENV_VAR)
+                .unwrap_or("0.0.0.0:8080".to_string()),
+            max_clients,
+            waiting_room_size: vars.number(
+                CHAT_SERVER_WAITING_ROOM_SIZE_ENV_VAR,
+                DEFAULT_WAITING_ROOM_SIZE,
+            ),
+            reserved_slots,
+            server_identity: vars
+                .get(CHAT_SERVER_IDENTITY_ENV_VAR)
+                .unwrap_or("Server".to_string()),
+            info,
+            paranoid_max_violations,
+            // 0 means unlimited, as unset does
+            bandwidth_quota: vars
+                .size(
+                    CHAT_SERVER_HOURLY_QUOTA_MB_ENV_VAR,
+                    MB,
+                    MAX_CONFIGURED_MEMORY,
+                )?
+                .filter(|bytes| *bytes > 0)
+                .map(|bytes| bytes as u64),
+            memory_cap: vars
+                .size(CHAT_SERVER_MEMORY_CAP_MB_ENV_VAR, MB, MAX_CONFIGURED_MEMORY)?
+                .filter(|bytes| *bytes > 0),
+            broadcast_shards: vars.number(CHAT_SERVER_BROADCAST_SHARDS_ENV_VAR, 0),
+            write_buffer: vars
+                .size(
+                    CHAT_SERVER_WRITE_BUFFER_KB_ENV_VAR,
+                    KB,
+                    MAX_CONFIGURED_BUFFER,
+                )?
+                .unwrap_or(WRITE_BUFFER_SIZE),
+            socket_options,
+            room_export,
+            room_export_limit,
+            translator: vars
+                .get(CHAT_SERVER_TRANSLATE_COMMAND_ENV_VAR)
+                .map(Translator::new),
+            pow_difficulty,
+            open_registration: vars.flag(CHAT_SERVER_OPEN_REGISTRATION_ENV_VAR, true),
+            guest_scopes,
+            multi_session: vars.flag(CHAT_SERVER_MULTI_SESSION_ENV_VAR, false),
+            log_content: vars.flag(CHAT_SERVER_LOG_CONTENT_ENV_VAR, false),
+            #[cfg(feature = "ldap")]
+            ldap: vars
+                .get(CHAT_SERVER_LDAP_URL_ENV_VAR)
+                .zip(vars.get(CHAT_SERVER_LDAP_USER_DN_ENV_VAR))
+                .map(|(url, user_dn)| LdapSettings { url, user_dn }),
+            #[cfg(feature = "oidc")]
+            oidc: match (
+                vars.get(CHAT_SERVER_OIDC_KEY_PATH_ENV_VAR),
+                vars.get(CHAT_SERVER_OIDC_ISSUER_ENV_VAR),
+                vars.get(CHAT_SERVER_OIDC_AUDIENCE_ENV_VAR),
+            ) {
+                (Some(key_path), Some(issuer), Some(audience)) => Some(OidcSettings {
+                    key_path,
+                    issuer,
+                    audience,
+                    username_claim: vars
+                        .get(CHAT_SERVER_OIDC_USERNAME_CLAIM_ENV_VAR)
+                        .unwrap_or(crate::auth::oidc::DEFAULT_USERNAME_CLAIM.to_string()),
+                }),
+                _ => None,
+            },
+            data_dir,
+            admin_password: (vars.0)(CHAT_SERVER_ADMIN_PASSWORD_ENV_VAR)
+                .filter(|val| !val.is_empty()),
+            reclaim_policy,
+            nick_conflict,
+            kick_cooldown,
+            trace: vars.flag(CHAT_TRACE_ENV_VAR, false),
+            #[cfg(feature = "sqlite")]
+            history_db,
+            persist_history: vars.flag(CHAT_SERVER_PERSIST_HISTORY_ENV_VAR, false),
+            syslog,
+            client_certs,
+            tls_paths,
+            self_signed,
+            onion_dir: vars.get(CHAT_SERVER_ONION_DIR_ENV_VAR).map(PathBuf::from),
+        })
+    }
+
+    /// The SQLite database room history is kept in (None = not using one)
+    pub fn history_db(&self) -> Option<&PathBuf> {
+        #[cfg(feature = "sqlite")]
+        return self.history_db.as_ref();
+        #[cfg(not(feature = "sqlite"))]
+        None
+    }
+
+    /// Whether room history is kept across restarts, in the log or the database
+    pub fn keeps_history(&self) -> bool {
+        self.persist_history || self.history_db().is_some()
+    }
+
+    /// Where room history is kept across restarts
+    pub fn history_path(&self) -> PathBuf {
+        self.history_db()
+            .cloned()
+            .unwrap_or_else(|| self.data_dir.join(history_writer::HISTORY_FILE))
+    }
+
+    pub fn trace_path(&self) -> PathBuf {
+        self.data_dir.join(trace::TRACE_FILE)
+    }
+
+    /// Log what the server was configured with, and how to change it
+    pub fn log_settings(&self) {
+        info!(
+            "To change address, set {} environment variable",
+            CHAT_SERVER_ADDR_ENV_VAR
+        );
+        info!(
+            "To change max clients, set {} environment variable",
+            CHAT_SERVER_MAX_CLIENTS_ENV_VAR
+        );
+        if self.waiting_room_size > 0 {
+            info!(
+                "Up to {} connections can wait for a slot when the chat is full. To change this, set {} (0 turns them away)",
+                self.waiting_room_size, CHAT_SERVER_WAITING_ROOM_SIZE_ENV_VAR
+            );
+        } else {
+            info!("Connections are turned away when the chat is full (no waiting room)");
+        }
+        if self.reserved_slots > 0 {
+            info!(
+                "{} of {} slots are reserved for registered users; guests can fill the other {}",
+                self.reserved_slots,
+                self.max_clients,
+                self.max_clients - self.reserved_slots
+            );
+        } else {
+            info!(
+                "To keep slots free for registered users, set {} to how many guests can't fill",
+                CHAT_SERVER_RESERVED_SLOTS_ENV_VAR
+            );
+        }
+        info!(
+            "To change the name used by /say and /announce, set {} environment variable",
+            CHAT_SERVER_IDENTITY_ENV_VAR
+        );
+        info!(
+            "To brand the server, set {}, {} and {} environment variables",
+            CHAT_SERVER_NAME_ENV_VAR, CHAT_SERVER_NETWORK_ENV_VAR, CHAT_SERVER_DESCRIPTION_ENV_VAR
+        );
+        info!(
+            "Server data is stored in '{}'. To change it, set {} environment variable",
+            self.data_dir.display(),
+            CHAT_SERVER_DATA_DIR_ENV_VAR
+        );
+        match self.bandwidth_quota {
+            Some(quota) => info!(
+                "Bandwidth quota: {} per user per hour",
+                bandwidth::format_bytes(quota)
+            ),
+            None => info!(
+                "To limit bandwidth per user, set {} environment variable",
+                CHAT_SERVER_HOURLY_QUOTA_MB_ENV_VAR
+            ),
+        }
+        match self.memory_cap {
+            Some(cap) => info!(
+                "Memory cap: {} for room history and queued messages",
+                bandwidth::format_bytes(cap as u64)
+            ),
+            None => info!(
+                "To cap memory used by room history and queued messages, set {} environment variable",
+                CHAT_SERVER_MEMORY_CAP_MB_ENV_VAR
+            ),
+        }
+        match self.broadcast_shards {
+            0 => info!(
+                "Broadcasts are read by each connection. To fan them out from shard tasks, set {}",
+                CHAT_SERVER_BROADCAST_SHARDS_ENV_VAR
+            ),
+            shards => info!(
+                "Broadcasts fanned out by {} shard task(s). To change it, set {} (0 = off)",
+                shards, CHAT_SERVER_BROADCAST_SHARDS_ENV_VAR
+            ),
+        }
+        match self.write_buffer {
+            0 => info!(
+                "Frames are written to sockets piece by piece. To write each in one go, set {}",
+                CHAT_SERVER_WRITE_BUFFER_KB_ENV_VAR
+            ),
+            size => info!(
+                "Frames are written through a {} buffer per connection. To change it, set {} (0 = off)",
+                bandwidth::format_bytes(size as u64),
+                CHAT_SERVER_WRITE_BUFFER_KB_ENV_VAR
+            ),
+        }
+        info!(
+            "Accepted sockets: {}. To tune them, set {}, {} and {}",
+            self.socket_options,
+            CHAT_SERVER_TCP_NODELAY_ENV_VAR,
+            CHAT_SERVER_KEEPALIVE_INTERVAL_ENV_VAR,
+            CHAT_SERVER_RECV_BUFFER_KB_ENV_VAR
+        );
+        match self.room_export {
+            ExportPolicy::Off => info!(
+                "Room history can't be exported. To allow /export-room, set {}=members|anyone",
+                CHAT_SERVER_ROOM_EXPORT_ENV_VAR
+            ),
+            policy => info!(
+                "Room history can be exported by {} (up to {} messages). To change it, set {}=off|members|anyone and {}",
+                policy,
+                self.room_export_limit,
+                CHAT_SERVER_ROOM_EXPORT_ENV_VAR,
+                CHAT_SERVER_ROOM_EXPORT_LIMIT_ENV_VAR
+            ),
+        }
+        match &self.translator {
+            Some(translator) => info!(
+                "Rooms can have their messages translated with /room translate, by '{}'",
+                translator.command()
+            ),
+            None => info!(
+                "To let moderators have room messages machine translated, set {} to a translation command",
+                CHAT_SERVER_TRANSLATE_COMMAND_ENV_VAR
+            ),
+        }
+        match self.paranoid_max_violations {
+            Some(max) => warn!(
+                "Paranoid mode enabled - IPs are banned after {} protocol violations",
+                max
+            ),
+            None => info!(
+                "To ban clients that send malformed messages, set {}=1 ({} sets the limit)",
+                CHAT_SERVER_PARANOID_ENV_VAR, CHAT_SERVER_PARANOID_MAX_VIOLATIONS_ENV_VAR
+            ),
+        }
+        info!(
+            "Guests using a registered nickname are {} when its owner logs in. To change it, set {}=rename|disconnect",
+            match self.reclaim_policy {
+                ReclaimPolicy::Rename => "renamed",
+                ReclaimPolicy::Disconnect => "disconnected",
+            },
+            CHAT_SERVER_NICK_RECLAIM_ENV_VAR
+        );
+        info!(
+            "Joining with a nickname already in use {}. To change it, set {}=random|suffix|reject",
+            match self.nick_conflict {
+                NickConflictPolicy::Random => "adds a random number to it",
+                NickConflictPolicy::Suffix => "adds the next free number to it (name_2)",
+                NickConflictPolicy::Reject => "is refused",
+            },
+            CHAT_SERVER_NICK_CONFLICT_ENV_VAR
+        );
+        if self.open_registration {
+            info!(
+                "Guests can /register their nickname. To turn this off, set {}=0",
+                CHAT_SERVER_OPEN_REGISTRATION_ENV_VAR
+            );
+        } else {
+            info!("Guests can't register nicknames - use /register <username> <password>");
+        }
+        if self.guest_scopes != Scopes::all() {
+            info!("Guests are limited to: {}", self.guest_scopes);
+        }
+        if self.multi_session {
+            info!(
+                "Registered users can be logged in from several clients at once - messages sent from one show on the others"
+            );
+        } else {
+            info!(
+                "Logging in to a registered nickname that is already online gets another name. To share it between sessions, set {}=1",
+                CHAT_SERVER_MULTI_SESSION_ENV_VAR
+            );
+        }
+        if self.log_content {
+            warn!(
+                "Message text is logged to the console and the audit log says so (/privacy on stops it)"
+            );
+        } else {
+            info!(
+                "Only sender, room and size of messages are logged. To log their text too, set {}=1",
+                CHAT_SERVER_LOG_CONTENT_ENV_VAR
+            );
+        }
+        match self.kick_cooldown {
+            Some(cooldown) => info!(
+                "Kicked users are kept out for {} (/kick <user> --for <interval> overrides it)",
+                schedule::format_interval(cooldown)
+            ),
+            None => info!(
+                "To keep kicked users out for a while, set {} environment variable (e.g. 10m)",
+                CHAT_SERVER_KICK_COOLDOWN_ENV_VAR
+            ),
+        }
+        match self.pow_difficulty {
+            Some(bits) => info!(
+                "New connections must solve a {}-bit proof-of-work challenge before joining",
+                bits
+            ),
+            None => info!(
+                "To make new connections solve a proof-of-work challenge, set {} environment variable (e.g. 20)",
+                CHAT_SERVER_POW_DIFFICULTY_ENV_VAR
+            ),
+        }
+        if self.keeps_history() {
+            info!(
+                "Room history is kept in {} across restarts",
+                self.history_path().display()
+            );
+        } else {
+            info!(
+                "To keep room history across restarts, set {}=1",
+                CHAT_SERVER_PERSIST_HISTORY_ENV_VAR
+            );
+        }
+        if self.trace {
+            warn!(
+                "Protocol tracing enabled - every frame is written to {} (including message contents)",
+                self.trace_path().display()
+            );
+        } else {
+            info!(
+                "To trace every frame sent and received for debugging, set {}=1",
+                CHAT_TRACE_ENV_VAR
+            );
+        }
+    }
+}
+
+/// Whether socket I/O should go through io_uring (read before the runtime starts)
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+pub fn io_uring() -> bool {
+    let enabled = Vars(|name: &str| env::var(name).ok()).flag(CHAT_SERVER_IO_URING_ENV_VAR, false);
+    if !enabled {
+        info!(
+            "To do socket I/O through io_uring, set {}=1",
+            CHAT_SERVER_IO_URING_ENV_VAR
+        );
+    }
+    enabled
+}
+
+/// Settings read through a lookup function, warning about values that can't be used
+struct Vars<F>(F);
+
+impl<F: Fn(&str) -> Option<String>> Vars<F> {
+    /// The value of `name` (None if it's unset or blank)
+    fn get(&self, name: &str) -> Option<String> {
+        (self.0)(name).filter(|val| !val.trim().is_empty())
+    }
+
+    /// A switch given as 1/0, true/false, yes/no or on/off
+    fn flag(&self, name: &str, default: bool) -> bool {
+        let Some(val) = self.get(name) else {
+            return default;
+        };
+        match val.trim().to_lowercase().as_str() {
+            "1" | "true" | "yes" | "on" => true,
+            "0" | "false" | "no" | "off" => false,
+            _ => {
+                warn!(
+                    "Invalid {} '{}' (expected 1 or 0) - using {}",
+                    name,
+                    val,
+                    u8::from(default)
+                );
+                default
+            }
+        }
+    }
+
+    fn number<T: FromStr + Display>(&self, name: &str, default: T) -> T {
+        let Some(val) = self.get(name) else {
+            return default;
+        };
+        val.trim().parse().unwrap_or_else(|_| {
+            warn!(
+                "Invalid {} '{}' (expected a whole number) - using {}",
+                name, val, default
+            );
+            default
+        })
+    }
+
+    /// A size given in `unit`-byte units, in bytes (None if it's unset or not a
+    /// number). Sizes over `max` bytes are refused rather than overflowing or being
+    /// allocated.
+    fn size(&self, name: &str, unit: u64, max: u64) -> io::Result<Option<usize>> {
+        let Some(val) = self.get(name) else {
+            return Ok(None);
+        };
+        let Ok(count) = val.trim().parse::<u64>() else {
+            warn!(
+                "Invalid {} '{}' (expected a whole number) - using the default",
+                name, val
+            );
+            return Ok(None);
+        };
+        match count
+            .checked_mul(unit)
+            .filter(|bytes| *bytes <= max)
+            .and_then(|bytes| usize::try_from(bytes).ok())
+        {
+            Some(bytes) => Ok(Some(bytes)),
+            None => {
+                error!(
+                    "{} is too large - the most it takes is {}",
+                    name,
+                    max / unit
+                );
+                Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{} is too large", name),
+                ))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn config(vars: &[(&str, &str)]) -> io::Result<ServerConfig> {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(name, val)| (name.to_string(), val.to_string()))
+            .collect();
+        ServerConfig::from_vars(|name| vars.get(name).cloned())
+    }
+
+    #[test]
+    fn test_defaults() {
+        let config = config(&[]).unwrap();
+        assert_eq!(config.addr, "0.0.0.0:8080");
+        assert_eq!(config.max_clients, 100);
+        assert_eq!(config.reserved_slots, 0);
+        assert_eq!(config.info.name, DEFAULT_SERVER_NAME);
+        assert_eq!(config.broadcast_shards, 0);
+        assert_eq!(config.write_buffer, WRITE_BUFFER_SIZE);
+        assert_eq!(config.socket_options, SocketOptions::default());
+        assert_eq!(config.data_dir, PathBuf::from("data"));
+        assert!(config.open_registration);
+        assert!(!config.keeps_history());
+        assert!(config.tls_paths.is_none());
+    }
+
+    #[test]
+    fn test_invalid_values_fall_back_to_defaults() {
+        let config = config(&[
+            (CHAT_SERVER_MAX_CLIENTS_ENV_VAR, "lots"),
+            (CHAT_SERVER_BROADCAST_SHARDS_ENV_VAR, "-4"),
+            (CHAT_SERVER_TCP_NODELAY_ENV_VAR, "maybe"),
+            (CHAT_SERVER_WRITE_BUFFER_KB_ENV_VAR, "8k"),
+            (CHAT_SERVER_POW_DIFFICULTY_ENV_VAR, "hard"),
+            (CHAT_SERVER_ADDR_ENV_VAR, "  "),
+        ])
+        .unwrap();
+        assert_eq!(config.max_clients, 100);
+        assert_eq!(config.broadcast_shards, 0);
+        assert!(config.socket_options.nodelay);
+        assert_eq!(config.write_buffer, WRITE_BUFFER_SIZE);
+        assert_eq!(config.pow_difficulty, None);
+        assert_eq!(config.addr, "0.0.0.0:8080");
+    }
+
+    #[test]
+    fn test_values_are_parsed_and_capped() {
+        let config = config(&[
+            (CHAT_SERVER_MAX_CLIENTS_ENV_VAR, " 10 "),
+            (CHAT_SERVER_RESERVED_SLOTS_ENV_VAR, "50"),
+            (CHAT_SERVER_MEMORY_CAP_MB_ENV_VAR, "2"),
+            (CHAT_SERVER_TCP_NODELAY_ENV_VAR, "off"),
+            (CHAT_SERVER_KEEPALIVE_INTERVAL_ENV_VAR, "30"),
+            (CHAT_SERVER_POW_DIFFICULTY_ENV_VAR, "1000"),
+            (CHAT_SERVER_PERSIST_HISTORY_ENV_VAR, "yes"),
+            (TLS_SELF_SIGNED_ENV_VAR, "1"),
+        ])
+        .unwrap();
+        assert_eq!(config.max_clients, 10);
+        assert_eq!(config.reserved_slots, 10);
+        assert_eq!(config.memory_cap, Some(2 * MB as usize));
+        assert!(!config.socket_options.nodelay);
+        assert_eq!(
+            config
+                .socket_options
+                .keepalive
+                .map(|keepalive| keepalive.idle),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(config.pow_difficulty, Some(MAX_DIFFICULTY));
+        assert!(config.keeps_history());
+        assert!(config.tls_paths.is_some());
+    }
+
+    #[test]
+    fn test_oversized_values_are_rejected() {
+        assert!(config(&[(CHAT_SERVER_MEMORY_CAP_MB_ENV_VAR, "99999999999")]).is_err());
+        assert!(config(&[(CHAT_SERVER_RECV_BUFFER_KB_ENV_VAR, "1000000")]).is_err());
+    }
+}
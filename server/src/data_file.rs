@@ -0,0 +1,121 @@
+//! Files in the data directory that are rewritten whole. A save goes to a temp file
+//! that is synced and then renamed over the old one, so a crash leaves the old
+//! contents or the new ones, never half of them. Saves are written off the async
+//! runtime's threads, after the store they were taken from has been unlocked.
+
+use std::fs;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug, Clone)]
+pub struct DataFile {
+    path: PathBuf,
+    /// Only readable by the server (password and token hashes)
+    secret: bool,
+    /// Number of the last snapshot taken
+    taken: Arc<AtomicU64>,
+    /// Number of the last snapshot written, so an older one finishing late is dropped
+    written: Arc<Mutex<u64>>,
+}
+
+impl DataFile {
+    pub fn new(path: PathBuf) -> Self {
+        DataFile {
+            path,
+            secret: false,
+            taken: Arc::new(AtomicU64::new(0)),
+            written: Arc::new(Mutex::new(0)),
+        }
+    }
+
+    /// A file only the server can read
+    pub fn secret(path: PathBuf) -> Self {
+        DataFile {
+            secret: true,
+            ..DataFile::new(path)
+        }
+    }
+
+    /// `contents` to be written once the store is unlocked
+    pub fn snapshot(&self, contents: String) -> Save {
+        let number = self.taken.fetch_add(1, Ordering::SeqCst) + 1;
+        Save(Some((self.clone(), number, contents)))
+    }
+
+    fn write(&self, number: u64, contents: &[u8]) -> io::Result<()> {
+        let mut written = self.written.lock().unwrap_or_else(|e| e.into_inner());
+        if *written > number {
+            return Ok(());
+        }
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let temp = self.path.with_extension("tmp");
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        if self.secret {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let mut file = options.open(&temp)?;
+        // A leftover temp file keeps whatever mode it was made with
+        #[cfg(unix)]
+        if self.secret {
+            use std::os::unix::fs::PermissionsExt;
+            file.set_permissions(fs::Permissions::from_mode(0o600))?;
+        }
+        file.write_all(contents)?;
+        file.sync_all()?;
+        drop(file);
+        fs::rename(&temp, &self.path)?;
+        *written = number;
+        Ok(())
+    }
+}
+
+/// A snapshot of a store waiting to be written (nothing, for stores kept in memory)
+#[must_use = "a snapshot is only saved once written"]
+#[derive(Debug, Default)]
+pub struct Save(Option<(DataFile, u64, String)>);
+
+impl Save {
+    /// Write the snapshot, unless a newer one of the same file got there first
+    pub async fn write(self) -> io::Result<()> {
+        let Some((file, number, contents)) = self.0 else {
+            return Ok(());
+        };
+        tokio::task::spawn_blocking(move || file.write(number, contents.as_bytes()))
+            .await
+            .map_err(io::Error::other)?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_newest_snapshot_wins() {
+        let dir = std::env::temp_dir().join(format!("rust_chat_data_file_{}", std::process::id()));
+        let path = dir.join("store.tsv");
+        let file = DataFile::secret(path.clone());
+
+        let older = file.snapshot("old\n".to_string());
+        let newer = file.snapshot("new\n".to_string());
+        newer.write().await.unwrap();
+        older.write().await.unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new\n");
+        assert!(!path.with_extension("tmp").exists());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -0,0 +1,125 @@
+//! Connection draining: the server stops accepting new connections and shuts
+//! down once a countdown expires or the last client has left.
+
+use std::time::{Duration, Instant};
+
+/// Remaining-time marks at which connected clients are reminded of the pending shutdown
+const REMINDER_MARKS: &[Duration] = &[
+    Duration::from_secs(30 * 60),
+    Duration::from_secs(15 * 60),
+    Duration::from_secs(10 * 60),
+    Duration::from_secs(5 * 60),
+    Duration::from_secs(2 * 60),
+    Duration::from_secs(60),
+    Duration::from_secs(30),
+    Duration::from_secs(10),
+];
+
+pub struct Drain {
+    /// None = no countdown, wait for the last client to leave
+    deadline: Option<Instant>,
+    /// Index of the next reminder mark that hasn't been announced yet
+    next_mark: usize,
+}
+
+impl Drain {
+    pub fn new(duration: Option<Duration>) -> Self {
+        // Skip marks that are longer than the whole countdown
+        let next_mark = duration
+            .map(|d| REMINDER_MARKS.iter().take_while(|mark| **mark >= d).count())
+            .unwrap_or(REMINDER_MARKS.len());
+        Self {
+            deadline: duration.map(|d| Instant::now() + d),
+            next_mark,
+        }
+    }
+
+    pub fn remaining(&self) -> Option<Duration> {
+        self.deadline
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+    }
+
+    pub fn expired(&self) -> bool {
+        self.remaining().is_some_and(|r| r.is_zero())
+    }
+
+    /// Returns the reminder mark that was crossed since the last call, if any
+    pub fn due_reminder(&mut self) -> Option<Duration> {
+        let remaining = self.remaining()?;
+        self.reminder_for(remaining)
+    }
+
+    fn reminder_for(&mut self, remaining: Duration) -> Option<Duration> {
+        let mut due = None;
+        while let Some(mark) = REMINDER_MARKS.get(self.next_mark) {
+            if remaining > *mark {
+                break;
+            }
+            due = Some(*mark);
+            self.next_mark += 1;
+        }
+        due
+    }
+}
+
+/// Format a countdown for announcements, e.g. "5 minutes" or "30 seconds"
+pub fn format_remaining(remaining: Duration) -> String {
+    let secs = remaining.as_secs();
+    if secs >= 60 && secs.is_multiple_of(60) {
+        let minutes = secs / 60;
+        format!("{} minute{}", minutes, if minutes == 1 { "" } else { "s" })
+    } else if secs >= 60 {
+        format!("{}m {}s", secs / 60, secs % 60)
+    } else {
+        format!("{} second{}", secs, if secs == 1 { "" } else { "s" })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reminders_skip_marks_longer_than_countdown() {
+        let mut drain = Drain::new(Some(Duration::from_secs(5 * 60)));
+        // The 5 minute mark itself is covered by the initial announcement
+        assert_eq!(drain.reminder_for(Duration::from_secs(299)), None);
+        assert_eq!(
+            drain.reminder_for(Duration::from_secs(120)),
+            Some(Duration::from_secs(120))
+        );
+        // Each mark is only announced once
+        assert_eq!(drain.reminder_for(Duration::from_secs(119)), None);
+    }
+
+    #[test]
+    fn test_reminders_collapse_missed_marks() {
+        let mut drain = Drain::new(Some(Duration::from_secs(10 * 60)));
+        // If several marks were crossed at once only the latest is announced
+        assert_eq!(
+            drain.reminder_for(Duration::from_secs(25)),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            drain.reminder_for(Duration::from_secs(10)),
+            Some(Duration::from_secs(10))
+        );
+    }
+
+    #[test]
+    fn test_no_deadline() {
+        let mut drain = Drain::new(None);
+        assert_eq!(drain.remaining(), None);
+        assert!(!drain.expired());
+        assert_eq!(drain.due_reminder(), None);
+    }
+
+    #[test]
+    fn test_format_remaining() {
+        assert_eq!(format_remaining(Duration::from_secs(60)), "1 minute");
+        assert_eq!(format_remaining(Duration::from_secs(300)), "5 minutes");
+        assert_eq!(format_remaining(Duration::from_secs(90)), "1m 30s");
+        assert_eq!(format_remaining(Duration::from_secs(30)), "30 seconds");
+        assert_eq!(format_remaining(Duration::from_secs(1)), "1 second");
+    }
+}
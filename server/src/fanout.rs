@@ -0,0 +1,417 @@
+//! Sharded broadcast fan-out (CHAT_SERVER_BROADCAST_SHARDS). Instead of every
+//! connection subscribing to the broadcast channel, a few shard tasks do, and each
+//! passes messages on to the queues of the connections it owns. A broadcast then
+//! wakes a handful of shards rather than contending for the channel with thousands
+//! of connection tasks, and a slow connection only fills its own queue. Each queued
+//! broadcast carries its number on the channel, so a connection that skips ahead
+//! doesn't get what a shard was still holding from before.
+
+use crate::channel::{Broadcast, BroadcastChannel, ChannelReceiver};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+use tracing::info_span;
+
+/// A connection owned by a shard
+struct Member {
+    queue: mpsc::Sender<(u64, Broadcast)>,
+    /// Broadcasts dropped because the queue was full, reported as a lag
+    skipped: Arc<AtomicU64>,
+    /// Broadcasts numbered below this were skipped (see Subscription::skip_queued)
+    skip_before: Arc<AtomicU64>,
+}
+
+#[derive(Default)]
+struct Shard {
+    members: Mutex<Vec<Member>>,
+}
+
+impl Shard {
+    fn len(&self) -> usize {
+        self.members.lock().unwrap().len()
+    }
+
+    /// Queue broadcast number `seq` for every member, forgetting connections that
+    /// have closed
+    fn deliver(&self, seq: u64, message: &Broadcast, queued: &AtomicUsize) {
+        self.members.lock().unwrap().retain(|member| {
+            if seq < member.skip_before.load(Ordering::Relaxed) {
+                return !member.queue.is_closed();
+            }
+            match member.queue.try_send((seq, message.clone())) {
+                Ok(()) => {
+                    queued.fetch_add(1, Ordering::Relaxed);
+                    true
+                }
+                Err(TrySendError::Full(_)) => {
+                    member.skipped.fetch_add(1, Ordering::Relaxed);
+                    true
+                }
+                Err(TrySendError::Closed(_)) => false,
+            }
+        });
+    }
+
+    /// The shard itself fell behind the channel, so every member missed the
+    /// `skipped` broadcasts before number `next` (except those it had skipped)
+    fn lagged(&self, skipped: u64, next: u64) {
+        for member in self.members.lock().unwrap().iter() {
+            let skip_before = member.skip_before.load(Ordering::Relaxed);
+            let missed = next.saturating_sub(skip_before.max(next - skipped));
+            member.skipped.fetch_add(missed, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Shard tasks and the connections they own (cheap to clone)
+#[derive(Clone)]
+pub struct Fanout {
+    shards: Arc<Vec<Shard>>,
+    /// Messages each new connection's queue holds before it starts lagging
+    capacity: Arc<AtomicUsize>,
+    /// Broadcasts sitting in connection queues, for memory accounting
+    queued: Arc<AtomicUsize>,
+    channel: BroadcastChannel,
+}
+
+impl Fanout {
+    /// Start `shards` tasks fanning out what's sent on `channel`
+    pub fn start(channel: &BroadcastChannel, shards: usize, capacity: usize) -> Self {
+        let fanout = Fanout {
+            shards: Arc::new((0..shards.max(1)).map(|_| Shard::default()).collect()),
+            capacity: Arc::new(AtomicUsize::new(capacity.max(1))),
+            queued: Arc::new(AtomicUsize::new(0)),
+            channel: channel.clone(),
+        };
+        for index in 0..fanout.shards.len() {
+            let mut rx = channel.subscribe();
+            let shards = Arc::clone(&fanout.shards);
+            let queued = Arc::clone(&fanout.queued);
+            tokio::spawn(async move {
+                let shard = &shards[index];
+                loop {
+                    match rx.recv().await {
+                        Ok(message) => {
+                            let _span =
+                                info_span!("fanout", shard = index, from = %message.1).entered();
+                            shard.deliver(rx.position() - 1, &message, &queued);
+                        }
+                        Err(RecvError::Lagged(skipped)) => shard.lagged(skipped, rx.position()),
+                        Err(RecvError::Closed) => break,
+                    }
+                }
+            });
+        }
+        fanout
+    }
+
+    /// Broadcasts queued for connections but not yet read by them
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    /// Queue size for connections that join from now on (/channel resize)
+    pub fn set_capacity(&self, capacity: usize) {
+        self.capacity.store(capacity.max(1), Ordering::Relaxed);
+    }
+
+    /// Add a connection to the shard with the fewest
+    pub fn subscribe(&self) -> Subscription {
+        let (queue, rx) = mpsc::channel(self.capacity.load(Ordering::Relaxed));
+        let skipped = Arc::new(AtomicU64::new(0));
+        // A shard still behind the channel mustn't pass on what was sent before the
+        // connection subscribed
+        let position = self.channel.sent();
+        let skip_before = Arc::new(AtomicU64::new(position));
+        let shard = self
+            .shards
+            .iter()
+            .min_by_key(|shard| shard.len())
+            .expect("at least one shard");
+        shard.members.lock().unwrap().push(Member {
+            queue,
+            skipped: Arc::clone(&skipped),
+            skip_before: Arc::clone(&skip_before),
+        });
+        Subscription::Sharded {
+            rx,
+            skipped,
+            skip_before,
+            position,
+            queued: Arc::clone(&self.queued),
+            channel: self.channel.clone(),
+        }
+    }
+}
+
+/// A connection's feed of broadcasts, from a shard or straight off the channel
+/// (when sharding is off). Reports lag the way a broadcast receiver does.
+pub enum Subscription {
+    Direct(ChannelReceiver),
+    Sharded {
+        rx: mpsc::Receiver<(u64, Broadcast)>,
+        skipped: Arc<AtomicU64>,
+        skip_before: Arc<AtomicU64>,
+        /// Number of the broadcast after the last one received
+        position: u64,
+        queued: Arc<AtomicUsize>,
+        channel: BroadcastChannel,
+    },
+}
+
+impl Subscription {
+    pub async fn recv(&mut self) -> Result<Broadcast, RecvError> {
+        match self {
+            Subscription::Direct(rx) => rx.recv().await,
+            Subscription::Sharded {
+                rx,
+                skipped,
+                skip_before,
+                position,
+                queued,
+                ..
+            } => loop {
+                let lag = skipped.swap(0, Ordering::Relaxed);
+                if lag > 0 {
+                    return Err(RecvError::Lagged(lag));
+                }
+                let (seq, message) = rx.recv().await.ok_or(RecvError::Closed)?;
+                queued.fetch_sub(1, Ordering::Relaxed);
+                // Queued by its shard just as the connection skipped ahead
+                if seq >= skip_before.load(Ordering::Relaxed) {
+                    *position = seq + 1;
+                    return Ok(message);
+                }
+            },
+        }
+    }
+
+    /// Number of the broadcast after the last one received. A shard drops what a full
+    /// queue can't take, so unlike a channel receiver's this doesn't move on a lag;
+    /// read it after receiving to know where a later lag starts.
+    pub fn position(&self) -> u64 {
+        match self {
+            Subscription::Direct(rx) => rx.position(),
+            Subscription::Sharded { position, .. } => *position,
+        }
+    }
+
+    /// Skip everything sent so far, queued or not; only broadcasts sent from now on
+    /// are received
+    pub fn skip_queued(&mut self) {
+        match self {
+            Subscription::Direct(rx) => rx.skip_queued(),
+            Subscription::Sharded {
+                rx,
+                skipped,
+                skip_before,
+                position,
+                queued,
+                channel,
+            } => {
+                *position = channel.sent();
+                skip_before.store(*position, Ordering::Relaxed);
+                while rx.try_recv().is_ok() {
+                    queued.fetch_sub(1, Ordering::Relaxed);
+                }
+                skipped.store(0, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        // What the connection never read no longer counts towards memory use
+        self.skip_queued();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::ConnectionId;
+    use shared::message::{ChatMessage, MessageTypes};
+    use std::time::Duration;
+
+    fn message(text: &str) -> Broadcast {
+        let message =
+            ChatMessage::try_new(MessageTypes::ChatMessage, Some(text.as_bytes().to_vec()))
+                .unwrap();
+        (message, ConnectionId::next())
+    }
+
+    async fn next(subscription: &mut Subscription) -> Result<Broadcast, RecvError> {
+        tokio::time::timeout(Duration::from_secs(1), subscription.recv())
+            .await
+            .expect("broadcast should arrive")
+    }
+
+    #[tokio::test]
+    async fn test_fanout_spreads_connections_and_reports_lag() {
+        let tx = BroadcastChannel::new(16);
+        let fanout = Fanout::start(&tx, 2, 2);
+        let mut first = fanout.subscribe();
+        let mut second = fanout.subscribe();
+        assert!(fanout.shards.iter().all(|shard| shard.len() == 1));
+
+        for text in ["one", "two", "three"] {
+            tx.send(message(text)).unwrap();
+        }
+        // Let the shards queue what fits (two each) before anyone reads
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(fanout.queued(), 4);
+
+        for subscription in [&mut first, &mut second] {
+            assert!(matches!(
+                next(subscription).await,
+                Err(RecvError::Lagged(1))
+            ));
+            subscription.skip_queued();
+        }
+        assert_eq!(fanout.queued(), 0);
+
+        tx.send(message("four")).unwrap();
+        let (received, _) = next(&mut first).await.unwrap();
+        assert_eq!(received.content_as_string().as_deref(), Some("four"));
+
+        // A closed connection is dropped from its shard on the next broadcast
+        drop(second);
+        tx.send(message("five")).unwrap();
+        next(&mut first).await.unwrap();
+        assert_eq!(fanout.shards.iter().map(Shard::len).sum::<usize>(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_skipping_ahead_drops_what_a_shard_still_held() {
+        let tx = BroadcastChannel::new(16);
+        let fanout = Fanout::start(&tx, 1, 4);
+        let mut subscription = fanout.subscribe();
+
+        tx.send(message("one")).unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        subscription.skip_queued();
+        // The shard gets to broadcast 0 only now, after the connection skipped it
+        fanout.shards[0].deliver(0, &message("one"), &fanout.queued);
+        assert_eq!(fanout.queued(), 0);
+
+        tx.send(message("two")).unwrap();
+        let (received, _) = next(&mut subscription).await.unwrap();
+        assert_eq!(received.content_as_string().as_deref(), Some("two"));
+    }
+
+    #[tokio::test]
+    async fn test_subscriber_gets_nothing_sent_before_it_subscribed() {
+        let tx = BroadcastChannel::new(16);
+        let fanout = Fanout::start(&tx, 1, 4);
+        tx.send(message("before")).unwrap();
+        let mut subscription = fanout.subscribe();
+        // The shard gets to broadcast 0 only now, after the connection subscribed
+        fanout.shards[0].deliver(0, &message("before"), &fanout.queued);
+        assert_eq!(fanout.queued(), 0);
+
+        tx.send(message("after")).unwrap();
+        let (received, _) = next(&mut subscription).await.unwrap();
+        assert_eq!(received.content_as_string().as_deref(), Some("after"));
+    }
+
+    /// Delivery latency of `messages` broadcasts to `connections` subscribers, each
+    /// reading on its own task: every delivery's (p50, p99, max) and the same for the
+    /// time until the last subscriber had each broadcast
+    fn measure_fanout(
+        shards: usize,
+        connections: usize,
+        messages: usize,
+    ) -> ([Duration; 3], [Duration; 3]) {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async move {
+            let tx = BroadcastChannel::new(messages * 2);
+            let fanout = (shards > 0).then(|| Fanout::start(&tx, shards, messages * 2));
+            let start = std::time::Instant::now();
+            let received: Arc<Vec<AtomicUsize>> =
+                Arc::new((0..messages).map(|_| AtomicUsize::new(0)).collect());
+            let completed: Arc<Vec<AtomicU64>> =
+                Arc::new((0..messages).map(|_| AtomicU64::new(0)).collect());
+            let mut readers = Vec::new();
+            for _ in 0..connections {
+                let mut subscription = match &fanout {
+                    Some(fanout) => fanout.subscribe(),
+                    None => Subscription::Direct(tx.subscribe()),
+                };
+                let received = Arc::clone(&received);
+                let completed = Arc::clone(&completed);
+                readers.push(tokio::spawn(async move {
+                    let mut latencies = Vec::with_capacity(messages);
+                    for _ in 0..messages {
+                        let (message, _) = subscription.recv().await.unwrap();
+                        let now = start.elapsed().as_nanos() as u64;
+                        let text = message.content_as_string().unwrap();
+                        let (index, sent) = text.split_once(' ').unwrap();
+                        let index: usize = index.parse().unwrap();
+                        latencies.push(now - sent.parse::<u64>().unwrap());
+                        if received[index].fetch_add(1, Ordering::Relaxed) + 1 == connections {
+                            completed[index].store(now, Ordering::Relaxed);
+                        }
+                    }
+                    latencies
+                }));
+            }
+            let mut sent_at = Vec::with_capacity(messages);
+            for index in 0..messages {
+                let sent = start.elapsed().as_nanos() as u64;
+                sent_at.push(sent);
+                tx.send(message(&format!("{} {}", index, sent))).unwrap();
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+            let mut deliveries = Vec::new();
+            for reader in readers {
+                deliveries.extend(reader.await.unwrap());
+            }
+            let fanouts: Vec<u64> = completed
+                .iter()
+                .zip(&sent_at)
+                .map(|(done, sent)| done.load(Ordering::Relaxed) - sent)
+                .collect();
+            let percentiles = |mut nanos: Vec<u64>| {
+                nanos.sort_unstable();
+                [50, 99, 100].map(|percent| {
+                    let at = (nanos.len() * percent / 100).min(nanos.len() - 1);
+                    Duration::from_nanos(nanos[at])
+                })
+            };
+            (percentiles(deliveries), percentiles(fanouts))
+        })
+    }
+
+    /// Not a check but the benchmark behind CHAT_SERVER_BROADCAST_SHARDS' default:
+    ///
+    ///     cargo test --release -p server fanout_benchmark -- --ignored --nocapture
+    ///
+    /// Runs on as many worker threads as the machine has cores (TOKIO_WORKER_THREADS
+    /// overrides it); the README has the results.
+    #[test]
+    #[ignore]
+    fn fanout_benchmark() {
+        let connections = 2000;
+        let messages = 200;
+        println!(
+            "{} connections, {} broadcasts, {} core(s)",
+            connections,
+            messages,
+            std::thread::available_parallelism().map_or(1, |cores| cores.get())
+        );
+        println!("shards | delivery p50/p99/max | fan-out p50/p99/max");
+        for shards in [0, 1, 4, 16] {
+            let (delivery, fanout) = measure_fanout(shards, connections, messages);
+            println!(
+                "{:>6} | {:?} / {:?} / {:?} | {:?} / {:?} / {:?}",
+                shards, delivery[0], delivery[1], delivery[2], fanout[0], fanout[1], fanout[2]
+            );
+        }
+    }
+}
@@ -0,0 +1,587 @@
+//! Recent room messages, replayed to users when they join a room, and recent main
+//! chat messages, replayed to users when they join the chat (kept under MAIN_CHAT).
+//! Each room has a retention policy deciding how much of its history is kept;
+//! history outlives the room itself so it is still there when people come back.
+
+use crate::fanout::Subscription;
+use crate::state::ConnectionId;
+use chrono::{DateTime, Duration as ChronoDuration, Local};
+use shared::digest;
+use shared::parts::Reassembler;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use tokio::sync::RwLock;
+
+/// Upper bound on stored messages per room, whatever the policy
+pub const MAX_RETAINED_MESSAGES: usize = 1000;
+pub const MAX_RETENTION_DAYS: u32 = 365;
+/// Policy for rooms whose moderators haven't chosen one
+pub const DEFAULT_RETENTION: Retention = Retention::Messages(100);
+/// Messages replayed to a user joining a room or the chat
+pub const REPLAY_MESSAGES: usize = 20;
+/// What the main chat's history is kept under: no room has an empty name
+pub const MAIN_CHAT: &str = "";
+/// Messages sent for /export-room when the server doesn't set a limit
+pub const DEFAULT_EXPORT_LIMIT: usize = 500;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Retention {
+    /// Keep the most recent N messages
+    Messages(usize),
+    /// Keep messages younger than N days
+    Days(u32),
+    /// Keep nothing
+    Nothing,
+}
+
+impl Retention {
+    /// Parse "<count>", "<days>d" or "off"
+    pub fn parse(value: &str) -> Option<Self> {
+        let value = value.trim().to_lowercase();
+        if matches!(value.as_str(), "off" | "none" | "0" | "0d") {
+            return Some(Retention::Nothing);
+        }
+        if let Some(days) = value.strip_suffix('d') {
+            return days
+                .parse()
+                .ok()
+                .filter(|days| (1..=MAX_RETENTION_DAYS).contains(days))
+                .map(Retention::Days);
+        }
+        value
+            .parse()
+            .ok()
+            .filter(|count| (1..=MAX_RETAINED_MESSAGES).contains(count))
+            .map(Retention::Messages)
+    }
+
+    /// The policy as `parse` reads it
+    pub fn spec(&self) -> String {
+        match self {
+            Retention::Messages(count) => count.to_string(),
+            Retention::Days(days) => format!("{}d", days),
+            Retention::Nothing => "off".to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Retention {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Retention::Messages(1) => write!(f, "last message"),
+            Retention::Messages(count) => write!(f, "last {} messages", count),
+            Retention::Days(1) => write!(f, "1 day"),
+            Retention::Days(days) => write!(f, "{} days", days),
+            Retention::Nothing => write!(f, "off"),
+        }
+    }
+}
+
+/// Who may download a room's history with /export-room
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExportPolicy {
+    Off,
+    /// Only current members of the room
+    Members,
+    /// Anyone in the chat, member or not
+    Anyone,
+}
+
+impl ExportPolicy {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "off" | "0" | "no" | "false" => Some(ExportPolicy::Off),
+            "members" => Some(ExportPolicy::Members),
+            "anyone" | "all" => Some(ExportPolicy::Anyone),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ExportPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExportPolicy::Off => write!(f, "nobody"),
+            ExportPolicy::Members => write!(f, "room members"),
+            ExportPolicy::Anyone => write!(f, "anyone"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct HistoryEntry {
+    pub at: DateTime<Local>,
+    pub sender: String,
+    pub message: String,
+    /// Connection it was sent from, so a lagging connection isn't sent its own messages
+    pub origin: ConnectionId,
+    /// Number of the broadcast that relayed it, so a lagging connection can tell which
+    /// it missed (None for messages from before the server started)
+    pub seq: Option<u64>,
+}
+
+impl HistoryEntry {
+    /// Approximate memory used by the entry, for the server's memory cap
+    pub fn size(&self) -> usize {
+        std::mem::size_of::<Self>() + self.sender.len() + self.message.len()
+    }
+}
+
+#[derive(Debug)]
+struct RoomLog {
+    retention: Retention,
+    entries: VecDeque<HistoryEntry>,
+    /// Sum of the entries' sizes
+    bytes: usize,
+}
+
+impl Default for RoomLog {
+    fn default() -> Self {
+        RoomLog {
+            retention: DEFAULT_RETENTION,
+            entries: VecDeque::new(),
+            bytes: 0,
+        }
+    }
+}
+
+impl RoomLog {
+    fn push_back(&mut self, entry: HistoryEntry) {
+        self.bytes += entry.size();
+        self.entries.push_back(entry);
+    }
+
+    fn pop_front(&mut self) -> Option<HistoryEntry> {
+        let entry = self.entries.pop_front()?;
+        self.bytes -= entry.size();
+        Some(entry)
+    }
+
+    /// Drop whatever the retention policy no longer allows
+    fn enforce(&mut self, now: DateTime<Local>) -> usize {
+        let before = self.entries.len();
+        let keep = match self.retention {
+            Retention::Messages(count) => count.min(MAX_RETAINED_MESSAGES),
+            Retention::Days(_) => MAX_RETAINED_MESSAGES,
+            Retention::Nothing => 0,
+        };
+        while self.entries.len() > keep {
+            self.pop_front();
+        }
+        if let Retention::Days(days) = self.retention {
+            let cutoff = now - ChronoDuration::days(i64::from(days));
+            while self.entries.front().is_some_and(|e| e.at < cutoff) {
+                self.pop_front();
+            }
+        }
+        before - self.entries.len()
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct RoomHistory {
+    rooms: HashMap<String, RoomLog>,
+    /// Long messages still arriving in parts
+    parts: Reassembler,
+}
+
+impl RoomHistory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Store a message relayed as broadcast number `seq`, subject to the room's
+    /// retention policy. The parts of a long message are held back until the last one
+    /// arrives and stored as one message, numbered with the last part.
+    pub fn record(
+        &mut self,
+        room: &str,
+        sender: &str,
+        message: &str,
+        origin: ConnectionId,
+        seq: u64,
+    ) -> Option<String> {
+        let message = self.parts.push(room, sender, message)?;
+        self.store(
+            room,
+            HistoryEntry {
+                at: Local::now(),
+                sender: sender.to_string(),
+                message: message.clone(),
+                origin,
+                seq: Some(seq),
+            },
+        );
+        Some(message)
+    }
+
+    /// Store a whole message said at `at` (one replayed from the history log)
+    pub fn restore(&mut self, room: &str, sender: &str, message: &str, at: DateTime<Local>) {
+        self.store(
+            room,
+            HistoryEntry {
+                at,
+                sender: sender.to_string(),
+                message: message.to_string(),
+                origin: crate::state::SERVER_ORIGIN,
+                seq: None,
+            },
+        );
+    }
+
+    fn store(&mut self, room: &str, entry: HistoryEntry) {
+        let log = self.rooms.entry(room.to_string()).or_default();
+        if log.retention == Retention::Nothing {
+            return;
+        }
+        let at = entry.at;
+        log.push_back(entry);
+        log.enforce(at);
+    }
+
+    /// The most recent `limit` messages of a room, oldest first
+    pub fn recent(&self, room: &str, limit: usize) -> Vec<HistoryEntry> {
+        self.rooms
+            .get(room)
+            .map(|log| {
+                let skip = log.entries.len().saturating_sub(limit);
+                log.entries.iter().skip(skip).cloned().collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Messages in any of `rooms` relayed as broadcast number `from` or later, in the
+    /// order they were relayed. Used to catch up a connection that fell behind the
+    /// broadcast channel.
+    pub fn since(&self, rooms: &[String], from: u64) -> Vec<(String, HistoryEntry)> {
+        let mut entries: Vec<(String, HistoryEntry)> = rooms
+            .iter()
+            .filter_map(|room| self.rooms.get(room).map(|log| (room, log)))
+            .flat_map(|(room, log)| {
+                log.entries
+                    .iter()
+                    .filter(move |entry| entry.seq.is_some_and(|seq| seq >= from))
+                    .map(move |entry| (room.clone(), entry.clone()))
+            })
+            .collect();
+        entries.sort_by_key(|(_, entry)| entry.seq);
+        entries
+    }
+
+    /// How many messages others sent in each room after `after` (busiest room first),
+    /// and how many of them mention `user`. For the welcome-back digest.
+    pub fn activity_since(
+        &self,
+        user: &str,
+        after: DateTime<Local>,
+    ) -> (usize, Vec<(String, usize)>) {
+        let mut mentions = 0;
+        let mut rooms = Vec::new();
+        for (room, log) in &self.rooms {
+            // The main chat is replayed on joining, so it isn't listed
+            if room == MAIN_CHAT {
+                continue;
+            }
+            let new: Vec<&HistoryEntry> = log
+                .entries
+                .iter()
+                .filter(|entry| entry.at > after && entry.sender != user)
+                .collect();
+            if new.is_empty() {
+                continue;
+            }
+            mentions += new
+                .iter()
+                .filter(|entry| digest::mentions(&entry.message, user))
+                .count();
+            rooms.push((room.clone(), new.len()));
+        }
+        rooms.sort_by(|(a, a_count), (b, b_count)| b_count.cmp(a_count).then(a.cmp(b)));
+        (mentions, rooms)
+    }
+
+    /// Every room with a policy or stored messages: its name, policy and messages,
+    /// oldest first
+    pub fn rooms(&self) -> impl Iterator<Item = (&str, Retention, &VecDeque<HistoryEntry>)> {
+        self.rooms
+            .iter()
+            .map(|(room, log)| (room.as_str(), log.retention, &log.entries))
+    }
+
+    pub fn retention(&self, room: &str) -> Retention {
+        self.rooms
+            .get(room)
+            .map_or(DEFAULT_RETENTION, |log| log.retention)
+    }
+
+    /// Change a room's policy, dropping anything it no longer allows
+    pub fn set_retention(&mut self, room: &str, retention: Retention) {
+        let log = self.rooms.entry(room.to_string()).or_default();
+        log.retention = retention;
+        log.enforce(Local::now());
+    }
+
+    /// Delete everything stored for a room, keeping its retention policy.
+    /// Returns the number of messages removed.
+    pub fn clear(&mut self, room: &str) -> usize {
+        self.rooms.get_mut(room).map_or(0, |log| {
+            let removed = log.entries.len();
+            log.entries.clear();
+            log.bytes = 0;
+            removed
+        })
+    }
+
+    /// Expire old messages in every room (run periodically by the maintenance task).
+    /// Returns the number of messages removed.
+    pub fn prune(&mut self, now: DateTime<Local>) -> usize {
+        self.rooms.values_mut().map(|log| log.enforce(now)).sum()
+    }
+
+    /// Approximate memory used by stored messages
+    pub fn bytes(&self) -> usize {
+        self.rooms.values().map(|log| log.bytes).sum()
+    }
+
+    /// Number of stored messages across all rooms
+    pub fn message_count(&self) -> usize {
+        self.rooms.values().map(|log| log.entries.len()).sum()
+    }
+
+    /// Free at least `bytes` by dropping the oldest messages of any room, whatever
+    /// the rooms' policies (used under memory pressure). Returns the number removed.
+    pub fn evict_oldest(&mut self, bytes: usize) -> usize {
+        let mut freed = 0;
+        let mut removed = 0;
+        while freed < bytes {
+            let oldest = self
+                .rooms
+                .values_mut()
+                .filter(|log| !log.entries.is_empty())
+                .min_by_key(|log| log.entries.front().map(|entry| entry.at));
+            let Some(entry) = oldest.and_then(|log| log.pop_front()) else {
+                break;
+            };
+            freed += entry.size();
+            removed += 1;
+        }
+        removed
+    }
+}
+
+/// Catch up a receiver that lagged: skip it past everything sent so far and return
+/// the messages in `rooms` from broadcast number `from` on, in the order they were
+/// relayed. Room messages are broadcast while the history is locked, so holding it
+/// while skipping ahead means each is either returned or still ahead of the
+/// receiver - never both, never neither.
+pub async fn catch_up(
+    history: &RwLock<RoomHistory>,
+    rx: &mut Subscription,
+    rooms: &[String],
+    from: u64,
+) -> Vec<(String, HistoryEntry)> {
+    let history = history.read().await;
+    rx.skip_queued();
+    history.since(rooms, from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channel::BroadcastChannel;
+    use crate::state::SERVER_ORIGIN;
+    use shared::message::{ChatMessage, MessageTypes};
+    use tokio::sync::broadcast::error::RecvError;
+
+    #[test]
+    fn test_parse_retention() {
+        assert_eq!(Retention::parse("50"), Some(Retention::Messages(50)));
+        assert_eq!(Retention::parse("7d"), Some(Retention::Days(7)));
+        assert_eq!(Retention::parse("off"), Some(Retention::Nothing));
+        assert_eq!(Retention::parse("0"), Some(Retention::Nothing));
+        assert_eq!(Retention::parse("5000"), None);
+        assert_eq!(Retention::parse("400d"), None);
+        assert_eq!(Retention::parse("soon"), None);
+        for retention in [
+            Retention::Messages(50),
+            Retention::Days(7),
+            Retention::Nothing,
+        ] {
+            assert_eq!(Retention::parse(&retention.spec()), Some(retention));
+        }
+    }
+
+    #[test]
+    fn test_parse_export_policy() {
+        assert_eq!(ExportPolicy::parse("Members"), Some(ExportPolicy::Members));
+        assert_eq!(ExportPolicy::parse("anyone"), Some(ExportPolicy::Anyone));
+        assert_eq!(ExportPolicy::parse("off"), Some(ExportPolicy::Off));
+        assert_eq!(ExportPolicy::parse("friends"), None);
+    }
+
+    #[test]
+    fn test_message_count_retention() {
+        let mut history = RoomHistory::new();
+        history.set_retention("ops", Retention::Messages(2));
+        for (seq, msg) in ["one", "two", "three"].into_iter().enumerate() {
+            history.record("ops", "alice", msg, SERVER_ORIGIN, seq as u64);
+        }
+        let recent: Vec<String> = history
+            .recent("ops", 10)
+            .into_iter()
+            .map(|e| e.message)
+            .collect();
+        assert_eq!(recent, vec!["two", "three"]);
+        assert_eq!(history.recent("ops", 1)[0].message, "three");
+        assert!(history.recent("dev", 10).is_empty());
+    }
+
+    #[test]
+    fn test_parts_stored_as_one_message() {
+        let mut history = RoomHistory::new();
+        let text = "a message too long to fit in a single part";
+        for part in shared::parts::split(text, 20, "a1").unwrap() {
+            history.record("ops", "alice", &part, SERVER_ORIGIN, 0);
+        }
+        let recent = history.recent("ops", 10);
+        assert_eq!(recent.len(), 1);
+        assert_eq!(recent[0].message, text);
+    }
+
+    #[test]
+    fn test_day_retention_is_pruned() {
+        let mut history = RoomHistory::new();
+        history.set_retention("ops", Retention::Days(1));
+        let now = Local::now();
+        history.restore("ops", "alice", "old", now - ChronoDuration::hours(20));
+        history.restore("ops", "alice", "new", now - ChronoDuration::hours(2));
+        assert_eq!(history.prune(now), 0);
+        // Six hours later the first message is over a day old
+        assert_eq!(history.prune(now + ChronoDuration::hours(6)), 1);
+        assert_eq!(history.recent("ops", 10)[0].message, "new");
+    }
+
+    #[test]
+    fn test_since_merges_rooms_in_order() {
+        let mut history = RoomHistory::new();
+        history.restore("ops", "alice", "from last run", Local::now());
+        history.record("ops", "alice", "before", SERVER_ORIGIN, 3);
+        history.record("dev", "bob", "first", SERVER_ORIGIN, 5);
+        history.record("ops", "alice", "second", SERVER_ORIGIN, 7);
+        history.record("lobby", "carol", "not a member", SERVER_ORIGIN, 8);
+        let missed: Vec<(String, String)> = history
+            .since(&["ops".to_string(), "dev".to_string()], 5)
+            .into_iter()
+            .map(|(room, entry)| (room, entry.message))
+            .collect();
+        assert_eq!(
+            missed,
+            vec![
+                ("dev".to_string(), "first".to_string()),
+                ("ops".to_string(), "second".to_string())
+            ]
+        );
+    }
+
+    /// Broadcast a room message and store it the way ServerState::record_history does
+    async fn say(history: &RwLock<RoomHistory>, channel: &BroadcastChannel, text: &str) {
+        let mut history = history.write().await;
+        let message =
+            ChatMessage::try_new(MessageTypes::RoomMessage, Some(text.as_bytes().to_vec()))
+                .unwrap();
+        let seq = channel.send((message, SERVER_ORIGIN)).unwrap();
+        history.record("ops", "alice", text, SERVER_ORIGIN, seq);
+    }
+
+    #[tokio::test]
+    async fn test_lagged_receiver_gets_each_room_message_once() {
+        let history = RwLock::new(RoomHistory::new());
+        let channel = BroadcastChannel::new(4);
+        let mut rx = Subscription::Direct(channel.subscribe());
+        let rooms = ["ops".to_string()];
+        let mut seen = Vec::new();
+
+        say(&history, &channel, "m0").await;
+        let (message, _) = rx.recv().await.unwrap();
+        seen.push(message.content_as_string().unwrap());
+        let next = rx.position();
+
+        // More than the channel holds: the oldest are lost, the rest still queued
+        for i in 1..10 {
+            say(&history, &channel, &format!("m{}", i)).await;
+        }
+        assert!(matches!(rx.recv().await, Err(RecvError::Lagged(_))));
+        for (_, entry) in catch_up(&history, &mut rx, &rooms, next).await {
+            seen.push(entry.message);
+        }
+
+        say(&history, &channel, "m10").await;
+        let (message, _) = rx.recv().await.unwrap();
+        seen.push(message.content_as_string().unwrap());
+
+        let expected: Vec<String> = (0..=10).map(|i| format!("m{}", i)).collect();
+        assert_eq!(seen, expected);
+        // A later lag starts after what was caught up on
+        assert!(history.read().await.since(&rooms, rx.position()).is_empty());
+    }
+
+    #[test]
+    fn test_activity_since() {
+        let mut history = RoomHistory::new();
+        let now = Local::now();
+        let earlier = now - ChronoDuration::seconds(60);
+        history.restore("ops", "bob", "alice: old news", earlier);
+        history.restore("ops", "bob", "ping alice", now);
+        history.restore("ops", "alice", "mine", now);
+        history.restore("dev", "carol", "deploying", now);
+        history.restore("dev", "carol", "done", now);
+        history.restore(MAIN_CHAT, "dave", "hi alice", now);
+        let (mentions, rooms) = history.activity_since("alice", now - ChronoDuration::seconds(5));
+        assert_eq!(mentions, 1);
+        assert_eq!(rooms, vec![("dev".to_string(), 2), ("ops".to_string(), 1)]);
+    }
+
+    #[test]
+    fn test_retention_off_clears_history() {
+        let mut history = RoomHistory::new();
+        history.record("ops", "alice", "hello", SERVER_ORIGIN, 0);
+        assert_eq!(history.retention("ops"), DEFAULT_RETENTION);
+        history.set_retention("ops", Retention::Nothing);
+        assert!(history.recent("ops", 10).is_empty());
+        history.record("ops", "alice", "not kept", SERVER_ORIGIN, 1);
+        assert!(history.recent("ops", 10).is_empty());
+    }
+
+    #[test]
+    fn test_evict_oldest_across_rooms() {
+        let mut history = RoomHistory::new();
+        let now = Local::now();
+        history.restore("ops", "alice", "first", now - ChronoDuration::seconds(3));
+        history.restore("dev", "bob", "second", now - ChronoDuration::seconds(2));
+        history.restore("ops", "alice", "third", now - ChronoDuration::seconds(1));
+        let total = history.bytes();
+        assert_eq!(history.message_count(), 3);
+
+        // Freeing one byte takes a whole message: the oldest, whichever room it's in
+        assert_eq!(history.evict_oldest(1), 1);
+        assert_eq!(history.recent("ops", 10)[0].message, "third");
+        assert_eq!(history.recent("dev", 10)[0].message, "second");
+        assert!(history.bytes() < total);
+
+        assert_eq!(history.evict_oldest(usize::MAX), 2);
+        assert_eq!(history.bytes(), 0);
+        assert_eq!(history.message_count(), 0);
+    }
+
+    #[test]
+    fn test_clear_keeps_retention() {
+        let mut history = RoomHistory::new();
+        history.set_retention("ops", Retention::Messages(5));
+        history.record("ops", "alice", "one", SERVER_ORIGIN, 0);
+        history.record("ops", "bob", "two", SERVER_ORIGIN, 1);
+        assert_eq!(history.clear("ops"), 2);
+        assert!(history.recent("ops", 10).is_empty());
+        assert_eq!(history.retention("ops"), Retention::Messages(5));
+        assert_eq!(history.clear("nowhere"), 0);
+    }
+}
@@ -0,0 +1,239 @@
+//! Persisted room history in an SQLite database (built with the `sqlite` feature,
+//! CHAT_SERVER_HISTORY_DB=<path>) instead of `history.log`. The same records go
+//! through the same queue and writer task (crate::history_writer); each batch is
+//! written in one transaction. Messages are rows of `messages` (room, sender, time and
+//! text, in the order they were sent), a clear deletes a room's rows, and rooms with a
+//! retention other than the default have a row in `retention`. On startup the rooms
+//! are filled from it and it's trimmed to what their policies still keep, as the log
+//! is. Before that, `PRAGMA integrity_check` vets it (see crate::integrity): a damaged
+//! database is set aside and the server starts with an empty one.
+//!
+//! The main chat's messages are rows too, with an empty room (history::MAIN_CHAT),
+//! as they are in the log.
+
+use crate::history::{DEFAULT_RETENTION, Retention, RoomHistory};
+use crate::history_writer::{self, Record};
+use chrono::{DateTime, Local};
+use rusqlite::{Connection, params};
+use std::io;
+use std::path::Path;
+use tracing::warn;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS messages (
+        id INTEGER PRIMARY KEY,
+        room TEXT NOT NULL,
+        sender TEXT NOT NULL,
+        sent_at TEXT NOT NULL,
+        message TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS messages_by_room ON messages (room, id);
+    CREATE TABLE IF NOT EXISTS retention (
+        room TEXT PRIMARY KEY,
+        policy TEXT NOT NULL
+    );
+";
+
+pub struct Database {
+    connection: Connection,
+}
+
+impl Database {
+    /// Open the database at `path`, creating it and its tables if need be
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let connection = Connection::open(path).map_err(io::Error::other)?;
+        // A crash loses at most the last batches, never the database
+        connection
+            .execute_batch("PRAGMA journal_mode = WAL; PRAGMA synchronous = NORMAL;")
+            .and_then(|()| connection.execute_batch(SCHEMA))
+            .map_err(io::Error::other)?;
+        Ok(Database { connection })
+    }
+
+    /// Store `records` in one transaction
+    pub fn write(&mut self, records: &[Record]) -> io::Result<()> {
+        self.try_write(records).map_err(io::Error::other)
+    }
+
+    fn try_write(&mut self, records: &[Record]) -> rusqlite::Result<()> {
+        let transaction = self.connection.transaction()?;
+        for record in records {
+            match record {
+                Record::Message {
+                    at,
+                    room,
+                    sender,
+                    message,
+                } => transaction.execute(
+                    "INSERT INTO messages (room, sender, sent_at, message) VALUES (?1, ?2, ?3, ?4)",
+                    params![room, sender, at.to_rfc3339(), message],
+                )?,
+                Record::Retention {
+                    room, retention, ..
+                } if *retention == DEFAULT_RETENTION => {
+                    transaction.execute("DELETE FROM retention WHERE room = ?1", params![room])?
+                }
+                Record::Retention {
+                    room, retention, ..
+                } => transaction.execute(
+                    "INSERT OR REPLACE INTO retention (room, policy) VALUES (?1, ?2)",
+                    params![room, retention.spec()],
+                )?,
+                Record::Clear { room, .. } => {
+                    transaction.execute("DELETE FROM messages WHERE room = ?1", params![room])?
+                }
+            };
+        }
+        transaction.commit()
+    }
+
+    /// Fill `history` from the database, skipping rows that don't parse
+    fn read(&self, history: &mut RoomHistory) -> rusqlite::Result<usize> {
+        let mut skipped = 0;
+        let mut policies = self
+            .connection
+            .prepare("SELECT room, policy FROM retention")?;
+        let rows = policies.query_map([], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+        })?;
+        for row in rows {
+            let (room, policy) = row?;
+            match Retention::parse(&policy) {
+                Some(retention) => history.set_retention(&room, retention),
+                None => skipped += 1,
+            }
+        }
+
+        let mut messages = self
+            .connection
+            .prepare("SELECT room, sender, sent_at, message FROM messages ORDER BY id")?;
+        let rows = messages.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, String>(3)?,
+            ))
+        })?;
+        for row in rows {
+            let (room, sender, sent_at, message) = row?;
+            match DateTime::parse_from_rfc3339(&sent_at) {
+                Ok(at) => history.restore(&room, &sender, &message, at.with_timezone(&Local)),
+                Err(_) => skipped += 1,
+            }
+        }
+        Ok(skipped)
+    }
+
+    /// Replace what's stored with the records recreating `history`
+    fn compact(&mut self, history: &RoomHistory) -> rusqlite::Result<()> {
+        self.connection
+            .execute_batch("DELETE FROM messages; DELETE FROM retention;")?;
+        self.try_write(&history_writer::snapshot(history))?;
+        self.connection.execute_batch("VACUUM;")
+    }
+}
+
+/// What `PRAGMA integrity_check` finds wrong with the database at `path`, or that it
+/// isn't one (None = sound)
+pub fn problem(path: &Path) -> Option<String> {
+    let connection = match Connection::open(path) {
+        Ok(connection) => connection,
+        Err(e) => return Some(e.to_string()),
+    };
+    let result: rusqlite::Result<Vec<String>> = connection
+        .prepare("PRAGMA integrity_check")
+        .and_then(|mut statement| {
+            statement
+                .query_map([], |row| row.get::<_, String>(0))?
+                .collect()
+        });
+    match result {
+        Ok(lines) if lines == ["ok"] => None,
+        Ok(lines) => Some(lines.join("; ")),
+        Err(e) => Some(e.to_string()),
+    }
+}
+
+/// Fill `history` from the database at `path` (creating it if there's none), then
+/// trim it to what the rooms still keep. Returns how many messages were restored.
+pub fn load(path: &Path, history: &mut RoomHistory) -> io::Result<usize> {
+    let mut database = Database::open(path)?;
+    let skipped = database.read(history).map_err(io::Error::other)?;
+    if skipped > 0 {
+        warn!(
+            "Skipped {} unreadable row(s) in {}",
+            skipped,
+            path.display()
+        );
+    }
+    history.prune(Local::now());
+    database.compact(history).map_err(io::Error::other)?;
+    Ok(history.message_count())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history_writer::HistoryWriter;
+
+    #[tokio::test]
+    async fn test_stored_history_is_restored() {
+        let path =
+            std::env::temp_dir().join(format!("rust_chat_history_{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let now = Local::now();
+        let writer = HistoryWriter::start_db(&path).await.unwrap();
+        writer.send(Record::Retention {
+            at: now,
+            room: "ops".to_string(),
+            retention: Retention::Messages(2),
+        });
+        for message in ["one", "two", "three's a crowd"] {
+            writer.send(Record::Message {
+                at: now,
+                room: "ops".to_string(),
+                sender: "alice".to_string(),
+                message: message.to_string(),
+            });
+        }
+        writer.send(Record::Message {
+            at: now,
+            room: "dev".to_string(),
+            sender: "bob".to_string(),
+            message: "gone".to_string(),
+        });
+        writer.send(Record::Clear {
+            at: now,
+            room: "dev".to_string(),
+        });
+        assert!(writer.flush().await);
+        assert_eq!(writer.stats().written(), 6);
+
+        let mut history = RoomHistory::new();
+        assert_eq!(load(&path, &mut history).unwrap(), 2);
+        let messages: Vec<String> = history
+            .recent("ops", 10)
+            .into_iter()
+            .map(|entry| entry.message)
+            .collect();
+        assert_eq!(messages, vec!["two", "three's a crowd"]);
+        assert_eq!(history.retention("ops"), Retention::Messages(2));
+        assert!(history.recent("dev", 10).is_empty());
+
+        // Trimmed to what's kept
+        let database = Database::open(&path).unwrap();
+        let rows: i64 = database
+            .connection
+            .query_row("SELECT COUNT(*) FROM messages", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(rows, 2);
+        drop(database);
+        let mut reloaded = RoomHistory::new();
+        assert_eq!(load(&path, &mut reloaded).unwrap(), 2);
+        assert_eq!(problem(&path), None);
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+    }
+}
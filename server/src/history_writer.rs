@@ -0,0 +1,523 @@
+//! Persisted room history (CHAT_SERVER_PERSIST_HISTORY=1): what rooms' histories
+//! store is also appended to `history.log` in the data directory and replayed into
+//! them on startup, so a restart doesn't empty every room. Writing stays off the
+//! broadcast path: connections only put records on a bounded queue, and a task of its
+//! own writes whatever has queued up as one batch, syncing the file to disk every
+//! FSYNC_INTERVAL. When the disk can't keep up and the queue is full, records are
+//! left out of the log (the messages are still in memory) and counted; /stats shows
+//! the queue depth and how many were dropped.
+//!
+//! One record per line: `M|<time>|<room>|<sender>|<message>` for a message,
+//! `R|<time>|<room>|<retention>` when a room's retention changes and `C|<time>|<room>`
+//! when its history is cleared. The main chat's room is empty (history::MAIN_CHAT). Backslashes, line breaks and carriage returns in a
+//! message are escaped (`\\`, `\n`, `\r`), so whatever a message holds, it stays on
+//! its line. On startup the log is rewritten with only what the rooms' policies still
+//! keep.
+//!
+//! With the `sqlite` feature the task can write to a database instead (see
+//! crate::history_db).
+
+use crate::history::{Retention, RoomHistory};
+#[cfg(feature = "sqlite")]
+use crate::history_db::Database;
+use chrono::{DateTime, Local};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::sync::{mpsc, oneshot};
+use tracing::{error, warn};
+
+pub const HISTORY_FILE: &str = "history.log";
+/// Records waiting to be written before new ones are dropped
+pub const QUEUE_SIZE: usize = 4096;
+/// Most records written in one batch
+pub const MAX_BATCH: usize = 256;
+/// How often what was written is synced to disk
+pub const FSYNC_INTERVAL: Duration = Duration::from_secs(1);
+/// Longest shutting down waits for the queue to be written
+pub const FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Record {
+    Message {
+        at: DateTime<Local>,
+        room: String,
+        sender: String,
+        message: String,
+    },
+    Retention {
+        at: DateTime<Local>,
+        room: String,
+        retention: Retention,
+    },
+    Clear {
+        at: DateTime<Local>,
+        room: String,
+    },
+}
+
+impl Record {
+    pub fn encode(&self) -> String {
+        match self {
+            Record::Message {
+                at,
+                room,
+                sender,
+                message,
+            } => format!(
+                "M|{}|{}|{}|{}",
+                at.to_rfc3339(),
+                room,
+                sender,
+                escape(message)
+            ),
+            Record::Retention {
+                at,
+                room,
+                retention,
+            } => format!("R|{}|{}|{}", at.to_rfc3339(), room, retention.spec()),
+            Record::Clear { at, room } => format!("C|{}|{}", at.to_rfc3339(), room),
+        }
+    }
+
+    /// None if the line isn't a record
+    pub fn decode(line: &str) -> Option<Self> {
+        let mut fields = line.splitn(5, '|');
+        let kind = fields.next()?;
+        let at = DateTime::parse_from_rfc3339(fields.next()?)
+            .ok()?
+            .with_timezone(&Local);
+        let room = fields.next()?.to_string();
+        match kind {
+            "M" => Some(Record::Message {
+                at,
+                room,
+                sender: fields.next()?.to_string(),
+                message: unescape(fields.next()?),
+            }),
+            "R" => Some(Record::Retention {
+                at,
+                room,
+                retention: Retention::parse(fields.next()?)?,
+            }),
+            "C" => Some(Record::Clear { at, room }),
+            _ => None,
+        }
+    }
+
+    /// Apply the record to `history`, as it was when it was written
+    fn replay(self, history: &mut RoomHistory) {
+        match self {
+            Record::Message {
+                at,
+                room,
+                sender,
+                message,
+            } => history.restore(&room, &sender, &message, at),
+            Record::Retention {
+                room, retention, ..
+            } => history.set_retention(&room, retention),
+            Record::Clear { room, .. } => {
+                history.clear(&room);
+            }
+        }
+    }
+}
+
+/// A message's text with what would end its line escaped
+fn escape(message: &str) -> String {
+    let mut escaped = String::with_capacity(message.len());
+    for c in message.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Undo `escape`. A backslash before anything else is kept as it is, as logs written
+/// before messages were escaped have them.
+fn unescape(escaped: &str) -> String {
+    let mut message = String::with_capacity(escaped.len());
+    let mut chars = escaped.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            message.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('\\') => message.push('\\'),
+            Some('n') => message.push('\n'),
+            Some('r') => message.push('\r'),
+            Some(other) => {
+                message.push('\\');
+                message.push(other);
+            }
+            None => message.push('\\'),
+        }
+    }
+    message
+}
+
+/// Counters for /stats
+#[derive(Debug, Default)]
+pub struct WriterStats {
+    written: AtomicU64,
+    dropped: AtomicU64,
+    batches: AtomicU64,
+    failed: AtomicU64,
+}
+
+impl WriterStats {
+    /// Records written to the file
+    pub fn written(&self) -> u64 {
+        self.written.load(Ordering::Relaxed)
+    }
+
+    /// Records left out because the queue was full
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Batches the records were written in
+    pub fn batches(&self) -> u64 {
+        self.batches.load(Ordering::Relaxed)
+    }
+
+    /// Records lost to write errors
+    pub fn failed(&self) -> u64 {
+        self.failed.load(Ordering::Relaxed)
+    }
+}
+
+enum Job {
+    Write(Record),
+    /// Write and sync everything queued before it, then answer
+    Flush(oneshot::Sender<()>),
+}
+
+/// Handle to the writer task. Clones share the same queue.
+#[derive(Clone)]
+pub struct HistoryWriter {
+    tx: mpsc::Sender<Job>,
+    stats: Arc<WriterStats>,
+}
+
+impl HistoryWriter {
+    /// Start the writer task, appending to the log at `path`
+    pub async fn start(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await?;
+        Ok(Self::spawn(Sink::Log(BufWriter::new(file))))
+    }
+
+    /// Start the writer task, storing records in the SQLite database at `path`
+    #[cfg(feature = "sqlite")]
+    pub async fn start_db(path: &Path) -> io::Result<Self> {
+        let path = path.to_path_buf();
+        let database = tokio::task::spawn_blocking(move || Database::open(&path))
+            .await
+            .map_err(io::Error::other)??;
+        Ok(Self::spawn(Sink::Db(Some(database))))
+    }
+
+    fn spawn(sink: Sink) -> Self {
+        let (tx, rx) = mpsc::channel(QUEUE_SIZE);
+        let stats = Arc::new(WriterStats::default());
+        tokio::spawn(run(sink, rx, Arc::clone(&stats)));
+        HistoryWriter { tx, stats }
+    }
+
+    /// Queue a record without waiting; it's dropped (and counted) if the queue is full
+    pub fn send(&self, record: Record) {
+        if let Err(mpsc::error::TrySendError::Full(_)) = self.tx.try_send(Job::Write(record)) {
+            // Warn once per thousand, or a slow disk floods the console too
+            if self
+                .stats
+                .dropped
+                .fetch_add(1, Ordering::Relaxed)
+                .is_multiple_of(1000)
+            {
+                warn!(
+                    "History log queue is full ({} records) - messages are being left out of {}",
+                    QUEUE_SIZE, HISTORY_FILE
+                );
+            }
+        }
+    }
+
+    /// Records waiting to be written
+    pub fn queued(&self) -> usize {
+        self.tx.max_capacity() - self.tx.capacity()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.tx.max_capacity()
+    }
+
+    pub fn stats(&self) -> &WriterStats {
+        &self.stats
+    }
+
+    /// Wait until everything queued so far is written and synced (for shutting down).
+    /// Returns false if that took longer than FLUSH_TIMEOUT.
+    pub async fn flush(&self) -> bool {
+        let (done_tx, done_rx) = oneshot::channel();
+        tokio::time::timeout(FLUSH_TIMEOUT, async {
+            self.tx.send(Job::Flush(done_tx)).await.is_ok() && done_rx.await.is_ok()
+        })
+        .await
+        .unwrap_or(false)
+    }
+}
+
+/// Where the writer task puts records
+enum Sink {
+    Log(BufWriter<File>),
+    /// Taken while a batch is written on a blocking thread
+    #[cfg(feature = "sqlite")]
+    Db(Option<Database>),
+}
+
+impl Sink {
+    async fn write(&mut self, records: Vec<Record>) -> io::Result<()> {
+        match self {
+            Sink::Log(file) => {
+                let lines: String = records
+                    .iter()
+                    .map(|record| format!("{}\n", record.encode()))
+                    .collect();
+                file.write_all(lines.as_bytes()).await?;
+                file.flush().await
+            }
+            #[cfg(feature = "sqlite")]
+            Sink::Db(slot) => {
+                let Some(mut database) = slot.take() else {
+                    return Err(io::Error::other("the history database was lost"));
+                };
+                let (database, result) = tokio::task::spawn_blocking(move || {
+                    let result = database.write(&records);
+                    (database, result)
+                })
+                .await
+                .map_err(io::Error::other)?;
+                *slot = Some(database);
+                result
+            }
+        }
+    }
+
+    /// Make what was written durable. Each database batch is already a committed
+    /// transaction, so there's nothing left to do for one.
+    async fn sync(&mut self) -> io::Result<()> {
+        match self {
+            Sink::Log(file) => file.get_ref().sync_data().await,
+            #[cfg(feature = "sqlite")]
+            Sink::Db(_) => Ok(()),
+        }
+    }
+}
+
+/// The writer task: wait for a record, write it along with whatever else has queued
+/// up, and sync the file once a tick if anything was written since the last one
+async fn run(mut sink: Sink, mut rx: mpsc::Receiver<Job>, stats: Arc<WriterStats>) {
+    let mut sync_tick = tokio::time::interval(FSYNC_INTERVAL);
+    let mut unsynced = false;
+    let mut batch = Vec::with_capacity(MAX_BATCH);
+    loop {
+        tokio::select! {
+            received = rx.recv_many(&mut batch, MAX_BATCH) => {
+                if received == 0 {
+                    // Every handle is gone
+                    break;
+                }
+                let mut flushed = Vec::new();
+                let mut records = Vec::new();
+                for job in batch.drain(..) {
+                    match job {
+                        Job::Write(record) => records.push(record),
+                        Job::Flush(done) => flushed.push(done),
+                    }
+                }
+                if !records.is_empty() {
+                    let count = records.len() as u64;
+                    match sink.write(records).await {
+                        Ok(()) => {
+                            stats.written.fetch_add(count, Ordering::Relaxed);
+                            stats.batches.fetch_add(1, Ordering::Relaxed);
+                            unsynced = true;
+                        }
+                        Err(e) => {
+                            stats.failed.fetch_add(count, Ordering::Relaxed);
+                            error!("Failed to write to the history log: {}", e);
+                        }
+                    }
+                }
+                if !flushed.is_empty() {
+                    sync(&mut sink, &mut unsynced).await;
+                    for done in flushed {
+                        let _ = done.send(());
+                    }
+                }
+            }
+            _ = sync_tick.tick(), if unsynced => {
+                sync(&mut sink, &mut unsynced).await;
+            }
+        }
+    }
+    sync(&mut sink, &mut unsynced).await;
+}
+
+async fn sync(sink: &mut Sink, unsynced: &mut bool) {
+    if !std::mem::take(unsynced) {
+        return;
+    }
+    if let Err(e) = sink.sync().await {
+        error!("Failed to sync the history log: {}", e);
+    }
+}
+
+/// Replay the log at `path` into `history` (a missing file is an empty log; lines
+/// that aren't records are skipped), then rewrite it with only what the rooms still
+/// keep. Returns how many messages were restored.
+pub fn load(path: &Path, history: &mut RoomHistory) -> io::Result<usize> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+    let mut skipped = 0;
+    for line in contents.lines().filter(|line| !line.is_empty()) {
+        match Record::decode(line) {
+            Some(record) => record.replay(history),
+            None => skipped += 1,
+        }
+    }
+    if skipped > 0 {
+        warn!(
+            "Skipped {} unreadable line(s) in {}",
+            skipped,
+            path.display()
+        );
+    }
+    history.prune(Local::now());
+    compact(path, history)?;
+    Ok(history.message_count())
+}
+
+/// The records recreating `history`: each room's retention (unless it's the
+/// default), then its messages
+pub fn snapshot(history: &RoomHistory) -> Vec<Record> {
+    let now = Local::now();
+    let mut records = Vec::new();
+    for (room, retention, entries) in history.rooms() {
+        if retention != crate::history::DEFAULT_RETENTION {
+            records.push(Record::Retention {
+                at: now,
+                room: room.to_string(),
+                retention,
+            });
+        }
+        records.extend(entries.iter().map(|entry| Record::Message {
+            at: entry.at,
+            room: room.to_string(),
+            sender: entry.sender.clone(),
+            message: entry.message.clone(),
+        }));
+    }
+    records
+}
+
+/// Rewrite the log at `path` as the records recreating `history`
+fn compact(path: &Path, history: &RoomHistory) -> io::Result<()> {
+    let contents: String = snapshot(history)
+        .iter()
+        .map(|record| format!("{}\n", record.encode()))
+        .collect();
+    let tmp = PathBuf::from(format!("{}.tmp", path.display()));
+    let mut file = fs::File::create(&tmp)?;
+    file.write_all(contents.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history::MAIN_CHAT;
+    use crate::state::SERVER_ORIGIN;
+
+    #[tokio::test]
+    async fn test_written_log_is_replayed() {
+        let path =
+            std::env::temp_dir().join(format!("rust_chat_history_{}.log", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let now = Local::now();
+        let writer = HistoryWriter::start(&path).await.unwrap();
+        writer.send(Record::Retention {
+            at: now,
+            room: "ops".to_string(),
+            retention: Retention::Messages(2),
+        });
+        let forged = format!("three\nC|{}|ops\\n", now.to_rfc3339());
+        for message in ["one", "two | with a bar", forged.as_str()] {
+            writer.send(Record::Message {
+                at: now,
+                room: "ops".to_string(),
+                sender: "alice".to_string(),
+                message: message.to_string(),
+            });
+        }
+        writer.send(Record::Message {
+            at: now,
+            room: "dev".to_string(),
+            sender: "bob".to_string(),
+            message: "gone".to_string(),
+        });
+        writer.send(Record::Clear {
+            at: now,
+            room: "dev".to_string(),
+        });
+        writer.send(Record::Message {
+            at: now,
+            room: MAIN_CHAT.to_string(),
+            sender: "carol".to_string(),
+            message: "hi all".to_string(),
+        });
+        assert!(writer.flush().await);
+        assert_eq!(writer.queued(), 0);
+        assert_eq!(writer.stats().written(), 7);
+        assert_eq!(writer.stats().dropped(), 0);
+
+        let mut history = RoomHistory::new();
+        assert_eq!(load(&path, &mut history).unwrap(), 3);
+        let messages: Vec<String> = history
+            .recent("ops", 10)
+            .into_iter()
+            .map(|entry| entry.message)
+            .collect();
+        assert_eq!(messages, vec!["two | with a bar", forged.as_str()]);
+        assert_eq!(history.recent("ops", 1)[0].origin, SERVER_ORIGIN);
+        assert_eq!(history.retention("ops"), Retention::Messages(2));
+        assert!(history.recent("dev", 10).is_empty());
+        assert_eq!(history.recent(MAIN_CHAT, 10)[0].message, "hi all");
+
+        // The log was compacted to what's kept
+        let contents = fs::read_to_string(&path).unwrap();
+        assert_eq!(contents.lines().count(), 4);
+        let mut reloaded = RoomHistory::new();
+        assert_eq!(load(&path, &mut reloaded).unwrap(), 3);
+        fs::remove_file(&path).unwrap();
+    }
+}
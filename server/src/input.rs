@@ -1,18 +1,109 @@
+use crate::auth::bot_token::Grant;
+use crate::bans;
+use crate::channel;
+use crate::maintenance_window::Start;
+use crate::schedule;
+use crate::scopes::Scopes;
+use crate::stats_history;
+use chrono::NaiveTime;
+use ip_network::IpNetwork;
 use shared::commands::server as commands;
+use shared::fingerprint;
 use shared::input::{UserInput, UserInputError};
 
 use std::net::IpAddr;
+use std::time::Duration;
 
 #[derive(Debug)]
 pub enum ServerUserInput {
     Help,
     ListUsers,
-    Kick(String),
-    Rename { old_name: String, new_name: String },
-    Ban(String),   // Ban by username (will resolve to IP)
-    BanIp(IpAddr), // Ban by IP directly
-    Unban(IpAddr), // Unban by IP
-    BanList,       // List all banned IPs
+    Whois(String),
+    Seen(String),
+    Stats,
+    /// /stats --graph: sparklines of the last this long
+    StatsGraph(Duration),
+    ChannelStats,
+    ChannelResize(usize),  // New broadcast channel capacity
+    Privacy(Option<bool>), // Some(true) = log metadata only, None = show the setting
+    Kick {
+        username: String,
+        cooldown: Option<Duration>, // --for: how long before they can rejoin
+    },
+    KickAll {
+        room: Option<String>, // None = everyone on the server
+    },
+    MuteAll {
+        room: String,
+        duration: Option<Duration>, // None = lift the mute
+    },
+    ClearHistory(String), // Room whose stored history is deleted
+    Rename {
+        old_name: String,
+        new_name: String,
+    },
+    // Ban by username (will resolve to IP); duration None = permanent
+    Ban {
+        username: String,
+        duration: Option<Duration>,
+    },
+    // Ban an IP or CIDR range directly
+    BanIp {
+        network: IpNetwork,
+        duration: Option<Duration>,
+    },
+    // Ban a client fingerprint (fp:<id>), following the client across IPs and nicknames
+    BanFingerprint {
+        id: String,
+        duration: Option<Duration>,
+    },
+    Unban(IpNetwork),         // Unban by IP or CIDR range
+    UnbanFingerprint(String), // Unban a client fingerprint
+    BanList,                  // List all banned IPs
+    Register {
+        username: String,
+        password: String,
+    },
+    Unregister(String),
+    ListAccounts,
+    ListTokens,
+    CreateToken {
+        bot: String, // Without its bot: prefix
+        grant: Grant,
+    },
+    RevokeToken(String),
+    Announce {
+        room: Option<String>, // None = everyone
+        message: String,
+    },
+    ScheduleAnnouncement {
+        room: Option<String>,
+        message: String,
+        at: Option<NaiveTime>,   // First send at this local time
+        every: Option<Duration>, // Repeat interval
+    },
+    ListScheduled,
+    CancelScheduled(u32),
+    Say {
+        room: String,
+        message: String,
+    },
+    Shell {
+        command: String,
+        room: String,
+        sender: Option<String>, // --as: name the lines are posted under (default: server identity)
+    },
+    ShellList,
+    ShellStop(u32),
+    Drain(Option<Duration>), // Time until shutdown; None = wait for the last client to leave
+    DrainCancel,
+    MaintenanceShow,
+    MaintenanceSet {
+        start: Start,
+        duration: Duration,
+        text: String, // Shown to users in the banner
+    },
+    MaintenanceClear,
     Quit,
 }
 
@@ -34,15 +125,80 @@ impl TryFrom<&str> for ServerUserInput {
             Ok(ServerUserInput::Quit)
         } else if commands::LIST.matches(cmd) {
             Ok(ServerUserInput::ListUsers)
+        } else if commands::WHOIS.matches(cmd) {
+            match parts.as_slice() {
+                [_, username] => Ok(ServerUserInput::Whois(username.to_string())),
+                _ => Err(UserInputError::InvalidCommand),
+            }
+        } else if commands::SEEN.matches(cmd) {
+            match parts.as_slice() {
+                [_, username] => Ok(ServerUserInput::Seen(username.to_string())),
+                _ => Err(UserInputError::InvalidCommand),
+            }
+        } else if commands::STATS.matches(cmd) {
+            match parts.as_slice() {
+                [_] => Ok(ServerUserInput::Stats),
+                [_, "--graph"] => Ok(ServerUserInput::StatsGraph(stats_history::DEFAULT_SPAN)),
+                [_, "--graph", span] => schedule::parse_interval(span)
+                    .map(ServerUserInput::StatsGraph)
+                    .ok_or(UserInputError::InvalidCommand),
+                _ => Err(UserInputError::InvalidCommand),
+            }
+        } else if commands::CHANNEL.matches(cmd) {
+            match parts.as_slice() {
+                [_] | [_, "stats"] => Ok(ServerUserInput::ChannelStats),
+                [_, "resize", capacity] => match capacity.parse::<usize>() {
+                    Ok(capacity) if (1..=channel::MAX_CAPACITY).contains(&capacity) => {
+                        Ok(ServerUserInput::ChannelResize(capacity))
+                    }
+                    _ => Err(UserInputError::InvalidCommand),
+                },
+                _ => Err(UserInputError::InvalidCommand),
+            }
+        } else if commands::PRIVACY.matches(cmd) {
+            match parts.as_slice() {
+                [_] => Ok(ServerUserInput::Privacy(None)),
+                [_, "on"] => Ok(ServerUserInput::Privacy(Some(true))),
+                [_, "off"] => Ok(ServerUserInput::Privacy(Some(false))),
+                _ => Err(UserInputError::InvalidCommand),
+            }
         } else if commands::HELP.matches(cmd) {
             Ok(ServerUserInput::Help)
         } else if commands::KICK.matches(cmd) {
-            let username = parts.get(1..).map(|p| p.join(" ")).unwrap_or_default();
-            let username = username.trim();
+            let mut args = parts.get(1..).unwrap_or_default().to_vec();
+            let cooldown = take_for(&mut args)?;
+            let username = args.join(" ");
             if username.is_empty() {
                 Err(UserInputError::InvalidCommand)
             } else {
-                Ok(ServerUserInput::Kick(username.to_string()))
+                Ok(ServerUserInput::Kick { username, cooldown })
+            }
+        } else if commands::KICKALL.matches(cmd) {
+            match parts.as_slice() {
+                [_] => Ok(ServerUserInput::KickAll { room: None }),
+                [_, "--room", room] => Ok(ServerUserInput::KickAll {
+                    room: Some(room.to_string()),
+                }),
+                _ => Err(UserInputError::InvalidCommand),
+            }
+        } else if commands::MUTEALL.matches(cmd) {
+            match parts.as_slice() {
+                [_, room, "off"] => Ok(ServerUserInput::MuteAll {
+                    room: room.to_string(),
+                    duration: None,
+                }),
+                [_, room, interval] => Ok(ServerUserInput::MuteAll {
+                    room: room.to_string(),
+                    duration: Some(
+                        schedule::parse_interval(interval).ok_or(UserInputError::InvalidCommand)?,
+                    ),
+                }),
+                _ => Err(UserInputError::InvalidCommand),
+            }
+        } else if commands::CLEAR.matches(cmd) {
+            match parts.as_slice() {
+                [_, room] => Ok(ServerUserInput::ClearHistory(room.to_string())),
+                _ => Err(UserInputError::InvalidCommand),
             }
         } else if commands::RENAME.matches(cmd) {
             if parts.len() != 3 {
@@ -54,25 +210,93 @@ impl TryFrom<&str> for ServerUserInput {
                 })
             }
         } else if commands::BAN.matches(cmd) {
-            let target = parts.get(1).map(|s| s.trim()).unwrap_or("");
+            let mut args = parts.get(1..).unwrap_or_default().to_vec();
+            let duration = take_for(&mut args)?;
+            let target = args.first().copied().unwrap_or("");
             if target.is_empty() {
                 Err(UserInputError::InvalidCommand)
-            } else if let Ok(ip) = target.parse::<IpAddr>() {
-                // It's an IP address
-                Ok(ServerUserInput::BanIp(ip))
+            } else if let Some(id) = parse_fingerprint(target)? {
+                Ok(ServerUserInput::BanFingerprint { id, duration })
+            } else if let Some(network) = parse_network(target)? {
+                // It's an IP address or range
+                Ok(ServerUserInput::BanIp { network, duration })
             } else {
                 // It's a username
-                Ok(ServerUserInput::Ban(target.to_string()))
+                Ok(ServerUserInput::Ban {
+                    username: target.to_string(),
+                    duration,
+                })
             }
         } else if commands::UNBAN.matches(cmd) {
-            let ip_str = parts.get(1).map(|s| s.trim()).unwrap_or("");
-            if let Ok(ip) = ip_str.parse::<IpAddr>() {
-                Ok(ServerUserInput::Unban(ip))
-            } else {
-                Err(UserInputError::InvalidCommand)
+            let target = parts.get(1).map(|s| s.trim()).unwrap_or("");
+            if let Some(id) = parse_fingerprint(target)? {
+                return Ok(ServerUserInput::UnbanFingerprint(id));
+            }
+            match parse_network(target)? {
+                Some(network) => Ok(ServerUserInput::Unban(network)),
+                None => Err(UserInputError::InvalidCommand),
             }
         } else if commands::BANLIST.matches(cmd) {
             Ok(ServerUserInput::BanList)
+        } else if commands::REGISTER.matches(cmd) {
+            if parts.len() != 3 {
+                Err(UserInputError::InvalidCommand)
+            } else {
+                Ok(ServerUserInput::Register {
+                    username: parts[1].to_string(),
+                    password: parts[2].to_string(),
+                })
+            }
+        } else if commands::UNREGISTER.matches(cmd) {
+            match parts.as_slice() {
+                [_, username] => Ok(ServerUserInput::Unregister(username.to_string())),
+                _ => Err(UserInputError::InvalidCommand),
+            }
+        } else if commands::ACCOUNTS.matches(cmd) {
+            Ok(ServerUserInput::ListAccounts)
+        } else if commands::TOKEN.matches(cmd) {
+            parse_token(&parts[1..])
+        } else if commands::ANNOUNCE.matches(cmd) {
+            parse_announce(&parts[1..])
+        } else if commands::SAY.matches(cmd) {
+            if parts.len() < 3 {
+                Err(UserInputError::InvalidCommand)
+            } else {
+                Ok(ServerUserInput::Say {
+                    room: parts[1].to_string(),
+                    message: parts[2..].join(" "),
+                })
+            }
+        } else if commands::SHELL.matches(cmd) {
+            parse_shell(trimmed[cmd.len()..].trim_start())
+        } else if commands::DRAIN.matches(cmd) {
+            match parts.get(1) {
+                None => Ok(ServerUserInput::Drain(None)),
+                Some(&"cancel") => Ok(ServerUserInput::DrainCancel),
+                Some(minutes) => minutes
+                    .parse::<u64>()
+                    .ok()
+                    .filter(|minutes| *minutes > 0)
+                    .and_then(|minutes| minutes.checked_mul(60))
+                    .map(Duration::from_secs)
+                    .filter(|remaining| *remaining <= schedule::MAX_INTERVAL)
+                    .map(|remaining| ServerUserInput::Drain(Some(remaining)))
+                    .ok_or(UserInputError::InvalidCommand),
+            }
+        } else if commands::MAINTENANCE.matches(cmd) {
+            match &parts[1..] {
+                [] => Ok(ServerUserInput::MaintenanceShow),
+                ["clear"] => Ok(ServerUserInput::MaintenanceClear),
+                ["set", start, duration, text @ ..] if !text.is_empty() => {
+                    Ok(ServerUserInput::MaintenanceSet {
+                        start: Start::parse(start).ok_or(UserInputError::InvalidCommand)?,
+                        duration: schedule::parse_interval(duration)
+                            .ok_or(UserInputError::InvalidCommand)?,
+                        text: text.join(" "),
+                    })
+                }
+                _ => Err(UserInputError::InvalidCommand),
+            }
         } else if trimmed.starts_with('/') {
             Err(UserInputError::InvalidCommand)
         } else {
@@ -82,6 +306,177 @@ impl TryFrom<&str> for ServerUserInput {
     }
 }
 
+/// Parse `/announce [--room <room>] [--at HH:MM] [--every <interval>] <message>`,
+/// `/announce --list` and `/announce --cancel <id>`
+/// An IP address or CIDR range ("203.0.113.0/24"); Ok(None) if `target` is neither (a username).
+/// Ranges must not have host bits set, so a typo can't ban more than intended.
+fn parse_network(target: &str) -> Result<Option<IpNetwork>, UserInputError> {
+    if let Ok(ip) = target.parse::<IpAddr>() {
+        Ok(Some(bans::host_network(ip)))
+    } else if target.contains('/') {
+        target
+            .parse()
+            .map(Some)
+            .map_err(|_| UserInputError::InvalidCommand)
+    } else {
+        Ok(None)
+    }
+}
+
+/// A client fingerprint ID written as "fp:<id>"; Ok(None) if `target` isn't one
+fn parse_fingerprint(target: &str) -> Result<Option<String>, UserInputError> {
+    match target.strip_prefix("fp:") {
+        Some(id) if fingerprint::is_hash(id) => Ok(Some(id.to_lowercase())),
+        Some(_) => Err(UserInputError::InvalidCommand),
+        None => Ok(None),
+    }
+}
+
+/// Remove `--for <interval>` from `args`, returning the interval if it was there
+fn take_for(args: &mut Vec<&str>) -> Result<Option<Duration>, UserInputError> {
+    let Some(i) = args.iter().position(|arg| *arg == "--for") else {
+        return Ok(None);
+    };
+    let value = args.get(i + 1).ok_or(UserInputError::InvalidCommand)?;
+    let interval = schedule::parse_interval(value).ok_or(UserInputError::InvalidCommand)?;
+    args.drain(i..i + 2);
+    Ok(Some(interval))
+}
+
+/// Parse `/token`, `/token revoke <bot>` and
+/// `/token create <bot> [--scopes send,read] [--rooms <room,...>]`
+fn parse_token(args: &[&str]) -> Result<ServerUserInput, UserInputError> {
+    let bot = |name: &str| name.strip_prefix("bot:").unwrap_or(name).to_string();
+    let (name, mut rest) = match args {
+        [] => return Ok(ServerUserInput::ListTokens),
+        ["revoke", name] => return Ok(ServerUserInput::RevokeToken(bot(name))),
+        ["create", name, rest @ ..] => (name, rest),
+        _ => return Err(UserInputError::InvalidCommand),
+    };
+    let (mut scopes, mut rooms) = (None, Vec::new());
+    loop {
+        match rest {
+            ["--scopes", value, tail @ ..] => {
+                scopes = Some(Scopes::parse(value).ok_or(UserInputError::InvalidCommand)?);
+                rest = tail;
+            }
+            ["--rooms", value, tail @ ..] => {
+                rooms = value
+                    .split(',')
+                    .map(|room| room.trim_start_matches('#').to_string())
+                    .filter(|room| !room.is_empty())
+                    .collect();
+                rest = tail;
+            }
+            [] => break,
+            _ => return Err(UserInputError::InvalidCommand),
+        }
+    }
+    // Without --scopes the bot may send and read
+    let scopes = scopes.unwrap_or(Grant::default().scopes());
+    Ok(ServerUserInput::CreateToken {
+        bot: bot(name),
+        grant: Grant::new(scopes, rooms),
+    })
+}
+
+fn parse_announce(args: &[&str]) -> Result<ServerUserInput, UserInputError> {
+    match args {
+        ["--list"] => return Ok(ServerUserInput::ListScheduled),
+        ["--cancel", id] => {
+            return id
+                .parse()
+                .map(ServerUserInput::CancelScheduled)
+                .map_err(|_| UserInputError::InvalidCommand);
+        }
+        _ => {}
+    }
+
+    let (mut room, mut at, mut every) = (None, None, None);
+    let mut rest = args;
+    loop {
+        match rest {
+            ["--room", value, tail @ ..] => {
+                room = Some(value.to_string());
+                rest = tail;
+            }
+            ["--at", value, tail @ ..] => {
+                let time = NaiveTime::parse_from_str(value.trim_matches('"'), "%H:%M")
+                    .map_err(|_| UserInputError::InvalidCommand)?;
+                at = Some(time);
+                rest = tail;
+            }
+            ["--every", value, tail @ ..] => {
+                every =
+                    Some(schedule::parse_interval(value).ok_or(UserInputError::InvalidCommand)?);
+                rest = tail;
+            }
+            _ => break,
+        }
+    }
+
+    // A flag left over here is missing its value
+    if rest.is_empty() || matches!(rest[0], "--room" | "--at" | "--every") {
+        return Err(UserInputError::InvalidCommand);
+    }
+    let message = rest.join(" ");
+    if at.is_some() || every.is_some() {
+        Ok(ServerUserInput::ScheduleAnnouncement {
+            room,
+            message,
+            at,
+            every,
+        })
+    } else {
+        Ok(ServerUserInput::Announce { room, message })
+    }
+}
+
+/// Parse `/shell "<command>" --to <room> [--as <name>]`, `/shell --list` and
+/// `/shell --stop <id>`. The command is quoted since it has spaces of its own.
+fn parse_shell(args: &str) -> Result<ServerUserInput, UserInputError> {
+    match args.split_whitespace().collect::<Vec<_>>().as_slice() {
+        ["--list"] => return Ok(ServerUserInput::ShellList),
+        ["--stop", id] => {
+            return id
+                .parse()
+                .map(ServerUserInput::ShellStop)
+                .map_err(|_| UserInputError::InvalidCommand);
+        }
+        _ => {}
+    }
+
+    let (command, options) = args
+        .strip_prefix('"')
+        .and_then(|rest| rest.split_once('"'))
+        .ok_or(UserInputError::InvalidCommand)?;
+    let command = command.trim();
+    let (mut room, mut sender) = (None, None);
+    let mut rest = options.split_whitespace().collect::<Vec<_>>();
+    loop {
+        match rest.as_slice() {
+            ["--to", value, tail @ ..] => {
+                room = Some(value.to_string());
+                rest = tail.to_vec();
+            }
+            ["--as", value, tail @ ..] => {
+                sender = Some(value.to_string());
+                rest = tail.to_vec();
+            }
+            [] => break,
+            _ => return Err(UserInputError::InvalidCommand),
+        }
+    }
+    match room {
+        Some(room) if !command.is_empty() => Ok(ServerUserInput::Shell {
+            command: command.to_string(),
+            room,
+            sender,
+        }),
+        _ => Err(UserInputError::InvalidCommand),
+    }
+}
+
 impl TryFrom<String> for ServerUserInput {
     type Error = UserInputError;
 
@@ -122,6 +517,32 @@ mod tests {
         assert!(matches!(input.unwrap(), ServerUserInput::ListUsers));
     }
 
+    #[test]
+    fn test_stats_command() {
+        let input = ServerUserInput::try_from("/stats");
+        assert!(matches!(input.unwrap(), ServerUserInput::Stats));
+        assert!(matches!(
+            ServerUserInput::try_from("/stats --graph").unwrap(),
+            ServerUserInput::StatsGraph(span) if span == stats_history::DEFAULT_SPAN
+        ));
+        assert!(matches!(
+            ServerUserInput::try_from("/stats --graph 6h").unwrap(),
+            ServerUserInput::StatsGraph(span) if span == Duration::from_secs(6 * 3600)
+        ));
+        assert!(ServerUserInput::try_from("/stats --graph soon").is_err());
+    }
+
+    #[test]
+    fn test_whois_command() {
+        let input = ServerUserInput::try_from("/whois alice");
+        assert!(matches!(input.unwrap(), ServerUserInput::Whois(user) if user == "alice"));
+        assert!(ServerUserInput::try_from("/whois").is_err());
+        assert!(ServerUserInput::try_from("/whois alice bob").is_err());
+        let input = ServerUserInput::try_from("/seen bob");
+        assert!(matches!(input.unwrap(), ServerUserInput::Seen(user) if user == "bob"));
+        assert!(ServerUserInput::try_from("/seen").is_err());
+    }
+
     #[test]
     fn test_invalid_command() {
         let input = ServerUserInput::try_from("/unknown");
@@ -141,7 +562,10 @@ mod tests {
         let input = ServerUserInput::try_from("/kick Alice");
         assert!(input.is_ok());
         match input.unwrap() {
-            ServerUserInput::Kick(username) => assert_eq!(username, "Alice"),
+            ServerUserInput::Kick { username, cooldown } => {
+                assert_eq!(username, "Alice");
+                assert_eq!(cooldown, None);
+            }
             _ => panic!("Expected Kick variant"),
         }
     }
@@ -151,7 +575,7 @@ mod tests {
         let input = ServerUserInput::try_from("/kick   Bob  ");
         assert!(input.is_ok());
         match input.unwrap() {
-            ServerUserInput::Kick(username) => assert_eq!(username, "Bob"),
+            ServerUserInput::Kick { username, .. } => assert_eq!(username, "Bob"),
             _ => panic!("Expected Kick variant"),
         }
     }
@@ -167,4 +591,363 @@ mod tests {
         let input = ServerUserInput::try_from("/kick   ");
         assert!(input.is_err());
     }
+
+    #[test]
+    fn test_kick_command_with_cooldown() {
+        match ServerUserInput::try_from("/kick Bob --for 10m").unwrap() {
+            ServerUserInput::Kick { username, cooldown } => {
+                assert_eq!(username, "Bob");
+                assert_eq!(cooldown, Some(Duration::from_secs(600)));
+            }
+            _ => panic!("Expected Kick variant"),
+        }
+        assert!(ServerUserInput::try_from("/kick Bob --for").is_err());
+        assert!(ServerUserInput::try_from("/kick Bob --for soon").is_err());
+        assert!(ServerUserInput::try_from("/kick --for 10m").is_err());
+    }
+
+    #[test]
+    fn test_bulk_moderation_commands() {
+        assert!(matches!(
+            ServerUserInput::try_from("/kickall").unwrap(),
+            ServerUserInput::KickAll { room: None }
+        ));
+        assert!(matches!(
+            ServerUserInput::try_from("/kickall --room #ops").unwrap(),
+            ServerUserInput::KickAll { room: Some(room) } if room == "#ops"
+        ));
+        assert!(ServerUserInput::try_from("/kickall --room").is_err());
+
+        match ServerUserInput::try_from("/muteall ops 10m").unwrap() {
+            ServerUserInput::MuteAll { room, duration } => {
+                assert_eq!(room, "ops");
+                assert_eq!(duration, Some(Duration::from_secs(600)));
+            }
+            _ => panic!("Expected MuteAll variant"),
+        }
+        assert!(matches!(
+            ServerUserInput::try_from("/muteall ops off").unwrap(),
+            ServerUserInput::MuteAll { duration: None, .. }
+        ));
+        assert!(ServerUserInput::try_from("/muteall ops").is_err());
+        assert!(ServerUserInput::try_from("/muteall ops soon").is_err());
+
+        assert!(matches!(
+            ServerUserInput::try_from("/clear ops").unwrap(),
+            ServerUserInput::ClearHistory(room) if room == "ops"
+        ));
+        assert!(ServerUserInput::try_from("/clear").is_err());
+    }
+
+    #[test]
+    fn test_ban_command() {
+        match ServerUserInput::try_from("/ban 203.0.113.7 --for 24h").unwrap() {
+            ServerUserInput::BanIp { network, duration } => {
+                assert_eq!(bans::describe(network), "203.0.113.7");
+                assert_eq!(duration, Some(Duration::from_secs(24 * 60 * 60)));
+            }
+            _ => panic!("Expected BanIp variant"),
+        }
+        match ServerUserInput::try_from("/ban Alice").unwrap() {
+            ServerUserInput::Ban { username, duration } => {
+                assert_eq!(username, "Alice");
+                assert_eq!(duration, None);
+            }
+            _ => panic!("Expected Ban variant"),
+        }
+        match ServerUserInput::try_from("/ban 203.0.113.0/24").unwrap() {
+            ServerUserInput::BanIp { network, .. } => {
+                assert_eq!(network.to_string(), "203.0.113.0/24")
+            }
+            _ => panic!("Expected BanIp variant"),
+        }
+        assert!(ServerUserInput::try_from("/ban 203.0.113.5/24").is_err());
+        assert!(ServerUserInput::try_from("/unban 2001:db8::/32").is_ok());
+        match ServerUserInput::try_from("/ban fp:0123456789ABCDEF --for 7d").unwrap() {
+            ServerUserInput::BanFingerprint { id, duration } => {
+                assert_eq!(id, "0123456789abcdef");
+                assert_eq!(duration, Some(Duration::from_secs(7 * 24 * 60 * 60)));
+            }
+            _ => panic!("Expected BanFingerprint variant"),
+        }
+        assert!(ServerUserInput::try_from("/ban fp:nothex").is_err());
+        assert!(matches!(
+            ServerUserInput::try_from("/unban fp:0123456789abcdef"),
+            Ok(ServerUserInput::UnbanFingerprint(_))
+        ));
+        assert!(ServerUserInput::try_from("/ban --for 1h").is_err());
+        assert!(ServerUserInput::try_from("/ban Alice --for forever").is_err());
+    }
+
+    #[test]
+    fn test_announce_global() {
+        let input = ServerUserInput::try_from("/announce Server restarting soon");
+        match input.unwrap() {
+            ServerUserInput::Announce { room, message } => {
+                assert_eq!(room, None);
+                assert_eq!(message, "Server restarting soon");
+            }
+            _ => panic!("Expected Announce variant"),
+        }
+    }
+
+    #[test]
+    fn test_announce_room() {
+        let input = ServerUserInput::try_from("/announce --room #ops Deploy at 5pm");
+        match input.unwrap() {
+            ServerUserInput::Announce { room, message } => {
+                assert_eq!(room, Some("#ops".to_string()));
+                assert_eq!(message, "Deploy at 5pm");
+            }
+            _ => panic!("Expected Announce variant"),
+        }
+    }
+
+    #[test]
+    fn test_announce_missing_message() {
+        assert!(ServerUserInput::try_from("/announce").is_err());
+        assert!(ServerUserInput::try_from("/announce --room").is_err());
+        assert!(ServerUserInput::try_from("/announce --room ops").is_err());
+    }
+
+    #[test]
+    fn test_announce_scheduled() {
+        let input = ServerUserInput::try_from(
+            "/announce --room ops --at \"02:00\" --every 1d Maintenance at 02:15",
+        );
+        match input.unwrap() {
+            ServerUserInput::ScheduleAnnouncement {
+                room,
+                message,
+                at,
+                every,
+            } => {
+                assert_eq!(room, Some("ops".to_string()));
+                assert_eq!(message, "Maintenance at 02:15");
+                assert_eq!(at, NaiveTime::from_hms_opt(2, 0, 0));
+                assert_eq!(every, Some(Duration::from_secs(86400)));
+            }
+            _ => panic!("Expected ScheduleAnnouncement variant"),
+        }
+        assert!(matches!(
+            ServerUserInput::try_from("/announce --every 6h Read the rules").unwrap(),
+            ServerUserInput::ScheduleAnnouncement { at: None, .. }
+        ));
+        assert!(ServerUserInput::try_from("/announce --at 25:00 hi").is_err());
+        assert!(ServerUserInput::try_from("/announce --every often hi").is_err());
+        assert!(ServerUserInput::try_from("/announce --every 6h").is_err());
+    }
+
+    #[test]
+    fn test_announce_list_and_cancel() {
+        assert!(matches!(
+            ServerUserInput::try_from("/announce --list").unwrap(),
+            ServerUserInput::ListScheduled
+        ));
+        assert!(matches!(
+            ServerUserInput::try_from("/announce --cancel 3").unwrap(),
+            ServerUserInput::CancelScheduled(3)
+        ));
+        assert!(ServerUserInput::try_from("/announce --cancel x").is_err());
+    }
+
+    #[test]
+    fn test_say_command() {
+        let input = ServerUserInput::try_from("/say ops hello team");
+        match input.unwrap() {
+            ServerUserInput::Say { room, message } => {
+                assert_eq!(room, "ops");
+                assert_eq!(message, "hello team");
+            }
+            _ => panic!("Expected Say variant"),
+        }
+        assert!(ServerUserInput::try_from("/say ops").is_err());
+    }
+
+    #[test]
+    fn test_channel_command() {
+        assert!(matches!(
+            ServerUserInput::try_from("/channel").unwrap(),
+            ServerUserInput::ChannelStats
+        ));
+        assert!(matches!(
+            ServerUserInput::try_from("/channel stats").unwrap(),
+            ServerUserInput::ChannelStats
+        ));
+        assert!(matches!(
+            ServerUserInput::try_from("/channel resize 4096").unwrap(),
+            ServerUserInput::ChannelResize(4096)
+        ));
+        assert!(ServerUserInput::try_from("/channel resize 0").is_err());
+        assert!(ServerUserInput::try_from("/channel resize 99999999").is_err());
+        assert!(ServerUserInput::try_from("/channel shrink").is_err());
+    }
+
+    #[test]
+    fn test_privacy_command() {
+        assert!(matches!(
+            ServerUserInput::try_from("/privacy").unwrap(),
+            ServerUserInput::Privacy(None)
+        ));
+        assert!(matches!(
+            ServerUserInput::try_from("/privacy off").unwrap(),
+            ServerUserInput::Privacy(Some(false))
+        ));
+        assert!(ServerUserInput::try_from("/privacy maybe").is_err());
+    }
+
+    #[test]
+    fn test_drain_command() {
+        assert!(matches!(
+            ServerUserInput::try_from("/drain").unwrap(),
+            ServerUserInput::Drain(None)
+        ));
+        assert!(matches!(
+            ServerUserInput::try_from("/drain 5").unwrap(),
+            ServerUserInput::Drain(Some(remaining)) if remaining == Duration::from_secs(300)
+        ));
+        assert!(matches!(
+            ServerUserInput::try_from("/drain cancel").unwrap(),
+            ServerUserInput::DrainCancel
+        ));
+        assert!(ServerUserInput::try_from("/drain 0").is_err());
+        assert!(ServerUserInput::try_from("/drain soon").is_err());
+        assert!(ServerUserInput::try_from("/drain 18446744073709551615").is_err());
+    }
+
+    #[test]
+    fn test_maintenance_command() {
+        match ServerUserInput::try_from("/maintenance set 02:00 1h Database upgrade").unwrap() {
+            ServerUserInput::MaintenanceSet {
+                start,
+                duration,
+                text,
+            } => {
+                assert_eq!(start, Start::At(NaiveTime::from_hms_opt(2, 0, 0).unwrap()));
+                assert_eq!(duration, Duration::from_secs(3600));
+                assert_eq!(text, "Database upgrade");
+            }
+            _ => panic!("Expected MaintenanceSet variant"),
+        }
+        assert!(matches!(
+            ServerUserInput::try_from("/maintenance set 30m 90m Reboot").unwrap(),
+            ServerUserInput::MaintenanceSet {
+                start: Start::In(_),
+                ..
+            }
+        ));
+        assert!(matches!(
+            ServerUserInput::try_from("/maintenance").unwrap(),
+            ServerUserInput::MaintenanceShow
+        ));
+        assert!(matches!(
+            ServerUserInput::try_from("/maintenance clear").unwrap(),
+            ServerUserInput::MaintenanceClear
+        ));
+        assert!(ServerUserInput::try_from("/maintenance set 02:00 1h").is_err());
+        assert!(ServerUserInput::try_from("/maintenance set later 1h Reboot").is_err());
+    }
+
+    #[test]
+    fn test_account_commands() {
+        match ServerUserInput::try_from("/register alice hunter22").unwrap() {
+            ServerUserInput::Register { username, password } => {
+                assert_eq!(username, "alice");
+                assert_eq!(password, "hunter22");
+            }
+            _ => panic!("Expected Register variant"),
+        }
+        assert!(ServerUserInput::try_from("/register alice").is_err());
+        assert!(matches!(
+            ServerUserInput::try_from("/unregister alice").unwrap(),
+            ServerUserInput::Unregister(name) if name == "alice"
+        ));
+        assert!(ServerUserInput::try_from("/unregister").is_err());
+        assert!(matches!(
+            ServerUserInput::try_from("/accounts").unwrap(),
+            ServerUserInput::ListAccounts
+        ));
+    }
+
+    #[test]
+    fn test_shell_command() {
+        let input = ServerUserInput::try_from(r#"/shell "tail -f alerts.log" --to ops"#).unwrap();
+        match input {
+            ServerUserInput::Shell {
+                command,
+                room,
+                sender,
+            } => {
+                assert_eq!(command, "tail -f alerts.log");
+                assert_eq!(room, "ops");
+                assert_eq!(sender, None);
+            }
+            _ => panic!("Expected Shell"),
+        }
+        assert!(matches!(
+            ServerUserInput::try_from(r#"/shell "df -h" --as disks --to ops"#),
+            Ok(ServerUserInput::Shell { sender: Some(ref name), .. }) if name == "disks"
+        ));
+        assert!(matches!(
+            ServerUserInput::try_from("/shell --list"),
+            Ok(ServerUserInput::ShellList)
+        ));
+        assert!(matches!(
+            ServerUserInput::try_from("/shell --stop 2"),
+            Ok(ServerUserInput::ShellStop(2))
+        ));
+
+        // Unquoted command, no room, unterminated quote, empty command
+        for invalid in [
+            "/shell tail -f alerts.log --to ops",
+            r#"/shell "tail -f alerts.log""#,
+            r#"/shell "tail -f alerts.log --to ops"#,
+            r#"/shell "" --to ops"#,
+            r#"/shell "uptime" --to"#,
+        ] {
+            assert!(ServerUserInput::try_from(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_token_command() {
+        match ServerUserInput::try_from(
+            "/token create bot:deploy --scopes send --rooms #ops,Builds",
+        ) {
+            Ok(ServerUserInput::CreateToken { bot, grant }) => {
+                assert_eq!(bot, "deploy");
+                assert_eq!(grant.scopes(), Scopes::parse("send").unwrap());
+                assert!(grant.allows_room("ops") && grant.allows_room("builds"));
+                assert!(!grant.allows_room("general"));
+            }
+            other => panic!("Expected CreateToken, got {:?}", other),
+        }
+        assert!(matches!(
+            ServerUserInput::try_from("/token create monitor"),
+            Ok(ServerUserInput::CreateToken { grant, .. }) if grant == Grant::default()
+        ));
+        assert!(matches!(
+            ServerUserInput::try_from("/token create auditor --scopes read,moderate,files"),
+            Ok(ServerUserInput::CreateToken { grant, .. })
+                if grant.scopes().to_string() == "read,moderate,file-transfer"
+        ));
+        assert!(matches!(
+            ServerUserInput::try_from("/token revoke bot:deploy"),
+            Ok(ServerUserInput::RevokeToken(ref bot)) if bot == "deploy"
+        ));
+        assert!(matches!(
+            ServerUserInput::try_from("/token"),
+            Ok(ServerUserInput::ListTokens)
+        ));
+
+        for invalid in [
+            "/token create",
+            "/token create deploy --scopes root",
+            "/token create deploy --rooms",
+            "/token revoke",
+            "/token delete deploy",
+        ] {
+            assert!(ServerUserInput::try_from(invalid).is_err(), "{}", invalid);
+        }
+    }
 }
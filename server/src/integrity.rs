@@ -0,0 +1,323 @@
+//! Startup integrity check for the state kept in the data directory. Before anything
+//! is loaded, each store's file is read and every line checked against the store's
+//! format (each store's `check_line`). A file that isn't text or has lines that don't
+//! parse - a write cut short by a crash, a disk error, a bad hand edit - is moved
+//! aside to `<file>.corrupt-<time>` and the server starts with that store empty,
+//! rather than refusing to start or loading half of it. With `--repair` the lines
+//! that do parse are kept instead: the original is still moved aside, and a file
+//! holding only its readable lines takes its place. An append-only log (room history)
+//! is always repaired that way - each line stands on its own, so one bad record is no
+//! reason to lose the rest. The room history database (`sqlite` feature) gets SQLite's
+//! own integrity check instead, and is set aside whole when it fails.
+
+use crate::accounts::{self, AccountStore};
+use crate::auth::bot_token::{self, TokenStore};
+use crate::blocks::{self, BlockList};
+use crate::history_writer::{self, Record};
+use crate::maintenance_window::{self, MaintenanceWindow};
+use crate::seen::{self, SeenLog};
+use chrono::{DateTime, Local};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// A file in the data directory and how to tell its lines are sound
+pub struct Store {
+    pub file: &'static str,
+    /// What's lost when it's emptied, for log messages
+    pub holds: &'static str,
+    check_line: fn(&str) -> bool,
+    /// Lines are records of their own: keep the readable ones even without --repair
+    append_only: bool,
+}
+
+pub const STORES: [Store; 6] = [
+    Store {
+        file: accounts::ACCOUNTS_FILE,
+        holds: "registered nicknames",
+        check_line: AccountStore::check_line,
+        append_only: false,
+    },
+    Store {
+        file: bot_token::TOKENS_FILE,
+        holds: "bot tokens",
+        check_line: TokenStore::check_line,
+        append_only: false,
+    },
+    Store {
+        file: blocks::BLOCKS_FILE,
+        holds: "block lists",
+        check_line: BlockList::check_line,
+        append_only: false,
+    },
+    Store {
+        file: seen::SEEN_FILE,
+        holds: "last-seen times",
+        check_line: SeenLog::check_line,
+        append_only: false,
+    },
+    Store {
+        file: maintenance_window::MAINTENANCE_FILE,
+        holds: "planned maintenance",
+        check_line: MaintenanceWindow::check_line,
+        append_only: false,
+    },
+    Store {
+        file: history_writer::HISTORY_FILE,
+        holds: "room history",
+        check_line: |line| Record::decode(line).is_some(),
+        append_only: true,
+    },
+];
+
+#[derive(Debug, PartialEq)]
+pub enum Outcome {
+    /// There's no file yet
+    Missing,
+    Intact,
+    /// Moved aside; the store starts empty
+    Quarantined {
+        moved_to: PathBuf,
+        bad_lines: usize,
+    },
+    /// A database that failed its integrity check, moved aside (even with --repair)
+    #[cfg(feature = "sqlite")]
+    Damaged {
+        moved_to: PathBuf,
+        problem: String,
+    },
+    /// Moved aside, and replaced by its readable lines (--repair)
+    Repaired {
+        moved_to: PathBuf,
+        kept: usize,
+        dropped: usize,
+    },
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Outcome::Missing => write!(f, "not created yet"),
+            Outcome::Intact => write!(f, "intact"),
+            Outcome::Quarantined {
+                moved_to,
+                bad_lines,
+            } => write!(
+                f,
+                "{} unreadable line(s) - moved to {}",
+                bad_lines,
+                moved_to.display()
+            ),
+            #[cfg(feature = "sqlite")]
+            Outcome::Damaged { moved_to, problem } => {
+                write!(f, "{} - moved to {}", problem, moved_to.display())
+            }
+            Outcome::Repaired {
+                moved_to,
+                kept,
+                dropped,
+            } => write!(
+                f,
+                "kept {} line(s), dropped {} unreadable one(s) - the original is in {}",
+                kept,
+                dropped,
+                moved_to.display()
+            ),
+        }
+    }
+}
+
+/// Check every store in `data_dir`, quarantining (or with `repair`, repairing) the
+/// corrupt ones. Only errors moving or rewriting a file are returned.
+pub fn check(data_dir: &Path, repair: bool) -> io::Result<Vec<(&'static Store, Outcome)>> {
+    let now = Local::now();
+    STORES
+        .iter()
+        .map(|store| {
+            Ok((
+                store,
+                check_file(&data_dir.join(store.file), store, repair, now)?,
+            ))
+        })
+        .collect()
+}
+
+/// Check the room history database at `path`, setting it aside (with its WAL files)
+/// if SQLite finds it damaged or it isn't a database at all
+#[cfg(feature = "sqlite")]
+pub fn check_database(path: &Path) -> io::Result<Outcome> {
+    if !path.exists() {
+        return Ok(Outcome::Missing);
+    }
+    let Some(problem) = crate::history_db::problem(path) else {
+        return Ok(Outcome::Intact);
+    };
+    let moved_to = quarantine_path(path, Local::now());
+    fs::rename(path, &moved_to)?;
+    for suffix in ["-wal", "-shm"] {
+        let companion = PathBuf::from(format!("{}{}", path.display(), suffix));
+        if companion.exists() {
+            fs::rename(&companion, format!("{}{}", moved_to.display(), suffix))?;
+        }
+    }
+    Ok(Outcome::Damaged { moved_to, problem })
+}
+
+fn quarantine_path(path: &Path, now: DateTime<Local>) -> PathBuf {
+    PathBuf::from(format!(
+        "{}.corrupt-{}",
+        path.display(),
+        now.format("%Y%m%d-%H%M%S")
+    ))
+}
+
+fn check_file(
+    path: &Path,
+    store: &Store,
+    repair: bool,
+    now: DateTime<Local>,
+) -> io::Result<Outcome> {
+    let bytes = match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Outcome::Missing),
+        Err(e) => return Err(e),
+    };
+    let is_text = std::str::from_utf8(&bytes).is_ok();
+    // Undecodable bytes become U+FFFD, which no store writes, so those lines are bad too
+    let contents = String::from_utf8_lossy(&bytes);
+    let (good, bad): (Vec<&str>, Vec<&str>) = contents
+        .lines()
+        .filter(|line| !line.is_empty())
+        .partition(|line| !line.contains('\u{FFFD}') && (store.check_line)(line));
+    if is_text && bad.is_empty() {
+        return Ok(Outcome::Intact);
+    }
+
+    let moved_to = quarantine_path(path, now);
+    fs::rename(path, &moved_to)?;
+    if !repair && !store.append_only {
+        return Ok(Outcome::Quarantined {
+            moved_to,
+            bad_lines: bad.len().max(1),
+        });
+    }
+    let kept: String = good.iter().map(|line| format!("{}\n", line)).collect();
+    fs::write(path, kept)?;
+    // Accounts and tokens hold password hashes - keep them as private as the original
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = fs::metadata(&moved_to)?.permissions().mode();
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    }
+    Ok(Outcome::Repaired {
+        moved_to,
+        kept: good.len(),
+        dropped: bad.len().max(1),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_corrupt_store_is_quarantined_or_repaired() {
+        let dir = std::env::temp_dir().join(format!("rust_chat_integrity_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let blocks = dir.join(blocks::BLOCKS_FILE);
+        let seen = dir.join(seen::SEEN_FILE);
+        fs::write(&blocks, "alice\tbob\ncarol\tdave\n").unwrap();
+        // A save cut short: the last line lost its fields, then zeroed bytes
+        fs::write(&seen, b"alice\t1700000000\t\nbob\t17000\0\0\0").unwrap();
+
+        let outcomes = check(&dir, false).unwrap();
+        let outcome = |file| {
+            &outcomes
+                .iter()
+                .find(|(store, _)| store.file == file)
+                .unwrap()
+                .1
+        };
+        assert_eq!(outcome(blocks::BLOCKS_FILE), &Outcome::Intact);
+        assert_eq!(outcome(accounts::ACCOUNTS_FILE), &Outcome::Missing);
+        let Outcome::Quarantined {
+            moved_to,
+            bad_lines,
+        } = outcome(seen::SEEN_FILE)
+        else {
+            panic!("seen.tsv wasn't quarantined");
+        };
+        assert_eq!(*bad_lines, 1);
+        assert!(!seen.exists());
+        assert!(
+            SeenLog::load(Some(seen.clone()))
+                .unwrap()
+                .last_connected("alice")
+                .is_none()
+        );
+
+        // --repair keeps what it can
+        fs::rename(moved_to, &seen).unwrap();
+        let outcomes = check(&dir, true).unwrap();
+        assert!(matches!(
+            outcomes[3].1,
+            Outcome::Repaired {
+                kept: 1,
+                dropped: 1,
+                ..
+            }
+        ));
+        let repaired = SeenLog::load(Some(seen.clone())).unwrap();
+        assert!(repaired.last_connected("alice").is_some());
+        assert!(repaired.last_connected("bob").is_none());
+        assert!(check(&dir, false).unwrap()[3].1 == Outcome::Intact);
+
+        // One bad record doesn't cost the rest of the history
+        let history = dir.join(history_writer::HISTORY_FILE);
+        let record = Record::Clear {
+            at: Local::now(),
+            room: "ops".to_string(),
+        };
+        fs::write(&history, format!("{}\nM|garbage\n", record.encode())).unwrap();
+        assert!(matches!(
+            check(&dir, false).unwrap()[5].1,
+            Outcome::Repaired {
+                kept: 1,
+                dropped: 1,
+                ..
+            }
+        ));
+        assert_eq!(
+            fs::read_to_string(&history).unwrap(),
+            format!("{}\n", record.encode())
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn test_damaged_database_is_set_aside() {
+        let dir =
+            std::env::temp_dir().join(format!("rust_chat_integrity_db_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("history.db");
+        assert!(matches!(check_database(&path).unwrap(), Outcome::Missing));
+        fs::write(
+            &path,
+            "not a database, just a text file left in its place\n",
+        )
+        .unwrap();
+        fs::write(dir.join("history.db-wal"), "").unwrap();
+        let Outcome::Damaged { moved_to, .. } = check_database(&path).unwrap() else {
+            panic!("expected the database to be set aside");
+        };
+        assert!(!path.exists() && moved_to.exists());
+        assert!(!dir.join("history.db-wal").exists());
+        assert!(PathBuf::from(format!("{}-wal", moved_to.display())).exists());
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
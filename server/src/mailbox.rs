@@ -0,0 +1,147 @@
+//! Direct messages to a registered nickname whose owner is offline, held until they
+//! next log in. Kept in memory only, so a restart loses them. They count against the
+//! memory cap (see crate::memory), which evicts the oldest under pressure.
+
+use std::collections::{HashMap, VecDeque};
+
+/// Most messages held per user; the oldest make way for new ones
+pub const MAX_HELD_PER_USER: usize = 50;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HeldMessage {
+    pub sender: String,
+    /// Message text, with its signature trailer if it had one
+    pub text: String,
+    /// Messages are numbered as they're held, to evict the oldest first
+    number: u64,
+}
+
+impl HeldMessage {
+    /// Approximate memory used by the message, for the server's memory cap
+    pub fn size(&self) -> usize {
+        std::mem::size_of::<Self>() + self.sender.len() + self.text.len()
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Mailbox {
+    held: HashMap<String, VecDeque<HeldMessage>>,
+    /// Sum of the held messages' sizes
+    bytes: usize,
+    /// Number of the next message held
+    next: u64,
+}
+
+impl Mailbox {
+    /// Hold a message for `recipient`. Returns false if it pushed out an older one.
+    pub fn hold(&mut self, recipient: &str, sender: &str, text: &str) -> bool {
+        let messages = self.held.entry(recipient.to_string()).or_default();
+        let full = messages.len() >= MAX_HELD_PER_USER;
+        if full && let Some(dropped) = messages.pop_front() {
+            self.bytes -= dropped.size();
+        }
+        let message = HeldMessage {
+            sender: sender.to_string(),
+            text: text.to_string(),
+            number: self.next,
+        };
+        self.next += 1;
+        self.bytes += message.size();
+        messages.push_back(message);
+        !full
+    }
+
+    /// Everything held for `user`, oldest first, leaving nothing behind
+    pub fn take(&mut self, user: &str) -> Vec<HeldMessage> {
+        let held = self.held.remove(user).map(Vec::from).unwrap_or_default();
+        self.bytes -= held.iter().map(HeldMessage::size).sum::<usize>();
+        held
+    }
+
+    /// The nickname was released: its messages have nobody to go to
+    pub fn discard(&mut self, user: &str) {
+        self.take(user);
+    }
+
+    /// Approximate memory used by the held messages
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Number of messages held across all users
+    pub fn message_count(&self) -> usize {
+        self.held.values().map(VecDeque::len).sum()
+    }
+
+    /// Free at least `bytes` by dropping the oldest messages held for anyone (used
+    /// under memory pressure). Returns the number removed.
+    pub fn evict_oldest(&mut self, bytes: usize) -> usize {
+        let mut freed = 0;
+        let mut removed = 0;
+        while freed < bytes {
+            let oldest = self
+                .held
+                .iter_mut()
+                .filter(|(_, messages)| !messages.is_empty())
+                .min_by_key(|(_, messages)| messages.front().map(|message| message.number));
+            let Some((user, messages)) = oldest else {
+                break;
+            };
+            let user = user.clone();
+            if let Some(message) = messages.pop_front() {
+                freed += message.size();
+                removed += 1;
+            }
+            if messages.is_empty() {
+                self.held.remove(&user);
+            }
+        }
+        self.bytes -= freed;
+        removed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_held_until_taken() {
+        let mut mailbox = Mailbox::default();
+        assert!(mailbox.hold("alice", "bob", "first"));
+        assert!(mailbox.hold("alice", "carol", "second"));
+        let held = mailbox.take("alice");
+        assert_eq!(held.len(), 2);
+        assert_eq!(held[0].sender, "bob");
+        assert!(mailbox.take("alice").is_empty());
+
+        for i in 0..MAX_HELD_PER_USER {
+            assert!(mailbox.hold("dave", "bob", &i.to_string()));
+        }
+        assert!(!mailbox.hold("dave", "bob", "one more"));
+        let held = mailbox.take("dave");
+        assert_eq!(held.len(), MAX_HELD_PER_USER);
+        assert_eq!(held[0].text, "1");
+        assert_eq!(held.last().unwrap().text, "one more");
+        assert_eq!(mailbox.bytes(), 0);
+    }
+
+    #[test]
+    fn test_evict_oldest_across_users() {
+        let mut mailbox = Mailbox::default();
+        mailbox.hold("alice", "bob", "first");
+        mailbox.hold("carol", "bob", "second");
+        mailbox.hold("alice", "bob", "third");
+        let used = mailbox.bytes();
+        assert!(used > 0);
+
+        assert_eq!(mailbox.evict_oldest(1), 1);
+        assert_eq!(mailbox.evict_oldest(1), 1);
+        let held = mailbox.take("alice");
+        assert_eq!(held.len(), 1);
+        assert_eq!(held[0].text, "third");
+        assert!(mailbox.take("carol").is_empty());
+        assert_eq!(mailbox.bytes(), 0);
+        assert_eq!(mailbox.evict_oldest(used), 0);
+    }
+}
@@ -1,76 +1,270 @@
-use rustls::ServerConfig;
+use rustls::server::danger::ClientCertVerifier;
 use rustls_pemfile::{certs, private_key};
 use shared::commands::server as commands;
-use shared::logger;
-use shared::message::ChatMessage;
-use std::collections::{HashMap, HashSet};
-use std::fs::File;
+use shared::error::ChatError;
+use shared::message::{ChatMessage, MessageTypes};
+use shared::sender_class::{self, SenderClass};
+use shared::socket::SocketOptions;
+use shared::trace::Tracer;
+use std::fs::{self, File};
 use std::io::BufReader;
-use std::net::{IpAddr, SocketAddr};
+use std::net::SocketAddr;
 use std::path::Path;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 use std::{env, io};
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::TcpListener;
-use tokio::sync::{RwLock, broadcast};
+use tokio::sync::watch;
 use tokio_rustls::TlsAcceptor;
+use tracing::{Instrument, Span, error, field, info, info_span, warn};
 
+mod accounts;
+mod action_queue;
+mod audit;
+mod auth;
+mod bandwidth;
+mod bans;
+mod blocks;
+mod channel;
 mod completer;
+mod config;
+mod data_file;
+mod drain;
+mod fanout;
+mod history;
+#[cfg(feature = "sqlite")]
+mod history_db;
+mod history_writer;
 mod input;
+mod integrity;
+mod mailbox;
+mod maintenance_window;
+mod memory;
+mod presence;
+mod privacy;
 mod readline_helper;
+mod reserved;
+mod rooms;
+mod schedule;
+mod scopes;
+mod seen;
+mod self_signed;
+mod sessions;
+mod shell;
+mod state;
+mod stats_history;
+mod syslog;
+mod telemetry;
+mod translate;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+mod uring;
 mod user_connection;
+mod violations;
+mod waiting_room;
+use accounts::{ADMIN_NAME, AccountError, AccountStore, HashedPassword};
+use action_queue::{ActionQueue, KICK_BATCH_SIZE, Progress, STEP_INTERVAL, Step};
+use audit::AuditLog;
+use auth::AuthProvider;
+use auth::bot_token::{self, Grant, NewToken, TokenError, TokenStore};
+use auth::client_cert::{self, ClientCertMap};
+use blocks::BlockList;
+use chrono::{DateTime, Local, NaiveTime, TimeDelta};
+use config::{ClientCertSettings, ServerConfig, SyslogSettings};
+use drain::Drain;
+use history::RoomHistory;
+use history_writer::{HistoryWriter, Record};
 use input::ServerUserInput;
-use user_connection::{UserConnection, UserConnectionError};
+use integrity::Outcome;
+use ip_network::IpNetwork;
+use maintenance_window::{Due, MaintenanceWindow, Window};
+use reserved::Namespace;
+use schedule::Schedule;
+use scopes::Scope;
+use seen::SeenLog;
+use shell::ShellRelays;
+use state::{ConnectionId, SERVER_ORIGIN, ServerSettings, ServerState};
+use stats_history::StatsHistory;
+use telemetry::{announcement, chat, success};
+use user_connection::UserConnection;
+
+/// How often the maintenance task runs
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(60);
+/// How long connections get to tell their clients the server is shutting down
+const SHUTDOWN_GRACE: Duration = Duration::from_millis(300);
 
 #[derive(Debug, Clone)]
 pub enum ServerCommand {
-    Kick(String),
-    Rename { old_name: String, new_name: String },
-    Ban(IpAddr),
+    /// Disconnect these users (one command for /kickall, so the channel can't overflow)
+    Kick {
+        usernames: Vec<String>,
+        /// How long before they can rejoin (None = at once)
+        cooldown: Option<Duration>,
+    },
+    Rename {
+        old_name: String,
+        new_name: String,
+    },
+    /// Disconnect every connection from inside `network`
+    Ban {
+        network: IpNetwork,
+        /// How long the ban lasts (None = permanent)
+        duration: Option<Duration>,
+    },
+    /// Disconnect every connection that sent fingerprint `id`
+    BanFingerprint {
+        id: String,
+        /// How long the ban lasts (None = permanent)
+        duration: Option<Duration>,
+    },
     /// Session taken over by a new connection - old connection should disconnect silently
     SessionTakeover(String),
+    /// The owner of a registered nickname logged in (from `owner`) while a guest was using it.
+    /// Server state has already been moved to `new_name`; the guest's connection just follows.
+    NicknameReclaimed {
+        old_name: String,
+        new_name: String,
+        owner: SocketAddr,
+    },
+    /// The server is exiting: tell every connection before it goes
+    Shutdown,
+}
+
+/// A bulk moderation command waiting for the operator to confirm it
+enum PendingAction {
+    KickAll {
+        room: Option<String>,
+        usernames: Vec<String>,
+    },
+    MuteAll {
+        room: String,
+        duration: Duration,
+    },
+    ClearHistory {
+        room: String,
+    },
 }
 
 pub struct ChatServer {
     listener: TcpListener,
-    broadcaster: broadcast::Sender<(ChatMessage, SocketAddr)>,
-    server_commands: broadcast::Sender<ServerCommand>,
-    connected_clients: Arc<RwLock<HashSet<String>>>,
-    /// Maps username to their IP address
-    user_ips: Arc<RwLock<HashMap<String, IpAddr>>>,
-    /// Maps username to their status message
-    user_statuses: Arc<RwLock<HashMap<String, String>>>,
-    /// Maps username to their session token (for reconnection validation)
-    user_sessions: Arc<RwLock<HashMap<String, String>>>,
-    /// Set of banned IP addresses
-    banned_ips: Arc<RwLock<HashSet<IpAddr>>>,
+    state: ServerState,
+    /// Users allowed in the chat at once
     max_clients: usize,
+    /// Sockets allowed at once: the chat plus its waiting room
+    max_connections: usize,
     active_connections: Arc<AtomicUsize>,
     tls_acceptor: Option<TlsAcceptor>,
+    /// Set while the server is draining connections ahead of a shutdown
+    drain: Option<Drain>,
+    /// Announcements waiting to be sent at a set time
+    schedule: Schedule,
+    /// Bulk moderation command to run if the next console line is "yes"
+    pending_action: Option<PendingAction>,
+    /// Paces kicks and announcements that reach many connections
+    actions: ActionQueue,
+    /// Commands whose output is being posted to rooms (/shell)
+    shells: ShellRelays,
+    /// Samples of connections, messages and errors for /stats --graph
+    stats_history: StatsHistory,
+    /// Applied to every accepted socket
+    socket_options: SocketOptions,
+}
+
+/// Handle one client until it disconnects, over TLS if configured. `T` is the
+/// socket's transport (tokio's, or io_uring's with that backend).
+async fn serve_connection<T: AsyncRead + AsyncWrite + Unpin>(
+    socket: T,
+    addr: SocketAddr,
+    state: ServerState,
+    tls_acceptor: Option<TlsAcceptor>,
+    active_connections: Arc<AtomicUsize>,
+) {
+    // Numbered as soon as it's accepted, so even a failed TLS handshake is logged with
+    // the connection's ID
+    let id = ConnectionId::next();
+    let span = info_span!("connection", id = %id, %addr, user = field::Empty);
+    serve_accepted(socket, addr, id, span.clone(), state, tls_acceptor)
+        .instrument(span)
+        .await;
+
+    // Decrement connection count when done
+    active_connections.fetch_sub(1, Ordering::Relaxed);
+}
+
+async fn serve_accepted<T: AsyncRead + AsyncWrite + Unpin>(
+    socket: T,
+    addr: SocketAddr,
+    id: ConnectionId,
+    span: Span,
+    state: ServerState,
+    tls_acceptor: Option<TlsAcceptor>,
+) {
+    // Wrap socket in TLS if configured
+    let result = if let Some(acceptor) = tls_acceptor {
+        // Add timeout to TLS handshake to prevent hanging connections
+        match tokio::time::timeout(std::time::Duration::from_secs(30), acceptor.accept(socket))
+            .await
+        {
+            Ok(Ok(tls_stream)) => {
+                let mut client_connection =
+                    UserConnection::new_tls(tls_stream, addr, id, span, state);
+                client_connection.handle().await
+            }
+            Ok(Err(e)) => {
+                error!("TLS handshake failed for {}: {:?}", addr, e);
+                Err(ChatError::IoError(io::Error::other("TLS handshake failed")))
+            }
+            Err(_) => {
+                error!("TLS handshake timed out for {}", addr);
+                Err(ChatError::IoError(io::Error::other(
+                    "TLS handshake timed out",
+                )))
+            }
+        }
+    } else {
+        let mut client_connection = UserConnection::new(socket, addr, id, span, state);
+        client_connection.handle().await
+    };
+
+    if let Err(e) = result {
+        error!("Error handling client {}: {:?}", addr, e);
+    }
+    info!("Connection from {} closed", addr);
 }
 
 impl ChatServer {
     async fn new(
         bind_addr: &str,
-        max_clients: usize,
+        settings: ServerSettings,
+        blocks: BlockList,
+        accounts: AccountStore,
+        tokens: TokenStore,
+        seen: SeenLog,
         tls_acceptor: Option<TlsAcceptor>,
     ) -> io::Result<Self> {
-        let (tx, _rx) = broadcast::channel(max_clients * 16); // Allow message buffering
-        let (cmd_tx, _cmd_rx) = broadcast::channel(100); // Server commands channel
         let listener = TcpListener::bind(bind_addr).await?;
+        let max_clients = settings.max_clients;
+        let max_connections = settings.max_clients + settings.waiting_room_size;
+        let socket_options = settings.socket_options;
+
+        let state = ServerState::new(settings, blocks, accounts, tokens, seen);
+        let actions = ActionQueue::start(state.clone(), STEP_INTERVAL);
 
         Ok(ChatServer {
             listener,
-            broadcaster: tx,
-            server_commands: cmd_tx,
-            connected_clients: Arc::new(RwLock::new(HashSet::new())),
-            user_ips: Arc::new(RwLock::new(HashMap::new())),
-            user_statuses: Arc::new(RwLock::new(HashMap::new())),
-            user_sessions: Arc::new(RwLock::new(HashMap::new())),
-            banned_ips: Arc::new(RwLock::new(HashSet::new())),
+            state,
             max_clients,
+            max_connections,
             active_connections: Arc::new(AtomicUsize::new(0)),
             tls_acceptor,
+            drain: None,
+            schedule: Schedule::new(),
+            pending_action: None,
+            actions,
+            shells: ShellRelays::new(),
+            stats_history: StatsHistory::default(),
+            socket_options,
         })
     }
 
@@ -79,89 +273,98 @@ impl ChatServer {
         let mut readline_rx = readline_helper::spawn_readline_handler();
 
         if readline_rx.is_none() {
-            logger::log_info("Running in non-interactive mode (no TTY)");
-            logger::log_info("Server commands disabled - use docker exec for admin tasks");
+            info!("Running in non-interactive mode (no TTY)");
+            info!("Server commands disabled - use docker exec for admin tasks");
         }
 
+        // Drives drain countdown reminders and the final shutdown check
+        let mut drain_tick = tokio::time::interval(Duration::from_secs(1));
+        // Periodic housekeeping (expiring room history)
+        let mut maintenance_tick = tokio::time::interval(MAINTENANCE_INTERVAL);
+        let mut stats_tick = tokio::time::interval(stats_history::SAMPLE_INTERVAL);
+
         loop {
+            let next_announcement = self.schedule.next_due();
+            let next_maintenance = self.state.maintenance.read().await.next_due();
             tokio::select! {
                 // Handle incoming client connections
                 result = self.listener.accept() => {
                     match result {
                         Ok((socket, addr)) => {
-                            // Check if IP is banned
-                            let banned = self.banned_ips.read().await;
-                            if banned.contains(&addr.ip()) {
-                                logger::log_warning(&format!(
-                                    "Rejected connection from banned IP: {}",
-                                    addr.ip()
-                                ));
+                            // Refuse new connections while draining
+                            if self.drain.is_some() {
+                                warn!("Rejected connection from {} (server is draining)",
+                                    addr);
                                 drop(socket);
                                 continue;
                             }
-                            drop(banned);
 
-                            // Check connection limit
+                            // Check if IP is banned or was kicked recently
+                            let bans = self.state.bans.read().await;
+                            let now = Instant::now();
+                            if bans.is_banned(addr.ip(), now) {
+                                warn!("Rejected connection from banned IP: {}",
+                                    addr.ip());
+                                drop(socket);
+                                continue;
+                            }
+                            if let Some(left) = bans.ip_cooldown(addr.ip(), now) {
+                                warn!("Rejected connection from {} (kicked, {} left)",
+                                    addr.ip(),
+                                    schedule::format_interval(left));
+                                drop(socket);
+                                continue;
+                            }
+                            drop(bans);
+
+                            if let Err(e) = self.socket_options.apply(&socket) {
+                                warn!("Could not tune the socket for {}: {}", addr, e);
+                            }
+
+                            // Check connection limit (joins past max_clients wait in the waiting room)
                             let current_connections = self.active_connections.load(Ordering::Relaxed);
-                            if current_connections >= self.max_clients {
-                                logger::log_warning(&format!(
-                                    "Connection limit reached ({}/{}), rejecting connection from {}",
-                                    current_connections, self.max_clients, addr
-                                ));
+                            if current_connections >= self.max_connections {
+                                warn!("Connection limit reached ({}/{}), rejecting connection from {}",
+                                    current_connections, self.max_connections, addr);
                                 continue;
                             }
 
                             // Increment connection count
                             self.active_connections.fetch_add(1, Ordering::Relaxed);
 
-                            let tx_clone = self.broadcaster.clone();
-                            let cmd_tx_clone = self.server_commands.clone();
+                            let state = self.state.clone();
                             let active_connections_clone = self.active_connections.clone();
                             let tls_acceptor = self.tls_acceptor.clone();
-                            let connected_clients = self.connected_clients.clone();
-                            let user_ips = self.user_ips.clone();
-                            let user_statuses = self.user_statuses.clone();
-                            let user_sessions = self.user_sessions.clone();
-
-                            tokio::spawn(async move {
-                                // Wrap socket in TLS if configured
-                                let result = if let Some(acceptor) = tls_acceptor {
-                                    // Add timeout to TLS handshake to prevent hanging connections
-                                    match tokio::time::timeout(
-                                        std::time::Duration::from_secs(30),
-                                        acceptor.accept(socket)
-                                    ).await {
-                                        Ok(Ok(tls_stream)) => {
-                                            let mut client_connection =
-                                                UserConnection::new_tls(tls_stream, addr, tx_clone, cmd_tx_clone, connected_clients, user_ips, user_statuses, user_sessions);
-                                            client_connection.handle().await
-                                        }
-                                        Ok(Err(e)) => {
-                                            logger::log_error(&format!("TLS handshake failed for {}: {:?}", addr, e));
-                                            Err(UserConnectionError::IoError(io::Error::other("TLS handshake failed")))
-                                        }
-                                        Err(_) => {
-                                            logger::log_error(&format!("TLS handshake timed out for {}", addr));
-                                            Err(UserConnectionError::IoError(io::Error::other("TLS handshake timed out")))
-                                        }
-                                    }
-                                } else {
-                                    let mut client_connection =
-                                        UserConnection::new(socket, addr, tx_clone, cmd_tx_clone, connected_clients, user_ips, user_statuses, user_sessions);
-                                    client_connection.handle().await
-                                };
 
-                                if let Err(e) = result {
-                                    logger::log_error(&format!("Error handling client {}: {:?}", addr, e));
+                            #[cfg(all(feature = "io-uring", target_os = "linux"))]
+                            if uring::active() {
+                                match uring::UringStream::from_tokio(socket) {
+                                    Ok(stream) => {
+                                        tokio_uring::spawn(serve_connection(
+                                            stream,
+                                            addr,
+                                            state,
+                                            tls_acceptor,
+                                            active_connections_clone,
+                                        ));
+                                    }
+                                    Err(e) => {
+                                        error!("Failed to set up io_uring for {}: {}", addr, e);
+                                        self.active_connections.fetch_sub(1, Ordering::Relaxed);
+                                    }
                                 }
-
-                                // Decrement connection count when done
-                                active_connections_clone.fetch_sub(1, Ordering::Relaxed);
-                                logger::log_info(&format!("Connection from {} closed", addr));
-                            });
+                                continue;
+                            }
+                            tokio::spawn(serve_connection(
+                                socket,
+                                addr,
+                                state,
+                                tls_acceptor,
+                                active_connections_clone,
+                            ));
                         }
                         Err(e) => {
-                            logger::log_error(&format!("Failed to accept connection: {:?}", e));
+                            error!("Failed to accept connection: {:?}", e);
                         }
                     }
                 }
@@ -173,107 +376,622 @@ impl ChatServer {
                     }
                 } => {
                     match line {
+                        Some(input_line) if self.pending_action.is_some() => {
+                            self.handle_confirmation(&input_line).await;
+                        }
                         Some(input_line) => {
                             match ServerUserInput::try_from(input_line.as_str()) {
                                 Ok(ServerUserInput::Quit) => {
-                                    logger::log_info("Server shutting down...");
+                                    self.shut_down().await;
                                     return Ok(());
                                 }
                                 Ok(ServerUserInput::ListUsers) => {
                                     self.handle_list_users().await;
                                 }
-                                Ok(ServerUserInput::Kick(username)) => {
-                                    self.handle_kick(username).await;
+                                Ok(ServerUserInput::Whois(username)) => {
+                                    self.handle_whois(username).await;
+                                }
+                                Ok(ServerUserInput::Seen(username)) => {
+                                    self.handle_seen(username).await;
+                                }
+                                Ok(ServerUserInput::Kick { username, cooldown }) => {
+                                    self.handle_kick(username, cooldown).await;
+                                }
+                                Ok(ServerUserInput::KickAll { room }) => {
+                                    self.handle_kickall(room).await;
+                                }
+                                Ok(ServerUserInput::MuteAll { room, duration }) => {
+                                    self.handle_muteall(room, duration).await;
+                                }
+                                Ok(ServerUserInput::ClearHistory(room)) => {
+                                    self.handle_clear_history(room).await;
                                 }
                                 Ok(ServerUserInput::Rename { old_name, new_name }) => {
                                     self.handle_rename(old_name, new_name).await;
                                 }
-                                Ok(ServerUserInput::Ban(username)) => {
-                                    self.handle_ban_user(username).await;
+                                Ok(ServerUserInput::Ban { username, duration }) => {
+                                    self.handle_ban_user(username, duration).await;
                                 }
-                                Ok(ServerUserInput::BanIp(ip)) => {
-                                    self.handle_ban_ip(ip).await;
+                                Ok(ServerUserInput::BanIp { network, duration }) => {
+                                    self.handle_ban_ip(network, duration).await;
                                 }
                                 Ok(ServerUserInput::Unban(ip)) => {
                                     self.handle_unban(ip).await;
                                 }
+                                Ok(ServerUserInput::BanFingerprint { id, duration }) => {
+                                    self.handle_ban_fingerprint(id, duration).await;
+                                }
+                                Ok(ServerUserInput::UnbanFingerprint(id)) => {
+                                    self.handle_unban_fingerprint(id).await;
+                                }
                                 Ok(ServerUserInput::BanList) => {
                                     self.handle_banlist().await;
                                 }
+                                Ok(ServerUserInput::Register { username, password }) => {
+                                    self.handle_register(username, password).await;
+                                }
+                                Ok(ServerUserInput::Unregister(username)) => {
+                                    self.handle_unregister(username).await;
+                                }
+                                Ok(ServerUserInput::ListAccounts) => {
+                                    self.handle_list_accounts().await;
+                                }
+                                Ok(ServerUserInput::ListTokens) => {
+                                    self.handle_list_tokens().await;
+                                }
+                                Ok(ServerUserInput::CreateToken { bot, grant }) => {
+                                    self.handle_create_token(bot, grant).await;
+                                }
+                                Ok(ServerUserInput::RevokeToken(bot)) => {
+                                    self.handle_revoke_token(bot).await;
+                                }
+                                Ok(ServerUserInput::Announce { room, message }) => {
+                                    self.handle_announce(room, message).await;
+                                }
+                                Ok(ServerUserInput::ScheduleAnnouncement { room, message, at, every }) => {
+                                    self.handle_schedule_announcement(room, message, at, every);
+                                }
+                                Ok(ServerUserInput::ListScheduled) => {
+                                    self.handle_list_scheduled();
+                                }
+                                Ok(ServerUserInput::CancelScheduled(id)) => {
+                                    self.handle_cancel_scheduled(id);
+                                }
+                                Ok(ServerUserInput::Say { room, message }) => {
+                                    self.handle_say(room, message).await;
+                                }
+                                Ok(ServerUserInput::Shell { command, room, sender }) => {
+                                    self.handle_shell(command, room, sender).await;
+                                }
+                                Ok(ServerUserInput::ShellList) => {
+                                    self.handle_shell_list();
+                                }
+                                Ok(ServerUserInput::ShellStop(id)) => {
+                                    self.handle_shell_stop(id);
+                                }
+                                Ok(ServerUserInput::Stats) => {
+                                    self.handle_stats().await;
+                                }
+                                Ok(ServerUserInput::StatsGraph(span)) => {
+                                    self.handle_stats_graph(span);
+                                }
+                                Ok(ServerUserInput::ChannelStats) => {
+                                    self.handle_channel_stats();
+                                }
+                                Ok(ServerUserInput::ChannelResize(capacity)) => {
+                                    self.handle_channel_resize(capacity);
+                                }
+                                Ok(ServerUserInput::Privacy(private)) => {
+                                    self.handle_privacy(private);
+                                }
+                                Ok(ServerUserInput::Drain(remaining)) => {
+                                    self.handle_drain(remaining);
+                                }
+                                Ok(ServerUserInput::DrainCancel) => {
+                                    self.handle_drain_cancel();
+                                }
+                                Ok(ServerUserInput::MaintenanceShow) => {
+                                    self.handle_maintenance_show().await;
+                                }
+                                Ok(ServerUserInput::MaintenanceSet { start, duration, text }) => {
+                                    self.handle_maintenance_set(start, duration, text).await;
+                                }
+                                Ok(ServerUserInput::MaintenanceClear) => {
+                                    self.handle_maintenance_clear().await;
+                                }
                                 Ok(ServerUserInput::Help) => {
                                     self.handle_help();
                                 }
                                 Err(_) => {
-                                    logger::log_error("Invalid command. Type /help for available commands.");
+                                    error!("Invalid command. Type /help for available commands.");
                                 }
                             }
                         }
                         None => {
                             // EOF from readline
-                            logger::log_info("Server shutting down...");
+                            self.shut_down().await;
                             return Ok(());
                         }
                     }
                 }
+                // Send scheduled announcements once they're due
+                _ = sleep_until(next_announcement) => {
+                    self.send_scheduled_announcements().await;
+                }
+                // Remind users of planned maintenance and drain when it starts
+                _ = sleep_until(next_maintenance) => {
+                    self.check_maintenance_window().await;
+                }
+                _ = maintenance_tick.tick() => {
+                    self.run_maintenance().await;
+                }
+                _ = stats_tick.tick() => {
+                    self.stats_history.record(
+                        self.active_connections.load(Ordering::Relaxed),
+                        &self.state.counters,
+                    );
+                }
+                // Announce the countdown and shut down once draining is complete
+                _ = drain_tick.tick(), if self.drain.is_some() => {
+                    if self.check_drain() {
+                        // Give connections a moment to deliver the final announcement
+                        tokio::time::sleep(Duration::from_millis(500)).await;
+                        self.shut_down().await;
+                        return Ok(());
+                    }
+                }
             }
         }
     }
 
+    async fn run_maintenance(&self) {
+        let expired = self.state.history.write().await.prune(Local::now());
+        if expired > 0 {
+            info!("Expired {} room history message(s)", expired);
+        }
+        // Also notices when the queue has drained and the pressure is over
+        self.state.enforce_memory().await;
+        for target in self.state.bans.write().await.prune(Instant::now()) {
+            info!("Ban on {} expired", target);
+            self.state
+                .audit
+                .record("UNBAN", &format!("{} (expired)", target));
+        }
+        // Written once the log is unlocked: every message updates it
+        let save = self.state.seen.write().await.save_if_changed();
+        if let Err(e) = save.write().await {
+            error!("Failed to save last-seen log: {}", e);
+        }
+    }
+
+    /// Save what's only written out periodically before the server exits
+    async fn shut_down(&self) {
+        info!("Server shutting down...");
+        if self
+            .state
+            .server_commands
+            .send(ServerCommand::Shutdown)
+            .is_ok()
+        {
+            // Give connections a moment to send their Disconnect frames
+            tokio::time::sleep(SHUTDOWN_GRACE).await;
+        }
+        let save = self.state.seen.write().await.save();
+        if let Err(e) = save.write().await {
+            error!("Failed to save last-seen log: {}", e);
+        }
+        if let Some(writer) = &self.state.history_writer
+            && !writer.flush().await
+        {
+            error!(
+                "Gave up writing the history log after {}s - recent messages may be missing from it",
+                history_writer::FLUSH_TIMEOUT.as_secs()
+            );
+        }
+    }
+
     async fn handle_list_users(&self) {
-        let clients = self.connected_clients.read().await;
+        let clients: Vec<String> = self
+            .state
+            .connected_clients
+            .read()
+            .await
+            .iter()
+            .cloned()
+            .collect();
         let count = clients.len();
         if count == 0 {
-            logger::log_info("No users currently connected.");
+            info!("No users currently connected.");
         } else {
-            logger::log_info(&format!("Connected users ({}):", count));
+            info!("Connected users ({}):", count);
+            let presence = self.state.presence.read().await;
             for user in clients.iter() {
-                logger::log_info(&format!("  - {}", user));
+                match presence.get(user) {
+                    Some(session) => info!("  - {} ({})", user, session.id),
+                    None => info!("  - {}", user),
+                }
             }
         }
     }
 
-    async fn handle_kick(&self, username: String) {
-        let clients = self.connected_clients.read().await;
+    async fn handle_seen(&self, username: String) {
+        let online = self
+            .state
+            .connected_clients
+            .read()
+            .await
+            .contains(&username);
+        info!(
+            "{}",
+            self.state.seen.read().await.describe(&username, online)
+        );
+    }
+
+    async fn handle_whois(&self, username: String) {
+        let Some(session) = self.state.presence.read().await.get(&username).cloned() else {
+            error!("User '{}' not found", username);
+            return;
+        };
+        let connected_for = (Local::now() - session.connected_at)
+            .to_std()
+            .unwrap_or_default();
+        let usage = self
+            .state
+            .bandwidth
+            .read()
+            .await
+            .usage_by_user()
+            .into_iter()
+            .find(|(user, _)| *user == username)
+            .map(|(_, usage)| usage);
+        let rooms = self.state.rooms.read().await;
+        let room_list = rooms
+            .rooms_for(&username)
+            .iter()
+            .map(|room| {
+                if rooms.is_moderator(room, &username) {
+                    format!("#{} (moderator)", room)
+                } else {
+                    format!("#{}", room)
+                }
+            })
+            .collect::<Vec<_>>();
+        drop(rooms);
+        let role = if self.state.authenticated.read().await.contains(&username) {
+            "registered (logged in)"
+        } else {
+            "guest"
+        };
+
+        info!("Whois {}:", username);
+        info!("  Connection: {}", session.id);
+        info!("  Address: {}", session.addr);
+        info!(
+            "  Connected: {} ({} ago)",
+            session.connected_at.format("%Y-%m-%d %H:%M:%S"),
+            schedule::format_interval(connected_for)
+        );
+        info!("  Idle: {}", schedule::format_interval(session.idle()));
+        info!(
+            "  Messages: {} ({}) | Rate limit hits: {}",
+            usage.as_ref().map_or(0, |usage| usage.total_messages),
+            bandwidth::format_bytes(usage.as_ref().map_or(0, |usage| usage.total_bytes)),
+            session.rate_limit_hits
+        );
+        info!(
+            "  Rooms: {}",
+            if room_list.is_empty() {
+                "none".to_string()
+            } else {
+                room_list.join(", ")
+            }
+        );
+        info!("  Role: {}", role);
+        let client = match self.state.user_fingerprints.read().await.get(&username) {
+            Some(fingerprint) => format!(
+                "{} (fingerprint {})",
+                fingerprint.client_version,
+                fingerprint.id()
+            ),
+            None => session
+                .client_version
+                .map(|version| format!("v{}", version))
+                .unwrap_or("unknown".to_string()),
+        };
+        info!("  Client: {}", client);
+        if let Some(status) = self.state.user_statuses.read().await.get(&username) {
+            info!("  Status: {}", status);
+        }
+    }
+
+    /// The connection a user joined on
+    async fn connection_of(&self, username: &str) -> Option<ConnectionId> {
+        let presence = self.state.presence.read().await;
+        presence.get(username).map(|session| session.id)
+    }
+
+    /// Audit an action on a user, tagged with their connection if they have one
+    fn audit_user(&self, conn: Option<ConnectionId>, event: &str, details: &str) {
+        match conn {
+            Some(conn) => self.state.audit.record_from(conn, event, details),
+            None => self.state.audit.record(event, details),
+        }
+    }
+
+    async fn handle_kick(&self, username: String, cooldown: Option<Duration>) {
+        let clients = self.state.connected_clients.read().await;
         if clients.contains(&username) {
             drop(clients);
+            // Keep them out by IP and by name, so neither a new nick nor a new address gets them back in
+            let cooldown = cooldown.or(self.state.kick_cooldown);
+            if let Some(cooldown) = cooldown {
+                let ip = self.state.user_ips.read().await.get(&username).copied();
+                self.state
+                    .bans
+                    .write()
+                    .await
+                    .add_cooldown(&username, ip, cooldown, Instant::now());
+            }
+            let conn = self.connection_of(&username).await;
             // Send kick command to all connections - the matching one will disconnect
             if self
+                .state
                 .server_commands
-                .send(ServerCommand::Kick(username.clone()))
+                .send(ServerCommand::Kick {
+                    usernames: vec![username.clone()],
+                    cooldown,
+                })
                 .is_ok()
             {
-                logger::log_warning(&format!("Kicking user: {}", username));
+                self.audit_user(
+                    conn,
+                    "KICK",
+                    &format!("{}{}", username, describe_ban_duration(cooldown)),
+                );
+                match cooldown {
+                    Some(cooldown) => warn!(
+                        "Kicking user: {} (can rejoin in {})",
+                        username,
+                        schedule::format_interval(cooldown)
+                    ),
+                    None => warn!("Kicking user: {}", username),
+                }
             }
         } else {
-            logger::log_error(&format!("User '{}' not found", username));
+            error!("User '{}' not found", username);
         }
     }
 
+    async fn handle_kickall(&mut self, room: Option<String>) {
+        let usernames = match &room {
+            Some(room) => {
+                let Some(room) = self.resolve_room(room).await else {
+                    return;
+                };
+                self.state.rooms.read().await.members(&room)
+            }
+            None => {
+                let mut usernames: Vec<String> = self
+                    .state
+                    .connected_clients
+                    .read()
+                    .await
+                    .iter()
+                    .cloned()
+                    .collect();
+                usernames.sort();
+                usernames
+            }
+        };
+        if usernames.is_empty() {
+            info!("No users to kick.");
+            return;
+        }
+        let room = room.and_then(|room| rooms::normalize_room_name(&room));
+        warn!(
+            "This will kick {} user(s){}: {}",
+            usernames.len(),
+            room.as_ref()
+                .map(|room| format!(" in #{}", room))
+                .unwrap_or_default(),
+            usernames.join(", ")
+        );
+        self.ask_confirmation(PendingAction::KickAll { room, usernames });
+    }
+
+    async fn handle_muteall(&mut self, room: String, duration: Option<Duration>) {
+        let Some(room) = self.resolve_room(&room).await else {
+            return;
+        };
+        let Some(duration) = duration else {
+            // Lifting a mute can't do any harm, so it needs no confirmation
+            self.state.rooms.write().await.set_mute(&room, None);
+            self.state.audit.record("UNMUTE", &format!("#{}", room));
+            self.send_announcement(&room, "This room is no longer muted");
+            success!("Lifted the mute on #{}", room);
+            return;
+        };
+        let members = self.state.rooms.read().await.members(&room).len();
+        warn!(
+            "This will mute all {} member(s) of #{} for {}",
+            members,
+            room,
+            schedule::format_interval(duration)
+        );
+        self.ask_confirmation(PendingAction::MuteAll { room, duration });
+    }
+
+    async fn handle_clear_history(&mut self, room: String) {
+        let Some(room) = rooms::normalize_room_name(&room) else {
+            error!("Invalid room name '{}'", room);
+            return;
+        };
+        let stored = self
+            .state
+            .history
+            .read()
+            .await
+            .recent(&room, usize::MAX)
+            .len();
+        if stored == 0 {
+            info!("#{} has no stored history.", room);
+            return;
+        }
+        warn!(
+            "This will delete the {} stored message(s) of #{}",
+            stored, room
+        );
+        self.ask_confirmation(PendingAction::ClearHistory { room });
+    }
+
+    fn ask_confirmation(&mut self, action: PendingAction) {
+        info!("Type 'yes' to confirm, anything else cancels.");
+        self.pending_action = Some(action);
+    }
+
+    /// Run the pending bulk action if the operator answered "yes"
+    async fn handle_confirmation(&mut self, answer: &str) {
+        let Some(action) = self.pending_action.take() else {
+            return;
+        };
+        if !answer.trim().eq_ignore_ascii_case("yes") {
+            info!("Cancelled - nothing was changed.");
+            return;
+        }
+        match action {
+            PendingAction::KickAll { room, usernames } => self.kick_all(room, usernames).await,
+            PendingAction::MuteAll { room, duration } => self.mute_all(room, duration).await,
+            PendingAction::ClearHistory { room } => self.clear_history(room).await,
+        }
+    }
+
+    async fn kick_all(&self, room: Option<String>, usernames: Vec<String>) {
+        // Only users still online (some may have left while we asked)
+        let clients = self.state.connected_clients.read().await;
+        let usernames: Vec<String> = usernames
+            .into_iter()
+            .filter(|user| clients.contains(user))
+            .collect();
+        drop(clients);
+
+        let cooldown = self.state.kick_cooldown;
+        let context = room
+            .map(|room| format!(" (kickall #{})", room))
+            .unwrap_or(" (kickall)".to_string());
+        for username in &usernames {
+            if let Some(cooldown) = cooldown {
+                let ip = self.state.user_ips.read().await.get(username).copied();
+                self.state
+                    .bans
+                    .write()
+                    .await
+                    .add_cooldown(username, ip, cooldown, Instant::now());
+            }
+            self.state.audit.record(
+                "KICK",
+                &format!("{}{}{}", username, describe_ban_duration(cooldown), context),
+            );
+        }
+        // Disconnect them in batches so thousands of kicks don't arrive at once
+        warn!("Kicking {} user(s)...", usernames.len());
+        let steps = usernames
+            .chunks(KICK_BATCH_SIZE)
+            .map(|batch| Step::Command {
+                command: ServerCommand::Kick {
+                    usernames: batch.to_vec(),
+                    cooldown,
+                },
+                items: batch.len(),
+            })
+            .collect();
+        report_progress("kickall", "users kicked", self.actions.submit(steps));
+    }
+
+    async fn mute_all(&self, room: String, duration: Duration) {
+        let mut rooms = self.state.rooms.write().await;
+        if !rooms.set_mute(&room, Some(Instant::now() + duration)) {
+            error!("Room '#{}' not found", room);
+            return;
+        }
+        let members = rooms.members(&room);
+        drop(rooms);
+
+        let interval = schedule::format_interval(duration);
+        for member in &members {
+            self.state
+                .audit
+                .record("MUTE", &format!("{} in #{} for {}", member, room, interval));
+        }
+        self.send_announcement(&room, &format!("This room has been muted for {}", interval));
+        warn!(
+            "Muted #{} ({} member(s)) for {}",
+            room,
+            members.len(),
+            interval
+        );
+    }
+
+    async fn clear_history(&self, room: String) {
+        let mut history = self.state.history.write().await;
+        let mut senders: Vec<String> = history
+            .recent(&room, usize::MAX)
+            .into_iter()
+            .map(|entry| entry.sender)
+            .collect();
+        let removed = history.clear(&room);
+        drop(history);
+        self.state.log_history(Record::Clear {
+            at: Local::now(),
+            room: room.clone(),
+        });
+
+        // One audit entry per user whose messages were deleted
+        senders.sort();
+        for sender in senders.chunk_by(|a, b| a == b) {
+            self.state.audit.record(
+                "CLEAR",
+                &format!("#{} {} ({} message(s))", room, sender[0], sender.len()),
+            );
+        }
+        warn!("Deleted {} stored message(s) of #{}", removed, room);
+    }
+
     async fn handle_rename(&self, old_name: String, new_name: String) {
-        let mut clients = self.connected_clients.write().await;
+        let mut clients = self.state.connected_clients.write().await;
 
         // Check if the user to rename exists
         if !clients.contains(&old_name) {
-            logger::log_error(&format!("User '{}' not found", old_name));
+            error!("User '{}' not found", old_name);
             return;
         }
 
         // Check if the new name is already taken
         if clients.contains(&new_name) {
-            logger::log_error(&format!("Username '{}' is already taken", new_name));
+            error!("Username '{}' is already taken", new_name);
+            return;
+        }
+
+        // Every session would be renamed, but they'd still be counted under the old name
+        let others = self.state.sessions.read().await.extra(&old_name);
+        if others > 0 {
+            error!(
+                "'{}' is logged in from {} devices - it can be renamed once only one is left",
+                old_name,
+                others + 1
+            );
             return;
         }
 
         // Validate new username
         if new_name.is_empty() || new_name.len() > 32 {
-            logger::log_error("Invalid username length (1-32 characters)");
+            error!("Invalid username length (1-32 characters)");
             return;
         }
         if !new_name
             .chars()
             .all(|c| c.is_alphanumeric() || c == '_' || c == '-')
         {
-            logger::log_error("Invalid characters (only alphanumeric, underscore, hyphen allowed)");
+            error!("Invalid characters (only alphanumeric, underscore, hyphen allowed)");
             return;
         }
 
@@ -284,6 +1002,7 @@ impl ChatServer {
 
         // Send rename command to all connections - the matching one will handle it
         if self
+            .state
             .server_commands
             .send(ServerCommand::Rename {
                 old_name: old_name.clone(),
@@ -291,81 +1010,1086 @@ impl ChatServer {
             })
             .is_ok()
         {
-            logger::log_success(&format!("Renaming user '{}' to '{}'", old_name, new_name));
+            success!("Renaming user '{}' to '{}'", old_name, new_name);
         }
     }
 
-    async fn handle_ban_user(&self, username: String) {
+    async fn handle_ban_user(&self, username: String, duration: Option<Duration>) {
         // Look up the user's IP
-        let user_ips = self.user_ips.read().await;
+        let user_ips = self.state.user_ips.read().await;
         let ip = match user_ips.get(&username) {
             Some(ip) => *ip,
             None => {
-                logger::log_error(&format!("User '{}' not found or not connected", username));
+                error!("User '{}' not found or not connected", username);
                 return;
             }
         };
         drop(user_ips);
+        let conn = self.connection_of(&username).await;
+
+        // A fingerprint keeps them out after changing IP and nickname too
+        let fingerprint = self
+            .state
+            .user_fingerprints
+            .read()
+            .await
+            .get(&username)
+            .map(|fingerprint| fingerprint.id().to_string());
 
         // Add to banned IPs
-        let mut banned = self.banned_ips.write().await;
-        if banned.insert(ip) {
-            drop(banned);
-            logger::log_warning(&format!("Banned IP {} (user '{}')", ip, username));
+        let network = bans::host_network(ip);
+        let now = Instant::now();
+        let mut bans = self.state.bans.write().await;
+        if let Some(id) = &fingerprint
+            && bans.ban_fingerprint(id, duration, now)
+        {
+            warn!(
+                "Banned fingerprint {} (user '{}'){}",
+                id,
+                username,
+                describe_ban_duration(duration)
+            );
+            self.audit_user(
+                conn,
+                "BAN",
+                &format!(
+                    "fp={} user={}{}",
+                    id,
+                    username,
+                    describe_ban_duration(duration)
+                ),
+            );
+        }
+        if bans.ban(network, duration, now) {
+            drop(bans);
+            warn!(
+                "Banned IP {} (user '{}'){}",
+                ip,
+                username,
+                describe_ban_duration(duration)
+            );
+            self.audit_user(
+                conn,
+                "BAN",
+                &format!(
+                    "{} user={}{}",
+                    ip,
+                    username,
+                    describe_ban_duration(duration)
+                ),
+            );
 
             // Kick the user and disconnect them
-            if self.server_commands.send(ServerCommand::Ban(ip)).is_ok() {
-                logger::log_info(&format!("Disconnecting user '{}' from banned IP", username));
+            if self
+                .state
+                .server_commands
+                .send(ServerCommand::Ban { network, duration })
+                .is_ok()
+            {
+                info!("Disconnecting user '{}' from banned IP", username);
+            }
+        } else {
+            info!("IP {} is already banned", ip);
+        }
+    }
+
+    async fn handle_ban_ip(&self, network: IpNetwork, duration: Option<Duration>) {
+        let target = bans::describe(network);
+        let now = Instant::now();
+        let mut bans = self.state.bans.write().await;
+        let overlapping = bans.overlapping(network, now);
+        if bans.ban(network, duration, now) {
+            drop(bans);
+            warn!("Banned {}{}", target, describe_ban_duration(duration));
+            self.state.audit.record(
+                "BAN",
+                &format!("{}{}", target, describe_ban_duration(duration)),
+            );
+            if !overlapping.is_empty() {
+                info!(
+                    "{} overlaps existing ban(s): {}",
+                    target,
+                    describe_networks(&overlapping)
+                );
+            }
+
+            // Disconnect any users from this IP or range
+            if self
+                .state
+                .server_commands
+                .send(ServerCommand::Ban { network, duration })
+                .is_ok()
+            {
+                info!("Disconnecting users from banned {}", target);
             }
         } else {
-            logger::log_info(&format!("IP {} is already banned", ip));
+            info!("{} is already banned", target);
         }
     }
 
-    async fn handle_ban_ip(&self, ip: IpAddr) {
-        let mut banned = self.banned_ips.write().await;
-        if banned.insert(ip) {
-            drop(banned);
-            logger::log_warning(&format!("Banned IP {}", ip));
+    async fn handle_unban(&self, network: IpNetwork) {
+        let target = bans::describe(network);
+        let mut bans = self.state.bans.write().await;
+        if bans.unban(network) {
+            success!("Unbanned {}", target);
+            self.state.audit.record("UNBAN", &target);
+            // Lifting one IP (or a narrow range) doesn't lift a wider ban around it
+            let still_covered = bans.overlapping(network, Instant::now());
+            if !still_covered.is_empty() {
+                warn!(
+                    "{} is still covered by: {}",
+                    target,
+                    describe_networks(&still_covered)
+                );
+            }
+        } else {
+            error!("{} is not banned", target);
+        }
+    }
+
+    async fn handle_ban_fingerprint(&self, id: String, duration: Option<Duration>) {
+        let mut bans = self.state.bans.write().await;
+        if bans.ban_fingerprint(&id, duration, Instant::now()) {
+            drop(bans);
+            warn!(
+                "Banned fingerprint {}{}",
+                id,
+                describe_ban_duration(duration)
+            );
+            self.state.audit.record(
+                "BAN",
+                &format!("fp={}{}", id, describe_ban_duration(duration)),
+            );
 
-            // Disconnect any users from this IP
-            if self.server_commands.send(ServerCommand::Ban(ip)).is_ok() {
-                logger::log_info(&format!("Disconnecting users from banned IP {}", ip));
+            // Disconnect whoever is connected with this fingerprint
+            if self
+                .state
+                .server_commands
+                .send(ServerCommand::BanFingerprint {
+                    id: id.clone(),
+                    duration,
+                })
+                .is_ok()
+            {
+                info!("Disconnecting users with fingerprint {}", id);
             }
         } else {
-            logger::log_info(&format!("IP {} is already banned", ip));
+            info!("Fingerprint {} is already banned", id);
         }
     }
 
-    async fn handle_unban(&self, ip: IpAddr) {
-        let mut banned = self.banned_ips.write().await;
-        if banned.remove(&ip) {
-            logger::log_success(&format!("Unbanned IP {}", ip));
+    async fn handle_unban_fingerprint(&self, id: String) {
+        if self.state.bans.write().await.unban_fingerprint(&id) {
+            success!("Unbanned fingerprint {}", id);
+            self.state.audit.record("UNBAN", &format!("fp={}", id));
         } else {
-            logger::log_error(&format!("IP {} is not banned", ip));
+            error!("Fingerprint {} is not banned", id);
         }
     }
 
     async fn handle_banlist(&self) {
-        let banned = self.banned_ips.read().await;
-        if banned.is_empty() {
-            logger::log_info("No IPs are currently banned.");
+        let bans = self.state.bans.read().await;
+        let now = Instant::now();
+        let banned = bans.bans(now);
+        let fingerprints = bans.fingerprint_bans(now);
+        let cooldowns = bans.cooldowns(now);
+        if banned.is_empty() && fingerprints.is_empty() && cooldowns.is_empty() {
+            info!("No IPs are currently banned.");
+            return;
+        }
+        if !banned.is_empty() {
+            info!("Banned IPs ({}):", banned.len());
+            for (network, left) in &banned {
+                let mut line = format!("  - {}", bans::describe(*network));
+                if let Some(left) = left {
+                    line.push_str(&format!(" ({} left)", schedule::format_interval(*left)));
+                }
+                // Point out entries made redundant by a wider ban
+                let wider: Vec<IpNetwork> = bans
+                    .overlapping(*network, now)
+                    .into_iter()
+                    .filter(|other| other.netmask() < network.netmask())
+                    .collect();
+                if !wider.is_empty() {
+                    line.push_str(&format!(" [inside {}]", describe_networks(&wider)));
+                }
+                info!("{}", line);
+            }
+        }
+        if !fingerprints.is_empty() {
+            info!("Banned fingerprints ({}):", fingerprints.len());
+            for (id, left) in fingerprints {
+                match left {
+                    Some(left) => info!("  - {} ({} left)", id, schedule::format_interval(left)),
+                    None => info!("  - {}", id),
+                }
+            }
+        }
+        if !cooldowns.is_empty() {
+            info!("Kick cooldowns ({}):", cooldowns.len());
+            for (target, left) in cooldowns {
+                info!("  - {} ({} left)", target, schedule::format_interval(left));
+            }
+        }
+    }
+
+    async fn handle_register(&self, username: String, password: String) {
+        if username.is_empty() || username.len() > 32 {
+            error!("Invalid username length (1-32 characters)");
+            return;
+        }
+        if !reserved::is_well_formed(&username) {
+            error!("Invalid characters (only alphanumeric, underscore, hyphen allowed)");
+            return;
+        }
+        match reserved::split(&username) {
+            (Namespace::Server, _) => {
+                error!(
+                    "'{}' names are reserved for the server",
+                    reserved::SERVER_PREFIX
+                );
+                return;
+            }
+            (Namespace::Bot, bot) => {
+                error!(
+                    "Bots log in with tokens, not passwords - use /token create {}",
+                    bot
+                );
+                return;
+            }
+            (Namespace::User, _) => {}
+        }
+
+        let result = match HashedPassword::new(&password).await {
+            Ok(password) => self
+                .state
+                .accounts
+                .write()
+                .await
+                .register(&username, password),
+            Err(e) => Err(e),
+        };
+        let result = match result {
+            Ok(save) => save.write().await.map_err(AccountError::Io),
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(()) => {
+                success!("Registered nickname '{}'", username);
+                if self
+                    .state
+                    .connected_clients
+                    .read()
+                    .await
+                    .contains(&username)
+                {
+                    info!(
+                        "'{}' is connected without a password; they keep the name until its owner logs in",
+                        username
+                    );
+                }
+            }
+            Err(e) => error!("Cannot register '{}': {}", username, e),
+        }
+    }
+
+    async fn handle_unregister(&self, username: String) {
+        let result = self.state.accounts.write().await.unregister(&username);
+        let result = match result {
+            Ok(save) => save.write().await.map_err(AccountError::Io),
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(()) => {
+                self.state.authenticated.write().await.remove(&username);
+                self.state.mailbox.write().await.discard(&username);
+                success!("Unregistered nickname '{}'", username);
+            }
+            Err(e) => error!("Cannot unregister '{}': {}", username, e),
+        }
+    }
+
+    async fn handle_list_tokens(&self) {
+        let tokens = self.state.tokens.read().await.list();
+        if tokens.is_empty() {
+            info!("No bot tokens have been created.");
+            return;
+        }
+        info!("Bot tokens ({}):", tokens.len());
+        let clients = self.state.connected_clients.read().await;
+        for (name, grant) in tokens {
+            let online = if clients.contains(&name) {
+                " (online)"
+            } else {
+                ""
+            };
+            info!("  - {}: {}{}", name, grant.describe(), online);
+        }
+    }
+
+    async fn handle_create_token(&self, bot: String, grant: Grant) {
+        // Moderating and changing rooms' policy wait until the server has an owner
+        // who isn't using the password printed on first start
+        let privileged = [Scope::Moderate, Scope::Admin]
+            .into_iter()
+            .any(|scope| grant.scopes().contains(scope));
+        if privileged
+            && self
+                .state
+                .accounts
+                .read()
+                .await
+                .has_one_time_password(ADMIN_NAME)
+        {
+            error!(
+                "Tokens with the moderate or admin scope can't be created until '{}' has logged in and replaced its one-time password (/register <password>)",
+                ADMIN_NAME
+            );
+            return;
+        }
+        let name = format!("{}{}", reserved::BOT_PREFIX, bot);
+        let described = grant.describe();
+        // Hashed before the store is locked, so logins aren't held up
+        let result = match NewToken::new(&bot).await {
+            Ok(token) => {
+                let mut tokens = self.state.tokens.write().await;
+                let rotated = tokens.grant(&name).is_some();
+                let (token, save) = tokens.create(token, grant);
+                drop(tokens);
+                save.write()
+                    .await
+                    .map(|()| (token, rotated))
+                    .map_err(TokenError::Io)
+            }
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok((token, rotated)) => {
+                self.state
+                    .audit
+                    .record("TOKEN", &format!("create {} {}", name, described));
+                success!("Created a token for {} ({})", name, described);
+                // Only the hash is kept, so this is the one chance to copy it
+                info!("Token (shown once): {}", token);
+                info!(
+                    "The bot joins as {} with the token as its password (chat-client --token)",
+                    name
+                );
+                if rotated {
+                    info!("The earlier token for {} no longer works", name);
+                }
+            }
+            Err(e) => error!("Cannot create a token for '{}': {}", bot, e),
+        }
+    }
+
+    async fn handle_revoke_token(&self, bot: String) {
+        let name = format!("{}{}", reserved::BOT_PREFIX, bot);
+        let result = self.state.tokens.write().await.revoke(&bot);
+        let result = match result {
+            Ok(save) => save.write().await.map_err(TokenError::Io),
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(()) => {
+                self.state
+                    .audit
+                    .record("TOKEN", &format!("revoke {}", name));
+                success!("Revoked the token for {}", name);
+                // A revoked bot doesn't get to stay
+                if self.state.connected_clients.read().await.contains(&name) {
+                    let _ = self.state.server_commands.send(ServerCommand::Kick {
+                        usernames: vec![name.clone()],
+                        cooldown: None,
+                    });
+                    info!("Disconnected {}", name);
+                }
+            }
+            Err(e) => error!("Cannot revoke the token for '{}': {}", bot, e),
+        }
+    }
+
+    async fn handle_list_accounts(&self) {
+        let names = self.state.accounts.read().await.names();
+        if names.is_empty() {
+            info!("No nicknames are registered.");
         } else {
-            logger::log_info(&format!("Banned IPs ({}):", banned.len()));
-            for ip in banned.iter() {
-                logger::log_info(&format!("  - {}", ip));
+            info!("Registered nicknames ({}):", names.len());
+            let authenticated = self.state.authenticated.read().await;
+            for name in names {
+                let online = if authenticated.contains(&name) {
+                    " (online)"
+                } else {
+                    ""
+                };
+                info!("  - {}{}", name, online);
             }
         }
     }
 
+    async fn handle_announce(&self, room: Option<String>, message: String) {
+        let room = match room {
+            Some(room) => match self.resolve_room(&room).await {
+                Some(room) => room,
+                None => return,
+            },
+            None => String::new(),
+        };
+
+        let identity = &self.state.server_identity;
+        if !self.send_announcement(&room, &message) {
+            return;
+        }
+
+        if room.is_empty() {
+            announcement!("{}: {}", identity, message);
+        } else {
+            announcement!("#{} {}: {}", room, identity, message);
+        }
+    }
+
+    /// Broadcast an announcement as the server identity (empty room = everyone)
+    fn send_announcement(&self, room: &str, message: &str) -> bool {
+        let content = sender_class::tag(
+            SenderClass::Server,
+            &format!("{}|{}|{}", room, self.state.server_identity, message),
+        );
+        let Ok(announcement) =
+            ChatMessage::try_new(MessageTypes::Announcement, Some(content.into_bytes()))
+        else {
+            error!("Announcement is too large to send");
+            return false;
+        };
+        self.actions.submit(vec![Step::Broadcast(announcement)]);
+        true
+    }
+
+    fn handle_schedule_announcement(
+        &mut self,
+        room: Option<String>,
+        message: String,
+        at: Option<NaiveTime>,
+        every: Option<Duration>,
+    ) {
+        // Rooms come and go, so only the name is checked now
+        let room = match room {
+            Some(room) => match rooms::normalize_room_name(&room) {
+                Some(room) => Some(room),
+                None => {
+                    error!("Invalid room name '{}'", room);
+                    return;
+                }
+            },
+            None => None,
+        };
+        if let Some(every) = every
+            && every < schedule::MIN_INTERVAL
+        {
+            error!(
+                "Announcements can repeat at most every {}",
+                schedule::format_interval(schedule::MIN_INTERVAL)
+            );
+            return;
+        }
+
+        match self.schedule.add(room, message, at, every) {
+            Some(entry) => success!(
+                "Scheduled announcement {}: {}",
+                entry.id,
+                describe_scheduled(entry)
+            ),
+            None => error!("Use --at and/or --every to schedule an announcement"),
+        }
+    }
+
+    fn handle_list_scheduled(&self) {
+        let entries = self.schedule.entries();
+        if entries.is_empty() {
+            info!("No scheduled announcements.");
+            return;
+        }
+        info!("Scheduled announcements ({}):", entries.len());
+        for entry in entries {
+            info!("  {}. {}", entry.id, describe_scheduled(entry));
+        }
+    }
+
+    fn handle_cancel_scheduled(&mut self, id: u32) {
+        if self.schedule.cancel(id) {
+            success!("Cancelled scheduled announcement {}", id);
+        } else {
+            error!("No scheduled announcement with id {}", id);
+        }
+    }
+
+    async fn send_scheduled_announcements(&mut self) {
+        for entry in self.schedule.take_due(Local::now()) {
+            let room = entry.room.unwrap_or_default();
+            if !room.is_empty() && !self.state.rooms.read().await.exists(&room) {
+                info!(
+                    "Skipped scheduled announcement {} (#{} has no members)",
+                    entry.id, room
+                );
+                continue;
+            }
+            if !self.send_announcement(&room, &entry.message) {
+                continue;
+            }
+            let identity = &self.state.server_identity;
+            if room.is_empty() {
+                announcement!("{}: {}", identity, entry.message);
+            } else {
+                announcement!("#{} {}: {}", room, identity, entry.message);
+            }
+        }
+    }
+
+    /// Schedule the announcements listed in the announcements file (one per line,
+    /// in /announce syntax; blank lines and lines starting with # are ignored)
+    fn load_scheduled_announcements(&mut self, path: &Path) {
+        let Ok(contents) = std::fs::read_to_string(path) else {
+            return; // No announcements configured
+        };
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match ServerUserInput::try_from(format!("{} {}", commands::ANNOUNCE.name, line)) {
+                Ok(ServerUserInput::ScheduleAnnouncement {
+                    room,
+                    message,
+                    at,
+                    every,
+                }) => self.handle_schedule_announcement(room, message, at, every),
+                _ => error!(
+                    "{}:{}: expected [--room <room>] [--at HH:MM] [--every <interval>] <message>",
+                    path.display(),
+                    number + 1
+                ),
+            }
+        }
+    }
+
+    fn handle_drain(&mut self, remaining: Option<Duration>) {
+        if self.drain.is_some() {
+            error!("Server is already draining. Use /drain cancel to abort.");
+            return;
+        }
+
+        let message = match remaining {
+            Some(remaining) => {
+                self.drain = Some(Drain::new(Some(remaining)));
+                format!(
+                    "Server is shutting down in {}. New connections are no longer accepted.",
+                    drain::format_remaining(remaining)
+                )
+            }
+            None => {
+                self.drain = Some(Drain::new(None));
+                "Server is shutting down once all users have left. New connections are no longer accepted.".to_string()
+            }
+        };
+        self.send_announcement("", &message);
+        warn!("Draining connections: {}", message);
+    }
+
+    fn handle_drain_cancel(&mut self) {
+        if self.drain.take().is_none() {
+            error!("Server is not draining");
+            return;
+        }
+        self.send_announcement("", "Scheduled shutdown has been cancelled.");
+        success!("Drain cancelled - accepting new connections again");
+    }
+
+    async fn handle_maintenance_show(&self) {
+        match self.state.maintenance.read().await.banner(Local::now()) {
+            Some(banner) => info!("{}", banner),
+            None => info!("No maintenance planned."),
+        }
+    }
+
+    async fn handle_maintenance_set(
+        &mut self,
+        start: maintenance_window::Start,
+        duration: Duration,
+        text: String,
+    ) {
+        let now = Local::now();
+        let start = start.resolve(now);
+        let window = Window {
+            start,
+            end: start + TimeDelta::from_std(duration).unwrap_or(TimeDelta::MAX),
+            text,
+        };
+        let mut maintenance = self.state.maintenance.write().await;
+        if let Err(e) = maintenance.set(window, now) {
+            error!("Failed to save maintenance window: {}", e);
+        }
+        let banner = maintenance.banner(now).unwrap_or_default();
+        drop(maintenance);
+        self.send_announcement("", &banner);
+        success!("{}", banner);
+    }
+
+    async fn handle_maintenance_clear(&mut self) {
+        let cleared = self.state.maintenance.write().await.clear();
+        match cleared {
+            Ok(Some(window)) => {
+                if Local::now() < window.start {
+                    self.send_announcement("", "Planned maintenance has been cancelled.");
+                }
+                success!("Maintenance window cleared");
+                if self.drain.is_some() {
+                    info!("The server is still draining. Use /drain cancel to abort.");
+                }
+            }
+            Ok(None) => error!("No maintenance planned"),
+            Err(e) => error!("Failed to save maintenance window: {}", e),
+        }
+    }
+
+    /// Remind users of the maintenance window, start draining when it begins and
+    /// forget it once it's over
+    async fn check_maintenance_window(&mut self) {
+        let now = Local::now();
+        let mut maintenance = self.state.maintenance.write().await;
+        let due = match maintenance.take_due(now) {
+            Ok(due) => due,
+            Err(e) => {
+                error!("Failed to save maintenance window: {}", e);
+                return;
+            }
+        };
+        let banner = maintenance.banner(now).unwrap_or_default();
+        let text = maintenance
+            .window()
+            .map(|window| window.text.clone())
+            .unwrap_or_default();
+        drop(maintenance);
+
+        match due {
+            Some(Due::Reminder(_)) => {
+                self.send_announcement("", &banner);
+                info!("{}", banner);
+            }
+            Some(Due::Start) if self.drain.is_some() => {
+                self.send_announcement("", &format!("Maintenance is starting: {}", text));
+            }
+            Some(Due::Start) => {
+                self.drain = Some(Drain::new(Some(maintenance_window::DRAIN_GRACE)));
+                let message = format!(
+                    "Maintenance is starting: {}. Server is shutting down in {}. New connections are no longer accepted.",
+                    text,
+                    drain::format_remaining(maintenance_window::DRAIN_GRACE)
+                );
+                self.send_announcement("", &message);
+                warn!("Draining connections: {}", message);
+            }
+            Some(Due::End) => {
+                self.send_announcement("", "Maintenance is over.");
+                success!("Maintenance window is over");
+            }
+            None => {}
+        }
+    }
+
+    /// Send any due countdown reminder. Returns true once the server should shut down.
+    fn check_drain(&mut self) -> bool {
+        let Some(drain) = self.drain.as_mut() else {
+            return false;
+        };
+
+        if self.active_connections.load(Ordering::Relaxed) == 0 {
+            info!("All clients have left");
+            return true;
+        }
+        if drain.expired() {
+            self.send_announcement("", "Server is shutting down now.");
+            return true;
+        }
+        if let Some(remaining) = drain.due_reminder() {
+            let message = format!(
+                "Server is shutting down in {}.",
+                drain::format_remaining(remaining)
+            );
+            self.send_announcement("", &message);
+            warn!("{}", message);
+        }
+        false
+    }
+
+    fn handle_stats_graph(&self, span: Duration) {
+        let span = span.min(stats_history::RETENTION);
+        let Some(graph) = self.stats_history.graph(span) else {
+            info!(
+                "No statistics yet - a sample is taken every {}",
+                schedule::format_interval(stats_history::SAMPLE_INTERVAL)
+            );
+            return;
+        };
+        info!("Last {}:", schedule::format_interval(span));
+        for line in graph {
+            info!("  {}", line);
+        }
+    }
+
+    async fn handle_stats(&self) {
+        let uptime = self.state.started_at.elapsed();
+        let bandwidth = self.state.bandwidth.read().await;
+        let quota = bandwidth
+            .quota()
+            .map(bandwidth::format_bytes)
+            .unwrap_or("unlimited".to_string());
+
+        info!(
+            "Server: {} v{}",
+            self.state.info.display_name(),
+            self.state.info.version
+        );
+        let waiting = match &self.state.waiting_room {
+            Some(waiting_room) => waiting_room.read().await.len(),
+            None => 0,
+        };
+        info!(
+            "Uptime: {}h {:02}m | Connections: {}/{} | Users: {}/{} | Waiting: {} | Rooms: {}",
+            uptime.as_secs() / 3600,
+            uptime.as_secs() % 3600 / 60,
+            self.active_connections.load(Ordering::Relaxed),
+            self.max_connections,
+            self.state.connected_clients.read().await.len(),
+            self.max_clients,
+            waiting,
+            self.state.rooms.read().await.len()
+        );
+        info!(
+            "Bandwidth: {} received in total | Quota: {} per user per hour",
+            bandwidth::format_bytes(bandwidth.total_bytes()),
+            quota
+        );
+        let history = self.state.history.read().await;
+        let queued = self.state.queued_broadcasts();
+        let memory = &self.state.memory;
+        let mailbox = self.state.mailbox.read().await;
+        let usage = memory.usage(&history, &mailbox, queued);
+        let held = mailbox.message_count();
+        drop(mailbox);
+        let cap = match memory.cap() {
+            Some(cap) => format!(
+                "{} ({}%{})",
+                bandwidth::format_bytes(cap as u64),
+                usage.total() * 100 / cap,
+                if memory.under_pressure() {
+                    ", under pressure"
+                } else {
+                    ""
+                }
+            ),
+            None => "none".to_string(),
+        };
+        info!(
+            "Memory: history {} ({} messages) | Held DMs {} ({} messages) | Outbound queue ~{} ({} messages) | Cap: {} | Evicted: {} messages",
+            bandwidth::format_bytes(usage.history as u64),
+            history.message_count(),
+            bandwidth::format_bytes(usage.mailbox as u64),
+            held,
+            bandwidth::format_bytes(usage.outbound as u64),
+            queued,
+            cap,
+            memory.evicted()
+        );
+        drop(history);
+        if let Some(writer) = &self.state.history_writer {
+            let stats = writer.stats();
+            info!(
+                "History log: queue {}/{} | Written: {} records in {} batches | Dropped: {} (queue full) | Failed: {}",
+                writer.queued(),
+                writer.capacity(),
+                stats.written(),
+                stats.batches(),
+                stats.dropped(),
+                stats.failed()
+            );
+        }
+
+        let usage = bandwidth.usage_by_user();
+        if usage.is_empty() {
+            return;
+        }
+        info!("Per-user usage (this hour / total):");
+        for (user, usage) in usage {
+            let share = bandwidth
+                .quota()
+                .map(|limit| format!(" ({}%)", usage.window_bytes * 100 / limit))
+                .unwrap_or_default();
+            info!(
+                "  - {}: {}{} / {} in {} messages",
+                user,
+                bandwidth::format_bytes(usage.window_bytes),
+                share,
+                bandwidth::format_bytes(usage.total_bytes),
+                usage.total_messages
+            );
+        }
+    }
+
+    fn handle_channel_stats(&self) {
+        let channel = &self.state.channel;
+        let capacity = channel.capacity();
+        let queued = channel.queued();
+        info!(
+            "Broadcast channel: capacity {} | receivers {} | queued {} ({}%)",
+            capacity,
+            channel.receivers(),
+            queued,
+            queued * 100 / capacity
+        );
+        if let Some(fanout) = &self.state.fanout {
+            info!(
+                "Connection queues: {} broadcast(s) waiting to be sent",
+                fanout.queued()
+            );
+        }
+        let lags = channel.lags();
+        if lags.is_empty() {
+            info!("No connection has fallen behind");
+            return;
+        }
+        info!("Connections that fell behind (most often first):");
+        for (id, lag) in lags {
+            info!(
+                "  - {} {}: {} time(s), {} broadcast(s) missed",
+                id, lag.who, lag.events, lag.skipped
+            );
+        }
+        info!(
+            "If they keep falling behind, give them more room with /channel resize <capacity> (now {})",
+            capacity
+        );
+    }
+
+    fn handle_channel_resize(&self, capacity: usize) {
+        let previous = self.state.channel.capacity();
+        let moved = self.state.resize_channel(capacity);
+        success!(
+            "Broadcast channel resized from {} to {}, {} receiver(s) moved over",
+            previous,
+            capacity,
+            moved
+        );
+        if self.state.fanout.is_some() {
+            info!(
+                "Connections that join from now on get queues of {} too",
+                capacity
+            );
+        }
+    }
+
+    fn handle_privacy(&self, private: Option<bool>) {
+        let logging = &self.state.content_logging;
+        let Some(private) = private else {
+            if logging.enabled() {
+                warn!(
+                    "Message text is being logged. To log only sender, room and size, use /privacy on"
+                );
+            } else {
+                info!(
+                    "Only sender, room and size of messages are logged. To log their text too, use /privacy off"
+                );
+            }
+            return;
+        };
+        if !logging.set(!private, &self.state.audit, "console") {
+            info!(
+                "{}",
+                if private {
+                    "Message text already isn't logged"
+                } else {
+                    "Message text is already being logged"
+                }
+            );
+        } else if private {
+            success!("Message text is no longer logged");
+        } else {
+            warn!("Message text is now logged (noted in the audit log)");
+        }
+    }
+
+    async fn handle_say(&self, room: String, message: String) {
+        let Some(room) = self.resolve_room(&room).await else {
+            return;
+        };
+        if self.state.rooms.read().await.is_archived(&room) {
+            error!("#{} is archived - it's read-only", room);
+            return;
+        }
+
+        let identity = &self.state.server_identity;
+        let content = sender_class::tag(
+            SenderClass::Server,
+            &format!("{}|{}|{}", room, identity, message),
+        );
+        let Ok(room_message) =
+            ChatMessage::try_new(MessageTypes::RoomMessage, Some(content.into_bytes()))
+        else {
+            error!("Message is too large to send");
+            return;
+        };
+        // Kept in the room's history like anything else said there
+        let _ = self
+            .state
+            .record_history(&room, identity, &message, room_message, SERVER_ORIGIN)
+            .await;
+        chat!(room = %room, "{}: {}", identity, message);
+    }
+
+    async fn handle_shell(&mut self, command: String, room: String, sender: Option<String>) {
+        let Some(room) = self.resolve_room(&room).await else {
+            return;
+        };
+        if self.state.rooms.read().await.is_archived(&room) {
+            error!("#{} is archived - it's read-only", room);
+            return;
+        }
+        let sender = sender.unwrap_or_else(|| self.state.server_identity.clone());
+        match self
+            .shells
+            .start(command.clone(), room.clone(), sender, self.state.clone())
+        {
+            Ok(id) => success!(
+                "Shell relay {}: posting the output of '{}' to #{} (stop it with /shell --stop {})",
+                id,
+                command,
+                room,
+                id
+            ),
+            Err(e) => error!("Failed to run '{}': {}", command, e),
+        }
+    }
+
+    fn handle_shell_list(&mut self) {
+        let relays = self.shells.running();
+        if relays.is_empty() {
+            info!("No shell relays running.");
+            return;
+        }
+        info!("Shell relays ({}):", relays.len());
+        for relay in relays {
+            info!(
+                "  [{}] '{}' to #{} for {}",
+                relay.id,
+                relay.command,
+                relay.room,
+                schedule::format_interval(relay.started.elapsed())
+            );
+        }
+    }
+
+    fn handle_shell_stop(&mut self, id: u32) {
+        match self.shells.stop(id) {
+            Some(relay) => {
+                success!("Stopped shell relay {} ('{}')", id, relay.command)
+            }
+            None => error!("No shell relay with id {}", id),
+        }
+    }
+
+    /// Normalize a room name typed on the console and check that the room exists
+    async fn resolve_room(&self, room: &str) -> Option<String> {
+        let Some(room) = rooms::normalize_room_name(room) else {
+            error!("Invalid room name '{}'", room);
+            return None;
+        };
+        if !self.state.rooms.read().await.exists(&room) {
+            error!("Room '#{}' not found", room);
+            return None;
+        }
+        Some(room)
+    }
+
     fn handle_help(&self) {
         for line in commands::help_text() {
-            logger::log_info(&line);
+            info!("{}", line);
         }
     }
 }
 
-fn load_tls_config(cert_path: &str, key_path: &str) -> io::Result<ServerConfig> {
+/// Log a queued job's progress (at most once a second) and when it finishes
+fn report_progress(
+    label: &'static str,
+    unit: &'static str,
+    mut progress: watch::Receiver<Progress>,
+) {
+    tokio::spawn(async move {
+        let mut last_report = Instant::now();
+        while progress.changed().await.is_ok() {
+            let current = *progress.borrow_and_update();
+            if current.is_finished() {
+                success!("{}: done ({} {})", label, current.total, unit);
+                return;
+            }
+            if last_report.elapsed() >= Duration::from_secs(1) {
+                info!("{}: {}/{} {}", label, current.done, current.total, unit);
+                last_report = Instant::now();
+            }
+        }
+    });
+}
+
+/// Sleep until `when`, e.g. a scheduled announcement is due (forever if None)
+async fn sleep_until(when: Option<DateTime<Local>>) {
+    match when {
+        Some(when) => {
+            let delay = (when - Local::now()).to_std().unwrap_or_default();
+            tokio::time::sleep(delay).await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// e.g. "every 6h, next at 2026-01-15 02:00 to #ops: Maintenance tonight"
+fn describe_scheduled(entry: &schedule::ScheduledAnnouncement) -> String {
+    let when = match entry.every {
+        Some(every) => format!("every {}, next at", schedule::format_interval(every)),
+        None => "once at".to_string(),
+    };
+    let target = match &entry.room {
+        Some(room) => format!("#{}", room),
+        None => "everyone".to_string(),
+    };
+    format!(
+        "{} {} to {}: {}",
+        when,
+        entry.next.format("%Y-%m-%d %H:%M"),
+        target,
+        entry.message
+    )
+}
+
+fn describe_networks(networks: &[IpNetwork]) -> String {
+    networks
+        .iter()
+        .map(|network| bans::describe(*network))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// " for 24h" for a temporary ban, nothing for a permanent one
+fn describe_ban_duration(duration: Option<Duration>) -> String {
+    duration
+        .map(|duration| format!(" for {}", schedule::format_interval(duration)))
+        .unwrap_or_default()
+}
+
+/// Load the certificate and key, returning the TLS config and the certificate's
+/// fingerprint (for clients that pin it). With a client verifier, clients may log
+/// in with certificates (mutual TLS).
+fn load_tls_config(
+    cert_path: &str,
+    key_path: &str,
+    client_verifier: Option<Arc<dyn ClientCertVerifier>>,
+) -> io::Result<(rustls::ServerConfig, String)> {
     let cert_file = File::open(cert_path).map_err(|e| {
         io::Error::new(
             io::ErrorKind::NotFound,
@@ -390,6 +2114,10 @@ fn load_tls_config(cert_path: &str, key_path: &str) -> io::Result<ServerConfig>
                 format!("Invalid certificate: {}", e),
             )
         })?;
+    let fingerprint = certs
+        .first()
+        .map(|cert| shared::tls::certificate_fingerprint(cert))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "No certificate found"))?;
 
     let key = private_key(&mut key_reader)
         .map_err(|e| {
@@ -400,75 +2128,469 @@ fn load_tls_config(cert_path: &str, key_path: &str) -> io::Result<ServerConfig>
         })?
         .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "No private key found"))?;
 
-    let config = ServerConfig::builder()
-        .with_no_client_auth()
-        .with_single_cert(certs, key)
-        .map_err(|e| {
-            io::Error::new(
-                io::ErrorKind::InvalidData,
-                format!("TLS config error: {}", e),
+    let builder = rustls::ServerConfig::builder();
+    let builder = match client_verifier {
+        Some(verifier) => builder.with_client_cert_verifier(verifier),
+        None => builder.with_no_client_auth(),
+    };
+    let config = builder.with_single_cert(certs, key).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("TLS config error: {}", e),
+        )
+    })?;
+
+    Ok((config, fingerprint))
+}
+
+/// First start: make an admin account whose password has to be changed before the
+/// console grants moderator or admin scopes
+async fn create_admin(accounts: &mut AccountStore, password: Option<String>) {
+    let (password, generated) = match password {
+        Some(password) => (password, false),
+        None => (accounts::one_time_password(), true),
+    };
+    let result = match HashedPassword::new(&password).await {
+        Ok(hashed) => match accounts.register_one_time(ADMIN_NAME, hashed) {
+            Ok(save) => save.write().await.map_err(AccountError::Io),
+            Err(e) => Err(e),
+        },
+        Err(e) => Err(e),
+    };
+    match result {
+        Ok(()) => {
+            success!("No accounts yet - created '{}'", ADMIN_NAME);
+            if generated {
+                // Only the hash is kept, so this is the one chance to copy it
+                warn!("One-time password for '{}': {}", ADMIN_NAME, password);
+            } else {
+                info!(
+                    "Its one-time password is {}",
+                    config::CHAT_SERVER_ADMIN_PASSWORD_ENV_VAR
+                );
+            }
+            info!(
+                "Log in as '{}' and set a new password with /register <password>",
+                ADMIN_NAME
+            );
+        }
+        Err(e) => error!("Failed to create the '{}' account: {}", ADMIN_NAME, e),
+    }
+}
+
+/// Identity systems registered-nickname logins are checked against after the local accounts
+#[allow(unused_variables)]
+fn auth_providers(config: &ServerConfig) -> Vec<Box<dyn AuthProvider>> {
+    #[allow(unused_mut)]
+    let mut providers: Vec<Box<dyn AuthProvider>> = Vec::new();
+    #[cfg(feature = "ldap")]
+    if let Some(ldap) = &config.ldap {
+        match auth::ldap::LdapProvider::new(ldap.url.clone(), ldap.user_dn.clone()) {
+            Some(provider) => providers.push(Box::new(provider)),
+            None => warn!(
+                "{} must contain {} - LDAP logins disabled",
+                config::CHAT_SERVER_LDAP_USER_DN_ENV_VAR,
+                auth::ldap::USERNAME_PLACEHOLDER
+            ),
+        }
+    }
+    #[cfg(feature = "oidc")]
+    if let Some(oidc) = &config.oidc {
+        let provider = fs::read(&oidc.key_path)
+            .map_err(|e| e.to_string())
+            .and_then(|pem| {
+                auth::oidc::OidcProvider::new(
+                    &pem,
+                    &oidc.issuer,
+                    &oidc.audience,
+                    oidc.username_claim.clone(),
+                )
+            });
+        match provider {
+            Ok(provider) => providers.push(Box::new(provider)),
+            Err(e) => warn!(
+                "Failed to load OIDC key '{}': {} - OIDC logins disabled",
+                oidc.key_path, e
+            ),
+        }
+    }
+    providers
+}
+
+/// The audit log, also streaming to a syslog collector if one is configured
+fn with_syslog(audit: AuditLog, settings: Option<&SyslogSettings>) -> io::Result<AuditLog> {
+    let Some(settings) = settings else {
+        info!(
+            "To stream audit events to a syslog collector, set {} (udp://, tcp:// or tls://host[:port])",
+            config::CHAT_SERVER_SYSLOG_ENV_VAR
+        );
+        return Ok(audit);
+    };
+    let roots = match &settings.ca_path {
+        Some(path) => Some(syslog::load_roots(path).inspect_err(|e| {
+            error!(
+                "Failed to load syslog CA certificates '{}': {}",
+                path.display(),
+                e
             )
-        })?;
+        })?),
+        None => None,
+    };
+    info!(
+        "Audit events are also sent to syslog at {}",
+        settings.target
+    );
+    Ok(
+        audit.with_syslog(syslog::SyslogSink::start(syslog::SyslogConfig {
+            target: settings.target.clone(),
+            facility: settings.facility,
+            severities: settings.severities.clone(),
+            roots,
+        })),
+    )
+}
 
-    Ok(config)
+/// Mutual TLS: clients may present a certificate signed by one of the configured CAs,
+/// and the mapping file says which nickname it logs in as
+fn client_certificates(
+    settings: Option<&ClientCertSettings>,
+) -> (Option<Arc<dyn ClientCertVerifier>>, Option<ClientCertMap>) {
+    let Some(settings) = settings else {
+        return (None, None);
+    };
+    let verifier = match client_cert::client_verifier(&settings.ca_path, settings.required) {
+        Ok(verifier) => verifier,
+        Err(e) => {
+            error!(
+                "Failed to load client CA certificates from '{}': {} - client certificates disabled",
+                settings.ca_path, e
+            );
+            return (None, None);
+        }
+    };
+    let map = ClientCertMap::load(&settings.map_path).unwrap_or_else(|e| {
+        warn!(
+            "Failed to load client certificate mappings from '{}': {}",
+            settings.map_path.display(),
+            e
+        );
+        ClientCertMap::default()
+    });
+    if map.is_empty() {
+        warn!(
+            "No client certificate mappings in '{}' - certificates won't log anyone in. To change the file, set {}",
+            settings.map_path.display(),
+            config::TLS_CLIENT_CERT_MAP_ENV_VAR
+        );
+    } else {
+        info!(
+            "Client certificates log in to {} nickname(s) mapped in '{}'",
+            map.len(),
+            settings.map_path.display()
+        );
+    }
+    if settings.required {
+        info!("Clients without a certificate are turned away");
+    } else {
+        info!(
+            "Clients without a certificate can still connect. To require one, set {}=1",
+            config::TLS_CLIENT_CERT_REQUIRED_ENV_VAR
+        );
+    }
+    (Some(verifier), Some(map))
 }
 
-#[tokio::main]
-async fn main() -> io::Result<()> {
-    const CHAT_SERVER_ADDR_ENV_VAR: &str = "CHAT_SERVER_ADDR";
-    const CHAT_SERVER_MAX_CLIENTS_ENV_VAR: &str = "CHAT_SERVER_MAX_CLIENTS";
-    const TLS_CERT_PATH_ENV_VAR: &str = "TLS_CERT_PATH";
-    const TLS_KEY_PATH_ENV_VAR: &str = "TLS_KEY_PATH";
-
-    let chat_server_addr = env::var(CHAT_SERVER_ADDR_ENV_VAR).unwrap_or("0.0.0.0:8080".to_string());
-    let max_clients = env::var(CHAT_SERVER_MAX_CLIENTS_ENV_VAR)
-        .unwrap_or("100".to_string())
-        .parse::<usize>()
-        .unwrap_or(100);
-
-    // Check if TLS is configured
-    let tls_acceptor = match (
-        env::var(TLS_CERT_PATH_ENV_VAR),
-        env::var(TLS_KEY_PATH_ENV_VAR),
-    ) {
-        (Ok(cert_path), Ok(key_path))
-            if Path::new(&cert_path).exists() && Path::new(&key_path).exists() =>
+/// TLS for the configured certificate, generated first if it should be self-signed
+/// and isn't there yet (None = connections are unencrypted)
+fn tls_acceptor(
+    config: &ServerConfig,
+    client_verifier: Option<Arc<dyn ClientCertVerifier>>,
+    client_certs: bool,
+) -> Option<TlsAcceptor> {
+    let Some((cert_path, key_path)) = config.tls_paths.as_ref().filter(|(cert_path, key_path)| {
+        (Path::new(cert_path).exists() && Path::new(key_path).exists()) || config.self_signed
+    }) else {
+        info!("TLS not configured - running without encryption");
+        info!(
+            "To enable TLS, set {} and {} environment variables, or {}=1 for a self-signed certificate",
+            config::TLS_CERT_PATH_ENV_VAR,
+            config::TLS_KEY_PATH_ENV_VAR,
+            config::TLS_SELF_SIGNED_ENV_VAR
+        );
+        return None;
+    };
+    if !Path::new(cert_path).exists() || !Path::new(key_path).exists() {
+        let mut names = vec!["localhost".to_string()];
+        if let Ok(addr) = config.addr.parse::<SocketAddr>()
+            && !addr.ip().is_unspecified()
         {
-            logger::log_info("TLS enabled - loading certificates...");
-            match load_tls_config(&cert_path, &key_path) {
-                Ok(config) => {
-                    logger::log_success("TLS certificates loaded successfully");
-                    Some(TlsAcceptor::from(Arc::new(config)))
-                }
-                Err(e) => {
-                    logger::log_error(&format!("Failed to load TLS config: {}", e));
-                    logger::log_warning("Starting server WITHOUT TLS encryption");
-                    None
-                }
+            names.push(addr.ip().to_string());
+        }
+        match self_signed::generate(Path::new(cert_path), Path::new(key_path), names) {
+            Ok(()) => success!("Generated a self-signed certificate in '{}'", cert_path),
+            Err(e) => error!("Failed to generate a self-signed certificate: {}", e),
+        }
+    }
+    info!("TLS enabled - loading certificates...");
+    match load_tls_config(cert_path, key_path, client_verifier) {
+        Ok((tls_config, fingerprint)) => {
+            success!("TLS certificates loaded successfully");
+            info!("Certificate fingerprint (SHA-256): {}", fingerprint);
+            if config.self_signed {
+                info!("Clients connecting with tls:// can trust it with /trust <fingerprint>");
+            }
+            if !client_certs {
+                info!(
+                    "To let clients log in with certificates (mutual TLS), set {}",
+                    config::TLS_CLIENT_CA_PATH_ENV_VAR
+                );
             }
+            Some(TlsAcceptor::from(Arc::new(tls_config)))
         }
-        _ => {
-            logger::log_info("TLS not configured - running without encryption");
-            logger::log_info(&format!(
-                "To enable TLS, set {} and {} environment variables",
-                TLS_CERT_PATH_ENV_VAR, TLS_KEY_PATH_ENV_VAR
-            ));
+        Err(e) => {
+            error!("Failed to load TLS config: {}", e);
+            warn!("Starting server WITHOUT TLS encryption");
             None
         }
+    }
+}
+
+/// Tor publishes the onion service; we only read the address it generated
+fn announce_onion(config: &ServerConfig) {
+    let Some(onion_dir) = &config.onion_dir else {
+        info!(
+            "To announce as a Tor onion service, set {} to tor's HiddenServiceDir",
+            config::CHAT_SERVER_ONION_DIR_ENV_VAR
+        );
+        return;
+    };
+    match fs::read_to_string(onion_dir.join("hostname")) {
+        Ok(hostname) => {
+            success!("Announced as Tor onion service {}", hostname.trim());
+            if config
+                .addr
+                .parse::<SocketAddr>()
+                .is_ok_and(|addr| addr.ip().is_unspecified())
+            {
+                warn!(
+                    "Also reachable without Tor - to serve only the onion service, listen on 127.0.0.1"
+                );
+            }
+        }
+        Err(e) => warn!(
+            "No onion service hostname in '{}' ({}) - is HiddenServiceDir set in torrc and tor running?",
+            onion_dir.display(),
+            e
+        ),
+    }
+}
+
+fn main() -> io::Result<()> {
+    let _telemetry = telemetry::init();
+
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    if config::io_uring() {
+        match uring::run(serve()) {
+            Ok(result) => return result,
+            Err(e) => warn!(
+                "io_uring isn't available ({}) - using the default runtime",
+                e
+            ),
+        }
+    }
+
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?
+        .block_on(serve())
+}
+
+async fn serve() -> io::Result<()> {
+    // Everything else is set with environment variables
+    let mut repair = false;
+    for arg in env::args().skip(1) {
+        match arg.as_str() {
+            "--repair" => repair = true,
+            _ => {
+                error!(
+                    "Unknown argument '{}' - the server only takes --repair; everything else is set with environment variables",
+                    arg
+                );
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "unknown argument",
+                ));
+            }
+        }
+    }
+    let config = ServerConfig::from_env()?;
+    let data_dir = &config.data_dir;
+
+    // Check the data directory before loading it: a corrupt file is set aside (or
+    // with --repair, cut down to its readable lines) rather than stopping the server
+    let outcomes = integrity::check(data_dir, repair)
+        .inspect_err(|e| error!("Failed to check the data directory: {}", e))?;
+    for (store, outcome) in outcomes {
+        match outcome {
+            Outcome::Quarantined { .. } => error!(
+                "{} is corrupt: {} - starting without its {}. Run the server with --repair to keep the readable lines",
+                store.file, outcome, store.holds
+            ),
+            // Only the history database is checked this way, below
+            #[cfg(feature = "sqlite")]
+            Outcome::Damaged { .. } => error!("{} is corrupt: {}", store.file, outcome),
+            Outcome::Repaired { .. } => warn!("Repaired {}: {}", store.file, outcome),
+            Outcome::Intact | Outcome::Missing if repair => {
+                info!("{}: {}", store.file, outcome)
+            }
+            Outcome::Intact | Outcome::Missing => {}
+        }
+    }
+    let blocks = BlockList::load(Some(data_dir.join(blocks::BLOCKS_FILE)))
+        .inspect_err(|e| error!("Failed to load block list: {}", e))?;
+    let mut accounts = AccountStore::load(Some(data_dir.join(accounts::ACCOUNTS_FILE)))
+        .inspect_err(|e| error!("Failed to load accounts: {}", e))?;
+    if accounts.is_empty() {
+        create_admin(&mut accounts, config.admin_password.clone()).await;
+    }
+    let tokens = TokenStore::load(Some(data_dir.join(bot_token::TOKENS_FILE)))
+        .inspect_err(|e| error!("Failed to load bot tokens: {}", e))?;
+    let seen = SeenLog::load(Some(data_dir.join(seen::SEEN_FILE)))
+        .inspect_err(|e| error!("Failed to load last-seen log: {}", e))?;
+    let maintenance =
+        MaintenanceWindow::load(Some(data_dir.join(maintenance_window::MAINTENANCE_FILE)))
+            .inspect_err(|e| error!("Failed to load maintenance window: {}", e))?;
+
+    let tracer = if config.trace {
+        Some(
+            Tracer::open(&config.trace_path())
+                .inspect_err(|e| error!("Failed to open trace file: {}", e))?,
+        )
+    } else {
+        None
     };
 
-    let mut server = ChatServer::new(&chat_server_addr, max_clients, tls_acceptor).await?;
-
-    logger::log_success(&format!("Chat Server started at {}", chat_server_addr));
-    logger::log_info(&format!(
-        "To change address, set {} environment variable",
-        CHAT_SERVER_ADDR_ENV_VAR
-    ));
-    logger::log_info(&format!(
-        "To change max clients, set {} environment variable",
-        CHAT_SERVER_MAX_CLIENTS_ENV_VAR
-    ));
-    logger::log_info("Server commands: /help, /list, /quit");
+    let audit_path = data_dir.join(audit::AUDIT_FILE);
+    let audit =
+        AuditLog::open(&audit_path).inspect_err(|e| error!("Failed to open audit log: {}", e))?;
+    let audit = with_syslog(audit, config.syslog.as_ref())?;
+
+    // Room history kept in the data directory (or an SQLite database): replayed now,
+    // then appended to as messages are stored, by a task of its own
+    let history_path = config.history_path();
+    let mut history = RoomHistory::new();
+    let mut history_writer = None;
+    #[cfg(feature = "sqlite")]
+    if config.history_db().is_some() {
+        match integrity::check_database(&history_path)
+            .inspect_err(|e| error!("Failed to check the history database: {}", e))?
+        {
+            outcome @ Outcome::Damaged { .. } => error!(
+                "{} is corrupt: {} - starting without its room history",
+                history_path.display(),
+                outcome
+            ),
+            outcome if repair => info!("{}: {}", history_path.display(), outcome),
+            _ => {}
+        }
+        let restored = history_db::load(&history_path, &mut history)
+            .inspect_err(|e| error!("Failed to load the history database: {}", e))?;
+        info!(
+            "Restored {} stored message(s) from {}",
+            restored,
+            history_path.display()
+        );
+        history_writer = Some(
+            HistoryWriter::start_db(&history_path)
+                .await
+                .inspect_err(|e| error!("Failed to open the history database: {}", e))?,
+        );
+    }
+    if config.history_db().is_none() && config.persist_history {
+        let restored = history_writer::load(&history_path, &mut history)
+            .inspect_err(|e| error!("Failed to load the history log: {}", e))?;
+        info!(
+            "Restored {} stored message(s) from {}",
+            restored,
+            history_path.display()
+        );
+        history_writer = Some(
+            HistoryWriter::start(&history_path)
+                .await
+                .inspect_err(|e| error!("Failed to open the history log: {}", e))?,
+        );
+    }
+
+    let (client_verifier, client_certs) = client_certificates(config.client_certs.as_ref());
+    let tls_acceptor = tls_acceptor(&config, client_verifier, client_certs.is_some());
+
+    let settings = ServerSettings {
+        max_clients: config.max_clients,
+        waiting_room_size: config.waiting_room_size,
+        reserved_slots: config.reserved_slots,
+        server_identity: config.server_identity.clone(),
+        info: config.info.clone(),
+        paranoid_max_violations: config.paranoid_max_violations,
+        bandwidth_quota: config.bandwidth_quota,
+        reclaim_policy: config.reclaim_policy,
+        nick_conflict: config.nick_conflict,
+        tracer,
+        kick_cooldown: config.kick_cooldown,
+        audit,
+        pow_difficulty: config.pow_difficulty,
+        auth_providers: auth_providers(&config),
+        open_registration: config.open_registration,
+        guest_scopes: config.guest_scopes,
+        multi_session: config.multi_session,
+        log_content: config.log_content,
+        // Without TLS there are no certificates to log in with
+        client_certs: client_certs.filter(|_| tls_acceptor.is_some()),
+        memory_cap: config.memory_cap,
+        broadcast_shards: config.broadcast_shards,
+        room_export: config.room_export,
+        room_export_limit: config.room_export_limit,
+        translator: config.translator.clone(),
+        history,
+        history_writer,
+        write_buffer: config.write_buffer,
+        socket_options: config.socket_options,
+    };
+    let mut server = ChatServer::new(
+        &config.addr,
+        settings,
+        blocks,
+        accounts,
+        tokens,
+        seen,
+        tls_acceptor,
+    )
+    .await?;
+    server.load_scheduled_announcements(&data_dir.join(schedule::ANNOUNCEMENTS_FILE));
+    if let Some(banner) = maintenance.banner(Local::now()) {
+        info!("{}", banner);
+    }
+    *server.state.maintenance.write().await = maintenance;
+
+    success!(
+        "Chat Server '{}' started at {}",
+        config.info.display_name(),
+        config.addr
+    );
+    announce_onion(&config);
+    config.log_settings();
+    info!(
+        "Client fingerprints, joins, kicks and bans are recorded in '{}'",
+        audit_path.display()
+    );
+    info!(
+        "Nickname logins are checked against: {}",
+        server.state.auth.provider_names().join(", ")
+    );
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    if uring::active() {
+        info!("Socket I/O through io_uring (single-threaded runtime)");
+    }
+    info!("Server commands: /help, /list, /stats, /drain, /quit");
 
     server.run().await
 }
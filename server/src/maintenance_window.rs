@@ -0,0 +1,325 @@
+//! Planned maintenance (/maintenance set). Users are shown a banner when they join
+//! and reminded as the window approaches; when it starts the server drains and shuts
+//! down. The window is saved to the data directory, so a server brought back up
+//! before the window is over keeps showing it (without draining again), and it's
+//! forgotten once it has ended. Stored as one `start<TAB>end<TAB>text` line (unix
+//! seconds).
+
+use crate::schedule;
+use chrono::{DateTime, Local, NaiveTime, TimeDelta, TimeZone};
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// File name of the maintenance window inside the server data directory
+pub const MAINTENANCE_FILE: &str = "maintenance.tsv";
+
+/// How long users have to finish up once the window starts
+pub const DRAIN_GRACE: Duration = Duration::from_secs(60);
+
+/// Time before the window at which connected users are reminded of it
+const REMINDER_MARKS: &[Duration] = &[
+    Duration::from_secs(24 * 60 * 60),
+    Duration::from_secs(6 * 60 * 60),
+    Duration::from_secs(60 * 60),
+    Duration::from_secs(30 * 60),
+    Duration::from_secs(10 * 60),
+    Duration::from_secs(60),
+];
+
+/// When a window starts, as given to /maintenance set
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Start {
+    /// The next time the clock reads HH:MM
+    At(NaiveTime),
+    /// This long from now
+    In(Duration),
+}
+
+impl Start {
+    /// Parse "HH:MM" or an interval like "30m"
+    pub fn parse(value: &str) -> Option<Self> {
+        match NaiveTime::parse_from_str(value, "%H:%M") {
+            Ok(time) => Some(Start::At(time)),
+            Err(_) => schedule::parse_interval(value).map(Start::In),
+        }
+    }
+
+    pub fn resolve(self, now: DateTime<Local>) -> DateTime<Local> {
+        match self {
+            Start::At(time) => schedule::next_time_of_day(time, now),
+            Start::In(delay) => now + TimeDelta::from_std(delay).unwrap_or(TimeDelta::MAX),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Window {
+    pub start: DateTime<Local>,
+    pub end: DateTime<Local>,
+    pub text: String,
+}
+
+/// What has come due for the window
+#[derive(Debug, PartialEq)]
+pub enum Due {
+    /// Remind users; the window starts in about this long
+    Reminder(Duration),
+    /// The window has started: time to drain
+    Start,
+    /// The window is over and has been forgotten
+    End,
+}
+
+#[derive(Debug, Default)]
+pub struct MaintenanceWindow {
+    /// None = only kept in memory
+    path: Option<PathBuf>,
+    window: Option<Window>,
+    /// Index of the next reminder mark that hasn't been announced yet
+    next_mark: usize,
+    /// The window's start has been handled (or had passed when the server started)
+    started: bool,
+}
+
+impl MaintenanceWindow {
+    /// Load the window saved at `path`, unless it's over (a missing file is no window)
+    pub fn load(path: Option<PathBuf>) -> io::Result<Self> {
+        let mut maintenance = MaintenanceWindow {
+            path,
+            ..MaintenanceWindow::default()
+        };
+        let Some(path) = &maintenance.path else {
+            return Ok(maintenance);
+        };
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(maintenance),
+            Err(e) => return Err(e),
+        };
+        let timestamp = |secs: &str| Local.timestamp_opt(secs.parse().ok()?, 0).single();
+        let mut fields = contents.trim_end_matches('\n').splitn(3, '\t');
+        if let (Some(start), Some(end), Some(text)) = (
+            fields.next().and_then(timestamp),
+            fields.next().and_then(timestamp),
+            fields.next(),
+        ) {
+            let now = Local::now();
+            if end > now {
+                maintenance.started = start <= now;
+                maintenance.skip_marks(start - now);
+                maintenance.window = Some(Window {
+                    start,
+                    end,
+                    text: text.to_string(),
+                });
+            } else {
+                maintenance.save()?;
+            }
+        }
+        Ok(maintenance)
+    }
+
+    /// Whether `line` is a window as `save` writes it (for the startup integrity check)
+    pub fn check_line(line: &str) -> bool {
+        let timestamp = |secs: &str| Local.timestamp_opt(secs.parse().ok()?, 0).single();
+        let mut fields = line.splitn(3, '\t');
+        matches!(
+            (
+                fields.next().and_then(timestamp),
+                fields.next().and_then(timestamp),
+                fields.next(),
+            ),
+            (Some(_), Some(_), Some(_))
+        )
+    }
+
+    pub fn window(&self) -> Option<&Window> {
+        self.window.as_ref()
+    }
+
+    /// Plan `window`, replacing any window already planned
+    pub fn set(&mut self, window: Window, now: DateTime<Local>) -> io::Result<()> {
+        self.started = window.start <= now;
+        self.skip_marks(window.start - now);
+        self.window = Some(window);
+        self.save()
+    }
+
+    /// Forget the window. Returns it if there was one.
+    pub fn clear(&mut self) -> io::Result<Option<Window>> {
+        let window = self.window.take();
+        self.save()?;
+        Ok(window)
+    }
+
+    /// What users are told about the window, e.g. "Maintenance 2026-10-17 02:00 to
+    /// 03:00 (in 6h): Database upgrade"
+    pub fn banner(&self, now: DateTime<Local>) -> Option<String> {
+        let window = self.window.as_ref()?;
+        let end = if window.end.date_naive() == window.start.date_naive() {
+            window.end.format("%H:%M")
+        } else {
+            window.end.format("%Y-%m-%d %H:%M")
+        };
+        Some(if now < window.start {
+            // Rounded up to the minute, so a reminder shows the mark it's for
+            let until = (window.start - now).to_std().unwrap_or_default();
+            let minutes = until.as_secs().div_ceil(60);
+            format!(
+                "Maintenance {} to {} (in {}): {}",
+                window.start.format("%Y-%m-%d %H:%M"),
+                end,
+                schedule::format_interval(Duration::from_secs(minutes * 60)),
+                window.text
+            )
+        } else {
+            format!("Maintenance in progress until {}: {}", end, window.text)
+        })
+    }
+
+    /// When `take_due` next has something to return (None = no window)
+    pub fn next_due(&self) -> Option<DateTime<Local>> {
+        let window = self.window.as_ref()?;
+        if self.started {
+            return Some(window.end);
+        }
+        Some(match REMINDER_MARKS.get(self.next_mark) {
+            Some(mark) => window.start - TimeDelta::from_std(*mark).unwrap_or(TimeDelta::MAX),
+            None => window.start,
+        })
+    }
+
+    /// What has come due by `now`; a window that's over is forgotten
+    pub fn take_due(&mut self, now: DateTime<Local>) -> io::Result<Option<Due>> {
+        let Some(window) = &self.window else {
+            return Ok(None);
+        };
+        if now >= window.end {
+            self.clear()?;
+            return Ok(Some(Due::End));
+        }
+        if self.started {
+            return Ok(None);
+        }
+        if now >= window.start {
+            self.started = true;
+            return Ok(Some(Due::Start));
+        }
+        // If several marks were crossed at once only the latest is announced
+        let until = (window.start - now).to_std().unwrap_or_default();
+        let mut due = None;
+        while let Some(mark) = REMINDER_MARKS.get(self.next_mark) {
+            if until > *mark {
+                break;
+            }
+            due = Some(Due::Reminder(*mark));
+            self.next_mark += 1;
+        }
+        Ok(due)
+    }
+
+    /// Skip marks further out than `until`, the time left before the start
+    fn skip_marks(&mut self, until: TimeDelta) {
+        let until = until.to_std().unwrap_or_default();
+        self.next_mark = REMINDER_MARKS
+            .iter()
+            .take_while(|mark| **mark >= until)
+            .count();
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let Some(window) = &self.window else {
+            return match fs::remove_file(path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+                _ => Ok(()),
+            };
+        };
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(
+            path,
+            format!(
+                "{}\t{}\t{}\n",
+                window.start.timestamp(),
+                window.end.timestamp(),
+                window.text
+            ),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn minutes(n: u64) -> Duration {
+        Duration::from_secs(n * 60)
+    }
+
+    #[test]
+    fn test_window_reminds_starts_and_ends() {
+        assert_eq!(
+            Start::parse("02:30"),
+            Some(Start::At(NaiveTime::from_hms_opt(2, 30, 0).unwrap()))
+        );
+        assert_eq!(Start::parse("45m"), Some(Start::In(minutes(45))));
+        assert_eq!(Start::parse("soon"), None);
+
+        let path =
+            std::env::temp_dir().join(format!("rust_chat_maintenance_{}.tsv", std::process::id()));
+        let mut maintenance = MaintenanceWindow::load(Some(path.clone())).unwrap();
+        let now = Local::now();
+        let start = Start::In(minutes(45)).resolve(now);
+        let window = Window {
+            start,
+            end: start + TimeDelta::hours(1),
+            text: "Database upgrade".to_string(),
+        };
+        maintenance.set(window.clone(), now).unwrap();
+        assert!(
+            maintenance
+                .banner(now)
+                .unwrap()
+                .ends_with("(in 45m): Database upgrade")
+        );
+
+        // Marks further out than the window are covered by /maintenance set itself
+        let at = |left: u64| start - TimeDelta::minutes(left as i64);
+        assert_eq!(maintenance.next_due(), Some(at(30)));
+        assert_eq!(maintenance.take_due(at(40)).unwrap(), None);
+        assert_eq!(
+            maintenance.take_due(at(30)).unwrap(),
+            Some(Due::Reminder(minutes(30)))
+        );
+        assert_eq!(maintenance.take_due(at(30)).unwrap(), None);
+        assert_eq!(
+            maintenance.take_due(at(1)).unwrap(),
+            Some(Due::Reminder(minutes(1)))
+        );
+
+        // A server restarted before the window picks it up again
+        let reloaded = MaintenanceWindow::load(Some(path.clone())).unwrap();
+        let saved = reloaded.window().unwrap();
+        assert_eq!(saved.start.timestamp(), start.timestamp());
+        assert_eq!(saved.text, window.text);
+
+        assert_eq!(maintenance.take_due(start).unwrap(), Some(Due::Start));
+        assert!(
+            maintenance
+                .banner(start)
+                .unwrap()
+                .starts_with("Maintenance in progress")
+        );
+        assert_eq!(maintenance.take_due(start).unwrap(), None);
+        assert_eq!(maintenance.next_due(), Some(window.end));
+        assert_eq!(maintenance.take_due(window.end).unwrap(), Some(Due::End));
+        assert_eq!(maintenance.window(), None);
+        assert!(!path.exists());
+    }
+}
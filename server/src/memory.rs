@@ -0,0 +1,194 @@
+//! Memory cap (CHAT_SERVER_MEMORY_CAP_MB) for what the server holds on behalf of
+//! users: room history, direct messages held for offline users and broadcasts queued
+//! for connections that haven't read them yet. As usage nears the cap the oldest
+//! history and held messages are evicted, whatever the rooms' retention policies, so
+//! a server in a small container isn't OOM killed.
+
+use crate::bandwidth::format_bytes;
+use crate::history::RoomHistory;
+use crate::mailbox::Mailbox;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use tracing::{info, warn};
+
+/// Share of the cap (percent) at which history starts being evicted
+pub const PRESSURE_PERCENT: usize = 90;
+/// Share of the cap (percent) eviction brings usage back down to
+pub const TARGET_PERCENT: usize = 75;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MemoryUsage {
+    pub history: usize,
+    /// Direct messages held for offline users
+    pub mailbox: usize,
+    /// Estimate for broadcasts not yet delivered to every connection
+    pub outbound: usize,
+}
+
+impl MemoryUsage {
+    pub fn total(&self) -> usize {
+        self.history + self.mailbox + self.outbound
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct MemoryBudget {
+    /// Bytes allowed (None = no cap, usage is only reported)
+    cap: Option<usize>,
+    /// Moving average of broadcast sizes, to estimate the queue from its length
+    average_broadcast: AtomicUsize,
+    under_pressure: AtomicBool,
+    /// History and held messages evicted since startup
+    evicted: AtomicU64,
+}
+
+impl MemoryBudget {
+    pub fn new(cap: Option<usize>) -> Self {
+        MemoryBudget {
+            cap,
+            ..Self::default()
+        }
+    }
+
+    pub fn cap(&self) -> Option<usize> {
+        self.cap
+    }
+
+    pub fn evicted(&self) -> u64 {
+        self.evicted.load(Ordering::Relaxed)
+    }
+
+    pub fn under_pressure(&self) -> bool {
+        self.under_pressure.load(Ordering::Relaxed)
+    }
+
+    /// Note the size of a message put on the broadcast channel
+    pub fn note_broadcast(&self, bytes: usize) {
+        let average = self.average_broadcast.load(Ordering::Relaxed);
+        let average = if average == 0 {
+            bytes
+        } else {
+            (average * 15 + bytes) / 16
+        };
+        self.average_broadcast.store(average, Ordering::Relaxed);
+    }
+
+    /// Current usage, with `queued` messages waiting on the broadcast channel
+    pub fn usage(&self, history: &RoomHistory, mailbox: &Mailbox, queued: usize) -> MemoryUsage {
+        MemoryUsage {
+            history: history.bytes(),
+            mailbox: mailbox.bytes(),
+            outbound: queued * self.average_broadcast.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Evict the oldest history and held messages when usage is near the cap, each
+    /// in proportion to what it holds, warning on the console when pressure starts
+    /// and saying when it's over. Returns the messages evicted.
+    pub fn enforce(
+        &self,
+        history: &mut RoomHistory,
+        mailbox: &mut Mailbox,
+        queued: usize,
+    ) -> usize {
+        let Some(cap) = self.cap else {
+            return 0;
+        };
+        let usage = self.usage(history, mailbox, queued);
+        if usage.total() * 100 < cap * PRESSURE_PERCENT {
+            if self.under_pressure.swap(false, Ordering::Relaxed) {
+                info!(
+                    "Memory pressure over: {} of {} in use",
+                    format_bytes(usage.total() as u64),
+                    format_bytes(cap as u64)
+                );
+            }
+            return 0;
+        }
+
+        if !self.under_pressure.swap(true, Ordering::Relaxed) {
+            warn!(
+                "Memory use is near the cap: {} of {} (history {}, held DMs {}, outbound queue {}) - evicting the oldest room history and held DMs",
+                format_bytes(usage.total() as u64),
+                format_bytes(cap as u64),
+                format_bytes(usage.history as u64),
+                format_bytes(usage.mailbox as u64),
+                format_bytes(usage.outbound as u64)
+            );
+        }
+        let excess = usage.total() - cap * TARGET_PERCENT / 100;
+        let stored = usage.history + usage.mailbox;
+        let from_mailbox = if stored == 0 {
+            0
+        } else {
+            (excess as u128 * usage.mailbox as u128 / stored as u128) as usize
+        };
+        let evicted = mailbox.evict_oldest(from_mailbox)
+            + history.evict_oldest(excess.saturating_sub(from_mailbox));
+        self.evicted.fetch_add(evicted as u64, Ordering::Relaxed);
+        evicted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mailbox::MAX_HELD_PER_USER;
+    use crate::state::SERVER_ORIGIN;
+
+    #[test]
+    fn test_history_is_evicted_near_the_cap() {
+        let mut mailbox = Mailbox::default();
+        let mut history = RoomHistory::new();
+        for i in 0..100 {
+            history.record("ops", "alice", &format!("message {}", i), SERVER_ORIGIN, i);
+        }
+        let used = history.bytes();
+
+        // Plenty of room: nothing happens
+        let roomy = MemoryBudget::new(Some(used * 2));
+        assert_eq!(roomy.enforce(&mut history, &mut mailbox, 0), 0);
+        assert!(!roomy.under_pressure());
+
+        // Queued broadcasts count too, and push usage over the cap
+        let tight = MemoryBudget::new(Some(used));
+        tight.note_broadcast(used / 10);
+        let evicted = tight.enforce(&mut history, &mut mailbox, 1);
+        assert!(evicted > 0);
+        assert!(tight.under_pressure());
+        assert_eq!(tight.evicted(), evicted as u64);
+        assert!(tight.usage(&history, &mailbox, 1).total() <= used * TARGET_PERCENT / 100);
+        // The newest messages are the ones kept
+        assert_eq!(history.recent("ops", 1)[0].message, "message 99");
+
+        // Once the queue drains, the pressure is over
+        assert_eq!(tight.enforce(&mut history, &mut mailbox, 0), 0);
+        assert!(!tight.under_pressure());
+
+        let uncapped = MemoryBudget::new(None);
+        assert_eq!(uncapped.enforce(&mut history, &mut mailbox, 1000), 0);
+    }
+
+    #[test]
+    fn test_held_messages_count_and_are_evicted() {
+        let mut history = RoomHistory::new();
+        let mut mailbox = Mailbox::default();
+        for i in 0..100 {
+            history.record("ops", "alice", &format!("message {}", i), SERVER_ORIGIN, i);
+            mailbox.hold("carol", "bob", &format!("held {}", i));
+        }
+        let budget = MemoryBudget::new(None);
+        let usage = budget.usage(&history, &mailbox, 0);
+        assert_eq!(usage.mailbox, mailbox.bytes());
+        assert_eq!(usage.total(), history.bytes() + mailbox.bytes());
+
+        // Both give up their oldest messages, in proportion to what they hold
+        let used = usage.total();
+        let tight = MemoryBudget::new(Some(used));
+        assert!(tight.enforce(&mut history, &mut mailbox, 0) > 0);
+        assert!(tight.usage(&history, &mailbox, 0).total() <= used * TARGET_PERCENT / 100);
+        assert!(history.message_count() < 100);
+        let held = mailbox.take("carol");
+        assert!(held.len() < MAX_HELD_PER_USER);
+        assert_eq!(held.last().unwrap().text, "held 99");
+    }
+}
@@ -0,0 +1,116 @@
+//! Per-user session details for the console's /whois: where and when each user
+//! connected, when they last did something, how often they hit the rate limit and
+//! which client version they run.
+
+use crate::state::ConnectionId;
+use chrono::{DateTime, Local};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone)]
+pub struct Session {
+    /// The connection the user joined on
+    pub id: ConnectionId,
+    pub addr: SocketAddr,
+    pub connected_at: DateTime<Local>,
+    last_active: Instant,
+    pub rate_limit_hits: u32,
+    /// Version from the client's version check (None = not sent)
+    pub client_version: Option<String>,
+}
+
+impl Session {
+    pub fn idle(&self) -> Duration {
+        self.last_active.elapsed()
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct PresenceTracker {
+    /// Connected users by name
+    sessions: HashMap<String, Session>,
+    /// Versions of connections that haven't joined yet
+    versions: HashMap<SocketAddr, String>,
+}
+
+impl PresenceTracker {
+    /// A connection passed the version check (it has no name until it joins)
+    pub fn version_checked(&mut self, addr: SocketAddr, version: &str) {
+        self.versions.insert(addr, version.to_string());
+    }
+
+    pub fn joined(&mut self, user: &str, addr: SocketAddr, id: ConnectionId) {
+        self.sessions.insert(
+            user.to_string(),
+            Session {
+                id,
+                addr,
+                connected_at: Local::now(),
+                last_active: Instant::now(),
+                rate_limit_hits: 0,
+                client_version: self.versions.remove(&addr),
+            },
+        );
+    }
+
+    /// The user sent something other than a keepalive
+    pub fn active(&mut self, user: &str) {
+        if let Some(session) = self.sessions.get_mut(user) {
+            session.last_active = Instant::now();
+        }
+    }
+
+    pub fn rate_limited(&mut self, user: &str) {
+        if let Some(session) = self.sessions.get_mut(user) {
+            session.rate_limit_hits += 1;
+        }
+    }
+
+    pub fn rename(&mut self, old_name: &str, new_name: &str) {
+        if let Some(session) = self.sessions.remove(old_name) {
+            self.sessions.insert(new_name.to_string(), session);
+        }
+    }
+
+    pub fn left(&mut self, user: &str) {
+        self.sessions.remove(user);
+    }
+
+    /// Forget a closed connection that never joined
+    pub fn disconnected(&mut self, addr: SocketAddr) {
+        self.versions.remove(&addr);
+    }
+
+    pub fn get(&self, user: &str) -> Option<&Session> {
+        self.sessions.get(user)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_session_follows_user() {
+        let addr = SocketAddr::from(([127, 0, 0, 1], 4000));
+        let mut presence = PresenceTracker::default();
+        presence.version_checked(addr, "0.1.12");
+        let id = ConnectionId::next();
+        presence.joined("alice", addr, id);
+        presence.rate_limited("alice");
+        presence.rename("alice", "alicia");
+
+        assert!(presence.get("alice").is_none());
+        let session = presence.get("alicia").unwrap();
+        assert_eq!(session.addr, addr);
+        assert_eq!(session.id, id);
+        assert_eq!(session.rate_limit_hits, 1);
+        assert_eq!(session.client_version.as_deref(), Some("0.1.12"));
+
+        presence.left("alicia");
+        assert!(presence.get("alicia").is_none());
+        // The version was claimed by the join, so nothing is left behind
+        assert!(presence.versions.is_empty());
+    }
+}
@@ -0,0 +1,70 @@
+//! Whether the console shows what people say (CHAT_SERVER_LOG_CONTENT, /privacy).
+//! By default only who sent a message, where and how long it was are logged. Turning
+//! content logging on or off is recorded in the audit log, so anyone reading it can
+//! tell which stretches of the console log include message text.
+
+use crate::audit::AuditLog;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Content logging switch shared by every connection (cheap to clone)
+#[derive(Clone, Default)]
+pub struct ContentLogging {
+    enabled: Arc<AtomicBool>,
+}
+
+impl ContentLogging {
+    pub fn enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    /// Turn content logging on or off, noting it in the audit log along with who
+    /// did it. Returns false if it already was.
+    pub fn set(&self, enabled: bool, audit: &AuditLog, by: &str) -> bool {
+        if self.enabled.swap(enabled, Ordering::Relaxed) == enabled {
+            return false;
+        }
+        audit.record(
+            "CONTENT_LOGGING",
+            &format!("{} ({})", if enabled { "on" } else { "off" }, by),
+        );
+        true
+    }
+
+    /// How a message is logged: `sender: text`, or `sender: [12 bytes]` with
+    /// content logging off
+    pub fn line(&self, sender: &str, text: &str) -> String {
+        if self.enabled() {
+            format!("{}: {}", sender, text)
+        } else {
+            format!("{}: [{} bytes]", sender, text.len())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_content_logging_marked_in_audit_log() {
+        let path =
+            std::env::temp_dir().join(format!("rust_chat_privacy_{}.log", std::process::id()));
+        let audit = AuditLog::open(&path).unwrap();
+        let logging = ContentLogging::default();
+        assert_eq!(logging.line("alice", "hello"), "alice: [5 bytes]");
+
+        assert!(logging.clone().set(true, &audit, "console"));
+        assert!(!logging.set(true, &audit, "console"));
+        assert_eq!(logging.line("alice", "hello"), "alice: hello");
+        assert!(logging.set(false, &audit, "console"));
+
+        let contents = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].ends_with(" CONTENT_LOGGING on (console)"));
+        assert!(lines[1].ends_with(" CONTENT_LOGGING off (console)"));
+    }
+}
@@ -0,0 +1,104 @@
+//! Reserved nicknames, so announcements and integrations can't be spoofed. `srv:`
+//! names speak for the server and nobody may join as one. `bot:` names belong to
+//! bots: the console makes a token for one (`/token create deploy`), and only a Join
+//! carrying that token gets it (see auth::bot_token). The server's own identity
+//! (CHAT_SERVER_IDENTITY) is kept from users too.
+//! Prefixes match in any case, so `SRV:` is as reserved as `srv:`.
+
+use shared::sender_class::SenderClass;
+
+pub const SERVER_PREFIX: &str = "srv:";
+pub const BOT_PREFIX: &str = "bot:";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Namespace {
+    /// An ordinary nickname
+    User,
+    /// Only the server speaks as these
+    Server,
+    /// Only bots with a registered token join as these
+    Bot,
+}
+
+/// The namespace `name` is in, and what follows its prefix
+pub fn split(name: &str) -> (Namespace, &str) {
+    for (prefix, namespace) in [
+        (SERVER_PREFIX, Namespace::Server),
+        (BOT_PREFIX, Namespace::Bot),
+    ] {
+        if let Some(head) = name.get(..prefix.len())
+            && head.eq_ignore_ascii_case(prefix)
+        {
+            return (namespace, &name[prefix.len()..]);
+        }
+    }
+    (Namespace::User, name)
+}
+
+/// Letters, digits, '_' and '-', after a reserved prefix if there is one
+pub fn is_well_formed(name: &str) -> bool {
+    let (_, rest) = split(name);
+    !rest.is_empty()
+        && rest
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '_' || c == '-')
+}
+
+/// Why someone can't take `name`, None if they can. `bot_token` is whether they
+/// presented the bot's token.
+pub fn refusal(name: &str, server_identity: &str, bot_token: bool) -> Option<String> {
+    match split(name).0 {
+        Namespace::Server => Some(format!(
+            "'{}' is reserved for the server - '{}' names can't be used",
+            name, SERVER_PREFIX
+        )),
+        Namespace::Bot if !bot_token => Some(format!(
+            "'{}' is reserved for a bot - join with its token as the password",
+            name
+        )),
+        _ if name.eq_ignore_ascii_case(server_identity) => {
+            Some(format!("'{}' is the server's name", name))
+        }
+        _ => None,
+    }
+}
+
+/// The class clients are told messages from `name` are in
+pub fn class(name: &str, server_identity: &str) -> SenderClass {
+    match split(name).0 {
+        Namespace::Server => SenderClass::Server,
+        Namespace::Bot => SenderClass::Bot,
+        _ if name.eq_ignore_ascii_case(server_identity) => SenderClass::Server,
+        Namespace::User => SenderClass::User,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reserved_names() {
+        assert_eq!(split("srv:motd"), (Namespace::Server, "motd"));
+        assert_eq!(split("BOT:deploy"), (Namespace::Bot, "deploy"));
+        assert_eq!(split("robot"), (Namespace::User, "robot"));
+        assert_eq!(split("é"), (Namespace::User, "é"));
+
+        assert!(is_well_formed("bot:deploy-2"));
+        assert!(is_well_formed("alice"));
+        assert!(!is_well_formed("bot:"));
+        assert!(!is_well_formed("alice:bob"));
+        assert!(!is_well_formed("bot:srv:motd"));
+
+        assert!(refusal("srv:motd", "Server", true).is_some());
+        assert!(refusal("bot:deploy", "Server", false).is_some());
+        assert_eq!(refusal("bot:deploy", "Server", true), None);
+        assert!(refusal("server", "Server", false).is_some());
+        assert_eq!(refusal("alice", "Server", false), None);
+
+        assert_eq!(class("srv:shell", "Server"), SenderClass::Server);
+        assert_eq!(class("SERVER", "Server"), SenderClass::Server);
+        assert_eq!(class("bot:deploy", "Server"), SenderClass::Bot);
+        assert_eq!(class("robot", "Server"), SenderClass::User);
+    }
+}
@@ -0,0 +1,642 @@
+//! Chat rooms: named groups of users that receive room-scoped messages
+//! Rooms are created when the first user joins and removed when the last one leaves.
+//! The user who creates a room becomes its moderator.
+//! A moderator can make a room private: invite-only, with its messages end-to-end
+//! encrypted by the members (see shared::room_keys). The server only keeps each
+//! member's public key and asks one of them, the keeper, for a new room key whenever
+//! someone joins or leaves.
+//! A moderator can also archive a room: it turns read-only, its history stays there
+//! to read and export, and it outlives its last member, keeping its moderators so
+//! one of them can unarchive it later. Joining an archived room makes nobody a
+//! moderator.
+//! A room can hold its messages to a shorter length than the server does; messages
+//! to it must then fit whole, without being split into parts.
+//! With a translation hook configured (see translate), a room can also have its
+//! messages machine translated into a language.
+
+use chrono::{DateTime, Local};
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+pub const MAX_ROOM_NAME_LENGTH: usize = 32;
+pub const MAX_TOPIC_LENGTH: usize = 200;
+
+/// Normalize a room name: strips an optional leading '#', lowercases it and
+/// validates its length and characters (alphanumeric, underscore, hyphen only)
+pub fn normalize_room_name(name: &str) -> Option<String> {
+    let name = name.strip_prefix('#').unwrap_or(name);
+    if name.is_empty() || name.len() > MAX_ROOM_NAME_LENGTH {
+        return None;
+    }
+    if !name
+        .chars()
+        .all(|c| c.is_alphanumeric() || c == '_' || c == '-')
+    {
+        return None;
+    }
+    Some(name.to_lowercase())
+}
+
+#[derive(Debug, Default)]
+pub struct Room {
+    members: HashSet<String>,
+    moderators: HashSet<String>,
+    /// Minimum interval between messages from each non-moderator
+    slowmode: Option<Duration>,
+    /// When each member last spoke (only tracked while slow mode is on)
+    last_message: HashMap<String, Instant>,
+    /// Nobody may speak until then (set from the server console with /muteall)
+    muted_until: Option<Instant>,
+    topic: Option<String>,
+    created_at: DateTime<Local>,
+    private: bool,
+    /// Users a moderator invited who haven't joined yet (private rooms only)
+    invited: HashSet<String>,
+    /// Members' hex ed25519 public keys, sent with their JoinRoom
+    public_keys: HashMap<String, String>,
+    /// Read-only, and kept when empty
+    archived: bool,
+    /// Longest message text the room takes, in bytes (None = the server's limit)
+    max_length: Option<usize>,
+    /// Language its messages are translated into
+    translate_to: Option<String>,
+}
+
+/// Snapshot of a room's settings for /room info and /rooms
+#[derive(Debug, PartialEq)]
+pub struct RoomInfo {
+    pub members: usize,
+    /// Sorted by name
+    pub moderators: Vec<String>,
+    pub slowmode: Option<Duration>,
+    pub topic: Option<String>,
+    pub created_at: DateTime<Local>,
+    pub private: bool,
+    pub archived: bool,
+    pub max_length: Option<usize>,
+    pub translate_to: Option<String>,
+}
+
+#[derive(Debug, Default)]
+pub struct RoomRegistry {
+    rooms: HashMap<String, Room>,
+}
+
+impl RoomRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a user to a room, creating the room (with them as moderator) if needed.
+    /// Returns false if the user was already a member.
+    pub fn join(&mut self, room: &str, user: &str) -> bool {
+        let entry = self.rooms.entry(room.to_string()).or_insert_with(|| Room {
+            created_at: Local::now(),
+            ..Default::default()
+        });
+        if entry.members.is_empty() && !entry.archived {
+            entry.moderators.insert(user.to_string());
+        }
+        entry.members.insert(user.to_string())
+    }
+
+    /// Remove a user from a room, dropping the room once it is empty (unless it's
+    /// archived). Returns false if the user was not a member.
+    pub fn leave(&mut self, room: &str, user: &str) -> bool {
+        let Some(entry) = self.rooms.get_mut(room) else {
+            return false;
+        };
+        let removed = entry.members.remove(user);
+        if !entry.archived {
+            entry.moderators.remove(user);
+        }
+        entry.last_message.remove(user);
+        entry.public_keys.remove(user);
+        if entry.members.is_empty() && !entry.archived {
+            self.rooms.remove(room);
+        }
+        removed
+    }
+
+    /// Remove a user from every room, returning the rooms they were in
+    pub fn leave_all(&mut self, user: &str) -> Vec<String> {
+        let rooms = self.rooms_for(user);
+        for room in &rooms {
+            self.leave(room, user);
+        }
+        rooms
+    }
+
+    /// Carry a user's memberships over to their new name
+    pub fn rename_member(&mut self, old_name: &str, new_name: &str) {
+        for room in self.rooms.values_mut() {
+            if room.members.remove(old_name) {
+                room.members.insert(new_name.to_string());
+            }
+            if room.moderators.remove(old_name) {
+                room.moderators.insert(new_name.to_string());
+            }
+            if let Some(last) = room.last_message.remove(old_name) {
+                room.last_message.insert(new_name.to_string(), last);
+            }
+            if let Some(key) = room.public_keys.remove(old_name) {
+                room.public_keys.insert(new_name.to_string(), key);
+            }
+            if room.invited.remove(old_name) {
+                room.invited.insert(new_name.to_string());
+            }
+        }
+    }
+
+    pub fn is_moderator(&self, room: &str, user: &str) -> bool {
+        self.rooms
+            .get(room)
+            .is_some_and(|r| r.moderators.contains(user))
+    }
+
+    /// Set or clear (None) slow mode. Returns false if the room doesn't exist.
+    pub fn set_slowmode(&mut self, room: &str, interval: Option<Duration>) -> bool {
+        let Some(entry) = self.rooms.get_mut(room) else {
+            return false;
+        };
+        entry.slowmode = interval;
+        entry.last_message.clear();
+        true
+    }
+
+    /// Set or clear (None) the room's own message length limit. Returns false if the
+    /// room doesn't exist.
+    pub fn set_max_length(&mut self, room: &str, max_length: Option<usize>) -> bool {
+        let Some(entry) = self.rooms.get_mut(room) else {
+            return false;
+        };
+        entry.max_length = max_length;
+        true
+    }
+
+    pub fn max_length(&self, room: &str) -> Option<usize> {
+        self.rooms.get(room).and_then(|r| r.max_length)
+    }
+
+    /// Set or clear (None) the language the room's messages are translated into.
+    /// Returns false if the room doesn't exist.
+    pub fn set_translation(&mut self, room: &str, lang: Option<String>) -> bool {
+        let Some(entry) = self.rooms.get_mut(room) else {
+            return false;
+        };
+        entry.translate_to = lang;
+        true
+    }
+
+    pub fn translation(&self, room: &str) -> Option<&str> {
+        self.rooms.get(room).and_then(|r| r.translate_to.as_deref())
+    }
+
+    /// Silence every member, moderators included, until `until` (None = lift the mute).
+    /// Returns false if the room doesn't exist.
+    pub fn set_mute(&mut self, room: &str, until: Option<Instant>) -> bool {
+        let Some(entry) = self.rooms.get_mut(room) else {
+            return false;
+        };
+        entry.muted_until = until;
+        true
+    }
+
+    /// Time left on a room-wide mute (None = members may talk)
+    pub fn muted_for(&self, room: &str) -> Option<Duration> {
+        self.muted_for_at(room, Instant::now())
+    }
+
+    fn muted_for_at(&self, room: &str, now: Instant) -> Option<Duration> {
+        self.rooms
+            .get(room)?
+            .muted_until
+            .filter(|until| *until > now)
+            .map(|until| until - now)
+    }
+
+    /// Set or clear (None) the topic. Returns false if the room doesn't exist.
+    pub fn set_topic(&mut self, room: &str, topic: Option<String>) -> bool {
+        let Some(entry) = self.rooms.get_mut(room) else {
+            return false;
+        };
+        entry.topic = topic;
+        true
+    }
+
+    pub fn is_archived(&self, room: &str) -> bool {
+        self.rooms.get(room).is_some_and(|r| r.archived)
+    }
+
+    /// Archive a room or bring it back. Unarchiving hands the room back to the
+    /// members still in it: moderators who left stop being moderators, and a room
+    /// nobody is in is dropped. Returns false if the room doesn't exist.
+    pub fn set_archived(&mut self, room: &str, archived: bool) -> bool {
+        let Some(entry) = self.rooms.get_mut(room) else {
+            return false;
+        };
+        entry.archived = archived;
+        if !archived {
+            let members = &entry.members;
+            entry
+                .moderators
+                .retain(|moderator| members.contains(moderator));
+            if entry.members.is_empty() {
+                self.rooms.remove(room);
+            }
+        }
+        true
+    }
+
+    pub fn is_private(&self, room: &str) -> bool {
+        self.rooms.get(room).is_some_and(|r| r.private)
+    }
+
+    /// Make a room private or public again. A room only becomes private once every
+    /// member has a public key: otherwise returns the members without one.
+    pub fn set_private(&mut self, room: &str, private: bool) -> Result<(), Vec<String>> {
+        let Some(entry) = self.rooms.get_mut(room) else {
+            return Ok(());
+        };
+        if private {
+            let mut keyless: Vec<String> = entry
+                .members
+                .iter()
+                .filter(|member| !entry.public_keys.contains_key(*member))
+                .cloned()
+                .collect();
+            if !keyless.is_empty() {
+                keyless.sort();
+                return Err(keyless);
+            }
+        } else {
+            entry.invited.clear();
+        }
+        entry.private = private;
+        Ok(())
+    }
+
+    /// Let `user` join a private room once. Returns false if the room doesn't exist.
+    pub fn invite(&mut self, room: &str, user: &str) -> bool {
+        let Some(entry) = self.rooms.get_mut(room) else {
+            return false;
+        };
+        entry.invited.insert(user.to_string());
+        true
+    }
+
+    /// Use up `user`'s invitation to a private room. Anyone may join other rooms.
+    pub fn admit(&mut self, room: &str, user: &str) -> bool {
+        match self.rooms.get_mut(room) {
+            Some(entry) if entry.private && !entry.members.contains(user) => {
+                entry.invited.remove(user)
+            }
+            _ => true,
+        }
+    }
+
+    /// Record the public key a member joined with
+    pub fn set_public_key(&mut self, room: &str, user: &str, key: String) {
+        if let Some(entry) = self.rooms.get_mut(room)
+            && entry.members.contains(user)
+        {
+            entry.public_keys.insert(user.to_string(), key);
+        }
+    }
+
+    /// Who should make a private room's next key, and the members (with their public
+    /// keys) to seal it to. The keeper is a moderator if one is still there, otherwise
+    /// the first member by name. None for public rooms.
+    pub fn key_rotation(&self, room: &str) -> Option<(String, Vec<(String, String)>)> {
+        let entry = self.rooms.get(room).filter(|entry| entry.private)?;
+        let mut members: Vec<(String, String)> = entry
+            .public_keys
+            .iter()
+            .map(|(user, key)| (user.clone(), key.clone()))
+            .collect();
+        members.sort();
+        let keeper = members
+            .iter()
+            .find(|(user, _)| entry.moderators.contains(user))
+            .or_else(|| members.first())?
+            .0
+            .clone();
+        Some((keeper, members))
+    }
+
+    /// Check slow mode for a message from `user` and record it if allowed.
+    /// Returns the remaining cooldown if the user has to wait.
+    pub fn check_slowmode(&mut self, room: &str, user: &str) -> Result<(), Duration> {
+        self.check_slowmode_at(room, user, Instant::now())
+    }
+
+    fn check_slowmode_at(&mut self, room: &str, user: &str, now: Instant) -> Result<(), Duration> {
+        let Some(entry) = self.rooms.get_mut(room) else {
+            return Ok(());
+        };
+        let Some(interval) = entry.slowmode else {
+            return Ok(());
+        };
+        if entry.moderators.contains(user) {
+            return Ok(());
+        }
+        if let Some(last) = entry.last_message.get(user) {
+            let elapsed = now.duration_since(*last);
+            if elapsed < interval {
+                return Err(interval - elapsed);
+            }
+        }
+        entry.last_message.insert(user.to_string(), now);
+        Ok(())
+    }
+
+    pub fn info(&self, room: &str) -> Option<RoomInfo> {
+        let entry = self.rooms.get(room)?;
+        let mut moderators: Vec<String> = entry.moderators.iter().cloned().collect();
+        moderators.sort();
+        Some(RoomInfo {
+            members: entry.members.len(),
+            moderators,
+            slowmode: entry.slowmode,
+            topic: entry.topic.clone(),
+            created_at: entry.created_at,
+            private: entry.private,
+            archived: entry.archived,
+            max_length: entry.max_length,
+            translate_to: entry.translate_to.clone(),
+        })
+    }
+
+    /// Every room with its info, sorted by name
+    pub fn list(&self) -> Vec<(String, RoomInfo)> {
+        let mut names: Vec<&String> = self.rooms.keys().collect();
+        names.sort();
+        names
+            .into_iter()
+            .filter_map(|name| Some((name.clone(), self.info(name)?)))
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.rooms.len()
+    }
+
+    pub fn exists(&self, room: &str) -> bool {
+        self.rooms.contains_key(room)
+    }
+
+    pub fn is_member(&self, room: &str, user: &str) -> bool {
+        self.rooms
+            .get(room)
+            .is_some_and(|r| r.members.contains(user))
+    }
+
+    /// Rooms the user is currently a member of, sorted by name
+    /// Members of a room, sorted by name
+    pub fn members(&self, room: &str) -> Vec<String> {
+        let mut members: Vec<String> = self
+            .rooms
+            .get(room)
+            .map(|entry| entry.members.iter().cloned().collect())
+            .unwrap_or_default();
+        members.sort();
+        members
+    }
+
+    pub fn rooms_for(&self, user: &str) -> Vec<String> {
+        let mut rooms: Vec<String> = self
+            .rooms
+            .iter()
+            .filter(|(_, room)| room.members.contains(user))
+            .map(|(name, _)| name.clone())
+            .collect();
+        rooms.sort();
+        rooms
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_room_name() {
+        assert_eq!(normalize_room_name("#General"), Some("general".to_string()));
+        assert_eq!(
+            normalize_room_name("ops-team"),
+            Some("ops-team".to_string())
+        );
+        assert_eq!(normalize_room_name("#"), None);
+        assert_eq!(normalize_room_name(""), None);
+        assert_eq!(normalize_room_name("bad room"), None);
+        assert_eq!(
+            normalize_room_name(&"a".repeat(MAX_ROOM_NAME_LENGTH + 1)),
+            None
+        );
+    }
+
+    #[test]
+    fn test_join_creates_room() {
+        let mut rooms = RoomRegistry::new();
+        assert!(!rooms.exists("ops"));
+        assert!(rooms.join("ops", "alice"));
+        assert!(rooms.exists("ops"));
+        assert!(rooms.is_member("ops", "alice"));
+        // Joining twice is a no-op
+        assert!(!rooms.join("ops", "alice"));
+    }
+
+    #[test]
+    fn test_leave_removes_empty_room() {
+        let mut rooms = RoomRegistry::new();
+        rooms.join("ops", "alice");
+        rooms.join("ops", "bob");
+        assert!(rooms.leave("ops", "alice"));
+        assert!(rooms.exists("ops"));
+        assert!(rooms.leave("ops", "bob"));
+        assert!(!rooms.exists("ops"));
+        assert!(!rooms.leave("ops", "bob"));
+    }
+
+    #[test]
+    fn test_leave_all() {
+        let mut rooms = RoomRegistry::new();
+        rooms.join("ops", "alice");
+        rooms.join("dev", "alice");
+        rooms.join("dev", "bob");
+        assert_eq!(rooms.leave_all("alice"), vec!["dev", "ops"]);
+        assert!(!rooms.exists("ops"));
+        assert!(rooms.is_member("dev", "bob"));
+    }
+
+    #[test]
+    fn test_creator_is_moderator() {
+        let mut rooms = RoomRegistry::new();
+        rooms.join("ops", "alice");
+        rooms.join("ops", "bob");
+        assert!(rooms.is_moderator("ops", "alice"));
+        assert!(!rooms.is_moderator("ops", "bob"));
+        rooms.rename_member("alice", "alicia");
+        assert!(rooms.is_moderator("ops", "alicia"));
+    }
+
+    #[test]
+    fn test_slowmode() {
+        let mut rooms = RoomRegistry::new();
+        rooms.join("ops", "alice");
+        rooms.join("ops", "bob");
+        assert!(rooms.set_slowmode("ops", Some(Duration::from_secs(10))));
+        assert!(!rooms.set_slowmode("missing", Some(Duration::from_secs(10))));
+
+        let start = Instant::now();
+        assert_eq!(rooms.check_slowmode_at("ops", "bob", start), Ok(()));
+        assert_eq!(
+            rooms.check_slowmode_at("ops", "bob", start + Duration::from_secs(4)),
+            Err(Duration::from_secs(6))
+        );
+        assert_eq!(
+            rooms.check_slowmode_at("ops", "bob", start + Duration::from_secs(10)),
+            Ok(())
+        );
+
+        // Moderators are exempt
+        assert_eq!(rooms.check_slowmode_at("ops", "alice", start), Ok(()));
+        assert_eq!(rooms.check_slowmode_at("ops", "alice", start), Ok(()));
+
+        rooms.set_slowmode("ops", None);
+        assert_eq!(rooms.check_slowmode_at("ops", "bob", start), Ok(()));
+    }
+
+    #[test]
+    fn test_mute() {
+        let mut rooms = RoomRegistry::new();
+        rooms.join("ops", "alice");
+        rooms.join("ops", "bob");
+        assert_eq!(rooms.members("ops"), vec!["alice", "bob"]);
+        assert!(!rooms.set_mute("nowhere", Some(Instant::now())));
+
+        let now = Instant::now();
+        assert!(rooms.set_mute("ops", Some(now + Duration::from_secs(60))));
+        assert_eq!(
+            rooms.muted_for_at("ops", now + Duration::from_secs(20)),
+            Some(Duration::from_secs(40))
+        );
+        assert_eq!(
+            rooms.muted_for_at("ops", now + Duration::from_secs(60)),
+            None
+        );
+        rooms.set_mute("ops", None);
+        assert_eq!(rooms.muted_for_at("ops", now), None);
+    }
+
+    #[test]
+    fn test_info() {
+        let mut rooms = RoomRegistry::new();
+        rooms.join("ops", "bob");
+        rooms.join("ops", "alice");
+        rooms.join("dev", "alice");
+        rooms.set_slowmode("ops", Some(Duration::from_secs(5)));
+        assert!(rooms.set_topic("ops", Some("Deploys".to_string())));
+        assert!(!rooms.set_topic("missing", None));
+        assert!(rooms.set_max_length("ops", Some(200)));
+        assert!(!rooms.set_max_length("missing", Some(200)));
+        assert!(rooms.set_translation("ops", Some("fr".to_string())));
+
+        let info = rooms.info("ops").unwrap();
+        assert_eq!(info.members, 2);
+        assert_eq!(info.moderators, vec!["bob".to_string()]);
+        assert_eq!(info.slowmode, Some(Duration::from_secs(5)));
+        assert_eq!(info.topic.as_deref(), Some("Deploys"));
+        assert_eq!(info.max_length, Some(200));
+        assert_eq!(rooms.max_length("dev"), None);
+        assert_eq!(info.translate_to.as_deref(), Some("fr"));
+        assert_eq!(rooms.translation("dev"), None);
+        assert!(info.created_at <= Local::now());
+        assert_eq!(rooms.info("missing"), None);
+
+        let names: Vec<String> = rooms.list().into_iter().map(|(name, _)| name).collect();
+        assert_eq!(names, vec!["dev", "ops"]);
+    }
+
+    #[test]
+    fn test_private_room() {
+        let mut rooms = RoomRegistry::new();
+        rooms.join("ops", "alice");
+        rooms.join("ops", "bob");
+        rooms.set_public_key("ops", "alice", "aa".to_string());
+        assert_eq!(rooms.key_rotation("ops"), None);
+        assert_eq!(rooms.set_private("ops", true), Err(vec!["bob".to_string()]));
+        assert!(!rooms.is_private("ops"));
+
+        rooms.set_public_key("ops", "bob", "bb".to_string());
+        assert_eq!(rooms.set_private("ops", true), Ok(()));
+        assert!(rooms.info("ops").unwrap().private);
+
+        // Invite-only, and each invitation is good for one join
+        assert!(!rooms.admit("ops", "carol"));
+        assert!(rooms.invite("ops", "carol"));
+        assert!(rooms.admit("ops", "carol"));
+        assert!(!rooms.admit("ops", "carol"));
+        assert!(rooms.admit("dev", "carol"));
+
+        rooms.join("ops", "carol");
+        rooms.set_public_key("ops", "carol", "cc".to_string());
+        let (keeper, members) = rooms.key_rotation("ops").unwrap();
+        assert_eq!(keeper, "alice");
+        assert_eq!(members.len(), 3);
+
+        // The moderator left: the first member by name keeps the key
+        rooms.leave("ops", "alice");
+        rooms.rename_member("carol", "abby");
+        let (keeper, members) = rooms.key_rotation("ops").unwrap();
+        assert_eq!(keeper, "abby");
+        assert_eq!(
+            members,
+            vec![
+                ("abby".to_string(), "cc".to_string()),
+                ("bob".to_string(), "bb".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn test_archive() {
+        let mut rooms = RoomRegistry::new();
+        rooms.join("ops", "alice");
+        rooms.join("ops", "bob");
+        assert!(rooms.set_archived("ops", true));
+        assert!(!rooms.set_archived("missing", true));
+        assert!(rooms.info("ops").unwrap().archived);
+
+        // Kept with its moderator once everyone has left
+        rooms.leave("ops", "alice");
+        rooms.leave_all("bob");
+        assert!(rooms.is_archived("ops"));
+        assert!(rooms.is_moderator("ops", "alice"));
+
+        // Joining it doesn't make anyone a moderator
+        rooms.join("ops", "carol");
+        assert!(!rooms.is_moderator("ops", "carol"));
+
+        assert!(rooms.set_archived("ops", false));
+        assert!(!rooms.is_moderator("ops", "alice"));
+        rooms.leave("ops", "carol");
+        assert!(!rooms.exists("ops"));
+
+        // Unarchiving an empty room drops it
+        rooms.join("dev", "alice");
+        rooms.set_archived("dev", true);
+        rooms.leave("dev", "alice");
+        assert!(rooms.set_archived("dev", false));
+        assert!(!rooms.exists("dev"));
+    }
+
+    #[test]
+    fn test_rename_member() {
+        let mut rooms = RoomRegistry::new();
+        rooms.join("ops", "alice");
+        rooms.rename_member("alice", "alicia");
+        assert!(!rooms.is_member("ops", "alice"));
+        assert!(rooms.is_member("ops", "alicia"));
+    }
+}
@@ -0,0 +1,250 @@
+//! Scheduled announcements: one-off (`--at 02:00`) or recurring (`--every 6h`)
+//! messages broadcast by the server, e.g. maintenance reminders or the rules.
+
+use chrono::{DateTime, Local, NaiveTime, TimeDelta};
+use std::time::Duration;
+
+/// File in the server data directory with announcements scheduled at startup.
+/// Each line uses the /announce syntax, e.g. `--every 6h Please read the rules`.
+pub const ANNOUNCEMENTS_FILE: &str = "announcements.txt";
+
+/// Shortest allowed repeat interval, so a typo can't flood the chat
+pub const MIN_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone)]
+pub struct ScheduledAnnouncement {
+    pub id: u32,
+    pub room: Option<String>,
+    pub message: String,
+    /// Repeat interval (None = announce once)
+    pub every: Option<Duration>,
+    pub next: DateTime<Local>,
+}
+
+#[derive(Debug, Default)]
+pub struct Schedule {
+    next_id: u32,
+    entries: Vec<ScheduledAnnouncement>,
+}
+
+impl Schedule {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Schedule an announcement. `at` is the first time of day to send it (the next
+    /// occurrence, so it may be tomorrow); without it the first one goes out after `every`.
+    /// Returns None if neither is given.
+    pub fn add(
+        &mut self,
+        room: Option<String>,
+        message: String,
+        at: Option<NaiveTime>,
+        every: Option<Duration>,
+    ) -> Option<&ScheduledAnnouncement> {
+        self.add_at(room, message, at, every, Local::now())
+    }
+
+    fn add_at(
+        &mut self,
+        room: Option<String>,
+        message: String,
+        at: Option<NaiveTime>,
+        every: Option<Duration>,
+        now: DateTime<Local>,
+    ) -> Option<&ScheduledAnnouncement> {
+        let next = match (at, every) {
+            (Some(at), _) => next_time_of_day(at, now),
+            (None, Some(every)) => now + TimeDelta::from_std(every).ok()?,
+            (None, None) => return None,
+        };
+        self.next_id += 1;
+        self.entries.push(ScheduledAnnouncement {
+            id: self.next_id,
+            room,
+            message,
+            every,
+            next,
+        });
+        self.entries.last()
+    }
+
+    /// Returns false if there is no announcement with this id
+    pub fn cancel(&mut self, id: u32) -> bool {
+        let before = self.entries.len();
+        self.entries.retain(|entry| entry.id != id);
+        self.entries.len() != before
+    }
+
+    pub fn entries(&self) -> &[ScheduledAnnouncement] {
+        &self.entries
+    }
+
+    /// When the next announcement is due
+    pub fn next_due(&self) -> Option<DateTime<Local>> {
+        self.entries.iter().map(|entry| entry.next).min()
+    }
+
+    /// Remove and return everything due at `now`. Recurring announcements are
+    /// rescheduled (skipping runs missed while the server was busy).
+    pub fn take_due(&mut self, now: DateTime<Local>) -> Vec<ScheduledAnnouncement> {
+        let mut due = Vec::new();
+        self.entries.retain_mut(|entry| {
+            if entry.next > now {
+                return true;
+            }
+            due.push(entry.clone());
+            let Some(every) = entry
+                .every
+                .and_then(|every| TimeDelta::from_std(every).ok())
+            else {
+                return false;
+            };
+            while entry.next <= now {
+                entry.next += every;
+            }
+            true
+        });
+        due
+    }
+}
+
+/// Next occurrence of a local time of day (today if it's still ahead, otherwise tomorrow)
+pub fn next_time_of_day(at: NaiveTime, now: DateTime<Local>) -> DateTime<Local> {
+    let mut date = now.date_naive();
+    loop {
+        // earliest() skips times that don't exist because of a DST change
+        if let Some(candidate) = date.and_time(at).and_local_timezone(Local).earliest()
+            && candidate > now
+        {
+            return candidate;
+        }
+        date = date.succ_opt().unwrap_or(date);
+    }
+}
+
+/// Longest interval accepted: ten years. Bans, mutes and cooldowns are added to the
+/// current time, and much more than this would overflow it.
+pub const MAX_INTERVAL: Duration = Duration::from_secs(3650 * 24 * 60 * 60);
+
+/// Parse an interval like "90s", "30m", "6h" or "1d" (None if it's over MAX_INTERVAL)
+pub fn parse_interval(value: &str) -> Option<Duration> {
+    let unit = value.chars().last()?;
+    let number: u64 = value[..value.len() - unit.len_utf8()].parse().ok()?;
+    let seconds = match unit {
+        's' => number,
+        'm' => number.checked_mul(60)?,
+        'h' => number.checked_mul(60 * 60)?,
+        'd' => number.checked_mul(24 * 60 * 60)?,
+        _ => return None,
+    };
+    let interval = Duration::from_secs(seconds);
+    (seconds > 0 && interval <= MAX_INTERVAL).then_some(interval)
+}
+
+/// Compact interval for display, e.g. "6h", "1h30m", "45s"
+pub fn format_interval(interval: Duration) -> String {
+    let secs = interval.as_secs();
+    let (days, hours, minutes, seconds) = (
+        secs / 86400,
+        secs % 86400 / 3600,
+        secs % 3600 / 60,
+        secs % 60,
+    );
+    let mut out = String::new();
+    for (value, unit) in [(days, "d"), (hours, "h"), (minutes, "m"), (seconds, "s")] {
+        if value > 0 {
+            out.push_str(&format!("{}{}", value, unit));
+        }
+    }
+    if out.is_empty() {
+        out.push_str("0s");
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn local(hour: u32, minute: u32) -> DateTime<Local> {
+        Local
+            .with_ymd_and_hms(2026, 1, 15, hour, minute, 0)
+            .earliest()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_parse_and_format_interval() {
+        assert_eq!(parse_interval("90s"), Some(Duration::from_secs(90)));
+        assert_eq!(parse_interval("30m"), Some(Duration::from_secs(1800)));
+        assert_eq!(parse_interval("6h"), Some(Duration::from_secs(6 * 3600)));
+        assert_eq!(parse_interval("1d"), Some(Duration::from_secs(86400)));
+        assert_eq!(parse_interval("0h"), None);
+        assert_eq!(parse_interval("6"), None);
+        assert_eq!(parse_interval("h"), None);
+        assert_eq!(parse_interval(""), None);
+        assert_eq!(parse_interval("5é"), None);
+        assert_eq!(parse_interval("3650d"), Some(MAX_INTERVAL));
+        assert_eq!(parse_interval("3651d"), None);
+        assert_eq!(parse_interval("99999999999999d"), None);
+        assert_eq!(parse_interval("é"), None);
+        assert_eq!(format_interval(Duration::from_secs(5400)), "1h30m");
+        assert_eq!(format_interval(Duration::from_secs(86400)), "1d");
+    }
+
+    #[test]
+    fn test_at_uses_next_occurrence() {
+        let mut schedule = Schedule::new();
+        let now = local(10, 0);
+        let later = NaiveTime::from_hms_opt(12, 0, 0).unwrap();
+        let earlier = NaiveTime::from_hms_opt(2, 0, 0).unwrap();
+        let today = schedule
+            .add_at(None, "a".into(), Some(later), None, now)
+            .unwrap()
+            .next;
+        assert_eq!(today, local(12, 0));
+        let tomorrow = schedule
+            .add_at(None, "b".into(), Some(earlier), None, now)
+            .unwrap()
+            .next;
+        assert_eq!(tomorrow, local(2, 0) + TimeDelta::days(1));
+        assert!(schedule.add_at(None, "c".into(), None, None, now).is_none());
+    }
+
+    #[test]
+    fn test_take_due_reschedules_recurring() {
+        let mut schedule = Schedule::new();
+        let now = local(10, 0);
+        let hourly = Some(Duration::from_secs(3600));
+        schedule.add_at(None, "rules".into(), None, hourly, now);
+        let at = NaiveTime::from_hms_opt(10, 30, 0).unwrap();
+        schedule.add_at(Some("ops".into()), "deploy".into(), Some(at), None, now);
+
+        assert!(schedule.take_due(local(10, 29)).is_empty());
+        assert_eq!(schedule.next_due(), Some(local(10, 30)));
+
+        // Both are due; the one-off is removed, the recurring one moves on
+        let due = schedule.take_due(local(11, 0));
+        assert_eq!(due.len(), 2);
+        assert_eq!(schedule.entries().len(), 1);
+        assert_eq!(schedule.next_due(), Some(local(12, 0)));
+
+        // Missed runs are skipped rather than sent in a burst
+        assert_eq!(schedule.take_due(local(15, 30)).len(), 1);
+        assert_eq!(schedule.next_due(), Some(local(16, 0)));
+    }
+
+    #[test]
+    fn test_cancel() {
+        let mut schedule = Schedule::new();
+        let id = schedule
+            .add(None, "rules".into(), None, Some(MIN_INTERVAL))
+            .unwrap()
+            .id;
+        assert!(schedule.cancel(id));
+        assert!(!schedule.cancel(id));
+        assert_eq!(schedule.next_due(), None);
+    }
+}
@@ -0,0 +1,211 @@
+//! Scopes: what a connection may do. Every frame a client sends needs at most one
+//! scope, and every frame relayed to it likewise; `MessageHandlers` checks the first
+//! and the connection the second, so a connection without a scope can't get around
+//! it with some other frame. Bots get the scopes their token grants
+//! (auth::bot_token), guests the ones in CHAT_SERVER_GUEST_SCOPES, and users logged
+//! in to a registered nickname every scope.
+
+use shared::message::{ChatMessage, MessageTypes};
+use std::fmt;
+
+/// Something a connection may be allowed to do
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    /// Post to the chat, rooms and direct messages, and set a status
+    Send,
+    /// Receive what's said, list who's around, and fetch room history
+    Read,
+    /// Moderate rooms it moderates: announcements, slow mode, topics, invitations
+    Moderate,
+    /// Change the policy of rooms it moderates: privacy, retention, message length,
+    /// translation and archiving
+    Admin,
+    /// Send and receive files
+    FileTransfer,
+}
+
+impl Scope {
+    pub const ALL: [Scope; 5] = [
+        Scope::Send,
+        Scope::Read,
+        Scope::Moderate,
+        Scope::Admin,
+        Scope::FileTransfer,
+    ];
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.to_lowercase().as_str() {
+            "send" | "write" => Some(Scope::Send),
+            "read" => Some(Scope::Read),
+            "moderate" | "mod" => Some(Scope::Moderate),
+            "admin" => Some(Scope::Admin),
+            "file-transfer" | "files" => Some(Scope::FileTransfer),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Scope::Send => "send",
+            Scope::Read => "read",
+            Scope::Moderate => "moderate",
+            Scope::Admin => "admin",
+            Scope::FileTransfer => "file-transfer",
+        }
+    }
+
+    fn bit(&self) -> u8 {
+        1 << *self as u8
+    }
+
+    /// What a client sending `message` needs (None = anyone may send it)
+    pub fn to_send(message: &ChatMessage) -> Option<Scope> {
+        match message.msg_type {
+            MessageTypes::ChatMessage
+            | MessageTypes::RoomMessage
+            | MessageTypes::DirectMessage
+            | MessageTypes::SetStatus => Some(Scope::Send),
+            MessageTypes::ListUsers
+            | MessageTypes::RoomInfoRequest
+            | MessageTypes::HistoryRequest
+            | MessageTypes::Seen => Some(Scope::Read),
+            MessageTypes::FileTransfer
+            | MessageTypes::FileTransferRequest
+            | MessageTypes::FileTransferResponse => Some(Scope::FileTransfer),
+            MessageTypes::RoomCommand => {
+                // room|command|args
+                let content = message.content_as_string().unwrap_or_default();
+                match content.split('|').nth(1) {
+                    Some(
+                        "private" | "retention" | "maxlength" | "translate" | "archive"
+                        | "unarchive",
+                    ) => Some(Scope::Admin),
+                    _ => Some(Scope::Moderate),
+                }
+            }
+            // The handshake, renames, registering (CHAT_SERVER_OPEN_REGISTRATION),
+            // rooms (bots' rooms are limited by their grant), blocks, keys and server info
+            _ => None,
+        }
+    }
+
+    /// What a connection needs to be relayed `message` (None = everyone gets it)
+    pub fn to_receive(message: &ChatMessage) -> Option<Scope> {
+        match message.msg_type {
+            MessageTypes::ChatMessage
+            | MessageTypes::RoomMessage
+            | MessageTypes::DirectMessage
+            | MessageTypes::Announcement
+            | MessageTypes::RoomAnnouncement
+            | MessageTypes::Translation => Some(Scope::Read),
+            MessageTypes::FileTransfer
+            | MessageTypes::FileTransferRequest
+            | MessageTypes::FileTransferResponse => Some(Scope::FileTransfer),
+            _ => None,
+        }
+    }
+}
+
+/// A set of scopes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Scopes(u8);
+
+impl Scopes {
+    pub fn all() -> Self {
+        Scopes::from_iter(Scope::ALL)
+    }
+
+    /// "send,read" (None if any scope is unknown; an empty list is no scopes)
+    pub fn parse(list: &str) -> Option<Self> {
+        list.split(',')
+            .map(str::trim)
+            .filter(|scope| !scope.is_empty())
+            .map(Scope::parse)
+            .collect()
+    }
+
+    pub fn contains(&self, scope: Scope) -> bool {
+        self.0 & scope.bit() != 0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+}
+
+impl FromIterator<Scope> for Scopes {
+    fn from_iter<I: IntoIterator<Item = Scope>>(scopes: I) -> Self {
+        Scopes(scopes.into_iter().fold(0, |bits, scope| bits | scope.bit()))
+    }
+}
+
+impl fmt::Display for Scopes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names: Vec<&str> = Scope::ALL
+            .iter()
+            .filter(|scope| self.contains(**scope))
+            .map(Scope::name)
+            .collect();
+        write!(f, "{}", names.join(","))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(msg_type: MessageTypes, content: &str) -> ChatMessage {
+        ChatMessage::try_new(msg_type, Some(content.as_bytes().to_vec())).unwrap()
+    }
+
+    #[test]
+    fn test_scopes() {
+        let scopes = Scopes::parse("read, files,READ").unwrap();
+        assert!(scopes.contains(Scope::Read) && scopes.contains(Scope::FileTransfer));
+        assert!(!scopes.contains(Scope::Send));
+        assert_eq!(scopes.to_string(), "read,file-transfer");
+        assert!(Scopes::parse("").unwrap().is_empty());
+        assert_eq!(Scopes::parse("read,root"), None);
+        assert_eq!(
+            Scopes::all().to_string(),
+            "send,read,moderate,admin,file-transfer"
+        );
+    }
+
+    #[test]
+    fn test_frames_need_scopes() {
+        let read_only = Scopes::from_iter([Scope::Read]);
+        let need = |msg_type, content| Scope::to_send(&frame(msg_type, content));
+        let allowed = |scope: Option<Scope>| scope.is_none_or(|scope| read_only.contains(scope));
+
+        // A read-only bot can look but not speak, in any way
+        for (msg_type, content) in [
+            (MessageTypes::ChatMessage, "hi"),
+            (MessageTypes::RoomMessage, "ops|hi"),
+            (MessageTypes::DirectMessage, "alice|hi"),
+            (MessageTypes::SetStatus, "watching"),
+            (MessageTypes::FileTransferRequest, "alice|log.txt|10"),
+            (MessageTypes::RoomCommand, "ops|topic|hi"),
+        ] {
+            assert!(!allowed(need(msg_type, content)), "{:?}", msg_type);
+        }
+        assert!(allowed(need(MessageTypes::HistoryRequest, "ops|10")));
+        assert!(allowed(need(MessageTypes::JoinRoom, "ops")));
+
+        assert_eq!(
+            need(MessageTypes::RoomCommand, "ops|slowmode|10"),
+            Some(Scope::Moderate)
+        );
+        assert_eq!(
+            need(MessageTypes::RoomCommand, "ops|private|on"),
+            Some(Scope::Admin)
+        );
+
+        let to_receive = |msg_type, content| Scope::to_receive(&frame(msg_type, content));
+        assert_eq!(
+            to_receive(MessageTypes::RoomMessage, "ops|alice|hi"),
+            Some(Scope::Read)
+        );
+        assert_eq!(to_receive(MessageTypes::PresenceDelta, ""), None);
+    }
+}
@@ -0,0 +1,210 @@
+//! When each user was last connected and last spoke, for /seen. Kept after users
+//! leave and saved to the data directory, so offline users and restarts are covered.
+//! Stored as one `name<TAB>last_connected<TAB>last_spoke` line per user (unix
+//! seconds, an empty field for a user who never spoke).
+
+use crate::data_file::{DataFile, Save};
+use crate::schedule;
+use chrono::{DateTime, Local, TimeZone};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// File name of the last-seen log inside the server data directory
+pub const SEEN_FILE: &str = "seen.tsv";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LastSeen {
+    /// Last time the user was known to be connected (join, heartbeat or leave)
+    pub connected: DateTime<Local>,
+    pub spoke: Option<DateTime<Local>>,
+}
+
+#[derive(Debug, Default)]
+pub struct SeenLog {
+    /// None = only kept in memory
+    file: Option<DataFile>,
+    users: BTreeMap<String, LastSeen>,
+    /// Changed since it was last saved (heartbeats only update memory)
+    changed: bool,
+}
+
+fn timestamp(secs: &str) -> Option<DateTime<Local>> {
+    Local.timestamp_opt(secs.parse().ok()?, 0).single()
+}
+
+impl SeenLog {
+    /// Load the log from `path` (a missing file is an empty log)
+    pub fn load(path: Option<PathBuf>) -> io::Result<Self> {
+        let mut log = SeenLog {
+            file: path.clone().map(DataFile::new),
+            ..SeenLog::default()
+        };
+        let Some(path) = &path else {
+            return Ok(log);
+        };
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(log),
+            Err(e) => return Err(e),
+        };
+        for line in contents.lines() {
+            let mut fields = line.split('\t');
+            let (Some(name), Some(connected), spoke) =
+                (fields.next(), fields.next(), fields.next())
+            else {
+                continue;
+            };
+            if let Some(connected) = timestamp(connected) {
+                let spoke = spoke.and_then(timestamp);
+                log.users
+                    .insert(name.to_string(), LastSeen { connected, spoke });
+            }
+        }
+        Ok(log)
+    }
+
+    /// Whether `line` is a user as `save` writes it (for the startup integrity check)
+    pub fn check_line(line: &str) -> bool {
+        let fields: Vec<&str> = line.split('\t').collect();
+        match fields.as_slice() {
+            [name, connected, spoke] => {
+                !name.is_empty()
+                    && timestamp(connected).is_some()
+                    && (spoke.is_empty() || timestamp(spoke).is_some())
+            }
+            _ => false,
+        }
+    }
+
+    /// The user is connected right now (joined, answered a heartbeat or is leaving)
+    pub fn connected(&mut self, user: &str) {
+        let now = Local::now();
+        self.changed = true;
+        self.users
+            .entry(user.to_string())
+            .and_modify(|seen| seen.connected = now)
+            .or_insert(LastSeen {
+                connected: now,
+                spoke: None,
+            });
+    }
+
+    /// The user sent a message to the chat, a room or another user
+    pub fn spoke(&mut self, user: &str) {
+        self.connected(user);
+        if let Some(seen) = self.users.get_mut(user) {
+            seen.spoke = Some(seen.connected);
+        }
+    }
+
+    /// When `user` was last known to be connected
+    pub fn last_connected(&self, user: &str) -> Option<DateTime<Local>> {
+        self.users.get(user).map(|seen| seen.connected)
+    }
+
+    /// A connected user changed name: the old one was last seen now
+    pub fn renamed(&mut self, old_name: &str, new_name: &str) {
+        self.connected(old_name);
+        self.connected(new_name);
+    }
+
+    /// What /seen says about `user`; `online` is whether they're connected now
+    pub fn describe(&self, user: &str, online: bool) -> String {
+        let Some(seen) = self.users.get(user) else {
+            return if online {
+                format!("{} is online now", user)
+            } else {
+                format!("{} hasn't been seen here", user)
+            };
+        };
+        let ago = |at: DateTime<Local>| {
+            let elapsed = (Local::now() - at).to_std().unwrap_or_default();
+            format!(
+                "{} ({} ago)",
+                at.format("%Y-%m-%d %H:%M"),
+                schedule::format_interval(Duration::from_secs(elapsed.as_secs()))
+            )
+        };
+        let connected = if online {
+            format!("{} is online now", user)
+        } else {
+            format!("{} was last connected {}", user, ago(seen.connected))
+        };
+        match seen.spoke {
+            Some(spoke) => format!("{}; last spoke {}", connected, ago(spoke)),
+            None => format!("{}; hasn't spoken", connected),
+        }
+    }
+
+    /// The log to write to disk once it's unlocked, if anything changed since the
+    /// last save
+    pub fn save_if_changed(&mut self) -> Save {
+        if !self.changed {
+            return Save::default();
+        }
+        self.save()
+    }
+
+    /// The log to write to disk once it's unlocked
+    pub fn save(&mut self) -> Save {
+        self.changed = false;
+        let Some(file) = &self.file else {
+            return Save::default();
+        };
+        let mut contents = String::new();
+        for (name, seen) in &self.users {
+            contents.push_str(&format!(
+                "{}\t{}\t{}\n",
+                name,
+                seen.connected.timestamp(),
+                seen.spoke
+                    .map(|at| at.timestamp().to_string())
+                    .unwrap_or_default()
+            ));
+        }
+        file.snapshot(contents)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_seen_survives_restart() {
+        let path =
+            std::env::temp_dir().join(format!("seen_test_{}_{}", std::process::id(), SEEN_FILE));
+        let mut log = SeenLog::load(Some(path.clone())).unwrap();
+        log.connected("alice");
+        log.spoke("bob");
+        log.save().write().await.unwrap();
+
+        let loaded = SeenLog::load(Some(path.clone())).unwrap();
+        fs::remove_file(&path).unwrap();
+        let alice = loaded.users.get("alice").unwrap();
+        assert_eq!(alice.spoke, None);
+        assert_eq!(
+            loaded.users.get("bob").unwrap().spoke.unwrap().timestamp(),
+            log.users.get("bob").unwrap().spoke.unwrap().timestamp()
+        );
+
+        assert!(
+            loaded
+                .describe("alice", true)
+                .starts_with("alice is online now")
+        );
+        assert!(
+            loaded
+                .describe("alice", false)
+                .contains("was last connected")
+        );
+        assert!(loaded.describe("bob", false).contains("last spoke"));
+        assert_eq!(
+            loaded.describe("carol", false),
+            "carol hasn't been seen here"
+        );
+    }
+}
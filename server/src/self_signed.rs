@@ -0,0 +1,62 @@
+//! Self-signed TLS certificate for servers without one (e.g. on a LAN): generated
+//! on first start and kept, so its fingerprint - which clients pin with /trust -
+//! stays the same across restarts.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Default certificate location inside the data directory
+pub const CERT_FILE: &str = "tls/cert.pem";
+/// Default private key location inside the data directory
+pub const KEY_FILE: &str = "tls/key.pem";
+
+/// Generate a certificate for `names` (host names or IP addresses) and write it
+/// and its private key as PEM
+pub fn generate(cert_path: &Path, key_path: &Path, names: Vec<String>) -> io::Result<()> {
+    let certified = rcgen::generate_simple_self_signed(names)
+        .map_err(|e| io::Error::other(format!("Could not generate a certificate: {}", e)))?;
+
+    for path in [cert_path, key_path] {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+    }
+    fs::write(cert_path, certified.cert.pem())?;
+    fs::write(key_path, certified.key_pair.serialize_pem())?;
+    // The key is a secret - keep it readable by us only
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(key_path, fs::Permissions::from_mode(0o600))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generated_certificate_loads() {
+        let dir = std::env::temp_dir().join(format!("rust_chat_tls_{}", std::process::id()));
+        let cert_path = dir.join(CERT_FILE);
+        let key_path = dir.join(KEY_FILE);
+        generate(&cert_path, &key_path, vec!["localhost".to_string()]).unwrap();
+
+        let (_, fingerprint) = crate::load_tls_config(
+            cert_path.to_str().unwrap(),
+            key_path.to_str().unwrap(),
+            None,
+        )
+        .unwrap();
+        assert!(shared::tls::parse_fingerprint(&fingerprint).is_some());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&key_path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,55 @@
+//! Extra sessions of users logged in to their registered nickname from more than one
+//! device (CHAT_SERVER_MULTI_SESSION). The first session is the one in the connected
+//! clients list; only the last one to close makes the user leave. A user with other
+//! sessions open can't be renamed - the nickname is still theirs on the other devices.
+
+use std::collections::HashMap;
+
+#[derive(Debug, Default)]
+pub struct Sessions {
+    /// Sessions beyond the first, per user
+    extra: HashMap<String, usize>,
+}
+
+impl Sessions {
+    /// Another session logged in to `user`
+    pub fn opened(&mut self, user: &str) {
+        *self.extra.entry(user.to_string()).or_default() += 1;
+    }
+
+    /// How many sessions `user` has beyond the first
+    pub fn extra(&self, user: &str) -> usize {
+        self.extra.get(user).copied().unwrap_or_default()
+    }
+
+    /// One of `user`'s sessions closed. Returns true if others are still open.
+    pub fn closed(&mut self, user: &str) -> bool {
+        let Some(extra) = self.extra.get_mut(user) else {
+            return false;
+        };
+        *extra -= 1;
+        if *extra == 0 {
+            self.extra.remove(user);
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_last_session_leaves() {
+        let mut sessions = Sessions::default();
+        assert!(!sessions.closed("alice"));
+
+        sessions.opened("alice");
+        sessions.opened("alice");
+        assert_eq!(sessions.extra("alice"), 2);
+        assert!(sessions.closed("alice"));
+        assert!(sessions.closed("alice"));
+        assert_eq!(sessions.extra("alice"), 0);
+        assert!(!sessions.closed("alice"));
+    }
+}
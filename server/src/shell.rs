@@ -0,0 +1,268 @@
+//! Console relays (`/shell "tail -f alerts.log" --to ops`): run a command and post
+//! each line it prints to a room, so monitoring output can be piped into chat
+//! without writing a bot. A relay runs until the command exits or is stopped.
+
+use crate::state::{SERVER_ORIGIN, ServerState};
+use crate::user_connection::MAX_MESSAGE_LENGTH;
+use shared::message::{ChatMessage, MessageTypes};
+use shared::sender_class::{self, SenderClass};
+use std::io;
+use std::process::Stdio;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::process::{Child, Command};
+use tokio::task::JoinHandle;
+use tracing::{error, info};
+
+/// Lines relayed per second; a noisy command's extra lines are dropped
+pub const MAX_LINES_PER_SECOND: usize = 5;
+
+pub struct ShellRelay {
+    pub id: u32,
+    pub command: String,
+    pub room: String,
+    pub started: Instant,
+    /// Process ID of the shell, which leads the command's process group
+    pid: Option<u32>,
+    task: JoinHandle<()>,
+}
+
+impl ShellRelay {
+    fn kill(&self) {
+        // Once the relay has finished the shell is gone and its ID may be reused
+        if self.task.is_finished() {
+            return;
+        }
+        // Killing just the shell would leave what it started (e.g. a pipeline) running
+        #[cfg(unix)]
+        if let Some(pid) = self.pid.and_then(|pid| i32::try_from(pid).ok()) {
+            // SAFETY: kill() has no memory safety requirements
+            unsafe {
+                libc::kill(-pid, libc::SIGTERM);
+            }
+        }
+        // Dropping the child with the task kills the shell itself
+        self.task.abort();
+    }
+}
+
+/// Relays started from the console
+#[derive(Default)]
+pub struct ShellRelays {
+    next_id: u32,
+    relays: Vec<ShellRelay>,
+}
+
+impl ShellRelays {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start `command` and post its output to `room` as `sender`
+    pub fn start(
+        &mut self,
+        command: String,
+        room: String,
+        sender: String,
+        state: ServerState,
+    ) -> io::Result<u32> {
+        let child = spawn(&command)?;
+        let pid = child.id();
+        self.next_id += 1;
+        let id = self.next_id;
+        let task = tokio::spawn(relay(id, child, room.clone(), sender, state));
+        self.relays.push(ShellRelay {
+            id,
+            command,
+            room,
+            started: Instant::now(),
+            pid,
+            task,
+        });
+        Ok(id)
+    }
+
+    /// Stop a relay, killing its command
+    pub fn stop(&mut self, id: u32) -> Option<ShellRelay> {
+        let index = self.relays.iter().position(|relay| relay.id == id)?;
+        let relay = self.relays.remove(index);
+        relay.kill();
+        Some(relay)
+    }
+
+    /// Relays still running (finished ones are forgotten)
+    pub fn running(&mut self) -> &[ShellRelay] {
+        self.relays.retain(|relay| !relay.task.is_finished());
+        &self.relays
+    }
+}
+
+impl Drop for ShellRelays {
+    /// Commands don't outlive the server
+    fn drop(&mut self) {
+        for relay in &self.relays {
+            relay.kill();
+        }
+    }
+}
+
+#[cfg(unix)]
+fn spawn(command: &str) -> io::Result<Child> {
+    shell_command("sh", "-c", command).process_group(0).spawn()
+}
+
+#[cfg(windows)]
+fn spawn(command: &str) -> io::Result<Child> {
+    shell_command("cmd", "/C", command).spawn()
+}
+
+fn shell_command(shell: &str, flag: &str, command: &str) -> Command {
+    let mut cmd = Command::new(shell);
+    cmd.arg(flag)
+        .arg(command)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .kill_on_drop(true);
+    cmd
+}
+
+async fn relay(id: u32, mut child: Child, room: String, sender: String, state: ServerState) {
+    let Some(stdout) = child.stdout.take() else {
+        return;
+    };
+    let mut lines = BufReader::new(stdout).lines();
+    let mut window_start = Instant::now();
+    let (mut sent, mut dropped) = (0, 0);
+
+    while let Ok(Some(line)) = lines.next_line().await {
+        let line = clean_line(&line);
+        if line.is_empty() {
+            continue;
+        }
+        if window_start.elapsed() >= Duration::from_secs(1) {
+            if dropped > 0 {
+                send_line(
+                    &state,
+                    &room,
+                    &sender,
+                    &format!("({} lines skipped)", dropped),
+                )
+                .await;
+            }
+            window_start = Instant::now();
+            (sent, dropped) = (0, 0);
+        }
+        if sent >= MAX_LINES_PER_SECOND {
+            dropped += 1;
+            continue;
+        }
+        send_line(&state, &room, &sender, &line).await;
+        sent += 1;
+    }
+    if dropped > 0 {
+        send_line(
+            &state,
+            &room,
+            &sender,
+            &format!("({} lines skipped)", dropped),
+        )
+        .await;
+    }
+
+    match child.wait().await {
+        Ok(status) => info!("Shell relay {} finished ({})", id, status),
+        Err(e) => error!("Shell relay {} failed: {}", id, e),
+    }
+}
+
+/// A line as it can be shown in chat: no color codes or other control characters,
+/// and no longer than a chat message
+pub(crate) fn clean_line(line: &str) -> String {
+    let mut cleaned = String::new();
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' && chars.peek() == Some(&'[') {
+            // Skip an escape sequence such as "\x1b[31m" up to its final byte
+            for c in chars.by_ref().skip(1) {
+                if ('@'..='~').contains(&c) {
+                    break;
+                }
+            }
+        } else if c == '\t' {
+            cleaned.push(' ');
+        } else if !c.is_control() {
+            cleaned.push(c);
+        }
+    }
+    cleaned
+        .trim_end()
+        .chars()
+        .take(MAX_MESSAGE_LENGTH)
+        .collect()
+}
+
+/// Post a line to the room, keeping it in the room's history like anything else said there
+async fn send_line(state: &ServerState, room: &str, sender: &str, line: &str) {
+    // Whoever started it, it's the console talking
+    let content = sender_class::tag(
+        SenderClass::Server,
+        &format!("{}|{}|{}", room, sender, line),
+    );
+    if let Ok(message) = ChatMessage::try_new(MessageTypes::RoomMessage, Some(content.into_bytes()))
+    {
+        let _ = state
+            .record_history(room, sender, line, message, SERVER_ORIGIN)
+            .await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_relay_posts_command_output() {
+        let state = ServerState::for_tests();
+        let mut rx = state.channel.subscribe();
+        let mut relays = ShellRelays::new();
+        let id = relays
+            .start(
+                "printf 'disk 91%%\\n\\n\\033[31mred\\033[0m\\n'".to_string(),
+                "ops".to_string(),
+                "monitor".to_string(),
+                state.clone(),
+            )
+            .unwrap();
+        assert_eq!(id, 1);
+
+        let (first, origin) = rx.recv().await.unwrap();
+        assert_eq!(origin, SERVER_ORIGIN);
+        let first = first.content_as_string().unwrap();
+        assert_eq!(
+            sender_class::untag(&first),
+            (Some(SenderClass::Server), "ops|monitor|disk 91%")
+        );
+        // Blank lines are skipped and colors removed
+        let (second, _) = rx.recv().await.unwrap();
+        let second = second.content_as_string().unwrap();
+        assert_eq!(sender_class::untag(&second).1, "ops|monitor|red");
+        let history = state.history.read().await.recent("ops", 10);
+        assert_eq!(history.len(), 2);
+
+        let mut long_running = ShellRelays::new();
+        let id = long_running
+            .start(
+                "sleep 30".to_string(),
+                "ops".to_string(),
+                "monitor".to_string(),
+                ServerState::for_tests(),
+            )
+            .unwrap();
+        assert_eq!(long_running.running().len(), 1);
+        assert!(long_running.stop(id).is_some());
+        assert!(long_running.running().is_empty());
+        assert!(long_running.stop(id).is_none());
+    }
+}
@@ -0,0 +1,570 @@
+use crate::ServerCommand;
+use crate::accounts::{AccountStore, NickConflictPolicy, ReclaimPolicy};
+use crate::audit::AuditLog;
+use crate::auth::bot_token::{BotTokenProvider, Grant, TokenStore};
+use crate::auth::client_cert::{ClientCertMap, ClientCertProvider};
+use crate::auth::{AuthProvider, Authenticator, LocalProvider};
+use crate::bandwidth::BandwidthTracker;
+use crate::bans::BanList;
+use crate::blocks::{BlockList, Holder};
+use crate::channel::{Broadcast, BroadcastChannel};
+use crate::fanout::{Fanout, Subscription};
+use crate::history::{ExportPolicy, Retention, RoomHistory};
+use crate::history_writer::{HistoryWriter, Record};
+use crate::mailbox::Mailbox;
+use crate::maintenance_window::MaintenanceWindow;
+use crate::memory::MemoryBudget;
+use crate::presence::PresenceTracker;
+use crate::privacy::ContentLogging;
+use crate::rooms::RoomRegistry;
+use crate::scopes::{Scope, Scopes};
+use crate::seen::SeenLog;
+use crate::sessions::Sessions;
+use crate::stats_history::Counters;
+use crate::translate::Translator;
+use crate::violations::ViolationTracker;
+use crate::waiting_room::WaitingRoom;
+use chrono::Local;
+use shared::error::ChatError;
+use shared::fingerprint::Fingerprint;
+use shared::message::{ChatMessage, MessageTypes};
+use shared::presence::{self, PresenceDelta};
+use shared::room_keys::RoomKeyFrame;
+use shared::sender_class::{self, SenderClass};
+use shared::server_info::ServerInfo;
+use shared::socket::SocketOptions;
+use shared::trace::Tracer;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::{RwLock, broadcast};
+
+/// Identifies one connection for as long as the server runs. Broadcasts carry the
+/// ID of the connection they came from, so it isn't sent its own messages back -
+/// unlike addresses, IDs are never shared or reused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ConnectionId(u64);
+
+impl ConnectionId {
+    /// An ID no connection has had before
+    pub fn next() -> Self {
+        static NEXT: AtomicU64 = AtomicU64::new(1);
+        ConnectionId(NEXT.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+impl fmt::Display for ConnectionId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#{}", self.0)
+    }
+}
+
+/// Origin of messages from the server itself (console commands, shell relays)
+pub const SERVER_ORIGIN: ConnectionId = ConnectionId(0);
+
+/// Settings read from the environment at startup
+pub struct ServerSettings {
+    pub max_clients: usize,
+    /// Connections allowed to queue for a slot while the chat is full (0 = turn them away)
+    pub waiting_room_size: usize,
+    /// Slots of max_clients only registered users may fill
+    pub reserved_slots: usize,
+    /// Name the server speaks as when the operator uses /say or /announce
+    pub server_identity: String,
+    /// Name, network and description sent to clients when they join
+    pub info: ServerInfo,
+    /// Protocol violations allowed per IP (None = paranoid mode disabled)
+    pub paranoid_max_violations: Option<u32>,
+    /// Bytes each user may send per hour (None = unlimited)
+    pub bandwidth_quota: Option<u64>,
+    /// What happens to a guest using a registered nickname when its owner logs in
+    pub reclaim_policy: ReclaimPolicy,
+    /// What happens when someone joins with a nickname that's already in use
+    pub nick_conflict: NickConflictPolicy,
+    /// Trace file every connection's frames are written to (None = tracing off)
+    pub tracer: Option<Tracer>,
+    /// How long a kicked user is kept out when /kick has no --for (None = they can rejoin at once)
+    pub kick_cooldown: Option<Duration>,
+    /// Where client fingerprints, joins and moderation actions are recorded
+    pub audit: AuditLog,
+    /// Proof-of-work difficulty in bits required before joining (None = no challenge)
+    pub pow_difficulty: Option<u8>,
+    /// Identity systems checked after the local accounts (LDAP, OIDC)
+    pub auth_providers: Vec<Box<dyn AuthProvider>>,
+    /// Whether guests may /register the nickname they are using
+    pub open_registration: bool,
+    /// What guests may do (CHAT_SERVER_GUEST_SCOPES)
+    pub guest_scopes: Scopes,
+    /// Whether a registered nickname may be logged in to from several clients at once
+    pub multi_session: bool,
+    /// Whether the console shows message text, not just who sent what where
+    pub log_content: bool,
+    /// Client certificate subjects and their nicknames (None = mutual TLS off)
+    pub client_certs: Option<ClientCertMap>,
+    /// Bytes of history and queued broadcasts allowed (None = no cap)
+    pub memory_cap: Option<usize>,
+    /// Tasks fanning broadcasts out to connections (0 = each connection reads the channel)
+    pub broadcast_shards: usize,
+    /// Who may download a room's history with /export-room
+    pub room_export: ExportPolicy,
+    /// Most messages sent for one /export-room
+    pub room_export_limit: usize,
+    /// Command translating messages in rooms with a language set (None = no translations)
+    pub translator: Option<Translator>,
+    /// Room history restored from the data directory (empty unless it's kept there)
+    pub history: RoomHistory,
+    /// Task appending room history to the data directory (None = history is only kept
+    /// in memory)
+    pub history_writer: Option<HistoryWriter>,
+    /// Bytes of each frame gathered before writing to a connection's socket (0 = each
+    /// piece is written as it comes)
+    pub write_buffer: usize,
+    /// Nagle's algorithm, keepalive probes and buffer sizes for accepted sockets
+    pub socket_options: SocketOptions,
+}
+
+/// State shared between the server console and every user connection
+#[derive(Clone)]
+pub struct ServerState {
+    /// Every relayed message goes through it (resizable with /channel resize)
+    pub channel: BroadcastChannel,
+    pub server_commands: broadcast::Sender<ServerCommand>,
+    /// Shard tasks passing broadcasts on to connections (None = sharding off)
+    pub fanout: Option<Fanout>,
+    pub connected_clients: Arc<RwLock<HashSet<String>>>,
+    /// Joins queued while the chat is full (None = waiting room disabled)
+    pub waiting_room: Option<Arc<RwLock<WaitingRoom>>>,
+    /// Messages relayed and errors handled, sampled for /stats --graph
+    pub counters: Arc<Counters>,
+    /// Users who aren't logged in to a registered nickname allowed in the chat at
+    /// once; the other slots are kept for registered users (None = none reserved)
+    pub guest_slots: Option<usize>,
+    /// Maps username to their IP address
+    pub user_ips: Arc<RwLock<HashMap<String, IpAddr>>>,
+    /// Maps username to their status message
+    pub user_statuses: Arc<RwLock<HashMap<String, String>>>,
+    /// Maps username to their session token (for reconnection validation)
+    pub user_sessions: Arc<RwLock<HashMap<String, String>>>,
+    /// Maps username to the fingerprint their client sent (if any)
+    pub user_fingerprints: Arc<RwLock<HashMap<String, Fingerprint>>>,
+    /// Connect time, activity and client version of each user (for /whois)
+    pub presence: Arc<RwLock<PresenceTracker>>,
+    /// When users were last connected and last spoke (persisted to the data directory)
+    pub seen: Arc<RwLock<SeenLog>>,
+    /// Direct messages held for registered users while they're offline
+    pub mailbox: Arc<RwLock<Mailbox>>,
+    /// Planned downtime, shown to users as they join (/maintenance)
+    pub maintenance: Arc<RwLock<MaintenanceWindow>>,
+    /// Chat rooms and their members
+    pub rooms: Arc<RwLock<RoomRegistry>>,
+    /// Recent messages per room and each room's retention policy
+    pub history: Arc<RwLock<RoomHistory>>,
+    /// Persists what the history stores (CHAT_SERVER_PERSIST_HISTORY)
+    pub history_writer: Option<HistoryWriter>,
+    /// Memory used by history and queued broadcasts, and the cap on it
+    pub memory: Arc<MemoryBudget>,
+    /// Name the server speaks as when the operator uses /say or /announce
+    pub server_identity: String,
+    /// Name, network and description sent to clients when they join
+    pub info: ServerInfo,
+    /// Banned IP addresses and post-kick cooldowns
+    pub bans: Arc<RwLock<BanList>>,
+    /// Default post-kick cooldown (CHAT_SERVER_KICK_COOLDOWN)
+    pub kick_cooldown: Option<Duration>,
+    /// Protocol violations per IP (None = paranoid mode disabled)
+    pub violations: Option<Arc<RwLock<ViolationTracker>>>,
+    /// Bytes sent per user and hourly quotas
+    pub bandwidth: Arc<RwLock<BandwidthTracker>>,
+    /// Users each user has blocked (persisted to the data directory)
+    pub blocks: Arc<RwLock<BlockList>>,
+    /// Registered nicknames (persisted to the data directory)
+    pub accounts: Arc<RwLock<AccountStore>>,
+    /// Bot tokens and what each lets its bot do (persisted to the data directory)
+    pub tokens: Arc<RwLock<TokenStore>>,
+    /// Checks logins against the local accounts, then any external providers
+    pub auth: Arc<Authenticator>,
+    /// Guests may register their own nickname (CHAT_SERVER_OPEN_REGISTRATION)
+    pub open_registration: bool,
+    /// What users who aren't logged in may do (CHAT_SERVER_GUEST_SCOPES)
+    pub guest_scopes: Scopes,
+    /// Connected users who logged in to their registered nickname
+    pub authenticated: Arc<RwLock<HashSet<String>>>,
+    /// Logging in again keeps the first session open (CHAT_SERVER_MULTI_SESSION)
+    pub multi_session: bool,
+    /// Sessions beyond the first of users logged in from several clients
+    pub sessions: Arc<RwLock<Sessions>>,
+    /// Maps client certificates to nicknames (None = mutual TLS off)
+    pub client_certs: Option<Arc<ClientCertMap>>,
+    pub reclaim_policy: ReclaimPolicy,
+    /// Nicknames already in use are suffixed or refused (CHAT_SERVER_NICK_CONFLICT)
+    pub nick_conflict: NickConflictPolicy,
+    /// Protocol trace file (CHAT_TRACE=1)
+    pub tracer: Option<Tracer>,
+    pub audit: AuditLog,
+    /// Message text shown on the console, or only its size (CHAT_SERVER_LOG_CONTENT)
+    pub content_logging: ContentLogging,
+    /// Proof-of-work challenge difficulty (CHAT_SERVER_POW_DIFFICULTY)
+    pub pow_difficulty: Option<u8>,
+    /// Who may export room history (CHAT_SERVER_ROOM_EXPORT)
+    pub room_export: ExportPolicy,
+    pub room_export_limit: usize,
+    /// Translation hook (CHAT_SERVER_TRANSLATE_COMMAND)
+    pub translator: Option<Translator>,
+    /// Write buffer per connection (CHAT_SERVER_WRITE_BUFFER_KB)
+    pub write_buffer: usize,
+    pub started_at: Instant,
+}
+
+impl ServerState {
+    pub fn new(
+        settings: ServerSettings,
+        blocks: BlockList,
+        accounts: AccountStore,
+        tokens: TokenStore,
+        seen: SeenLog,
+    ) -> Self {
+        let capacity = settings.max_clients * 16; // Allow message buffering
+        let channel = BroadcastChannel::new(capacity);
+        let fanout = (settings.broadcast_shards > 0)
+            .then(|| Fanout::start(&channel, settings.broadcast_shards, capacity));
+        let (cmd_tx, _cmd_rx) = broadcast::channel(100); // Server commands channel
+        let accounts = Arc::new(RwLock::new(accounts));
+        let mut auth_providers: Vec<Box<dyn AuthProvider>> =
+            vec![Box::new(LocalProvider::new(Arc::clone(&accounts)))];
+        let tokens = Arc::new(RwLock::new(tokens));
+        auth_providers.push(Box::new(BotTokenProvider::new(Arc::clone(&tokens))));
+        auth_providers.extend(settings.auth_providers);
+        let client_certs = settings.client_certs.map(Arc::new);
+        let content_logging = ContentLogging::default();
+        content_logging.set(
+            settings.log_content,
+            &settings.audit,
+            "CHAT_SERVER_LOG_CONTENT",
+        );
+        if let Some(map) = &client_certs {
+            auth_providers.push(Box::new(ClientCertProvider::new(Arc::clone(map))));
+        }
+
+        ServerState {
+            channel,
+            server_commands: cmd_tx,
+            fanout,
+            connected_clients: Arc::new(RwLock::new(HashSet::new())),
+            waiting_room: (settings.waiting_room_size > 0).then(|| {
+                Arc::new(RwLock::new(WaitingRoom::new(
+                    settings.max_clients,
+                    settings.waiting_room_size,
+                )))
+            }),
+            counters: Arc::new(Counters::default()),
+            guest_slots: (settings.reserved_slots > 0)
+                .then(|| settings.max_clients.saturating_sub(settings.reserved_slots)),
+            user_ips: Arc::new(RwLock::new(HashMap::new())),
+            user_statuses: Arc::new(RwLock::new(HashMap::new())),
+            user_sessions: Arc::new(RwLock::new(HashMap::new())),
+            user_fingerprints: Arc::new(RwLock::new(HashMap::new())),
+            presence: Arc::new(RwLock::new(PresenceTracker::default())),
+            seen: Arc::new(RwLock::new(seen)),
+            mailbox: Arc::new(RwLock::new(Mailbox::default())),
+            maintenance: Arc::new(RwLock::new(MaintenanceWindow::default())),
+            rooms: Arc::new(RwLock::new(RoomRegistry::new())),
+            history: Arc::new(RwLock::new(settings.history)),
+            history_writer: settings.history_writer,
+            memory: Arc::new(MemoryBudget::new(settings.memory_cap)),
+            server_identity: settings.server_identity,
+            info: settings.info,
+            bans: Arc::new(RwLock::new(BanList::default())),
+            kick_cooldown: settings.kick_cooldown,
+            violations: settings
+                .paranoid_max_violations
+                .map(|max| Arc::new(RwLock::new(ViolationTracker::new(max)))),
+            bandwidth: Arc::new(RwLock::new(BandwidthTracker::new(settings.bandwidth_quota))),
+            blocks: Arc::new(RwLock::new(blocks)),
+            accounts,
+            tokens,
+            auth: Arc::new(Authenticator::new(auth_providers)),
+            open_registration: settings.open_registration,
+            guest_scopes: settings.guest_scopes,
+            authenticated: Arc::new(RwLock::new(HashSet::new())),
+            multi_session: settings.multi_session,
+            sessions: Arc::new(RwLock::new(Sessions::default())),
+            client_certs,
+            reclaim_policy: settings.reclaim_policy,
+            nick_conflict: settings.nick_conflict,
+            tracer: settings.tracer,
+            audit: settings.audit,
+            content_logging,
+            pow_difficulty: settings.pow_difficulty,
+            room_export: settings.room_export,
+            room_export_limit: settings.room_export_limit,
+            translator: settings.translator,
+            write_buffer: settings.write_buffer,
+            started_at: Instant::now(),
+        }
+    }
+
+    /// Send a message to every connection, noting its size for memory accounting.
+    /// Returns the broadcast's number on the channel.
+    pub fn broadcast(
+        &self,
+        message: ChatMessage,
+        origin: ConnectionId,
+    ) -> Result<u64, broadcast::error::SendError<Broadcast>> {
+        self.memory.note_broadcast(message.wire_size());
+        if matches!(
+            message.msg_type,
+            MessageTypes::ChatMessage | MessageTypes::RoomMessage | MessageTypes::DirectMessage
+        ) {
+            self.counters.message();
+        }
+        self.channel.send((message, origin))
+    }
+
+    /// Tell every connection that someone came, went, was renamed or set a status
+    pub fn broadcast_presence(
+        &self,
+        delta: &PresenceDelta,
+        origin: ConnectionId,
+    ) -> Result<(), ChatError> {
+        let message = ChatMessage::try_new(
+            MessageTypes::PresenceDelta,
+            Some(delta.encode().into_bytes()),
+        )
+        .map_err(|_| ChatError::InvalidMessage)?;
+        self.broadcast(message, origin)
+            .map_err(|_| ChatError::BroadcastError)?;
+        Ok(())
+    }
+
+    /// Tell every connection, the renamed one too, that `from` is now `to`: the notice
+    /// people read (with why, when it wasn't their doing) and the delta clients act on
+    pub fn broadcast_rename(
+        &self,
+        from: &str,
+        to: &str,
+        why: Option<&str>,
+    ) -> Result<(), ChatError> {
+        let mut notice = format!("{}{}{}", from, presence::RENAME_NOTICE, to);
+        if let Some(why) = why {
+            notice.push_str(&format!(" ({})", why));
+        }
+        let message = ChatMessage::try_new(
+            MessageTypes::ChatMessage,
+            Some(sender_class::tag(SenderClass::Notice, &notice).into_bytes()),
+        )
+        .map_err(|_| ChatError::InvalidMessage)?;
+        self.broadcast(message, SERVER_ORIGIN)
+            .map_err(|_| ChatError::BroadcastError)?;
+        self.broadcast_presence(
+            &PresenceDelta::Renamed {
+                from: from.to_string(),
+                to: to.to_string(),
+            },
+            SERVER_ORIGIN,
+        )
+    }
+
+    /// Send a RoomKey frame: to the room's members, the keeper or a key's recipient
+    pub fn broadcast_room_key(
+        &self,
+        frame: &RoomKeyFrame,
+        origin: ConnectionId,
+    ) -> Result<(), ChatError> {
+        let message =
+            ChatMessage::try_new(MessageTypes::RoomKey, Some(frame.encode().into_bytes()))
+                .map_err(|_| ChatError::InvalidMessage)?;
+        self.broadcast(message, origin)
+            .map_err(|_| ChatError::BroadcastError)?;
+        Ok(())
+    }
+
+    /// Ask a private room's keeper for a new key, after someone joined or left.
+    /// Nothing happens for public rooms, or rooms that are gone.
+    pub async fn rotate_room_key(&self, room: &str) -> Result<(), ChatError> {
+        let Some((keeper, members)) = self.rooms.read().await.key_rotation(room) else {
+            return Ok(());
+        };
+        self.broadcast_room_key(
+            &RoomKeyFrame::Rotate {
+                room: room.to_string(),
+                keeper,
+                members,
+            },
+            SERVER_ORIGIN,
+        )
+    }
+
+    /// What `name` may do, if it's a bot logged in with a token (None = anyone else)
+    pub async fn bot_grant(&self, name: &str) -> Option<Grant> {
+        self.tokens.read().await.grant(name).cloned()
+    }
+
+    /// What `name` may do: a bot what its token grants, a guest what
+    /// CHAT_SERVER_GUEST_SCOPES allows, and a logged in user everything
+    pub async fn scopes(&self, name: &str) -> Scopes {
+        if let Some(grant) = self.bot_grant(name).await {
+            grant.scopes()
+        } else if self.authenticated.read().await.contains(name) {
+            Scopes::all()
+        } else {
+            self.guest_scopes
+        }
+    }
+
+    /// Whose list `name`'s blocks are on: their account's if they're logged in
+    pub async fn block_holder(&self, name: &str) -> Holder {
+        if self.authenticated.read().await.contains(name) {
+            Holder::Account
+        } else {
+            Holder::Guest
+        }
+    }
+
+    /// Whether `name` is sent room history and what's said
+    pub async fn may_read(&self, name: &str) -> bool {
+        self.scopes(name).await.contains(Scope::Read)
+    }
+
+    /// Snapshot of everyone online and their status (see shared::presence)
+    pub async fn user_list(&self) -> String {
+        let clients = self.connected_clients.read().await;
+        let statuses = self.user_statuses.read().await;
+        let mut users: Vec<&String> = clients.iter().collect();
+        users.sort();
+        users
+            .into_iter()
+            .map(|user| presence::snapshot_line(user, statuses.get(user).map(String::as_str)))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// A new connection's feed of broadcasts
+    pub fn subscribe(&self) -> Subscription {
+        match &self.fanout {
+            Some(fanout) => fanout.subscribe(),
+            None => Subscription::Direct(self.channel.subscribe()),
+        }
+    }
+
+    /// Broadcasts not yet read by every connection
+    pub fn queued_broadcasts(&self) -> usize {
+        self.channel.queued() + self.fanout.as_ref().map_or(0, Fanout::queued)
+    }
+
+    /// Move every connection onto a broadcast channel holding `capacity` messages.
+    /// With sharding, connections that join from now on get queues that size too.
+    /// Returns how many receivers were moved.
+    pub fn resize_channel(&self, capacity: usize) -> usize {
+        if let Some(fanout) = &self.fanout {
+            fanout.set_capacity(capacity);
+        }
+        self.channel.resize(capacity)
+    }
+
+    /// Store a room message in the history, evicting old history if memory is
+    /// near the cap, and broadcast it as `room_message`. `origin` is the connection
+    /// that sent it. It's broadcast while the history is locked and stored under the
+    /// broadcast's number, so a connection catching up from history after a lag can
+    /// tell exactly which messages it missed.
+    /// Returns the whole message once its last part has arrived (see history::record)
+    pub async fn record_history(
+        &self,
+        room: &str,
+        sender: &str,
+        message: &str,
+        room_message: ChatMessage,
+        origin: ConnectionId,
+    ) -> Result<Option<String>, ChatError> {
+        let mut history = self.history.write().await;
+        let sent = self.broadcast(room_message, origin);
+        // With nobody to send it to there's nobody to catch up either
+        let seq = sent
+            .as_ref()
+            .map_or_else(|_| self.channel.sent(), |seq| *seq);
+        let whole = history.record(room, sender, message, origin, seq);
+        if let Some(message) = &whole
+            && history.retention(room) != Retention::Nothing
+        {
+            self.log_history(Record::Message {
+                at: Local::now(),
+                room: room.to_string(),
+                sender: sender.to_string(),
+                message: message.clone(),
+            });
+        }
+        let mut mailbox = self.mailbox.write().await;
+        self.memory
+            .enforce(&mut history, &mut mailbox, self.queued_broadcasts());
+        drop(mailbox);
+        sent.map_err(|_| ChatError::BroadcastError)?;
+        Ok(whole)
+    }
+
+    /// Evict the oldest history and held messages if memory use is near the cap
+    /// (see crate::memory). Locks the history, then the mailbox.
+    pub async fn enforce_memory(&self) {
+        let mut history = self.history.write().await;
+        let mut mailbox = self.mailbox.write().await;
+        self.memory
+            .enforce(&mut history, &mut mailbox, self.queued_broadcasts());
+    }
+
+    /// Queue a change to the history for the history log, if it's kept
+    pub fn log_history(&self, record: Record) {
+        if let Some(writer) = &self.history_writer {
+            writer.send(record);
+        }
+    }
+}
+
+#[cfg(test)]
+impl ServerState {
+    /// A server with default settings and nothing stored, for tests
+    pub fn for_tests() -> Self {
+        let settings = ServerSettings {
+            max_clients: 16,
+            waiting_room_size: 0,
+            reserved_slots: 0,
+            server_identity: "server".to_string(),
+            info: ServerInfo {
+                name: "test".to_string(),
+                network: String::new(),
+                version: String::new(),
+                description: String::new(),
+            },
+            paranoid_max_violations: None,
+            bandwidth_quota: None,
+            reclaim_policy: ReclaimPolicy::Rename,
+            nick_conflict: NickConflictPolicy::Random,
+            tracer: None,
+            kick_cooldown: None,
+            audit: AuditLog::default(),
+            pow_difficulty: None,
+            auth_providers: Vec::new(),
+            open_registration: false,
+            guest_scopes: Scopes::all(),
+            multi_session: false,
+            log_content: false,
+            client_certs: None,
+            memory_cap: None,
+            broadcast_shards: 0,
+            room_export: ExportPolicy::Off,
+            room_export_limit: 0,
+            translator: None,
+            history: RoomHistory::new(),
+            history_writer: None,
+            write_buffer: 0,
+            socket_options: SocketOptions::default(),
+        };
+        ServerState::new(
+            settings,
+            BlockList::default(),
+            AccountStore::default(),
+            TokenStore::default(),
+            SeenLog::default(),
+        )
+    }
+}
@@ -0,0 +1,194 @@
+//! Rolling history of the server's vital signs for /stats --graph. Every
+//! SAMPLE_INTERVAL the number of connections, and how many messages were relayed and
+//! errors handled since the sample before, are kept in memory for RETENTION and drawn
+//! as sparklines on the console.
+
+use crate::schedule;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// How often a sample is taken
+pub const SAMPLE_INTERVAL: Duration = Duration::from_secs(10);
+/// How far back samples are kept (and the longest span /stats --graph shows)
+pub const RETENTION: Duration = Duration::from_secs(24 * 60 * 60);
+/// Span /stats --graph shows when none is given
+pub const DEFAULT_SPAN: Duration = Duration::from_secs(60 * 60);
+/// Columns of a sparkline; longer spans put several samples in a column
+const WIDTH: usize = 60;
+const BARS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+/// Running totals connections add to as they go
+#[derive(Debug, Default)]
+pub struct Counters {
+    messages: AtomicU64,
+    errors: AtomicU64,
+}
+
+impl Counters {
+    /// A chat, room or direct message was relayed
+    pub fn message(&self) {
+        self.messages.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Handling something a client sent failed
+    pub fn error(&self) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Sample {
+    connections: usize,
+    /// Relayed since the sample before
+    messages: u64,
+    /// Handled since the sample before
+    errors: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct StatsHistory {
+    samples: VecDeque<Sample>,
+    /// Counter totals at the last sample
+    messages: u64,
+    errors: u64,
+}
+
+impl StatsHistory {
+    /// Take a sample, dropping the oldest once RETENTION is covered
+    pub fn record(&mut self, connections: usize, counters: &Counters) {
+        let messages = counters.messages.load(Ordering::Relaxed);
+        let errors = counters.errors.load(Ordering::Relaxed);
+        self.samples.push_back(Sample {
+            connections,
+            messages: messages - self.messages,
+            errors: errors - self.errors,
+        });
+        self.messages = messages;
+        self.errors = errors;
+        let kept = (RETENTION.as_secs() / SAMPLE_INTERVAL.as_secs()) as usize;
+        while self.samples.len() > kept {
+            self.samples.pop_front();
+        }
+    }
+
+    /// Sparklines of the last `span` (oldest on the left), one line per stat, or
+    /// None before the first sample
+    pub fn graph(&self, span: Duration) -> Option<Vec<String>> {
+        let wanted = (span.as_secs() / SAMPLE_INTERVAL.as_secs()).max(1) as usize;
+        let samples: Vec<Sample> = self
+            .samples
+            .iter()
+            .skip(self.samples.len().saturating_sub(wanted))
+            .copied()
+            .collect();
+        let last = *samples.last()?;
+        let per_column = samples.len().div_ceil(WIDTH);
+        let columns: Vec<&[Sample]> = samples.chunks(per_column).collect();
+
+        // Each column shows the busiest moment it covers
+        let connections: Vec<f64> = columns
+            .iter()
+            .map(|column| column.iter().map(|s| s.connections).max().unwrap_or(0) as f64)
+            .collect();
+        let rate = |sample: &Sample| sample.messages as f64 / SAMPLE_INTERVAL.as_secs_f64();
+        let messages: Vec<f64> = columns
+            .iter()
+            .map(|column| column.iter().map(rate).fold(0.0, f64::max))
+            .collect();
+        let errors: Vec<f64> = columns
+            .iter()
+            .map(|column| column.iter().map(|s| s.errors).sum::<u64>() as f64)
+            .collect();
+
+        let covered = Duration::from_secs(samples.len() as u64 * SAMPLE_INTERVAL.as_secs());
+        Some(vec![
+            line(
+                "Connections",
+                &connections,
+                &format!("now {}", last.connections),
+            ),
+            line("Messages/s", &messages, &format!("now {:.1}", rate(&last))),
+            line(
+                "Errors",
+                &errors,
+                &format!("total {}", samples.iter().map(|s| s.errors).sum::<u64>()),
+            ),
+            format!(
+                "{:<12} {} per column, oldest on the left",
+                "",
+                schedule::format_interval(covered / columns.len() as u32)
+            ),
+        ])
+    }
+}
+
+fn line(label: &str, values: &[f64], summary: &str) -> String {
+    let max = values.iter().copied().fold(0.0, f64::max);
+    format!(
+        "{:<12} {} max {} {}",
+        label,
+        sparkline(values, max),
+        if max.fract() == 0.0 {
+            format!("{}", max)
+        } else {
+            format!("{:.1}", max)
+        },
+        summary
+    )
+}
+
+/// Zero is a blank, so quiet stretches stand out; anything else gets at least the
+/// lowest bar
+fn sparkline(values: &[f64], max: f64) -> String {
+    values
+        .iter()
+        .map(|value| {
+            if *value <= 0.0 || max <= 0.0 {
+                ' '
+            } else {
+                let level = (value / max * BARS.len() as f64).ceil() as usize;
+                BARS[level.clamp(1, BARS.len()) - 1]
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_samples_become_sparklines() {
+        let mut history = StatsHistory::default();
+        assert!(history.graph(DEFAULT_SPAN).is_none());
+
+        let counters = Counters::default();
+        for connections in [0, 2, 4, 8] {
+            for _ in 0..connections * 10 {
+                counters.message();
+            }
+            history.record(connections, &counters);
+        }
+        counters.error();
+        history.record(8, &counters);
+
+        let graph = history.graph(DEFAULT_SPAN).unwrap();
+        assert_eq!(graph[0], "Connections   ▂▄██ max 8 now 8");
+        assert_eq!(graph[1], "Messages/s    ▂▄█  max 8 now 0.0");
+        assert_eq!(graph[2], "Errors           █ max 1 total 1");
+        assert!(graph[3].ends_with("10s per column, oldest on the left"));
+
+        // A shorter span only shows the latest samples
+        let graph = history.graph(Duration::from_secs(20)).unwrap();
+        assert_eq!(graph[0], "Connections  ██ max 8 now 8");
+
+        // Longer histories are squeezed into the same width
+        for _ in 0..RETENTION.as_secs() / SAMPLE_INTERVAL.as_secs() {
+            history.record(1, &counters);
+        }
+        let graph = history.graph(RETENTION).unwrap();
+        assert_eq!(graph[0].chars().filter(|c| *c == '█').count(), WIDTH);
+        assert!(graph[3].contains("24m per column"));
+    }
+}
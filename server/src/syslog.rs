@@ -0,0 +1,522 @@
+//! Streams audit events to a syslog collector as they happen, so they land in a SIEM
+//! alongside the audit log file. Events are RFC 5424 messages sent over UDP, TCP or
+//! TLS (with RFC 6587 octet-counting framing on streams), the event name as MSGID and
+//! its details as the message.
+//!
+//! Recording an event never waits for the collector: events go into a bounded queue
+//! that a background task drains. If the collector is slow or unreachable the queue
+//! fills, further events are dropped and counted, and once it catches up an
+//! AUDIT_DROPPED event says how many were lost. Stream connections are retried with
+//! backoff.
+
+use chrono::{Local, SecondsFormat};
+use rustls::pki_types::ServerName;
+use rustls::{ClientConfig, RootCertStore};
+use rustls_pemfile::certs;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpStream, UdpSocket, lookup_host};
+use tokio::sync::mpsc;
+use tokio::time::{sleep, timeout};
+use tokio_rustls::TlsConnector;
+use tokio_rustls::client::TlsStream;
+use tracing::warn;
+
+/// Events waiting for the collector before new ones are dropped
+const QUEUE_SIZE: usize = 1024;
+/// A write taking longer than this means the collector is stuck: reconnect
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+const FIRST_RETRY: Duration = Duration::from_secs(1);
+const MAX_RETRY: Duration = Duration::from_secs(60);
+const APP_NAME: &str = "rust_chat";
+/// Security/authorization messages
+pub const DEFAULT_FACILITY: u8 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Transport {
+    Udp,
+    Tcp,
+    Tls,
+}
+
+/// Where events are sent, e.g. `udp://siem.example.com` or `tls://siem.example.com:6514`
+#[derive(Debug, Clone, PartialEq)]
+pub struct SyslogTarget {
+    pub transport: Transport,
+    pub host: String,
+    pub port: u16,
+}
+
+impl SyslogTarget {
+    /// Parse `scheme://host[:port]`; the port defaults to the scheme's registered one
+    /// (514, 601 or 6514)
+    pub fn parse(url: &str) -> Result<Self, String> {
+        let (scheme, address) = url
+            .split_once("://")
+            .ok_or("expected udp://, tcp:// or tls:// followed by a host")?;
+        let (transport, default_port) = match scheme.to_lowercase().as_str() {
+            "udp" => (Transport::Udp, 514),
+            "tcp" => (Transport::Tcp, 601),
+            "tls" => (Transport::Tls, 6514),
+            _ => return Err(format!("unknown transport '{}'", scheme)),
+        };
+        let address = address.trim_end_matches('/');
+        // IPv6 addresses are written in brackets: [::1]:514
+        let (host, port) = match address.rsplit_once(':') {
+            Some((host, port)) if !port.contains(']') => (
+                host,
+                port.parse::<u16>()
+                    .map_err(|_| format!("invalid port '{}'", port))?,
+            ),
+            _ => (address, default_port),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() {
+            return Err("missing host".to_string());
+        }
+        Ok(SyslogTarget {
+            transport,
+            host: host.to_string(),
+            port,
+        })
+    }
+}
+
+impl fmt::Display for SyslogTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let scheme = match self.transport {
+            Transport::Udp => "udp",
+            Transport::Tcp => "tcp",
+            Transport::Tls => "tls",
+        };
+        if self.host.contains(':') {
+            write!(f, "{}://[{}]:{}", scheme, self.host, self.port)
+        } else {
+            write!(f, "{}://{}:{}", scheme, self.host, self.port)
+        }
+    }
+}
+
+const FACILITIES: [(&str, u8); 20] = [
+    ("kern", 0),
+    ("user", 1),
+    ("mail", 2),
+    ("daemon", 3),
+    ("auth", 4),
+    ("syslog", 5),
+    ("lpr", 6),
+    ("news", 7),
+    ("uucp", 8),
+    ("cron", 9),
+    ("authpriv", 10),
+    ("ftp", 11),
+    ("local0", 16),
+    ("local1", 17),
+    ("local2", 18),
+    ("local3", 19),
+    ("local4", 20),
+    ("local5", 21),
+    ("local6", 22),
+    ("local7", 23),
+];
+
+/// Facility code for a name like "auth" or "local3"
+pub fn parse_facility(name: &str) -> Option<u8> {
+    let name = name.trim().to_lowercase();
+    FACILITIES
+        .iter()
+        .find(|(known, _)| *known == name)
+        .map(|(_, code)| *code)
+}
+
+const SEVERITIES: [&str; 8] = [
+    "emerg", "alert", "crit", "err", "warning", "notice", "info", "debug",
+];
+
+/// Severity code for a name like "warning" (or its number)
+pub fn parse_severity(name: &str) -> Option<u8> {
+    let name = name.trim().to_lowercase();
+    let name = match name.as_str() {
+        "error" => "err",
+        "warn" => "warning",
+        "critical" => "crit",
+        "emergency" => "emerg",
+        other => other,
+    };
+    SEVERITIES
+        .iter()
+        .position(|known| *known == name)
+        .or_else(|| name.parse::<usize>().ok().filter(|code| *code < 8))
+        .map(|code| code as u8)
+}
+
+/// Which severity each audit event is sent with
+#[derive(Debug, Clone, Default)]
+pub struct SeverityMap {
+    overrides: HashMap<String, u8>,
+}
+
+impl SeverityMap {
+    /// Parse overrides like "JOIN=debug,KICK=warning"; `*=` sets every other event
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let mut overrides = HashMap::new();
+        for entry in spec.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let (event, severity) = entry
+                .split_once('=')
+                .ok_or_else(|| format!("expected EVENT=severity, got '{}'", entry))?;
+            let severity = parse_severity(severity)
+                .ok_or_else(|| format!("unknown severity '{}'", severity.trim()))?;
+            overrides.insert(event.trim().to_uppercase(), severity);
+        }
+        Ok(SeverityMap { overrides })
+    }
+
+    pub fn severity(&self, event: &str) -> u8 {
+        if let Some(severity) = self
+            .overrides
+            .get(event)
+            .or_else(|| self.overrides.get("*"))
+        {
+            return *severity;
+        }
+        match event {
+            "BAN" | "REJECT" | "CONTENT_LOGGING" | "AUDIT_DROPPED" => 4,
+            "KICK" | "MUTE" | "UNBAN" | "UNMUTE" | "CLEAR" | "REGISTER" | "EXPORT"
+            | "ROOM_PRIVATE" => 5,
+            _ => 6,
+        }
+    }
+}
+
+/// Format one event as an RFC 5424 message
+fn format_event(facility: u8, severity: u8, hostname: &str, event: &str, details: &str) -> String {
+    // MSGID is printable ASCII without spaces, at most 32 characters
+    let msgid: String = event
+        .chars()
+        .filter(|c| c.is_ascii_graphic())
+        .take(32)
+        .collect();
+    format!(
+        "<{}>1 {} {} {} {} {} - {}",
+        u16::from(facility) * 8 + u16::from(severity),
+        Local::now().to_rfc3339_opts(SecondsFormat::Millis, false),
+        hostname,
+        APP_NAME,
+        std::process::id(),
+        if msgid.is_empty() { "-" } else { &msgid },
+        details
+    )
+}
+
+/// Settings read from CHAT_SERVER_SYSLOG*
+pub struct SyslogConfig {
+    pub target: SyslogTarget,
+    pub facility: u8,
+    pub severities: SeverityMap,
+    /// Certificates the collector's TLS certificate is checked against (None = the
+    /// public web roots)
+    pub roots: Option<RootCertStore>,
+}
+
+/// Read CA certificates for checking a collector with a private CA
+pub fn load_roots(path: &Path) -> io::Result<RootCertStore> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut roots = RootCertStore::empty();
+    for cert in certs(&mut reader) {
+        roots.add(cert?).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid CA certificate: {}", e),
+            )
+        })?;
+    }
+    Ok(roots)
+}
+
+/// Handle for queueing events to the collector. Clones share the queue.
+#[derive(Clone)]
+pub struct SyslogSink {
+    queue: mpsc::Sender<String>,
+    facility: u8,
+    severities: Arc<SeverityMap>,
+    hostname: Arc<str>,
+    /// Events dropped because the queue was full, not yet reported
+    dropped: Arc<AtomicU64>,
+}
+
+impl SyslogSink {
+    /// Start the task sending events to the collector
+    pub fn start(config: SyslogConfig) -> Self {
+        let (queue, events) = mpsc::channel(QUEUE_SIZE);
+        let sink = SyslogSink {
+            queue,
+            facility: config.facility,
+            severities: Arc::new(config.severities),
+            hostname: hostname().into(),
+            dropped: Arc::new(AtomicU64::new(0)),
+        };
+        let roots = config.roots.unwrap_or_else(|| {
+            let mut roots = RootCertStore::empty();
+            roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+            roots
+        });
+        let tls = TlsConnector::from(Arc::new(
+            ClientConfig::builder()
+                .with_root_certificates(roots)
+                .with_no_client_auth(),
+        ));
+        tokio::spawn(run(config.target, tls, events, sink.clone()));
+        sink
+    }
+
+    /// Queue an event, dropping it if the collector has fallen too far behind
+    pub fn send(&self, event: &str, details: &str) {
+        let line = format_event(
+            self.facility,
+            self.severities.severity(event),
+            &self.hostname,
+            event,
+            details,
+        );
+        if self.queue.try_send(line).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// The AUDIT_DROPPED event reporting `dropped` lost events
+    fn dropped_event(&self, dropped: u64) -> String {
+        format_event(
+            self.facility,
+            self.severities.severity("AUDIT_DROPPED"),
+            &self.hostname,
+            "AUDIT_DROPPED",
+            &format!(
+                "{} audit event(s) dropped while the collector was behind",
+                dropped
+            ),
+        )
+    }
+}
+
+fn hostname() -> String {
+    std::env::var("HOSTNAME")
+        .ok()
+        .or_else(|| std::fs::read_to_string("/etc/hostname").ok())
+        .map(|name| name.trim().replace(' ', "_"))
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "-".to_string())
+}
+
+enum Connection {
+    Udp(UdpSocket),
+    Tcp(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl Connection {
+    async fn open(target: &SyslogTarget, tls: &TlsConnector) -> io::Result<Self> {
+        let addr: SocketAddr = lookup_host((target.host.as_str(), target.port))
+            .await?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "host not found"))?;
+        match target.transport {
+            Transport::Udp => {
+                let local: SocketAddr = if addr.is_ipv4() {
+                    "0.0.0.0:0".parse().unwrap()
+                } else {
+                    "[::]:0".parse().unwrap()
+                };
+                let socket = UdpSocket::bind(local).await?;
+                socket.connect(addr).await?;
+                Ok(Connection::Udp(socket))
+            }
+            Transport::Tcp => Ok(Connection::Tcp(TcpStream::connect(addr).await?)),
+            Transport::Tls => {
+                let name = ServerName::try_from(target.host.clone())
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+                let stream = TcpStream::connect(addr).await?;
+                Ok(Connection::Tls(Box::new(tls.connect(name, stream).await?)))
+            }
+        }
+    }
+
+    async fn send(&mut self, line: &str) -> io::Result<()> {
+        // Streams frame each message with its length (RFC 6587 octet counting)
+        let framed = || format!("{} {}", line.len(), line);
+        match self {
+            // A datagram the collector didn't take is lost either way
+            Connection::Udp(socket) => {
+                let _ = socket.send(line.as_bytes()).await;
+                Ok(())
+            }
+            Connection::Tcp(stream) => stream.write_all(framed().as_bytes()).await,
+            Connection::Tls(stream) => {
+                stream.write_all(framed().as_bytes()).await?;
+                stream.flush().await
+            }
+        }
+    }
+}
+
+/// Send queued events until the server stops, reconnecting when the collector goes
+/// away. The event being sent when a connection breaks is sent again on the next.
+async fn run(
+    target: SyslogTarget,
+    tls: TlsConnector,
+    mut events: mpsc::Receiver<String>,
+    sink: SyslogSink,
+) {
+    let mut connection: Option<Connection> = None;
+    let mut retry = FIRST_RETRY;
+    let mut pending: Option<String> = None;
+    loop {
+        let line = match pending.take() {
+            Some(line) => line,
+            None => match events.recv().await {
+                Some(line) => line,
+                None => return,
+            },
+        };
+        if connection.is_none() {
+            match Connection::open(&target, &tls).await {
+                Ok(opened) => {
+                    connection = Some(opened);
+                    retry = FIRST_RETRY;
+                }
+                Err(e) => {
+                    warn!(
+                        "Syslog collector {} unreachable: {} (retrying in {}s)",
+                        target,
+                        e,
+                        retry.as_secs()
+                    );
+                    pending = Some(line);
+                    sleep(retry).await;
+                    retry = (retry * 2).min(MAX_RETRY);
+                    continue;
+                }
+            }
+        }
+        let Some(open) = connection.as_mut() else {
+            continue;
+        };
+        // Events lost since the last report are reported before the next one
+        let dropped = sink.dropped.swap(0, Ordering::Relaxed);
+        let mut result = Ok(());
+        if dropped > 0 {
+            result = send(open, &sink.dropped_event(dropped)).await;
+            if result.is_err() {
+                sink.dropped.fetch_add(dropped, Ordering::Relaxed);
+            }
+        }
+        if result.is_ok() {
+            result = send(open, &line).await;
+        }
+        if let Err(reason) = result {
+            warn!("Lost the syslog collector {}: {}", target, reason);
+            connection = None;
+            pending = Some(line);
+        }
+    }
+}
+
+async fn send(connection: &mut Connection, line: &str) -> Result<(), String> {
+    match timeout(WRITE_TIMEOUT, connection.send(line)).await {
+        Ok(Ok(())) => Ok(()),
+        Ok(Err(e)) => Err(e.to_string()),
+        Err(_) => Err("timed out".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn test_targets_and_severities() {
+        assert_eq!(
+            SyslogTarget::parse("tls://siem.example.com").unwrap(),
+            SyslogTarget {
+                transport: Transport::Tls,
+                host: "siem.example.com".to_string(),
+                port: 6514,
+            }
+        );
+        let target = SyslogTarget::parse("udp://[::1]:5514").unwrap();
+        assert_eq!((target.host.as_str(), target.port), ("::1", 5514));
+        assert_eq!(target.to_string(), "udp://[::1]:5514");
+        assert!(SyslogTarget::parse("siem.example.com:514").is_err());
+        assert!(SyslogTarget::parse("http://siem.example.com").is_err());
+        assert!(SyslogTarget::parse("tcp://siem:port").is_err());
+
+        assert_eq!(parse_facility("LOCAL3"), Some(19));
+        assert_eq!(parse_facility("nope"), None);
+
+        let map = SeverityMap::default();
+        assert_eq!(map.severity("BAN"), 4);
+        assert_eq!(map.severity("KICK"), 5);
+        assert_eq!(map.severity("JOIN"), 6);
+        let map = SeverityMap::parse("join=debug, *=notice").unwrap();
+        assert_eq!(map.severity("JOIN"), 7);
+        assert_eq!(map.severity("BAN"), 5);
+        assert!(SeverityMap::parse("BAN=loud").is_err());
+
+        let line = format_event(4, 4, "chat1", "BAN", "10.0.0.1/32");
+        assert!(line.starts_with("<36>1 "));
+        assert!(line.ends_with(&format!(
+            " chat1 rust_chat {} BAN - 10.0.0.1/32",
+            std::process::id()
+        )));
+    }
+
+    #[tokio::test]
+    async fn test_events_reach_a_tcp_collector() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let sink = SyslogSink::start(SyslogConfig {
+            target: SyslogTarget::parse(&format!("tcp://127.0.0.1:{}", port)).unwrap(),
+            facility: 4,
+            severities: SeverityMap::default(),
+            roots: None,
+        });
+        // Two events more than the queue holds: the collector isn't reading yet
+        for i in 0..QUEUE_SIZE + 2 {
+            sink.send("JOIN", &format!("user{}", i));
+        }
+
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut received = String::new();
+        let last = format!(" JOIN - user{}", QUEUE_SIZE - 1);
+        while !received.ends_with(&last) {
+            let mut buf = [0u8; 4096];
+            let read = stream.read(&mut buf).await.unwrap();
+            assert!(read > 0);
+            received.push_str(std::str::from_utf8(&buf[..read]).unwrap());
+        }
+
+        // Each message is framed with its length; the count of dropped events
+        // goes out first, then everything that was queued
+        let (length, rest) = received.split_once(' ').unwrap();
+        let first = &rest[..length.parse::<usize>().unwrap()];
+        assert!(first.starts_with("<36>1 "));
+        assert!(
+            first.ends_with(
+                "AUDIT_DROPPED - 2 audit event(s) dropped while the collector was behind"
+            )
+        );
+        let (length, rest) = rest[first.len()..].split_once(' ').unwrap();
+        let second = &rest[..length.parse::<usize>().unwrap()];
+        assert!(second.starts_with("<38>1 "));
+        assert!(second.ends_with(" JOIN - user0"));
+    }
+}
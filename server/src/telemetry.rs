@@ -0,0 +1,313 @@
+//! Tracing for the server. Log lines are `tracing` events, written to the console by a
+//! subscriber layer that hands them to the shared logger: `info!`, `warn!` and `error!`
+//! look the way log lines always have, and the `success!`, `system!`, `announcement!`
+//! and `chat!` macros below pick the logger's other styles.
+//!
+//! Each connection runs in a `connection` span, each frame it sends in a `message`
+//! span and each broadcast it passes on to its client in a `relay` span; shard tasks
+//! deliver broadcasts to connection queues in `fanout` spans. Built with the
+//! `otlp` feature and with CHAT_SERVER_OTLP_ENDPOINT set, the spans are exported to an
+//! OpenTelemetry collector, showing how long a message spends being read, handled and
+//! fanned out to connections.
+//!
+//! Lines logged inside a connection's span start with its ID (`[#12]`), which stays
+//! the same through renames and is never reused, so everything one connection did
+//! can be picked out of the log even when nicknames change or repeat.
+
+use shared::logger;
+use std::fmt;
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::Layer;
+use tracing_subscriber::filter::filter_fn;
+use tracing_subscriber::layer::{Context, SubscriberExt};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
+
+/// An operation that worked, logged as `[OK]`
+macro_rules! success {
+    ($($arg:tt)+) => { tracing::info!(kind = "success", $($arg)+) };
+}
+
+/// Users coming, going and renaming, logged as `[SYSTEM]`
+macro_rules! system {
+    ($($arg:tt)+) => { tracing::info!(kind = "system", $($arg)+) };
+}
+
+/// An announcement sent to users
+macro_rules! announcement {
+    ($($arg:tt)+) => { tracing::info!(kind = "announcement", $($arg)+) };
+}
+
+/// A chat message as `sender: text`, with `room = %room` for one sent to a room
+macro_rules! chat {
+    ($($arg:tt)+) => { tracing::info!(kind = "chat", $($arg)+) };
+}
+
+pub(crate) use {announcement, chat, success, system};
+
+/// How the logger shows a line
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Style {
+    Info,
+    Success,
+    Warning,
+    Error,
+    System,
+    Announcement,
+    Chat,
+}
+
+impl Style {
+    fn of(level: &Level, kind: Option<&str>) -> Self {
+        match (*level, kind) {
+            (Level::ERROR, _) => Style::Error,
+            (Level::WARN, _) => Style::Warning,
+            (_, Some("success")) => Style::Success,
+            (_, Some("system")) => Style::System,
+            (_, Some("announcement")) => Style::Announcement,
+            (_, Some("chat")) => Style::Chat,
+            _ => Style::Info,
+        }
+    }
+}
+
+/// One event, ready for the logger
+#[derive(Debug, Default)]
+struct Line {
+    message: String,
+    kind: Option<String>,
+    room: Option<String>,
+}
+
+/// The `id` of a `connection` span, kept in the span's extensions
+struct ConnectionTag(String);
+
+impl Visit for ConnectionTag {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "id" {
+            self.0 = format!("{:?}", value);
+        }
+    }
+}
+
+impl Visit for Line {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        match field.name() {
+            "kind" => self.kind = Some(value.to_string()),
+            "room" => self.room = Some(value.to_string()),
+            _ => self.record_debug(field, &value),
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        match field.name() {
+            "message" => self.message = format!("{:?}", value),
+            "room" => self.room = Some(format!("{:?}", value)),
+            _ => {}
+        }
+    }
+}
+
+/// Writes the server's events to the console through the shared logger
+struct LoggerLayer {
+    output: fn(Style, Line),
+}
+
+impl<S> Layer<S> for LoggerLayer
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        if attrs.metadata().name() != "connection" {
+            return;
+        }
+        let mut tag = ConnectionTag(String::new());
+        attrs.record(&mut tag);
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(tag);
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let mut line = Line::default();
+        event.record(&mut line);
+        let connection = ctx.event_scope(event).and_then(|scope| {
+            scope
+                .filter_map(|span| {
+                    span.extensions()
+                        .get::<ConnectionTag>()
+                        .map(|tag| tag.0.clone())
+                })
+                .next()
+        });
+        if let Some(connection) = connection {
+            line.message = format!("[{}] {}", connection, line.message);
+        }
+        let style = Style::of(event.metadata().level(), line.kind.as_deref());
+        (self.output)(style, line);
+    }
+}
+
+fn log(style: Style, line: Line) {
+    let message = &line.message;
+    match style {
+        Style::Info => logger::log_info(message),
+        Style::Success => logger::log_success(message),
+        Style::Warning => logger::log_warning(message),
+        Style::Error => logger::log_error(message),
+        Style::System => logger::log_system(message),
+        Style::Announcement => logger::log_announcement(message),
+        Style::Chat => match &line.room {
+            Some(room) => logger::log_room_chat(room, message),
+            None => logger::log_chat(message),
+        },
+    }
+}
+
+/// The server's own events at info level and above (libraries' aren't shown), and
+/// the connection spans they're tagged with
+fn console_layer<S>(output: fn(Style, Line)) -> impl Layer<S>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    LoggerLayer { output }.with_filter(filter_fn(|metadata| {
+        metadata.target().starts_with(env!("CARGO_CRATE_NAME"))
+            && if metadata.is_span() {
+                metadata.name() == "connection"
+            } else {
+                *metadata.level() <= Level::INFO
+            }
+    }))
+}
+
+/// Flushes exported spans when the server exits
+pub struct Telemetry {
+    #[cfg(feature = "otlp")]
+    provider: Option<opentelemetry_sdk::trace::SdkTracerProvider>,
+}
+
+impl Drop for Telemetry {
+    fn drop(&mut self) {
+        #[cfg(feature = "otlp")]
+        if let Some(provider) = self.provider.take() {
+            let _ = provider.shutdown();
+        }
+    }
+}
+
+/// Start sending events to the console, and spans to CHAT_SERVER_OTLP_ENDPOINT if set
+pub fn init() -> Telemetry {
+    let registry = tracing_subscriber::registry().with(console_layer(log));
+
+    #[cfg(feature = "otlp")]
+    {
+        let endpoint = std::env::var(otlp::CHAT_SERVER_OTLP_ENDPOINT_ENV_VAR).ok();
+        let (layer, provider) = match endpoint.as_deref().map(otlp::layer) {
+            Some(Ok((layer, provider))) => (Some(layer), Some(provider)),
+            Some(Err(e)) => {
+                registry.init();
+                tracing::error!("Can't export traces: {}", e);
+                return Telemetry { provider: None };
+            }
+            None => (None, None),
+        };
+        registry.with(layer).init();
+        match endpoint {
+            Some(endpoint) => tracing::info!("Exporting traces to {}", endpoint),
+            None => tracing::info!(
+                "To export traces to an OpenTelemetry collector, set {}",
+                otlp::CHAT_SERVER_OTLP_ENDPOINT_ENV_VAR
+            ),
+        }
+        Telemetry { provider }
+    }
+
+    #[cfg(not(feature = "otlp"))]
+    {
+        registry.init();
+        Telemetry {}
+    }
+}
+
+#[cfg(feature = "otlp")]
+mod otlp {
+    use opentelemetry::trace::TracerProvider;
+    use opentelemetry_otlp::{ExporterBuildError, SpanExporter, WithExportConfig};
+    use opentelemetry_sdk::Resource;
+    use opentelemetry_sdk::trace::SdkTracerProvider;
+    use tracing::Subscriber;
+    use tracing_subscriber::Layer;
+    use tracing_subscriber::registry::LookupSpan;
+
+    pub const CHAT_SERVER_OTLP_ENDPOINT_ENV_VAR: &str = "CHAT_SERVER_OTLP_ENDPOINT";
+    const SERVICE_NAME: &str = "rust_chat_server";
+
+    /// A layer exporting spans over OTLP/HTTP to `endpoint`
+    /// (e.g. `http://localhost:4318/v1/traces`)
+    pub fn layer<S>(
+        endpoint: &str,
+    ) -> Result<(impl Layer<S>, SdkTracerProvider), ExporterBuildError>
+    where
+        S: Subscriber + for<'span> LookupSpan<'span>,
+    {
+        let exporter = SpanExporter::builder()
+            .with_http()
+            .with_endpoint(endpoint)
+            .build()?;
+        let provider = SdkTracerProvider::builder()
+            .with_batch_exporter(exporter)
+            .with_resource(Resource::builder().with_service_name(SERVICE_NAME).build())
+            .build();
+        let layer = tracing_opentelemetry::layer().with_tracer(provider.tracer(SERVICE_NAME));
+        Ok((layer, provider))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    thread_local! {
+        static LINES: RefCell<Vec<(Style, String, Option<String>)>> = const { RefCell::new(Vec::new()) };
+    }
+
+    fn capture(style: Style, line: Line) {
+        LINES.with(|lines| lines.borrow_mut().push((style, line.message, line.room)));
+    }
+
+    #[test]
+    fn test_events_reach_the_logger_in_their_style() {
+        let subscriber = tracing_subscriber::registry().with(console_layer(capture));
+        tracing::subscriber::with_default(subscriber, || {
+            let room = "ops";
+            tracing::info!("{} connected", "alice");
+            success!("User {} registered", "alice");
+            tracing::warn!(kind = "success", "careful");
+            tracing::debug!("too detailed");
+            chat!(room = %room, "{}", "alice: [5 bytes]");
+            tracing::info!(target: "rustls", "not ours");
+            let connection = tracing::info_span!("connection", id = %"#7", user = "bob");
+            let _entered = connection.enter();
+            tracing::info_span!("message").in_scope(|| tracing::warn!("rejected"));
+        });
+
+        let lines = LINES.with(|lines| lines.take());
+        assert_eq!(
+            lines,
+            vec![
+                (Style::Info, "alice connected".to_string(), None),
+                (Style::Success, "User alice registered".to_string(), None),
+                (Style::Warning, "careful".to_string(), None),
+                (
+                    Style::Chat,
+                    "alice: [5 bytes]".to_string(),
+                    Some("ops".to_string())
+                ),
+                (Style::Warning, "[#7] rejected".to_string(), None),
+            ]
+        );
+    }
+}
@@ -0,0 +1,169 @@
+//! Translation hook (CHAT_SERVER_TRANSLATE_COMMAND): a command run for each message
+//! said in a room a moderator set a language on (`/room translate fr`). It gets the
+//! message on stdin, and CHAT_TRANSLATE_TO, CHAT_TRANSLATE_ROOM and
+//! CHAT_TRANSLATE_SENDER in its environment, and prints the translation, which is
+//! sent to the room's members as a Translation frame linked to the message (see
+//! shared::translation). Any translation service can be plugged in this way, e.g. a
+//! script posting to an HTTP API with curl. A command that fails, takes too long or
+//! prints nothing - or the message as it was, already in the language - adds nothing.
+
+use crate::shell;
+use crate::state::{SERVER_ORIGIN, ServerState};
+use shared::message::{ChatMessage, MessageTypes};
+use shared::translation::{self, Translation};
+use std::io;
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tokio::sync::Semaphore;
+use tokio::time::timeout;
+use tracing::warn;
+
+/// Longest a translation may take before it's given up on
+pub const TIMEOUT: Duration = Duration::from_secs(10);
+/// Translations run at once; messages arriving while they're all busy go untranslated
+pub const MAX_RUNNING: usize = 4;
+
+#[derive(Clone)]
+pub struct Translator {
+    command: String,
+    running: Arc<Semaphore>,
+}
+
+impl Translator {
+    pub fn new(command: String) -> Self {
+        Translator {
+            command,
+            running: Arc::new(Semaphore::new(MAX_RUNNING)),
+        }
+    }
+
+    pub fn command(&self) -> &str {
+        &self.command
+    }
+
+    /// Translate `text`, said by `sender` in `room`, into `lang` in the background,
+    /// and send the translation to the room
+    pub fn spawn(&self, state: ServerState, room: &str, sender: &str, lang: &str, text: &str) {
+        let Ok(permit) = Arc::clone(&self.running).try_acquire_owned() else {
+            warn!(
+                "Translation hook busy - a message from {} in #{} was left untranslated",
+                sender, room
+            );
+            return;
+        };
+        let translator = self.clone();
+        let (room, sender, lang, text) = (
+            room.to_string(),
+            sender.to_string(),
+            lang.to_string(),
+            text.to_string(),
+        );
+        tokio::spawn(async move {
+            let _permit = permit;
+            let translated =
+                match timeout(TIMEOUT, translator.run(&room, &sender, &lang, &text)).await {
+                    Ok(Ok(translated)) => translated,
+                    Ok(Err(e)) => {
+                        warn!("Translation hook failed: {}", e);
+                        return;
+                    }
+                    Err(_) => {
+                        warn!("Translation hook took over {}s", TIMEOUT.as_secs());
+                        return;
+                    }
+                };
+            if translated.is_empty() || translated == text {
+                return;
+            }
+            let frame = Translation {
+                reference: translation::reference(&text),
+                room,
+                sender,
+                lang,
+                text: translated,
+            };
+            if let Ok(message) =
+                ChatMessage::try_new(MessageTypes::Translation, Some(frame.encode().into_bytes()))
+            {
+                let _ = state.broadcast(message, SERVER_ORIGIN);
+            }
+        });
+    }
+
+    /// The command's translation, on one line as a chat message would be
+    async fn run(&self, room: &str, sender: &str, lang: &str, text: &str) -> io::Result<String> {
+        let mut child = shell_command(&self.command)
+            .env("CHAT_TRANSLATE_TO", lang)
+            .env("CHAT_TRANSLATE_ROOM", room)
+            .env("CHAT_TRANSLATE_SENDER", sender)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()?;
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(text.as_bytes()).await?;
+        }
+        let output = child.wait_with_output().await?;
+        if !output.status.success() {
+            return Err(io::Error::other(format!(
+                "'{}' exited with {}",
+                self.command, output.status
+            )));
+        }
+        let lines: Vec<String> = String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .map(shell::clean_line)
+            .filter(|line| !line.is_empty())
+            .collect();
+        Ok(shell::clean_line(&lines.join(" ")))
+    }
+}
+
+#[cfg(unix)]
+fn shell_command(command: &str) -> Command {
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c").arg(command);
+    cmd
+}
+
+#[cfg(windows)]
+fn shell_command(command: &str) -> Command {
+    let mut cmd = Command::new("cmd");
+    cmd.arg("/C").arg(command);
+    cmd
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_translation_follows_message() {
+        let state = ServerState::for_tests();
+        let mut rx = state.channel.subscribe();
+        let translator =
+            Translator::new("printf '[%s] ' \"$CHAT_TRANSLATE_TO\"; tr a-z A-Z".to_string());
+        translator.spawn(state.clone(), "ops", "alice", "fr", "bonjour");
+
+        let (message, origin) = rx.recv().await.unwrap();
+        assert_eq!(origin, SERVER_ORIGIN);
+        assert_eq!(message.msg_type, MessageTypes::Translation);
+        let frame = Translation::decode(&message.content_as_string().unwrap()).unwrap();
+        assert_eq!(frame.text, "[fr] BONJOUR");
+        assert_eq!(frame.reference, translation::reference("bonjour"));
+        assert_eq!((frame.room.as_str(), frame.lang.as_str()), ("ops", "fr"));
+
+        // Nothing is sent for a message the command leaves as it is
+        Translator::new("cat".to_string()).spawn(state, "ops", "alice", "fr", "bonjour");
+        assert!(
+            tokio::time::timeout(Duration::from_millis(500), rx.recv())
+                .await
+                .is_err()
+        );
+    }
+}
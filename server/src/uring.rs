@@ -0,0 +1,189 @@
+//! io_uring backend for client sockets (the `io-uring` feature, turned on with
+//! CHAT_SERVER_IO_URING=1 on Linux). The server then runs on tokio-uring's
+//! single-threaded runtime: connections are still accepted by tokio, but reads and
+//! writes on them are submitted to the kernel's io_uring instead of waiting for
+//! readiness. Compare the two with the `load` example before switching.
+
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::task::{Context, Poll, ready};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_uring::BufResult;
+use tokio_uring::net::TcpStream;
+
+/// Size of each socket read (a frame is read in as many as it takes)
+const READ_BUFFER_SIZE: usize = 8 * 1024;
+/// Writes are submitted once this much is buffered, without waiting for a flush
+const WRITE_BUFFER_SIZE: usize = 8 * 1024;
+/// Submission queue entries; each connection has at most a read and a write in flight
+const RING_ENTRIES: u32 = 4096;
+
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// Whether the server is running on the io_uring runtime
+pub fn active() -> bool {
+    ACTIVE.load(Ordering::Relaxed)
+}
+
+/// Run the server on tokio-uring's runtime. Fails if the kernel doesn't support
+/// io_uring (or it's blocked, as some container runtimes do).
+pub fn run<F: Future>(future: F) -> io::Result<F::Output> {
+    let runtime = tokio_uring::Runtime::new(tokio_uring::builder().entries(RING_ENTRIES))?;
+    ACTIVE.store(true, Ordering::Relaxed);
+    Ok(runtime.block_on(future))
+}
+
+type ReadOp = Pin<Box<dyn Future<Output = BufResult<usize, Vec<u8>>>>>;
+type WriteOp = Pin<Box<dyn Future<Output = BufResult<(), Vec<u8>>>>>;
+
+/// A client socket doing its I/O through io_uring, usable wherever the connection
+/// code expects a tokio stream. Writes are buffered until a flush (or the buffer
+/// fills), so a frame goes to the kernel in one submission.
+pub struct UringStream {
+    stream: Rc<TcpStream>,
+    read: Option<ReadOp>,
+    /// Last read from the socket, and how much of it has been handed out
+    buffered: Vec<u8>,
+    consumed: usize,
+    /// Written but not yet submitted
+    outgoing: Vec<u8>,
+    write: Option<WriteOp>,
+    /// Buffer of the last completed write, reused for the next
+    spare: Vec<u8>,
+}
+
+impl UringStream {
+    /// Take over a socket accepted by tokio
+    pub fn from_tokio(socket: tokio::net::TcpStream) -> io::Result<Self> {
+        let socket = socket.into_std()?;
+        // io_uring waits for the socket itself; it doesn't need (or want) O_NONBLOCK
+        socket.set_nonblocking(false)?;
+        Ok(UringStream {
+            stream: Rc::new(TcpStream::from_std(socket)),
+            read: None,
+            buffered: Vec::with_capacity(READ_BUFFER_SIZE),
+            consumed: 0,
+            outgoing: Vec::with_capacity(WRITE_BUFFER_SIZE),
+            write: None,
+            spare: Vec::new(),
+        })
+    }
+
+    /// Submit what's buffered and wait until the kernel has all of it
+    fn poll_submit(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        loop {
+            if let Some(op) = &mut self.write {
+                let (result, mut buffer) = ready!(op.as_mut().poll(cx));
+                self.write = None;
+                buffer.clear();
+                self.spare = buffer;
+                result?;
+            }
+            if self.outgoing.is_empty() {
+                return Poll::Ready(Ok(()));
+            }
+            let stream = self.stream.clone();
+            let buffer = std::mem::replace(&mut self.outgoing, std::mem::take(&mut self.spare));
+            self.write = Some(Box::pin(async move { stream.write_all(buffer).await }));
+        }
+    }
+}
+
+impl AsyncRead for UringStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.consumed == this.buffered.len() {
+            let op = this.read.get_or_insert_with(|| {
+                let stream = this.stream.clone();
+                let mut buffer = std::mem::take(&mut this.buffered);
+                buffer.clear();
+                this.consumed = 0;
+                Box::pin(async move { stream.read(buffer).await })
+            });
+            let (result, buffer) = ready!(op.as_mut().poll(cx));
+            this.read = None;
+            this.buffered = buffer;
+            // Nothing read (end of stream) leaves `buf` unfilled
+            result?;
+        }
+        let available = &this.buffered[this.consumed..];
+        let n = available.len().min(buf.remaining());
+        buf.put_slice(&available[..n]);
+        this.consumed += n;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for UringStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.outgoing.len() >= WRITE_BUFFER_SIZE {
+            ready!(this.poll_submit(cx))?;
+        }
+        this.outgoing.extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().poll_submit(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_submit(cx))?;
+        Poll::Ready(this.stream.shutdown(std::net::Shutdown::Write))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn test_stream_round_trip() {
+        // Skip where io_uring isn't allowed (e.g. some CI containers)
+        let Ok(runtime) = tokio_uring::Runtime::new(&tokio_uring::builder()) else {
+            return;
+        };
+        runtime.block_on(async {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let client = tokio::spawn(async move {
+                let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+                let mut received = vec![0; 100_000];
+                client.read_exact(&mut received).await.unwrap();
+                client.write_all(b"done").await.unwrap();
+                received
+            });
+
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut stream = UringStream::from_tokio(socket).unwrap();
+            // Bigger than a read buffer, and written in pieces
+            let sent: Vec<u8> = (0..100_000).map(|i| (i % 251) as u8).collect();
+            for chunk in sent.chunks(7_000) {
+                stream.write_all(chunk).await.unwrap();
+            }
+            stream.flush().await.unwrap();
+
+            let mut reply = [0; 4];
+            stream.read_exact(&mut reply).await.unwrap();
+            assert_eq!(&reply, b"done");
+            assert_eq!(client.await.unwrap(), sent);
+
+            // The client has closed its end
+            assert_eq!(stream.read(&mut reply).await.unwrap(), 0);
+        });
+    }
+}
@@ -0,0 +1,85 @@
+use rand::Rng;
+use shared::challenge::{Challenge, NONCE_LENGTH};
+use std::time::{Duration, Instant};
+
+/// How long a client has to answer a proof-of-work challenge
+pub const CHALLENGE_TIMEOUT: Duration = Duration::from_secs(60);
+
+// Where a connection is with its proof-of-work challenge
+#[derive(Debug, Default)]
+pub enum ChallengeState {
+    #[default]
+    NotSent,
+    Sent {
+        challenge: Challenge,
+        sent_at: Instant,
+    },
+    Solved,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ChallengeError {
+    /// Answer without an outstanding challenge
+    NotSent,
+    Expired,
+    WrongAnswer,
+}
+
+impl ChallengeState {
+    pub fn is_solved(&self) -> bool {
+        matches!(self, ChallengeState::Solved)
+    }
+
+    /// Start a new challenge, replacing any unanswered one
+    pub fn issue(&mut self, difficulty: u8, now: Instant) -> Challenge {
+        let mut rng = rand::thread_rng();
+        let nonce = (0..NONCE_LENGTH)
+            .map(|_| char::from_digit(rng.gen_range(0..16), 16).unwrap_or('0'))
+            .collect();
+        let challenge = Challenge { difficulty, nonce };
+        *self = ChallengeState::Sent {
+            challenge: challenge.clone(),
+            sent_at: now,
+        };
+        challenge
+    }
+
+    pub fn answer(&mut self, answer: u64, now: Instant) -> Result<(), ChallengeError> {
+        let ChallengeState::Sent { challenge, sent_at } = self else {
+            return Err(ChallengeError::NotSent);
+        };
+        if now.duration_since(*sent_at) > CHALLENGE_TIMEOUT {
+            return Err(ChallengeError::Expired);
+        }
+        if !challenge.verify(answer) {
+            return Err(ChallengeError::WrongAnswer);
+        }
+        *self = ChallengeState::Solved;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_challenge_answers() {
+        let now = Instant::now();
+        let mut state = ChallengeState::default();
+        assert_eq!(state.answer(0, now), Err(ChallengeError::NotSent));
+
+        let challenge = state.issue(8, now);
+        let answer = challenge.solve();
+        let wrong = (0..).find(|&n| !challenge.verify(n)).unwrap();
+        assert_eq!(state.answer(wrong, now), Err(ChallengeError::WrongAnswer));
+        assert_eq!(
+            state.answer(answer, now + CHALLENGE_TIMEOUT + Duration::from_secs(1)),
+            Err(ChallengeError::Expired)
+        );
+        assert_eq!(state.answer(answer, now), Ok(()));
+        assert!(state.is_solved());
+        // Each challenge is answered once
+        assert_eq!(state.answer(answer, now), Err(ChallengeError::NotSent));
+    }
+}
@@ -1,17 +1,46 @@
 use crate::ServerCommand;
+use crate::accounts::{self, AccountError, HashedPassword, NickConflictPolicy};
+use crate::auth::bot_token;
+use crate::auth::client_cert;
+use crate::bandwidth::{self, QuotaStatus};
+use crate::blocks::Holder;
+use crate::drain;
+use crate::history::{self, ExportPolicy, Retention};
+use crate::history_writer::Record;
+use crate::reserved;
+use crate::rooms;
+use crate::schedule;
+use crate::scopes::Scope;
+use crate::state::{ConnectionId, SERVER_ORIGIN, ServerState};
+use crate::telemetry::{announcement, chat, success, system};
+use chrono::{DateTime, Local};
 use rand::Rng;
-use shared::logger;
+use shared::challenge::MAX_DIFFICULTY;
+use shared::digest::Digest;
+use shared::error::ChatError;
+use shared::fingerprint::Fingerprint;
+use shared::history::{self as shared_history, HistoryLine};
+use shared::join_ack::JoinAck;
+use shared::limits::RateLimits;
 use shared::message::{ChatMessage, MessageTypes};
+use shared::name_check::NameStatus;
 use shared::network::TcpMessageHandler;
+use shared::parts;
+use shared::presence::{self, PresenceDelta};
+use shared::room_keys::{self, RoomKeyFrame};
+use shared::rooms::{self as shared_rooms, RoomSummary};
+use shared::sender_class::{self, SenderClass};
+use shared::signing;
+use shared::translation;
 use shared::version::{self, VERSION};
-use std::collections::{HashMap, HashSet};
-use std::net::{IpAddr, SocketAddr};
-use std::sync::Arc;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
 use tokio::io::{AsyncRead, AsyncWrite};
-use tokio::sync::{RwLock, broadcast};
+use tracing::{error, info, warn};
 
-use super::error::UserConnectionError;
-use super::rate_limiting::RateLimiter;
+use super::challenge::{ChallengeError, ChallengeState};
+use super::lifecycle::Lifecycle;
+use super::rate_limiting::{RATE_LIMIT_MESSAGES, RATE_LIMIT_WINDOW, RateLimiter};
 
 // Helper struct to implement TcpMessageHandler for any AsyncRead + AsyncWrite stream
 struct StreamWrapper<'a, S> {
@@ -29,15 +58,29 @@ impl<'a, S: AsyncRead + AsyncWrite + Unpin> TcpMessageHandler for StreamWrapper<
 pub const MAX_USERNAME_LENGTH: usize = 32;
 pub const MAX_MESSAGE_LENGTH: usize = 1024; // 1KB max message content
 pub const MAX_STATUS_LENGTH: usize = 128; // Max status message length
+pub const MAX_SLOWMODE_SECS: u64 = 6 * 60 * 60; // Longest room slow mode interval
+
+/// Sent back to the sender when the recipient has blocked them
+const BLOCKED_ERROR: &str = "message not delivered: blocked";
+
+/// Separate a message from its signature trailer. Returns the text and the suffix to
+/// forward with it (empty when unsigned or when the trailer is malformed).
+fn split_signed(content: &str) -> (&str, String) {
+    match signing::split_signature(content) {
+        (text, Some(trailer)) if signing::is_well_formed(trailer) => {
+            (text, signing::attach("", trailer))
+        }
+        (text, _) => (text, String::new()),
+    }
+}
 
 pub struct MessageHandlers<'a> {
     pub addr: SocketAddr,
-    pub tx: &'a broadcast::Sender<(ChatMessage, SocketAddr)>,
-    pub server_commands: &'a broadcast::Sender<ServerCommand>,
-    pub connected_clients: &'a Arc<RwLock<HashSet<String>>>,
-    pub user_ips: &'a Arc<RwLock<HashMap<String, IpAddr>>>,
-    pub user_statuses: &'a Arc<RwLock<HashMap<String, String>>>,
-    pub user_sessions: &'a Arc<RwLock<HashMap<String, String>>>,
+    /// This connection, as the origin of what it broadcasts
+    pub id: ConnectionId,
+    pub state: &'a ServerState,
+    /// Nickname the client's TLS certificate logs in as (mutual TLS)
+    pub certificate_user: Option<&'a str>,
 }
 
 impl<'a> MessageHandlers<'a> {
@@ -52,35 +95,165 @@ impl<'a> MessageHandlers<'a> {
         message: ChatMessage,
         rate_limiter: &mut RateLimiter,
         stream: &mut S,
-        chat_name: &mut Option<String>,
-    ) -> Result<(), UserConnectionError> {
+        lifecycle: &mut Lifecycle,
+        fingerprint: &mut Option<Fingerprint>,
+        challenge: &mut ChallengeState,
+    ) -> Result<(), ChatError> {
+        lifecycle.check(&message.msg_type)?;
         let mut tcp_handler = StreamWrapper { stream };
         // Rate limiting check (except for Join messages)
         if !matches!(message.msg_type, MessageTypes::Join) && !rate_limiter.check_and_consume() {
-            logger::log_warning(&format!("Rate limit exceeded for {}", self.addr));
-            let error_msg = ChatMessage::try_new(
-                MessageTypes::Error,
-                Some(b"Rate limit exceeded. Please slow down.".to_vec()),
-            )
-            .map_err(|_| UserConnectionError::InvalidMessage)?;
+            warn!("Rate limit exceeded for {}", self.addr);
+            if let Some(name) = lifecycle.name() {
+                self.state.presence.write().await.rate_limited(name);
+            }
+            let error_msg =
+                ChatError::RateLimited.to_message("Rate limit exceeded. Please slow down.")?;
             tcp_handler
                 .send_message_chunked(error_msg)
                 .await
-                .map_err(UserConnectionError::IoError)?;
+                .map_err(ChatError::IoError)?;
             return Ok(());
         }
 
+        // Bandwidth accounting (connection management messages are free)
+        if let Some(name) = lifecycle.name()
+            && !matches!(
+                message.msg_type,
+                MessageTypes::Join
+                    | MessageTypes::VersionCheck
+                    | MessageTypes::Fingerprint
+                    | MessageTypes::Challenge
+                    | MessageTypes::Leave
+            )
+        {
+            let status = self
+                .state
+                .bandwidth
+                .write()
+                .await
+                .record(name, message.wire_size() as u64);
+            match status {
+                QuotaStatus::Ok => {}
+                QuotaStatus::Warning { used, limit } => {
+                    let notice = format!(
+                        "You have used {} of your {} hourly bandwidth quota",
+                        bandwidth::format_bytes(used),
+                        bandwidth::format_bytes(limit)
+                    );
+                    self.send_notice(&mut tcp_handler, &notice).await?;
+                }
+                QuotaStatus::Exceeded {
+                    used,
+                    limit,
+                    retry_after,
+                } => {
+                    warn!(
+                        "Bandwidth quota exceeded for {} ({} of {})",
+                        name,
+                        bandwidth::format_bytes(used),
+                        bandwidth::format_bytes(limit)
+                    );
+                    let text = format!(
+                        "Hourly bandwidth quota exceeded ({} of {} used). Try again in {}.",
+                        bandwidth::format_bytes(used),
+                        bandwidth::format_bytes(limit),
+                        drain::format_remaining(retry_after)
+                    );
+                    self.send_rate_limited(&mut tcp_handler, "bandwidth", retry_after, &text)
+                        .await?;
+                    return Ok(());
+                }
+            }
+        }
+
+        if let Some(name) = lifecycle.name()
+            && !matches!(message.msg_type, MessageTypes::Pong)
+        {
+            self.state.presence.write().await.active(name);
+            if matches!(
+                message.msg_type,
+                MessageTypes::ChatMessage | MessageTypes::RoomMessage | MessageTypes::DirectMessage
+            ) {
+                self.state.seen.write().await.spoke(name);
+            }
+        }
+
+        // The handshake, and renames (which change the nickname)
         match message.msg_type {
             MessageTypes::VersionCheck => {
                 self.process_version_check(message.content_as_string(), &mut tcp_handler)
                     .await?;
+                lifecycle.hello_received();
+                return Ok(());
+            }
+            MessageTypes::Fingerprint => {
+                return self
+                    .process_fingerprint(message.content_as_string(), &mut tcp_handler, fingerprint)
+                    .await;
+            }
+            MessageTypes::Challenge => {
+                return self
+                    .process_challenge_answer(
+                        message.content_as_string(),
+                        &mut tcp_handler,
+                        challenge,
+                    )
+                    .await;
+            }
+            MessageTypes::NameCheck => {
+                return self
+                    .process_name_check(message.content_as_string(), &mut tcp_handler)
+                    .await;
             }
             MessageTypes::Join => {
-                self.process_join(message.content_as_string(), &mut tcp_handler, chat_name)
-                    .await?;
+                // Joins wait until the proof-of-work challenge is solved
+                if let Some(difficulty) = self.state.pow_difficulty
+                    && !challenge.is_solved()
+                {
+                    return self
+                        .send_challenge(&mut tcp_handler, challenge, difficulty)
+                        .await;
+                }
+                lifecycle.authenticated();
+                self.process_join(
+                    message.content_as_string(),
+                    &mut tcp_handler,
+                    lifecycle,
+                    fingerprint.as_ref(),
+                )
+                .await?;
+                lifecycle.activate();
+                return Ok(());
+            }
+            MessageTypes::RenameRequest => {
+                return self
+                    .process_rename_request(
+                        message.content_as_string(),
+                        &mut tcp_handler,
+                        lifecycle,
+                    )
+                    .await;
+            }
+            MessageTypes::Leave => {
+                // User explicitly quit - signal this to the connection handler
+                return Err(ChatError::ExplicitQuit);
             }
+            _ => {}
+        }
+
+        // Everything else only gets past the lifecycle check once joined
+        let Some(chat_name) = lifecycle.name() else {
+            return Err(ChatError::InvalidMessage);
+        };
+        if let Some(refusal) = self.scope_refusal(&message, chat_name).await {
+            return self
+                .send_error(&mut tcp_handler, ChatError::Refused, &refusal)
+                .await;
+        }
+        match message.msg_type {
             MessageTypes::ChatMessage => {
-                self.process_chat_message(message.content_as_string(), chat_name)
+                self.process_chat_message(message.content_as_string(), &mut tcp_handler, chat_name)
                     .await?;
             }
             MessageTypes::ListUsers => {
@@ -94,13 +267,9 @@ impl<'a> MessageHandlers<'a> {
                 )
                 .await?;
             }
-            MessageTypes::RenameRequest => {
-                self.process_rename_request(
-                    message.content_as_string(),
-                    &mut tcp_handler,
-                    chat_name,
-                )
-                .await?;
+            MessageTypes::Register => {
+                self.process_register(message.content_as_string(), &mut tcp_handler, chat_name)
+                    .await?;
             }
             MessageTypes::FileTransfer => {
                 self.process_file_transfer(message.get_content(), &mut tcp_handler, chat_name)
@@ -126,150 +295,219 @@ impl<'a> MessageHandlers<'a> {
                 self.process_set_status(message.content_as_string(), &mut tcp_handler, chat_name)
                     .await?;
             }
-            MessageTypes::Leave => {
-                // User explicitly quit - signal this to the connection handler
-                return Err(UserConnectionError::ExplicitQuit);
+            MessageTypes::JoinRoom => {
+                self.process_join_room(message.content_as_string(), &mut tcp_handler, chat_name)
+                    .await?;
+            }
+            MessageTypes::LeaveRoom => {
+                self.process_leave_room(message.content_as_string(), &mut tcp_handler, chat_name)
+                    .await?;
+            }
+            MessageTypes::RoomMessage => {
+                self.process_room_message(message.content_as_string(), &mut tcp_handler, chat_name)
+                    .await?;
+            }
+            MessageTypes::BlockUser => {
+                self.process_block_user(message.content_as_string(), &mut tcp_handler, chat_name)
+                    .await?;
+            }
+            MessageTypes::RoomCommand => {
+                self.process_room_command(message.content_as_string(), &mut tcp_handler, chat_name)
+                    .await?;
+            }
+            MessageTypes::RoomInfoRequest => {
+                self.process_room_info_request(message.content_as_string(), &mut tcp_handler)
+                    .await?;
+            }
+            MessageTypes::RoomKey => {
+                self.process_room_key(message.content_as_string(), chat_name)
+                    .await?;
+            }
+            MessageTypes::ServerInfo => {
+                self.send_server_info(&mut tcp_handler).await?;
+            }
+            MessageTypes::HistoryRequest => {
+                self.process_history_request(
+                    message.content_as_string(),
+                    &mut tcp_handler,
+                    chat_name,
+                )
+                .await?;
+            }
+            MessageTypes::Seen => {
+                self.process_seen_request(message.content_as_string(), &mut tcp_handler)
+                    .await?;
+            }
+            other => {
+                // Server-only or unknown message types are never sent by a well-behaved client
+                return Err(ChatError::ProtocolViolation(format!(
+                    "unexpected message type {:?}",
+                    other
+                )));
             }
-            _ => (),
         }
         Ok(())
     }
 
+    /// Send everyone online and their status: asked for, and the snapshot a joining
+    /// client keeps up to date from presence deltas
     async fn process_list_users<S: AsyncRead + AsyncWrite + Unpin>(
         &self,
         tcp_handler: &mut StreamWrapper<'_, S>,
-    ) -> Result<(), UserConnectionError> {
-        let clients = self.connected_clients.read().await;
-        let statuses = self.user_statuses.read().await;
-
-        // Build user list with statuses
-        let user_list: Vec<String> = clients
-            .iter()
-            .map(|username| {
-                if let Some(status) = statuses.get(username) {
-                    format!("{} - {}", username, status)
-                } else {
-                    username.clone()
-                }
-            })
-            .collect();
-
-        drop(clients);
-        drop(statuses);
-
+    ) -> Result<(), ChatError> {
         let list_message = ChatMessage::try_new(
             MessageTypes::ListUsers,
-            Some(user_list.join("\n").into_bytes()),
+            Some(self.state.user_list().await.into_bytes()),
         )
-        .map_err(|_| UserConnectionError::InvalidMessage)?;
+        .map_err(|_| ChatError::InvalidMessage)?;
         tcp_handler
             .send_message_chunked(list_message)
             .await
-            .map_err(UserConnectionError::IoError)?;
+            .map_err(ChatError::IoError)?;
         Ok(())
     }
 
-    async fn process_chat_message(
+    async fn process_chat_message<S: AsyncRead + AsyncWrite + Unpin>(
         &self,
         content: Option<String>,
-        chat_name: &Option<String>,
-    ) -> Result<(), UserConnectionError> {
-        let chat_content = content.ok_or(UserConnectionError::InvalidMessage)?;
+        tcp_handler: &mut StreamWrapper<'_, S>,
+        chat_name: &str,
+    ) -> Result<(), ChatError> {
+        let content = content.ok_or(ChatError::InvalidMessage)?;
+        let (chat_content, signature) = split_signed(&content);
 
         // Validate message length
         if chat_content.is_empty() || chat_content.len() > MAX_MESSAGE_LENGTH {
-            logger::log_warning(&format!(
+            warn!(
                 "Invalid message length from {}: {} chars",
                 self.addr,
                 chat_content.len()
-            ));
-            return Err(UserConnectionError::InvalidMessage);
-        }
-
-        if let Some(chat_name) = chat_name {
-            let full_message = format!("{}: {}", chat_name, chat_content);
-            logger::log_chat(&full_message);
-            let broadcast_message =
-                ChatMessage::try_new(MessageTypes::ChatMessage, Some(full_message.into_bytes()))
-                    .map_err(|_| UserConnectionError::InvalidMessage)?;
-            self.tx
-                .send((broadcast_message, self.addr))
-                .map_err(UserConnectionError::BroadcastError)?;
-            Ok(())
-        } else {
-            logger::log_warning(&format!(
-                "User at {} sent chat message before joining",
-                self.addr
-            ));
-            Err(UserConnectionError::InvalidMessage)
+            );
+            return self.refuse_length(tcp_handler).await;
+        }
+        if chat_content.chars().any(char::is_control) {
+            return self.refuse_control(tcp_handler).await;
         }
+
+        let full_message = format!("{}: {}", chat_name, chat_content);
+        chat!(
+            "{}",
+            self.state.content_logging.line(chat_name, chat_content)
+        );
+        let class = reserved::class(chat_name, &self.state.server_identity);
+        let broadcast_message = ChatMessage::try_new(
+            MessageTypes::ChatMessage,
+            Some(sender_class::tag(class, &format!("{}{}", full_message, signature)).into_bytes()),
+        )
+        .map_err(|_| ChatError::InvalidMessage)?;
+        // Kept, so users joining later are shown what was said before them
+        self.state
+            .record_history(
+                history::MAIN_CHAT,
+                chat_name,
+                chat_content,
+                broadcast_message,
+                self.id,
+            )
+            .await?;
+        Ok(())
     }
 
     async fn process_direct_message<S: AsyncRead + AsyncWrite + Unpin>(
         &self,
         content: Option<String>,
         tcp_handler: &mut StreamWrapper<'_, S>,
-        chat_name: &Option<String>,
-    ) -> Result<(), UserConnectionError> {
-        let content = content.ok_or(UserConnectionError::InvalidMessage)?;
+        sender: &str,
+    ) -> Result<(), ChatError> {
+        let content = content.ok_or(ChatError::InvalidMessage)?;
 
         if let Some((recipient, message)) = content.split_once('|') {
+            let (message, signature) = split_signed(message);
+
             // Validate message length
             if message.is_empty() || message.len() > MAX_MESSAGE_LENGTH {
-                logger::log_warning(&format!(
+                warn!(
                     "Invalid DM length from {}: {} chars",
                     self.addr,
                     message.len()
-                ));
-                return Err(UserConnectionError::InvalidMessage);
-            }
-            if let Some(sender) = chat_name {
-                // Check if recipient exists
-                let clients = self.connected_clients.read().await;
-                if !clients.contains(recipient) {
-                    drop(clients); // Release the lock before sending error
-
-                    // Send error message back to sender
-                    let error_msg = format!("User '{}' not found", recipient);
-                    logger::log_warning(&format!(
-                        "[DM] {} -> {} (user not found)",
-                        sender, recipient
-                    ));
-
-                    let error_message =
-                        ChatMessage::try_new(MessageTypes::Error, Some(error_msg.into_bytes()))
-                            .map_err(|_| UserConnectionError::InvalidMessage)?;
-
-                    tcp_handler
-                        .send_message_chunked(error_message)
+                );
+                return self.refuse_length(tcp_handler).await;
+            }
+            if message.chars().any(char::is_control) {
+                return self.refuse_control(tcp_handler).await;
+            }
+            // Check if recipient exists
+            let clients = self.state.connected_clients.read().await;
+            if !clients.contains(recipient) {
+                drop(clients); // Release the lock before sending error
+
+                // A registered user gets it when they next log in
+                if self.state.auth.is_registered(recipient).await {
+                    if self.is_blocked_by(recipient, sender).await {
+                        system!("[DM] {} -> {} (blocked)", sender, recipient);
+                        return self
+                            .send_error(tcp_handler, ChatError::Refused, BLOCKED_ERROR)
+                            .await;
+                    }
+                    let text = format!("{}{}", message, signature);
+                    let kept_all = self
+                        .state
+                        .mailbox
+                        .write()
                         .await
-                        .map_err(UserConnectionError::IoError)?;
-                    return Ok(());
+                        .hold(recipient, sender, &text);
+                    self.state.enforce_memory().await;
+                    system!("[DM] {} -> {} (held, offline)", sender, recipient);
+                    let mut notice = format!(
+                        "{} is offline - they'll get your message when they next log in",
+                        recipient
+                    );
+                    if !kept_all {
+                        notice.push_str(" (their oldest held message was dropped to make room)");
+                    }
+                    return self.send_notice(tcp_handler, &notice).await;
                 }
-                drop(clients); // Release the lock
 
-                // Log that a DM is happening, but don't show the content
-                logger::log_system(&format!("[DM] {} -> {}", sender, recipient));
+                // Send error message back to sender
+                let error_msg = format!("User '{}' not found", recipient);
+                warn!("[DM] {} -> {} (user not found)", sender, recipient);
 
-                // Format: sender|recipient|message for client filtering
-                let dm_content = format!("{}|{}|{}", sender, recipient, message);
-                let dm_message = ChatMessage::try_new(
-                    MessageTypes::DirectMessage,
-                    Some(dm_content.into_bytes()),
-                )
-                .map_err(|_| UserConnectionError::InvalidMessage)?;
+                let error_message = ChatError::UserNotFound.to_message(&error_msg)?;
 
-                // Broadcast to all clients (clients will filter)
-                self.tx
-                    .send((dm_message, self.addr))
-                    .map_err(UserConnectionError::BroadcastError)?;
-                Ok(())
-            } else {
-                logger::log_warning(&format!("User at {} sent DM before joining", self.addr));
-                Err(UserConnectionError::InvalidMessage)
+                tcp_handler
+                    .send_message_chunked(error_message)
+                    .await
+                    .map_err(ChatError::IoError)?;
+                return Ok(());
+            }
+            drop(clients); // Release the lock
+
+            if self.is_blocked_by(recipient, sender).await {
+                system!("[DM] {} -> {} (blocked)", sender, recipient);
+                return self
+                    .send_error(tcp_handler, ChatError::Refused, BLOCKED_ERROR)
+                    .await;
             }
+
+            // Log that a DM is happening, but never show the content
+            system!("[DM] {} -> {} ({} bytes)", sender, recipient, message.len());
+
+            // Format: sender|recipient|message for client filtering
+            let dm_content = sender_class::tag(
+                reserved::class(sender, &self.state.server_identity),
+                &format!("{}|{}|{}{}", sender, recipient, message, signature),
+            );
+            let dm_message =
+                ChatMessage::try_new(MessageTypes::DirectMessage, Some(dm_content.into_bytes()))
+                    .map_err(|_| ChatError::InvalidMessage)?;
+
+            // Broadcast to all clients (clients will filter)
+            self.state
+                .broadcast(dm_message, self.id)
+                .map_err(|_| ChatError::BroadcastError)?;
+            Ok(())
         } else {
-            Err(UserConnectionError::InvalidMessage)
+            Err(ChatError::InvalidMessage)
         }
     }
 
@@ -277,53 +515,243 @@ impl<'a> MessageHandlers<'a> {
         &self,
         username: Option<String>,
         tcp_handler: &mut StreamWrapper<'_, S>,
-        chat_name: &mut Option<String>,
-    ) -> Result<(), UserConnectionError> {
-        let content = username.ok_or(UserConnectionError::InvalidMessage)?;
-
-        // Parse username and session token (format: username|session_token)
-        let (requested_username, session_token) = if let Some((user, token)) = content.split_once('|') {
-            (user.to_string(), Some(token.to_string()))
-        } else {
-            // Backwards compatibility: if no session token, just use the username
-            (content, None)
-        };
+        lifecycle: &mut Lifecycle,
+        fingerprint: Option<&Fingerprint>,
+    ) -> Result<(), ChatError> {
+        let content = username.ok_or(ChatError::InvalidMessage)?;
+
+        // Parse username, session token and password (format: username|session_token[|password])
+        // Backwards compatibility: the session token is optional too
+        let mut fields = content.splitn(3, '|');
+        let requested_username = fields.next().unwrap_or_default().to_string();
+        let session_token = fields.next().map(str::to_string);
+        let password = fields.next();
 
         // Validate username length
         if requested_username.is_empty() || requested_username.len() > MAX_USERNAME_LENGTH {
-            logger::log_warning(&format!(
+            warn!(
                 "Invalid username length from {}: {} chars",
                 self.addr,
                 requested_username.len()
-            ));
-            return Err(UserConnectionError::InvalidMessage);
+            );
+            return self
+                .send_error(
+                    tcp_handler,
+                    ChatError::InvalidUsername,
+                    "Invalid username length (1-32 characters)",
+                )
+                .await;
         }
 
-        // Validate username characters (alphanumeric, underscore, hyphen only)
-        if !requested_username
-            .chars()
-            .all(|c| c.is_alphanumeric() || c == '_' || c == '-')
-        {
-            logger::log_warning(&format!(
+        // Validate username characters (alphanumeric, underscore, hyphen only, after
+        // a reserved prefix)
+        if !reserved::is_well_formed(&requested_username) {
+            warn!(
                 "Invalid username characters from {}: {}",
                 self.addr, requested_username
-            ));
-            return Err(UserConnectionError::InvalidMessage);
+            );
+            return self
+                .send_error(
+                    tcp_handler,
+                    ChatError::InvalidUsername,
+                    "Invalid characters (only alphanumeric, underscore, hyphen allowed)",
+                )
+                .await;
         }
 
-        let connected_clients = self.connected_clients.clone();
-        {
+        // A kicked nickname stays out until its cooldown ends, whatever address it comes from
+        let cooldown = self
+            .state
+            .bans
+            .read()
+            .await
+            .name_cooldown(&requested_username, Instant::now());
+        if let Some(left) = cooldown {
+            warn!(
+                "Rejected join as '{}' from {} (kicked, {} left)",
+                requested_username,
+                self.addr,
+                schedule::format_interval(left)
+            );
+            self.send_error(
+                tcp_handler,
+                ChatError::KickCooldown,
+                &format!(
+                    "You were kicked from this server - you can rejoin in {}",
+                    schedule::format_interval(left)
+                ),
+            )
+            .await?;
+            return Err(ChatError::KickCooldown);
+        }
+
+        // Registered nicknames belong to whoever knows the password - or presents
+        // a client certificate mapped to them
+        let registered = self.state.auth.is_registered(&requested_username).await;
+        let provider = match password {
+            _ if self.certificate_user == Some(requested_username.as_str()) => {
+                Some(client_cert::PROVIDER_NAME)
+            }
+            Some(password) => self.state.auth.verify(&requested_username, password).await,
+            None => None,
+        };
+        let owner = provider.is_some();
+
+        // Server and bot names can't be spoofed; a bot's token is its password
+        if let Some(reason) = reserved::refusal(
+            &requested_username,
+            &self.state.server_identity,
+            provider == Some(bot_token::PROVIDER_NAME),
+        ) {
+            warn!(
+                "Refused reserved nickname '{}' from {}",
+                requested_username, self.addr
+            );
+            self.state
+                .audit
+                .record("RESERVED", &format!("{} {}", self.addr, requested_username));
+            self.send_error(tcp_handler, ChatError::JoinError, &reason)
+                .await?;
+            return Err(ChatError::JoinError);
+        }
+        if registered && !owner {
+            warn!(
+                "{} tried to use registered nickname '{}' without logging in",
+                self.addr, requested_username
+            );
+        }
+
+        let connected_clients = self.state.connected_clients.clone();
+        let extra_session = {
             let mut clients = connected_clients.write().await;
+            let holder_is_guest = owner
+                && clients.contains(&requested_username)
+                && !self
+                    .state
+                    .authenticated
+                    .read()
+                    .await
+                    .contains(&requested_username);
+            // The owner is already logged in from another client and may stay there
+            let extra_session = owner
+                && self.state.multi_session
+                && clients.contains(&requested_username)
+                && !holder_is_guest;
+
+            // Guests can't take the slots kept for registered users (one reconnecting
+            // to its nickname already has a slot)
+            if !owner
+                && let Some(guest_slots) = self.state.guest_slots
+                && !clients.contains(&requested_username)
+            {
+                let logged_in = self.state.authenticated.read().await.len();
+                if clients.len().saturating_sub(logged_in) >= guest_slots {
+                    drop(clients);
+                    warn!(
+                        "Rejected guest '{}' from {}: all {} guest slot(s) are taken",
+                        requested_username, self.addr, guest_slots
+                    );
+                    self.send_error(
+                        tcp_handler,
+                        ChatError::GuestsFull,
+                        "The server is full for guests - only registered users can join right now",
+                    )
+                    .await?;
+                    return Err(ChatError::GuestsFull);
+                }
+            }
 
-            // Check if username already exists
-            if clients.contains(&requested_username) {
+            if registered && !owner {
+                // Guests can't use registered nicknames - give them a random one
+                let new_name = self.randomize_username(&requested_username);
+                if !clients.insert(new_name.clone()) {
+                    return Err(ChatError::JoinError);
+                }
+                drop(clients);
+                self.send_error(
+                    tcp_handler,
+                    ChatError::UsernameTaken,
+                    &format!(
+                        "'{}' is a registered nickname - log in with its password to use it",
+                        requested_username
+                    ),
+                )
+                .await?;
+                let rename_message = ChatMessage::try_new(
+                    MessageTypes::UserRename,
+                    Some(presence::encode_rename(&requested_username, &new_name).into_bytes()),
+                )
+                .map_err(|_| ChatError::InvalidMessage)?;
+                tcp_handler
+                    .send_message_chunked(rename_message)
+                    .await
+                    .map_err(ChatError::IoError)?;
+                lifecycle.joined(new_name.clone());
+                if let Some(token) = session_token {
+                    self.state
+                        .user_sessions
+                        .write()
+                        .await
+                        .insert(new_name, token);
+                }
+            } else if holder_is_guest {
+                // The owner is back - move the guest using their nickname out of the way
+                let guest_name = self.randomize_username(&requested_username);
+                if !clients.insert(guest_name.clone()) {
+                    return Err(ChatError::JoinError);
+                }
+                clients.remove(&requested_username);
+                drop(clients);
+                self.move_guest(&requested_username, &guest_name).await;
+                success!(
+                    "Owner of '{}' logged in from {} - guest renamed to '{}'",
+                    requested_username,
+                    self.addr,
+                    guest_name
+                );
+                let _ = self
+                    .state
+                    .server_commands
+                    .send(ServerCommand::NicknameReclaimed {
+                        old_name: requested_username.clone(),
+                        new_name: guest_name,
+                        owner: self.addr,
+                    });
+
+                lifecycle.joined(requested_username.clone());
+                if let Some(token) = session_token {
+                    self.state
+                        .user_sessions
+                        .write()
+                        .await
+                        .insert(requested_username.clone(), token);
+                }
+            } else if extra_session {
+                // Counted before the clients lock goes, so a rename can't slip in between
+                self.state
+                    .sessions
+                    .write()
+                    .await
+                    .opened(&requested_username);
+                drop(clients);
+                success!(
+                    "{} opened another session from {}",
+                    requested_username,
+                    self.addr
+                );
+                lifecycle.joined(requested_username.clone());
+            } else if clients.contains(&requested_username) {
                 // Username exists - check if this is a valid reconnection (same session token and IP)
                 let can_reclaim = if let Some(ref token) = session_token {
-                    let sessions = self.user_sessions.read().await;
-                    let ips = self.user_ips.read().await;
+                    let sessions = self.state.user_sessions.read().await;
+                    let ips = self.state.user_ips.read().await;
 
-                    let session_matches = sessions.get(&requested_username).is_some_and(|t| t == token);
-                    let ip_matches = ips.get(&requested_username).is_some_and(|ip| *ip == self.addr.ip());
+                    let session_matches = sessions
+                        .get(&requested_username)
+                        .is_some_and(|t| t == token);
+                    let ip_matches = ips
+                        .get(&requested_username)
+                        .is_some_and(|ip| *ip == self.addr.ip());
 
                     drop(sessions);
                     drop(ips);
@@ -335,270 +763,606 @@ impl<'a> MessageHandlers<'a> {
 
                 if can_reclaim {
                     // This is a valid reconnection - reclaim the ghost session
-                    logger::log_success(&format!(
+                    success!(
                         "User '{}' reclaiming ghost session from {} (same token and IP)",
-                        requested_username, self.addr
-                    ));
+                        requested_username,
+                        self.addr
+                    );
 
                     // Signal the old connection to disconnect silently
-                    let _ = self.server_commands.send(ServerCommand::SessionTakeover(requested_username.clone()));
+                    let _ = self
+                        .state
+                        .server_commands
+                        .send(ServerCommand::SessionTakeover(requested_username.clone()));
 
                     // The username is already in the set, so we just claim it for this connection
-                    *chat_name = Some(requested_username.clone());
+                    lifecycle.joined(requested_username.clone());
+                } else if self.state.nick_conflict == NickConflictPolicy::Reject {
+                    drop(clients);
+                    warn!(
+                        "Rejected join as '{}' from {} (nickname in use)",
+                        requested_username, self.addr
+                    );
+                    self.send_error(
+                        tcp_handler,
+                        ChatError::JoinError,
+                        &format!(
+                            "The nickname '{}' is already in use - join with another one",
+                            requested_username
+                        ),
+                    )
+                    .await?;
+                    return Err(ChatError::JoinError);
                 } else {
                     // Not a valid reconnection - rename the user
-                    logger::log_warning(&format!("User '{}' already exists, renaming...", requested_username));
-                    let new_name = self.randomize_username(&requested_username);
+                    warn!("User '{}' already exists, renaming...", requested_username);
+                    let new_name = match self.state.nick_conflict {
+                        NickConflictPolicy::Suffix => {
+                            let mut free = None;
+                            // Not a name in use, nor one a guest may not take
+                            for candidate in
+                                accounts::suffixed_names(&requested_username, MAX_USERNAME_LENGTH)
+                            {
+                                if !clients.contains(&candidate)
+                                    && reserved::refusal(
+                                        &candidate,
+                                        &self.state.server_identity,
+                                        provider == Some(bot_token::PROVIDER_NAME),
+                                    )
+                                    .is_none()
+                                    && !self.state.auth.is_registered(&candidate).await
+                                {
+                                    free = Some(candidate);
+                                    break;
+                                }
+                            }
+                            free.unwrap_or_else(|| self.randomize_username(&requested_username))
+                        }
+                        _ => self.randomize_username(&requested_username),
+                    };
                     if !clients.insert(new_name.clone()) {
-                        logger::log_error(&format!(
+                        error!(
                             "Failed to assign random username to '{}'",
                             requested_username
-                        ));
-                        return Err(UserConnectionError::JoinError);
+                        );
+                        return Err(ChatError::JoinError);
                     }
-                    logger::log_success(&format!("User '{}' renamed to '{}'", requested_username, new_name));
-                    let rename_message = ChatMessage::try_new(
-                        MessageTypes::UserRename,
-                        Some(new_name.clone().into_bytes()),
-                    )
-                    .map_err(|_| UserConnectionError::InvalidMessage)?;
-                    tcp_handler
-                        .send_message_chunked(rename_message)
-                        .await
-                        .map_err(UserConnectionError::IoError)?;
-                    *chat_name = Some(new_name.clone());
+                    success!("User '{}' renamed to '{}'", requested_username, new_name);
+                    // The join acknowledgement tells them the name they got
+                    lifecycle.joined(new_name.clone());
 
                     // Store session token for the new name
                     if let Some(token) = session_token {
-                        let mut sessions = self.user_sessions.write().await;
+                        let mut sessions = self.state.user_sessions.write().await;
                         sessions.insert(new_name, token);
                     }
                 }
             } else {
                 // Username is available - claim it
                 clients.insert(requested_username.clone());
-                *chat_name = Some(requested_username.clone());
+                lifecycle.joined(requested_username.clone());
 
                 // Store session token for this username
                 if let Some(token) = session_token {
                     drop(clients); // Release clients lock before acquiring sessions lock
-                    let mut sessions = self.user_sessions.write().await;
+                    let mut sessions = self.state.user_sessions.write().await;
                     sessions.insert(requested_username.clone(), token);
                 }
             }
-        }
+            extra_session
+        };
 
-        if let Some(chat_name) = &chat_name {
+        if let Some(chat_name) = lifecycle.name() {
             // Store the user's IP address
-            let mut ips = self.user_ips.write().await;
-            ips.insert(chat_name.clone(), self.addr.ip());
+            let mut ips = self.state.user_ips.write().await;
+            ips.insert(chat_name.to_string(), self.addr.ip());
             drop(ips);
+            self.state
+                .presence
+                .write()
+                .await
+                .joined(chat_name, self.addr, self.id);
+            let mut seen = self.state.seen.write().await;
+            let away_since = seen.last_connected(chat_name);
+            seen.connected(chat_name);
+            drop(seen);
+
+            // Remember the fingerprint so /ban <user> covers it too
+            let fingerprint_id = match fingerprint {
+                Some(fingerprint) => {
+                    self.state
+                        .user_fingerprints
+                        .write()
+                        .await
+                        .insert(chat_name.to_string(), fingerprint.clone());
+                    fingerprint.id()
+                }
+                None => "none",
+            };
+            self.state.audit.record_from(
+                self.id,
+                "JOIN",
+                &format!("{} {} fp={}", self.addr, chat_name, fingerprint_id),
+            );
+
+            if owner && chat_name == requested_username {
+                self.state
+                    .authenticated
+                    .write()
+                    .await
+                    .insert(chat_name.to_string());
+                info!(
+                    "{} logged in to their registered nickname ({})",
+                    chat_name,
+                    provider.unwrap_or_default()
+                );
+            }
+
+            // Everyone else already has them in the chat
+            if !extra_session {
+                // A reconnecting user may have kept their status
+                let status = self
+                    .state
+                    .user_statuses
+                    .read()
+                    .await
+                    .get(chat_name)
+                    .cloned();
+                self.state.broadcast_presence(
+                    &PresenceDelta::Joined {
+                        user: chat_name.to_string(),
+                        status,
+                    },
+                    self.id,
+                )?;
+                system!("{} has joined the chat", chat_name);
+            }
+            if owner && chat_name == requested_username {
+                self.send_welcome_back(tcp_handler, chat_name, away_since)
+                    .await?;
+            }
+            self.process_list_users(tcp_handler).await?;
+            self.send_maintenance_banner(tcp_handler).await?;
+            self.send_join_ack(tcp_handler, chat_name).await?;
+            if self.state.may_read(chat_name).await {
+                self.replay_history(tcp_handler, history::MAIN_CHAT).await?;
+            }
+            if owner
+                && chat_name == requested_username
+                && self
+                    .state
+                    .accounts
+                    .read()
+                    .await
+                    .has_one_time_password(chat_name)
+            {
+                self.send_notice(
+                    tcp_handler,
+                    "You logged in with a one-time password - set your own with /register <password>",
+                )
+                .await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Why `chat_name` can't send `message`, if their scopes don't allow it: bots are
+    /// held to their token, guests to CHAT_SERVER_GUEST_SCOPES
+    async fn scope_refusal(&self, message: &ChatMessage, chat_name: &str) -> Option<String> {
+        if let Some(scope) = Scope::to_send(message)
+            && !self.state.scopes(chat_name).await.contains(scope)
+        {
+            let who = match reserved::split(chat_name).0 {
+                reserved::Namespace::Bot => "This bot's token",
+                _ => "This server",
+            };
+            return Some(format!(
+                "{} doesn't allow that (needs the '{}' scope)",
+                who,
+                scope.name()
+            ));
+        }
+        // A bot's token can also limit it to some rooms
+        if message.msg_type == MessageTypes::JoinRoom
+            && let Some(grant) = self.state.bot_grant(chat_name).await
+        {
+            let content = message.content_as_string()?;
+            let room = rooms::normalize_room_name(content.split('|').next().unwrap_or_default())?;
+            return (!grant.allows_room(&room))
+                .then(|| format!("This bot's token doesn't allow joining #{}", room));
+        }
+        None
+    }
 
-            let join_message =
-                ChatMessage::try_new(MessageTypes::Join, Some(chat_name.clone().into_bytes()))
-                    .map_err(|_| UserConnectionError::InvalidMessage)?;
-            self.tx
-                .send((join_message, self.addr))
-                .map_err(UserConnectionError::BroadcastError)?;
-            logger::log_system(&format!("{} has joined the chat", chat_name));
+    /// Tell a user who logged in to their registered nickname what they missed, then
+    /// hand over the direct messages held for them. Nothing is sent if nothing happened.
+    async fn send_welcome_back<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        tcp_handler: &mut StreamWrapper<'_, S>,
+        name: &str,
+        away_since: Option<DateTime<Local>>,
+    ) -> Result<(), ChatError> {
+        let held = self.state.mailbox.write().await.take(name);
+        let (mentions, rooms) = match away_since {
+            Some(since) => self.state.history.read().await.activity_since(name, since),
+            None => (0, Vec::new()),
+        };
+        let digest = Digest {
+            away: away_since
+                .and_then(|since| (Local::now() - since).to_std().ok())
+                .unwrap_or_default(),
+            direct_messages: held.len(),
+            mentions,
+            rooms,
+        };
+        if digest.is_empty() {
+            return Ok(());
+        }
+        let digest_msg = ChatMessage::try_new(
+            MessageTypes::WelcomeBack,
+            Some(digest.encode().into_bytes()),
+        )
+        .map_err(|_| ChatError::InvalidMessage)?;
+        tcp_handler
+            .send_message_chunked(digest_msg)
+            .await
+            .map_err(ChatError::IoError)?;
+        for message in held {
+            let content = sender_class::tag(
+                reserved::class(&message.sender, &self.state.server_identity),
+                &format!("{}|{}|{}", message.sender, name, message.text),
+            );
+            let dm = ChatMessage::try_new(MessageTypes::DirectMessage, Some(content.into_bytes()))
+                .map_err(|_| ChatError::InvalidMessage)?;
+            tcp_handler
+                .send_message_chunked(dm)
+                .await
+                .map_err(ChatError::IoError)?;
         }
         Ok(())
     }
 
+    /// Tell a joining user about planned maintenance, if there is any
+    async fn send_maintenance_banner<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        tcp_handler: &mut StreamWrapper<'_, S>,
+    ) -> Result<(), ChatError> {
+        let Some(banner) = self.state.maintenance.read().await.banner(Local::now()) else {
+            return Ok(());
+        };
+        let content = sender_class::tag(
+            SenderClass::Server,
+            &format!("|{}|{}", self.state.server_identity, banner),
+        );
+        let announcement =
+            ChatMessage::try_new(MessageTypes::Announcement, Some(content.into_bytes()))
+                .map_err(|_| ChatError::InvalidMessage)?;
+        tcp_handler
+            .send_message_chunked(announcement)
+            .await
+            .map_err(ChatError::IoError)
+    }
+
+    /// Acknowledge the join: the nickname the client got, how fast it may send and how
+    /// long its messages may be, and which server it is on. Sent last while joining,
+    /// so it comes before anything relayed to the connection.
+    async fn send_join_ack<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        tcp_handler: &mut StreamWrapper<'_, S>,
+        chat_name: &str,
+    ) -> Result<(), ChatError> {
+        let ack = JoinAck {
+            nickname: chat_name.to_string(),
+            version: VERSION.to_string(),
+            room: None,
+            limits: RateLimits {
+                messages: RATE_LIMIT_MESSAGES,
+                window: RATE_LIMIT_WINDOW,
+                max_message: Some(MAX_MESSAGE_LENGTH),
+            },
+            server: self.state.info.clone(),
+        };
+        let ack_msg = ChatMessage::try_new(MessageTypes::JoinAck, Some(ack.encode().into_bytes()))
+            .map_err(|_| ChatError::InvalidMessage)?;
+        tcp_handler
+            .send_message_chunked(ack_msg)
+            .await
+            .map_err(ChatError::IoError)
+    }
+
+    /// Tell the client which server it is on (/server info)
+    async fn send_server_info<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        tcp_handler: &mut StreamWrapper<'_, S>,
+    ) -> Result<(), ChatError> {
+        let info = ChatMessage::try_new(
+            MessageTypes::ServerInfo,
+            Some(self.state.info.encode().into_bytes()),
+        )
+        .map_err(|_| ChatError::InvalidMessage)?;
+        tcp_handler
+            .send_message_chunked(info)
+            .await
+            .map_err(ChatError::IoError)
+    }
+
+    /// Carry a guest's presence, and their block list, over to the random name they get
+    /// when the owner of their nickname logs in
+    async fn move_guest(&self, old_name: &str, new_name: &str) {
+        let mut ips = self.state.user_ips.write().await;
+        if let Some(ip) = ips.remove(old_name) {
+            ips.insert(new_name.to_string(), ip);
+        }
+        drop(ips);
+        let mut fingerprints = self.state.user_fingerprints.write().await;
+        if let Some(fingerprint) = fingerprints.remove(old_name) {
+            fingerprints.insert(new_name.to_string(), fingerprint);
+        }
+        drop(fingerprints);
+        let mut statuses = self.state.user_statuses.write().await;
+        if let Some(status) = statuses.remove(old_name) {
+            statuses.insert(new_name.to_string(), status);
+        }
+        drop(statuses);
+        let mut sessions = self.state.user_sessions.write().await;
+        if let Some(token) = sessions.remove(old_name) {
+            sessions.insert(new_name.to_string(), token);
+        }
+        drop(sessions);
+        self.state
+            .rooms
+            .write()
+            .await
+            .rename_member(old_name, new_name);
+        self.state
+            .bandwidth
+            .write()
+            .await
+            .rename(old_name, new_name);
+        self.state.presence.write().await.rename(old_name, new_name);
+        self.state.seen.write().await.renamed(old_name, new_name);
+        self.state
+            .blocks
+            .write()
+            .await
+            .move_guest(old_name, new_name);
+    }
+
     async fn process_rename_request<S: AsyncRead + AsyncWrite + Unpin>(
         &self,
         new_name: Option<String>,
         tcp_handler: &mut StreamWrapper<'_, S>,
-        chat_name: &mut Option<String>,
-    ) -> Result<(), UserConnectionError> {
-        let new_name = new_name.ok_or(UserConnectionError::InvalidMessage)?;
+        lifecycle: &mut Lifecycle,
+    ) -> Result<(), ChatError> {
+        let new_name = new_name.ok_or(ChatError::InvalidMessage)?;
 
         // Validate new username length
         if new_name.is_empty() || new_name.len() > MAX_USERNAME_LENGTH {
-            logger::log_warning(&format!(
+            warn!(
                 "Invalid username length for rename from {}: {} chars",
                 self.addr,
                 new_name.len()
-            ));
-            let error_msg = ChatMessage::try_new(
-                MessageTypes::Error,
-                Some(b"Invalid username length (1-32 characters)".to_vec()),
-            )
-            .map_err(|_| UserConnectionError::InvalidMessage)?;
+            );
+            let error_msg = ChatError::InvalidUsername
+                .to_message("Invalid username length (1-32 characters)")?;
             tcp_handler
                 .send_message_chunked(error_msg)
                 .await
-                .map_err(UserConnectionError::IoError)?;
+                .map_err(ChatError::IoError)?;
             return Ok(());
         }
 
         // Validate username characters (alphanumeric, underscore, hyphen only)
-        if !new_name
-            .chars()
-            .all(|c| c.is_alphanumeric() || c == '_' || c == '-')
-        {
-            logger::log_warning(&format!(
+        if !reserved::is_well_formed(&new_name) {
+            warn!(
                 "Invalid username characters for rename from {}: {}",
                 self.addr, new_name
-            ));
-            let error_msg = ChatMessage::try_new(
-                MessageTypes::Error,
-                Some(
-                    b"Invalid characters (only alphanumeric, underscore, hyphen allowed)".to_vec(),
-                ),
-            )
-            .map_err(|_| UserConnectionError::InvalidMessage)?;
+            );
+            let error_msg = ChatError::InvalidUsername
+                .to_message("Invalid characters (only alphanumeric, underscore, hyphen allowed)")?;
             tcp_handler
                 .send_message_chunked(error_msg)
                 .await
-                .map_err(UserConnectionError::IoError)?;
+                .map_err(ChatError::IoError)?;
             return Ok(());
         }
 
-        // Check if user has joined first
-        let old_name = match chat_name {
-            Some(name) => name.clone(),
-            None => {
-                logger::log_warning(&format!(
-                    "User at {} tried to rename before joining",
-                    self.addr
-                ));
-                return Err(UserConnectionError::InvalidMessage);
-            }
-        };
+        let old_name = lifecycle.name().unwrap_or_default().to_string();
+
+        // A bot's token is for its own name only, so it can't shed its grant
+        if reserved::split(&old_name).0 == reserved::Namespace::Bot {
+            return self
+                .send_error(
+                    tcp_handler,
+                    ChatError::InvalidUsername,
+                    "Bots keep the name their token is for",
+                )
+                .await;
+        }
+
+        // Bots only get their names by joining with their token
+        if let Some(reason) = reserved::refusal(&new_name, &self.state.server_identity, false) {
+            warn!(
+                "Refused rename of '{}' to reserved nickname '{}'",
+                old_name, new_name
+            );
+            return self
+                .send_error(tcp_handler, ChatError::InvalidUsername, &reason)
+                .await;
+        }
+
+        if self.state.auth.is_registered(&new_name).await {
+            return self
+                .send_error(
+                    tcp_handler,
+                    ChatError::UsernameTaken,
+                    &format!(
+                        "'{}' is a registered nickname - log in with its password to use it",
+                        new_name
+                    ),
+                )
+                .await;
+        }
 
         // Try to claim the new name
-        let mut clients = self.connected_clients.write().await;
+        let mut clients = self.state.connected_clients.write().await;
 
         // Check if new name is already taken
         if clients.contains(&new_name) {
             drop(clients);
-            let error_msg = ChatMessage::try_new(
-                MessageTypes::Error,
-                Some(format!("Username '{}' is already taken", new_name).into_bytes()),
-            )
-            .map_err(|_| UserConnectionError::InvalidMessage)?;
+            let error_msg = ChatError::UsernameTaken
+                .to_message(&format!("Username '{}' is already taken", new_name))?;
             tcp_handler
                 .send_message_chunked(error_msg)
                 .await
-                .map_err(UserConnectionError::IoError)?;
+                .map_err(ChatError::IoError)?;
             return Ok(());
         }
 
+        // The other sessions still go by the old name
+        let others = self.state.sessions.read().await.extra(&old_name);
+        if others > 0 {
+            drop(clients);
+            return self
+                .send_error(
+                    tcp_handler,
+                    ChatError::Refused,
+                    &format!(
+                        "You're logged in as '{}' from {} other device(s) - log out there before changing nickname",
+                        old_name, others
+                    ),
+                )
+                .await;
+        }
+
         // Remove old name and add new name
         clients.remove(&old_name);
         clients.insert(new_name.clone());
         drop(clients);
 
         // Update user_ips mapping
-        let mut ips = self.user_ips.write().await;
+        let mut ips = self.state.user_ips.write().await;
         if let Some(ip) = ips.remove(&old_name) {
             ips.insert(new_name.clone(), ip);
         }
         drop(ips);
+        let mut fingerprints = self.state.user_fingerprints.write().await;
+        if let Some(fingerprint) = fingerprints.remove(&old_name) {
+            fingerprints.insert(new_name.clone(), fingerprint);
+        }
+        drop(fingerprints);
 
-        // Update the chat_name
-        *chat_name = Some(new_name.clone());
+        // Carry room memberships over to the new name
+        self.state
+            .rooms
+            .write()
+            .await
+            .rename_member(&old_name, &new_name);
+        self.state
+            .bandwidth
+            .write()
+            .await
+            .rename(&old_name, &new_name);
+        self.state
+            .presence
+            .write()
+            .await
+            .rename(&old_name, &new_name);
+        self.state.seen.write().await.renamed(&old_name, &new_name);
+        // A registered nickname keeps its block list when its owner goes by another name
+        let logged_in = self.state.authenticated.write().await.remove(&old_name);
+        let mut blocks = self.state.blocks.write().await;
+        if !logged_in
+            && blocks.rename(&old_name, &new_name)
+            && let Err(e) = blocks.save().await
+        {
+            error!("Failed to save block list: {}", e);
+        }
+        drop(blocks);
 
-        logger::log_success(&format!("User '{}' renamed to '{}'", old_name, new_name));
+        lifecycle.rename(new_name.clone());
+
+        success!("User '{}' renamed to '{}'", old_name, new_name);
 
         // Send UserRename message back to the client
         let rename_message = ChatMessage::try_new(
             MessageTypes::UserRename,
-            Some(new_name.clone().into_bytes()),
+            Some(presence::encode_rename(&old_name, &new_name).into_bytes()),
         )
-        .map_err(|_| UserConnectionError::InvalidMessage)?;
+        .map_err(|_| ChatError::InvalidMessage)?;
         tcp_handler
             .send_message_chunked(rename_message)
             .await
-            .map_err(UserConnectionError::IoError)?;
+            .map_err(ChatError::IoError)?;
 
         // Broadcast rename announcement to all clients
-        let announcement = format!("{} is now known as {}", old_name, new_name);
-        let broadcast_message =
-            ChatMessage::try_new(MessageTypes::ChatMessage, Some(announcement.into_bytes()))
-                .map_err(|_| UserConnectionError::InvalidMessage)?;
-        self.tx
-            .send((broadcast_message, self.addr))
-            .map_err(UserConnectionError::BroadcastError)?;
-
-        Ok(())
+        self.state.broadcast_rename(&old_name, &new_name, None)
     }
 
     async fn process_file_transfer<S: AsyncRead + AsyncWrite + Unpin>(
         &self,
         content: Option<&[u8]>,
         tcp_handler: &mut StreamWrapper<'_, S>,
-        chat_name: &Option<String>,
-    ) -> Result<(), UserConnectionError> {
-        let content = content.ok_or(UserConnectionError::InvalidMessage)?;
-
-        // Check if user has joined
-        let sender = match chat_name {
-            Some(name) => name.clone(),
-            None => {
-                logger::log_warning(&format!(
-                    "User at {} tried to send file before joining",
-                    self.addr
-                ));
-                return Err(UserConnectionError::InvalidMessage);
-            }
-        };
+        sender: &str,
+    ) -> Result<(), ChatError> {
+        let content = content.ok_or(ChatError::InvalidMessage)?;
 
         // Parse binary format: recipient_len(1)|recipient|filename_len(1)|filename|filedata
         if content.len() < 2 {
-            logger::log_warning(&format!("Invalid file transfer format from {}", self.addr));
-            return Err(UserConnectionError::InvalidMessage);
+            warn!("Invalid file transfer format from {}", self.addr);
+            return Err(ChatError::InvalidMessage);
         }
 
         let recipient_len = content[0] as usize;
         if content.len() < 1 + recipient_len + 1 {
-            logger::log_warning(&format!("Invalid file transfer format from {}", self.addr));
-            return Err(UserConnectionError::InvalidMessage);
+            warn!("Invalid file transfer format from {}", self.addr);
+            return Err(ChatError::InvalidMessage);
         }
 
         let recipient = std::str::from_utf8(&content[1..1 + recipient_len])
-            .map_err(|_| UserConnectionError::InvalidMessage)?;
+            .map_err(|_| ChatError::InvalidMessage)?;
 
         let filename_len = content[1 + recipient_len] as usize;
         let filename_start = 1 + recipient_len + 1;
         if content.len() < filename_start + filename_len {
-            logger::log_warning(&format!("Invalid file transfer format from {}", self.addr));
-            return Err(UserConnectionError::InvalidMessage);
+            warn!("Invalid file transfer format from {}", self.addr);
+            return Err(ChatError::InvalidMessage);
         }
 
         let filename = std::str::from_utf8(&content[filename_start..filename_start + filename_len])
-            .map_err(|_| UserConnectionError::InvalidMessage)?;
+            .map_err(|_| ChatError::InvalidMessage)?;
 
         let file_data = &content[filename_start + filename_len..];
 
         // Check if recipient exists
-        let clients = self.connected_clients.read().await;
+        let clients = self.state.connected_clients.read().await;
         if !clients.contains(recipient) {
             drop(clients);
             let error_msg = format!("User '{}' not found", recipient);
-            logger::log_warning(&format!(
-                "[FILE] {} -> {} (user not found)",
-                sender, recipient
-            ));
-            let error_message =
-                ChatMessage::try_new(MessageTypes::Error, Some(error_msg.into_bytes()))
-                    .map_err(|_| UserConnectionError::InvalidMessage)?;
+            warn!("[FILE] {} -> {} (user not found)", sender, recipient);
+            let error_message = ChatError::UserNotFound.to_message(&error_msg)?;
             tcp_handler
                 .send_message_chunked(error_message)
                 .await
-                .map_err(UserConnectionError::IoError)?;
+                .map_err(ChatError::IoError)?;
             return Ok(());
         }
         drop(clients);
 
-        logger::log_system(&format!(
+        if self.is_blocked_by(recipient, sender).await {
+            system!("[FILE] {} -> {} (blocked)", sender, recipient);
+            return self
+                .send_error(tcp_handler, ChatError::Refused, BLOCKED_ERROR)
+                .await;
+        }
+
+        system!(
             "[FILE] {} -> {} ('{}', {} bytes)",
             sender,
             recipient,
             filename,
             file_data.len()
-        ));
+        );
 
         // Build outgoing message with sender instead of recipient
         // Format: sender_len(1)|sender|filename_len(1)|filename|filedata
@@ -617,12 +1381,12 @@ impl<'a> MessageHandlers<'a> {
         final_content.extend_from_slice(&outgoing_content);
 
         let file_message = ChatMessage::try_new(MessageTypes::FileTransfer, Some(final_content))
-            .map_err(|_| UserConnectionError::InvalidMessage)?;
+            .map_err(|_| ChatError::InvalidMessage)?;
 
         // Broadcast to all clients (recipient will filter)
-        self.tx
-            .send((file_message, self.addr))
-            .map_err(UserConnectionError::BroadcastError)?;
+        self.state
+            .broadcast(file_message, self.id)
+            .map_err(|_| ChatError::BroadcastError)?;
 
         Ok(())
     }
@@ -631,55 +1395,34 @@ impl<'a> MessageHandlers<'a> {
         &self,
         content: Option<&[u8]>,
         tcp_handler: &mut StreamWrapper<'_, S>,
-        chat_name: &Option<String>,
-    ) -> Result<(), UserConnectionError> {
-        let content = content.ok_or(UserConnectionError::InvalidMessage)?;
-
-        // Check if user has joined
-        let sender = match chat_name {
-            Some(name) => name.clone(),
-            None => {
-                logger::log_warning(&format!(
-                    "User at {} tried to send file request before joining",
-                    self.addr
-                ));
-                return Err(UserConnectionError::InvalidMessage);
-            }
-        };
+        sender: &str,
+    ) -> Result<(), ChatError> {
+        let content = content.ok_or(ChatError::InvalidMessage)?;
 
         // Parse binary format: recipient_len(1)|recipient|filename_len(1)|filename|filesize(8 bytes)
         if content.len() < 2 {
-            logger::log_warning(&format!(
-                "Invalid file transfer request format from {}",
-                self.addr
-            ));
-            return Err(UserConnectionError::InvalidMessage);
+            warn!("Invalid file transfer request format from {}", self.addr);
+            return Err(ChatError::InvalidMessage);
         }
 
         let recipient_len = content[0] as usize;
         if content.len() < 1 + recipient_len + 1 {
-            logger::log_warning(&format!(
-                "Invalid file transfer request format from {}",
-                self.addr
-            ));
-            return Err(UserConnectionError::InvalidMessage);
+            warn!("Invalid file transfer request format from {}", self.addr);
+            return Err(ChatError::InvalidMessage);
         }
 
         let recipient = std::str::from_utf8(&content[1..1 + recipient_len])
-            .map_err(|_| UserConnectionError::InvalidMessage)?;
+            .map_err(|_| ChatError::InvalidMessage)?;
 
         let filename_len = content[1 + recipient_len] as usize;
         let filename_start = 1 + recipient_len + 1;
         if content.len() < filename_start + filename_len + 8 {
-            logger::log_warning(&format!(
-                "Invalid file transfer request format from {}",
-                self.addr
-            ));
-            return Err(UserConnectionError::InvalidMessage);
+            warn!("Invalid file transfer request format from {}", self.addr);
+            return Err(ChatError::InvalidMessage);
         }
 
         let filename = std::str::from_utf8(&content[filename_start..filename_start + filename_len])
-            .map_err(|_| UserConnectionError::InvalidMessage)?;
+            .map_err(|_| ChatError::InvalidMessage)?;
 
         let size_start = filename_start + filename_len;
         let file_size = u64::from_be_bytes([
@@ -694,29 +1437,37 @@ impl<'a> MessageHandlers<'a> {
         ]);
 
         // Check if recipient exists
-        let clients = self.connected_clients.read().await;
+        let clients = self.state.connected_clients.read().await;
         if !clients.contains(recipient) {
             drop(clients);
             let error_msg = format!("User '{}' not found", recipient);
-            logger::log_warning(&format!(
+            warn!(
                 "[FILE REQUEST] {} -> {} (user not found)",
                 sender, recipient
-            ));
-            let error_message =
-                ChatMessage::try_new(MessageTypes::Error, Some(error_msg.into_bytes()))
-                    .map_err(|_| UserConnectionError::InvalidMessage)?;
+            );
+            let error_message = ChatError::UserNotFound.to_message(&error_msg)?;
             tcp_handler
                 .send_message_chunked(error_message)
                 .await
-                .map_err(UserConnectionError::IoError)?;
+                .map_err(ChatError::IoError)?;
             return Ok(());
         }
         drop(clients);
 
-        logger::log_system(&format!(
+        if self.is_blocked_by(recipient, sender).await {
+            system!("[FILE REQUEST] {} -> {} (blocked)", sender, recipient);
+            return self
+                .send_error(tcp_handler, ChatError::Refused, BLOCKED_ERROR)
+                .await;
+        }
+
+        system!(
             "[FILE REQUEST] {} -> {} ('{}', {} bytes)",
-            sender, recipient, filename, file_size
-        ));
+            sender,
+            recipient,
+            filename,
+            file_size
+        );
 
         // Build outgoing message with sender info
         // Format: recipient_len(1)|recipient|sender_len(1)|sender|filename_len(1)|filename|filesize(8 bytes)
@@ -731,12 +1482,12 @@ impl<'a> MessageHandlers<'a> {
 
         let request_message =
             ChatMessage::try_new(MessageTypes::FileTransferRequest, Some(outgoing_content))
-                .map_err(|_| UserConnectionError::InvalidMessage)?;
+                .map_err(|_| ChatError::InvalidMessage)?;
 
         // Broadcast to all clients (recipient will filter)
-        self.tx
-            .send((request_message, self.addr))
-            .map_err(UserConnectionError::BroadcastError)?;
+        self.state
+            .broadcast(request_message, self.id)
+            .map_err(|_| ChatError::BroadcastError)?;
 
         Ok(())
     }
@@ -745,72 +1496,52 @@ impl<'a> MessageHandlers<'a> {
         &self,
         content: Option<&[u8]>,
         tcp_handler: &mut StreamWrapper<'_, S>,
-        chat_name: &Option<String>,
-    ) -> Result<(), UserConnectionError> {
-        let content = content.ok_or(UserConnectionError::InvalidMessage)?;
-
-        // Check if user has joined
-        let responder = match chat_name {
-            Some(name) => name.clone(),
-            None => {
-                logger::log_warning(&format!(
-                    "User at {} tried to send file response before joining",
-                    self.addr
-                ));
-                return Err(UserConnectionError::InvalidMessage);
-            }
-        };
+        responder: &str,
+    ) -> Result<(), ChatError> {
+        let content = content.ok_or(ChatError::InvalidMessage)?;
 
         // Parse binary format: sender_len(1)|sender|accepted(1)
         // sender here is the original file sender (who we're responding to)
         if content.len() < 3 {
-            logger::log_warning(&format!(
-                "Invalid file transfer response format from {}",
-                self.addr
-            ));
-            return Err(UserConnectionError::InvalidMessage);
+            warn!("Invalid file transfer response format from {}", self.addr);
+            return Err(ChatError::InvalidMessage);
         }
 
         let original_sender_len = content[0] as usize;
         if content.len() < 1 + original_sender_len + 1 {
-            logger::log_warning(&format!(
-                "Invalid file transfer response format from {}",
-                self.addr
-            ));
-            return Err(UserConnectionError::InvalidMessage);
+            warn!("Invalid file transfer response format from {}", self.addr);
+            return Err(ChatError::InvalidMessage);
         }
 
         let original_sender = std::str::from_utf8(&content[1..1 + original_sender_len])
-            .map_err(|_| UserConnectionError::InvalidMessage)?;
+            .map_err(|_| ChatError::InvalidMessage)?;
 
         let accepted = content[1 + original_sender_len] == 1;
 
         // Check if original sender exists
-        let clients = self.connected_clients.read().await;
+        let clients = self.state.connected_clients.read().await;
         if !clients.contains(original_sender) {
             drop(clients);
             let error_msg = format!("User '{}' not found", original_sender);
-            logger::log_warning(&format!(
+            warn!(
                 "[FILE RESPONSE] {} -> {} (user not found)",
                 responder, original_sender
-            ));
-            let error_message =
-                ChatMessage::try_new(MessageTypes::Error, Some(error_msg.into_bytes()))
-                    .map_err(|_| UserConnectionError::InvalidMessage)?;
+            );
+            let error_message = ChatError::UserNotFound.to_message(&error_msg)?;
             tcp_handler
                 .send_message_chunked(error_message)
                 .await
-                .map_err(UserConnectionError::IoError)?;
+                .map_err(ChatError::IoError)?;
             return Ok(());
         }
         drop(clients);
 
-        logger::log_system(&format!(
+        system!(
             "[FILE RESPONSE] {} {} file from {}",
             responder,
             if accepted { "accepted" } else { "rejected" },
             original_sender
-        ));
+        );
 
         // Build outgoing message
         // Format: recipient_len(1)|recipient|sender_len(1)|sender|accepted(1)
@@ -825,12 +1556,12 @@ impl<'a> MessageHandlers<'a> {
 
         let response_message =
             ChatMessage::try_new(MessageTypes::FileTransferResponse, Some(outgoing_content))
-                .map_err(|_| UserConnectionError::InvalidMessage)?;
+                .map_err(|_| ChatError::InvalidMessage)?;
 
         // Broadcast to all clients (original sender will filter)
-        self.tx
-            .send((response_message, self.addr))
-            .map_err(UserConnectionError::BroadcastError)?;
+        self.state
+            .broadcast(response_message, self.id)
+            .map_err(|_| ChatError::BroadcastError)?;
 
         Ok(())
     }
@@ -839,48 +1570,41 @@ impl<'a> MessageHandlers<'a> {
         &self,
         status: Option<String>,
         tcp_handler: &mut StreamWrapper<'_, S>,
-        chat_name: &Option<String>,
-    ) -> Result<(), UserConnectionError> {
-        // Check if user has joined first
-        let username = match chat_name {
-            Some(name) => name.clone(),
-            None => {
-                logger::log_warning(&format!(
-                    "User at {} tried to set status before joining",
-                    self.addr
-                ));
-                return Err(UserConnectionError::InvalidMessage);
-            }
-        };
-
+        username: &str,
+    ) -> Result<(), ChatError> {
         let status_text = status.unwrap_or_default();
 
         // Validate status length
         if status_text.len() > MAX_STATUS_LENGTH {
-            let error_msg = ChatMessage::try_new(
-                MessageTypes::Error,
-                Some(
-                    format!("Status too long (max {} characters)", MAX_STATUS_LENGTH).into_bytes(),
-                ),
-            )
-            .map_err(|_| UserConnectionError::InvalidMessage)?;
+            let error_msg = ChatError::Refused.to_message(&format!(
+                "Status too long (max {} characters)",
+                MAX_STATUS_LENGTH
+            ))?;
             tcp_handler
                 .send_message_chunked(error_msg)
                 .await
-                .map_err(UserConnectionError::IoError)?;
+                .map_err(ChatError::IoError)?;
             return Ok(());
         }
 
         // Update or remove status
-        let mut statuses = self.user_statuses.write().await;
+        let mut statuses = self.state.user_statuses.write().await;
         if status_text.is_empty() {
-            statuses.remove(&username);
-            logger::log_system(&format!("{} cleared their status", username));
+            statuses.remove(username);
+            system!("{} cleared their status", username);
         } else {
-            statuses.insert(username.clone(), status_text.clone());
-            logger::log_system(&format!("{} set status: {}", username, status_text));
+            statuses.insert(username.to_string(), status_text.clone());
+            system!("{} set status: {}", username, status_text);
         }
         drop(statuses);
+        // The server's, so the user's own client (and other sessions) hear of it too
+        self.state.broadcast_presence(
+            &PresenceDelta::Status {
+                user: username.to_string(),
+                status: Some(status_text.clone()).filter(|status| !status.is_empty()),
+            },
+            SERVER_ORIGIN,
+        )?;
 
         // Send confirmation back to client
         let confirm_msg = if status_text.is_empty() {
@@ -890,27 +1614,1402 @@ impl<'a> MessageHandlers<'a> {
         };
         let response =
             ChatMessage::try_new(MessageTypes::SetStatus, Some(confirm_msg.into_bytes()))
-                .map_err(|_| UserConnectionError::InvalidMessage)?;
+                .map_err(|_| ChatError::InvalidMessage)?;
         tcp_handler
             .send_message_chunked(response)
             .await
-            .map_err(UserConnectionError::IoError)?;
+            .map_err(ChatError::IoError)?;
+
+        Ok(())
+    }
+
+    /// Format: room, or room|public_key from clients that sign (which private rooms need)
+    async fn process_join_room<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        content: Option<String>,
+        tcp_handler: &mut StreamWrapper<'_, S>,
+        username: &str,
+    ) -> Result<(), ChatError> {
+        let content = content.ok_or(ChatError::InvalidMessage)?;
+        let (room, public_key) = match content.split_once('|') {
+            Some((room, key)) if room_keys::is_public_key(key) => {
+                (room.to_string(), Some(key.to_string()))
+            }
+            Some(_) => return Err(ChatError::InvalidMessage),
+            None => (content, None),
+        };
+
+        let Some(room) = rooms::normalize_room_name(&room) else {
+            return self
+                .send_error(
+                    tcp_handler,
+                    ChatError::Refused,
+                    "Invalid room name (1-32 characters: alphanumeric, underscore, hyphen)",
+                )
+                .await;
+        };
 
+        let (newly_joined, private, archived) = {
+            let mut rooms = self.state.rooms.write().await;
+            let private = rooms.is_private(&room);
+            if private && !rooms.is_member(&room, username) {
+                let refusal = if public_key.is_none() {
+                    Some(format!(
+                        "#{} is private and end-to-end encrypted - turn on message signing (CHAT_SIGN_MESSAGES=1) to join it",
+                        room
+                    ))
+                } else if !rooms.admit(&room, username) {
+                    Some(format!(
+                        "#{} is private - ask one of its moderators for an invitation",
+                        room
+                    ))
+                } else {
+                    None
+                };
+                if let Some(refusal) = refusal {
+                    drop(rooms);
+                    return self
+                        .send_error(tcp_handler, ChatError::Refused, &refusal)
+                        .await;
+                }
+            }
+            let newly_joined = rooms.join(&room, username);
+            if let Some(key) = public_key {
+                rooms.set_public_key(&room, username, key);
+            }
+            (newly_joined, private, rooms.is_archived(&room))
+        };
+
+        let join_message = ChatMessage::try_new(
+            MessageTypes::JoinRoom,
+            Some(format!("{}|{}", room, username).into_bytes()),
+        )
+        .map_err(|_| ChatError::InvalidMessage)?;
+
+        if newly_joined {
+            system!("{} joined #{}", username, room);
+            // Relayed to the room's members, and to the joiner's other sessions
+            self.state
+                .broadcast(join_message, self.id)
+                .map_err(|_| ChatError::BroadcastError)?;
+            if private {
+                // Its history was sealed with keys the joiner was never given
+                self.send_room_key_frame(
+                    tcp_handler,
+                    &RoomKeyFrame::Private {
+                        room: room.clone(),
+                        on: true,
+                    },
+                )
+                .await?;
+                self.state.rotate_room_key(&room).await?;
+            } else if self.state.may_read(username).await {
+                self.replay_history(tcp_handler, &room).await?;
+            }
+            if archived {
+                self.send_notice(
+                    tcp_handler,
+                    &format!(
+                        "#{} is archived - its history is here to read, but it's read-only",
+                        room
+                    ),
+                )
+                .await?;
+            }
+        } else {
+            // Already a member - just confirm to the requester
+            tcp_handler
+                .send_message_chunked(join_message)
+                .await
+                .map_err(ChatError::IoError)?;
+        }
+        Ok(())
+    }
+
+    async fn process_leave_room<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        room: Option<String>,
+        tcp_handler: &mut StreamWrapper<'_, S>,
+        username: &str,
+    ) -> Result<(), ChatError> {
+        let room = room.ok_or(ChatError::InvalidMessage)?;
+
+        let room = rooms::normalize_room_name(&room).unwrap_or(room);
+        if !self.state.rooms.write().await.leave(&room, username) {
+            return self
+                .send_error(
+                    tcp_handler,
+                    ChatError::Refused,
+                    &format!("You are not in #{}", room),
+                )
+                .await;
+        }
+
+        system!("{} left #{}", username, room);
+        let leave_message = ChatMessage::try_new(
+            MessageTypes::LeaveRoom,
+            Some(format!("{}|{}", room, username).into_bytes()),
+        )
+        .map_err(|_| ChatError::InvalidMessage)?;
+        self.state
+            .broadcast(leave_message, self.id)
+            .map_err(|_| ChatError::BroadcastError)?;
+        self.state.rotate_room_key(&room).await
+    }
+
+    async fn process_room_message<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        content: Option<String>,
+        tcp_handler: &mut StreamWrapper<'_, S>,
+        sender: &str,
+    ) -> Result<(), ChatError> {
+        let content = content.ok_or(ChatError::InvalidMessage)?;
+        let (room, message) = content.split_once('|').ok_or(ChatError::InvalidMessage)?;
+        let (message, signature) = split_signed(message);
+
+        // Validate message length
+        if message.is_empty() || message.len() > MAX_MESSAGE_LENGTH {
+            warn!(
+                "Invalid room message length from {}: {} chars",
+                self.addr,
+                message.len()
+            );
+            return self.refuse_length(tcp_handler).await;
+        }
+        // Room messages also go into the room's history, which is kept one per line
+        if message.chars().any(char::is_control) {
+            return self.refuse_control(tcp_handler).await;
+        }
+
+        let wait = {
+            let mut rooms = self.state.rooms.write().await;
+            if !rooms.is_member(room, sender) {
+                drop(rooms);
+                return self
+                    .send_error(
+                        tcp_handler,
+                        ChatError::Refused,
+                        &format!("You are not in #{}", room),
+                    )
+                    .await;
+            }
+            if rooms.is_archived(room) {
+                drop(rooms);
+                return self
+                    .send_error(
+                        tcp_handler,
+                        ChatError::Refused,
+                        &format!("#{} is archived - it's read-only", room),
+                    )
+                    .await;
+            }
+            // Later parts of a long message can't be told from plain text on their own
+            let part = parts::parse(message);
+            if let Some(max_length) = rooms.max_length(room)
+                && (part.is_some() || message.len() > max_length)
+            {
+                drop(rooms);
+                return self
+                    .send_error(
+                        tcp_handler,
+                        ChatError::Refused,
+                        &format!(
+                            "Messages to #{} are at most {} bytes, in one piece",
+                            room, max_length
+                        ),
+                    )
+                    .await;
+            }
+            let first_part =
+                part.map_or(Some(message), |part| (part.seq == 0).then_some(part.text));
+            if rooms.is_private(room)
+                && first_part.is_some_and(|text| !room_keys::is_encrypted(text))
+            {
+                drop(rooms);
+                return self
+                    .send_error(
+                        tcp_handler,
+                        ChatError::Refused,
+                        &format!("#{} is private - messages to it must be encrypted", room),
+                    )
+                    .await;
+            }
+            match rooms.muted_for(room) {
+                Some(remaining) => Err(("muted", remaining)),
+                None => rooms
+                    .check_slowmode(room, sender)
+                    .map_err(|remaining| ("slowmode", remaining)),
+            }
+        };
+        if let Err((reason, remaining)) = wait {
+            // Round up so clients never retry a moment too early
+            let remaining =
+                Duration::from_secs(remaining.as_secs() + u64::from(remaining.subsec_nanos() > 0));
+            let text = if reason == "muted" {
+                format!(
+                    "#{} has been muted by the server. You can talk again in {}.",
+                    room,
+                    drain::format_remaining(remaining)
+                )
+            } else {
+                format!(
+                    "Slow mode is on in #{}. You can talk again in {}.",
+                    room,
+                    drain::format_remaining(remaining)
+                )
+            };
+            return self
+                .send_rate_limited(tcp_handler, reason, remaining, &text)
+                .await;
+        }
+
+        chat!(room = %room, "{}", self.state.content_logging.line(sender, message));
+        // Format: room|sender|message; only the room's members are sent it
+        let content = format!("{}|{}|{}{}", room, sender, message, signature);
+        let class = reserved::class(sender, &self.state.server_identity);
+        let room_message = ChatMessage::try_new(
+            MessageTypes::RoomMessage,
+            Some(sender_class::tag(class, &content).into_bytes()),
+        )
+        .map_err(|_| ChatError::InvalidMessage)?;
+        let whole = self
+            .state
+            .record_history(room, sender, message, room_message, self.id)
+            .await?;
+
+        // Translated once the whole message is in, and never when it's encrypted
+        if let Some(translator) = &self.state.translator
+            && let Some(whole) = whole.filter(|whole| !room_keys::is_encrypted(whole))
+            && let Some(lang) = self.state.rooms.read().await.translation(room)
+        {
+            translator.spawn(self.state.clone(), room, sender, lang, &whole);
+        }
+        Ok(())
+    }
+
+    async fn is_blocked_by(&self, recipient: &str, sender: &str) -> bool {
+        let holder = self.state.block_holder(recipient).await;
+        self.state
+            .blocks
+            .read()
+            .await
+            .is_blocked(recipient, sender, holder)
+    }
+
+    /// Manage the sender's block list. Format: block|user, unblock|user or list|
+    /// Only the blocker is told about changes - the blocked user is not notified.
+    async fn process_block_user<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        content: Option<String>,
+        tcp_handler: &mut StreamWrapper<'_, S>,
+        username: &str,
+    ) -> Result<(), ChatError> {
+        let content = content.ok_or(ChatError::InvalidMessage)?;
+        let (action, target) = content.split_once('|').ok_or(ChatError::InvalidMessage)?;
+
+        if action != "list" && (target.is_empty() || target.len() > MAX_USERNAME_LENGTH) {
+            return Err(ChatError::InvalidMessage);
+        }
+
+        let holder = self.state.block_holder(username).await;
+        let mut blocks = self.state.blocks.write().await;
+        let (changed, notice) = match action {
+            "block" if target == username => {
+                drop(blocks);
+                return self
+                    .send_error(tcp_handler, ChatError::Refused, "You can't block yourself")
+                    .await;
+            }
+            "block" => (
+                blocks.block(username, target, holder),
+                format!(
+                    "Blocked {}. Their direct messages and file transfers will not reach you.",
+                    target
+                ),
+            ),
+            "unblock" => {
+                if !blocks.unblock(username, target, holder) {
+                    drop(blocks);
+                    return self
+                        .send_error(
+                            tcp_handler,
+                            ChatError::Refused,
+                            &format!("You have not blocked {}", target),
+                        )
+                        .await;
+                }
+                (true, format!("Unblocked {}", target))
+            }
+            "list" => {
+                let blocked = blocks.blocked_by(username, holder);
+                let notice = if blocked.is_empty() {
+                    "You have not blocked anyone".to_string()
+                } else {
+                    format!("Blocked users: {}", blocked.join(", "))
+                };
+                (false, notice)
+            }
+            other => {
+                return Err(ChatError::ProtocolViolation(format!(
+                    "unknown block action '{}'",
+                    other
+                )));
+            }
+        };
+        if changed {
+            let verb = if action == "block" {
+                "blocked"
+            } else {
+                "unblocked"
+            };
+            info!("{} {} {}", username, verb, target);
+            if holder == Holder::Account
+                && let Err(e) = blocks.save().await
+            {
+                error!("Failed to save block list: {}", e);
+            }
+        }
+        drop(blocks);
+
+        self.send_notice(tcp_handler, &notice).await
+    }
+
+    /// Room moderation commands. Format: room|command|args
+    async fn process_room_command<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        content: Option<String>,
+        tcp_handler: &mut StreamWrapper<'_, S>,
+        username: &str,
+    ) -> Result<(), ChatError> {
+        let content = content.ok_or(ChatError::InvalidMessage)?;
+        let mut parts = content.splitn(3, '|');
+        let (Some(room), Some(command), Some(args)) = (parts.next(), parts.next(), parts.next())
+        else {
+            return Err(ChatError::InvalidMessage);
+        };
+
+        let (moderator, archived) = {
+            let rooms = self.state.rooms.read().await;
+            (rooms.is_moderator(room, username), rooms.is_archived(room))
+        };
+        if !moderator {
+            return self
+                .send_error(
+                    tcp_handler,
+                    ChatError::Refused,
+                    &format!("Only moderators of #{} can do that", room),
+                )
+                .await;
+        }
+        // An archived room is frozen until it's unarchived
+        if archived && command != "unarchive" {
+            return self
+                .send_error(
+                    tcp_handler,
+                    ChatError::Refused,
+                    &format!("#{} is archived - /room unarchive {} first", room, room),
+                )
+                .await;
+        }
+
+        let notice = match command {
+            "announce" => {
+                return self
+                    .announce_to_room(room, args, tcp_handler, username)
+                    .await;
+            }
+            "slowmode" => {
+                let Ok(seconds) = args.parse::<u64>() else {
+                    return self
+                        .send_error(
+                            tcp_handler,
+                            ChatError::Refused,
+                            "Usage: /room slowmode <seconds>",
+                        )
+                        .await;
+                };
+                if seconds > MAX_SLOWMODE_SECS {
+                    return self
+                        .send_error(
+                            tcp_handler,
+                            ChatError::Refused,
+                            &format!("Slow mode can be at most {} seconds", MAX_SLOWMODE_SECS),
+                        )
+                        .await;
+                }
+                let interval = (seconds > 0).then(|| Duration::from_secs(seconds));
+                self.state.rooms.write().await.set_slowmode(room, interval);
+                match interval {
+                    Some(interval) => format!(
+                        "Slow mode enabled: one message every {}",
+                        drain::format_remaining(interval)
+                    ),
+                    None => "Slow mode disabled".to_string(),
+                }
+            }
+            "retention" => {
+                let Some(retention) = Retention::parse(args) else {
+                    return self
+                        .send_error(
+                            tcp_handler,
+                            ChatError::Refused,
+                            &format!(
+                                "Usage: /room retention <messages (max {})|<days>d (max {})|off>",
+                                history::MAX_RETAINED_MESSAGES,
+                                history::MAX_RETENTION_DAYS
+                            ),
+                        )
+                        .await;
+                };
+                self.state
+                    .history
+                    .write()
+                    .await
+                    .set_retention(room, retention);
+                self.state.log_history(Record::Retention {
+                    at: Local::now(),
+                    room: room.to_string(),
+                    retention,
+                });
+                format!("History retention set to: {}", retention)
+            }
+            "translate" => {
+                if self.state.translator.is_none() {
+                    return self
+                        .send_error(
+                            tcp_handler,
+                            ChatError::Refused,
+                            "This server has no translation hook",
+                        )
+                        .await;
+                }
+                let lang = match args {
+                    "off" => None,
+                    lang if translation::is_language(lang) => Some(lang.to_string()),
+                    _ => {
+                        return self
+                            .send_error(
+                                tcp_handler,
+                                ChatError::Refused,
+                                "Usage: /room translate <language code, e.g. en or pt-BR|off>",
+                            )
+                            .await;
+                    }
+                };
+                let mut rooms = self.state.rooms.write().await;
+                if lang.is_some() && rooms.is_private(room) {
+                    drop(rooms);
+                    return self
+                        .send_error(
+                            tcp_handler,
+                            ChatError::Refused,
+                            &format!(
+                                "#{} is private - the server can't read its messages to translate them",
+                                room
+                            ),
+                        )
+                        .await;
+                }
+                let notice = match &lang {
+                    Some(lang) => format!("Messages will be machine translated into '{}'", lang),
+                    None => "Machine translation turned off".to_string(),
+                };
+                rooms.set_translation(room, lang);
+                notice
+            }
+            "maxlength" => {
+                let max_length = match args {
+                    "off" | "0" => None,
+                    args => match args.parse::<usize>() {
+                        Ok(bytes) if bytes <= MAX_MESSAGE_LENGTH => Some(bytes),
+                        _ => {
+                            return self
+                                .send_error(
+                                    tcp_handler,
+                                    ChatError::Refused,
+                                    &format!(
+                                        "Usage: /room maxlength <bytes (max {})|off>",
+                                        MAX_MESSAGE_LENGTH
+                                    ),
+                                )
+                                .await;
+                        }
+                    },
+                };
+                self.state
+                    .rooms
+                    .write()
+                    .await
+                    .set_max_length(room, max_length);
+                match max_length {
+                    Some(bytes) => format!(
+                        "Messages limited to {} bytes, and no longer sent in parts",
+                        bytes
+                    ),
+                    None => "Message length limit removed".to_string(),
+                }
+            }
+            "topic" => {
+                let topic = args.trim();
+                if topic.len() > rooms::MAX_TOPIC_LENGTH || topic.chars().any(char::is_control) {
+                    return self
+                        .send_error(
+                            tcp_handler,
+                            ChatError::Refused,
+                            &format!(
+                                "Topics are at most {} characters on one line",
+                                rooms::MAX_TOPIC_LENGTH
+                            ),
+                        )
+                        .await;
+                }
+                let topic = (!topic.is_empty()).then(|| topic.to_string());
+                let notice = match &topic {
+                    Some(topic) => format!("Topic set to: {}", topic),
+                    None => "Topic cleared".to_string(),
+                };
+                self.state.rooms.write().await.set_topic(room, topic);
+                notice
+            }
+            "private" => {
+                let private = match args {
+                    "on" => true,
+                    "off" => false,
+                    _ => {
+                        return self
+                            .send_error(
+                                tcp_handler,
+                                ChatError::Refused,
+                                "Usage: /room private <on|off>",
+                            )
+                            .await;
+                    }
+                };
+                if self.state.rooms.read().await.is_private(room) == private {
+                    return self
+                        .send_notice(
+                            tcp_handler,
+                            &format!(
+                                "#{} is already {}",
+                                room,
+                                if private { "private" } else { "public" }
+                            ),
+                        )
+                        .await;
+                }
+                let changed = self.state.rooms.write().await.set_private(room, private);
+                if let Err(keyless) = changed {
+                    return self
+                        .send_error(
+                            tcp_handler,
+                            ChatError::Refused,
+                            &format!(
+                                "#{} can't be private while {} can't take part in encryption (they need message signing on)",
+                                room,
+                                keyless.join(", ")
+                            ),
+                        )
+                        .await;
+                }
+                self.state.audit.record_from(
+                    self.id,
+                    "ROOM_PRIVATE",
+                    &format!("#{} {} by {}", room, args, username),
+                );
+                self.state.broadcast_room_key(
+                    &RoomKeyFrame::Private {
+                        room: room.to_string(),
+                        on: private,
+                    },
+                    self.id,
+                )?;
+                if private {
+                    self.state.rotate_room_key(room).await?;
+                    "Room is now private: invite-only, with end-to-end encrypted messages"
+                        .to_string()
+                } else {
+                    "Room is public again: anyone may join and messages are no longer encrypted"
+                        .to_string()
+                }
+            }
+            "archive" | "unarchive" => {
+                let archive = command == "archive";
+                if !archive && !archived {
+                    return self
+                        .send_notice(tcp_handler, &format!("#{} isn't archived", room))
+                        .await;
+                }
+                let member = {
+                    let mut rooms = self.state.rooms.write().await;
+                    let member = rooms.is_member(room, username);
+                    rooms.set_archived(room, archive);
+                    member
+                };
+                self.state.audit.record_from(
+                    self.id,
+                    "ROOM_ARCHIVE",
+                    &format!("#{} {} by {}", room, command, username),
+                );
+                let notice = if archive {
+                    "Room archived: it's read-only, and its history stays available"
+                } else {
+                    "Room unarchived: members may talk again"
+                };
+                // A moderator who left the archived room isn't sent the announcement
+                if !member {
+                    system!("#{} {} ({})", room, notice, username);
+                    return self
+                        .send_notice(tcp_handler, &format!("#{}: {}", room, notice))
+                        .await;
+                }
+                notice.to_string()
+            }
+            "invite" => {
+                let invitee = args.trim();
+                if invitee.is_empty() || invitee.len() > MAX_USERNAME_LENGTH {
+                    return self
+                        .send_error(
+                            tcp_handler,
+                            ChatError::Refused,
+                            "Usage: /room invite <user>",
+                        )
+                        .await;
+                }
+                let mut rooms = self.state.rooms.write().await;
+                if !rooms.is_private(room) {
+                    drop(rooms);
+                    return self
+                        .send_error(
+                            tcp_handler,
+                            ChatError::Refused,
+                            &format!("#{} is public - anyone can join it", room),
+                        )
+                        .await;
+                }
+                if rooms.is_member(room, invitee) {
+                    drop(rooms);
+                    return self
+                        .send_notice(tcp_handler, &format!("{} is already in #{}", invitee, room))
+                        .await;
+                }
+                rooms.invite(room, invitee);
+                drop(rooms);
+                // Told like a direct message from the moderator, unless they're blocked
+                if self.state.connected_clients.read().await.contains(invitee)
+                    && !self.is_blocked_by(invitee, username).await
+                {
+                    // The server wrote it, so it's a notice whoever it's from
+                    let invitation = sender_class::tag(
+                        SenderClass::Notice,
+                        &format!(
+                            "{}|{}|invited you to the private room #{} (/join {} to accept)",
+                            username, invitee, room, room
+                        ),
+                    );
+                    let dm = ChatMessage::try_new(
+                        MessageTypes::DirectMessage,
+                        Some(invitation.into_bytes()),
+                    )
+                    .map_err(|_| ChatError::InvalidMessage)?;
+                    self.state
+                        .broadcast(dm, self.id)
+                        .map_err(|_| ChatError::BroadcastError)?;
+                }
+                format!("{} was invited", invitee)
+            }
+            other => {
+                return self
+                    .send_error(
+                        tcp_handler,
+                        ChatError::Refused,
+                        &format!("Unknown room command: {}", other),
+                    )
+                    .await;
+            }
+        };
+
+        system!("#{} {} ({})", room, notice, username);
+        let content = format!("{}|{}|{}", room, username, notice);
+        let announcement = ChatMessage::try_new(
+            MessageTypes::Announcement,
+            Some(sender_class::tag(SenderClass::Notice, &content).into_bytes()),
+        )
+        .map_err(|_| ChatError::InvalidMessage)?;
+        self.state
+            .broadcast(announcement, self.id)
+            .map_err(|_| ChatError::BroadcastError)?;
         Ok(())
     }
 
+    /// A moderator's /announce: sent to the room as a RoomAnnouncement and recorded in
+    /// the audit log
+    async fn announce_to_room<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        room: &str,
+        text: &str,
+        tcp_handler: &mut StreamWrapper<'_, S>,
+        username: &str,
+    ) -> Result<(), ChatError> {
+        let text = text.trim();
+        if text.is_empty() || text.len() > MAX_MESSAGE_LENGTH || text.chars().any(char::is_control)
+        {
+            return self
+                .send_error(
+                    tcp_handler,
+                    ChatError::Refused,
+                    &format!(
+                        "Announcements are one line of at most {} bytes",
+                        MAX_MESSAGE_LENGTH
+                    ),
+                )
+                .await;
+        }
+        self.state.audit.record_from(
+            self.id,
+            "ROOM_ANNOUNCE",
+            &format!("#{} {}: {}", room, username, text),
+        );
+        announcement!("#{} {}: {}", room, username, text);
+        let content = format!("{}|{}|{}", room, username, text);
+        let class = reserved::class(username, &self.state.server_identity);
+        let announcement = ChatMessage::try_new(
+            MessageTypes::RoomAnnouncement,
+            Some(sender_class::tag(class, &content).into_bytes()),
+        )
+        .map_err(|_| ChatError::InvalidMessage)?;
+        self.state
+            .broadcast(announcement, self.id)
+            .map_err(|_| ChatError::BroadcastError)?;
+        Ok(())
+    }
+
+    /// A sealed room key from a private room's keeper for one of its members. Only
+    /// keys go this way; the server sends the other RoomKey frames.
+    async fn process_room_key(
+        &self,
+        content: Option<String>,
+        sender: &str,
+    ) -> Result<(), ChatError> {
+        let frame = content
+            .and_then(|content| RoomKeyFrame::decode(&content))
+            .ok_or(ChatError::InvalidMessage)?;
+        let RoomKeyFrame::Key {
+            room, to, sealed, ..
+        } = frame
+        else {
+            return Err(ChatError::ProtocolViolation(
+                "only sealed keys may be sent to the server".to_string(),
+            ));
+        };
+        {
+            let rooms = self.state.rooms.read().await;
+            if !rooms.is_private(&room)
+                || !rooms.is_member(&room, sender)
+                || !rooms.is_member(&room, &to)
+            {
+                // Membership moved on since the rotation was asked for
+                return Ok(());
+            }
+        }
+        self.state.broadcast_room_key(
+            &RoomKeyFrame::Key {
+                room,
+                to,
+                from: sender.to_string(),
+                sealed,
+            },
+            self.id,
+        )
+    }
+
+    async fn send_room_key_frame<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        tcp_handler: &mut StreamWrapper<'_, S>,
+        frame: &RoomKeyFrame,
+    ) -> Result<(), ChatError> {
+        let message =
+            ChatMessage::try_new(MessageTypes::RoomKey, Some(frame.encode().into_bytes()))
+                .map_err(|_| ChatError::InvalidMessage)?;
+        tcp_handler
+            .send_message_chunked(message)
+            .await
+            .map_err(ChatError::IoError)
+    }
+
+    async fn process_room_info_request<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        room: Option<String>,
+        tcp_handler: &mut StreamWrapper<'_, S>,
+    ) -> Result<(), ChatError> {
+        // Empty = every room
+        let query = room.unwrap_or_default();
+        let rooms = {
+            let registry = self.state.rooms.read().await;
+            if query.is_empty() {
+                registry.list()
+            } else {
+                let room = rooms::normalize_room_name(&query).unwrap_or(query.clone());
+                match registry.info(&room) {
+                    Some(info) => vec![(room, info)],
+                    None => {
+                        drop(registry);
+                        return self
+                            .send_error(
+                                tcp_handler,
+                                ChatError::Refused,
+                                &format!("No such room: #{}", room),
+                            )
+                            .await;
+                    }
+                }
+            }
+        };
+
+        let history = self.state.history.read().await;
+        let summaries: Vec<RoomSummary> = rooms
+            .into_iter()
+            .map(|(name, info)| RoomSummary {
+                retention: history.retention(&name).to_string(),
+                name,
+                members: info.members,
+                created_at: info.created_at.timestamp(),
+                slowmode_secs: info.slowmode.map_or(0, |interval| interval.as_secs()),
+                max_length: info.max_length.unwrap_or(0),
+                translate_to: info.translate_to.unwrap_or_default(),
+                private: info.private,
+                archived: info.archived,
+                moderators: info.moderators,
+                topic: info.topic.unwrap_or_default(),
+            })
+            .collect();
+        drop(history);
+
+        let response = ChatMessage::try_new(
+            MessageTypes::RoomInfoResponse,
+            Some(shared_rooms::encode_response(&query, &summaries).into_bytes()),
+        )
+        .map_err(|_| ChatError::InvalidMessage)?;
+        tcp_handler
+            .send_message_chunked(response)
+            .await
+            .map_err(ChatError::IoError)
+    }
+
+    /// Tell the client when a user was last connected and last spoke
+    async fn process_seen_request<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        content: Option<String>,
+        tcp_handler: &mut StreamWrapper<'_, S>,
+    ) -> Result<(), ChatError> {
+        let user = content.ok_or(ChatError::InvalidMessage)?;
+        let user = user.trim();
+        if user.is_empty() {
+            return Err(ChatError::InvalidMessage);
+        }
+        let online = self.state.connected_clients.read().await.contains(user);
+        let text = self.state.seen.read().await.describe(user, online);
+        self.send_notice(tcp_handler, &text).await
+    }
+
+    /// Tell a client that hasn't joined yet whether a nickname is free, so it can
+    /// pick another before joining
+    async fn process_name_check<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        content: Option<String>,
+        tcp_handler: &mut StreamWrapper<'_, S>,
+    ) -> Result<(), ChatError> {
+        let name = content.ok_or(ChatError::InvalidMessage)?;
+        let status = if name.is_empty()
+            || name.len() > MAX_USERNAME_LENGTH
+            || !reserved::is_well_formed(&name)
+        {
+            NameStatus::Invalid
+        } else if let Some(reason) = reserved::refusal(&name, &self.state.server_identity, false) {
+            NameStatus::Reserved(reason)
+        } else if self.state.connected_clients.read().await.contains(&name) {
+            NameStatus::InUse
+        } else if self.state.auth.is_registered(&name).await {
+            NameStatus::Registered
+        } else {
+            NameStatus::Available
+        };
+        let reply =
+            ChatMessage::try_new(MessageTypes::NameCheck, Some(status.encode().into_bytes()))
+                .map_err(|_| ChatError::InvalidMessage)?;
+        tcp_handler
+            .send_message_chunked(reply)
+            .await
+            .map_err(ChatError::IoError)
+    }
+
+    /// Send a room's stored history for /export-room, if the server's export
+    /// policy lets this user have it
+    async fn process_history_request<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        content: Option<String>,
+        tcp_handler: &mut StreamWrapper<'_, S>,
+        username: &str,
+    ) -> Result<(), ChatError> {
+        let (room, requested) = content
+            .as_deref()
+            .and_then(shared_history::decode_request)
+            .ok_or(ChatError::InvalidMessage)?;
+        let Some(room) = rooms::normalize_room_name(&room) else {
+            return self
+                .send_error(tcp_handler, ChatError::Refused, "Invalid room name")
+                .await;
+        };
+
+        let refusal = match self.state.room_export {
+            ExportPolicy::Off => {
+                Some("Room history can't be exported from this server".to_string())
+            }
+            ExportPolicy::Members if !self.state.rooms.read().await.is_member(&room, username) => {
+                Some(format!("You must be in #{} to export its history", room))
+            }
+            _ => None,
+        };
+        if let Some(text) = refusal {
+            return self
+                .send_error(tcp_handler, ChatError::Refused, &text)
+                .await;
+        }
+
+        let limit = match requested {
+            0 => self.state.room_export_limit,
+            requested => requested.min(self.state.room_export_limit),
+        };
+        let lines: Vec<HistoryLine> = self
+            .state
+            .history
+            .read()
+            .await
+            .recent(&room, limit)
+            .into_iter()
+            .map(|entry| HistoryLine {
+                sent_at: entry.at.timestamp(),
+                sender: entry.sender,
+                message: entry.message,
+            })
+            .collect();
+        info!(
+            "{} exported {} message(s) from #{}",
+            username,
+            lines.len(),
+            room
+        );
+        self.state.audit.record_from(
+            self.id,
+            "EXPORT",
+            &format!("{} {} #{} {}", self.addr, username, room, lines.len()),
+        );
+
+        let response = ChatMessage::try_new(
+            MessageTypes::HistoryResponse,
+            Some(shared_history::encode_response(&room, &lines).into_bytes()),
+        )
+        .map_err(|_| ChatError::InvalidMessage)?;
+        tcp_handler
+            .send_message_chunked(response)
+            .await
+            .map_err(ChatError::IoError)
+    }
+
+    /// Send a user who just joined a room (or the chat, for MAIN_CHAT) its most
+    /// recent messages
+    async fn replay_history<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        tcp_handler: &mut StreamWrapper<'_, S>,
+        room: &str,
+    ) -> Result<(), ChatError> {
+        let entries = self
+            .state
+            .history
+            .read()
+            .await
+            .recent(room, history::REPLAY_MESSAGES);
+        if entries.is_empty() {
+            return Ok(());
+        }
+
+        let today = Local::now().date_naive();
+        let mut notice = if room == history::MAIN_CHAT {
+            "Recent messages in the main chat:".to_string()
+        } else {
+            format!("Recent messages in #{}:", room)
+        };
+        for entry in entries {
+            let time = if entry.at.date_naive() == today {
+                entry.at.format("%H:%M")
+            } else {
+                entry.at.format("%d %b %H:%M")
+            };
+            notice.push_str(&format!(
+                "\n  [{}] {}: {}",
+                time, entry.sender, entry.message
+            ));
+        }
+        self.send_notice(tcp_handler, &notice).await
+    }
+
+    /// Tell the client about an error: `text` is shown to the user, `error` is sent
+    /// as its code
+    async fn send_error<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        tcp_handler: &mut StreamWrapper<'_, S>,
+        error: ChatError,
+        text: &str,
+    ) -> Result<(), ChatError> {
+        let error_msg = error.to_message(text)?;
+        tcp_handler
+            .send_message_chunked(error_msg)
+            .await
+            .map_err(ChatError::IoError)
+    }
+
+    /// Turn down a message that's empty or over MAX_MESSAGE_LENGTH - a mistake, not a
+    /// protocol violation
+    async fn refuse_length<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        tcp_handler: &mut StreamWrapper<'_, S>,
+    ) -> Result<(), ChatError> {
+        self.send_error(
+            tcp_handler,
+            ChatError::Refused,
+            &format!("Messages are 1 to {} bytes long", MAX_MESSAGE_LENGTH),
+        )
+        .await
+    }
+
+    /// Turn down a message with control characters in it: a line break in one could
+    /// pass the rest off as a line of its own (a forged system message, say)
+    async fn refuse_control<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        tcp_handler: &mut StreamWrapper<'_, S>,
+    ) -> Result<(), ChatError> {
+        self.send_error(
+            tcp_handler,
+            ChatError::Refused,
+            "Messages are one line - control characters aren't allowed",
+        )
+        .await
+    }
+
+    async fn send_notice<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        tcp_handler: &mut StreamWrapper<'_, S>,
+        notice: &str,
+    ) -> Result<(), ChatError> {
+        let notice = sender_class::tag(SenderClass::Notice, notice);
+        let notice_msg = ChatMessage::try_new(MessageTypes::Notice, Some(notice.into_bytes()))
+            .map_err(|_| ChatError::InvalidMessage)?;
+        tcp_handler
+            .send_message_chunked(notice_msg)
+            .await
+            .map_err(ChatError::IoError)
+    }
+
+    /// Tell the client a request was refused by a limit.
+    /// Format: reason|retry_after_secs|message
+    async fn send_rate_limited<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        tcp_handler: &mut StreamWrapper<'_, S>,
+        reason: &str,
+        retry_after: Duration,
+        message: &str,
+    ) -> Result<(), ChatError> {
+        let content = format!("{}|{}|{}", reason, retry_after.as_secs(), message);
+        let limited_msg =
+            ChatMessage::try_new(MessageTypes::RateLimited, Some(content.into_bytes()))
+                .map_err(|_| ChatError::InvalidMessage)?;
+        tcp_handler
+            .send_message_chunked(limited_msg)
+            .await
+            .map_err(ChatError::IoError)
+    }
+
+    /// Record the client's fingerprint, refusing the connection if it is banned.
+    /// Only accepted once, before joining.
+    async fn process_fingerprint<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        content: Option<String>,
+        tcp_handler: &mut StreamWrapper<'_, S>,
+        fingerprint: &mut Option<Fingerprint>,
+    ) -> Result<(), ChatError> {
+        if fingerprint.is_some() {
+            return Err(ChatError::ProtocolViolation(
+                "fingerprint sent more than once".to_string(),
+            ));
+        }
+        let received = content
+            .as_deref()
+            .and_then(Fingerprint::decode)
+            .ok_or(ChatError::InvalidMessage)?;
+
+        if self
+            .state
+            .bans
+            .read()
+            .await
+            .is_fingerprint_banned(received.id(), Instant::now())
+        {
+            warn!(
+                "Rejected connection from {}: fingerprint {} is banned",
+                self.addr,
+                received.id()
+            );
+            self.state.audit.record_from(
+                self.id,
+                "REJECT",
+                &format!("{} fp={} (banned)", self.addr, received.id()),
+            );
+            self.send_error(
+                tcp_handler,
+                ChatError::FingerprintBanned,
+                "You are banned from this server",
+            )
+            .await?;
+            return Err(ChatError::FingerprintBanned);
+        }
+
+        self.state.audit.record_from(
+            self.id,
+            "FINGERPRINT",
+            &format!(
+                "{} fp={} caps={} client=\"{}\"",
+                self.addr, received.lineage, received.capabilities, received.client_version
+            ),
+        );
+        *fingerprint = Some(received);
+        Ok(())
+    }
+
+    /// A guest registering the nickname they are using. They stay connected and
+    /// are logged in at once; rooms, status and blocks already belong to the name.
+    async fn process_register<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        password: Option<String>,
+        tcp_handler: &mut StreamWrapper<'_, S>,
+        name: &str,
+    ) -> Result<(), ChatError> {
+        let password = password.ok_or(ChatError::InvalidMessage)?;
+        let logged_in = self.state.authenticated.read().await.contains(name);
+        // Logged in to a local account: /register sets a new password
+        if logged_in && self.state.accounts.read().await.is_registered(name) {
+            return self
+                .process_password_change(&password, tcp_handler, name)
+                .await;
+        }
+        if !self.state.open_registration {
+            return self
+                .send_error(
+                    tcp_handler,
+                    ChatError::Refused,
+                    "Registration is disabled on this server - ask an administrator",
+                )
+                .await;
+        }
+        if logged_in {
+            return self
+                .send_error(
+                    tcp_handler,
+                    ChatError::Refused,
+                    &format!("You are already logged in to '{}'", name),
+                )
+                .await;
+        }
+        // Nicknames owned by an external identity provider can't be claimed locally
+        if self.state.auth.is_registered(name).await {
+            return self
+                .send_error(
+                    tcp_handler,
+                    ChatError::Refused,
+                    &format!("'{}' is already registered", name),
+                )
+                .await;
+        }
+
+        // Hashed before the accounts are locked: argon2 is slow on purpose
+        let hashed = match HashedPassword::new(&password).await {
+            Ok(hashed) => hashed,
+            Err(e) => {
+                return self
+                    .send_error(
+                        tcp_handler,
+                        ChatError::Refused,
+                        &format!("Cannot register '{}': {}", name, e),
+                    )
+                    .await;
+            }
+        };
+        // Register and log in under one lock, so no join can slip in between
+        let mut accounts = self.state.accounts.write().await;
+        let result = accounts.register(name, hashed);
+        if result.is_ok() {
+            self.state
+                .authenticated
+                .write()
+                .await
+                .insert(name.to_string());
+        }
+        drop(accounts);
+        // Written once the accounts are unlocked, so logins don't wait for the disk
+        let result = match result {
+            Ok(save) => {
+                // Still registered in memory, so it works until the server restarts
+                if let Err(e) = save.write().await {
+                    error!("Failed to save accounts: {}", e);
+                }
+                Ok(())
+            }
+            Err(e) => Err(e),
+        };
+
+        match result {
+            Ok(()) => {
+                success!("{} registered their nickname '{}'", self.addr, name);
+                // What they blocked as a guest is now saved with the account
+                let mut blocks = self.state.blocks.write().await;
+                if blocks.adopt(name)
+                    && let Err(e) = blocks.save().await
+                {
+                    error!("Failed to save block list: {}", e);
+                }
+                drop(blocks);
+                self.state
+                    .audit
+                    .record("REGISTER", &format!("{} {}", self.addr, name));
+                let registered = ChatMessage::try_new(MessageTypes::Register, None)
+                    .map_err(|_| ChatError::InvalidMessage)?;
+                tcp_handler
+                    .send_message_chunked(registered)
+                    .await
+                    .map_err(ChatError::IoError)
+            }
+            Err(e) => {
+                self.send_error(
+                    tcp_handler,
+                    ChatError::Refused,
+                    &format!("Cannot register '{}': {}", name, e),
+                )
+                .await
+            }
+        }
+    }
+
+    /// Replace the password of the local account `name` is logged in to
+    async fn process_password_change<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        password: &str,
+        tcp_handler: &mut StreamWrapper<'_, S>,
+        name: &str,
+    ) -> Result<(), ChatError> {
+        let result = match HashedPassword::new(password).await {
+            Ok(password) => self
+                .state
+                .accounts
+                .write()
+                .await
+                .change_password(name, password),
+            Err(e) => Err(e),
+        };
+        let result = match result {
+            Ok(save) => save.write().await.map_err(AccountError::Io),
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(()) => {
+                success!("{} changed the password of '{}'", self.addr, name);
+                self.state
+                    .audit
+                    .record("PASSWORD", &format!("{} {}", self.addr, name));
+                let changed = ChatMessage::try_new(MessageTypes::Register, None)
+                    .map_err(|_| ChatError::InvalidMessage)?;
+                tcp_handler
+                    .send_message_chunked(changed)
+                    .await
+                    .map_err(ChatError::IoError)
+            }
+            Err(e) => {
+                self.send_error(
+                    tcp_handler,
+                    ChatError::Refused,
+                    &format!("Cannot change the password of '{}': {}", name, e),
+                )
+                .await
+            }
+        }
+    }
+
+    /// Answer a Join with a proof-of-work challenge; the client re-sends the Join
+    /// once it has solved it
+    async fn send_challenge<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        tcp_handler: &mut StreamWrapper<'_, S>,
+        challenge: &mut ChallengeState,
+        difficulty: u8,
+    ) -> Result<(), ChatError> {
+        let issued = challenge.issue(difficulty.min(MAX_DIFFICULTY), Instant::now());
+        info!(
+            "Sent proof-of-work challenge to {} (difficulty {})",
+            self.addr, issued.difficulty
+        );
+        let challenge_msg =
+            ChatMessage::try_new(MessageTypes::Challenge, Some(issued.encode().into_bytes()))
+                .map_err(|_| ChatError::InvalidMessage)?;
+        tcp_handler
+            .send_message_chunked(challenge_msg)
+            .await
+            .map_err(ChatError::IoError)
+    }
+
+    async fn process_challenge_answer<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        content: Option<String>,
+        tcp_handler: &mut StreamWrapper<'_, S>,
+        challenge: &mut ChallengeState,
+    ) -> Result<(), ChatError> {
+        let answer = content
+            .and_then(|content| content.parse::<u64>().ok())
+            .ok_or(ChatError::InvalidMessage)?;
+        let reason = match challenge.answer(answer, Instant::now()) {
+            Ok(()) => {
+                info!("{} solved its proof-of-work challenge", self.addr);
+                return Ok(());
+            }
+            Err(ChallengeError::NotSent) => {
+                return Err(ChatError::ProtocolViolation(
+                    "challenge answer without a challenge".to_string(),
+                ));
+            }
+            Err(ChallengeError::Expired) => "challenge expired",
+            Err(ChallengeError::WrongAnswer) => "wrong answer",
+        };
+        warn!(
+            "{} failed its proof-of-work challenge: {}",
+            self.addr, reason
+        );
+        self.send_error(
+            tcp_handler,
+            ChatError::ChallengeFailed,
+            &format!("Proof-of-work challenge failed: {}", reason),
+        )
+        .await?;
+        Err(ChatError::ChallengeFailed)
+    }
+
     async fn process_version_check<S: AsyncRead + AsyncWrite + Unpin>(
         &self,
         client_version: Option<String>,
         tcp_handler: &mut StreamWrapper<'_, S>,
-    ) -> Result<(), UserConnectionError> {
-        let client_version = client_version.ok_or(UserConnectionError::InvalidMessage)?;
+    ) -> Result<(), ChatError> {
+        let client_version = client_version.ok_or(ChatError::InvalidMessage)?;
 
         if !version::versions_compatible(&client_version, VERSION) {
-            logger::log_warning(&format!(
+            warn!(
                 "Version mismatch from {}: client v{} != server v{}",
                 self.addr, client_version, VERSION
-            ));
+            );
 
             // Send version mismatch error with details
             let mismatch_content = format!(
@@ -923,19 +3022,24 @@ impl<'a> MessageHandlers<'a> {
                 MessageTypes::VersionMismatch,
                 Some(mismatch_content.into_bytes()),
             )
-            .map_err(|_| UserConnectionError::InvalidMessage)?;
+            .map_err(|_| ChatError::InvalidMessage)?;
             tcp_handler
                 .send_message_chunked(mismatch_msg)
                 .await
-                .map_err(UserConnectionError::IoError)?;
+                .map_err(ChatError::IoError)?;
 
-            return Err(UserConnectionError::VersionMismatch);
+            return Err(ChatError::VersionMismatch);
         }
 
-        logger::log_info(&format!(
+        info!(
             "Version check passed for {}: v{}",
             self.addr, client_version
-        ));
+        );
+        self.state
+            .presence
+            .write()
+            .await
+            .version_checked(self.addr, &client_version);
         Ok(())
     }
 }
@@ -0,0 +1,158 @@
+//! Where a connection is in its lifecycle. Each state accepts only the frames that
+//! make sense in it; the nickname exists from Joined on.
+
+use shared::error::ChatError;
+use shared::message::MessageTypes;
+
+#[derive(Debug, Default, PartialEq)]
+pub enum Lifecycle {
+    /// Waiting for the client's version check
+    #[default]
+    Connected,
+    /// Version check passed: fingerprint, challenge answer, nickname checks and Join
+    /// allowed
+    HelloReceived,
+    /// Proof-of-work solved (or not required), claiming a nickname
+    Authenticated,
+    /// Nickname claimed, welcome not yet sent
+    Joined(String),
+    /// In the chat: every client message except the handshake
+    Active(String),
+    /// Connection is closing and being cleaned up
+    Draining(Option<String>),
+}
+
+impl Lifecycle {
+    pub fn label(&self) -> &'static str {
+        match self {
+            Lifecycle::Connected => "Connected",
+            Lifecycle::HelloReceived => "HelloReceived",
+            Lifecycle::Authenticated => "Authenticated",
+            Lifecycle::Joined(_) => "Joined",
+            Lifecycle::Active(_) => "Active",
+            Lifecycle::Draining(_) => "Draining",
+        }
+    }
+
+    /// The connection's nickname, once it has one
+    pub fn name(&self) -> Option<&str> {
+        match self {
+            Lifecycle::Joined(name) | Lifecycle::Active(name) => Some(name),
+            Lifecycle::Draining(name) => name.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// Whether a frame of this type may be processed in the current state
+    pub fn accepts(&self, msg_type: &MessageTypes) -> bool {
+        let handshake = matches!(
+            msg_type,
+            MessageTypes::VersionCheck
+                | MessageTypes::Fingerprint
+                | MessageTypes::Challenge
+                | MessageTypes::NameCheck
+                | MessageTypes::Join
+        );
+        match self {
+            // Quitting and answering pings are fine until the connection closes
+            _ if matches!(msg_type, MessageTypes::Leave | MessageTypes::Pong) => {
+                !matches!(self, Lifecycle::Draining(_))
+            }
+            Lifecycle::Connected => matches!(msg_type, MessageTypes::VersionCheck),
+            Lifecycle::HelloReceived => handshake && *msg_type != MessageTypes::VersionCheck,
+            // A Join refused for its nickname may be retried
+            Lifecycle::Authenticated => *msg_type == MessageTypes::Join,
+            Lifecycle::Active(_) => !handshake,
+            Lifecycle::Joined(_) | Lifecycle::Draining(_) => false,
+        }
+    }
+
+    /// Reject a frame the current state doesn't accept
+    pub fn check(&self, msg_type: &MessageTypes) -> Result<(), ChatError> {
+        if self.accepts(msg_type) {
+            Ok(())
+        } else {
+            Err(ChatError::OutOfState {
+                state: self.label(),
+                message: *msg_type,
+            })
+        }
+    }
+
+    pub fn hello_received(&mut self) {
+        *self = Lifecycle::HelloReceived;
+    }
+
+    pub fn authenticated(&mut self) {
+        *self = Lifecycle::Authenticated;
+    }
+
+    pub fn joined(&mut self, name: String) {
+        *self = Lifecycle::Joined(name);
+    }
+
+    /// The welcome has been sent: the connection is in the chat
+    pub fn activate(&mut self) {
+        if let Lifecycle::Joined(name) = self {
+            *self = Lifecycle::Active(std::mem::take(name));
+        }
+    }
+
+    /// Change the nickname of a joined connection
+    pub fn rename(&mut self, new_name: String) {
+        match self {
+            Lifecycle::Joined(name) | Lifecycle::Active(name) => *name = new_name,
+            _ => {}
+        }
+    }
+
+    /// Stop accepting frames, keeping the nickname for cleanup
+    pub fn drain(&mut self) {
+        *self = Lifecycle::Draining(self.name().map(str::to_string));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lifecycle_accepts_frames_for_its_state() {
+        let mut lifecycle = Lifecycle::default();
+        assert!(lifecycle.check(&MessageTypes::VersionCheck).is_ok());
+        assert!(matches!(
+            lifecycle.check(&MessageTypes::Join),
+            Err(ChatError::OutOfState {
+                state: "Connected",
+                message: MessageTypes::Join
+            })
+        ));
+        assert!(!lifecycle.accepts(&MessageTypes::ChatMessage));
+
+        lifecycle.hello_received();
+        assert!(lifecycle.accepts(&MessageTypes::Fingerprint));
+        assert!(lifecycle.accepts(&MessageTypes::Join));
+        assert!(lifecycle.accepts(&MessageTypes::NameCheck));
+        assert!(!lifecycle.accepts(&MessageTypes::VersionCheck));
+        assert!(!lifecycle.accepts(&MessageTypes::ChatMessage));
+        assert_eq!(lifecycle.name(), None);
+
+        lifecycle.authenticated();
+        lifecycle.joined("alice".to_string());
+        assert!(!lifecycle.accepts(&MessageTypes::ChatMessage));
+        lifecycle.activate();
+        assert_eq!(lifecycle, Lifecycle::Active("alice".to_string()));
+        assert!(lifecycle.accepts(&MessageTypes::ChatMessage));
+        // Server-only types get past the state check and are refused as violations
+        assert!(lifecycle.accepts(&MessageTypes::Ping));
+        assert!(!lifecycle.accepts(&MessageTypes::Join));
+        assert!(!lifecycle.accepts(&MessageTypes::Fingerprint));
+        assert!(!lifecycle.accepts(&MessageTypes::NameCheck));
+
+        lifecycle.rename("bob".to_string());
+        assert_eq!(lifecycle.name(), Some("bob"));
+        lifecycle.drain();
+        assert_eq!(lifecycle.name(), Some("bob"));
+        assert!(!lifecycle.accepts(&MessageTypes::Leave));
+    }
+}
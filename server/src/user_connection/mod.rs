@@ -1,37 +1,61 @@
-mod error;
+mod challenge;
 mod handlers;
+mod lifecycle;
 mod rate_limiting;
 
-pub use error::UserConnectionError;
+use challenge::ChallengeState;
 use handlers::MessageHandlers;
+pub use handlers::{MAX_MESSAGE_LENGTH, MAX_USERNAME_LENGTH};
+use lifecycle::Lifecycle;
 use rate_limiting::{RATE_LIMIT_MESSAGES, RATE_LIMIT_WINDOW, RateLimiter};
 
 use crate::ServerCommand;
-use shared::logger;
+use crate::accounts::ReclaimPolicy;
+use crate::bans;
+use crate::fanout::Subscription;
+use crate::history;
+use crate::reserved;
+use crate::schedule;
+use crate::scopes::Scope;
+use crate::state::{ConnectionId, ServerState};
+use crate::telemetry::system;
+use shared::disconnect::{Disconnect, DisconnectReason};
+use shared::error::ChatError;
+use shared::fingerprint::Fingerprint;
 use shared::message::{ChatMessage, MessageTypes};
-use shared::network::{TcpMessageHandler, TcpMessageHandlerError};
-use std::collections::{HashMap, HashSet};
-use std::net::{IpAddr, SocketAddr};
+use shared::network::{CHUNK_SIZE, TcpMessageHandler};
+use shared::presence::{self, PresenceDelta};
+use shared::rejection::Rejection;
+use shared::room_keys::RoomKeyFrame;
+use shared::sender_class;
+use shared::trace::Tracer;
+use std::net::SocketAddr;
 use std::pin::Pin;
-use std::sync::Arc;
 use std::task::{Context, Poll};
 use std::time::{Duration, Instant};
-use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, BufStream, ReadBuf};
 use tokio::net::TcpStream;
-use tokio::sync::{RwLock, broadcast};
+use tokio::sync::broadcast;
 use tokio_rustls::server::TlsStream;
+use tracing::{Instrument, Span, error, field, info, info_span, warn};
 
 /// How often to send ping messages to clients
 const PING_INTERVAL: Duration = Duration::from_secs(30);
 /// How long to wait for a pong response before considering the client dead
 const PONG_TIMEOUT: Duration = Duration::from_secs(60);
-
-pub enum ConnectionStream {
-    Plain(TcpStream),
-    Tls(Box<TlsStream<TcpStream>>),
+/// Longest a client may take to finish sending a frame it has started
+const FRAME_TIMEOUT: Duration = Duration::from_secs(30);
+/// Longest a Disconnect frame may take to send before the connection is closed anyway
+const DISCONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// A client's socket, optionally wrapped in TLS. `T` is the transport: a tokio
+/// `TcpStream`, or an io_uring stream when the server runs with that backend.
+pub enum ConnectionStream<T = TcpStream> {
+    Plain(T),
+    Tls(Box<TlsStream<T>>),
 }
 
-impl AsyncRead for ConnectionStream {
+impl<T: AsyncRead + AsyncWrite + Unpin> AsyncRead for ConnectionStream<T> {
     fn poll_read(
         self: Pin<&mut Self>,
         cx: &mut Context<'_>,
@@ -44,7 +68,7 @@ impl AsyncRead for ConnectionStream {
     }
 }
 
-impl AsyncWrite for ConnectionStream {
+impl<T: AsyncRead + AsyncWrite + Unpin> AsyncWrite for ConnectionStream<T> {
     fn poll_write(
         self: Pin<&mut Self>,
         cx: &mut Context<'_>,
@@ -71,88 +95,146 @@ impl AsyncWrite for ConnectionStream {
     }
 }
 
-pub struct UserConnection {
-    socket: ConnectionStream,
+pub struct UserConnection<T = TcpStream> {
+    /// Buffered so the main loop can wait for a frame without taking any of it, and
+    /// so each frame sent is written in one go
+    socket: BufStream<ConnectionStream<T>>,
     addr: SocketAddr,
-    tx: broadcast::Sender<(ChatMessage, SocketAddr)>,
-    server_commands: broadcast::Sender<ServerCommand>,
-    connected_clients: Arc<RwLock<HashSet<String>>>,
-    user_ips: Arc<RwLock<HashMap<String, IpAddr>>>,
-    user_statuses: Arc<RwLock<HashMap<String, String>>>,
-    user_sessions: Arc<RwLock<HashMap<String, String>>>,
-    chat_name: Option<String>,
+    /// Origin of this connection's broadcasts
+    id: ConnectionId,
+    state: ServerState,
+    /// Handshake progress, and the nickname once joined
+    lifecycle: Lifecycle,
+    /// Sent by the client before joining (None = fingerprinting off or an older client)
+    fingerprint: Option<Fingerprint>,
+    /// Proof-of-work challenge progress (only used when the server requires one)
+    challenge: ChallengeState,
+    /// Join waiting for a slot while the server is full
+    queued_join: Option<ChatMessage>,
+    /// Last waiting room position sent to the client
+    queue_position: usize,
     rate_limiter: RateLimiter,
     /// True if user explicitly quit (vs connection drop which may be a reconnect)
     clear_status_on_disconnect: bool,
     /// True if session was taken over by a reconnecting client - don't clean up username
     session_taken_over: bool,
+    /// Protocol tracer labelled with this connection's address
+    tracer: Option<Tracer>,
+    /// Nickname the client's TLS certificate is mapped to (mutual TLS)
+    certificate_user: Option<String>,
+    /// The client asked to get its own messages back once they're relayed
+    self_echo: bool,
+    /// Tracing span the connection runs in, parent of one span per message
+    span: Span,
 }
 
-impl TcpMessageHandler for UserConnection {
-    type Stream = ConnectionStream;
+impl<T: AsyncRead + AsyncWrite + Unpin> TcpMessageHandler for UserConnection<T> {
+    type Stream = BufStream<ConnectionStream<T>>;
     fn get_stream(&mut self) -> &mut Self::Stream {
         &mut self.socket
     }
+
+    fn tracer(&self) -> Option<&Tracer> {
+        self.tracer.as_ref()
+    }
 }
 
-impl UserConnection {
+impl<T: AsyncRead + AsyncWrite + Unpin> UserConnection<T> {
+    /// `span` is the connection's `connection` span, with its `id`, `addr` and (empty)
+    /// `user` fields
     pub fn new(
-        socket: TcpStream,
+        socket: T,
         addr: SocketAddr,
-        tx: broadcast::Sender<(ChatMessage, SocketAddr)>,
-        server_commands: broadcast::Sender<ServerCommand>,
-        connected_clients: Arc<RwLock<HashSet<String>>>,
-        user_ips: Arc<RwLock<HashMap<String, IpAddr>>>,
-        user_statuses: Arc<RwLock<HashMap<String, String>>>,
-        user_sessions: Arc<RwLock<HashMap<String, String>>>,
+        id: ConnectionId,
+        span: Span,
+        state: ServerState,
     ) -> Self {
-        UserConnection {
-            socket: ConnectionStream::Plain(socket),
-            addr,
-            tx,
-            server_commands,
-            connected_clients,
-            user_ips,
-            user_statuses,
-            user_sessions,
-            chat_name: None,
-            rate_limiter: RateLimiter::new(RATE_LIMIT_MESSAGES, RATE_LIMIT_WINDOW),
-            clear_status_on_disconnect: false,
-            session_taken_over: false,
-        }
+        Self::with_stream(ConnectionStream::Plain(socket), addr, id, span, state)
     }
 
     pub fn new_tls(
-        socket: TlsStream<TcpStream>,
+        socket: TlsStream<T>,
+        addr: SocketAddr,
+        id: ConnectionId,
+        span: Span,
+        state: ServerState,
+    ) -> Self {
+        // The certificate was verified during the handshake; see who it logs in as
+        let certificate_user = match (&state.client_certs, socket.get_ref().1.peer_certificates()) {
+            (Some(map), Some([cert, ..])) => {
+                let username = map.username_for(cert);
+                match &username {
+                    Some(username) => {
+                        info!("Client certificate from {} logs in as '{}'", addr, username)
+                    }
+                    None => warn!(
+                        "Client certificate from {} isn't mapped to a nickname",
+                        addr
+                    ),
+                }
+                username
+            }
+            _ => None,
+        };
+        let mut connection = Self::with_stream(
+            ConnectionStream::Tls(Box::new(socket)),
+            addr,
+            id,
+            span,
+            state,
+        );
+        connection.certificate_user = certificate_user;
+        connection
+    }
+
+    fn with_stream(
+        socket: ConnectionStream<T>,
         addr: SocketAddr,
-        tx: broadcast::Sender<(ChatMessage, SocketAddr)>,
-        server_commands: broadcast::Sender<ServerCommand>,
-        connected_clients: Arc<RwLock<HashSet<String>>>,
-        user_ips: Arc<RwLock<HashMap<String, IpAddr>>>,
-        user_statuses: Arc<RwLock<HashMap<String, String>>>,
-        user_sessions: Arc<RwLock<HashMap<String, String>>>,
+        id: ConnectionId,
+        span: Span,
+        state: ServerState,
     ) -> Self {
+        let tracer = state.tracer.as_ref().map(|tracer| tracer.labelled(addr));
+        if let Some(tracer) = &tracer {
+            tracer.event("connection opened");
+        }
         UserConnection {
-            socket: ConnectionStream::Tls(Box::new(socket)),
+            socket: BufStream::with_capacity(CHUNK_SIZE, state.write_buffer, socket),
             addr,
-            tx,
-            server_commands,
-            connected_clients,
-            user_ips,
-            user_statuses,
-            user_sessions,
-            chat_name: None,
+            id,
+            state,
+            lifecycle: Lifecycle::default(),
+            fingerprint: None,
+            challenge: ChallengeState::default(),
+            queued_join: None,
+            queue_position: 0,
             rate_limiter: RateLimiter::new(RATE_LIMIT_MESSAGES, RATE_LIMIT_WINDOW),
             clear_status_on_disconnect: false,
             session_taken_over: false,
+            tracer,
+            certificate_user: None,
+            self_echo: false,
+            span,
         }
     }
 
-    pub async fn handle(&mut self) -> Result<(), UserConnectionError> {
-        logger::log_info(&format!("New client connected: {}", self.addr));
+    pub async fn handle(&mut self) -> Result<(), ChatError> {
+        let span = self.span.clone();
+        self.run().instrument(span).await
+    }
 
-        let mut rx = self.tx.subscribe();
-        let mut cmd_rx = self.server_commands.subscribe();
+    async fn run(&mut self) -> Result<(), ChatError> {
+        info!("New client connected: {}", self.addr);
+
+        let mut rx = self.state.subscribe();
+        let mut cmd_rx = self.state.server_commands.subscribe();
+        // Number of the broadcast after the last one received, so room history can
+        // fill any gap a lag leaves
+        let mut next_broadcast = rx.position();
+        let mut waiting_room_rx = match &self.state.waiting_room {
+            Some(waiting_room) => Some(waiting_room.read().await.subscribe()),
+            None => None,
+        };
 
         // Heartbeat tracking
         let mut last_activity = Instant::now();
@@ -163,57 +245,139 @@ impl UserConnection {
 
         loop {
             tokio::select! {
-                // Branch 1: Receive from client
-                result = self.read_message_chunked() => {
+                // Branch 1: Receive from client. Only waiting for the first bytes of a
+                // frame is raced against the other branches: giving up on a frame half
+                // read would lose its start and garble everything after it.
+                waiting = async { self.socket.fill_buf().await.map(|buf| !buf.is_empty()) } => {
+                    // Covers the message from its first byte to its fan-out
+                    let span = info_span!("message", kind = field::Empty, bytes = field::Empty);
+                    let result = match waiting {
+                        // The rest of the frame isn't raced, so it has a deadline of its
+                        // own: a client stalling partway through would otherwise hold up
+                        // pings, commands and broadcasts for good
+                        Ok(true) => match tokio::time::timeout(
+                            FRAME_TIMEOUT,
+                            self.read_message_chunked().instrument(span.clone()),
+                        ).await {
+                            Ok(result) => result,
+                            Err(_) => {
+                                warn!("Client {} stalled partway through a frame", self.addr);
+                                let text = format!(
+                                    "Disconnected after {}s without the rest of a frame",
+                                    FRAME_TIMEOUT.as_secs()
+                                );
+                                self.send_disconnect(Disconnect::new(DisconnectReason::IdleTimeout, &text)).await;
+                                break;
+                            }
+                        },
+                        Ok(false) => Err(ChatError::Disconnect),
+                        Err(e) => Err(ChatError::from(e)),
+                    };
                     match result {
                         Ok(msg) => {
                             // Update last activity on any message received
                             last_activity = Instant::now();
+                            span.record("kind", field::debug(&msg.msg_type));
+                            span.record("bytes", msg.get_content().map_or(0, <[u8]>::len));
 
-                            // Handle Pong silently (just updates last_activity above)
+                            // Handle Pong silently (just updates last_activity above,
+                            // and when the user was last seen)
                             if msg.msg_type == MessageTypes::Pong {
+                                if let Some(name) = self.lifecycle.name() {
+                                    self.state.seen.write().await.connected(name);
+                                }
                                 continue;
                             }
 
-                            match self.process_message(msg).await {
-                                Ok(()) => {}
-                                Err(UserConnectionError::ExplicitQuit) => {
-                                    // User explicitly quit - clear status on disconnect
-                                    self.clear_status_on_disconnect = true;
-                                    break;
-                                }
-                                Err(UserConnectionError::VersionMismatch) => {
-                                    // Version mismatch - disconnect client (error already sent)
-                                    logger::log_warning(&format!("Client {} disconnected due to version mismatch", self.addr));
+                            // Opting in or out of getting our own messages back
+                            if msg.msg_type == MessageTypes::SelfEcho && self.lifecycle.accepts(&msg.msg_type) {
+                                self.self_echo = msg.content_as_string().as_deref() == Some("1");
+                                continue;
+                            }
+
+                            // Joining a full chat: queue up instead
+                            if msg.msg_type == MessageTypes::Join && self.should_wait(&msg).await {
+                                if !self.enter_waiting_room(msg).await {
                                     break;
                                 }
-                                Err(e) => {
-                                    logger::log_error(&format!("Error handling message from {}: {:?}", self.addr, e));
-                                }
+                                continue;
+                            }
+
+                            let msg_type = msg.msg_type;
+                            let result = self.process_message(msg).instrument(span).await;
+                            if matches!(msg_type, MessageTypes::Join | MessageTypes::RenameRequest)
+                                && let Some(name) = self.lifecycle.name()
+                            {
+                                self.span.record("user", name);
+                            }
+                            if !self.handle_result(msg_type, result).await {
+                                break;
                             }
                         }
-                        Err(TcpMessageHandlerError::IoError(e)) => {
-                            logger::log_error(&format!("IO error reading from {}: {:?}", self.addr, e));
+                        Err(ChatError::Disconnect) => {
+                            warn!("Client {} disconnected", self.addr);
                             break;
                         }
-                        Err(TcpMessageHandlerError::Disconnect) => {
-                            logger::log_warning(&format!("Client {} disconnected", self.addr));
+                        Err(e) => {
+                            error!("IO error reading from {}: {:?}", self.addr, e);
+                            // Oversized frames are the only framing error a client can cause
+                            if matches!(e, ChatError::OversizedFrame(_)) {
+                                self.record_violation(&e.to_string()).await;
+                                self.send_disconnect(Disconnect::new(DisconnectReason::ProtocolViolation, &e.to_string())).await;
+                            }
                             break;
                         }
                     };
                 }
                 // Branch 2: Broadcast to other clients
                 result = rx.recv() => {
+                    if result.is_ok() || self.lifecycle.name().is_none() {
+                        next_broadcast = rx.position();
+                    }
                     match result {
-                        Ok((msg, _src_addr)) => {
-                            if let Err(e) = self.send_message_chunked(msg).await {
-                                logger::log_warning(&format!("Failed to send message to {}: {:?}", self.addr, e));
+                        // Nothing is relayed before the join is acknowledged
+                        Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) if self.lifecycle.name().is_none() => {}
+                        Ok((msg, origin)) => {
+                            // What this connection sent it already showed (unless it asked
+                            // for self-echo); other sessions of the same user still get it
+                            if origin == self.id && !self.self_echo && matches!(
+                                msg.msg_type,
+                                MessageTypes::ChatMessage | MessageTypes::RoomMessage | MessageTypes::DirectMessage
+                            ) {
+                                continue;
+                            }
+                            // Its own join was acknowledged already, and the snapshot
+                            // sent with it has it; the client may be sending by now
+                            if origin == self.id && msg.msg_type == MessageTypes::PresenceDelta {
+                                continue;
+                            }
+                            if !self.is_for_our_rooms(&msg).await || !self.may_receive(&msg).await {
+                                continue;
+                            }
+                            let span = info_span!("relay", from = %origin, kind = ?msg.msg_type);
+                            if let Err(e) = self.send_message_chunked(msg).instrument(span).await {
+                                warn!("Failed to send message to {}: {:?}", self.addr, e);
                                 // Client likely disconnected, break to clean up
                                 break;
                             }
                         }
+                        Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                            warn!("Client {} fell behind by {} message(s), catching up from room history", self.addr, skipped);
+                            self.state.channel.lagged(self.id, self.lifecycle.name().unwrap_or_default(), skipped);
+                            let result = self.backfill_after_lag(&mut rx, next_broadcast).await;
+                            next_broadcast = rx.position();
+                            match result {
+                                Ok(replayed) => {
+                                    info!("Replayed {} missed room message(s) to {}", replayed, self.addr);
+                                }
+                                Err(e) => {
+                                    warn!("Failed to send message to {}: {:?}", self.addr, e);
+                                    break;
+                                }
+                            }
+                        }
                         Err(e) => {
-                            logger::log_error(&format!("Broadcast receive error for {}: {:?}", self.addr, e));
+                            error!("Broadcast receive error for {}: {:?}", self.addr, e);
                             break;
                         }
                     }
@@ -221,79 +385,150 @@ impl UserConnection {
                 // Branch 3: Server commands (kick, rename, etc.)
                 result = cmd_rx.recv() => {
                     match result {
-                        Ok(ServerCommand::Kick(username)) => {
-                            if let Some(chat_name) = &self.chat_name
-                                && chat_name == &username {
-                                logger::log_info(&format!("User {} kicked by server", chat_name));
-                                // Send error message to client before disconnecting
-                                if let Ok(kick_msg) = ChatMessage::try_new(
-                                    MessageTypes::Error,
-                                    Some("You have been kicked by the server".as_bytes().to_vec())
-                                ) {
-                                    let _ = self.send_message_chunked(kick_msg).await;
-                                }
+                        Ok(ServerCommand::Kick { usernames, cooldown }) => {
+                            if let Some(chat_name) = self.lifecycle.name()
+                                && usernames.iter().any(|username| username == chat_name) {
+                                info!("User {} kicked by server", chat_name);
+                                let reason = match cooldown {
+                                    Some(cooldown) => format!(
+                                        "You have been kicked by the server - you can rejoin in {}",
+                                        schedule::format_interval(cooldown)
+                                    ),
+                                    None => "You have been kicked by the server".to_string(),
+                                };
+                                self.send_disconnect(
+                                    Disconnect::new(DisconnectReason::Kicked, &reason).with_retry_after(cooldown)
+                                ).await;
                                 // Clear status when kicked
                                 self.clear_status_on_disconnect = true;
                                 break;
                             }
                         }
                         Ok(ServerCommand::Rename { old_name, new_name }) => {
-                            if let Some(chat_name) = &self.chat_name
-                                && chat_name == &old_name {
+                            if self.lifecycle.name() == Some(old_name.as_str()) {
                                 // Update user_ips mapping
-                                let mut ips = self.user_ips.write().await;
+                                let mut ips = self.state.user_ips.write().await;
                                 if let Some(ip) = ips.remove(&old_name) {
                                     ips.insert(new_name.clone(), ip);
                                 }
                                 drop(ips);
+                                let mut fingerprints = self.state.user_fingerprints.write().await;
+                                if let Some(fingerprint) = fingerprints.remove(&old_name) {
+                                    fingerprints.insert(new_name.clone(), fingerprint);
+                                }
+                                drop(fingerprints);
+
+                                // Carry room memberships over to the new name
+                                self.state.rooms.write().await.rename_member(&old_name, &new_name);
+                                self.state.bandwidth.write().await.rename(&old_name, &new_name);
+                                self.state.presence.write().await.rename(&old_name, &new_name);
+                                self.state.seen.write().await.renamed(&old_name, &new_name);
+                                // A registered nickname keeps its block list
+                                let logged_in = self.state.authenticated.write().await.remove(&old_name);
+                                let mut blocks = self.state.blocks.write().await;
+                                if !logged_in
+                                    && blocks.rename(&old_name, &new_name)
+                                    && let Err(e) = blocks.save().await {
+                                    error!("Failed to save block list: {}", e);
+                                }
+                                drop(blocks);
 
-                                // Update the local chat_name
-                                self.chat_name = Some(new_name.clone());
+                                // Update the local nickname
+                                self.lifecycle.rename(new_name.clone());
 
                                 // Send UserRename message to client
                                 if let Ok(rename_msg) = ChatMessage::try_new(
                                     MessageTypes::UserRename,
-                                    Some(new_name.clone().into_bytes())
+                                    Some(presence::encode_rename(&old_name, &new_name).into_bytes())
                                 ) {
                                     let _ = self.send_message_chunked(rename_msg).await;
                                 }
 
-                                logger::log_info(&format!("User {} renamed to {} by server", old_name, new_name));
+                                info!("User {} renamed to {} by server", old_name, new_name);
 
                                 // Broadcast announcement to all clients
-                                let announcement = format!("{} is now known as {} (renamed by server)", old_name, new_name);
-                                if let Ok(broadcast_msg) = ChatMessage::try_new(
-                                    MessageTypes::ChatMessage,
-                                    Some(announcement.into_bytes())
-                                ) {
-                                    let _ = self.tx.send((broadcast_msg, self.addr));
-                                }
+                                let _ = self.state.broadcast_rename(&old_name, &new_name, Some("renamed by server"));
                             }
                         }
-                        Ok(ServerCommand::Ban(ip)) => {
-                            // Disconnect if our IP matches
-                            if self.addr.ip() == ip {
-                                logger::log_info(&format!("User {:?} banned (IP {})", self.chat_name, ip));
-                                // Send error message to client before disconnecting
-                                if let Ok(ban_msg) = ChatMessage::try_new(
-                                    MessageTypes::Error,
-                                    Some("You have been banned from the server".as_bytes().to_vec())
-                                ) {
-                                    let _ = self.send_message_chunked(ban_msg).await;
-                                }
+                        Ok(ServerCommand::Ban { network, duration }) => {
+                            // Disconnect if our IP is inside the banned range
+                            if network.contains(self.addr.ip().to_canonical()) {
+                                info!("User {:?} banned ({})", self.lifecycle.name(), bans::describe(network));
+                                let reason = match duration {
+                                    Some(duration) => format!(
+                                        "You have been banned from the server for {}",
+                                        schedule::format_interval(duration)
+                                    ),
+                                    None => "You have been banned from the server".to_string(),
+                                };
+                                self.send_disconnect(
+                                    Disconnect::new(DisconnectReason::Banned, &reason).with_retry_after(duration)
+                                ).await;
                                 // Clear status when banned
                                 self.clear_status_on_disconnect = true;
                                 break;
                             }
                         }
+                        Ok(ServerCommand::BanFingerprint { id, duration }) => {
+                            if self.fingerprint.as_ref().is_some_and(|fingerprint| fingerprint.id() == id) {
+                                info!("User {:?} banned (fingerprint {})", self.lifecycle.name(), id);
+                                let reason = match duration {
+                                    Some(duration) => format!(
+                                        "You have been banned from the server for {}",
+                                        schedule::format_interval(duration)
+                                    ),
+                                    None => "You have been banned from the server".to_string(),
+                                };
+                                self.send_disconnect(
+                                    Disconnect::new(DisconnectReason::Banned, &reason).with_retry_after(duration)
+                                ).await;
+                                self.clear_status_on_disconnect = true;
+                                break;
+                            }
+                        }
+                        Ok(ServerCommand::NicknameReclaimed { old_name, new_name, owner }) => {
+                            if self.addr != owner
+                                && self.lifecycle.name() == Some(old_name.as_str()) {
+                                self.lifecycle.rename(new_name.clone());
+                                let disconnect = self.state.reclaim_policy == ReclaimPolicy::Disconnect;
+                                let reason = if disconnect {
+                                    format!("'{}' is a registered nickname and its owner has logged in - disconnecting", old_name)
+                                } else {
+                                    format!("'{}' is a registered nickname and its owner has logged in - you are now '{}'", old_name, new_name)
+                                };
+                                if disconnect {
+                                    info!("Disconnecting guest {} (was using '{}')", new_name, old_name);
+                                    self.send_disconnect(Disconnect::new(DisconnectReason::NicknameReclaimed, &reason)).await;
+                                    self.clear_status_on_disconnect = true;
+                                    break;
+                                }
+                                if let Ok(error_msg) = ChatError::NicknameReclaimed.to_message(&reason) {
+                                    let _ = self.send_message_chunked(error_msg).await;
+                                }
+
+                                if let Ok(rename_msg) = ChatMessage::try_new(
+                                    MessageTypes::UserRename,
+                                    Some(presence::encode_rename(&old_name, &new_name).into_bytes())
+                                ) {
+                                    let _ = self.send_message_chunked(rename_msg).await;
+                                }
+                                let _ = self.state.broadcast_rename(
+                                    &old_name,
+                                    &new_name,
+                                    Some("nickname reclaimed by its owner"),
+                                );
+                            }
+                        }
+                        Ok(ServerCommand::Shutdown) => {
+                            self.send_disconnect(Disconnect::new(DisconnectReason::Shutdown, "")).await;
+                            break;
+                        }
                         Ok(ServerCommand::SessionTakeover(username)) => {
                             // Another connection is reclaiming this session
-                            if let Some(chat_name) = &self.chat_name
-                                && chat_name == &username {
-                                logger::log_info(&format!(
-                                    "Session for {} taken over by reconnecting client, closing old connection",
-                                    chat_name
-                                ));
+                            if let Some(chat_name) = self.lifecycle.name()
+                                && chat_name == username {
+                                info!("Session for {} taken over by reconnecting client, closing old connection",
+                                    chat_name);
                                 // Mark session as taken over - don't clean up username/session on disconnect
                                 self.session_taken_over = true;
                                 break;
@@ -304,16 +539,30 @@ impl UserConnection {
                         }
                     }
                 }
-                // Branch 4: Periodic ping and timeout check
+                // Branch 4: Waiting room moved (only while queued for a slot)
+                Ok(()) = async {
+                    match &mut waiting_room_rx {
+                        Some(rx) => rx.changed().await,
+                        None => std::future::pending().await,
+                    }
+                }, if self.queued_join.is_some() => {
+                    if !self.check_waiting_room().await {
+                        break;
+                    }
+                }
+                // Branch 5: Periodic ping and timeout check
                 _ = ping_interval.tick() => {
                     // Check if client has timed out (no activity for PONG_TIMEOUT)
                     if last_activity.elapsed() > PONG_TIMEOUT {
-                        logger::log_warning(&format!(
-                            "Client {} ({:?}) timed out - no response for {:?}",
+                        warn!("Client {} ({:?}) timed out - no response for {:?}",
                             self.addr,
-                            self.chat_name,
-                            last_activity.elapsed()
-                        ));
+                            self.lifecycle.name(),
+                            last_activity.elapsed());
+                        let text = format!(
+                            "Disconnected after {}s without a response",
+                            PONG_TIMEOUT.as_secs()
+                        );
+                        self.send_disconnect(Disconnect::new(DisconnectReason::IdleTimeout, &text)).await;
                         break;
                     }
 
@@ -321,7 +570,7 @@ impl UserConnection {
                     if let Ok(ping_msg) = ChatMessage::try_new(MessageTypes::Ping, None)
                         && let Err(e) = self.send_message_chunked(ping_msg).await
                     {
-                        logger::log_warning(&format!("Failed to send ping to {}: {:?}", self.addr, e));
+                        warn!("Failed to send ping to {}: {:?}", self.addr, e);
                         break;
                     }
                 }
@@ -329,58 +578,429 @@ impl UserConnection {
         }
 
         // Cleanup on disconnect
-        if let Some(chat_name) = &self.chat_name {
+        if self.queued_join.is_some()
+            && let Some(waiting_room) = &self.state.waiting_room
+        {
+            waiting_room.write().await.leave(self.addr);
+        }
+        self.state.presence.write().await.disconnected(self.addr);
+        self.state.channel.forget(self.id);
+        self.lifecycle.drain();
+        if let Some(chat_name) = self.lifecycle.name() {
             // If session was taken over by a reconnecting client, don't clean up
             // The new connection now owns the username and session
             if self.session_taken_over {
-                logger::log_info(&format!(
+                info!(
                     "Old connection for {} closed (session taken over)",
                     chat_name
-                ));
+                );
+                return Ok(());
+            }
+            // Logged in from another client too: the user is still here
+            if self.state.sessions.write().await.closed(chat_name) {
+                info!("{} closed one of their sessions ({})", chat_name, self.addr);
                 return Ok(());
             }
 
-            let mut clients = self.connected_clients.write().await;
+            let mut clients = self.state.connected_clients.write().await;
             clients.remove(chat_name);
             drop(clients);
+            if let Some(waiting_room) = &self.state.waiting_room {
+                waiting_room.read().await.slot_freed();
+            }
+            self.state.authenticated.write().await.remove(chat_name);
+            self.state.blocks.write().await.forget_guest(chat_name);
 
             // Remove from user_ips mapping
-            let mut ips = self.user_ips.write().await;
+            let mut ips = self.state.user_ips.write().await;
             ips.remove(chat_name);
             drop(ips);
+            self.state.user_fingerprints.write().await.remove(chat_name);
+            self.state.presence.write().await.left(chat_name);
+            self.state.seen.write().await.connected(chat_name);
+
+            // Leave all rooms (the Leave broadcast below covers room members too).
+            // A dropped connection may rejoin its private rooms when it reconnects.
+            let mut rooms = self.state.rooms.write().await;
+            let private: Vec<String> = rooms
+                .rooms_for(chat_name)
+                .into_iter()
+                .filter(|room| rooms.is_private(room))
+                .collect();
+            rooms.leave_all(chat_name);
+            if !self.clear_status_on_disconnect {
+                for room in &private {
+                    rooms.invite(room, chat_name);
+                }
+            }
+            drop(rooms);
+            for room in &private {
+                let _ = self.state.rotate_room_key(room).await;
+            }
 
             // Only remove status and session on explicit quit/kick/ban, not on connection drops
             // (which may be reconnection attempts)
             if self.clear_status_on_disconnect {
-                let mut statuses = self.user_statuses.write().await;
+                let mut statuses = self.state.user_statuses.write().await;
                 statuses.remove(chat_name);
                 drop(statuses);
 
-                let mut sessions = self.user_sessions.write().await;
+                let mut sessions = self.state.user_sessions.write().await;
                 sessions.remove(chat_name);
                 drop(sessions);
             }
 
-            if let Ok(leave_message) =
-                ChatMessage::try_new(MessageTypes::Leave, Some(chat_name.clone().into_bytes()))
+            let _ = self
+                .state
+                .broadcast_presence(&PresenceDelta::Left(chat_name.to_string()), self.id);
+            system!("{} has left the chat", chat_name);
+        }
+
+        Ok(())
+    }
+
+    /// Whether our scopes let us be sent `msg` (bots without the read scope, say,
+    /// aren't sent what anyone says)
+    async fn may_receive(&self, msg: &ChatMessage) -> bool {
+        match (self.lifecycle.name(), Scope::to_receive(msg)) {
+            (Some(chat_name), Some(scope)) => self.state.scopes(chat_name).await.contains(scope),
+            _ => true,
+        }
+    }
+
+    /// Room traffic is only written to the room's members, and a join or leave to the
+    /// user joining or leaving as well. A key rotation request only goes to the keeper
+    /// and a sealed key to its recipient. Everything else is for every connection.
+    async fn is_for_our_rooms(&self, msg: &ChatMessage) -> bool {
+        if msg.msg_type == MessageTypes::RoomKey {
+            let (Some(chat_name), Some(frame)) = (
+                self.lifecycle.name(),
+                msg.content_as_string()
+                    .and_then(|content| RoomKeyFrame::decode(&content)),
+            ) else {
+                return false;
+            };
+            return match frame {
+                RoomKeyFrame::Private { room, .. } => {
+                    self.state.rooms.read().await.is_member(&room, chat_name)
+                }
+                RoomKeyFrame::Rotate { keeper, .. } => keeper == chat_name,
+                RoomKeyFrame::Key { to, .. } => to == chat_name,
+            };
+        }
+        if !matches!(
+            msg.msg_type,
+            MessageTypes::RoomMessage
+                | MessageTypes::RoomAnnouncement
+                | MessageTypes::Translation
+                | MessageTypes::JoinRoom
+                | MessageTypes::LeaveRoom
+        ) {
+            return true;
+        }
+        let (Some(chat_name), Some(content)) = (self.lifecycle.name(), msg.content_as_string())
+        else {
+            return true;
+        };
+        let (_, content) = sender_class::untag(&content);
+        let mut fields = content.splitn(3, '|');
+        let room = fields.next().unwrap_or_default();
+        if matches!(
+            msg.msg_type,
+            MessageTypes::JoinRoom | MessageTypes::LeaveRoom
+        ) && fields.next() == Some(chat_name)
+        {
+            return true;
+        }
+        self.state.rooms.read().await.is_member(room, chat_name)
+    }
+
+    /// Catch up a client whose broadcast receiver lagged: skip the rest of the queue and
+    /// replay every room message from broadcast number `from` on from history, in
+    /// order, before live traffic resumes. Returns the number of messages replayed.
+    async fn backfill_after_lag(
+        &mut self,
+        rx: &mut Subscription,
+        from: u64,
+    ) -> std::io::Result<usize> {
+        let rooms = match self.lifecycle.name() {
+            Some(chat_name) if self.state.may_read(chat_name).await => {
+                self.state.rooms.read().await.rooms_for(chat_name)
+            }
+            _ => Vec::new(),
+        };
+        let missed = history::catch_up(&self.state.history, rx, &rooms, from).await;
+
+        // Comings and goings it missed aren't in the history; a fresh snapshot covers them
+        let users = self.state.user_list().await;
+        if let Ok(list_message) =
+            ChatMessage::try_new(MessageTypes::ListUsers, Some(users.into_bytes()))
+        {
+            self.send_message_chunked(list_message).await?;
+        }
+
+        let replayed = missed.len();
+        // Its own messages it already showed when they were sent
+        for (room, entry) in missed {
+            if entry.origin == self.id && !self.self_echo {
+                continue;
+            }
+            let content = sender_class::tag(
+                reserved::class(&entry.sender, &self.state.server_identity),
+                &format!("{}|{}|{}", room, entry.sender, entry.message),
+            );
+            if let Ok(room_message) =
+                ChatMessage::try_new(MessageTypes::RoomMessage, Some(content.into_bytes()))
             {
-                let _ = self.tx.send((leave_message, self.addr));
+                self.send_message_chunked(room_message).await?;
             }
-            logger::log_system(&format!("{} has left the chat", chat_name));
         }
+        Ok(replayed)
+    }
 
-        Ok(())
+    /// Log the outcome of processing a client message of type `frame`. Returns false if the
+    /// connection should be closed.
+    async fn handle_result(&mut self, frame: MessageTypes, result: Result<(), ChatError>) -> bool {
+        if result
+            .as_ref()
+            .is_err_and(|e| !matches!(e, ChatError::ExplicitQuit))
+        {
+            self.state.counters.error();
+        }
+        match result {
+            Ok(()) => {}
+            Err(ChatError::ExplicitQuit) => {
+                // User explicitly quit - clear status on disconnect
+                self.clear_status_on_disconnect = true;
+                return false;
+            }
+            Err(ChatError::KickCooldown) => {
+                // Kicked recently - disconnect client (error already sent)
+                warn!(
+                    "Client {} disconnected: nickname was kicked recently",
+                    self.addr
+                );
+                self.send_disconnect(Disconnect::new(DisconnectReason::Kicked, ""))
+                    .await;
+                return false;
+            }
+            Err(ChatError::FingerprintBanned) => {
+                // Banned client - disconnect (error already sent)
+                warn!("Client {} disconnected: fingerprint is banned", self.addr);
+                self.send_disconnect(Disconnect::new(DisconnectReason::Banned, ""))
+                    .await;
+                return false;
+            }
+            Err(ChatError::ChallengeFailed) => {
+                // Failed the proof-of-work challenge - disconnect (error already sent)
+                warn!(
+                    "Client {} disconnected: proof-of-work challenge failed",
+                    self.addr
+                );
+                self.send_disconnect(Disconnect::new(DisconnectReason::Refused, ""))
+                    .await;
+                return false;
+            }
+            Err(ChatError::GuestsFull) => {
+                // No guest slot free - disconnect client (error already sent)
+                warn!(
+                    "Client {} disconnected: server is full for guests",
+                    self.addr
+                );
+                self.send_disconnect(Disconnect::new(DisconnectReason::Refused, ""))
+                    .await;
+                return false;
+            }
+            Err(ChatError::JoinError) => {
+                // Nickname refused - disconnect client (error already sent)
+                warn!(
+                    "Client {} disconnected: no nickname could be assigned",
+                    self.addr
+                );
+                self.send_disconnect(Disconnect::new(DisconnectReason::Refused, ""))
+                    .await;
+                return false;
+            }
+            Err(ChatError::VersionMismatch) => {
+                // Version mismatch - disconnect client (error already sent)
+                warn!("Client {} disconnected due to version mismatch", self.addr);
+                self.send_disconnect(Disconnect::new(DisconnectReason::Refused, ""))
+                    .await;
+                return false;
+            }
+            Err(e) if Rejection::rejects_frame(&e) => {
+                // Tell the client which frame it got wrong and why; the frame is
+                // otherwise ignored
+                warn!("Rejected {:?} frame from {}: {}", frame, self.addr, e);
+                if let Ok(error_msg) = Rejection::new(frame, &e.to_string()).to_message(&e) {
+                    let _ = self.send_message_chunked(error_msg).await;
+                }
+                // A frame that doesn't parse may be a bug; only what no client should
+                // ever send counts towards a ban
+                if matches!(
+                    e,
+                    ChatError::ProtocolViolation(_) | ChatError::OutOfState { .. }
+                ) {
+                    self.record_violation(&e.to_string()).await;
+                }
+            }
+            Err(e) => {
+                error!("Error handling message from {}: {:?}", self.addr, e);
+            }
+        }
+        true
+    }
+
+    /// Whether a Join has to wait for a slot. Reconnecting users taking over their
+    /// own session don't (they already have one), and neither do clients that still
+    /// owe a proof-of-work answer - they queue once it's solved.
+    async fn should_wait(&self, join: &ChatMessage) -> bool {
+        let Some(waiting_room) = &self.state.waiting_room else {
+            return false;
+        };
+        if !self.lifecycle.accepts(&join.msg_type) || self.queued_join.is_some() {
+            return false;
+        }
+        if self.state.pow_difficulty.is_some() && !self.challenge.is_solved() {
+            return false;
+        }
+        let clients = self.state.connected_clients.read().await;
+        let reconnecting = join
+            .content_as_string()
+            .and_then(|content| content.split('|').next().map(str::to_string))
+            .is_some_and(|username| clients.contains(&username));
+        !reconnecting && waiting_room.read().await.must_wait(clients.len())
+    }
+
+    /// Queue a Join until a slot frees. Returns false if the connection should be
+    /// closed (the queue is full too).
+    async fn enter_waiting_room(&mut self, join: ChatMessage) -> bool {
+        let Some(waiting_room) = &self.state.waiting_room else {
+            return true;
+        };
+        let Some(position) = waiting_room.write().await.enqueue(self.addr) else {
+            warn!(
+                "Server and waiting room are full, disconnecting {}",
+                self.addr
+            );
+            if let Ok(error_msg) = ChatError::ServerFull
+                .to_message("The server is full and so is its waiting room - try again later")
+            {
+                let _ = self.send_message_chunked(error_msg).await;
+            }
+            self.send_disconnect(Disconnect::new(DisconnectReason::ServerFull, ""))
+                .await;
+            return false;
+        };
+        info!(
+            "Server is full, {} is #{} in the waiting room",
+            self.addr, position
+        );
+        self.queued_join = Some(join);
+        self.send_queue_position(position).await
+    }
+
+    /// Re-check our place in the waiting room after it changed: join if it's our turn,
+    /// otherwise tell the client if its position moved. Returns false if the connection
+    /// should be closed.
+    async fn check_waiting_room(&mut self) -> bool {
+        let Some(waiting_room) = self.state.waiting_room.clone() else {
+            return true;
+        };
+        let joined = self.state.connected_clients.read().await.len();
+        let (admit, position) = {
+            let waiting_room = waiting_room.read().await;
+            (
+                waiting_room.can_admit(self.addr, joined),
+                waiting_room.position(self.addr),
+            )
+        };
+
+        if admit && let Some(join) = self.queued_join.take() {
+            info!("Admitting {} from the waiting room", self.addr);
+            let result = self.process_message(join).await;
+            // Leave only once joined, so nobody slips into the slot in between
+            waiting_room.write().await.leave(self.addr);
+            return self.handle_result(MessageTypes::Join, result).await;
+        }
+        match position {
+            Some(position) if position != self.queue_position => {
+                self.send_queue_position(position).await
+            }
+            _ => true,
+        }
+    }
+
+    async fn send_queue_position(&mut self, position: usize) -> bool {
+        self.queue_position = position;
+        let Ok(queued_msg) = ChatMessage::try_new(
+            MessageTypes::WaitingRoom,
+            Some(position.to_string().into_bytes()),
+        ) else {
+            return true;
+        };
+        self.send_message_chunked(queued_msg).await.is_ok()
+    }
+
+    /// Tell the client why it's being disconnected, just before it is. Best effort: it
+    /// may be gone already, and a client that stopped reading can't hold the close up.
+    async fn send_disconnect(&mut self, disconnect: Disconnect) {
+        if let Ok(message) = disconnect.to_message() {
+            let _ =
+                tokio::time::timeout(DISCONNECT_TIMEOUT, self.send_message_chunked(message)).await;
+        }
+    }
+
+    /// Count a protocol violation against this IP (paranoid mode only),
+    /// banning it once it reaches the configured limit
+    async fn record_violation(&self, reason: &str) {
+        let Some(violations) = &self.state.violations else {
+            return;
+        };
+        let ip = self.addr.ip();
+
+        let mut tracker = violations.write().await;
+        let count = tracker.record(ip);
+        warn!(
+            "Protocol violation from {} ({}/{}): {}",
+            ip,
+            count,
+            tracker.max_violations(),
+            reason
+        );
+        if !tracker.should_ban(count) {
+            return;
+        }
+        tracker.forget(ip);
+        drop(tracker);
+
+        if self
+            .state
+            .bans
+            .write()
+            .await
+            .ban(bans::host_network(ip), None, Instant::now())
+        {
+            warn!("Banned IP {} after {} protocol violations", ip, count);
+            self.state.audit.record_from(
+                self.id,
+                "BAN",
+                &format!("{} after {} protocol violations", ip, count),
+            );
+            // Disconnects every connection from this IP, including this one
+            let _ = self.state.server_commands.send(ServerCommand::Ban {
+                network: bans::host_network(ip),
+                duration: None,
+            });
+        }
     }
 
-    async fn process_message(&mut self, message: ChatMessage) -> Result<(), UserConnectionError> {
+    async fn process_message(&mut self, message: ChatMessage) -> Result<(), ChatError> {
         let handlers = MessageHandlers {
             addr: self.addr,
-            tx: &self.tx,
-            server_commands: &self.server_commands,
-            connected_clients: &self.connected_clients,
-            user_ips: &self.user_ips,
-            user_statuses: &self.user_statuses,
-            user_sessions: &self.user_sessions,
+            id: self.id,
+            state: &self.state,
+            certificate_user: self.certificate_user.as_deref(),
         };
 
         handlers
@@ -388,7 +1008,9 @@ impl UserConnection {
                 message,
                 &mut self.rate_limiter,
                 &mut self.socket,
-                &mut self.chat_name,
+                &mut self.lifecycle,
+                &mut self.fingerprint,
+                &mut self.challenge,
             )
             .await
     }
@@ -0,0 +1,76 @@
+//! Paranoid mode: counts protocol violations per IP so repeat offenders can be banned
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+pub const DEFAULT_MAX_VIOLATIONS: u32 = 5;
+
+#[derive(Debug)]
+pub struct ViolationTracker {
+    max_violations: u32,
+    counts: HashMap<IpAddr, u32>,
+}
+
+impl ViolationTracker {
+    pub fn new(max_violations: u32) -> Self {
+        Self {
+            max_violations: max_violations.max(1),
+            counts: HashMap::new(),
+        }
+    }
+
+    pub fn max_violations(&self) -> u32 {
+        self.max_violations
+    }
+
+    /// Record a violation and return how many this IP has committed so far
+    pub fn record(&mut self, ip: IpAddr) -> u32 {
+        let count = self.counts.entry(ip).or_insert(0);
+        *count += 1;
+        *count
+    }
+
+    pub fn should_ban(&self, count: u32) -> bool {
+        count >= self.max_violations
+    }
+
+    /// Reset the count for an IP (after it has been banned)
+    pub fn forget(&mut self, ip: IpAddr) {
+        self.counts.remove(&ip);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counts_per_ip() {
+        let mut tracker = ViolationTracker::new(3);
+        let a: IpAddr = "10.0.0.1".parse().unwrap();
+        let b: IpAddr = "10.0.0.2".parse().unwrap();
+        assert_eq!(tracker.record(a), 1);
+        assert_eq!(tracker.record(a), 2);
+        assert_eq!(tracker.record(b), 1);
+        assert!(!tracker.should_ban(2));
+        let count = tracker.record(a);
+        assert!(tracker.should_ban(count));
+    }
+
+    #[test]
+    fn test_forget_resets_count() {
+        let mut tracker = ViolationTracker::new(3);
+        let ip: IpAddr = "::1".parse().unwrap();
+        tracker.record(ip);
+        tracker.record(ip);
+        tracker.forget(ip);
+        assert_eq!(tracker.record(ip), 1);
+    }
+
+    #[test]
+    fn test_zero_threshold_bans_on_first_violation() {
+        let tracker = ViolationTracker::new(0);
+        assert_eq!(tracker.max_violations(), 1);
+        assert!(tracker.should_ban(1));
+    }
+}
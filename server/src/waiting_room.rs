@@ -0,0 +1,117 @@
+//! Waiting room for a full server. Sockets are limited separately from chat capacity:
+//! connections that try to join while `max_clients` users are in the chat are queued in
+//! order, told their position, and admitted automatically as users leave.
+
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use tokio::sync::watch;
+
+/// Connections allowed to wait for a slot when not configured
+pub const DEFAULT_WAITING_ROOM_SIZE: usize = 50;
+
+pub struct WaitingRoom {
+    /// Users allowed in the chat at once
+    capacity: usize,
+    /// Connections allowed to wait
+    max_waiting: usize,
+    queue: VecDeque<SocketAddr>,
+    /// Nudged whenever the queue moves or a user leaves, so waiting connections re-check
+    changed: watch::Sender<()>,
+}
+
+impl WaitingRoom {
+    pub fn new(capacity: usize, max_waiting: usize) -> Self {
+        let (changed, _) = watch::channel(());
+        WaitingRoom {
+            capacity,
+            max_waiting,
+            queue: VecDeque::new(),
+            changed,
+        }
+    }
+
+    /// Whether a new join has to queue with `joined` users in the chat. Anyone already
+    /// waiting goes first, so a free slot can't be taken by someone who just arrived.
+    pub fn must_wait(&self, joined: usize) -> bool {
+        joined >= self.capacity || !self.queue.is_empty()
+    }
+
+    /// Add a connection to the back of the queue. Returns its position (1 = next in),
+    /// or None if the queue is full.
+    pub fn enqueue(&mut self, addr: SocketAddr) -> Option<usize> {
+        if self.queue.len() >= self.max_waiting {
+            return None;
+        }
+        self.queue.push_back(addr);
+        Some(self.queue.len())
+    }
+
+    pub fn position(&self, addr: SocketAddr) -> Option<usize> {
+        self.queue
+            .iter()
+            .position(|queued| *queued == addr)
+            .map(|index| index + 1)
+    }
+
+    /// Whether `addr` is at the front of the queue and there's room in the chat
+    pub fn can_admit(&self, addr: SocketAddr, joined: usize) -> bool {
+        self.position(addr) == Some(1) && joined < self.capacity
+    }
+
+    /// Take a connection out of the queue (admitted or gone)
+    pub fn leave(&mut self, addr: SocketAddr) {
+        if let Some(index) = self.queue.iter().position(|queued| *queued == addr) {
+            self.queue.remove(index);
+            self.changed.send_replace(());
+        }
+    }
+
+    /// A user left the chat, so the front of the queue may be able to join
+    pub fn slot_freed(&self) {
+        if !self.queue.is_empty() {
+            self.changed.send_replace(());
+        }
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<()> {
+        self.changed.subscribe()
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn test_queue_is_first_come_first_served() {
+        let mut room = WaitingRoom::new(2, 2);
+        assert!(!room.must_wait(1));
+        assert!(room.must_wait(2));
+
+        assert_eq!(room.enqueue(addr(1)), Some(1));
+        assert_eq!(room.enqueue(addr(2)), Some(2));
+        assert_eq!(room.enqueue(addr(3)), None);
+        // Someone is waiting, so newcomers queue even when a slot is free
+        assert!(room.must_wait(1));
+
+        assert!(!room.can_admit(addr(1), 2));
+        assert!(room.can_admit(addr(1), 1));
+        assert!(!room.can_admit(addr(2), 1));
+
+        let changed = room.subscribe();
+        room.leave(addr(1));
+        assert!(changed.has_changed().unwrap());
+        assert_eq!(room.position(addr(2)), Some(1));
+        room.leave(addr(2));
+        assert_eq!(room.len(), 0);
+        assert!(!room.must_wait(1));
+    }
+}
@@ -0,0 +1,94 @@
+//! Proof-of-work challenge a server can require before accepting a Join, so flooding
+//! it with automated connections costs CPU time on every one of them.
+//!
+//! The server sends `difficulty|nonce`; the client answers with a number such that
+//! sha256("nonce:answer") starts with at least `difficulty` zero bits.
+
+use sha2::{Digest, Sha256};
+
+/// Hardest puzzle a client will attempt (2^28 hashes is already several seconds)
+pub const MAX_DIFFICULTY: u8 = 28;
+/// Hex characters in a challenge nonce
+pub const NONCE_LENGTH: usize = 32;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Challenge {
+    /// Leading zero bits the hash of the answer needs
+    pub difficulty: u8,
+    /// Random per-connection value, so answers can't be reused
+    pub nonce: String,
+}
+
+impl Challenge {
+    pub fn encode(&self) -> String {
+        format!("{}|{}", self.difficulty, self.nonce)
+    }
+
+    /// None if malformed or harder than `MAX_DIFFICULTY`
+    pub fn decode(content: &str) -> Option<Self> {
+        let (difficulty, nonce) = content.split_once('|')?;
+        let difficulty: u8 = difficulty.parse().ok()?;
+        let valid = difficulty <= MAX_DIFFICULTY
+            && nonce.len() == NONCE_LENGTH
+            && nonce.chars().all(|c| c.is_ascii_hexdigit());
+        valid.then(|| Challenge {
+            difficulty,
+            nonce: nonce.to_string(),
+        })
+    }
+
+    pub fn verify(&self, answer: u64) -> bool {
+        leading_zero_bits(&self.hash(answer)) >= u32::from(self.difficulty)
+    }
+
+    /// Find an answer by brute force (about 2^difficulty hashes)
+    pub fn solve(&self) -> u64 {
+        (0..)
+            .find(|&answer| self.verify(answer))
+            .unwrap_or_default()
+    }
+
+    fn hash(&self, answer: u64) -> [u8; 32] {
+        Sha256::digest(format!("{}:{}", self.nonce, answer).as_bytes()).into()
+    }
+}
+
+fn leading_zero_bits(hash: &[u8]) -> u32 {
+    let mut bits = 0;
+    for byte in hash {
+        bits += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    bits
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_solve_and_verify() {
+        let challenge = Challenge {
+            difficulty: 12,
+            nonce: "0123456789abcdef0123456789abcdef".to_string(),
+        };
+        let answer = challenge.solve();
+        assert!(challenge.verify(answer));
+        assert_eq!(
+            Challenge::decode(&challenge.encode()),
+            Some(challenge.clone())
+        );
+
+        assert_eq!(leading_zero_bits(&[0, 0x1f, 0xff]), 11);
+        assert_eq!(leading_zero_bits(&[0x80]), 0);
+
+        assert_eq!(
+            Challenge::decode("29|0123456789abcdef0123456789abcdef"),
+            None
+        );
+        assert_eq!(Challenge::decode("12|not-hex"), None);
+        assert_eq!(Challenge::decode("12"), None);
+    }
+}
@@ -86,6 +86,17 @@ pub mod client {
         .with_usage("<message>")
         .with_description("Reply to last direct message");
 
+    pub const SPLIT: Command = Command::new("/split")
+        .with_description("Send the last message that was too long as several parts");
+
+    pub const LAST: Command = Command::new("/last")
+        .with_usage("<username> [count]")
+        .with_description("Show a user's most recent messages from your scrollback");
+
+    pub const SEEN: Command = Command::new("/seen")
+        .with_usage("<username>")
+        .with_description("Show when a user was last connected and last spoke");
+
     pub const SEND: Command = Command::new("/send")
         .with_usage("<username> <filepath>")
         .with_description("Send a file (max 100MB, requires acceptance)");
@@ -102,15 +113,155 @@ pub mod client {
         .with_usage("<new_name>")
         .with_description("Change your username");
 
+    pub const REGISTER: Command = Command::new("/register")
+        .with_usage("<password>")
+        .with_description("Register your current nickname so only you can use it (logged in: change its password)");
+
     pub const STATUS: Command = Command::new("/status")
         .with_usage("<message>")
         .with_description("Set your status (visible in /list)");
 
     pub const STATUS_CLEAR: Command = Command::new("/status").with_description("Clear your status");
 
-    /// All client commands (for completion - excludes STATUS_CLEAR as it's same command)
+    pub const JOIN: Command = Command::new("/join")
+        .with_usage("<room>")
+        .with_description("Join a room (created if it doesn't exist) or switch to one you're in");
+
+    pub const PART: Command = Command::new("/part")
+        .with_usage("[room]")
+        .with_description("Leave a room (defaults to the current room)");
+
+    pub const BLOCK: Command = Command::new("/block")
+        .with_usage("[username]")
+        .with_description("Stop a user's DMs and files reaching you (no name = list blocks)");
+
+    pub const UNBLOCK: Command = Command::new("/unblock")
+        .with_usage("<username>")
+        .with_description("Remove a block");
+
+    pub const ROOM_SLOWMODE: Command = Command::new("/room")
+        .with_usage("slowmode <seconds>")
+        .with_description("Limit how often members can talk in the current room (0 = off)");
+
+    pub const ROOM_RETENTION: Command = Command::new("/room")
+        .with_usage("retention <count|<days>d|off>")
+        .with_description("Set how much history the current room keeps");
+
+    pub const ROOM_MAXLENGTH: Command = Command::new("/room")
+        .with_usage("maxlength <bytes|off>")
+        .with_description("Limit how long messages to the current room can be, in one piece");
+
+    pub const ROOM_TRANSLATE: Command = Command::new("/room")
+        .with_usage("translate <language|off>")
+        .with_description("Have the current room's messages machine translated (e.g. en, pt-BR)");
+
+    pub const ROOM_INFO: Command = Command::new("/room")
+        .with_usage("info [room]")
+        .with_description("Show a room's topic, members and settings without joining");
+
+    pub const ROOM_TOPIC: Command = Command::new("/room")
+        .with_usage("topic [text]")
+        .with_description("Set the current room's topic (no text = clear)");
+
+    pub const ROOM_PRIVATE: Command = Command::new("/room")
+        .with_usage("private <on|off>")
+        .with_description(
+            "Make the current room invite-only and end-to-end encrypted, or public again",
+        );
+
+    pub const ROOM_INVITE: Command = Command::new("/room")
+        .with_usage("invite <user>")
+        .with_description("Let a user join the current private room once");
+
+    pub const ROOM_ARCHIVE: Command = Command::new("/room")
+        .with_usage("archive|unarchive [room]")
+        .with_description(
+            "Make a room read-only and leave it out of /rooms, keeping its history (or undo it)",
+        );
+
+    pub const ANNOUNCE: Command = Command::new("/announce")
+        .with_usage("<message>")
+        .with_description("Announce something to the current room (moderators only)");
+
+    pub const EXPORT_ROOM: Command = Command::new("/export-room")
+        .with_usage("<room> <path>")
+        .with_description(
+            "Save a room's stored history to a file (.json for JSON, otherwise text)",
+        );
+
+    pub const ROOMS: Command = Command::new("/rooms")
+        .with_usage("[--verbose] [--archived]")
+        .with_description(
+            "List rooms with member counts (--verbose: unread counts too, --archived: archived rooms)",
+        );
+
+    pub const SERVER_INFO: Command = Command::new("/server")
+        .with_usage("info")
+        .with_description("Show the server's name, network, description and version");
+
+    pub const DEBUG: Command = Command::new("/debug")
+        .with_usage("trace on|off")
+        .with_description("Write every frame sent and received to a trace file");
+
+    pub const TRUST: Command = Command::new("/trust")
+        .with_usage("[fingerprint]")
+        .with_description(
+            "Show the server's TLS certificate fingerprint, or pin it (self-signed servers)",
+        );
+
+    pub const DOCTOR: Command = Command::new("/doctor")
+        .with_description("Check the files the client keeps between runs and repair corrupt ones");
+
+    pub const HIGHLIGHT: Command = Command::new("/highlight")
+        .with_usage("[add|remove <word|/regex/>]")
+        .with_description("List, add or remove words highlighted like mentions of your nickname");
+
+    pub const FILTER: Command = Command::new("/filter")
+        .with_usage("[hide|only <rule>|remove <filter>|clear]")
+        .with_description(
+            "Choose what's shown: rules are join/leave, from:<nickname> and room:<room>",
+        );
+
+    pub const QUIET: Command = Command::new("/quiet")
+        .with_usage("joins")
+        .with_description("Hide joins, leaves and renames behind a count, or show them again");
+
+    pub const SHOW: Command = Command::new("/show")
+        .with_description("Show the joins, leaves and renames /quiet joins hid");
+
+    /// All client commands (for completion - excludes STATUS_CLEAR and the other
+    /// /room subcommands as they're the same command)
     pub const ALL: &[Command] = &[
-        HELP, LIST, DM, REPLY, SEND, ACCEPT, REJECT, RENAME, STATUS, QUIT,
+        HELP,
+        LIST,
+        DM,
+        REPLY,
+        SPLIT,
+        LAST,
+        SEEN,
+        SEND,
+        ACCEPT,
+        REJECT,
+        RENAME,
+        REGISTER,
+        STATUS,
+        BLOCK,
+        UNBLOCK,
+        JOIN,
+        PART,
+        ROOMS,
+        ROOM_SLOWMODE,
+        ANNOUNCE,
+        EXPORT_ROOM,
+        SERVER_INFO,
+        DEBUG,
+        TRUST,
+        DOCTOR,
+        HIGHLIGHT,
+        FILTER,
+        QUIET,
+        SHOW,
+        QUIT,
     ];
 
     /// All help entries (includes STATUS_CLEAR for documentation)
@@ -119,12 +270,40 @@ pub mod client {
         LIST,
         DM,
         REPLY,
+        SPLIT,
+        LAST,
+        SEEN,
         SEND,
         ACCEPT,
         REJECT,
         RENAME,
+        REGISTER,
         STATUS,
         STATUS_CLEAR,
+        BLOCK,
+        UNBLOCK,
+        JOIN,
+        PART,
+        ROOM_SLOWMODE,
+        ROOMS,
+        ROOM_RETENTION,
+        ROOM_MAXLENGTH,
+        ROOM_TRANSLATE,
+        ROOM_INFO,
+        ROOM_TOPIC,
+        ROOM_PRIVATE,
+        ROOM_INVITE,
+        ROOM_ARCHIVE,
+        ANNOUNCE,
+        EXPORT_ROOM,
+        SERVER_INFO,
+        DEBUG,
+        TRUST,
+        DOCTOR,
+        HIGHLIGHT,
+        FILTER,
+        QUIET,
+        SHOW,
         QUIT,
     ];
 
@@ -157,26 +336,125 @@ pub mod server {
 
     pub const LIST: Command = Command::new("/list").with_description("List all connected users");
 
-    pub const KICK: Command = Command::new("/kick")
+    pub const WHOIS: Command = Command::new("/whois")
+        .with_usage("<user>")
+        .with_description("Show a user's connection details, activity, rooms and client");
+
+    pub const SEEN: Command = Command::new("/seen")
         .with_usage("<user>")
-        .with_description("Kick a user from the server");
+        .with_description("Show when a user was last connected and last spoke");
+
+    pub const KICK: Command = Command::new("/kick")
+        .with_usage("<user> [--for <interval>]")
+        .with_description("Kick a user, optionally keeping them out for a while (e.g. --for 10m)");
+
+    pub const KICKALL: Command = Command::new("/kickall")
+        .with_usage("[--room <room>]")
+        .with_description("Kick every user, or everyone in a room (asks for confirmation)");
+
+    pub const MUTEALL: Command = Command::new("/muteall")
+        .with_usage("<room> <interval|off>")
+        .with_description(
+            "Silence everyone in a room for a while, e.g. 10m (asks for confirmation)",
+        );
+
+    pub const CLEAR: Command = Command::new("/clear")
+        .with_usage("<room>")
+        .with_description("Delete a room's stored history (asks for confirmation)");
 
     pub const RENAME: Command = Command::new("/rename")
         .with_usage("<user> <newname>")
         .with_description("Rename a user");
 
     pub const BAN: Command = Command::new("/ban")
-        .with_usage("<user|ip>")
-        .with_description("Ban a user by name or IP address");
+        .with_usage("<user|ip|cidr|fp:id> [--for <interval>]")
+        .with_description("Ban a user, IP, subnet or client fingerprint, for good or for a while");
 
     pub const UNBAN: Command = Command::new("/unban")
-        .with_usage("<ip>")
-        .with_description("Unban an IP address");
+        .with_usage("<ip|cidr|fp:id>")
+        .with_description("Lift a ban on an IP, subnet or client fingerprint");
 
     pub const BANLIST: Command = Command::new("/banlist").with_description("List all banned IPs");
 
+    pub const ANNOUNCE: Command = Command::new("/announce")
+        .with_usage(
+            "[--room <room>] [--at HH:MM] [--every <interval>] <message> | --list | --cancel <id>",
+        )
+        .with_description("Broadcast an announcement now or on a schedule");
+
+    pub const SAY: Command = Command::new("/say")
+        .with_usage("<room> <message>")
+        .with_description("Speak in a room as the server identity");
+
+    pub const SHELL: Command = Command::new("/shell")
+        .with_usage("\"<command>\" --to <room> [--as <name>] | --list | --stop <id>")
+        .with_description("Post each line a command prints to a room until it exits or is stopped");
+
+    pub const STATS: Command = Command::new("/stats")
+        .with_usage("[--graph [interval]]")
+        .with_description("Show server statistics and bandwidth usage, or graph them over time");
+
+    pub const CHANNEL: Command = Command::new("/channel")
+        .with_usage("stats | resize <capacity>")
+        .with_description(
+            "Show broadcast channel use and which connections fall behind it, or resize it live",
+        );
+
+    pub const PRIVACY: Command = Command::new("/privacy")
+        .with_usage("[on|off]")
+        .with_description("Log only who sent messages where (on), or their text too (off)");
+
+    pub const DRAIN: Command = Command::new("/drain")
+        .with_usage("[minutes|cancel]")
+        .with_description("Stop accepting connections and shut down when drained");
+
+    pub const MAINTENANCE: Command = Command::new("/maintenance")
+        .with_usage("[set <HH:MM|interval> <duration> <text>|clear]")
+        .with_description("Announce planned downtime and drain when it starts");
+
+    pub const REGISTER: Command = Command::new("/register")
+        .with_usage("<user> <password>")
+        .with_description("Register a nickname so only its owner can use it");
+
+    pub const UNREGISTER: Command = Command::new("/unregister")
+        .with_usage("<user>")
+        .with_description("Release a registered nickname");
+
+    pub const ACCOUNTS: Command =
+        Command::new("/accounts").with_description("List registered nicknames");
+
+    pub const TOKEN: Command = Command::new("/token")
+        .with_usage("[create <bot> [--scopes send,read] [--rooms <room,...>]|revoke <bot>]")
+        .with_description("Create, revoke or list the tokens bots log in with");
+
     /// All server commands
-    pub const ALL: &[Command] = &[LIST, KICK, RENAME, BAN, UNBAN, BANLIST, HELP, QUIT];
+    pub const ALL: &[Command] = &[
+        LIST,
+        WHOIS,
+        SEEN,
+        STATS,
+        CHANNEL,
+        KICK,
+        KICKALL,
+        MUTEALL,
+        CLEAR,
+        RENAME,
+        BAN,
+        UNBAN,
+        BANLIST,
+        REGISTER,
+        UNREGISTER,
+        ACCOUNTS,
+        TOKEN,
+        ANNOUNCE,
+        SAY,
+        SHELL,
+        PRIVACY,
+        DRAIN,
+        MAINTENANCE,
+        HELP,
+        QUIT,
+    ];
 
     /// Get all command names for completion (includes aliases)
     pub fn completion_names() -> Vec<&'static str> {
@@ -205,7 +483,23 @@ mod tests {
         assert!(names.contains(&"/status"));
         assert!(names.contains(&"/accept"));
         assert!(names.contains(&"/reject"));
-        assert_eq!(names.len(), 10); // 10 commands, no aliases
+        assert!(names.contains(&"/join"));
+        assert!(names.contains(&"/part"));
+        assert!(names.contains(&"/room"));
+        assert!(names.contains(&"/block"));
+        assert!(names.contains(&"/unblock"));
+        assert!(names.contains(&"/rooms"));
+        assert!(names.contains(&"/debug"));
+        assert!(names.contains(&"/server"));
+        assert!(names.contains(&"/last"));
+        assert!(names.contains(&"/trust"));
+        assert!(names.contains(&"/export-room"));
+        assert!(names.contains(&"/split"));
+        assert!(names.contains(&"/seen"));
+        assert!(names.contains(&"/doctor"));
+        assert!(names.contains(&"/filter"));
+        assert!(names.contains(&"/quiet"));
+        assert_eq!(names.len(), 30); // 30 commands, no aliases
     }
 
     #[test]
@@ -216,7 +510,23 @@ mod tests {
         assert!(names.contains(&"/quit"));
         assert!(names.contains(&"/q"));
         assert!(names.contains(&"/ban"));
-        assert_eq!(names.len(), 10); // 8 commands + 2 aliases
+        assert!(names.contains(&"/announce"));
+        assert!(names.contains(&"/say"));
+        assert!(names.contains(&"/drain"));
+        assert!(names.contains(&"/stats"));
+        assert!(names.contains(&"/register"));
+        assert!(names.contains(&"/accounts"));
+        assert!(names.contains(&"/whois"));
+        assert!(names.contains(&"/kickall"));
+        assert!(names.contains(&"/muteall"));
+        assert!(names.contains(&"/clear"));
+        assert!(names.contains(&"/shell"));
+        assert!(names.contains(&"/seen"));
+        assert!(names.contains(&"/channel"));
+        assert!(names.contains(&"/privacy"));
+        assert!(names.contains(&"/maintenance"));
+        assert!(names.contains(&"/token"));
+        assert_eq!(names.len(), 27); // 25 commands + 2 aliases
     }
 
     #[test]
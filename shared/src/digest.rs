@@ -0,0 +1,162 @@
+//! Welcome-back digest a registered user gets when they log in: what happened while
+//! they were away, sent before any live messages.
+//!
+//! Encoded as `away_secs|direct_messages|mentions|room:count,room:count`.
+
+use std::time::Duration;
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Digest {
+    /// How long since they were last connected
+    pub away: Duration,
+    /// Direct messages held for them while offline (delivered right after the digest)
+    pub direct_messages: usize,
+    /// Room messages that mention them
+    pub mentions: usize,
+    /// Rooms with new messages, busiest first
+    pub rooms: Vec<(String, usize)>,
+}
+
+/// Whether `text` mentions `name` as a whole word (case-insensitive, "@name" works too)
+pub fn mentions(text: &str, name: &str) -> bool {
+    if name.is_empty() {
+        return false;
+    }
+    let name = name.to_lowercase();
+    text.to_lowercase()
+        .split(|c: char| !(c.is_alphanumeric() || c == '_' || c == '-'))
+        .any(|word| word == name)
+}
+
+fn plural(count: usize, what: &str) -> String {
+    format!("{} {}{}", count, what, if count == 1 { "" } else { "s" })
+}
+
+/// `3d 4h`, `2h 15m` or `5m` - the two largest units are plenty here
+fn away_for(away: Duration) -> String {
+    let secs = away.as_secs();
+    let units = [
+        (secs / 86400, "d"),
+        (secs % 86400 / 3600, "h"),
+        (secs % 3600 / 60, "m"),
+    ];
+    let parts: Vec<String> = units
+        .iter()
+        .skip_while(|(value, _)| *value == 0)
+        .take(2)
+        .filter(|(value, _)| *value > 0)
+        .map(|(value, unit)| format!("{}{}", value, unit))
+        .collect();
+    if parts.is_empty() {
+        "under a minute".to_string()
+    } else {
+        parts.join(" ")
+    }
+}
+
+impl Digest {
+    /// Nothing happened while they were away
+    pub fn is_empty(&self) -> bool {
+        self.direct_messages == 0 && self.mentions == 0 && self.rooms.is_empty()
+    }
+
+    pub fn encode(&self) -> String {
+        let rooms: Vec<String> = self
+            .rooms
+            .iter()
+            .map(|(room, count)| format!("{}:{}", room, count))
+            .collect();
+        format!(
+            "{}|{}|{}|{}",
+            self.away.as_secs(),
+            self.direct_messages,
+            self.mentions,
+            rooms.join(",")
+        )
+    }
+
+    pub fn decode(content: &str) -> Option<Self> {
+        let mut fields = content.splitn(4, '|');
+        let away = Duration::from_secs(fields.next()?.parse().ok()?);
+        let direct_messages = fields.next()?.parse().ok()?;
+        let mentions = fields.next()?.parse().ok()?;
+        let rooms = fields
+            .next()?
+            .split(',')
+            .filter(|room| !room.is_empty())
+            .map(|room| {
+                let (name, count) = room.rsplit_once(':')?;
+                Some((name.to_string(), count.parse().ok()?))
+            })
+            .collect::<Option<Vec<_>>>()?;
+        Some(Digest {
+            away,
+            direct_messages,
+            mentions,
+            rooms,
+        })
+    }
+
+    /// One line for the terminal, e.g. `While you were away (2h 15m): 3 direct
+    /// messages, 1 mention; new in #ops (12), #rust (2)`
+    pub fn summary(&self) -> String {
+        let mut line = format!("While you were away ({}): ", away_for(self.away));
+        if self.is_empty() {
+            line.push_str("nothing new");
+            return line;
+        }
+        line.push_str(&format!(
+            "{}, {}",
+            plural(self.direct_messages, "direct message"),
+            plural(self.mentions, "mention")
+        ));
+        if !self.rooms.is_empty() {
+            let rooms: Vec<String> = self
+                .rooms
+                .iter()
+                .map(|(room, count)| format!("#{} ({})", room, count))
+                .collect();
+            line.push_str(&format!("; new in {}", rooms.join(", ")));
+        }
+        line
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mentions_whole_words_only() {
+        assert!(mentions("hey alice, lunch?", "Alice"));
+        assert!(mentions("@alice ping", "alice"));
+        assert!(mentions("thanks alice!", "alice"));
+        assert!(!mentions("alicent is here", "alice"));
+        assert!(!mentions("malice", "alice"));
+        assert!(!mentions("anything", ""));
+    }
+
+    #[test]
+    fn test_digest_round_trip() {
+        let digest = Digest {
+            away: Duration::from_secs(2 * 3600 + 15 * 60 + 9),
+            direct_messages: 3,
+            mentions: 1,
+            rooms: vec![("ops".to_string(), 12), ("rust".to_string(), 2)],
+        };
+        assert_eq!(digest.encode(), "8109|3|1|ops:12,rust:2");
+        assert_eq!(Digest::decode(&digest.encode()), Some(digest.clone()));
+        assert_eq!(
+            digest.summary(),
+            "While you were away (2h 15m): 3 direct messages, 1 mention; new in #ops (12), #rust (2)"
+        );
+
+        let quiet = Digest {
+            away: Duration::from_secs(3 * 86400 + 40),
+            ..Digest::default()
+        };
+        assert_eq!(Digest::decode(&quiet.encode()), Some(quiet.clone()));
+        assert_eq!(quiet.summary(), "While you were away (3d): nothing new");
+        assert_eq!(Digest::decode("soon|1|2|"), None);
+    }
+}
@@ -0,0 +1,190 @@
+//! Why the server closed a connection. Whenever it disconnects a client it sends a
+//! Disconnect frame first, `<code>|<retry after>|<text>`, so the client can say why
+//! without matching on wording and knows whether reconnecting makes sense - after a
+//! shutdown it does, after a kick it doesn't. A kick or ban that ends says when, in
+//! seconds, so a client can come back once it's over. The text is optional: when an
+//! Error frame already explained a refusal it's left empty.
+
+use crate::message::{ChatMessage, ChatMessageError, MessageTypes};
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisconnectReason {
+    Kicked,
+    Banned,
+    /// The server is shutting down or restarting
+    Shutdown,
+    /// Nothing heard from the client for too long
+    IdleTimeout,
+    /// The client sent something no client should
+    ProtocolViolation,
+    /// The server and its waiting room are full
+    ServerFull,
+    /// The connection was turned down while joining (the Error before it says why)
+    Refused,
+    /// A guest's nickname was taken back by its registered owner
+    NicknameReclaimed,
+}
+
+impl DisconnectReason {
+    pub const ALL: [DisconnectReason; 8] = [
+        DisconnectReason::Kicked,
+        DisconnectReason::Banned,
+        DisconnectReason::Shutdown,
+        DisconnectReason::IdleTimeout,
+        DisconnectReason::ProtocolViolation,
+        DisconnectReason::ServerFull,
+        DisconnectReason::Refused,
+        DisconnectReason::NicknameReclaimed,
+    ];
+
+    /// Stable code sent on the wire
+    pub fn code(&self) -> u16 {
+        match self {
+            DisconnectReason::Kicked => 1,
+            DisconnectReason::Banned => 2,
+            DisconnectReason::Shutdown => 3,
+            DisconnectReason::IdleTimeout => 4,
+            DisconnectReason::ProtocolViolation => 5,
+            DisconnectReason::ServerFull => 6,
+            DisconnectReason::Refused => 7,
+            DisconnectReason::NicknameReclaimed => 8,
+        }
+    }
+
+    pub fn from_code(code: u16) -> Option<Self> {
+        DisconnectReason::ALL
+            .into_iter()
+            .find(|reason| reason.code() == code)
+    }
+
+    /// Name used in JSON events
+    pub fn name(&self) -> &'static str {
+        match self {
+            DisconnectReason::Kicked => "kicked",
+            DisconnectReason::Banned => "banned",
+            DisconnectReason::Shutdown => "shutdown",
+            DisconnectReason::IdleTimeout => "idle_timeout",
+            DisconnectReason::ProtocolViolation => "protocol_violation",
+            DisconnectReason::ServerFull => "server_full",
+            DisconnectReason::Refused => "refused",
+            DisconnectReason::NicknameReclaimed => "nickname_reclaimed",
+        }
+    }
+
+    /// What to tell the user when the server sent no text
+    pub fn describe(&self) -> &'static str {
+        match self {
+            DisconnectReason::Kicked => "You have been kicked by the server",
+            DisconnectReason::Banned => "You have been banned from the server",
+            DisconnectReason::Shutdown => "The server is shutting down",
+            DisconnectReason::IdleTimeout => {
+                "The server heard nothing from this client for too long"
+            }
+            DisconnectReason::ProtocolViolation => "The server received a frame it doesn't allow",
+            DisconnectReason::ServerFull => "The server is full",
+            DisconnectReason::Refused => "The server refused the connection",
+            DisconnectReason::NicknameReclaimed => "Your nickname was reclaimed by its owner",
+        }
+    }
+
+    /// Whether a client should try connecting again (after its usual backoff)
+    pub fn reconnects(&self) -> bool {
+        matches!(
+            self,
+            DisconnectReason::Shutdown
+                | DisconnectReason::IdleTimeout
+                | DisconnectReason::ServerFull
+        )
+    }
+}
+
+/// A Disconnect frame: `<code>|<retry after>|<text>`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Disconnect {
+    pub reason: DisconnectReason,
+    /// How long until the server lets the client back after a kick or ban, when it
+    /// will (None = it didn't say, or won't)
+    pub retry_after: Option<Duration>,
+    /// Shown to the user (may be empty)
+    pub text: String,
+}
+
+impl Disconnect {
+    pub fn new(reason: DisconnectReason, text: &str) -> Self {
+        Disconnect {
+            reason,
+            retry_after: None,
+            text: text.to_string(),
+        }
+    }
+
+    pub fn with_retry_after(mut self, retry_after: Option<Duration>) -> Self {
+        self.retry_after = retry_after;
+        self
+    }
+
+    pub fn encode(&self) -> String {
+        let retry_after = self
+            .retry_after
+            .map(|wait| wait.as_secs().to_string())
+            .unwrap_or_default();
+        format!("{}|{}|{}", self.reason.code(), retry_after, self.text)
+    }
+
+    /// None if the code is unknown
+    pub fn decode(content: &str) -> Option<Self> {
+        let mut fields = content.splitn(3, '|');
+        let reason = DisconnectReason::from_code(fields.next()?.parse().ok()?)?;
+        let retry_after = fields
+            .next()
+            .and_then(|secs| secs.parse().ok())
+            .map(Duration::from_secs);
+        Some(Disconnect {
+            reason,
+            retry_after,
+            text: fields.next().unwrap_or_default().to_string(),
+        })
+    }
+
+    pub fn to_message(&self) -> Result<ChatMessage, ChatMessageError> {
+        ChatMessage::try_new(MessageTypes::Disconnect, Some(self.encode().into_bytes()))
+    }
+
+    /// The text, or a description of the reason when the server sent none
+    pub fn text(&self) -> &str {
+        if self.text.is_empty() {
+            self.reason.describe()
+        } else {
+            &self.text
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        for reason in DisconnectReason::ALL {
+            let disconnect = Disconnect::new(reason, "bye | now")
+                .with_retry_after(Some(Duration::from_secs(600)));
+            let message = disconnect.to_message().unwrap();
+            assert_eq!(
+                Disconnect::decode(&message.content_as_string().unwrap()),
+                Some(disconnect)
+            );
+        }
+        let shutdown = Disconnect::decode("3||").unwrap();
+        assert_eq!(shutdown.reason, DisconnectReason::Shutdown);
+        assert_eq!(shutdown.retry_after, None);
+        assert_eq!(shutdown.text(), "The server is shutting down");
+        assert_eq!(Disconnect::decode("99||gone"), None);
+        assert_eq!(Disconnect::decode("kicked"), None);
+
+        assert!(DisconnectReason::Shutdown.reconnects());
+        assert!(!DisconnectReason::Kicked.reconnects());
+        assert!(!DisconnectReason::ProtocolViolation.reconnects());
+    }
+}
@@ -0,0 +1,227 @@
+//! Errors shared by the client and server. Each kind has a stable numeric code; Error
+//! messages carry it on the wire ahead of their text (`<code>|<text>`), so a client can
+//! tell a kick from a ban or a taken nickname without matching on the wording.
+
+use crate::message::{ChatMessage, ChatMessageError, MessageTypes};
+use std::fmt;
+use std::io;
+use std::net::AddrParseError;
+
+#[derive(Debug)]
+pub enum ChatError {
+    IoError(io::Error),
+    /// The peer closed the connection
+    Disconnect,
+    /// A frame longer than the protocol allows (its claimed length)
+    OversizedFrame(usize),
+    InvalidAddress,
+    InvalidMessage,
+    /// The server's broadcast channel is closed
+    BroadcastError,
+    /// Client sent something it never should (e.g. a server-only message type)
+    ProtocolViolation(String),
+    ExplicitQuit,
+    /// A frame the connection's lifecycle state doesn't accept (e.g. a chat message
+    /// before joining)
+    OutOfState {
+        state: &'static str,
+        message: MessageTypes,
+    },
+    VersionMismatch,
+    /// No nickname could be assigned to a join
+    JoinError,
+    InvalidUsername,
+    UsernameTaken,
+    /// Nickname is still on its post-kick cooldown
+    KickCooldown,
+    /// Client fingerprint is banned
+    FingerprintBanned,
+    /// Wrong or late answer to the proof-of-work challenge
+    ChallengeFailed,
+    /// The chat and its waiting room are full
+    ServerFull,
+    /// Every slot guests may use is taken; the rest are kept for registered users
+    GuestsFull,
+    RateLimited,
+    Kicked,
+    Banned,
+    /// A guest's nickname was taken back by its registered owner
+    NicknameReclaimed,
+    UserNotFound,
+    /// Any other request the server turned down (the text says why)
+    Refused,
+}
+
+impl ChatError {
+    /// Stable code sent on the wire and used by clients to handle the error
+    pub fn code(&self) -> u16 {
+        match self {
+            ChatError::IoError(_) => 1,
+            ChatError::Disconnect => 2,
+            ChatError::OversizedFrame(_) => 3,
+            ChatError::InvalidAddress => 4,
+            ChatError::InvalidMessage => 5,
+            ChatError::BroadcastError => 6,
+            ChatError::ProtocolViolation(_) => 7,
+            ChatError::ExplicitQuit => 8,
+            ChatError::OutOfState { .. } => 9,
+            ChatError::VersionMismatch => 10,
+            ChatError::JoinError => 11,
+            ChatError::InvalidUsername => 12,
+            ChatError::UsernameTaken => 13,
+            ChatError::KickCooldown => 14,
+            ChatError::FingerprintBanned => 15,
+            ChatError::ChallengeFailed => 16,
+            ChatError::ServerFull => 17,
+            ChatError::GuestsFull => 18,
+            ChatError::RateLimited => 20,
+            ChatError::Kicked => 21,
+            ChatError::Banned => 22,
+            ChatError::NicknameReclaimed => 23,
+            ChatError::UserNotFound => 24,
+            ChatError::Refused => 25,
+        }
+    }
+
+    /// The error a code stands for, for codes a peer can send (not local I/O failures)
+    pub fn from_code(code: u16) -> Option<Self> {
+        Some(match code {
+            5 => ChatError::InvalidMessage,
+            // What broke the protocol is in the text
+            7 => ChatError::ProtocolViolation(String::new()),
+            // Which state and frame stay with the sender
+            9 => ChatError::OutOfState {
+                state: "unknown",
+                message: MessageTypes::Unknown(0),
+            },
+            10 => ChatError::VersionMismatch,
+            11 => ChatError::JoinError,
+            12 => ChatError::InvalidUsername,
+            13 => ChatError::UsernameTaken,
+            14 => ChatError::KickCooldown,
+            15 => ChatError::FingerprintBanned,
+            16 => ChatError::ChallengeFailed,
+            17 => ChatError::ServerFull,
+            18 => ChatError::GuestsFull,
+            20 => ChatError::RateLimited,
+            21 => ChatError::Kicked,
+            22 => ChatError::Banned,
+            23 => ChatError::NicknameReclaimed,
+            24 => ChatError::UserNotFound,
+            25 => ChatError::Refused,
+            _ => return None,
+        })
+    }
+
+    /// Error message telling the peer about this error, with `text` shown to the user
+    pub fn to_message(&self, text: &str) -> Result<ChatMessage, ChatError> {
+        let content = format!("{}|{}", self.code(), text);
+        Ok(ChatMessage::try_new(
+            MessageTypes::Error,
+            Some(content.into_bytes()),
+        )?)
+    }
+
+    /// Split an Error message's content into the error (None if the sender didn't
+    /// include a code, or it's one we don't know) and its text
+    pub fn parse(content: &str) -> (Option<Self>, &str) {
+        match content.split_once('|') {
+            Some((code, text)) if !code.is_empty() && code.bytes().all(|b| b.is_ascii_digit()) => {
+                (code.parse().ok().and_then(Self::from_code), text)
+            }
+            _ => (None, content),
+        }
+    }
+}
+
+impl fmt::Display for ChatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChatError::IoError(e) => write!(f, "IO Error: {}", e),
+            ChatError::Disconnect => write!(f, "Disconnected"),
+            ChatError::OversizedFrame(len) => {
+                write!(f, "Frame of {} bytes exceeds maximum size", len)
+            }
+            ChatError::InvalidAddress => write!(f, "Invalid address"),
+            ChatError::InvalidMessage => write!(f, "Invalid Message Error"),
+            ChatError::BroadcastError => write!(f, "Broadcast Error: channel closed"),
+            ChatError::ProtocolViolation(reason) => write!(f, "Protocol Violation: {}", reason),
+            ChatError::ExplicitQuit => write!(f, "User explicitly quit"),
+            ChatError::OutOfState { state, message } => {
+                write!(f, "{:?} not allowed in state {}", message, state)
+            }
+            ChatError::VersionMismatch => write!(f, "Client/Server version mismatch"),
+            ChatError::JoinError => write!(f, "Join Error: Username already taken"),
+            ChatError::InvalidUsername => write!(f, "Invalid username"),
+            ChatError::UsernameTaken => write!(f, "Username already taken"),
+            ChatError::KickCooldown => write!(f, "Nickname was kicked recently"),
+            ChatError::FingerprintBanned => write!(f, "Client fingerprint is banned"),
+            ChatError::ChallengeFailed => write!(f, "Proof-of-work challenge failed"),
+            ChatError::ServerFull => write!(f, "Server is full"),
+            ChatError::GuestsFull => write!(f, "Server is full for guests"),
+            ChatError::RateLimited => write!(f, "Rate limit exceeded"),
+            ChatError::Kicked => write!(f, "Kicked by the server"),
+            ChatError::Banned => write!(f, "Banned from the server"),
+            ChatError::NicknameReclaimed => write!(f, "Nickname reclaimed by its owner"),
+            ChatError::UserNotFound => write!(f, "User not found"),
+            ChatError::Refused => write!(f, "Request refused"),
+        }
+    }
+}
+
+impl std::error::Error for ChatError {}
+
+impl From<io::Error> for ChatError {
+    fn from(e: io::Error) -> Self {
+        if e.kind() == io::ErrorKind::UnexpectedEof {
+            ChatError::Disconnect
+        } else {
+            ChatError::IoError(e)
+        }
+    }
+}
+
+impl From<ChatMessageError> for ChatError {
+    fn from(_: ChatMessageError) -> Self {
+        ChatError::InvalidMessage
+    }
+}
+
+impl From<AddrParseError> for ChatError {
+    fn from(_: AddrParseError) -> Self {
+        ChatError::InvalidAddress
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_message_round_trip() {
+        let message = ChatError::Kicked
+            .to_message("You have been kicked")
+            .unwrap();
+        let content = message.content_as_string().unwrap();
+        assert_eq!(content, "21|You have been kicked");
+        let (error, text) = ChatError::parse(&content);
+        assert!(matches!(error, Some(ChatError::Kicked)));
+        assert_eq!(text, "You have been kicked");
+
+        // Errors from servers that don't send codes keep their whole text
+        let (error, text) = ChatError::parse("User 'a|b' not found");
+        assert!(error.is_none());
+        assert_eq!(text, "User 'a|b' not found");
+        // Unknown codes are still stripped from the text
+        let (error, text) = ChatError::parse("999|Something new");
+        assert!(error.is_none());
+        assert_eq!(text, "Something new");
+
+        // Codes a peer can send map back to the same error
+        for code in 0..100 {
+            if let Some(error) = ChatError::from_code(code) {
+                assert_eq!(error.code(), code);
+            }
+        }
+    }
+}
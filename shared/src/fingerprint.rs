@@ -0,0 +1,106 @@
+//! Client fingerprints: sent once per connection, after the version check, so the
+//! server can recognise a client that comes back under a new IP or nickname.
+//!
+//! Encoded as `client_version|capability_hash|lineage_hash`. The lineage is a random
+//! secret the client creates the first time it talks to a server and keeps reusing;
+//! only its hash is sent, and that hash is the fingerprint ID used in ban rules.
+
+use sha2::{Digest, Sha256};
+
+/// Hex characters in a capability or lineage hash
+pub const HASH_LENGTH: usize = 16;
+/// Longest client version string accepted
+pub const MAX_VERSION_LENGTH: usize = 64;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fingerprint {
+    /// e.g. "0.1.12 linux/x86_64"
+    pub client_version: String,
+    /// Hash of the features the client has enabled
+    pub capabilities: String,
+    /// Hash of the client's lineage secret
+    pub lineage: String,
+}
+
+impl Fingerprint {
+    pub fn new(client_version: &str, capabilities: &[&str], lineage_secret: &str) -> Self {
+        let mut capabilities = capabilities.to_vec();
+        capabilities.sort_unstable();
+        Fingerprint {
+            client_version: client_version.to_string(),
+            capabilities: short_hash(capabilities.join(",").as_bytes()),
+            lineage: short_hash(lineage_secret.as_bytes()),
+        }
+    }
+
+    /// ID used in logs and ban rules. It follows the lineage alone, so upgrading the
+    /// client or changing its settings doesn't shake off a ban.
+    pub fn id(&self) -> &str {
+        &self.lineage
+    }
+
+    pub fn encode(&self) -> String {
+        format!(
+            "{}|{}|{}",
+            self.client_version, self.capabilities, self.lineage
+        )
+    }
+
+    /// None if any field is malformed
+    pub fn decode(content: &str) -> Option<Self> {
+        let mut fields = content.split('|');
+        let fingerprint = Fingerprint {
+            client_version: fields.next()?.to_string(),
+            capabilities: fields.next()?.to_string(),
+            lineage: fields.next()?.to_string(),
+        };
+        let valid = fields.next().is_none()
+            && !fingerprint.client_version.is_empty()
+            && fingerprint.client_version.len() <= MAX_VERSION_LENGTH
+            && !fingerprint.client_version.chars().any(char::is_control)
+            && is_hash(&fingerprint.capabilities)
+            && is_hash(&fingerprint.lineage);
+        valid.then_some(fingerprint)
+    }
+}
+
+/// Whether `value` looks like a fingerprint ID (for `/ban fp:<id>`)
+pub fn is_hash(value: &str) -> bool {
+    value.len() == HASH_LENGTH && value.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+fn short_hash(data: &[u8]) -> String {
+    hex::encode(&Sha256::digest(data)[..HASH_LENGTH / 2])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fingerprint_round_trip() {
+        let fingerprint = Fingerprint::new("0.1.12 linux/x86_64", &["tls", "signing"], "secret");
+        assert_eq!(fingerprint.lineage.len(), HASH_LENGTH);
+        assert_eq!(fingerprint.id(), fingerprint.lineage);
+        assert_eq!(
+            Fingerprint::decode(&fingerprint.encode()),
+            Some(fingerprint.clone())
+        );
+
+        // Capability order doesn't matter; the lineage alone decides the ID
+        let reordered = Fingerprint::new("0.1.13 macos/aarch64", &["signing", "tls"], "secret");
+        assert_eq!(reordered.capabilities, fingerprint.capabilities);
+        assert_eq!(reordered.id(), fingerprint.id());
+        assert_ne!(
+            Fingerprint::new("0.1.12", &[], "other").id(),
+            fingerprint.id()
+        );
+
+        assert_eq!(Fingerprint::decode("0.1.12|abc|def"), None);
+        assert_eq!(
+            Fingerprint::decode(&format!("{}|extra", fingerprint.encode())),
+            None
+        );
+        assert!(!is_hash("not-a-hash"));
+    }
+}
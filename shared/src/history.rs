@@ -0,0 +1,108 @@
+//! Room history exchanged with HistoryRequest / HistoryResponse (/export-room).
+//!
+//! A request carries `room|limit` (limit 0 = as many messages as the server allows).
+//! The response echoes the room on its first line followed by one line per message,
+//! oldest first: `sent_at|sender|message` (sent_at in Unix seconds, message last so
+//! it may contain '|').
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HistoryLine {
+    pub sent_at: i64,
+    pub sender: String,
+    pub message: String,
+}
+
+impl HistoryLine {
+    pub fn encode(&self) -> String {
+        // A line per message, so a stray newline can't split one in two
+        format!(
+            "{}|{}|{}",
+            self.sent_at,
+            self.sender,
+            self.message.replace('\n', " ")
+        )
+    }
+
+    pub fn decode(line: &str) -> Option<Self> {
+        let mut fields = line.splitn(3, '|');
+        let sent_at = fields.next()?.parse().ok()?;
+        let sender = fields.next()?.to_string();
+        let message = fields.next()?.to_string();
+        Some(HistoryLine {
+            sent_at,
+            sender,
+            message,
+        })
+    }
+}
+
+pub fn encode_request(room: &str, limit: usize) -> String {
+    format!("{}|{}", room, limit)
+}
+
+/// Parse a HistoryRequest body into the room and the limit (0 = server maximum)
+pub fn decode_request(content: &str) -> Option<(String, usize)> {
+    match content.split_once('|') {
+        Some((room, limit)) => Some((room.to_string(), limit.parse().ok()?)),
+        None => Some((content.to_string(), 0)),
+    }
+}
+
+/// Build a HistoryResponse body for a room
+pub fn encode_response(room: &str, lines: &[HistoryLine]) -> String {
+    let mut content = room.to_string();
+    for line in lines {
+        content.push('\n');
+        content.push_str(&line.encode());
+    }
+    content
+}
+
+/// Parse a HistoryResponse body into the room and its messages
+pub fn decode_response(content: &str) -> Option<(String, Vec<HistoryLine>)> {
+    let mut lines = content.split('\n');
+    let room = lines.next()?.to_string();
+    let history = lines.map(HistoryLine::decode).collect::<Option<Vec<_>>>()?;
+    Some((room, history))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_history_round_trip() {
+        assert_eq!(
+            decode_request(&encode_request("ops", 50)),
+            Some(("ops".to_string(), 50))
+        );
+        assert_eq!(decode_request("ops"), Some(("ops".to_string(), 0)));
+        assert_eq!(decode_request("ops|lots"), None);
+
+        let lines = vec![
+            HistoryLine {
+                sent_at: 1_700_000_000,
+                sender: "alice".to_string(),
+                message: "deploy at 5 | ok?".to_string(),
+            },
+            HistoryLine {
+                sent_at: 1_700_000_060,
+                sender: "bob".to_string(),
+                message: "fine".to_string(),
+            },
+        ];
+        let content = encode_response("ops", &lines);
+        assert_eq!(decode_response(&content), Some(("ops".to_string(), lines)));
+        assert_eq!(
+            decode_response("ops"),
+            Some(("ops".to_string(), Vec::new()))
+        );
+
+        let multiline = HistoryLine {
+            sent_at: 0,
+            sender: "eve".to_string(),
+            message: "one\ntwo".to_string(),
+        };
+        assert_eq!(multiline.encode(), "0|eve|one two");
+    }
+}
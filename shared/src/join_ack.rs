@@ -0,0 +1,115 @@
+//! Join acknowledgement: the last thing the server sends while accepting a Join, and
+//! always before anything it relays, so the client knows it's in the chat and under
+//! which nickname without waiting for some broadcast to turn up.
+//!
+//! Encoded as `nickname|version|room|messages|window_ms|max_message_bytes|server info`
+//! (an empty room is the main chat, an empty size limit is none advertised; the server
+//! info comes last as its description may contain '|').
+
+use crate::limits::RateLimits;
+use crate::server_info::ServerInfo;
+use std::time::Duration;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct JoinAck {
+    /// Nickname the client joined under, which may not be the one it asked for
+    pub nickname: String,
+    /// Protocol version the server speaks
+    pub version: String,
+    /// Room the client starts in (None = the main chat)
+    pub room: Option<String>,
+    pub limits: RateLimits,
+    /// Server branding; its description is the message of the day
+    pub server: ServerInfo,
+}
+
+impl JoinAck {
+    pub fn encode(&self) -> String {
+        format!(
+            "{}|{}|{}|{}|{}|{}|{}",
+            self.nickname,
+            self.version,
+            self.room.as_deref().unwrap_or_default(),
+            self.limits.messages,
+            self.limits.window.as_millis(),
+            self.limits
+                .max_message
+                .map(|max| max.to_string())
+                .unwrap_or_default(),
+            self.server.encode()
+        )
+    }
+
+    pub fn decode(content: &str) -> Option<Self> {
+        let mut fields = content.splitn(7, '|');
+        let nickname = fields.next().filter(|name| !name.is_empty())?.to_string();
+        let version = fields.next()?.to_string();
+        let room = Some(fields.next()?)
+            .filter(|room| !room.is_empty())
+            .map(str::to_string);
+        let messages = fields
+            .next()?
+            .parse()
+            .ok()
+            .filter(|messages| *messages > 0)?;
+        let window_ms: u64 = fields.next()?.parse().ok().filter(|ms| *ms > 0)?;
+        let max_message = match fields.next()? {
+            "" => None,
+            max => Some(max.parse().ok().filter(|max| *max > 0)?),
+        };
+        let server = ServerInfo::decode(fields.next()?)?;
+        Some(JoinAck {
+            nickname,
+            version,
+            room,
+            limits: RateLimits {
+                messages,
+                window: Duration::from_millis(window_ms),
+                max_message,
+            },
+            server,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let ack = JoinAck {
+            nickname: "alice_2".to_string(),
+            version: "0.1.12".to_string(),
+            room: None,
+            limits: RateLimits {
+                messages: 10,
+                window: Duration::from_secs(1),
+                max_message: Some(1024),
+            },
+            server: ServerInfo {
+                name: "Miles Chat".to_string(),
+                network: String::new(),
+                version: "0.1.12".to_string(),
+                description: "General chat | be nice".to_string(),
+            },
+        };
+        assert_eq!(
+            ack.encode(),
+            "alice_2|0.1.12||10|1000|1024|Miles Chat||0.1.12|General chat | be nice"
+        );
+        assert_eq!(JoinAck::decode(&ack.encode()), Some(ack.clone()));
+
+        let in_room = JoinAck {
+            room: Some("lobby".to_string()),
+            limits: RateLimits {
+                max_message: None,
+                ..ack.limits
+            },
+            ..ack
+        };
+        assert_eq!(JoinAck::decode(&in_room.encode()), Some(in_room));
+        assert_eq!(JoinAck::decode("|0.1.12||10|1000||box|||"), None);
+        assert_eq!(JoinAck::decode("alice|0.1.12||10|1000|"), None);
+    }
+}
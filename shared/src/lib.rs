@@ -1,6 +1,28 @@
+pub mod challenge;
 pub mod commands;
+pub mod digest;
+pub mod disconnect;
+pub mod error;
+pub mod fingerprint;
+pub mod history;
 pub mod input;
+pub mod join_ack;
+pub mod limits;
 pub mod logger;
 pub mod message;
+pub mod name_check;
 pub mod network;
+pub mod parts;
+pub mod presence;
+pub mod rejection;
+pub mod room_keys;
+pub mod rooms;
+pub mod sender_class;
+pub mod server_info;
+pub mod signing;
+pub mod socket;
+pub mod tls;
+pub mod trace;
+pub mod translation;
 pub mod version;
+pub mod wrap;
@@ -0,0 +1,72 @@
+//! Limits a server advertises to clients after they join, so they can show how much
+//! of them is left before the server starts refusing messages.
+//!
+//! Encoded as `messages|window_ms|max_message_bytes` (older servers leave out the
+//! last field).
+
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimits {
+    /// Messages allowed per window
+    pub messages: usize,
+    pub window: Duration,
+    /// Longest message text the server accepts, in bytes (None = not advertised)
+    pub max_message: Option<usize>,
+}
+
+impl RateLimits {
+    pub fn encode(&self) -> String {
+        let mut content = format!("{}|{}", self.messages, self.window.as_millis());
+        if let Some(max_message) = self.max_message {
+            content.push_str(&format!("|{}", max_message));
+        }
+        content
+    }
+
+    pub fn decode(content: &str) -> Option<Self> {
+        let mut fields = content.split('|');
+        let messages = fields
+            .next()?
+            .parse()
+            .ok()
+            .filter(|messages| *messages > 0)?;
+        let window_ms: u64 = fields.next()?.parse().ok().filter(|ms| *ms > 0)?;
+        let max_message = match fields.next() {
+            Some(max) => Some(max.parse().ok().filter(|max| *max > 0)?),
+            None => None,
+        };
+        Some(RateLimits {
+            messages,
+            window: Duration::from_millis(window_ms),
+            max_message,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let limits = RateLimits {
+            messages: 10,
+            window: Duration::from_secs(1),
+            max_message: Some(1024),
+        };
+        assert_eq!(limits.encode(), "10|1000|1024");
+        assert_eq!(RateLimits::decode(&limits.encode()), Some(limits));
+        // Older servers don't advertise a message size
+        assert_eq!(
+            RateLimits::decode("10|1000"),
+            Some(RateLimits {
+                max_message: None,
+                ..limits
+            })
+        );
+        assert_eq!(RateLimits::decode("10|1000|big"), None);
+        assert_eq!(RateLimits::decode("0|1000"), None);
+        assert_eq!(RateLimits::decode("10"), None);
+    }
+}
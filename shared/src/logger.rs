@@ -1,75 +1,192 @@
+use crate::wrap;
 use chrono::Local;
 use colored::Colorize;
+use std::borrow::Cow;
 use std::collections::hash_map::DefaultHasher;
 use std::hash::{Hash, Hasher};
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicBool, Ordering};
+use unicode_width::UnicodeWidthStr;
+
+/// Off unless turned on by an interactive client (server logs end up in files and journals)
+static WRAPPING: AtomicBool = AtomicBool::new(false);
+/// Replaces stdout/stderr when set, e.g. by a line editor that redraws its prompt
+static OUTPUT: OnceLock<Box<dyn Fn(String) + Send + Sync>> = OnceLock::new();
+
+/// Wrap long lines at the terminal width with continuation lines indented under the
+/// message text
+pub fn enable_wrapping() {
+    WRAPPING.store(true, Ordering::Relaxed);
+}
+
+/// Send every log line (errors included) to `output` instead of stdout/stderr.
+/// Only the first call has any effect.
+pub fn set_output(output: impl Fn(String) + Send + Sync + 'static) {
+    let _ = OUTPUT.set(Box::new(output));
+}
+
+/// Show control characters in text from the network as `^[`-style caret notation
+/// (`<U+009B>` for the C1 range) instead of sending them to the terminal, where they
+/// could recolor, move the cursor or overwrite earlier lines. Tabs and line breaks
+/// are kept (lines after a break are indented under the message text).
+pub fn escape_control(text: &str) -> Cow<'_, str> {
+    if !text
+        .chars()
+        .any(|c| c.is_control() && c != '\n' && c != '\t')
+    {
+        return Cow::Borrowed(text);
+    }
+    let mut escaped = String::with_capacity(text.len() + 8);
+    for c in text.chars() {
+        match c {
+            '\n' | '\t' => escaped.push(c),
+            '\u{0}'..='\u{1f}' => {
+                escaped.push('^');
+                escaped.push(char::from(c as u8 + 0x40));
+            }
+            '\u{7f}' => escaped.push_str("^?"),
+            c if c.is_control() => escaped.push_str(&format!("<U+{:04X}>", u32::from(c))),
+            c => escaped.push(c),
+        }
+    }
+    Cow::Owned(escaped)
+}
 
 fn get_timestamp() -> String {
     Local::now().format("%H:%M:%S").to_string()
 }
 
+/// Wrap `body` for a line whose prefix (timestamp, tag, sender...) is made of `prefix`
+fn wrap_body(prefix: &[&str], body: &str) -> String {
+    let width = WRAPPING
+        .load(Ordering::Relaxed)
+        .then(wrap::terminal_width)
+        .flatten();
+    // Each prefix part is followed by a space
+    let indent = prefix.iter().map(|part| part.width() + 1).sum();
+    match width {
+        Some(width) => wrap::wrap(body, indent, width),
+        // Unwrapped, line breaks in the text still get the hanging indent, so no line
+        // of a message can pass for a line of its own
+        None => body.replace('\n', &format!("\n{}", " ".repeat(indent))),
+    }
+}
+
+fn print_line(line: String) {
+    match OUTPUT.get() {
+        Some(output) => output(line),
+        None => println!("{}", line),
+    }
+}
+
+fn eprint_line(line: String) {
+    match OUTPUT.get() {
+        Some(output) => output(line),
+        None => eprintln!("{}", line),
+    }
+}
+
+/// A line with the usual "[time] [TAG] message" layout
+fn tagged_line(tag: colored::ColoredString, message: &str) -> String {
+    let timestamp = format!("[{}]", get_timestamp());
+    let body = wrap_body(&[&timestamp, &tag], &escape_control(message));
+    format!("{} {} {}", timestamp.dimmed(), tag, body)
+}
+
 pub fn log_info(message: &str) {
-    println!(
-        "{} {} {}",
-        format!("[{}]", get_timestamp()).dimmed(),
-        "[INFO]".cyan().bold(),
-        message
-    );
+    print_line(tagged_line("[INFO]".cyan().bold(), message));
 }
 
 pub fn log_success(message: &str) {
-    println!(
-        "{} {} {}",
-        format!("[{}]", get_timestamp()).dimmed(),
-        "[OK]".green().bold(),
-        message
-    );
+    print_line(tagged_line("[OK]".green().bold(), message));
 }
 
 pub fn log_error(message: &str) {
-    eprintln!(
-        "{} {} {}",
-        format!("[{}]", get_timestamp()).dimmed(),
-        "[ERROR]".red().bold(),
-        message
-    );
+    eprint_line(tagged_line("[ERROR]".red().bold(), message));
 }
 
 pub fn log_warning(message: &str) {
-    println!(
-        "{} {} {}",
-        format!("[{}]", get_timestamp()).dimmed(),
-        "[WARN]".yellow().bold(),
-        message
-    );
+    print_line(tagged_line("[WARN]".yellow().bold(), message));
 }
 
 pub fn log_system(message: &str) {
-    println!(
-        "{} {} {}",
-        format!("[{}]", get_timestamp()).dimmed(),
-        "[SYSTEM]".magenta().bold(),
-        message
-    );
+    print_line(tagged_line("[SYSTEM]".magenta().bold(), message));
 }
 
 pub fn log_chat(message: &str) {
-    if let Some((username, msg)) = message.split_once(": ") {
-        let colored_username = colorize_username(username);
-        println!(
-            "{} {} {}: {}",
-            format!("[{}]", get_timestamp()).dimmed(),
-            "[CHAT]".white().bold(),
-            colored_username,
-            msg
-        );
+    print_line(chat_line(None, message, false));
+}
+
+pub fn log_room_chat(room: &str, message: &str) {
+    print_line(chat_line(Some(room), message, false));
+}
+
+/// A chat line that mentions us or one of our highlight words, with its text picked out
+pub fn log_highlighted_chat(room: Option<&str>, message: &str) {
+    print_line(chat_line(room, message, true));
+}
+
+/// "[time] [CHAT] #room sender: text", without the room outside rooms
+fn chat_line(room: Option<&str>, message: &str, highlighted: bool) -> String {
+    let message = &escape_control(message);
+    let timestamp = format!("[{}]", get_timestamp());
+    let room_tag = room.map(|room| format!("#{}", escape_control(room)));
+    let mut prefix: Vec<&str> = vec![&timestamp, "[CHAT]"];
+    prefix.extend(room_tag.as_deref());
+    let mut line = format!("{} {}", timestamp.dimmed(), "[CHAT]".white().bold());
+    if let Some(room_tag) = &room_tag {
+        line.push_str(&format!(" {}", room_tag.blue()));
+    }
+    let (sender, text) = match message.split_once(": ") {
+        Some((username, text)) => (Some(username), text),
+        None => (None, message.as_ref()),
+    };
+    let sender_tag = sender.map(|username| format!("{}:", username));
+    prefix.extend(sender_tag.as_deref());
+    let body = wrap_body(&prefix, text);
+    if let Some(username) = sender {
+        line.push_str(&format!(" {}:", colorize_username(username)));
+    }
+    if highlighted {
+        line.push_str(&format!(" {}", body.yellow().bold()));
     } else {
-        println!(
-            "{} {} {}",
-            format!("[{}]", get_timestamp()).dimmed(),
-            "[CHAT]".white().bold(),
-            message
-        );
+        line.push_str(&format!(" {}", body));
     }
+    line
+}
+
+pub fn log_announcement(message: &str) {
+    let timestamp = format!("[{}]", get_timestamp());
+    let body = wrap_body(&[&timestamp, "[ANNOUNCE]"], &escape_control(message));
+    print_line(format!(
+        "{} {} {}",
+        timestamp.dimmed(),
+        "[ANNOUNCE]".bright_blue().bold(),
+        body.bold()
+    ));
+}
+
+/// Message restored from a previous session (shown with its original timestamp)
+pub fn log_scrollback(timestamp: &str, message: &str) {
+    let timestamp = format!("[{}]", timestamp);
+    let body = wrap_body(&[&timestamp, "[PREV]"], &escape_control(message));
+    print_line(format!(
+        "{} {} {}",
+        timestamp.dimmed(),
+        "[PREV]".dimmed().bold(),
+        body.dimmed()
+    ));
+}
+
+/// A machine translation, under the message it translates
+pub fn log_translation(message: &str) {
+    let body = wrap_body(&["  ↳"], &escape_control(message));
+    print_line(format!("  {} {}", "↳".dimmed(), body.italic()));
+}
+
+/// A line of its own between messages, e.g. the date after a long gap
+pub fn log_divider(text: &str) {
+    print_line(escape_control(text).dimmed().to_string());
 }
 
 fn colorize_username(username: &str) -> colored::ColoredString {
@@ -95,3 +212,33 @@ fn colorize_username(username: &str) -> colored::ColoredString {
     let color_index = (hash as usize) % colors.len();
     username.color(colors[color_index]).bold()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_escape_control() {
+        assert!(matches!(escape_control("plain text"), Cow::Borrowed(_)));
+        assert_eq!(
+            escape_control("\x1b[2J\x1b[31mgotcha\r"),
+            "^[[2J^[[31mgotcha^M"
+        );
+        assert_eq!(escape_control("bell\x07 del\x7f"), "bell^G del^?");
+        assert_eq!(escape_control("csi \u{9b}31m"), "csi <U+009B>31m");
+        assert_eq!(escape_control("two\nlines\tand tab"), "two\nlines\tand tab");
+        assert_eq!(escape_control("héllo ✓"), "héllo ✓");
+    }
+
+    #[test]
+    fn test_line_breaks_are_indented_under_the_text() {
+        let forged = "hi\n[12:00:00] [SYSTEM] alice is now an operator";
+        assert_eq!(
+            wrap_body(&["[12:00:00]", "[CHAT]", "bob:"], forged),
+            format!(
+                "hi\n{}[12:00:00] [SYSTEM] alice is now an operator",
+                " ".repeat(23)
+            )
+        );
+    }
+}
@@ -17,6 +17,34 @@ pub enum MessageTypes {
     Pong,                 // Client response to Ping
     VersionCheck,         // Client sends version to server on connection: version string
     VersionMismatch, // Server responds with mismatch error: client_version|server_version|readme_url
+    JoinRoom,        // Join a room: room (broadcast as room|username)
+    LeaveRoom,       // Leave a room: room (broadcast as room|username)
+    RoomMessage, // Chat message scoped to a room: room|message (broadcast as room|sender|message)
+    Announcement, // Server announcement: room|sender|message (empty room = everyone)
+    Notice,      // Informational message from the server to one client: message
+    RateLimited, // Request refused by a limit: reason|retry_after_secs|message
+    RoomCommand, // Room moderation command: room|command|args (e.g. ops|slowmode|10)
+    BlockUser,   // Manage your server-side block list: block|user, unblock|user or list|
+    RoomInfoRequest, // Ask about a room without joining it: room (empty = all rooms)
+    RoomInfoResponse, // Room metadata: query, then one line per room (see shared::rooms)
+    ServerInfo,  // Server branding (see shared::server_info); empty from a client = request
+    Fingerprint, // Optional client fingerprint, sent after the version check (see shared::fingerprint)
+    Challenge, // Proof-of-work puzzle answering a Join: difficulty|nonce from the server, the answer from the client (see shared::challenge)
+    Register, // Guest claiming their current nickname: password from the client, empty reply = registered
+    WaitingRoom, // Server is full and the Join was queued: position in the queue (1 = next in)
+    RateLimits, // Message rate limit (see shared::limits); no longer sent, JoinAck carries it
+    HistoryRequest, // Ask for a room's stored history to export it: room|limit (see shared::history)
+    HistoryResponse, // A room's stored history: room, then one line per message (see shared::history)
+    Seen, // When a user was last connected and last spoke: username from the client, answered with a Notice
+    WelcomeBack, // What happened while a registered user was away, sent when they log in (see shared::digest)
+    SelfEcho, // "1" asks the server to send this connection's own messages back to it as well, "0" stops it
+    JoinAck, // Join accepted: nickname, version, room, limits and server info, before anything relayed (see shared::join_ack)
+    RoomAnnouncement, // A room moderator's announcement to their room: room|moderator|message
+    PresenceDelta, // Someone came, went, was renamed or set a status, after the ListUsers snapshot (see shared::presence)
+    RoomKey, // A private room's encryption: privacy changes, key rotation requests and sealed keys (see shared::room_keys)
+    NameCheck, // Is a nickname free, asked before joining: the nickname from the client, its status from the server (see shared::name_check)
+    Translation, // Machine translation of a room message, after it: room|sender|ref|lang|text (see shared::translation)
+    Disconnect, // Why the server is closing the connection, just before it does: code|text (see shared::disconnect)
     Unknown(u8),
 }
 
@@ -40,6 +68,34 @@ impl From<u8> for MessageTypes {
             15 => MessageTypes::Pong,
             16 => MessageTypes::VersionCheck,
             17 => MessageTypes::VersionMismatch,
+            18 => MessageTypes::JoinRoom,
+            19 => MessageTypes::LeaveRoom,
+            20 => MessageTypes::RoomMessage,
+            21 => MessageTypes::Announcement,
+            22 => MessageTypes::Notice,
+            23 => MessageTypes::RateLimited,
+            24 => MessageTypes::RoomCommand,
+            25 => MessageTypes::BlockUser,
+            26 => MessageTypes::RoomInfoRequest,
+            27 => MessageTypes::RoomInfoResponse,
+            28 => MessageTypes::ServerInfo,
+            29 => MessageTypes::Fingerprint,
+            30 => MessageTypes::Challenge,
+            31 => MessageTypes::Register,
+            32 => MessageTypes::WaitingRoom,
+            33 => MessageTypes::RateLimits,
+            34 => MessageTypes::HistoryRequest,
+            35 => MessageTypes::HistoryResponse,
+            36 => MessageTypes::Seen,
+            37 => MessageTypes::WelcomeBack,
+            38 => MessageTypes::SelfEcho,
+            39 => MessageTypes::JoinAck,
+            40 => MessageTypes::RoomAnnouncement,
+            41 => MessageTypes::PresenceDelta,
+            42 => MessageTypes::RoomKey,
+            43 => MessageTypes::NameCheck,
+            44 => MessageTypes::Translation,
+            45 => MessageTypes::Disconnect,
             other => MessageTypes::Unknown(other),
         }
     }
@@ -57,6 +113,11 @@ impl ChatMessage {
         self.content.as_deref()
     }
 
+    /// Size of the message on the wire (header + content), used for bandwidth accounting
+    pub fn wire_size(&self) -> usize {
+        5 + self.content.as_ref().map_or(0, |data| data.len())
+    }
+
     pub fn content_as_string(&self) -> Option<String> {
         self.content
             .as_ref()
@@ -123,11 +184,9 @@ impl From<Vec<u8>> for ChatMessage {
     }
 }
 
-impl From<ChatMessage> for Vec<u8> {
-    fn from(message: ChatMessage) -> Self {
-        let mut buffer = Vec::new();
-        buffer.extend_from_slice(&message.msg_len.to_be_bytes());
-        buffer.push(match message.msg_type {
+impl From<MessageTypes> for u8 {
+    fn from(msg_type: MessageTypes) -> Self {
+        match msg_type {
             MessageTypes::ChatMessage => 1,
             MessageTypes::Join => 2,
             MessageTypes::Leave => 3,
@@ -145,8 +204,44 @@ impl From<ChatMessage> for Vec<u8> {
             MessageTypes::Pong => 15,
             MessageTypes::VersionCheck => 16,
             MessageTypes::VersionMismatch => 17,
+            MessageTypes::JoinRoom => 18,
+            MessageTypes::LeaveRoom => 19,
+            MessageTypes::RoomMessage => 20,
+            MessageTypes::Announcement => 21,
+            MessageTypes::Notice => 22,
+            MessageTypes::RateLimited => 23,
+            MessageTypes::RoomCommand => 24,
+            MessageTypes::BlockUser => 25,
+            MessageTypes::RoomInfoRequest => 26,
+            MessageTypes::RoomInfoResponse => 27,
+            MessageTypes::ServerInfo => 28,
+            MessageTypes::Fingerprint => 29,
+            MessageTypes::Challenge => 30,
+            MessageTypes::Register => 31,
+            MessageTypes::WaitingRoom => 32,
+            MessageTypes::RateLimits => 33,
+            MessageTypes::HistoryRequest => 34,
+            MessageTypes::HistoryResponse => 35,
+            MessageTypes::Seen => 36,
+            MessageTypes::WelcomeBack => 37,
+            MessageTypes::SelfEcho => 38,
+            MessageTypes::JoinAck => 39,
+            MessageTypes::RoomAnnouncement => 40,
+            MessageTypes::PresenceDelta => 41,
+            MessageTypes::RoomKey => 42,
+            MessageTypes::NameCheck => 43,
+            MessageTypes::Translation => 44,
+            MessageTypes::Disconnect => 45,
             MessageTypes::Unknown(val) => val,
-        });
+        }
+    }
+}
+
+impl From<ChatMessage> for Vec<u8> {
+    fn from(message: ChatMessage) -> Self {
+        let mut buffer = Vec::new();
+        buffer.extend_from_slice(&message.msg_len.to_be_bytes());
+        buffer.push(message.msg_type.into());
         if let Some(content) = message.content {
             buffer.extend_from_slice(&content);
         }
@@ -224,6 +319,50 @@ mod tests {
         assert!(matches!(MessageTypes::from(5), MessageTypes::ListUsers));
         assert!(matches!(MessageTypes::from(6), MessageTypes::DirectMessage));
         assert!(matches!(MessageTypes::from(7), MessageTypes::Error));
+        assert!(matches!(MessageTypes::from(18), MessageTypes::JoinRoom));
+        assert!(matches!(MessageTypes::from(21), MessageTypes::Announcement));
+        assert!(matches!(MessageTypes::from(22), MessageTypes::Notice));
+        assert!(matches!(MessageTypes::from(23), MessageTypes::RateLimited));
+        assert!(matches!(MessageTypes::from(24), MessageTypes::RoomCommand));
+        assert!(matches!(MessageTypes::from(25), MessageTypes::BlockUser));
+        assert!(matches!(
+            MessageTypes::from(26),
+            MessageTypes::RoomInfoRequest
+        ));
+        assert!(matches!(
+            MessageTypes::from(27),
+            MessageTypes::RoomInfoResponse
+        ));
+        assert!(matches!(MessageTypes::from(28), MessageTypes::ServerInfo));
+        assert!(matches!(MessageTypes::from(29), MessageTypes::Fingerprint));
+        assert!(matches!(MessageTypes::from(30), MessageTypes::Challenge));
+        assert!(matches!(MessageTypes::from(31), MessageTypes::Register));
+        assert!(matches!(MessageTypes::from(32), MessageTypes::WaitingRoom));
+        assert!(matches!(MessageTypes::from(33), MessageTypes::RateLimits));
+        assert!(matches!(
+            MessageTypes::from(34),
+            MessageTypes::HistoryRequest
+        ));
+        assert!(matches!(
+            MessageTypes::from(35),
+            MessageTypes::HistoryResponse
+        ));
+        assert!(matches!(MessageTypes::from(36), MessageTypes::Seen));
+        assert!(matches!(MessageTypes::from(37), MessageTypes::WelcomeBack));
+        assert!(matches!(MessageTypes::from(38), MessageTypes::SelfEcho));
+        assert!(matches!(MessageTypes::from(39), MessageTypes::JoinAck));
+        assert!(matches!(
+            MessageTypes::from(40),
+            MessageTypes::RoomAnnouncement
+        ));
+        assert!(matches!(
+            MessageTypes::from(41),
+            MessageTypes::PresenceDelta
+        ));
+        assert!(matches!(MessageTypes::from(42), MessageTypes::RoomKey));
+        assert!(matches!(MessageTypes::from(43), MessageTypes::NameCheck));
+        assert!(matches!(MessageTypes::from(44), MessageTypes::Translation));
+        assert!(matches!(MessageTypes::from(45), MessageTypes::Disconnect));
         assert!(matches!(MessageTypes::from(99), MessageTypes::Unknown(99)));
     }
 
@@ -242,6 +381,14 @@ mod tests {
         assert!(matches!(msg.msg_type, MessageTypes::Unknown(0)));
     }
 
+    #[test]
+    fn test_wire_size() {
+        let msg = ChatMessage::try_new(MessageTypes::ChatMessage, Some(b"Test".to_vec())).unwrap();
+        assert_eq!(msg.wire_size(), 9);
+        let msg = ChatMessage::try_new(MessageTypes::ListUsers, None).unwrap();
+        assert_eq!(msg.wire_size(), 5);
+    }
+
     #[test]
     fn test_content_as_string_valid_utf8() {
         let msg =
@@ -0,0 +1,60 @@
+//! Nickname availability, asked during the handshake (after the version check,
+//! before joining) so a client can offer another nickname before anyone sees it
+//! come and go. The client sends the nickname; the server answers with one of
+//! `available`, `in-use`, `registered`, `reserved|<why>` or `invalid`.
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum NameStatus {
+    Available,
+    /// Someone is connected with it right now
+    InUse,
+    /// A registered nickname: joining with it needs its password
+    Registered,
+    /// Kept for the server or a bot (the text says why)
+    Reserved(String),
+    /// Too long, empty, or characters nicknames can't have
+    Invalid,
+}
+
+impl NameStatus {
+    pub fn encode(&self) -> String {
+        match self {
+            NameStatus::Available => "available".to_string(),
+            NameStatus::InUse => "in-use".to_string(),
+            NameStatus::Registered => "registered".to_string(),
+            NameStatus::Reserved(reason) => format!("reserved|{}", reason),
+            NameStatus::Invalid => "invalid".to_string(),
+        }
+    }
+
+    pub fn decode(content: &str) -> Option<Self> {
+        let (status, reason) = content.split_once('|').unwrap_or((content, ""));
+        match status {
+            "available" => Some(NameStatus::Available),
+            "in-use" => Some(NameStatus::InUse),
+            "registered" => Some(NameStatus::Registered),
+            "reserved" => Some(NameStatus::Reserved(reason.to_string())),
+            "invalid" => Some(NameStatus::Invalid),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        for status in [
+            NameStatus::Available,
+            NameStatus::InUse,
+            NameStatus::Registered,
+            NameStatus::Reserved("'srv:motd' is reserved for the server".to_string()),
+            NameStatus::Invalid,
+        ] {
+            assert_eq!(NameStatus::decode(&status.encode()), Some(status));
+        }
+        assert_eq!(NameStatus::decode("taken"), None);
+    }
+}
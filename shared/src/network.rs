@@ -1,22 +1,55 @@
-use crate::message::ChatMessage;
+use crate::error::ChatError;
+use crate::message::{ChatMessage, MessageTypes};
+use crate::trace::Tracer;
+use std::time::{Duration, Instant};
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 pub const CHUNK_SIZE: usize = 8192;
+/// Write buffer that fits a frame's length prefix with its first chunk, so a message
+/// goes out in one write (and one packet) rather than two
+pub const WRITE_BUFFER_SIZE: usize = 2 * CHUNK_SIZE;
 pub const MAX_MESSAGE_SIZE: usize = 8192; // 8KB max message size for regular messages
 pub const MAX_FILE_SIZE: usize = 100 * 1024 * 1024; // 100MB max file size
 
-pub enum TcpMessageHandlerError {
-    IoError(std::io::Error),
-    Disconnect,
-}
-
 #[allow(async_fn_in_trait)]
 pub trait TcpMessageHandler {
     type Stream: AsyncRead + AsyncWrite + Unpin;
     fn get_stream(&mut self) -> &mut Self::Stream;
 
+    /// Where to record frames when protocol tracing is on
+    fn tracer(&self) -> Option<&Tracer> {
+        None
+    }
+
+    /// Called with each frame's type as it's sent, e.g. to tie a rejection from the
+    /// peer back to what sent the frame
+    fn on_send(&mut self, _msg_type: MessageTypes) {}
+
+    /// How long a send may take, including the peer's OK (None = no limit)
+    fn write_timeout(&self) -> Option<Duration> {
+        None
+    }
+
     async fn send_message_chunked(&mut self, message: ChatMessage) -> Result<(), std::io::Error> {
+        let Some(limit) = self.write_timeout() else {
+            return self.send_frame(message).await;
+        };
+        tokio::time::timeout(limit, self.send_frame(message))
+            .await
+            .unwrap_or_else(|_| {
+                Err(std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    format!("No OK response after {}s", limit.as_secs_f64()),
+                ))
+            })
+    }
+
+    /// Send one frame and wait for the OK, without the write timeout
+    async fn send_frame(&mut self, message: ChatMessage) -> Result<(), std::io::Error> {
+        self.on_send(message.msg_type);
         let message_bytes: Vec<u8> = message.into();
+        let tracer = self.tracer().cloned();
+        let started = Instant::now();
 
         // Validate message size to prevent integer overflow
         let msg_len = u32::try_from(message_bytes.len()).map_err(|_| {
@@ -28,19 +61,38 @@ pub trait TcpMessageHandler {
 
         // Send the message in chunks
         let mut bytes_sent = 0;
+        let mut chunks = 0;
         while bytes_sent < message_bytes.len() {
             let chunk_size = std::cmp::min(CHUNK_SIZE, message_bytes.len() - bytes_sent);
             let chunk = &message_bytes[bytes_sent..bytes_sent + chunk_size];
 
             self.get_stream().write_all(chunk).await?;
             bytes_sent += chunk_size;
+            chunks += 1;
         }
 
         self.get_stream().flush().await?;
 
         // Wait for OK response (2 bytes: "OK")
         let mut ok_response = [0u8; 2];
-        self.get_stream().read_exact(&mut ok_response).await?;
+        if let Err(e) = self.get_stream().read_exact(&mut ok_response).await {
+            if let Some(tracer) = &tracer {
+                tracer.event(&format!("SEND waiting for OK failed: {}", e));
+            }
+            return Err(e);
+        }
+
+        if let Some(tracer) = &tracer {
+            let mut frame = msg_len.to_be_bytes().to_vec();
+            frame.extend_from_slice(&message_bytes);
+            tracer.sent(&frame, chunks, started.elapsed());
+            if &ok_response != b"OK" {
+                tracer.event(&format!(
+                    "SEND expected OK, got {:02x} {:02x}",
+                    ok_response[0], ok_response[1]
+                ));
+            }
+        }
 
         if &ok_response != b"OK" {
             return Err(std::io::Error::new(
@@ -52,68 +104,253 @@ pub trait TcpMessageHandler {
         Ok(())
     }
 
-    async fn read_message_chunked(&mut self) -> Result<ChatMessage, TcpMessageHandlerError> {
+    async fn read_message_chunked(&mut self) -> Result<ChatMessage, ChatError> {
+        let tracer = self.tracer().cloned();
+
         // Read the first 4 bytes to get the message length
         let mut len_bytes = [0u8; 4];
         self.get_stream()
             .read_exact(&mut len_bytes)
             .await
             .map_err(|e| {
-                if e.kind() == std::io::ErrorKind::UnexpectedEof {
-                    TcpMessageHandlerError::Disconnect
-                } else {
-                    TcpMessageHandlerError::IoError(e)
+                if let Some(tracer) = &tracer {
+                    tracer.event(&format!("RECV no frame: {}", e));
                 }
+                ChatError::from(e)
             })?;
 
         let msg_len = u32::from_be_bytes(len_bytes) as usize;
+        let started = Instant::now();
 
         // Peek at message type to determine max size (need to read it first)
         // For now, use MAX_FILE_SIZE as the upper bound
         if msg_len > MAX_FILE_SIZE {
-            return Err(TcpMessageHandlerError::IoError(std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                "Message exceeds maximum size",
-            )));
+            if let Some(tracer) = &tracer {
+                tracer.event(&format!(
+                    "RECV frame length {} exceeds maximum of {} bytes",
+                    msg_len, MAX_FILE_SIZE
+                ));
+            }
+            return Err(ChatError::OversizedFrame(msg_len));
         }
 
-        // Read the message in chunks to handle large messages
-        let mut message_bytes = Vec::with_capacity(msg_len);
+        // Read the message in chunks to handle large messages. The buffer grows as
+        // data arrives so a bogus length can't make us allocate 100MB up front.
+        let mut message_bytes = Vec::with_capacity(std::cmp::min(msg_len, CHUNK_SIZE));
         let mut bytes_read = 0;
+        let mut reads = Vec::new();
 
         while bytes_read < msg_len {
             let mut chunk = vec![0u8; std::cmp::min(CHUNK_SIZE, msg_len - bytes_read)];
-            let n = self
-                .get_stream()
-                .read(&mut chunk)
-                .await
-                .map_err(TcpMessageHandlerError::IoError)?;
+            let n = self.get_stream().read(&mut chunk).await?;
 
             if n == 0 {
-                return Err(TcpMessageHandlerError::Disconnect);
+                if let Some(tracer) = &tracer {
+                    tracer.event(&format!(
+                        "RECV connection closed after {} of {} bytes",
+                        bytes_read, msg_len
+                    ));
+                }
+                return Err(ChatError::Disconnect);
             }
 
             message_bytes.extend_from_slice(&chunk[..n]);
             bytes_read += n;
+            reads.push(n);
+        }
+
+        if let Some(tracer) = &tracer {
+            let mut frame = len_bytes.to_vec();
+            frame.extend_from_slice(&message_bytes);
+            tracer.received(&frame, &reads, started.elapsed());
         }
 
         // Send OK response to acknowledge receipt
-        self.get_stream().write_all(b"OK").await.map_err(|e| {
-            if e.kind() == std::io::ErrorKind::UnexpectedEof {
-                TcpMessageHandlerError::Disconnect
-            } else {
-                TcpMessageHandlerError::IoError(e)
-            }
-        })?;
-        self.get_stream().flush().await.map_err(|e| {
-            if e.kind() == std::io::ErrorKind::UnexpectedEof {
-                TcpMessageHandlerError::Disconnect
-            } else {
-                TcpMessageHandlerError::IoError(e)
-            }
-        })?;
+        self.get_stream().write_all(b"OK").await?;
+        self.get_stream().flush().await?;
         let message = ChatMessage::from(message_bytes);
 
         Ok(message)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use std::path::Path;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use tokio::io::ReadBuf;
+
+    /// Replays raw bytes as if they came from a peer and discards everything written,
+    /// noting the size of each write
+    struct ReplayStream {
+        input: Cursor<Vec<u8>>,
+        writes: Vec<usize>,
+    }
+
+    impl AsyncRead for ReplayStream {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            Pin::new(&mut self.get_mut().input).poll_read(cx, buf)
+        }
+    }
+
+    impl AsyncWrite for ReplayStream {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<std::io::Result<usize>> {
+            self.get_mut().writes.push(buf.len());
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    impl TcpMessageHandler for ReplayStream {
+        type Stream = Self;
+        fn get_stream(&mut self) -> &mut Self::Stream {
+            self
+        }
+    }
+
+    /// Peer that reads frames but never answers them
+    struct SilentPeer {
+        stream: tokio::io::DuplexStream,
+    }
+
+    impl TcpMessageHandler for SilentPeer {
+        type Stream = tokio::io::DuplexStream;
+        fn get_stream(&mut self) -> &mut Self::Stream {
+            &mut self.stream
+        }
+        fn write_timeout(&self) -> Option<Duration> {
+            Some(Duration::from_millis(50))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_send_times_out_without_ok() {
+        let (stream, _peer) = tokio::io::duplex(1024);
+        let mut handler = SilentPeer { stream };
+        let message = ChatMessage::try_new(
+            crate::message::MessageTypes::ChatMessage,
+            Some(b"hi".to_vec()),
+        )
+        .unwrap();
+        let error = handler.send_message_chunked(message).await.unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::TimedOut);
+    }
+
+    /// Buffered the way the client and server buffer their connections
+    struct BufferedPeer {
+        stream: tokio::io::BufStream<ReplayStream>,
+    }
+
+    impl TcpMessageHandler for BufferedPeer {
+        type Stream = tokio::io::BufStream<ReplayStream>;
+        fn get_stream(&mut self) -> &mut Self::Stream {
+            &mut self.stream
+        }
+    }
+
+    #[tokio::test]
+    async fn test_buffered_frame_is_one_write() {
+        let stream = ReplayStream {
+            input: Cursor::new(b"OKOK".to_vec()),
+            writes: Vec::new(),
+        };
+        let mut handler = BufferedPeer {
+            stream: tokio::io::BufStream::with_capacity(CHUNK_SIZE, WRITE_BUFFER_SIZE, stream),
+        };
+        let mut frames = Vec::new();
+        for size in [2, CHUNK_SIZE] {
+            let message = ChatMessage::try_new(
+                crate::message::MessageTypes::ChatMessage,
+                Some(vec![b'a'; size]),
+            )
+            .unwrap();
+            frames.push(4 + Vec::<u8>::from(message.clone()).len());
+            handler.send_message_chunked(message).await.unwrap();
+        }
+        // The length prefix goes out with the message, not on its own
+        assert_eq!(handler.stream.get_ref().writes, frames);
+    }
+
+    /// Read every message out of `data`, returning them and the error that ended the stream
+    async fn read_all(data: Vec<u8>) -> (Vec<ChatMessage>, ChatError) {
+        let mut stream = ReplayStream {
+            input: Cursor::new(data),
+            writes: Vec::new(),
+        };
+        let mut messages = Vec::new();
+        loop {
+            match stream.read_message_chunked().await {
+                Ok(message) => messages.push(message),
+                Err(e) => return (messages, e),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_oversized_frame_is_rejected() {
+        let data = ((MAX_FILE_SIZE + 1) as u32).to_be_bytes().to_vec();
+        let (messages, err) = read_all(data).await;
+        assert!(messages.is_empty());
+        assert!(matches!(err, ChatError::OversizedFrame(len) if len == MAX_FILE_SIZE + 1));
+    }
+
+    #[tokio::test]
+    async fn test_truncated_frame_is_a_disconnect() {
+        let mut data = 100u32.to_be_bytes().to_vec();
+        data.extend_from_slice(b"short");
+        let (messages, err) = read_all(data).await;
+        assert!(messages.is_empty());
+        assert!(matches!(err, ChatError::Disconnect));
+    }
+
+    #[tokio::test]
+    async fn test_frame_larger_than_u16() {
+        let content = vec![b'x'; u16::MAX as usize * 2];
+        let message = ChatMessage::try_new(
+            crate::message::MessageTypes::FileTransfer,
+            Some(content.clone()),
+        )
+        .unwrap();
+        let bytes: Vec<u8> = message.into();
+        let mut data = (bytes.len() as u32).to_be_bytes().to_vec();
+        data.extend_from_slice(&bytes);
+        let (messages, _) = read_all(data).await;
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].get_content(), Some(content.as_slice()));
+    }
+
+    /// Every file in the fuzzing corpus must be handled without panicking
+    #[tokio::test]
+    async fn test_fuzz_corpus_regressions() {
+        let corpus =
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("../fuzz/corpus/read_message_chunked");
+        let mut checked = 0;
+        for entry in std::fs::read_dir(&corpus).expect("fuzz corpus directory is missing") {
+            let data = std::fs::read(entry.unwrap().path()).unwrap();
+            let (messages, _) = read_all(data).await;
+            for message in messages {
+                let _ = message.content_as_string();
+            }
+            checked += 1;
+        }
+        assert!(checked > 0);
+    }
+}
@@ -0,0 +1,170 @@
+//! Messages longer than the server allows, sent as parts that receiving clients put
+//! back together and show as one message.
+//!
+//! Each part's text starts with a header, `<US>id:seq:more<US>` (US = ASCII unit
+//! separator): `id` is shared by the parts of one message, `seq` counts from 0 and
+//! `more` is 1 while further parts follow and 0 on the last. Clients that don't know
+//! about parts still show the text. A signature, if any, covers the whole message and
+//! rides on the last part.
+
+use std::collections::HashMap;
+
+/// Opens and closes a part header
+pub const PART_MARKER: char = '\u{1f}';
+
+/// Most parts one message may have (about 64 KB with the server's 1 KB limit)
+pub const MAX_PARTS: usize = 64;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Part<'a> {
+    pub id: &'a str,
+    pub seq: usize,
+    /// Further parts follow this one
+    pub more: bool,
+    pub text: &'a str,
+}
+
+fn header(id: &str, seq: usize, more: bool) -> String {
+    format!("{0}{1}:{2}:{3}{0}", PART_MARKER, id, seq, u8::from(more))
+}
+
+/// Split `text` into messages of at most `max` bytes. Text that already fits is
+/// returned as it is; otherwise each part gets a header and breaks after a space
+/// where possible. Returns None if it would take more than MAX_PARTS parts.
+pub fn split(text: &str, max: usize, id: &str) -> Option<Vec<String>> {
+    if text.len() <= max {
+        return Some(vec![text.to_string()]);
+    }
+    let mut parts = Vec::new();
+    let mut rest = text;
+    for seq in 0..MAX_PARTS {
+        let budget = max.saturating_sub(header(id, seq, true).len()).max(1);
+        if rest.len() <= budget {
+            parts.push(format!("{}{}", header(id, seq, false), rest));
+            return Some(parts);
+        }
+        // Longest prefix that fits without cutting a character in two
+        let mut end = budget;
+        while !rest.is_char_boundary(end) {
+            end -= 1;
+        }
+        if end == 0 {
+            end = rest.chars().next().map_or(rest.len(), char::len_utf8);
+        }
+        // Spaces stay with the part before them, so the text joins back exactly
+        let cut = rest[..end]
+            .rfind(char::is_whitespace)
+            .map(|at| at + 1)
+            .filter(|at| *at < end)
+            .unwrap_or(end);
+        parts.push(format!("{}{}", header(id, seq, true), &rest[..cut]));
+        rest = &rest[cut..];
+    }
+    None
+}
+
+/// The header of a part, None for a whole message
+pub fn parse(text: &str) -> Option<Part<'_>> {
+    let (header, text) = text.strip_prefix(PART_MARKER)?.split_once(PART_MARKER)?;
+    let mut fields = header.split(':');
+    let id = fields.next().filter(|id| !id.is_empty())?;
+    let seq = fields.next()?.parse().ok()?;
+    let more = match fields.next()? {
+        "1" => true,
+        "0" => false,
+        _ => return None,
+    };
+    Some(Part {
+        id,
+        seq,
+        more,
+        text,
+    })
+}
+
+#[derive(Debug)]
+struct Pending {
+    id: String,
+    next: usize,
+    text: String,
+}
+
+/// Parts received so far, per sender and scope (main chat, room or DM)
+#[derive(Debug, Default)]
+pub struct Reassembler {
+    pending: HashMap<(String, String), Pending>,
+}
+
+impl Reassembler {
+    /// What to show for a message from `sender` in `scope`: the text itself, nothing
+    /// while parts are still arriving, or the whole message once its last part is in.
+    /// A message whose parts don't all arrive in order is dropped.
+    pub fn push(&mut self, scope: &str, sender: &str, text: &str) -> Option<String> {
+        let key = (scope.to_string(), sender.to_string());
+        let Some(part) = parse(text) else {
+            // A whole message: anything unfinished from this sender was abandoned
+            self.pending.remove(&key);
+            return Some(text.to_string());
+        };
+        let mut pending = match self.pending.remove(&key) {
+            _ if part.seq == 0 => Pending {
+                id: part.id.to_string(),
+                next: 0,
+                text: String::new(),
+            },
+            Some(pending) if pending.id == part.id && pending.next == part.seq => pending,
+            _ => return None,
+        };
+        pending.text.push_str(part.text);
+        pending.next += 1;
+        if !part.more {
+            return Some(pending.text);
+        }
+        if pending.next < MAX_PARTS {
+            self.pending.insert(key, pending);
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_and_reassemble() {
+        assert_eq!(split("fits", 10, "a1"), Some(vec!["fits".to_string()]));
+
+        let text = "the quick brown fox jumps over the lazy dog";
+        let parts = split(text, 20, "a1").unwrap();
+        assert!(parts.len() > 1);
+        assert!(parts.iter().all(|part| part.len() <= 20));
+        let first = parse(&parts[0]).unwrap();
+        assert_eq!((first.id, first.seq, first.more), ("a1", 0, true));
+        assert!(!parse(parts.last().unwrap()).unwrap().more);
+
+        let mut parts_in = Reassembler::default();
+        for part in &parts[..parts.len() - 1] {
+            assert_eq!(parts_in.push("", "alice", part), None);
+        }
+        assert_eq!(
+            parts_in.push("", "alice", parts.last().unwrap()),
+            Some(text.to_string())
+        );
+        // Never inside a character
+        let accents = "é".repeat(30);
+        let parts = split(&accents, 16, "b2").unwrap();
+        let joined: String = parts.iter().map(|part| parse(part).unwrap().text).collect();
+        assert_eq!(joined, accents);
+
+        // A missing part drops the message; whole messages pass straight through
+        let parts = split(text, 20, "c3").unwrap();
+        assert_eq!(parts_in.push("#ops", "bob", &parts[0]), None);
+        assert_eq!(parts_in.push("#ops", "bob", &parts[2]), None);
+        assert_eq!(
+            parts_in.push("#ops", "bob", "hello"),
+            Some("hello".to_string())
+        );
+        assert_eq!(split(&"x".repeat(10_000), 20, "d4"), None);
+    }
+}
@@ -0,0 +1,210 @@
+//! Who is online, kept in sync without asking: while accepting a Join the server sends
+//! a snapshot of everyone (a ListUsers frame), then a PresenceDelta frame whenever
+//! someone comes, goes, changes nickname or sets a status.
+//!
+//! The snapshot has one `name` or `name - status` line per user. Deltas are encoded as
+//! `+|name|status` (joined), `-|name` (left), `>|old|new` (renamed) and
+//! `~|name|status` (status changed); an empty status is none.
+//!
+//! The renamed connection is also sent a UserRename frame, `old|new`, so it knows the
+//! nickname it had as well as the one it has now.
+
+use std::collections::BTreeMap;
+
+/// What the notice the server broadcasts for a rename says between the old nickname
+/// and the new one ("alice is now known as alicia", perhaps followed by why)
+pub const RENAME_NOTICE: &str = " is now known as ";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PresenceDelta {
+    Joined {
+        user: String,
+        status: Option<String>,
+    },
+    Left(String),
+    Renamed {
+        from: String,
+        to: String,
+    },
+    Status {
+        user: String,
+        status: Option<String>,
+    },
+}
+
+impl PresenceDelta {
+    pub fn encode(&self) -> String {
+        match self {
+            PresenceDelta::Joined { user, status } => {
+                format!("+|{}|{}", user, status.as_deref().unwrap_or_default())
+            }
+            PresenceDelta::Left(user) => format!("-|{}", user),
+            PresenceDelta::Renamed { from, to } => format!(">|{}|{}", from, to),
+            PresenceDelta::Status { user, status } => {
+                format!("~|{}|{}", user, status.as_deref().unwrap_or_default())
+            }
+        }
+    }
+
+    pub fn decode(content: &str) -> Option<Self> {
+        let mut fields = content.splitn(3, '|');
+        let kind = fields.next()?;
+        let user = fields.next().filter(|user| !user.is_empty())?.to_string();
+        let status = |status: Option<&str>| {
+            status
+                .filter(|status| !status.is_empty())
+                .map(str::to_string)
+        };
+        match kind {
+            "+" => Some(PresenceDelta::Joined {
+                user,
+                status: status(fields.next()),
+            }),
+            "-" => Some(PresenceDelta::Left(user)),
+            ">" => Some(PresenceDelta::Renamed {
+                from: user,
+                to: fields.next().filter(|to| !to.is_empty())?.to_string(),
+            }),
+            "~" => Some(PresenceDelta::Status {
+                user,
+                status: status(fields.next()),
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Content of a UserRename frame: the nickname a connection had, then its new one
+pub fn encode_rename(from: &str, to: &str) -> String {
+    format!("{}|{}", from, to)
+}
+
+pub fn decode_rename(content: &str) -> Option<(&str, &str)> {
+    content
+        .split_once('|')
+        .filter(|(from, to)| !from.is_empty() && !to.is_empty() && !to.contains('|'))
+}
+
+/// One line of a snapshot
+pub fn snapshot_line(user: &str, status: Option<&str>) -> String {
+    match status {
+        Some(status) => format!("{} - {}", user, status),
+        None => user.to_string(),
+    }
+}
+
+/// Everyone online and their status, as last heard from the server
+#[derive(Debug, Default)]
+pub struct Roster {
+    users: BTreeMap<String, Option<String>>,
+}
+
+impl Roster {
+    pub fn from_snapshot(content: &str) -> Self {
+        let users = content
+            .lines()
+            .filter(|line| !line.is_empty())
+            .map(|line| match line.split_once(" - ") {
+                Some((user, status)) => (user.to_string(), Some(status.to_string())),
+                None => (line.to_string(), None),
+            })
+            .collect();
+        Roster { users }
+    }
+
+    /// Bring the roster up to date. Deltas the roster already reflects (such as a
+    /// join that made it into the snapshot) change nothing.
+    pub fn apply(&mut self, delta: &PresenceDelta) {
+        match delta {
+            PresenceDelta::Joined { user, status } => {
+                self.users.insert(user.clone(), status.clone());
+            }
+            PresenceDelta::Left(user) => {
+                self.users.remove(user);
+            }
+            PresenceDelta::Renamed { from, to } => {
+                if let Some(status) = self.users.remove(from) {
+                    self.users.insert(to.clone(), status);
+                }
+            }
+            PresenceDelta::Status { user, status } => {
+                if let Some(current) = self.users.get_mut(user) {
+                    *current = status.clone();
+                }
+            }
+        }
+    }
+
+    /// Everyone online, by name, with their status
+    pub fn users(&self) -> impl Iterator<Item = (&str, Option<&str>)> {
+        self.users
+            .iter()
+            .map(|(user, status)| (user.as_str(), status.as_deref()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.users.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snapshot_then_deltas() {
+        for delta in [
+            PresenceDelta::Joined {
+                user: "carol".to_string(),
+                status: Some("on call | pager".to_string()),
+            },
+            PresenceDelta::Left("bob".to_string()),
+            PresenceDelta::Renamed {
+                from: "alice".to_string(),
+                to: "alicia".to_string(),
+            },
+            PresenceDelta::Status {
+                user: "alicia".to_string(),
+                status: None,
+            },
+        ] {
+            assert_eq!(PresenceDelta::decode(&delta.encode()), Some(delta));
+        }
+        assert_eq!(PresenceDelta::decode("?|alice"), None);
+        assert_eq!(PresenceDelta::decode(">|alice|"), None);
+        assert_eq!(
+            decode_rename(&encode_rename("alice", "alicia")),
+            Some(("alice", "alicia"))
+        );
+        assert_eq!(decode_rename("alicia"), None);
+
+        let snapshot = [
+            snapshot_line("alice", Some("away")),
+            snapshot_line("bob", None),
+        ];
+        let mut roster = Roster::from_snapshot(&snapshot.join("\n"));
+        assert_eq!(
+            roster.users().collect::<Vec<_>>(),
+            vec![("alice", Some("away")), ("bob", None)]
+        );
+        for delta in [
+            "+|carol|",
+            "+|carol|",
+            "-|bob",
+            ">|alice|alicia",
+            "~|carol|lunch",
+        ] {
+            roster.apply(&PresenceDelta::decode(delta).unwrap());
+        }
+        // A status for someone who isn't there doesn't add them
+        roster.apply(&PresenceDelta::decode("~|bob|back").unwrap());
+        assert_eq!(
+            roster.users().collect::<Vec<_>>(),
+            vec![("alicia", Some("away")), ("carol", Some("lunch"))]
+        );
+    }
+}
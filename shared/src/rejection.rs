@@ -0,0 +1,78 @@
+//! The server's answer to a frame it won't process: one the connection's state doesn't
+//! accept, one it can't parse, or one no client should send. It's an Error message
+//! whose text starts with the rejected frame's type, `<code>|<type>|<text>`, so a
+//! client can tell which of the frames it sent was turned down - and say which of its
+//! own commands sent it, rather than leave a bot's author guessing.
+
+use crate::error::ChatError;
+use crate::message::{ChatMessage, MessageTypes};
+
+#[derive(Debug, PartialEq)]
+pub struct Rejection {
+    /// Type of the frame the server rejected
+    pub frame: MessageTypes,
+    pub text: String,
+}
+
+impl Rejection {
+    pub fn new(frame: MessageTypes, text: &str) -> Self {
+        Self {
+            frame,
+            text: text.to_string(),
+        }
+    }
+
+    /// Errors that reject a single frame, and so carry a Rejection
+    pub fn rejects_frame(error: &ChatError) -> bool {
+        matches!(
+            error,
+            ChatError::InvalidMessage
+                | ChatError::ProtocolViolation(_)
+                | ChatError::OutOfState { .. }
+        )
+    }
+
+    pub fn to_message(&self, error: &ChatError) -> Result<ChatMessage, ChatError> {
+        error.to_message(&format!("{}|{}", u8::from(self.frame), self.text))
+    }
+
+    /// Read the text of an Error message (after its code)
+    pub fn decode(text: &str) -> Option<Self> {
+        let (frame, text) = text.split_once('|')?;
+        Some(Self::new(
+            MessageTypes::from(frame.parse::<u8>().ok()?),
+            text,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rejection_round_trip() {
+        let error = ChatError::OutOfState {
+            state: "Connected",
+            message: MessageTypes::ChatMessage,
+        };
+        assert!(Rejection::rejects_frame(&error));
+        assert!(!Rejection::rejects_frame(&ChatError::Kicked));
+        let message = Rejection::new(MessageTypes::ChatMessage, "Not joined | yet")
+            .to_message(&error)
+            .unwrap();
+        let content = message.content_as_string().unwrap();
+        assert_eq!(content, "9|1|Not joined | yet");
+
+        let (error, text) = ChatError::parse(&content);
+        assert!(matches!(error, Some(ChatError::OutOfState { .. })));
+        assert_eq!(
+            Rejection::decode(text),
+            Some(Rejection::new(
+                MessageTypes::ChatMessage,
+                "Not joined | yet"
+            ))
+        );
+        assert_eq!(Rejection::decode("no frame type"), None);
+    }
+}
@@ -0,0 +1,505 @@
+//! End-to-end encryption for private (invite-only) rooms. Each private room has a
+//! symmetric key the server never sees: one member, the keeper, makes it and seals a
+//! copy to every other member, and a new one is made whenever someone joins or leaves
+//! so nobody can read the room outside the time they were in it.
+//!
+//! RoomKey frames are `room|kind|...`:
+//! - `room|private|1` (or `0`): the room became (or stopped being) private, sent to
+//!   its members
+//! - `room|rotate|keeper|alice=<key>,bob=<key>`: the server asks the keeper for a new
+//!   key for these members (hex ed25519 public keys, the keeper's own included)
+//! - `room|key|to|from|sealed`: a key sealed to `to` by `from` (the keeper leaves
+//!   `from` empty and the server fills it in)
+//!
+//! Keys are sealed to a member's signing key (see shared::signing) in its X25519
+//! form: an ephemeral X25519 key agrees a secret with it, HKDF-SHA256 turns that into
+//! a ChaCha20-Poly1305 key, and the keeper signs the result so the server can't swap
+//! in a key of its own: `id:ephemeral:ciphertext<RS>signature` (hex).
+//!
+//! A message in a private room is `<GS>id:nonce:ciphertext` (hex, GS = ASCII group
+//! separator), where `id` names the key. The room, sender and key id are bound in as
+//! associated data, so a ciphertext can't be passed off as someone else's or moved to
+//! another room.
+
+use crate::signing::{self, SigningKey};
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use curve25519_dalek::montgomery::MontgomeryPoint;
+use ed25519_dalek::VerifyingKey;
+use hkdf::Hkdf;
+use sha2::Sha256;
+use std::collections::{HashMap, HashSet, VecDeque};
+
+/// Opens an encrypted message
+pub const ENCRYPTED_MARKER: char = '\u{1d}';
+
+/// Keys kept per room besides the current one, so messages sent just before a
+/// rotation can still be read
+const KEPT_KEYS: usize = 8;
+
+const SEAL_INFO: &[u8] = b"rust_chat room key";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RoomKeyFrame {
+    Private {
+        room: String,
+        on: bool,
+    },
+    Rotate {
+        room: String,
+        keeper: String,
+        /// Members and their hex public keys
+        members: Vec<(String, String)>,
+    },
+    Key {
+        room: String,
+        to: String,
+        from: String,
+        sealed: String,
+    },
+}
+
+impl RoomKeyFrame {
+    pub fn room(&self) -> &str {
+        match self {
+            RoomKeyFrame::Private { room, .. }
+            | RoomKeyFrame::Rotate { room, .. }
+            | RoomKeyFrame::Key { room, .. } => room,
+        }
+    }
+
+    pub fn encode(&self) -> String {
+        match self {
+            RoomKeyFrame::Private { room, on } => format!("{}|private|{}", room, u8::from(*on)),
+            RoomKeyFrame::Rotate {
+                room,
+                keeper,
+                members,
+            } => {
+                let members: Vec<String> = members
+                    .iter()
+                    .map(|(user, key)| format!("{}={}", user, key))
+                    .collect();
+                format!("{}|rotate|{}|{}", room, keeper, members.join(","))
+            }
+            RoomKeyFrame::Key {
+                room,
+                to,
+                from,
+                sealed,
+            } => format!("{}|key|{}|{}|{}", room, to, from, sealed),
+        }
+    }
+
+    pub fn decode(content: &str) -> Option<Self> {
+        let mut fields = content.splitn(3, '|');
+        let room = fields.next().filter(|room| !room.is_empty())?.to_string();
+        let kind = fields.next()?;
+        let rest = fields.next()?;
+        match kind {
+            "private" => Some(RoomKeyFrame::Private {
+                room,
+                on: match rest {
+                    "1" => true,
+                    "0" => false,
+                    _ => return None,
+                },
+            }),
+            "rotate" => {
+                let (keeper, members) = rest.split_once('|')?;
+                let members = members
+                    .split(',')
+                    .filter(|member| !member.is_empty())
+                    .map(|member| {
+                        let (user, key) = member.split_once('=')?;
+                        Some((user.to_string(), key.to_string()))
+                    })
+                    .collect::<Option<Vec<_>>>()?;
+                Some(RoomKeyFrame::Rotate {
+                    room,
+                    keeper: keeper.to_string(),
+                    members,
+                })
+            }
+            "key" => {
+                let mut fields = rest.splitn(3, '|');
+                let to = fields.next().filter(|to| !to.is_empty())?.to_string();
+                let from = fields.next()?.to_string();
+                let sealed = fields.next()?.to_string();
+                Some(RoomKeyFrame::Key {
+                    room,
+                    to,
+                    from,
+                    sealed,
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Whether `key` is a hex ed25519 public key, as sent with a JoinRoom
+pub fn is_public_key(key: &str) -> bool {
+    montgomery(key).is_some()
+}
+
+fn montgomery(public_key: &str) -> Option<MontgomeryPoint> {
+    let bytes: [u8; 32] = hex::decode(public_key).ok()?.try_into().ok()?;
+    Some(VerifyingKey::from_bytes(&bytes).ok()?.to_montgomery())
+}
+
+/// Scope of the keeper's signature over a sealed key
+fn key_scope(room: &str, member: &str) -> String {
+    format!("key:{}:{}", room, member)
+}
+
+/// Cipher for sealing a key to a member, from the secret agreed with them
+fn seal_cipher(
+    shared: MontgomeryPoint,
+    ephemeral: &MontgomeryPoint,
+    member: &MontgomeryPoint,
+) -> Option<ChaCha20Poly1305> {
+    // A low-order public key would agree the same secret with everyone
+    if shared.to_bytes() == [0u8; 32] {
+        return None;
+    }
+    let mut salt = ephemeral.to_bytes().to_vec();
+    salt.extend_from_slice(&member.to_bytes());
+    let mut key = [0u8; 32];
+    Hkdf::<Sha256>::new(Some(&salt), &shared.to_bytes())
+        .expand(SEAL_INFO, &mut key)
+        .ok()?;
+    Some(ChaCha20Poly1305::new(Key::from_slice(&key)))
+}
+
+fn associated_data(room: &str, user: &str, id: &str) -> Vec<u8> {
+    format!("{}\n{}\n{}", room, user, id).into_bytes()
+}
+
+/// A room's symmetric key and the id messages name it by
+#[derive(Clone, PartialEq)]
+pub struct RoomKey {
+    pub id: String,
+    key: [u8; 32],
+}
+
+impl std::fmt::Debug for RoomKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "RoomKey({})", self.id)
+    }
+}
+
+impl RoomKey {
+    pub fn generate() -> Self {
+        let mut id = [0u8; 4];
+        OsRng.fill_bytes(&mut id);
+        let mut key = [0u8; 32];
+        OsRng.fill_bytes(&mut key);
+        RoomKey {
+            id: hex::encode(id),
+            key,
+        }
+    }
+
+    fn cipher(&self) -> ChaCha20Poly1305 {
+        ChaCha20Poly1305::new(Key::from_slice(&self.key))
+    }
+
+    /// Encrypt a message `sender` is sending to `room`
+    pub fn encrypt(&self, room: &str, sender: &str, text: &str) -> String {
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher()
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: text.as_bytes(),
+                    aad: &associated_data(room, sender, &self.id),
+                },
+            )
+            .expect("encrypting in memory can't fail");
+        format!(
+            "{}{}:{}:{}",
+            ENCRYPTED_MARKER,
+            self.id,
+            hex::encode(nonce),
+            hex::encode(ciphertext)
+        )
+    }
+
+    fn decrypt(&self, room: &str, sender: &str, nonce: &[u8], ciphertext: &[u8]) -> Option<String> {
+        let plaintext = self
+            .cipher()
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: &associated_data(room, sender, &self.id),
+                },
+            )
+            .ok()?;
+        String::from_utf8(plaintext).ok()
+    }
+
+    /// Seal this key to `member` (whose hex public key is `public_key`), signed by the
+    /// keeper. None if the public key isn't a valid one.
+    pub fn seal(
+        &self,
+        keeper_key: &SigningKey,
+        keeper: &str,
+        room: &str,
+        member: &str,
+        public_key: &str,
+    ) -> Option<String> {
+        let member_point = montgomery(public_key)?;
+        let mut secret = [0u8; 32];
+        OsRng.fill_bytes(&mut secret);
+        let ephemeral = MontgomeryPoint::mul_base_clamped(secret);
+        let cipher = seal_cipher(member_point.mul_clamped(secret), &ephemeral, &member_point)?;
+        let ciphertext = cipher
+            .encrypt(
+                &Nonce::default(),
+                Payload {
+                    msg: &self.key,
+                    aad: &associated_data(room, member, &self.id),
+                },
+            )
+            .ok()?;
+        let body = format!(
+            "{}:{}:{}",
+            self.id,
+            hex::encode(ephemeral.to_bytes()),
+            hex::encode(ciphertext)
+        );
+        let signature = signing::sign(keeper_key, keeper, &key_scope(room, member), &body);
+        Some(signing::attach(&body, &signature))
+    }
+
+    /// Open a key `keeper` sealed to us. Returns it with the keeper's hex public key
+    /// (to check against the one pinned for them), or None if it isn't signed by them
+    /// or wasn't sealed to our key.
+    pub fn open(
+        sealed: &str,
+        own_key: &SigningKey,
+        me: &str,
+        keeper: &str,
+        room: &str,
+    ) -> Option<(RoomKey, String)> {
+        let (body, trailer) = signing::split_signature(sealed);
+        let keeper_key = signing::verify(trailer?, keeper, &key_scope(room, me), body)?;
+        let mut fields = body.split(':');
+        let id = fields.next().filter(|id| !id.is_empty())?;
+        let ephemeral: [u8; 32] = hex::decode(fields.next()?).ok()?.try_into().ok()?;
+        let ciphertext = hex::decode(fields.next()?).ok()?;
+        let ephemeral = MontgomeryPoint(ephemeral);
+        let own_point = own_key.verifying_key().to_montgomery();
+        let cipher = seal_cipher(
+            ephemeral.mul_clamped(own_key.to_scalar_bytes()),
+            &ephemeral,
+            &own_point,
+        )?;
+        let key: [u8; 32] = cipher
+            .decrypt(
+                &Nonce::default(),
+                Payload {
+                    msg: &ciphertext,
+                    aad: &associated_data(room, me, id),
+                },
+            )
+            .ok()?
+            .try_into()
+            .ok()?;
+        Some((
+            RoomKey {
+                id: id.to_string(),
+                key,
+            },
+            keeper_key,
+        ))
+    }
+}
+
+/// Whether a message's text is encrypted
+pub fn is_encrypted(text: &str) -> bool {
+    text.starts_with(ENCRYPTED_MARKER)
+}
+
+#[derive(Debug, Default)]
+struct RoomKeys {
+    /// Newest last; the newest is the one we send with
+    keys: VecDeque<RoomKey>,
+}
+
+/// Which of our rooms are private, and the keys we've been given for them
+#[derive(Debug, Default)]
+pub struct Keyring {
+    private: HashSet<String>,
+    rooms: HashMap<String, RoomKeys>,
+}
+
+impl Keyring {
+    /// Note that a room became (or stopped being) private. Keys go when it stops.
+    pub fn set_private(&mut self, room: &str, on: bool) {
+        if on {
+            self.private.insert(room.to_string());
+        } else {
+            self.forget(room);
+        }
+    }
+
+    pub fn is_private(&self, room: &str) -> bool {
+        self.private.contains(room)
+    }
+
+    /// Start sending with a new key, keeping a few older ones to read with
+    pub fn install(&mut self, room: &str, key: RoomKey) {
+        let keys = &mut self.rooms.entry(room.to_string()).or_default().keys;
+        keys.retain(|kept| kept.id != key.id);
+        keys.push_back(key);
+        while keys.len() > KEPT_KEYS + 1 {
+            keys.pop_front();
+        }
+    }
+
+    /// The key messages to `room` are sent with
+    pub fn current(&self, room: &str) -> Option<&RoomKey> {
+        self.rooms.get(room)?.keys.back()
+    }
+
+    /// Decrypt a message `sender` sent to `room`. None if we don't have its key or it
+    /// doesn't open with it.
+    pub fn decrypt(&self, room: &str, sender: &str, text: &str) -> Option<String> {
+        let mut fields = text.strip_prefix(ENCRYPTED_MARKER)?.split(':');
+        let id = fields.next()?;
+        let nonce = hex::decode(fields.next()?).ok().filter(|n| n.len() == 12)?;
+        let ciphertext = hex::decode(fields.next()?).ok()?;
+        self.rooms
+            .get(room)?
+            .keys
+            .iter()
+            .find(|key| key.id == id)?
+            .decrypt(room, sender, &nonce, &ciphertext)
+    }
+
+    /// Drop a room we left
+    pub fn forget(&mut self, room: &str) {
+        self.private.remove(room);
+        self.rooms.remove(room);
+    }
+
+    /// Drop everything: after a reconnect the server says again which rooms are private
+    pub fn clear(&mut self) {
+        self.private.clear();
+        self.rooms.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(seed: u8) -> SigningKey {
+        SigningKey::from_bytes(&[seed; 32])
+    }
+
+    fn public(key: &SigningKey) -> String {
+        hex::encode(key.verifying_key().to_bytes())
+    }
+
+    #[test]
+    fn test_frames_round_trip() {
+        for frame in [
+            RoomKeyFrame::Private {
+                room: "ops".to_string(),
+                on: true,
+            },
+            RoomKeyFrame::Rotate {
+                room: "ops".to_string(),
+                keeper: "alice".to_string(),
+                members: vec![
+                    ("alice".to_string(), "aa".to_string()),
+                    ("bob".to_string(), "bb".to_string()),
+                ],
+            },
+            RoomKeyFrame::Key {
+                room: "ops".to_string(),
+                to: "bob".to_string(),
+                from: String::new(),
+                sealed: "id:ab:cd\u{1e}ef:01".to_string(),
+            },
+        ] {
+            assert_eq!(RoomKeyFrame::decode(&frame.encode()), Some(frame));
+        }
+        assert_eq!(RoomKeyFrame::decode("ops|private|yes"), None);
+        assert_eq!(RoomKeyFrame::decode("|private|1"), None);
+    }
+
+    #[test]
+    fn test_sealed_key_opens_for_its_member_only() {
+        let (alice, bob, mallory) = (key(1), key(2), key(3));
+        assert!(is_public_key(&public(&bob)));
+        assert!(!is_public_key("abcd"));
+
+        let room_key = RoomKey::generate();
+        let sealed = room_key
+            .seal(&alice, "alice", "ops", "bob", &public(&bob))
+            .unwrap();
+        let (opened, keeper_key) = RoomKey::open(&sealed, &bob, "bob", "alice", "ops").unwrap();
+        assert_eq!(opened, room_key);
+        assert_eq!(keeper_key, public(&alice));
+
+        // Not for another member, another room, or from anyone but the keeper
+        assert!(RoomKey::open(&sealed, &mallory, "bob", "alice", "ops").is_none());
+        assert!(RoomKey::open(&sealed, &bob, "bob", "alice", "dev").is_none());
+        assert!(RoomKey::open(&sealed, &bob, "bob", "mallory", "ops").is_none());
+        let forged = room_key
+            .seal(&mallory, "mallory", "ops", "bob", &public(&bob))
+            .unwrap();
+        let (_, forged_signature) = signing::split_signature(&forged);
+        let (body, _) = signing::split_signature(&sealed);
+        let swapped = signing::attach(body, forged_signature.unwrap());
+        assert!(RoomKey::open(&swapped, &bob, "bob", "alice", "ops").is_none());
+    }
+
+    #[test]
+    fn test_keyring_reads_recent_keys() {
+        let mut keyring = Keyring::default();
+        keyring.set_private("ops", true);
+        assert!(keyring.is_private("ops"));
+        assert!(keyring.current("ops").is_none());
+
+        let old = RoomKey::generate();
+        let sent_before = old.encrypt("ops", "alice", "deploy at 5");
+        keyring.install("ops", old);
+        keyring.install("ops", RoomKey::generate());
+        let sent_after = keyring
+            .current("ops")
+            .unwrap()
+            .encrypt("ops", "alice", "done");
+        assert!(is_encrypted(&sent_after));
+        assert!(!sent_after.contains("done"));
+
+        assert_eq!(
+            keyring.decrypt("ops", "alice", &sent_before).as_deref(),
+            Some("deploy at 5")
+        );
+        assert_eq!(
+            keyring.decrypt("ops", "alice", &sent_after).as_deref(),
+            Some("done")
+        );
+        // Bound to the sender and the room
+        assert!(keyring.decrypt("ops", "mallory", &sent_after).is_none());
+        assert!(keyring.decrypt("dev", "alice", &sent_after).is_none());
+
+        // Rotated out after enough new keys
+        for _ in 0..KEPT_KEYS {
+            keyring.install("ops", RoomKey::generate());
+        }
+        assert!(keyring.decrypt("ops", "alice", &sent_before).is_none());
+
+        keyring.set_private("ops", false);
+        assert!(!keyring.is_private("ops"));
+        assert!(keyring.current("ops").is_none());
+    }
+}
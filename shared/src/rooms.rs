@@ -0,0 +1,158 @@
+//! Room metadata exchanged with RoomInfoRequest / RoomInfoResponse.
+//!
+//! A request carries a room name, or nothing to ask about every room. The response
+//! echoes the request on its first line followed by one line per room:
+//! `name|members|created_at|slowmode_secs|max_length|translate_to|retention|private|archived|moderators|topic`
+//! (created_at in Unix seconds, max_length in bytes with 0 for the server's limit,
+//! translate_to a language code or empty, private and archived 1 or 0, moderators comma separated, topic last so it may
+//! contain '|'). Archived rooms are listed too; clients leave them out of the usual
+//! listing. Clients ask about each room they join to learn its max_length, and check
+//! messages against it before sending them.
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RoomSummary {
+    pub name: String,
+    pub members: usize,
+    pub created_at: i64,
+    /// 0 = slow mode off
+    pub slowmode_secs: u64,
+    /// Longest message the room takes, in bytes, not split into parts (0 = the
+    /// server's limit, and long messages may be sent in parts)
+    pub max_length: usize,
+    /// Language the room's messages are machine translated into (empty = none, see
+    /// shared::translation)
+    pub translate_to: String,
+    /// Human readable retention policy (e.g. "last 100 messages")
+    pub retention: String,
+    /// Invite-only and end-to-end encrypted (see shared::room_keys)
+    pub private: bool,
+    /// Read-only, kept for its history
+    pub archived: bool,
+    pub moderators: Vec<String>,
+    /// Empty = no topic
+    pub topic: String,
+}
+
+impl RoomSummary {
+    pub fn encode(&self) -> String {
+        format!(
+            "{}|{}|{}|{}|{}|{}|{}|{}|{}|{}|{}",
+            self.name,
+            self.members,
+            self.created_at,
+            self.slowmode_secs,
+            self.max_length,
+            self.translate_to,
+            self.retention,
+            u8::from(self.private),
+            u8::from(self.archived),
+            self.moderators.join(","),
+            self.topic
+        )
+    }
+
+    pub fn decode(line: &str) -> Option<Self> {
+        let mut fields = line.splitn(11, '|');
+        let name = fields.next()?.to_string();
+        let members = fields.next()?.parse().ok()?;
+        let created_at = fields.next()?.parse().ok()?;
+        let slowmode_secs = fields.next()?.parse().ok()?;
+        let max_length = fields.next()?.parse().ok()?;
+        let translate_to = fields.next()?.to_string();
+        let retention = fields.next()?.to_string();
+        let mut flag = || match fields.next()? {
+            "1" => Some(true),
+            "0" => Some(false),
+            _ => None,
+        };
+        let private = flag()?;
+        let archived = flag()?;
+        let moderators = fields
+            .next()?
+            .split(',')
+            .filter(|m| !m.is_empty())
+            .map(str::to_string)
+            .collect();
+        let topic = fields.next()?.to_string();
+        Some(RoomSummary {
+            name,
+            members,
+            created_at,
+            slowmode_secs,
+            max_length,
+            translate_to,
+            retention,
+            private,
+            archived,
+            moderators,
+            topic,
+        })
+    }
+}
+
+/// Build a RoomInfoResponse body for a request (room name, or empty for all rooms)
+pub fn encode_response(query: &str, rooms: &[RoomSummary]) -> String {
+    let mut content = query.to_string();
+    for room in rooms {
+        content.push('\n');
+        content.push_str(&room.encode());
+    }
+    content
+}
+
+/// Parse a RoomInfoResponse body into the original query and the rooms
+pub fn decode_response(content: &str) -> Option<(String, Vec<RoomSummary>)> {
+    let mut lines = content.split('\n');
+    let query = lines.next()?.to_string();
+    let rooms = lines.map(RoomSummary::decode).collect::<Option<Vec<_>>>()?;
+    Some((query, rooms))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn summary(name: &str, topic: &str) -> RoomSummary {
+        RoomSummary {
+            name: name.to_string(),
+            members: 3,
+            created_at: 1_700_000_000,
+            slowmode_secs: 10,
+            max_length: if name == "ops" { 200 } else { 0 },
+            translate_to: if name == "ops" { "fr" } else { "" }.to_string(),
+            retention: "last 100 messages".to_string(),
+            private: name == "ops",
+            archived: name == "dev",
+            moderators: vec!["alice".to_string(), "bob".to_string()],
+            topic: topic.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_summary_round_trip() {
+        let room = summary("ops", "Deploys | incidents");
+        assert_eq!(RoomSummary::decode(&room.encode()), Some(room));
+
+        let mut no_moderators = summary("dev", "");
+        no_moderators.moderators.clear();
+        assert_eq!(
+            RoomSummary::decode(&no_moderators.encode()),
+            Some(no_moderators)
+        );
+        assert_eq!(RoomSummary::decode("ops|three|0|0|0||off|0|0||"), None);
+        assert_eq!(RoomSummary::decode("ops|3|0|0|0||off|yes|0||"), None);
+        assert_eq!(RoomSummary::decode("ops|3|0|0|0||off|0|||"), None);
+        assert_eq!(RoomSummary::decode("ops|3|0|0|-1||off|0|0||"), None);
+    }
+
+    #[test]
+    fn test_response_round_trip() {
+        let rooms = vec![summary("dev", ""), summary("ops", "Deploys")];
+        let content = encode_response("", &rooms);
+        assert_eq!(decode_response(&content), Some((String::new(), rooms)));
+        assert_eq!(
+            decode_response("ops"),
+            Some(("ops".to_string(), Vec::new()))
+        );
+    }
+}
@@ -0,0 +1,91 @@
+//! Who a relayed message is from, as the server sees it: a user, a bot (`bot:`
+//! names), the server speaking (its identity, `srv:` names, the console) or a system
+//! notice it generated (renames, refusals and the like). Clients style and hide each
+//! class on its own, and since only the server writes the class, a user can't dress
+//! their text up as a notice.
+//!
+//! The server puts the class in front of the content of every ChatMessage,
+//! RoomMessage, DirectMessage, Announcement, RoomAnnouncement and Notice it sends:
+//! `<GS>class<GS>content` (GS = ASCII group separator). What a client sends never
+//! starts the content, so it can't carry a class of its own.
+
+/// Opens and closes the class
+pub const CLASS_MARKER: char = '\u{1d}';
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SenderClass {
+    User,
+    Bot,
+    /// The server itself: its identity, `srv:` names and the console
+    Server,
+    /// Generated by the server rather than said by anyone
+    Notice,
+}
+
+impl SenderClass {
+    pub const ALL: [SenderClass; 4] = [
+        SenderClass::User,
+        SenderClass::Bot,
+        SenderClass::Server,
+        SenderClass::Notice,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            SenderClass::User => "user",
+            SenderClass::Bot => "bot",
+            SenderClass::Server => "server",
+            SenderClass::Notice => "notice",
+        }
+    }
+
+    /// "bot" or "bots", in any case
+    pub fn parse(value: &str) -> Option<Self> {
+        let value = value.to_lowercase();
+        let value = value.strip_suffix('s').unwrap_or(&value);
+        match value {
+            "user" => Some(SenderClass::User),
+            "bot" => Some(SenderClass::Bot),
+            "server" => Some(SenderClass::Server),
+            "notice" | "system" => Some(SenderClass::Notice),
+            _ => None,
+        }
+    }
+}
+
+/// `content` with `class` in front
+pub fn tag(class: SenderClass, content: &str) -> String {
+    format!("{0}{1}{0}{2}", CLASS_MARKER, class.name(), content)
+}
+
+/// The class in front of `content` (None if it has none) and the content after it
+pub fn untag(content: &str) -> (Option<SenderClass>, &str) {
+    content
+        .strip_prefix(CLASS_MARKER)
+        .and_then(|rest| rest.split_once(CLASS_MARKER))
+        .and_then(|(class, rest)| Some((Some(SenderClass::parse(class)?), rest)))
+        .unwrap_or((None, content))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tag_untag() {
+        for class in SenderClass::ALL {
+            let tagged = tag(class, "ops|alice|hi");
+            assert_eq!(untag(&tagged), (Some(class), "ops|alice|hi"));
+        }
+        assert_eq!(untag("alice: hi"), (None, "alice: hi"));
+        // Only a class in front counts
+        let text = format!("alice: {}", tag(SenderClass::Notice, "hi"));
+        assert_eq!(untag(&text), (None, text.as_str()));
+        let unknown = format!("{0}root{0}hi", CLASS_MARKER);
+        assert_eq!(untag(&unknown), (None, unknown.as_str()));
+
+        assert_eq!(SenderClass::parse("Bots"), Some(SenderClass::Bot));
+        assert_eq!(SenderClass::parse("system"), Some(SenderClass::Notice));
+        assert_eq!(SenderClass::parse("robots"), None);
+    }
+}
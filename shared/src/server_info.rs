@@ -0,0 +1,62 @@
+//! Server branding sent to clients when they join and on `/server info`.
+//!
+//! Encoded as `name|network|version|description` (description last so it may contain '|').
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServerInfo {
+    pub name: String,
+    /// Network the server belongs to (empty = standalone server)
+    pub network: String,
+    pub version: String,
+    /// Empty = no description
+    pub description: String,
+}
+
+impl ServerInfo {
+    pub fn encode(&self) -> String {
+        format!(
+            "{}|{}|{}|{}",
+            self.name, self.network, self.version, self.description
+        )
+    }
+
+    pub fn decode(content: &str) -> Option<Self> {
+        let mut fields = content.splitn(4, '|');
+        Some(ServerInfo {
+            name: fields.next()?.to_string(),
+            network: fields.next()?.to_string(),
+            version: fields.next()?.to_string(),
+            description: fields.next()?.to_string(),
+        })
+    }
+
+    /// "name" or "name (network)"
+    pub fn display_name(&self) -> String {
+        if self.network.is_empty() {
+            self.name.clone()
+        } else {
+            format!("{} ({})", self.name, self.network)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let info = ServerInfo {
+            name: "Miles Chat".to_string(),
+            network: "rustnet".to_string(),
+            version: "0.1.12".to_string(),
+            description: "General chat | be nice".to_string(),
+        };
+        assert_eq!(ServerInfo::decode(&info.encode()), Some(info.clone()));
+        assert_eq!(info.display_name(), "Miles Chat (rustnet)");
+
+        let standalone = ServerInfo::decode("box|||").unwrap();
+        assert_eq!(standalone.display_name(), "box");
+        assert_eq!(ServerInfo::decode("box|net"), None);
+    }
+}
@@ -0,0 +1,119 @@
+//! Optional ed25519 message signing so clients can tell a user's real messages
+//! from someone else using the same nickname.
+//!
+//! A signed message carries a trailer after the text: `text<RS>public_key:signature`
+//! (both hex encoded, RS = ASCII record separator). The signature covers the sender's
+//! name, the scope (main chat, room or DM recipient) and the text, so it can't be
+//! replayed under another name or in another room.
+
+pub use ed25519_dalek::SigningKey;
+use ed25519_dalek::{Signature, Signer, Verifier, VerifyingKey};
+
+/// Separates the message text from the signature trailer
+pub const SIGNATURE_SEPARATOR: char = '\u{1e}';
+
+/// Scope for messages in the main chat
+pub const MAIN_CHAT_SCOPE: &str = "";
+
+/// Scope for messages in a room
+pub fn room_scope(room: &str) -> String {
+    format!("#{}", room)
+}
+
+/// Scope for a direct message
+pub fn dm_scope(recipient: &str) -> String {
+    format!("dm:{}", recipient)
+}
+
+fn payload(sender: &str, scope: &str, message: &str) -> Vec<u8> {
+    format!("{}\n{}\n{}", sender, scope, message).into_bytes()
+}
+
+/// Sign a message, returning the trailer to append after SIGNATURE_SEPARATOR
+pub fn sign(key: &SigningKey, sender: &str, scope: &str, message: &str) -> String {
+    let signature = key.sign(&payload(sender, scope, message));
+    format!(
+        "{}:{}",
+        hex::encode(key.verifying_key().to_bytes()),
+        hex::encode(signature.to_bytes())
+    )
+}
+
+/// Append a signature trailer to a message
+pub fn attach(message: &str, trailer: &str) -> String {
+    format!("{}{}{}", message, SIGNATURE_SEPARATOR, trailer)
+}
+
+/// Split off the signature trailer, if any
+pub fn split_signature(content: &str) -> (&str, Option<&str>) {
+    match content.rsplit_once(SIGNATURE_SEPARATOR) {
+        Some((message, trailer)) => (message, Some(trailer)),
+        None => (content, None),
+    }
+}
+
+fn decode(trailer: &str) -> Option<(VerifyingKey, Signature)> {
+    let (public_key, signature) = trailer.split_once(':')?;
+    let public_key: [u8; 32] = hex::decode(public_key).ok()?.try_into().ok()?;
+    let signature: [u8; 64] = hex::decode(signature).ok()?.try_into().ok()?;
+    Some((
+        VerifyingKey::from_bytes(&public_key).ok()?,
+        Signature::from_bytes(&signature),
+    ))
+}
+
+/// Whether a trailer looks like a signature (the server drops anything else)
+pub fn is_well_formed(trailer: &str) -> bool {
+    decode(trailer).is_some()
+}
+
+/// Check a signature. Returns the signer's hex public key if it is valid.
+pub fn verify(trailer: &str, sender: &str, scope: &str, message: &str) -> Option<String> {
+    let (public_key, signature) = decode(trailer)?;
+    public_key
+        .verify(&payload(sender, scope, message), &signature)
+        .ok()?;
+    Some(hex::encode(public_key.to_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(seed: u8) -> SigningKey {
+        SigningKey::from_bytes(&[seed; 32])
+    }
+
+    #[test]
+    fn test_sign_and_verify() {
+        let key = key(1);
+        let trailer = sign(&key, "alice", MAIN_CHAT_SCOPE, "hello");
+        let content = attach("hello", &trailer);
+        let (message, trailer) = split_signature(&content);
+        assert_eq!(message, "hello");
+        let trailer = trailer.unwrap();
+        assert!(is_well_formed(trailer));
+        assert_eq!(
+            verify(trailer, "alice", MAIN_CHAT_SCOPE, "hello"),
+            Some(hex::encode(key.verifying_key().to_bytes()))
+        );
+    }
+
+    #[test]
+    fn test_signature_is_bound_to_sender_scope_and_text() {
+        let trailer = sign(&key(2), "alice", &room_scope("ops"), "deploy now");
+        assert!(verify(&trailer, "alice", &room_scope("ops"), "deploy now").is_some());
+        assert!(verify(&trailer, "mallory", &room_scope("ops"), "deploy now").is_none());
+        assert!(verify(&trailer, "alice", &room_scope("dev"), "deploy now").is_none());
+        assert!(verify(&trailer, "alice", &dm_scope("bob"), "deploy now").is_none());
+        assert!(verify(&trailer, "alice", &room_scope("ops"), "deploy later").is_none());
+    }
+
+    #[test]
+    fn test_unsigned_and_malformed() {
+        assert_eq!(split_signature("plain text"), ("plain text", None));
+        assert!(!is_well_formed("nothex:nothex"));
+        assert!(!is_well_formed("abcd"));
+        assert!(verify("", "alice", "", "hello").is_none());
+    }
+}
@@ -0,0 +1,136 @@
+//! TCP tuning applied to every connection, by the server to the sockets it accepts
+//! and by the client to the one it opens: Nagle's algorithm, keepalive probes and
+//! the kernel's buffer sizes.
+
+use socket2::{SockRef, TcpKeepalive};
+use std::fmt;
+use std::io;
+use std::time::Duration;
+use tokio::net::TcpStream;
+
+/// Probing of a connection that has gone quiet, so NAT routers and firewalls keep it
+/// open and a peer that vanished is noticed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Keepalive {
+    /// Idle time before the first probe
+    pub idle: Duration,
+    /// Time between unanswered probes (None = the idle time again)
+    pub interval: Option<Duration>,
+    /// Unanswered probes before the connection is dropped (None = the system's default)
+    pub probes: Option<u32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SocketOptions {
+    /// Send each write at once instead of holding small ones back until the last is
+    /// acknowledged (TCP_NODELAY)
+    pub nodelay: bool,
+    pub keepalive: Option<Keepalive>,
+    /// Kernel receive buffer in bytes (None = the system's default)
+    pub recv_buffer: Option<usize>,
+    /// Kernel send buffer in bytes (None = the system's default)
+    pub send_buffer: Option<usize>,
+}
+
+impl Default for SocketOptions {
+    /// Chat is interactive, so Nagle's algorithm is off
+    fn default() -> Self {
+        SocketOptions {
+            nodelay: true,
+            keepalive: None,
+            recv_buffer: None,
+            send_buffer: None,
+        }
+    }
+}
+
+impl SocketOptions {
+    /// Set every option on the socket, stopping at the first the system refuses
+    pub fn apply(&self, stream: &TcpStream) -> io::Result<()> {
+        stream.set_nodelay(self.nodelay)?;
+        let socket = SockRef::from(stream);
+        if let Some(keepalive) = &self.keepalive {
+            let probes = TcpKeepalive::new().with_time(keepalive.idle);
+            #[cfg(any(target_os = "linux", target_os = "macos", windows))]
+            let probes = probes.with_interval(keepalive.interval.unwrap_or(keepalive.idle));
+            #[cfg(any(target_os = "linux", target_os = "macos"))]
+            let probes = match keepalive.probes {
+                Some(count) => probes.with_retries(count),
+                None => probes,
+            };
+            socket.set_tcp_keepalive(&probes)?;
+        }
+        if let Some(size) = self.recv_buffer {
+            socket.set_recv_buffer_size(size)?;
+        }
+        if let Some(size) = self.send_buffer {
+            socket.set_send_buffer_size(size)?;
+        }
+        Ok(())
+    }
+}
+
+/// e.g. "TCP_NODELAY on, keepalive after 60s every 10s (5 probes), receive buffer 256KB"
+impl fmt::Display for SocketOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "TCP_NODELAY {}", if self.nodelay { "on" } else { "off" })?;
+        match &self.keepalive {
+            Some(keepalive) => {
+                write!(f, ", keepalive after {}s", keepalive.idle.as_secs())?;
+                if let Some(interval) = keepalive.interval {
+                    write!(f, " every {}s", interval.as_secs())?;
+                }
+                if let Some(probes) = keepalive.probes {
+                    write!(f, " ({} probes)", probes)?;
+                }
+            }
+            None => write!(f, ", keepalive off")?,
+        }
+        if let Some(size) = self.recv_buffer {
+            write!(f, ", receive buffer {}KB", size / 1024)?;
+        }
+        if let Some(size) = self.send_buffer {
+            write!(f, ", send buffer {}KB", size / 1024)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_options_reach_the_socket() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+
+        SocketOptions::default().apply(&stream).unwrap();
+        assert!(stream.nodelay().unwrap());
+        assert!(!SockRef::from(&stream).keepalive().unwrap());
+
+        let options = SocketOptions {
+            nodelay: false,
+            keepalive: Some(Keepalive {
+                idle: Duration::from_secs(60),
+                interval: Some(Duration::from_secs(10)),
+                probes: Some(5),
+            }),
+            recv_buffer: Some(256 * 1024),
+            send_buffer: None,
+        };
+        options.apply(&stream).unwrap();
+        let socket = SockRef::from(&stream);
+        assert!(!stream.nodelay().unwrap());
+        assert!(socket.keepalive().unwrap());
+        // Linux doubles what it's asked for, to leave room for its bookkeeping
+        assert!(socket.recv_buffer_size().unwrap() >= 256 * 1024);
+        assert_eq!(
+            options.to_string(),
+            "TCP_NODELAY off, keepalive after 60s every 10s (5 probes), receive buffer 256KB"
+        );
+    }
+}
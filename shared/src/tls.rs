@@ -0,0 +1,56 @@
+//! TLS certificate fingerprints: the server logs the one it serves, and clients pin
+//! it to trust a self-signed certificate (trust on first use).
+//!
+//! Written like `openssl x509 -fingerprint -sha256`: the SHA-256 of the DER
+//! certificate as colon-separated uppercase hex, so the two can be compared by eye.
+
+use sha2::{Digest, Sha256};
+
+/// SHA-256 fingerprint of a DER encoded certificate
+pub fn certificate_fingerprint(der: &[u8]) -> String {
+    Sha256::digest(der)
+        .iter()
+        .map(|byte| format!("{:02X}", byte))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// Normalize a fingerprint typed by the user. Colons are optional and case doesn't
+/// matter; None if it isn't a SHA-256 fingerprint.
+pub fn parse_fingerprint(value: &str) -> Option<String> {
+    let hex: String = value.trim().chars().filter(|&c| c != ':').collect();
+    if hex.len() != 64 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    let hex = hex.to_ascii_uppercase();
+    Some(
+        hex.as_bytes()
+            .chunks(2)
+            .map(|pair| String::from_utf8_lossy(pair).into_owned())
+            .collect::<Vec<_>>()
+            .join(":"),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fingerprint_format() {
+        let fingerprint = certificate_fingerprint(b"certificate");
+        assert_eq!(fingerprint.len(), 32 * 3 - 1);
+        assert_eq!(parse_fingerprint(&fingerprint), Some(fingerprint.clone()));
+
+        // Pasted without colons, in lower case
+        let bare = fingerprint.replace(':', "").to_lowercase();
+        assert_eq!(parse_fingerprint(&bare), Some(fingerprint.clone()));
+        assert_eq!(
+            parse_fingerprint(&format!("  {}\n", bare)),
+            Some(fingerprint)
+        );
+
+        assert_eq!(parse_fingerprint("AB:CD"), None);
+        assert_eq!(parse_fingerprint(&"zz".repeat(32)), None);
+    }
+}
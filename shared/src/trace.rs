@@ -0,0 +1,218 @@
+//! Protocol trace mode: every frame sent or received is written to a trace file
+//! with its size, timings and a hex dump, so framing problems (partial chunk reads,
+//! missing acknowledgements) can be debugged without a packet capture.
+
+use crate::message::MessageTypes;
+use chrono::Local;
+use std::fmt::{self, Write as _};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Name of the trace file in the server data / client state directory
+pub const TRACE_FILE: &str = "trace.log";
+/// Bytes of each frame included in the hex dump (file transfers can be 100MB)
+pub const DUMP_LIMIT: usize = 1024;
+const BYTES_PER_LINE: usize = 16;
+
+struct TraceFile {
+    writer: BufWriter<File>,
+    started: Instant,
+}
+
+/// Handle to a trace file, labelled with the connection it traces.
+/// Clones share the same file.
+#[derive(Clone)]
+pub struct Tracer {
+    file: Arc<Mutex<TraceFile>>,
+    label: String,
+}
+
+impl fmt::Debug for Tracer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Tracer")
+            .field("label", &self.label)
+            .finish()
+    }
+}
+
+impl Tracer {
+    /// Open (appending to) a trace file
+    pub fn open(path: &Path) -> io::Result<Self> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Tracer {
+            file: Arc::new(Mutex::new(TraceFile {
+                writer: BufWriter::new(file),
+                started: Instant::now(),
+            })),
+            label: String::new(),
+        })
+    }
+
+    /// A tracer writing to the same file, with entries labelled `label` (e.g. the peer address)
+    pub fn labelled(&self, label: impl fmt::Display) -> Self {
+        Tracer {
+            file: Arc::clone(&self.file),
+            label: label.to_string(),
+        }
+    }
+
+    /// Record a frame we sent: `frame` includes the length prefix
+    pub fn sent(&self, frame: &[u8], chunks: usize, ack_after: Duration) {
+        self.write(
+            &format!(
+                "SEND {} bytes {}, {} chunk(s), OK after {}",
+                frame.len(),
+                describe(frame),
+                chunks,
+                format_duration(ack_after)
+            ),
+            Some(frame),
+        );
+    }
+
+    /// Record a frame we received: `frame` includes the length prefix and `reads`
+    /// holds the size of every read it took to assemble the body
+    pub fn received(&self, frame: &[u8], reads: &[usize], took: Duration) {
+        let reads: Vec<String> = reads.iter().map(usize::to_string).collect();
+        self.write(
+            &format!(
+                "RECV {} bytes {}, {} read(s) [{}] in {}",
+                frame.len(),
+                describe(frame),
+                reads.len(),
+                reads.join(", "),
+                format_duration(took)
+            ),
+            Some(frame),
+        );
+    }
+
+    /// Record anything else worth knowing: bad acknowledgements, truncated frames, errors
+    pub fn event(&self, text: &str) {
+        self.write(text, None);
+    }
+
+    fn write(&self, summary: &str, frame: Option<&[u8]>) {
+        // A poisoned lock only means another connection panicked mid-write
+        let mut file = self.file.lock().unwrap_or_else(|e| e.into_inner());
+        let elapsed = file.started.elapsed();
+        let mut entry = format!(
+            "[{}] +{:.3}s {} {}\n",
+            Local::now().format("%H:%M:%S%.3f"),
+            elapsed.as_secs_f64(),
+            if self.label.is_empty() {
+                "-"
+            } else {
+                &self.label
+            },
+            summary
+        );
+        if let Some(frame) = frame {
+            entry.push_str(&hex_dump(frame, DUMP_LIMIT));
+        }
+        // Tracing must never take the connection down, so write errors are ignored
+        let _ = file.writer.write_all(entry.as_bytes());
+        let _ = file.writer.flush();
+    }
+}
+
+/// Message type of a frame (`[len][msg_len][type]...`), if it has one
+fn describe(frame: &[u8]) -> String {
+    match frame.get(8) {
+        Some(&byte) => format!("{:?}", MessageTypes::from(byte)),
+        None => "(no message type)".to_string(),
+    }
+}
+
+fn format_duration(duration: Duration) -> String {
+    let micros = duration.as_micros();
+    if micros < 1000 {
+        format!("{}µs", micros)
+    } else {
+        format!("{:.1}ms", micros as f64 / 1000.0)
+    }
+}
+
+/// Classic offset / hex / ASCII dump of at most `limit` bytes
+pub fn hex_dump(bytes: &[u8], limit: usize) -> String {
+    let mut out = String::new();
+    for (line, chunk) in bytes[..bytes.len().min(limit)]
+        .chunks(BYTES_PER_LINE)
+        .enumerate()
+    {
+        let _ = write!(out, "  {:08x} ", line * BYTES_PER_LINE);
+        for i in 0..BYTES_PER_LINE {
+            if i == BYTES_PER_LINE / 2 {
+                out.push(' ');
+            }
+            match chunk.get(i) {
+                Some(byte) => {
+                    let _ = write!(out, " {:02x}", byte);
+                }
+                None => out.push_str("   "),
+            }
+        }
+        let ascii: String = chunk
+            .iter()
+            .map(|&b| {
+                if b.is_ascii_graphic() || b == b' ' {
+                    b as char
+                } else {
+                    '.'
+                }
+            })
+            .collect();
+        let _ = writeln!(out, "  |{}|", ascii);
+    }
+    if bytes.len() > limit {
+        let _ = writeln!(out, "  ... {} more bytes", bytes.len() - limit);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hex_dump() {
+        let dump = hex_dump(b"OK\x00hello, world!!!", DUMP_LIMIT);
+        let lines: Vec<&str> = dump.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(
+            lines[0],
+            "  00000000  4f 4b 00 68 65 6c 6c 6f  2c 20 77 6f 72 6c 64 21  |OK.hello, world!|"
+        );
+        assert!(lines[1].starts_with("  00000010  21 21 "));
+        assert!(lines[1].ends_with("|!!|"));
+
+        let truncated = hex_dump(&[0u8; 40], 32);
+        assert_eq!(truncated.lines().count(), 3);
+        assert!(truncated.ends_with("... 8 more bytes\n"));
+    }
+
+    #[test]
+    fn test_trace_file_entries() {
+        let path = std::env::temp_dir().join(format!("rust_chat_trace_{}.log", std::process::id()));
+        let tracer = Tracer::open(&path).unwrap().labelled("127.0.0.1:9000");
+        let mut frame = 6u32.to_be_bytes().to_vec();
+        frame.extend_from_slice(&6u32.to_be_bytes());
+        frame.extend_from_slice(&[1, b'h']);
+        tracer.received(&frame, &[4, 2], Duration::from_micros(250));
+        tracer.event("expected OK, got 00 00");
+
+        let contents = fs::read_to_string(&path).unwrap();
+        assert!(
+            contents
+                .contains("127.0.0.1:9000 RECV 10 bytes ChatMessage, 2 read(s) [4, 2] in 250µs")
+        );
+        assert!(contents.contains("127.0.0.1:9000 expected OK, got 00 00"));
+        fs::remove_file(path).unwrap();
+    }
+}
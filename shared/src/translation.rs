@@ -0,0 +1,89 @@
+//! Machine translations of room messages, for rooms a moderator set a language on.
+//! The server's translation hook translates each message said in such a room and
+//! sends the members a Translation frame after it, `room|sender|ref|lang|text`.
+//! `ref` links the translation to the message it translates - a hash of that
+//! message's text - so clients can show it under the message, collapse it, or drop
+//! it with the message when they don't show that.
+
+use sha2::{Digest, Sha256};
+
+/// Hex characters in a reference
+pub const REFERENCE_LENGTH: usize = 16;
+/// Longest language code: "en", "pt-BR", "zh-Hant"
+pub const MAX_LANGUAGE_LENGTH: usize = 16;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Translation {
+    pub room: String,
+    pub sender: String,
+    /// reference() of the message translated
+    pub reference: String,
+    /// Language translated into
+    pub lang: String,
+    pub text: String,
+}
+
+impl Translation {
+    pub fn encode(&self) -> String {
+        format!(
+            "{}|{}|{}|{}|{}",
+            self.room, self.sender, self.reference, self.lang, self.text
+        )
+    }
+
+    pub fn decode(content: &str) -> Option<Self> {
+        let mut fields = content.splitn(5, '|');
+        Some(Translation {
+            room: fields.next()?.to_string(),
+            sender: fields.next()?.to_string(),
+            reference: fields.next()?.to_string(),
+            lang: fields.next().filter(|lang| is_language(lang))?.to_string(),
+            text: fields.next()?.to_string(),
+        })
+    }
+}
+
+/// The reference of a message, from its text as sent (parts put back together,
+/// without a signature)
+pub fn reference(message: &str) -> String {
+    let digest = Sha256::digest(message.as_bytes());
+    hex::encode(&digest[..REFERENCE_LENGTH / 2])
+}
+
+/// A language code: letters, then subtags of letters and digits after hyphens
+pub fn is_language(code: &str) -> bool {
+    let mut subtags = code.split('-');
+    code.len() <= MAX_LANGUAGE_LENGTH
+        && subtags.next().is_some_and(|primary| {
+            primary.len() >= 2 && primary.chars().all(|c| c.is_ascii_alphabetic())
+        })
+        && subtags
+            .all(|subtag| !subtag.is_empty() && subtag.chars().all(|c| c.is_ascii_alphanumeric()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let translation = Translation {
+            room: "ops".to_string(),
+            sender: "alice".to_string(),
+            reference: reference("bonjour | tout le monde"),
+            lang: "en".to_string(),
+            text: "hello | everyone".to_string(),
+        };
+        assert_eq!(translation.reference.len(), REFERENCE_LENGTH);
+        assert_eq!(
+            Translation::decode(&translation.encode()),
+            Some(translation)
+        );
+        assert_eq!(Translation::decode("ops|alice|00|e|hi"), None);
+        assert_eq!(Translation::decode("ops|alice|00"), None);
+
+        assert!(is_language("pt-BR") && is_language("zh-Hant") && is_language("fr"));
+        assert!(!is_language("f") && !is_language("en-") && !is_language("en_US"));
+        assert_ne!(reference("hi"), reference("hi "));
+    }
+}
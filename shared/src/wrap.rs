@@ -0,0 +1,99 @@
+//! Word-wrapping for terminal output. Long messages are broken between words at the
+//! terminal width, and continuation lines are indented to line up with the start of
+//! the message text (under the sender's name and timestamp). The width is read for
+//! every message, so output follows the terminal as it is resized.
+
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
+
+/// Narrowest room left for text; past this the hanging indent shrinks to MIN_INDENT
+const MIN_TEXT_WIDTH: usize = 20;
+/// Hanging indent kept however narrow the terminal, so a continuation line never
+/// starts at the left edge where it could pass for a line of its own
+const MIN_INDENT: usize = 2;
+
+/// Current terminal width, or None when output isn't a terminal (nothing is wrapped)
+pub fn terminal_width() -> Option<usize> {
+    terminal_size::terminal_size().map(|(width, _)| usize::from(width.0))
+}
+
+/// Wrap `text`, whose first line starts at column `indent`, to `width` columns.
+/// Existing line breaks are kept; continuation lines are indented by `indent` spaces.
+pub fn wrap(text: &str, indent: usize, width: usize) -> String {
+    let indent = if width.saturating_sub(indent) < MIN_TEXT_WIDTH {
+        indent.min(MIN_INDENT)
+    } else {
+        indent
+    };
+    let available = width.saturating_sub(indent).max(1);
+    let padding = " ".repeat(indent);
+
+    let mut lines = Vec::new();
+    for paragraph in text.split('\n') {
+        wrap_paragraph(paragraph, available, &mut lines);
+    }
+    lines.join(&format!("\n{}", padding))
+}
+
+fn wrap_paragraph(paragraph: &str, width: usize, lines: &mut Vec<String>) {
+    let mut line = String::new();
+    let mut line_width = 0;
+    for word in paragraph.split(' ') {
+        let word_width = word.width();
+        // Room for the word plus the space before it?
+        if line_width > 0 && line_width + 1 + word_width > width {
+            lines.push(std::mem::take(&mut line));
+            line_width = 0;
+        }
+        if line_width > 0 {
+            line.push(' ');
+            line_width += 1;
+        }
+        if word_width <= width - line_width {
+            line.push_str(word);
+            line_width += word_width;
+            continue;
+        }
+        // Longer than a whole line (e.g. a URL): break it wherever it has to be
+        for c in word.chars() {
+            let c_width = c.width().unwrap_or(0);
+            if line_width + c_width > width && line_width > 0 {
+                lines.push(std::mem::take(&mut line));
+                line_width = 0;
+            }
+            line.push(c);
+            line_width += c_width;
+        }
+    }
+    lines.push(line);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wraps_between_words_with_hanging_indent() {
+        let text = "the quick brown fox jumps over the lazy dog";
+        assert_eq!(
+            wrap(text, 4, 24),
+            "the quick brown fox\n    jumps over the lazy\n    dog"
+        );
+        assert_eq!(wrap("short", 4, 80), "short");
+        assert_eq!(wrap("one\ntwo", 2, 80), "one\n  two");
+    }
+
+    #[test]
+    fn test_long_words_and_narrow_terminals() {
+        assert_eq!(
+            wrap(&"x".repeat(30), 0, 25),
+            format!("{}\n{}", "x".repeat(25), "x".repeat(5))
+        );
+        // Too narrow for the indent: continuation lines are barely indented
+        assert_eq!(
+            wrap("aaaa bbbb cccc dddd eeee ffff", 20, 26),
+            "aaaa bbbb cccc dddd eeee\n  ffff"
+        );
+        // Wide characters take two columns
+        assert_eq!(wrap("日本語 日本語", 0, 8), "日本語\n日本語");
+    }
+}